@@ -0,0 +1,55 @@
+use std::collections::{HashSet, VecDeque};
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use hddmond::scanners::{scanner::ScanEventType, smartctl_scanner::diff_device_names};
+
+// A big box of drives, and how many of them change between two scans.
+const FLEET: usize = 200;
+const CHURN: usize = FLEET / 20;
+
+fn fleet(range: std::ops::Range<usize>) -> Vec<String> {
+    range.map(|i| format!("/dev/sd{}", i)).collect()
+}
+
+// What the stream does with every scan result: diff it against the known
+// devices and queue up the events.
+fn diff_and_queue(known: &mut HashSet<String>, scan: Vec<String>) -> VecDeque<ScanEventType> {
+    let diff = diff_device_names(known, scan);
+
+    let mut queue = VecDeque::new();
+    queue.extend(diff.added.into_iter().map(ScanEventType::DeviceFound));
+    queue.extend(diff.removed.into_iter().map(ScanEventType::DeviceLost));
+    queue
+}
+
+fn smartctl_diff(c: &mut Criterion) {
+    let before = fleet(0..FLEET);
+    // The first CHURN devices left, as many new ones showed up.
+    let after = fleet(CHURN..FLEET + CHURN);
+
+    c.bench_function("smartctl diff, 200 devices, no change", |b| {
+        let mut known = HashSet::new();
+        diff_and_queue(&mut known, before.clone());
+
+        b.iter_batched(
+            || before.clone(),
+            |scan| black_box(diff_and_queue(&mut known, scan)),
+            BatchSize::SmallInput,
+        )
+    });
+
+    c.bench_function("smartctl diff, 200 devices, 5% churn", |b| {
+        b.iter_batched(
+            || {
+                let mut known = HashSet::new();
+                diff_and_queue(&mut known, before.clone());
+                (known, after.clone())
+            },
+            |(mut known, scan)| black_box(diff_and_queue(&mut known, scan)),
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+criterion_group!(benches, smartctl_diff);
+criterion_main!(benches);
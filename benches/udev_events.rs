@@ -0,0 +1,46 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use hddmond::scanners::udev_scanner::{disk_event, UdevEvent};
+
+// The same box of drives as the smartctl benchmark, each with a few
+// partitions that udev tells us about as well.
+const FLEET: usize = 200;
+const CHURN: usize = FLEET / 20;
+const PARTITIONS: usize = 4;
+
+fn event(name: String, devtype: &str, action: &str) -> UdevEvent {
+    UdevEvent {
+        name: Some(name),
+        devtype: Some(devtype.to_string()),
+        action: Some(action.to_string()),
+    }
+}
+
+// What a tick with 5% churn brings: CHURN disks and their partitions
+// removed, as many added.
+fn churn() -> Vec<UdevEvent> {
+    let mut events = vec![];
+    for (disks, action) in [(0..CHURN, "remove"), (FLEET..FLEET + CHURN, "add")] {
+        for disk in disks {
+            events.push(event(format!("sd{}", disk), "disk", action));
+            for partition in 1..=PARTITIONS {
+                events.push(event(
+                    format!("sd{}{}", disk, partition),
+                    "partition",
+                    action,
+                ));
+            }
+        }
+    }
+    events
+}
+
+fn udev_mapping(c: &mut Criterion) {
+    let events = churn();
+
+    c.bench_function("udev mapping, 200 devices, 5% churn", |b| {
+        b.iter(|| black_box(events.iter().filter_map(disk_event).collect::<Vec<_>>()))
+    });
+}
+
+criterion_group!(benches, udev_mapping);
+criterion_main!(benches);
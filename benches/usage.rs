@@ -0,0 +1,25 @@
+use std::time::Duration;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use hddmond::usage::Counter;
+
+// What counting costs every instrumented call, against the commands and
+// reads it's wrapped around, which take milliseconds.
+fn counters(c: &mut Criterion) {
+    let counter = Counter::new();
+
+    c.bench_function("usage, timing a call", |b| {
+        b.iter(|| counter.time(|| black_box(1)))
+    });
+
+    c.bench_function("usage, recording a duration", |b| {
+        b.iter(|| counter.record(black_box(Duration::from_micros(10))))
+    });
+
+    c.bench_function("usage, adding bytes", |b| {
+        b.iter(|| counter.add_bytes(black_box(4096)))
+    });
+}
+
+criterion_group!(benches, counters);
+criterion_main!(benches);
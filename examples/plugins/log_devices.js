@@ -0,0 +1,11 @@
+// Example hddmond plugin. Copy it into the plugin directory and the daemon
+// will call whichever of these exported hooks it finds.
+
+export function onDeviceFound(device) {
+  hddmond.log("info", `found ${device.name}`);
+}
+
+export async function onDeviceLost(device) {
+  const remaining = await hddmond.getDevices();
+  hddmond.log("info", `lost ${device.name}, ${remaining.length} left`);
+}
@@ -0,0 +1,16 @@
+// The log_devices example again, in TypeScript. The daemon only loads
+// JavaScript, so compile it first:
+//
+//   hddmond plugin-types > hddmond.d.ts
+//   tsc --target es2020 --module es2020 log_devices.ts
+
+import type { Device, PluginHooks } from "./hddmond";
+
+export const onDeviceFound: PluginHooks["onDeviceFound"] = (device: Device) => {
+  hddmond.log("info", `found ${device.name}`);
+};
+
+export const onDeviceLost: PluginHooks["onDeviceLost"] = async (device: Device) => {
+  const remaining = await hddmond.getDevices();
+  hddmond.log("info", `lost ${device.name}, ${remaining.length} left`);
+};
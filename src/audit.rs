@@ -0,0 +1,713 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use chrono::{DateTime, Local, NaiveDate, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+
+use crate::{config::AuditConfig, error::AuditError};
+
+// How much of the end of the file startup reads to pick up the sequence
+// and check the chain.
+const TAIL_BYTES: u64 = 1024 * 1024;
+
+// One line of the audit trail.
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+    // One up from the record before, across restarts and rotations.
+    seq: u64,
+    at: String,
+    event: String,
+    #[serde(flatten)]
+    fields: Map<String, Value>,
+    // The SHA-256 of the line before this one, in hex, so a line that's
+    // changed or taken out breaks the chain from there on.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prev_sha256: Option<String>,
+}
+
+/// An append-only, newline delimited JSON record of everything the daemon
+/// did, kept apart from the registry, which forgets. Does nothing unless
+/// audit.path is set.
+///
+/// Writes are plain appends with no buffering of our own, so a crash loses
+/// at most what the kernel hadn't written out yet, fsynced every
+/// audit.fsync_interval_secs. A record a crash cut in half is dropped on the
+/// next start, it never made it in full, and the chain picks up from the
+/// last whole one.
+pub struct AuditLog {
+    trail: Option<Trail>,
+}
+
+struct Trail {
+    path: PathBuf,
+    chain: bool,
+    sync_every_record: bool,
+    file: File,
+    // The day the records in the file are from, it's rotated once that
+    // isn't today anymore.
+    day: NaiveDate,
+    next_seq: u64,
+    last_hash: Option<String>,
+    unsynced: bool,
+}
+
+impl AuditLog {
+    /// Opens the trail at audit.path, a log that records nothing if unset.
+    pub fn open(config: &AuditConfig) -> Result<Self, AuditError> {
+        let path = match &config.path {
+            Some(path) => path.clone(),
+            None => return Ok(Self { trail: None }),
+        };
+
+        let (file, last_line) = open_trail(&path).map_err(|error| AuditError::File {
+            operation: "open",
+            path: path.clone(),
+            error,
+        })?;
+        let day = file
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .map(|modified| DateTime::<Local>::from(modified).date_naive())
+            .unwrap_or_else(|_| Local::now().date_naive());
+
+        // Nothing in the current file yet, the chain goes on from the last
+        // rotated one.
+        let last_line = match last_line {
+            Some(line) => Some(line),
+            None => newest_rotated(&path).and_then(|rotated| last_line_of(&rotated).ok().flatten()),
+        };
+        let (next_seq, last_hash) = match &last_line {
+            Some(line) => {
+                let record: Record =
+                    serde_json::from_str(line).map_err(|error| AuditError::LastRecord {
+                        path: path.clone(),
+                        error,
+                    })?;
+                (record.seq + 1, Some(sha256(line)))
+            }
+            None => (1, None),
+        };
+
+        info!(
+            "Writing the audit trail to {}, from record {}.",
+            path.display(),
+            next_seq
+        );
+        Ok(Self {
+            trail: Some(Trail {
+                path,
+                chain: config.chain,
+                sync_every_record: config.fsync_interval_secs == 0,
+                file,
+                day,
+                next_seq,
+                last_hash,
+                unsynced: false,
+            }),
+        })
+    }
+
+    /// Appends a record. `fields` is an object of whatever else goes in it.
+    /// The trail failing is logged, it's no reason to stop the daemon.
+    pub fn record(&mut self, event: &str, fields: Value) {
+        let trail = match &mut self.trail {
+            Some(trail) => trail,
+            None => return,
+        };
+
+        let today = Local::now().date_naive();
+        if today != trail.day {
+            if let Err(e) = trail.rotate(today) {
+                error!(
+                    "Failed to rotate the audit trail {}, still writing to it: {:#}",
+                    trail.path.display(),
+                    e
+                );
+            }
+        }
+
+        if let Err(e) = trail.append(event, fields) {
+            error!(
+                "Failed to write to the audit trail {}: {:#}",
+                trail.path.display(),
+                e
+            );
+        }
+    }
+
+    /// Flushes what was written since the last time to disk, off the event
+    /// loop.
+    pub fn sync(&mut self) {
+        let trail = match &mut self.trail {
+            Some(trail) if trail.unsynced => trail,
+            _ => return,
+        };
+
+        match trail.file.try_clone() {
+            Ok(file) => {
+                trail.unsynced = false;
+                tokio::task::spawn_blocking(move || {
+                    if let Err(e) = file.sync_data() {
+                        error!("Failed to sync the audit trail: {}", e);
+                    }
+                });
+            }
+            Err(e) => error!("Failed to sync the audit trail: {}", e),
+        }
+    }
+
+    /// Syncs for the last time, waiting for it.
+    pub fn close(self) {
+        if let Some(trail) = self.trail {
+            if let Err(e) = trail.file.sync_data() {
+                error!("Failed to sync the audit trail: {}", e);
+            }
+        }
+    }
+}
+
+impl Trail {
+    fn append(&mut self, event: &str, fields: Value) -> Result<(), AuditError> {
+        let fields = match fields {
+            Value::Object(fields) => fields,
+            Value::Null => Map::new(),
+            fields => return Err(AuditError::NotFields { fields }),
+        };
+        let record = Record {
+            seq: self.next_seq,
+            at: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            event: event.to_string(),
+            fields,
+            prev_sha256: if self.chain {
+                self.last_hash.clone()
+            } else {
+                None
+            },
+        };
+
+        // Strings and JSON values all the way down, there's nothing in it
+        // serde_json could turn down.
+        let line = serde_json::to_string(&record).expect("an audit record");
+        // In one write, so the line goes in whole or the crash cuts it
+        // short, never interleaved with anything.
+        let write_error = |error| AuditError::File {
+            operation: "write to",
+            path: self.path.clone(),
+            error,
+        };
+        self.file
+            .write_all(format!("{}\n", line).as_bytes())
+            .map_err(write_error)?;
+        if self.sync_every_record {
+            self.file.sync_data().map_err(write_error)?;
+        } else {
+            self.unsynced = true;
+        }
+
+        self.next_seq += 1;
+        self.last_hash = Some(sha256(&line));
+        Ok(())
+    }
+
+    fn rotate(&mut self, today: NaiveDate) -> io::Result<()> {
+        self.file.sync_data()?;
+
+        // Only taken if the clock went back at some point.
+        let mut to = rotated_path(&self.path, self.day, 0);
+        let mut index = 0;
+        while to.exists() {
+            index += 1;
+            to = rotated_path(&self.path, self.day, index);
+        }
+        fs::rename(&self.path, &to)?;
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.day = today;
+        self.unsynced = false;
+        Ok(())
+    }
+}
+
+// `audit.jsonl.2024-05-01`, or `audit.jsonl.2024-05-01.1` and up if that's
+// taken.
+fn rotated_path(path: &Path, day: NaiveDate, index: usize) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{}", day.format("%Y-%m-%d")));
+    if index > 0 {
+        rotated.push(format!(".{}", index));
+    }
+    PathBuf::from(rotated)
+}
+
+fn newest_rotated(path: &Path) -> Option<PathBuf> {
+    let prefix = format!("{}.", path.to_string_lossy());
+    let pattern = format!("{}*", glob::Pattern::escape(&prefix));
+    glob::glob(&pattern)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        // The dates sort as text, but `.1` has to come after the plain one.
+        .max_by_key(|rotated| {
+            let name = rotated.to_string_lossy();
+            let suffix = name.strip_prefix(&prefix).unwrap_or_default();
+            let (day, index) = suffix.split_once('.').unwrap_or((suffix, "0"));
+            (day.to_string(), index.parse::<usize>().unwrap_or(0))
+        })
+}
+
+// Opens the trail for appending, first cutting off a record a crash left
+// half written, and checks the records at the end chain up. Returns the
+// last whole line, if there is one.
+fn open_trail(path: &Path) -> io::Result<(File, Option<String>)> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(path)?;
+
+    // Cut at the last newline in the bytes themselves: the tail can start
+    // partway into a character, and a crash can leave one half written, so
+    // offsets into a decoded copy needn't be offsets into the file.
+    let (start, tail) = read_tail(&mut file)?;
+    let complete = match tail.iter().rposition(|&byte| byte == b'\n') {
+        Some(end) => end + 1,
+        // A single record longer than TAIL_BYTES, or none at all.
+        None if start == 0 => 0,
+        None => tail.len(),
+    };
+    if complete < tail.len() {
+        warn!(
+            "The last record in the audit trail {} was cut short, probably by a crash. \
+             Dropping it: {}",
+            path.display(),
+            String::from_utf8_lossy(&tail[complete..]).trim_end()
+        );
+        file.set_len(start + complete as u64)?;
+    }
+
+    // Unless the tail is the whole file, its first line is only the end of
+    // one.
+    let first = match start {
+        0 => 0,
+        _ => tail[..complete]
+            .iter()
+            .position(|&byte| byte == b'\n')
+            .map_or(complete, |end| end + 1),
+    };
+    let whole = String::from_utf8_lossy(&tail[first..complete]);
+    let lines = whole.lines().collect::<Vec<_>>();
+    if let Err(problem) = check_links(lines.iter().copied()) {
+        warn!(
+            "The audit trail {} doesn't add up near its end, it may have been tampered with: {}",
+            path.display(),
+            problem
+        );
+    }
+
+    Ok((file, lines.last().map(|line| line.to_string())))
+}
+
+fn last_line_of(path: &Path) -> io::Result<Option<String>> {
+    let (_, tail) = read_tail(&mut File::open(path)?)?;
+    let tail = tail.strip_suffix(b"\n").unwrap_or(&tail);
+    let last = match tail.iter().rposition(|&byte| byte == b'\n') {
+        Some(end) => &tail[end + 1..],
+        None => tail,
+    };
+    Ok((!last.is_empty()).then(|| String::from_utf8_lossy(last).into_owned()))
+}
+
+// The last TAIL_BYTES of the file, and where they start in it.
+fn read_tail(file: &mut File) -> io::Result<(u64, Vec<u8>)> {
+    let len = file.metadata()?.len();
+    let start = len.saturating_sub(TAIL_BYTES);
+    file.seek(SeekFrom::Start(start))?;
+    let mut tail = vec![];
+    file.read_to_end(&mut tail)?;
+    Ok((start, tail))
+}
+
+/// What `hddmond audit verify` found.
+#[derive(Debug, Default)]
+pub struct Verified {
+    /// How many records the file holds.
+    pub records: u64,
+    /// How many carry the hash of the one before.
+    pub chained: u64,
+    /// The first record's sequence number.
+    pub first_seq: Option<u64>,
+    /// The last whole record's sequence number.
+    pub last_seq: Option<u64>,
+    /// Whether the file ends in a record a crash cut short, which the
+    /// daemon drops when it starts next.
+    pub partial_tail: bool,
+}
+
+/// Checks a whole audit file: every line a record, numbered one up from the
+/// last, carrying the hash of the line before if it carries one at all. The
+/// first record's hash points into the file before it, and isn't checked.
+pub fn verify(path: &Path) -> Result<Verified, AuditError> {
+    let contents = fs::read(path).map_err(|error| AuditError::File {
+        operation: "read",
+        path: path.to_path_buf(),
+        error,
+    })?;
+    let contents = String::from_utf8(contents).map_err(|_| AuditError::NotText {
+        path: path.to_path_buf(),
+    })?;
+
+    let (complete, partial) = match contents.rfind('\n') {
+        Some(end) => contents.split_at(end + 1),
+        None => ("", contents.as_str()),
+    };
+
+    let lines = complete.lines().collect::<Vec<_>>();
+    check_links(lines.iter().copied()).map_err(|problem| AuditError::Broken { problem })?;
+
+    let mut verified = Verified {
+        partial_tail: !partial.is_empty(),
+        ..Default::default()
+    };
+    for line in lines {
+        // Already known to parse.
+        let record: Record = serde_json::from_str(line).expect("an audit record");
+        verified.records += 1;
+        verified.chained += record.prev_sha256.is_some() as u64;
+        verified.first_seq.get_or_insert(record.seq);
+        verified.last_seq = Some(record.seq);
+    }
+    Ok(verified)
+}
+
+// Line numbers in the problem count from the first line given.
+fn check_links<'a>(lines: impl Iterator<Item = &'a str>) -> Result<(), String> {
+    let mut last: Option<(u64, &str)> = None;
+    for (number, line) in lines.enumerate() {
+        let number = number + 1;
+        let record: Record = serde_json::from_str(line)
+            .map_err(|e| format!("line {} isn't an audit record: {}", number, e))?;
+
+        if let Some((seq, previous)) = last {
+            if record.seq != seq + 1 {
+                return Err(format!(
+                    "line {} is record {}, but the one before it is {}",
+                    number, record.seq, seq
+                ));
+            }
+            if let Some(hash) = &record.prev_sha256 {
+                if *hash != sha256(previous) {
+                    return Err(format!(
+                        "line {} (record {}) doesn't match the hash of the line before it",
+                        number, record.seq
+                    ));
+                }
+            }
+        }
+
+        last = Some((record.seq, line));
+    }
+    Ok(())
+}
+
+fn sha256(line: &str) -> String {
+    Sha256::digest(line.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Sync interval for the event loop, which needs one even when there's
+/// nothing to sync.
+pub fn sync_interval(config: &AuditConfig) -> Duration {
+    config.fsync_interval().max(Duration::from_secs(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    struct TempDir(TestDir);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            Self(TestDir::new(&format!("audit-{}", name)))
+        }
+
+        fn trail(&self) -> PathBuf {
+            self.0.join("audit.jsonl")
+        }
+
+        fn open(&self) -> AuditLog {
+            AuditLog::open(&AuditConfig {
+                path: Some(self.trail()),
+                fsync_interval_secs: 0,
+                chain: true,
+            })
+            .unwrap()
+        }
+    }
+
+    fn lines(path: &Path) -> Vec<String> {
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    fn record(line: &str) -> Record {
+        serde_json::from_str(line).unwrap()
+    }
+
+    // Records `count` device_found events, numbered from `from`.
+    fn found(log: &mut AuditLog, from: usize, count: usize) {
+        for n in from..from + count {
+            log.record("device_found", json!({ "device": format!("sd{}", n) }));
+        }
+    }
+
+    #[test]
+    fn records_chain_up_across_restarts() {
+        let dir = TempDir::new("chain");
+        let mut log = dir.open();
+        found(&mut log, 0, 3);
+        // Not an object, so not a record.
+        log.record("device_found", json!("sdz"));
+        log.close();
+
+        let mut log = dir.open();
+        found(&mut log, 3, 2);
+        log.close();
+
+        let lines = lines(&dir.trail());
+        assert_eq!(lines.len(), 5);
+        for (n, line) in lines.iter().enumerate() {
+            let record = record(line);
+            assert_eq!(record.seq, n as u64 + 1);
+            assert_eq!(record.event, "device_found");
+            assert_eq!(record.fields["device"], format!("sd{}", n));
+            let previous = n.checked_sub(1).map(|previous| sha256(&lines[previous]));
+            assert_eq!(record.prev_sha256, previous, "{}", line);
+        }
+
+        let verified = verify(&dir.trail()).unwrap();
+        assert_eq!(verified.records, 5);
+        assert_eq!(verified.chained, 4);
+        assert_eq!((verified.first_seq, verified.last_seq), (Some(1), Some(5)));
+        assert!(!verified.partial_tail);
+    }
+
+    #[test]
+    fn tampering_breaks_the_chain() {
+        let dir = TempDir::new("tampered");
+        let mut log = dir.open();
+        found(&mut log, 0, 5);
+        log.close();
+        let original = lines(&dir.trail());
+
+        let mut edited = original.clone();
+        edited[2] = edited[2].replace("sd2", "sd9");
+        let mut removed = original.clone();
+        removed.remove(2);
+        let mut renumbered = removed.clone();
+        renumbered[2] = renumbered[2].replace("\"seq\":4", "\"seq\":3");
+        let mut garbled = original.clone();
+        garbled[1] = "{\"seq\":".to_string();
+
+        for (lines, problem) in [
+            (
+                edited,
+                "line 4 (record 4) doesn't match the hash of the line before it",
+            ),
+            (removed, "line 3 is record 4, but the one before it is 2"),
+            (
+                renumbered,
+                "line 3 (record 3) doesn't match the hash of the line before it",
+            ),
+            (garbled, "line 2 isn't an audit record"),
+        ] {
+            fs::write(dir.trail(), lines.join("\n") + "\n").unwrap();
+            let error = verify(&dir.trail()).unwrap_err().to_string();
+            assert!(error.starts_with(problem), "{}", error);
+        }
+
+        // The daemon only warns about it, and carries on from the last
+        // record there is.
+        fs::write(dir.trail(), original.join("\n") + "\n").unwrap();
+        let mut log = dir.open();
+        found(&mut log, 5, 1);
+        log.close();
+        assert_eq!(verify(&dir.trail()).unwrap().last_seq, Some(6));
+    }
+
+    #[test]
+    fn the_chain_carries_on_over_a_rotation() {
+        let dir = TempDir::new("rotation");
+        let mut log = dir.open();
+        found(&mut log, 0, 3);
+
+        // Written yesterday, as far as the log knows, so the next record
+        // rotates the file first.
+        let today = Local::now().date_naive();
+        let yesterday = today.pred_opt().unwrap();
+        log.trail.as_mut().unwrap().day = yesterday;
+        found(&mut log, 3, 2);
+        assert_eq!(log.trail.as_ref().unwrap().day, today);
+        log.close();
+
+        let rotated = rotated_path(&dir.trail(), yesterday, 0);
+        let (before, after) = (lines(&rotated), lines(&dir.trail()));
+        assert_eq!(before.len(), 3);
+        assert_eq!(after.len(), 2);
+        let first_after = record(&after[0]);
+        assert_eq!(first_after.seq, 4);
+        assert_eq!(first_after.prev_sha256, Some(sha256(&before[2])));
+
+        // Each file checks out on its own, and together.
+        assert_eq!(verify(&rotated).unwrap().last_seq, Some(3));
+        assert_eq!(verify(&dir.trail()).unwrap().first_seq, Some(4));
+        let both = dir.0.join("both.jsonl");
+        fs::write(&both, [before, after].concat().join("\n") + "\n").unwrap();
+        assert_eq!(verify(&both).unwrap().chained, 4);
+    }
+
+    #[test]
+    fn an_empty_trail_carries_on_from_the_newest_rotated_file() {
+        let dir = TempDir::new("after-rotation");
+        let mut log = dir.open();
+        found(&mut log, 0, 2);
+        let today = Local::now().date_naive();
+        let yesterday = today.pred_opt().unwrap();
+        let trail = log.trail.as_mut().unwrap();
+        trail.day = yesterday;
+        trail.rotate(today).unwrap();
+        found(&mut log, 2, 1);
+        // The clock went back, yesterday's name is taken.
+        let trail = log.trail.as_mut().unwrap();
+        trail.day = yesterday;
+        trail.rotate(today).unwrap();
+        log.close();
+
+        let newest = rotated_path(&dir.trail(), yesterday, 1);
+        assert_eq!(newest_rotated(&dir.trail()), Some(newest.clone()));
+        assert_eq!(lines(&dir.trail()), [] as [String; 0]);
+
+        let mut log = dir.open();
+        found(&mut log, 3, 1);
+        log.close();
+        let last = record(&lines(&dir.trail())[0]);
+        assert_eq!(last.seq, 4);
+        assert_eq!(last.prev_sha256, Some(sha256(&lines(&newest)[0])));
+    }
+
+    #[test]
+    fn a_record_cut_short_is_dropped_and_the_chain_resumes() {
+        let dir = TempDir::new("partial");
+        let mut log = dir.open();
+        found(&mut log, 0, 3);
+        log.close();
+        let whole = lines(&dir.trail());
+        let mut file = OpenOptions::new().append(true).open(dir.trail()).unwrap();
+        file.write_all(b"{\"seq\":4,\"at\":\"2026-").unwrap();
+
+        let verified = verify(&dir.trail()).unwrap();
+        assert!(verified.partial_tail);
+        assert_eq!(verified.records, 3);
+
+        let mut log = dir.open();
+        assert_eq!(lines(&dir.trail()), whole);
+        found(&mut log, 3, 1);
+        log.close();
+
+        let lines = lines(&dir.trail());
+        let resumed = record(&lines[3]);
+        assert_eq!(resumed.seq, 4);
+        assert_eq!(resumed.fields["device"], "sd3");
+        assert_eq!(resumed.prev_sha256, Some(sha256(&whole[2])));
+        let verified = verify(&dir.trail()).unwrap();
+        assert!(!verified.partial_tail);
+        assert_eq!(verified.records, 4);
+
+        // Nothing but half a record.
+        fs::write(dir.trail(), "{\"seq\":1,").unwrap();
+        let mut log = dir.open();
+        found(&mut log, 0, 1);
+        log.close();
+        assert_eq!(record(&self::lines(&dir.trail())[0]).seq, 1);
+    }
+
+    #[test]
+    fn a_tail_starting_inside_a_character_cuts_at_the_right_byte() {
+        let dir = TempDir::new("multibyte");
+        let mut log = dir.open();
+        // Longer than TAIL_BYTES, so the tail starts somewhere inside it.
+        let note = "é".repeat(TAIL_BYTES as usize / 2 + 1000);
+        log.record("note", json!({ "note": note }));
+        found(&mut log, 1, 2);
+        log.close();
+        let whole = fs::read(dir.trail()).unwrap();
+        let notes = whole
+            .windows(2)
+            .position(|pair| pair == "é".as_bytes())
+            .unwrap();
+
+        // Torn short enough that the tail starts on the second byte of an é,
+        // and ending on the first byte of another.
+        let mut torn = 40;
+        if (whole.len() + torn - TAIL_BYTES as usize - notes).is_multiple_of(2) {
+            torn += 1;
+        }
+        let mut cut = b"{\"seq\":4,\"note\":\"".to_vec();
+        cut.resize(torn - 1, b'x');
+        cut.push("é".as_bytes()[0]);
+        let mut file = OpenOptions::new().append(true).open(dir.trail()).unwrap();
+        file.write_all(&cut).unwrap();
+        let start = fs::metadata(dir.trail()).unwrap().len() - TAIL_BYTES;
+        assert_eq!(whole[start as usize], "é".as_bytes()[1]);
+
+        let mut log = dir.open();
+        let trail = fs::read(dir.trail()).unwrap();
+        assert_eq!(trail.len(), whole.len());
+        assert!(trail == whole);
+        found(&mut log, 3, 1);
+        log.close();
+
+        let lines = lines(&dir.trail());
+        let resumed = record(&lines[3]);
+        assert_eq!(resumed.seq, 4);
+        assert_eq!(resumed.prev_sha256, Some(sha256(&lines[2])));
+        let verified = verify(&dir.trail()).unwrap();
+        assert!(!verified.partial_tail);
+        assert_eq!(verified.records, 4);
+    }
+
+    #[test]
+    fn unchained_and_unset_trails() {
+        let dir = TempDir::new("unchained");
+        let mut log = AuditLog::open(&AuditConfig {
+            path: Some(dir.trail()),
+            fsync_interval_secs: 0,
+            chain: false,
+        })
+        .unwrap();
+        found(&mut log, 0, 3);
+        log.close();
+        let verified = verify(&dir.trail()).unwrap();
+        assert_eq!((verified.records, verified.chained), (3, 0));
+
+        let mut log = AuditLog::open(&AuditConfig::default()).unwrap();
+        assert!(log.trail.is_none());
+        found(&mut log, 0, 1);
+    }
+}
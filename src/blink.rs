@@ -0,0 +1,172 @@
+use std::{
+    io,
+    path::Path,
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::block_io::{AlignedBuf, BlockIo, ALIGN};
+
+// A quarter second of reads, once a second, stands out from whatever else
+// the drive's LED is doing.
+const BURST: Duration = Duration::from_millis(250);
+const PERIOD: Duration = Duration::from_secs(1);
+const READ_BYTES: usize = 64 * 1024;
+
+/// Blinks a drive's activity LED in a rhythm that can be picked out in a
+/// rack, for when there's no enclosure LED to turn on instead. Only ever
+/// reads, with O_DIRECT at offsets all over the disk so neither the page
+/// cache nor the drive's own cache can answer without the disk lighting up.
+pub fn blink(node: &Path, duration: Duration) -> io::Result<()> {
+    // Every read is ALIGN bytes at a time, whatever the drive's blocks.
+    let io = BlockIo::open(node, ALIGN as u64)?;
+    let size = io.size()?;
+    let blocks = (size / ALIGN as u64).saturating_sub((READ_BYTES / ALIGN) as u64);
+
+    let mut buf = AlignedBuf::new(READ_BYTES);
+    // A short read at the end of the disk still lit the LED.
+    pattern(duration, blocks, |block| {
+        io.read_at(&mut buf, block * ALIGN as u64, READ_BYTES)
+            .map(drop)
+    })
+}
+
+// The rhythm itself, with `read` reading READ_BYTES from the ALIGN sized
+// block it's given, out of `blocks`.
+fn pattern(
+    duration: Duration,
+    blocks: u64,
+    mut read: impl FnMut(u64) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut offsets = Offsets::new(blocks);
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        let period = Instant::now();
+        while period.elapsed() < BURST {
+            read(offsets.next())?;
+        }
+        thread::sleep(PERIOD.saturating_sub(period.elapsed()));
+    }
+
+    Ok(())
+}
+
+// Block numbers all over the disk, xorshift seeded from the clock. Nothing
+// depends on them but the drive not having seen them lately.
+struct Offsets {
+    state: u64,
+    blocks: u64,
+}
+
+impl Offsets {
+    fn new(blocks: u64) -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_nanos() as u64);
+        Self {
+            state: seed | 1,
+            blocks,
+        }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state % self.blocks.max(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    // Reads that take a millisecond, like a drive's would, and when each
+    // one started, from `start`.
+    fn mock_reads(
+        duration: Duration,
+        blocks: u64,
+        fail_after: Option<usize>,
+    ) -> (io::Result<()>, Vec<(Duration, u64)>) {
+        let start = Instant::now();
+        let mut reads = vec![];
+        let result = pattern(duration, blocks, |block| {
+            if Some(reads.len()) == fail_after {
+                return Err(io::Error::from_raw_os_error(5));
+            }
+            reads.push((start.elapsed(), block));
+            thread::sleep(Duration::from_millis(1));
+            Ok(())
+        });
+        (result, reads)
+    }
+
+    #[test]
+    fn reads_come_in_bursts_once_a_period() {
+        let duration = PERIOD * 2 + PERIOD / 10;
+        let blocks = 1000;
+        let started = Instant::now();
+        let (result, reads) = mock_reads(duration, blocks, None);
+        result.unwrap();
+
+        // Three periods started before the time was up, the last one
+        // finishes its burst and its rest.
+        let took = started.elapsed();
+        assert!(took >= PERIOD * 3, "{:?}", took);
+        assert!(took < PERIOD * 3 + BURST, "{:?}", took);
+
+        // No reads in between, and a burst's worth in every one.
+        let slack = Duration::from_millis(50);
+        for period in 0..3 {
+            let from = PERIOD * period;
+            let burst = reads
+                .iter()
+                .filter(|(at, _)| *at >= from && *at < from + PERIOD)
+                .collect::<Vec<_>>();
+            assert!(
+                burst.len() >= 50,
+                "Period {} had {} reads",
+                period,
+                burst.len()
+            );
+            for (at, _) in burst {
+                assert!(*at < from + BURST + slack, "A read at {:?}", at);
+            }
+        }
+
+        // All over the disk, and never past it.
+        assert!(reads.iter().all(|(_, block)| *block < blocks));
+        let low = reads
+            .iter()
+            .filter(|(_, block)| *block < blocks / 2)
+            .count();
+        assert!(low > reads.len() / 4 && low < reads.len() * 3 / 4);
+    }
+
+    #[test]
+    fn a_failed_read_stops_the_blinking() {
+        let (result, reads) = mock_reads(PERIOD * 10, 1000, Some(3));
+        assert_eq!(result.unwrap_err().raw_os_error(), Some(5));
+        assert_eq!(reads.len(), 3);
+    }
+
+    #[test]
+    fn tiny_disks_only_have_the_first_block() {
+        let mut offsets = Offsets::new(0);
+        assert!((0..100).all(|_| offsets.next() == 0));
+    }
+
+    #[test]
+    fn blinking_an_image() {
+        let dir = TestDir::new("blink");
+        let image = dir.join("disk.img");
+        fs::write(&image, vec![0; 4 * READ_BYTES]).unwrap();
+        blink(&image, Duration::from_millis(100)).unwrap();
+
+        let error = blink(Path::new("/nonexistent/sdx"), PERIOD).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,404 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Seek, SeekFrom},
+    os::unix::{
+        fs::{FileExt, OpenOptionsExt},
+        io::AsRawFd,
+    },
+    path::Path,
+};
+
+use nix::{
+    errno::Errno,
+    fcntl::{posix_fadvise, OFlag, PosixFadviseAdvice},
+};
+use serde::Serialize;
+
+use crate::faults::{self, FaultPoint};
+
+/// O_DIRECT wants buffers aligned to the logical block size. This covers
+/// every one there is.
+pub const ALIGN: usize = 4096;
+
+/// How reads get past the page cache, so what they return came off the
+/// device rather than out of memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheBypass {
+    /// Opened with O_DIRECT.
+    Direct,
+    /// O_DIRECT was refused, as it is by some USB bridges and some
+    /// filesystems images live on. The cached pages of a range are dropped
+    /// right before reading it instead, which can't drop pages someone has
+    /// dirtied and not written back yet.
+    DroppedCache,
+}
+
+impl CacheBypass {
+    /// How it was done, for what verify and image print.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CacheBypass::Direct => "O_DIRECT",
+            CacheBypass::DroppedCache => "dropping the page cache first",
+        }
+    }
+}
+
+/// Everything that reads a disk to find out what's on it goes through
+/// this, so none of it can end up reading the page cache by accident.
+/// Read-only, nothing in hddmond writes to disks.
+pub struct BlockIo {
+    file: File,
+    bypass: CacheBypass,
+    // What offsets and lengths must be multiples of.
+    block_size: u64,
+    // The kernel name, for the faults scoped to a device.
+    name: Option<String>,
+}
+
+impl BlockIo {
+    /// `block_size` is the device's logical block size, or any multiple of
+    /// it the caller only ever reads in.
+    pub fn open(path: &Path, block_size: u64) -> io::Result<Self> {
+        let direct = OpenOptions::new()
+            .read(true)
+            .custom_flags(OFlag::O_DIRECT.bits())
+            .open(path);
+        Self::opened(path, block_size, direct)
+    }
+
+    // Falls back on the page cache if opening `path` with O_DIRECT, which
+    // came to `direct`, was refused.
+    fn opened(path: &Path, block_size: u64, direct: io::Result<File>) -> io::Result<Self> {
+        let (file, bypass) = match direct {
+            Ok(file) => (file, CacheBypass::Direct),
+            Err(e) if e.raw_os_error() == Some(Errno::EINVAL as i32) => {
+                (File::open(path)?, CacheBypass::DroppedCache)
+            }
+            Err(e) => return Err(e),
+        };
+
+        Ok(Self {
+            file,
+            bypass,
+            block_size: block_size.max(1),
+            name: faults::node_name(path).map(String::from),
+        })
+    }
+
+    /// How reads get past the page cache.
+    pub fn bypass(&self) -> CacheBypass {
+        self.bypass
+    }
+
+    /// What offsets and lengths have to be multiples of.
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    /// How big the device is.
+    pub fn size(&self) -> io::Result<u64> {
+        (&self.file).seek(SeekFrom::End(0))
+    }
+
+    /// Fills the first `len` bytes of `buf` from `offset`, stopping early only
+    /// where the device ends. Returns how much was read.
+    pub fn read_at(&self, buf: &mut AlignedBuf, offset: u64, len: usize) -> io::Result<usize> {
+        if !offset.is_multiple_of(self.block_size) || !(len as u64).is_multiple_of(self.block_size)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "a read of {} bytes at {} isn't in whole {} byte blocks",
+                    len, offset, self.block_size
+                ),
+            ));
+        }
+
+        // Failing like a bad sector would.
+        if let Some(fault) = faults::check(
+            FaultPoint::BlockRead,
+            self.name.as_deref(),
+            Some((offset, len as u64)),
+        ) {
+            std::thread::sleep(fault.delay);
+            return Err(Errno::EIO.into());
+        }
+
+        if self.bypass == CacheBypass::DroppedCache {
+            posix_fadvise(
+                self.file.as_raw_fd(),
+                offset as i64,
+                len as i64,
+                PosixFadviseAdvice::POSIX_FADV_DONTNEED,
+            )?;
+        }
+
+        let buf = buf.get(len);
+        let mut filled = 0;
+        while filled < len {
+            match self
+                .file
+                .read_at(&mut buf[filled..], offset + filled as u64)
+            {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(filled)
+    }
+
+    /// Reads at least `want` bytes into `buf`. Asking for whole blocks keeps
+    /// O_DIRECT happy when the end of an image isn't on a sector boundary,
+    /// the read just comes up short there.
+    pub fn read_exact_at(&self, buf: &mut AlignedBuf, offset: u64, want: usize) -> io::Result<()> {
+        let len = (want as u64).next_multiple_of(self.block_size) as usize;
+        if self.read_at(buf, offset, len)? < want {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the device ended early",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Reads up to `len` bytes at `offset`, fewer if the device ends first.
+    /// Neither has to be aligned, the blocks around them are read and cut
+    /// down.
+    pub fn read_vec(&self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let start = offset - offset % self.block_size;
+        let end = offset
+            .checked_add(len as u64)
+            .and_then(|end| end.checked_next_multiple_of(self.block_size))
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "the read runs past 2^64 bytes")
+            })?;
+        let mut buf = AlignedBuf::new((end - start) as usize);
+        let read = self.read_at(&mut buf, start, (end - start) as usize)?;
+
+        let skip = (offset - start) as usize;
+        let data = buf.get(read);
+        Ok(data[skip.min(read)..(skip + len).min(read)].to_vec())
+    }
+}
+
+/// A buffer starting on an ALIGN boundary, carved out of a bigger Vec.
+pub struct AlignedBuf {
+    buf: Vec<u8>,
+    start: usize,
+}
+
+impl AlignedBuf {
+    /// Room for `len` bytes.
+    pub fn new(len: usize) -> Self {
+        let buf = vec![0; len + ALIGN];
+        let start = buf.as_ptr().align_offset(ALIGN);
+        Self { buf, start }
+    }
+
+    /// The first `len` bytes, `len` at most what it was made with.
+    pub fn get(&mut self, len: usize) -> &mut [u8] {
+        &mut self.buf[self.start..self.start + len]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf, process::Command};
+
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    // Never repeats on a block boundary, so a read from the wrong offset
+    // shows.
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    // A file to read, in a dir of its own that goes with it.
+    struct Image {
+        path: PathBuf,
+        _dir: TestDir,
+    }
+
+    impl Image {
+        fn new(name: &str, bytes: &[u8]) -> Self {
+            let dir = TestDir::new(&format!("block-io-{}", name));
+            let path = dir.join("image");
+            fs::write(&path, bytes).unwrap();
+            Self { path, _dir: dir }
+        }
+    }
+
+    // An image attached as a loop device with `sector_size` byte logical
+    // blocks. Takes root, so the tests using it are ignored unless asked
+    // for.
+    struct Loop(PathBuf);
+
+    impl Loop {
+        fn attach(image: &Image, sector_size: u64) -> Self {
+            let output = Command::new("losetup")
+                .args(["--find", "--show", "--sector-size"])
+                .arg(sector_size.to_string())
+                .arg(&image.path)
+                .output()
+                .expect("losetup to run");
+            assert!(
+                output.status.success(),
+                "No loop device: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            let node = String::from_utf8_lossy(&output.stdout).trim().to_string();
+            Self(PathBuf::from(node))
+        }
+    }
+
+    impl Drop for Loop {
+        fn drop(&mut self) {
+            let _ = Command::new("losetup").arg("-d").arg(&self.0).status();
+        }
+    }
+
+    fn refused() -> io::Result<File> {
+        Err(Errno::EINVAL.into())
+    }
+
+    #[test]
+    fn reads_have_to_be_in_whole_blocks() {
+        let image = Image::new("whole-blocks", &pattern(4 * 4096));
+        let io = BlockIo::open(&image.path, 4096).unwrap();
+        let mut buf = AlignedBuf::new(8192);
+
+        for (offset, len) in [(512, 4096), (0, 512), (4096, 6144), (1, 1)] {
+            let e = io.read_at(&mut buf, offset, len).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput, "{} {}", offset, len);
+        }
+        assert_eq!(io.read_at(&mut buf, 4096, 8192).unwrap(), 8192);
+        assert_eq!(buf.get(8192), &pattern(3 * 4096)[4096..]);
+    }
+
+    #[test]
+    fn unaligned_reads_are_cut_out_of_whole_blocks() {
+        let data = pattern(3 * 4096 + 1000);
+        let image = Image::new("unaligned", &data);
+        let io = BlockIo::open(&image.path, 4096).unwrap();
+        assert_eq!(io.size().unwrap(), data.len() as u64);
+
+        for (offset, len) in [(0, 1), (100, 5000), (4095, 2), (8192, 4096), (12000, 288)] {
+            assert_eq!(
+                io.read_vec(offset, len).unwrap(),
+                &data[offset as usize..offset as usize + len],
+                "{} {}",
+                offset,
+                len
+            );
+        }
+
+        // Short where the image ends, off a block boundary, and nothing
+        // past it.
+        assert_eq!(io.read_vec(12000, 4096).unwrap(), &data[12000..]);
+        assert_eq!(io.read_vec(20000, 10).unwrap(), b"");
+        assert_eq!(
+            io.read_vec(u64::MAX - 10, 100).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn reading_exactly_fails_where_the_device_ends() {
+        let data = pattern(4096 + 512);
+        let image = Image::new("exact", &data);
+        let io = BlockIo::open(&image.path, 4096).unwrap();
+        let mut buf = AlignedBuf::new(8192);
+
+        io.read_exact_at(&mut buf, 4096, 512).unwrap();
+        assert_eq!(buf.get(512), &data[4096..]);
+        assert_eq!(
+            io.read_exact_at(&mut buf, 4096, 1024).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn o_direct_refused_falls_back_on_dropping_the_cache() {
+        let data = pattern(4 * 4096);
+        let image = Image::new("fallback", &data);
+        let io = BlockIo::opened(&image.path, 512, refused()).unwrap();
+        assert_eq!(io.bypass(), CacheBypass::DroppedCache);
+        assert_eq!(io.read_vec(1000, 9000).unwrap(), &data[1000..10000]);
+
+        // The same rules as with O_DIRECT, so nothing only works here.
+        let mut buf = AlignedBuf::new(4096);
+        assert_eq!(
+            io.read_at(&mut buf, 100, 512).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn only_o_direct_being_refused_is_fallen_back_on() {
+        let image = Image::new("denied", &pattern(4096));
+        let e = BlockIo::opened(&image.path, 512, Err(Errno::EACCES.into()))
+            .err()
+            .unwrap();
+        assert_eq!(e.raw_os_error(), Some(Errno::EACCES as i32));
+
+        // Nor is there anything to fall back on without the file.
+        let missing = image.path.with_extension("missing");
+        let e = BlockIo::opened(&missing, 512, refused()).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    #[ignore = "needs a loop device (root)"]
+    fn a_4kn_device_takes_only_whole_4k_blocks() {
+        let data = pattern(64 * 4096);
+        let image = Image::new("4kn", &data);
+        let device = Loop::attach(&image, 4096);
+
+        let io = BlockIo::open(&device.0, 4096).unwrap();
+        assert_eq!(io.bypass(), CacheBypass::Direct);
+        assert_eq!(io.size().unwrap(), data.len() as u64);
+        assert_eq!(io.read_vec(1000, 10000).unwrap(), &data[1000..11000]);
+        let mut buf = AlignedBuf::new(8192);
+        io.read_exact_at(&mut buf, 60 * 4096, 8192).unwrap();
+        assert_eq!(buf.get(8192), &data[60 * 4096..62 * 4096]);
+
+        // Reading it as if it had 512 byte sectors is what the block size
+        // is there to stop. The device refuses it itself.
+        let io = BlockIo::open(&device.0, 512).unwrap();
+        let e = io.read_at(&mut buf, 512, 512).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(Errno::EINVAL as i32));
+    }
+
+    #[test]
+    #[ignore = "needs a loop device (root)"]
+    fn a_4kn_device_reads_the_same_without_o_direct() {
+        let data = pattern(16 * 4096);
+        let image = Image::new("4kn-fallback", &data);
+        let device = Loop::attach(&image, 4096);
+
+        let io = BlockIo::opened(&device.0, 4096, refused()).unwrap();
+        assert_eq!(io.bypass(), CacheBypass::DroppedCache);
+        assert_eq!(io.read_vec(5000, 30000).unwrap(), &data[5000..35000]);
+        assert_eq!(io.read_vec(15 * 4096, 8192).unwrap(), &data[15 * 4096..]);
+    }
+
+    #[test]
+    fn tasks_read_disks_only_through_here() {
+        // What's under test in each is free to make its own images.
+        for (module, source) in [
+            ("blink", include_str!("blink.rs")),
+            ("image", include_str!("image.rs")),
+            ("probe", include_str!("probe.rs")),
+            ("verify", include_str!("verify.rs")),
+        ] {
+            let code = source.split("#[cfg(test)]").next().unwrap();
+            for raw in ["File::open", "OpenOptions", "fs::read"] {
+                assert!(!code.contains(raw), "{} uses {}", module, raw);
+            }
+        }
+    }
+}
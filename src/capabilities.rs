@@ -0,0 +1,1098 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::{self, File, OpenOptions},
+    io,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use nix::{
+    errno::Errno,
+    unistd::{access, AccessFlags},
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use crate::{
+    device_policy::DeviceIdentity,
+    error::SmartError,
+    faults::{self, FaultPoint},
+    identify_queue::{IdentifyQueue, Slot},
+    usage,
+};
+
+// The critical warning bit for the media being read-only.
+const NVME_READ_ONLY: u64 = 1 << 3;
+
+/// Whether a device can do something, and why not if it can't right now.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Capability {
+    /// The device can do it.
+    Yes,
+    /// The device can't, or says it can't.
+    No,
+    /// The device supports it, but something stands in the way.
+    Blocked {
+        /// What's in the way.
+        reason: String,
+    },
+    /// Nothing we could ask the device told us.
+    Unknown,
+}
+
+impl Capability {
+    fn blocked(reason: &str) -> Self {
+        Capability::Blocked {
+            reason: reason.to_string(),
+        }
+    }
+
+    fn from_support(supported: Option<bool>) -> Self {
+        match supported {
+            Some(true) => Capability::Yes,
+            Some(false) => Capability::No,
+            None => Capability::Unknown,
+        }
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Capability::Yes => write!(f, "yes"),
+            Capability::No => write!(f, "no"),
+            Capability::Blocked { reason } => write!(f, "blocked, {}", reason),
+            Capability::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+/// What told us a device can't be written to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReadOnly {
+    /// The kernel's ro flag: a write-protect switch, a USB stick asking for
+    /// it, `blockdev --setro`, or the driver giving up on writing.
+    Kernel,
+    /// The NVMe critical warning bit for the media having been put in
+    /// read-only mode, usually after too many errors.
+    NvmeMedia,
+    /// Nothing said so, but opening it for writing was refused.
+    OpenRefused,
+}
+
+impl fmt::Display for ReadOnly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReadOnly::Kernel => write!(f, "the kernel has it read-only"),
+            ReadOnly::NvmeMedia => write!(f, "the drive put its media in read-only mode"),
+            ReadOnly::OpenRefused => write!(f, "opening it for writing was refused"),
+        }
+    }
+}
+
+/// What can be done to a device, worked out once when it's found so anything
+/// about to act on it can say up front why it can't.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceCapabilities {
+    /// Why the device can't be written to, if it can't. Left out of what
+    /// was recorded before this was checked.
+    #[serde(default)]
+    pub read_only: Option<ReadOnly>,
+    /// Writing to it at all.
+    pub write: Capability,
+    /// ATA SECURITY ERASE UNIT.
+    pub ata_secure_erase: Capability,
+    /// TRIM, UNMAP or Deallocate.
+    pub discard: Capability,
+    /// WRITE ZEROES, or a discard the device promises reads back as zeroes.
+    /// Plenty of drives discard without it, and what reads back after that
+    /// can be anything.
+    pub write_zeroes: Capability,
+    /// SMART self-tests.
+    pub self_test: Capability,
+}
+
+impl fmt::Display for DeviceCapabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "write {}; ATA secure erase {}; discard {}; write zeroes {}; self-test {}",
+            self.write, self.ata_secure_erase, self.discard, self.write_zeroes, self.self_test
+        )
+    }
+}
+
+/// The ATA security feature set, as smartctl reports it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtaSecurity {
+    /// An ATA drive without it, or not an ATA drive at all.
+    Unsupported,
+    /// It has the feature set, and this is what state it's in.
+    Supported {
+        /// A user password is set.
+        enabled: bool,
+        /// Security commands are refused until the next power cycle, most
+        /// BIOSes freeze drives on boot.
+        frozen: bool,
+    },
+}
+
+/// Everything capabilities are worked out from. None is for what couldn't
+/// be found out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProbeInputs {
+    /// The kernel's ro flag.
+    pub read_only: Option<bool>,
+    /// Bit 3 of the NVMe critical warning.
+    pub nvme_read_only: Option<bool>,
+    /// Whether opening the device for writing was refused for being
+    /// read-only, see write_open_refused.
+    pub write_open_refused: Option<bool>,
+    /// The kernel's discard_max_bytes, 0 for no discard.
+    pub discard_max_bytes: Option<u64>,
+    /// The kernel's write_zeroes_max_bytes, 0 for no WRITE ZEROES.
+    pub write_zeroes_max_bytes: Option<u64>,
+    /// What smartctl said about the ATA security feature set.
+    pub ata_security: Option<AtaSecurity>,
+    /// Whether smartctl said the device takes self-tests.
+    pub self_tests_supported: Option<bool>,
+}
+
+/// The first thing that says the device is read-only, the kernel's flag
+/// being the one most likely to change back.
+pub fn read_only(inputs: &ProbeInputs) -> Option<ReadOnly> {
+    if inputs.read_only == Some(true) {
+        Some(ReadOnly::Kernel)
+    } else if inputs.nvme_read_only == Some(true) {
+        Some(ReadOnly::NvmeMedia)
+    } else if inputs.write_open_refused == Some(true) {
+        Some(ReadOnly::OpenRefused)
+    } else {
+        None
+    }
+}
+
+/// What a device with these `inputs` can do.
+pub fn capabilities(inputs: &ProbeInputs) -> DeviceCapabilities {
+    let read_only = read_only(inputs);
+    let blocked = |read_only: ReadOnly| {
+        Capability::blocked(&format!("the device is read-only, {}", read_only))
+    };
+    // Everything but the self-test writes, so the device being read-only
+    // blocks whatever it would otherwise support.
+    let writing = |capability: Capability| match (capability, read_only) {
+        (Capability::Yes, Some(read_only)) => blocked(read_only),
+        (capability, _) => capability,
+    };
+
+    let ata_secure_erase = match inputs.ata_security {
+        None => Capability::Unknown,
+        Some(AtaSecurity::Unsupported) => Capability::No,
+        // Frozen first, unfreezing it is the step that has to come before
+        // anything about passwords.
+        Some(AtaSecurity::Supported { frozen: true, .. }) => {
+            Capability::blocked("the drive is frozen, suspending and resuming usually unfreezes it")
+        }
+        Some(AtaSecurity::Supported { enabled: true, .. }) => {
+            Capability::blocked("the drive has a user password set")
+        }
+        Some(AtaSecurity::Supported { .. }) => Capability::Yes,
+    };
+
+    let nonzero = |bytes: Option<u64>| bytes.map(|bytes| bytes > 0);
+    DeviceCapabilities {
+        read_only,
+        write: match read_only {
+            Some(read_only) => blocked(read_only),
+            None if inputs.read_only == Some(false) => Capability::Yes,
+            None => Capability::Unknown,
+        },
+        ata_secure_erase: writing(ata_secure_erase),
+        discard: writing(Capability::from_support(nonzero(inputs.discard_max_bytes))),
+        write_zeroes: writing(Capability::from_support(nonzero(
+            inputs.write_zeroes_max_bytes,
+        ))),
+        self_test: Capability::from_support(inputs.self_tests_supported),
+    }
+}
+
+/// Just the kernel's ro flag, cheap enough to check on every present device
+/// every minute.
+pub fn sysfs_read_only(sys: &Path, name: &str) -> Option<bool> {
+    let ro = fs::read_to_string(sys.join("class/block").join(name).join("ro")).ok()?;
+    ro.trim().parse::<u8>().ok().map(|ro| ro != 0)
+}
+
+/// Opens the device for writing and closes it straight away, writing
+/// nothing, to catch what the ro flag doesn't. None where the answer
+/// wouldn't mean anything: without permission to write to the node, the
+/// open is refused either way.
+///
+/// udev watches disks for being closed after writing, so this gets a
+/// change event from udev and the partitions probed again.
+pub fn write_open_refused(node: &Path) -> Option<bool> {
+    access(node, AccessFlags::W_OK).ok()?;
+    opened_for_writing(node, OpenOptions::new().write(true).open(node))
+}
+
+// What opening `node` for writing, which came to `opened`, says about it.
+fn opened_for_writing(node: &Path, opened: io::Result<File>) -> Option<bool> {
+    match opened {
+        Ok(_) => Some(false),
+        Err(e) => match e.raw_os_error().map(Errno::from_i32) {
+            Some(Errno::EROFS | Errno::EACCES) => Some(true),
+            _ => {
+                debug!("Can't tell if {} is read-only: {}", node.display(), e);
+                None
+            }
+        },
+    }
+}
+
+/// The parts of the inputs the kernel knows about.
+pub fn sysfs_inputs(sys: &Path, name: &str) -> ProbeInputs {
+    let dir = sys.join("class/block").join(name);
+    let read = |file: &str| -> Option<u64> {
+        fs::read_to_string(dir.join(file)).ok()?.trim().parse().ok()
+    };
+
+    ProbeInputs {
+        read_only: read("ro").map(|ro| ro != 0),
+        discard_max_bytes: read("queue/discard_max_bytes"),
+        write_zeroes_max_bytes: read("queue/write_zeroes_max_bytes"),
+        ..Default::default()
+    }
+}
+
+/// Fills in what smartctl -i -c -H knows: the ATA security state, whether
+/// the drive runs self-tests, and whether an NVMe drive went read-only.
+pub fn smartctl_inputs(
+    smartctl: &Path,
+    node: &Path,
+    inputs: &mut ProbeInputs,
+) -> Result<(), SmartError> {
+    let output = usage::SMARTCTL_WAIT
+        .time(|| {
+            faults::fail_blocking(FaultPoint::SmartctlExec, faults::node_name(node))?;
+            Command::new(smartctl)
+                .args(["-i", "-c", "-H", "-j"])
+                .arg(node)
+                .output()
+        })
+        .map_err(|error| SmartError::Run {
+            smartctl: smartctl.to_path_buf(),
+            error,
+        })?;
+    // Same as for the capacity, the exit status is a bitmask of complaints
+    // and the JSON says what it could find out.
+    let json: serde_json::Value = usage::SMARTCTL_PARSE
+        .time(|| serde_json::from_slice(&output.stdout))
+        .map_err(|error| SmartError::NotJson {
+            options: "-i -c",
+            node: node.to_path_buf(),
+            error,
+        })?;
+
+    let ata = matches!(json["device"]["type"].as_str(), Some("ata" | "sat"));
+    let security = &json["ata_security"];
+    inputs.ata_security = if security.is_object() {
+        Some(AtaSecurity::Supported {
+            enabled: security["enabled"].as_bool().unwrap_or(false),
+            frozen: security["frozen"].as_bool().unwrap_or(false),
+        })
+    } else if json["device"]["type"].is_string() {
+        // smartctl leaves it out for drives without the feature set.
+        Some(AtaSecurity::Unsupported)
+    } else {
+        None
+    };
+
+    if ata {
+        inputs.self_tests_supported =
+            json["ata_smart_data"]["capabilities"]["self_tests_supported"].as_bool();
+    } else if json["nvme_self_test_log"].is_object() {
+        inputs.self_tests_supported = Some(true);
+    }
+
+    // -H puts the critical warning under smart_status, -A would have it in
+    // the health log.
+    let critical_warning = json["smart_status"]["nvme"]["value"]
+        .as_u64()
+        .or_else(|| json["nvme_smart_health_information_log"]["critical_warning"].as_u64());
+    if let Some(critical_warning) = critical_warning {
+        inputs.nvme_read_only = Some(critical_warning & NVME_READ_ONLY != 0);
+    }
+
+    Ok(())
+}
+
+/// Works out new devices' capabilities off the event loop, results come back
+/// through next().
+pub struct CapabilityProber {
+    smartctl: PathBuf,
+    queue: IdentifyQueue,
+    sender: mpsc::Sender<(DeviceIdentity, DeviceCapabilities)>,
+    receiver: mpsc::Receiver<(DeviceIdentity, DeviceCapabilities)>,
+}
+
+impl CapabilityProber {
+    /// Looks smartctl up in PATH when `smartctl` isn't given.
+    pub fn new(smartctl: Option<&Path>, queue: IdentifyQueue) -> Self {
+        let (sender, receiver) = mpsc::channel(64);
+        Self {
+            smartctl: smartctl
+                .unwrap_or_else(|| Path::new("smartctl"))
+                .to_path_buf(),
+            queue,
+            sender,
+            receiver,
+        }
+    }
+
+    /// Protected devices aren't opened for writing, not even to find out
+    /// whether they could be.
+    pub fn probe(&self, identity: &DeviceIdentity, protected: bool) {
+        // Simulated drives have nothing to ask.
+        let node = match identity.paths.first() {
+            Some(node) if node.exists() => node.clone(),
+            _ => return,
+        };
+
+        let identity = identity.clone();
+        let smartctl = self.smartctl.clone();
+        let sender = self.sender.clone();
+        let queue = self.queue.clone();
+        tokio::spawn(async move {
+            let _permit = queue.acquire(Slot::lookup(&identity.name)).await;
+            let probed = tokio::task::spawn_blocking(move || {
+                let capabilities = probe_capabilities(&identity, &smartctl, &node, protected);
+                (identity, capabilities)
+            });
+            if let Ok(probed) = probed.await {
+                // Only fails once we're shutting down.
+                let _ = sender.send(probed).await;
+            }
+        });
+    }
+
+    /// The next device whose capabilities were worked out, in the order
+    /// they finish.
+    pub async fn next(&mut self) -> Option<(DeviceIdentity, DeviceCapabilities)> {
+        self.receiver.recv().await
+    }
+}
+
+// Everything there is to ask, on a blocking thread.
+fn probe_capabilities(
+    identity: &DeviceIdentity,
+    smartctl: &Path,
+    node: &Path,
+    protected: bool,
+) -> DeviceCapabilities {
+    let mut inputs = sysfs_inputs(Path::new("/sys"), &identity.name);
+    // What smartctl couldn't say stays unknown.
+    if let Err(e) = smartctl_inputs(smartctl, node, &mut inputs) {
+        debug!("Not asking smartctl about {}: {:#}", node.display(), e);
+    }
+    if !protected && read_only(&inputs).is_none() {
+        inputs.write_open_refused = write_open_refused(node);
+    }
+    capabilities(&inputs)
+}
+
+/// What changed about a present device's ro flag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadOnlyChange {
+    /// The ro flag was set.
+    BecameReadOnly,
+    /// Only for devices the ro flag was what made them read-only, nothing
+    /// else we check can be cleared without the device going away.
+    BecameWritable,
+}
+
+/// Keeps an eye on whether present devices went read-only since their
+/// capabilities were worked out, like a drive the kernel flips to read-only
+/// after write errors. Only the ro flag is checked again, the rest would
+/// mean asking the drive.
+#[derive(Debug, Default)]
+pub struct ReadOnlyWatch {
+    devices: HashMap<String, Option<ReadOnly>>,
+}
+
+impl ReadOnlyWatch {
+    /// Watches nothing yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// From fresh capabilities. Returns what was known before, None for a
+    /// device that's new to the watch.
+    pub fn set(&mut self, name: &str, read_only: Option<ReadOnly>) -> Option<Option<ReadOnly>> {
+        self.devices.insert(name.to_string(), read_only)
+    }
+
+    /// Stops watching a device that went away.
+    pub fn forget(&mut self, name: &str) {
+        self.devices.remove(name);
+    }
+
+    /// Kernel names of every device being watched.
+    pub fn names(&self) -> Vec<String> {
+        self.devices.keys().cloned().collect()
+    }
+
+    /// Reads the ro flag of a device with capabilities, and says if it
+    /// changed what we know.
+    pub fn check(&mut self, sys: &Path, name: &str) -> Option<ReadOnlyChange> {
+        let known = self.devices.get_mut(name)?;
+        match (sysfs_read_only(sys, name)?, *known) {
+            (true, None) => {
+                *known = Some(ReadOnly::Kernel);
+                Some(ReadOnlyChange::BecameReadOnly)
+            }
+            (false, Some(ReadOnly::Kernel)) => {
+                *known = None;
+                Some(ReadOnlyChange::BecameWritable)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::unix::fs::PermissionsExt, time::Duration};
+
+    use super::*;
+    use crate::{config::DevicesConfig, device_policy::DevicePolicy, test_dir::TestDir};
+
+    const YES: Capability = Capability::Yes;
+    const NO: Capability = Capability::No;
+    const UNKNOWN: Capability = Capability::Unknown;
+
+    fn blocked(reason: &str) -> Capability {
+        Capability::blocked(reason)
+    }
+
+    const FROZEN: &str = "the drive is frozen, suspending and resuming usually unfreezes it";
+    const PASSWORD: &str = "the drive has a user password set";
+    const KERNEL_RO: &str = "the device is read-only, the kernel has it read-only";
+
+    fn supported(enabled: bool, frozen: bool) -> Option<AtaSecurity> {
+        Some(AtaSecurity::Supported { enabled, frozen })
+    }
+
+    // A writable SATA drive that can do everything.
+    fn sata() -> ProbeInputs {
+        ProbeInputs {
+            read_only: Some(false),
+            nvme_read_only: None,
+            write_open_refused: Some(false),
+            discard_max_bytes: Some(2147450880),
+            write_zeroes_max_bytes: Some(33553920),
+            ata_security: supported(false, false),
+            self_tests_supported: Some(true),
+        }
+    }
+
+    #[test]
+    fn awkward_combinations() {
+        let cases = [
+            // write, secure erase, discard, write zeroes, self-test
+            ("everything", sata(), [YES, YES, YES, YES, YES]),
+            (
+                "frozen but supported",
+                ProbeInputs {
+                    ata_security: supported(false, true),
+                    ..sata()
+                },
+                [YES, blocked(FROZEN), YES, YES, YES],
+            ),
+            (
+                "frozen with a password, frozen is the first thing to fix",
+                ProbeInputs {
+                    ata_security: supported(true, true),
+                    ..sata()
+                },
+                [YES, blocked(FROZEN), YES, YES, YES],
+            ),
+            (
+                "a password set",
+                ProbeInputs {
+                    ata_security: supported(true, false),
+                    ..sata()
+                },
+                [YES, blocked(PASSWORD), YES, YES, YES],
+            ),
+            (
+                "discard without deterministic zeroes",
+                ProbeInputs {
+                    write_zeroes_max_bytes: Some(0),
+                    ..sata()
+                },
+                [YES, YES, YES, NO, YES],
+            ),
+            (
+                "a spinning drive, no discard at all",
+                ProbeInputs {
+                    discard_max_bytes: Some(0),
+                    write_zeroes_max_bytes: Some(0),
+                    ..sata()
+                },
+                [YES, YES, NO, NO, YES],
+            ),
+            (
+                "read-only, the self-test only reads",
+                ProbeInputs {
+                    read_only: Some(true),
+                    ..sata()
+                },
+                [
+                    blocked(KERNEL_RO),
+                    blocked(KERNEL_RO),
+                    blocked(KERNEL_RO),
+                    blocked(KERNEL_RO),
+                    YES,
+                ],
+            ),
+            (
+                "read-only and frozen says frozen",
+                ProbeInputs {
+                    read_only: Some(true),
+                    ata_security: supported(false, true),
+                    discard_max_bytes: Some(0),
+                    ..sata()
+                },
+                [
+                    blocked(KERNEL_RO),
+                    blocked(FROZEN),
+                    NO,
+                    blocked(KERNEL_RO),
+                    YES,
+                ],
+            ),
+            (
+                "an NVMe drive whose media went read-only",
+                ProbeInputs {
+                    nvme_read_only: Some(true),
+                    ata_security: Some(AtaSecurity::Unsupported),
+                    ..sata()
+                },
+                [
+                    blocked("the device is read-only, the drive put its media in read-only mode"),
+                    NO,
+                    blocked("the device is read-only, the drive put its media in read-only mode"),
+                    blocked("the device is read-only, the drive put its media in read-only mode"),
+                    YES,
+                ],
+            ),
+            (
+                "nothing known",
+                ProbeInputs::default(),
+                [UNKNOWN, UNKNOWN, UNKNOWN, UNKNOWN, UNKNOWN],
+            ),
+        ];
+
+        for (name, inputs, [write, ata_secure_erase, discard, write_zeroes, self_test]) in cases {
+            let capabilities = capabilities(&inputs);
+            assert_eq!(
+                [
+                    capabilities.write,
+                    capabilities.ata_secure_erase,
+                    capabilities.discard,
+                    capabilities.write_zeroes,
+                    capabilities.self_test,
+                ],
+                [write, ata_secure_erase, discard, write_zeroes, self_test],
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn every_combination_of_inputs() {
+        let flags = [None, Some(false), Some(true)];
+        let sizes = [None, Some(0), Some(4096)];
+        let securities = [
+            None,
+            Some(AtaSecurity::Unsupported),
+            supported(false, false),
+            supported(true, false),
+            supported(false, true),
+            supported(true, true),
+        ];
+
+        let mut checked = 0;
+        for read_only_flag in flags {
+            for nvme_read_only in flags {
+                for write_open_refused in flags {
+                    for discard_max_bytes in sizes {
+                        for write_zeroes_max_bytes in sizes {
+                            for ata_security in securities {
+                                for self_tests_supported in flags {
+                                    let inputs = ProbeInputs {
+                                        read_only: read_only_flag,
+                                        nvme_read_only,
+                                        write_open_refused,
+                                        discard_max_bytes,
+                                        write_zeroes_max_bytes,
+                                        ata_security,
+                                        self_tests_supported,
+                                    };
+                                    check(&inputs, &capabilities(&inputs));
+                                    checked += 1;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        assert_eq!(checked, 3 * 3 * 3 * 3 * 3 * 6 * 3);
+    }
+
+    // What has to hold whatever the inputs.
+    fn check(inputs: &ProbeInputs, capabilities: &DeviceCapabilities) {
+        let expected_read_only = if inputs.read_only == Some(true) {
+            Some(ReadOnly::Kernel)
+        } else if inputs.nvme_read_only == Some(true) {
+            Some(ReadOnly::NvmeMedia)
+        } else if inputs.write_open_refused == Some(true) {
+            Some(ReadOnly::OpenRefused)
+        } else {
+            None
+        };
+        assert_eq!(capabilities.read_only, expected_read_only, "{:?}", inputs);
+
+        let support = |bytes: Option<u64>| Capability::from_support(bytes.map(|bytes| bytes > 0));
+        let security = match inputs.ata_security {
+            None => UNKNOWN,
+            Some(AtaSecurity::Unsupported) => NO,
+            Some(AtaSecurity::Supported { frozen: true, .. }) => blocked(FROZEN),
+            Some(AtaSecurity::Supported { enabled: true, .. }) => blocked(PASSWORD),
+            Some(AtaSecurity::Supported { .. }) => YES,
+        };
+        let writes = [
+            (&capabilities.ata_secure_erase, security),
+            (&capabilities.discard, support(inputs.discard_max_bytes)),
+            (
+                &capabilities.write_zeroes,
+                support(inputs.write_zeroes_max_bytes),
+            ),
+        ];
+
+        match expected_read_only {
+            Some(read_only) => {
+                let reason = blocked(&format!("the device is read-only, {}", read_only));
+                assert_eq!(capabilities.write, reason, "{:?}", inputs);
+                // Whatever would have been possible is blocked, the rest
+                // says why it isn't anyway.
+                for (capability, otherwise) in writes {
+                    let expected = if otherwise == YES {
+                        reason.clone()
+                    } else {
+                        otherwise
+                    };
+                    assert_eq!(*capability, expected, "{:?}", inputs);
+                }
+            }
+            None => {
+                let write = if inputs.read_only == Some(false) {
+                    YES
+                } else {
+                    UNKNOWN
+                };
+                assert_eq!(capabilities.write, write, "{:?}", inputs);
+                for (capability, otherwise) in writes {
+                    assert_eq!(*capability, otherwise, "{:?}", inputs);
+                }
+            }
+        }
+
+        assert_eq!(
+            capabilities.self_test,
+            Capability::from_support(inputs.self_tests_supported),
+            "{:?}",
+            inputs
+        );
+    }
+
+    #[test]
+    fn capabilities_in_a_line() {
+        let capabilities = capabilities(&ProbeInputs {
+            ata_security: supported(false, true),
+            write_zeroes_max_bytes: Some(0),
+            self_tests_supported: None,
+            ..sata()
+        });
+        assert_eq!(
+            capabilities.to_string(),
+            format!(
+                "write yes; ATA secure erase blocked, {}; discard yes; write zeroes no; \
+                 self-test unknown",
+                FROZEN
+            )
+        );
+    }
+
+    struct TempDir(TestDir);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            Self(TestDir::new(&format!("capabilities-{}", name)))
+        }
+
+        fn write(&self, file: &str, contents: &str) -> PathBuf {
+            let path = self.0.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, contents).unwrap();
+            path
+        }
+
+        // Prints `json` and exits with `status`, whatever it's asked.
+        fn smartctl(&self, json: &str, status: u8) -> PathBuf {
+            let output = self.write("smartctl.json", json);
+            let smartctl = self.write(
+                "smartctl",
+                &format!("#!/bin/sh\ncat {}\nexit {}\n", output.display(), status),
+            );
+            fs::set_permissions(&smartctl, fs::Permissions::from_mode(0o755)).unwrap();
+            smartctl
+        }
+    }
+
+    // Trimmed from what smartctl 7.3 prints with `-i -c -H -j`.
+    const SATA_FROZEN: &str = r#"{
+      "device": { "name": "/dev/sda", "type": "sat", "protocol": "ATA" },
+      "model_name": "Samsung SSD 860 EVO 500GB",
+      "ata_security": { "state": 41, "string": "Disabled, frozen [SEC2]", "enabled": false, "frozen": true },
+      "smart_status": { "passed": true },
+      "ata_smart_data": { "capabilities": { "self_tests_supported": true, "conveyance_self_test_supported": false } }
+    }"#;
+    const SATA_NO_SECURITY: &str = r#"{
+      "device": { "name": "/dev/sdb", "type": "sat", "protocol": "ATA" },
+      "smart_status": { "passed": true },
+      "ata_smart_data": { "capabilities": { "self_tests_supported": false } }
+    }"#;
+    const NVME_READ_ONLY_MEDIA: &str = r#"{
+      "device": { "name": "/dev/nvme0", "type": "nvme", "protocol": "NVMe" },
+      "model_name": "Samsung SSD 970 EVO Plus 1TB",
+      "smart_status": { "passed": false, "nvme": { "value": 12 } },
+      "nvme_self_test_log": { "current_self_test_operation": { "value": 0 } }
+    }"#;
+    const NVME_HEALTH_LOG: &str = r#"{
+      "device": { "name": "/dev/nvme1", "type": "nvme", "protocol": "NVMe" },
+      "nvme_smart_health_information_log": { "critical_warning": 0, "temperature": 38 }
+    }"#;
+    const SAS: &str = r#"{
+      "device": { "name": "/dev/sdc", "type": "scsi", "protocol": "SCSI" },
+      "smart_status": { "passed": true }
+    }"#;
+    const NOTHING: &str = r#"{
+      "smartctl": { "exit_status": 2, "messages": [
+        { "string": "/dev/sdd: Unable to detect device type", "severity": "error" }
+      ] }
+    }"#;
+
+    #[test]
+    fn inputs_from_smartctl() {
+        let cases = [
+            (
+                "sata-frozen",
+                SATA_FROZEN,
+                supported(false, true),
+                Some(true),
+                None,
+            ),
+            (
+                "sata-no-security",
+                SATA_NO_SECURITY,
+                Some(AtaSecurity::Unsupported),
+                Some(false),
+                None,
+            ),
+            // 12 is the read-only and the volatile backup bits.
+            (
+                "nvme-read-only",
+                NVME_READ_ONLY_MEDIA,
+                Some(AtaSecurity::Unsupported),
+                Some(true),
+                Some(true),
+            ),
+            (
+                "nvme-health-log",
+                NVME_HEALTH_LOG,
+                Some(AtaSecurity::Unsupported),
+                None,
+                Some(false),
+            ),
+            ("sas", SAS, Some(AtaSecurity::Unsupported), None, None),
+            ("nothing", NOTHING, None, None, None),
+        ];
+
+        for (name, json, ata_security, self_tests_supported, nvme_read_only) in cases {
+            let dir = TempDir::new(name);
+            let smartctl = dir.smartctl(json, 4);
+            let mut inputs = ProbeInputs::default();
+            smartctl_inputs(&smartctl, Path::new("/dev/sdx"), &mut inputs).unwrap();
+
+            assert_eq!(inputs.ata_security, ata_security, "{}", name);
+            assert_eq!(
+                inputs.self_tests_supported, self_tests_supported,
+                "{}",
+                name
+            );
+            assert_eq!(inputs.nvme_read_only, nvme_read_only, "{}", name);
+            // What only the kernel knows is left alone.
+            assert_eq!(inputs.read_only, None, "{}", name);
+        }
+
+        let dir = TempDir::new("not-json");
+        let smartctl = dir.smartctl("Smartctl open device: /dev/sdx failed\n", 2);
+        let error = smartctl_inputs(
+            &smartctl,
+            Path::new("/dev/sdx"),
+            &mut ProbeInputs::default(),
+        )
+        .unwrap_err();
+        assert!(matches!(error, SmartError::NotJson { .. }), "{}", error);
+        assert!(error
+            .to_string()
+            .starts_with("smartctl -i -c /dev/sdx didn't print JSON: "));
+    }
+
+    #[test]
+    fn inputs_from_sysfs() {
+        let sys = TempDir::new("sysfs");
+        for (file, contents) in [
+            ("class/block/sda/ro", "0\n"),
+            ("class/block/sda/queue/discard_max_bytes", "2147450880\n"),
+            ("class/block/sda/queue/write_zeroes_max_bytes", "0\n"),
+            ("class/block/sdb/ro", "1\n"),
+            ("class/block/sdb/queue/discard_max_bytes", "junk\n"),
+            ("class/block/sdc/ro", ""),
+        ] {
+            sys.write(file, contents);
+        }
+
+        assert_eq!(
+            sysfs_inputs(&sys.0, "sda"),
+            ProbeInputs {
+                read_only: Some(false),
+                discard_max_bytes: Some(2147450880),
+                write_zeroes_max_bytes: Some(0),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            sysfs_inputs(&sys.0, "sdb"),
+            ProbeInputs {
+                read_only: Some(true),
+                ..Default::default()
+            }
+        );
+        assert_eq!(sysfs_inputs(&sys.0, "sdc"), ProbeInputs::default());
+        assert_eq!(sysfs_inputs(&sys.0, "sdz"), ProbeInputs::default());
+    }
+
+    #[test]
+    fn read_only_from_each_source() {
+        let inputs = |read_only, nvme_read_only, write_open_refused| ProbeInputs {
+            read_only,
+            nvme_read_only,
+            write_open_refused,
+            ..Default::default()
+        };
+        let cases = [
+            (inputs(Some(true), None, None), Some(ReadOnly::Kernel)),
+            (
+                inputs(Some(false), Some(true), None),
+                Some(ReadOnly::NvmeMedia),
+            ),
+            (
+                inputs(Some(false), Some(false), Some(true)),
+                Some(ReadOnly::OpenRefused),
+            ),
+            // The kernel's flag says the most, it's what gets cleared.
+            (
+                inputs(Some(true), Some(true), Some(true)),
+                Some(ReadOnly::Kernel),
+            ),
+            (
+                inputs(None, Some(true), Some(true)),
+                Some(ReadOnly::NvmeMedia),
+            ),
+            (inputs(Some(false), Some(false), Some(false)), None),
+            (inputs(None, None, None), None),
+        ];
+        for (inputs, expected) in cases {
+            assert_eq!(read_only(&inputs), expected, "{:?}", inputs);
+            let capabilities = capabilities(&inputs);
+            assert_eq!(capabilities.read_only, expected);
+            if let Some(read_only) = expected {
+                assert_eq!(
+                    capabilities.write,
+                    Capability::blocked(&format!("the device is read-only, {}", read_only))
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn the_kernels_ro_flag() {
+        let sys = TempDir::new("ro");
+        for (name, ro) in [
+            ("sda", "0\n"),
+            ("sdb", "1\n"),
+            ("sdc", ""),
+            ("sdd", "yes\n"),
+        ] {
+            sys.write(&format!("class/block/{}/ro", name), ro);
+        }
+
+        for (name, expected) in [
+            ("sda", Some(false)),
+            ("sdb", Some(true)),
+            ("sdc", None),
+            ("sdd", None),
+            ("sdz", None),
+        ] {
+            assert_eq!(sysfs_read_only(&sys.0, name), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn opening_for_writing() {
+        let dir = TempDir::new("write-open");
+        let writable = dir.write("image", "");
+        assert_eq!(write_open_refused(&writable), Some(false));
+        // Nothing to open, there's no telling.
+        assert_eq!(write_open_refused(&dir.0.join("missing")), None);
+
+        // Block devices the kernel has read-only still open for writing,
+        // only some drivers refuse it.
+        for (errno, expected) in [
+            (Errno::EROFS, Some(true)),
+            (Errno::EACCES, Some(true)),
+            (Errno::EBUSY, None),
+            (Errno::ENOMEDIUM, None),
+            (Errno::EIO, None),
+        ] {
+            assert_eq!(
+                opened_for_writing(&writable, Err(errno.into())),
+                expected,
+                "{}",
+                errno
+            );
+        }
+    }
+
+    #[test]
+    fn the_watch_notices_the_ro_flag_flipping() {
+        let sys = TempDir::new("watch");
+        let ro = |name: &str, ro: &str| {
+            sys.write(&format!("class/block/{}/ro", name), ro);
+        };
+        let mut watch = ReadOnlyWatch::new();
+        assert_eq!(watch.set("sda", None), None);
+        assert_eq!(watch.set("nvme0n1", Some(ReadOnly::NvmeMedia)), None);
+        ro("sda", "0\n");
+        ro("nvme0n1", "0\n");
+        ro("sdb", "1\n");
+
+        assert_eq!(watch.check(&sys.0, "sda"), None);
+        // Not watched until its capabilities are in.
+        assert_eq!(watch.check(&sys.0, "sdb"), None);
+
+        ro("sda", "1\n");
+        assert_eq!(
+            watch.check(&sys.0, "sda"),
+            Some(ReadOnlyChange::BecameReadOnly)
+        );
+        // Once, not every time it's checked.
+        assert_eq!(watch.check(&sys.0, "sda"), None);
+        ro("sda", "0\n");
+        assert_eq!(
+            watch.check(&sys.0, "sda"),
+            Some(ReadOnlyChange::BecameWritable)
+        );
+        assert_eq!(watch.check(&sys.0, "sda"), None);
+
+        // Read-only for a reason the flag doesn't clear, whichever way it
+        // goes.
+        assert_eq!(watch.check(&sys.0, "nvme0n1"), None);
+        ro("nvme0n1", "1\n");
+        assert_eq!(watch.check(&sys.0, "nvme0n1"), None);
+        ro("nvme0n1", "0\n");
+        assert_eq!(watch.check(&sys.0, "nvme0n1"), None);
+
+        // A flag that can't be read says nothing changed.
+        ro("sda", "");
+        assert_eq!(watch.check(&sys.0, "sda"), None);
+
+        // Fresh capabilities take over from what the flag said.
+        ro("sda", "1\n");
+        assert_eq!(
+            watch.check(&sys.0, "sda"),
+            Some(ReadOnlyChange::BecameReadOnly)
+        );
+        assert_eq!(
+            watch.set("sda", Some(ReadOnly::OpenRefused)),
+            Some(Some(ReadOnly::Kernel))
+        );
+        ro("sda", "0\n");
+        assert_eq!(watch.check(&sys.0, "sda"), None);
+
+        let mut names = watch.names();
+        names.sort();
+        assert_eq!(names, ["nvme0n1", "sda"]);
+        watch.forget("sda");
+        ro("sda", "1\n");
+        assert_eq!(watch.check(&sys.0, "sda"), None);
+        assert_eq!(watch.names(), ["nvme0n1"]);
+    }
+
+    #[test]
+    fn protected_and_quarantined_devices_arent_opened_for_writing() {
+        let dir = TempDir::new("protected");
+        // Opening a FIFO for writing waits for a reader, so whether the
+        // probe tried is plain to see.
+        let node = dir.0.join("node");
+        nix::unistd::mkfifo(&node, nix::sys::stat::Mode::from_bits_truncate(0o600)).unwrap();
+        let identity = DeviceIdentity {
+            name: "hddmond-test-fifo".to_string(),
+            ..Default::default()
+        };
+        let mut policy = DevicePolicy::new(DevicesConfig {
+            protect_root: false,
+            ..Default::default()
+        });
+        let probe = |protected: bool| {
+            let (sender, receiver) = std::sync::mpsc::channel();
+            let (identity, node) = (identity.clone(), node.clone());
+            let smartctl = dir.0.join("no-smartctl");
+            std::thread::spawn(move || {
+                let _ = sender.send(probe_capabilities(&identity, &smartctl, &node, protected));
+            });
+            receiver
+        };
+
+        policy.set_quarantined(&identity.name, true);
+        let probed = probe(policy.is_protected(&identity.name))
+            .recv_timeout(Duration::from_secs(5))
+            .expect("A quarantined device was opened for writing");
+        assert_eq!(probed.read_only, None);
+
+        // Let out, it is, as soon as something reads.
+        policy.set_quarantined(&identity.name, false);
+        let probing = probe(policy.is_protected(&identity.name));
+        assert!(probing.recv_timeout(Duration::from_millis(200)).is_err());
+        let reader = File::open(&node).unwrap();
+        probing.recv_timeout(Duration::from_secs(5)).unwrap();
+        drop(reader);
+    }
+}
@@ -0,0 +1,447 @@
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use crate::{
+    device_policy::DeviceIdentity,
+    error::SmartError,
+    faults::{self, FaultPoint},
+    identify_queue::{IdentifyQueue, Slot},
+    usage,
+};
+
+/// How big a device is and how it's addressed, as the kernel sees it.
+/// Anything that reads or writes a whole device goes by these, not by what
+/// the drive or smartctl claims.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capacity {
+    /// How big it is.
+    pub size_bytes: u64,
+    /// The unit reads and writes are addressed in, 4096 on 4Kn drives.
+    pub logical_block_size: u64,
+    /// What the drive writes at a time, 4096 on 512e drives too.
+    pub physical_block_size: u64,
+}
+
+impl Capacity {
+    /// From sysfs, None if the device isn't there.
+    pub fn lookup(name: &str) -> Option<Self> {
+        Self::from_sysfs(Path::new("/sys"), name)
+    }
+
+    /// Same as lookup, but with sysfs mounted at `sys`.
+    pub fn from_sysfs(sys: &Path, name: &str) -> Option<Self> {
+        let dir = sys.join("class/block").join(name);
+        let read = |file: &str| -> Option<u64> {
+            fs::read_to_string(dir.join(file)).ok()?.trim().parse().ok()
+        };
+
+        // `size` counts 512 byte sectors whatever the block size is.
+        let size_bytes = read("size")? * 512;
+        let logical_block_size = read("queue/logical_block_size")
+            .filter(|&size| size > 0)
+            .unwrap_or(512);
+        let physical_block_size = read("queue/physical_block_size")
+            .filter(|&size| size > 0)
+            .unwrap_or(logical_block_size);
+
+        Some(Self {
+            size_bytes,
+            logical_block_size,
+            physical_block_size,
+        })
+    }
+}
+
+impl fmt::Display for Capacity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} bytes, {}/{} byte sectors",
+            self.size_bytes, self.logical_block_size, self.physical_block_size
+        )
+    }
+}
+
+/// What the drive says about itself through smartctl, which USB bridges get
+/// in the way of less than they do the kernel's view. Bridges that only
+/// speak 32 bit LBAs cut drives over 2 TiB short, and some report 4K sectors
+/// for a 512e drive.
+pub fn smartctl_capacity(smartctl: &Path, node: &Path) -> Result<Capacity, SmartError> {
+    let output = usage::SMARTCTL_WAIT
+        .time(|| {
+            faults::fail_blocking(FaultPoint::SmartctlExec, faults::node_name(node))?;
+            Command::new(smartctl).args(["-i", "-j"]).arg(node).output()
+        })
+        .map_err(|error| SmartError::Run {
+            smartctl: smartctl.to_path_buf(),
+            error,
+        })?;
+
+    // smartctl exits with a bitmask of everything it didn't like, some of
+    // which still comes with the info. The JSON says whether it does.
+    let json: serde_json::Value = usage::SMARTCTL_PARSE
+        .time(|| serde_json::from_slice(&output.stdout))
+        .map_err(|error| SmartError::NotJson {
+            options: "-i",
+            node: node.to_path_buf(),
+            error,
+        })?;
+    let field = |key: &str| json[key].as_u64();
+
+    let size_bytes =
+        json["user_capacity"]["bytes"]
+            .as_u64()
+            .ok_or_else(|| SmartError::Unknown {
+                what: "capacity",
+                node: node.to_path_buf(),
+            })?;
+    let logical_block_size = field("logical_block_size").unwrap_or(512);
+    Ok(Capacity {
+        size_bytes,
+        logical_block_size,
+        physical_block_size: field("physical_block_size").unwrap_or(logical_block_size),
+    })
+}
+
+/// The kernel's view of a device next to smartctl's.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CapacityCheck {
+    /// What sysfs says.
+    pub sysfs: Capacity,
+    /// What smartctl says.
+    pub smartctl: Capacity,
+}
+
+impl CapacityCheck {
+    /// The physical block size is left out, plenty of drives don't report
+    /// it the same way through both.
+    pub fn agrees(&self) -> bool {
+        self.sysfs.size_bytes == self.smartctl.size_bytes
+            && self.sysfs.logical_block_size == self.smartctl.logical_block_size
+    }
+}
+
+impl fmt::Display for CapacityCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the kernel says {}, smartctl says {}",
+            self.sysfs, self.smartctl
+        )
+    }
+}
+
+/// Cross-checks new devices' capacity against smartctl off the event loop,
+/// results come back through next(). Devices smartctl can't tell about, like
+/// virtual disks, are left out.
+pub struct CapacityChecker {
+    smartctl: PathBuf,
+    queue: IdentifyQueue,
+    sender: mpsc::Sender<(DeviceIdentity, CapacityCheck)>,
+    receiver: mpsc::Receiver<(DeviceIdentity, CapacityCheck)>,
+}
+
+impl CapacityChecker {
+    /// Looks smartctl up in PATH when `smartctl` isn't given.
+    pub fn new(smartctl: Option<&Path>, queue: IdentifyQueue) -> Self {
+        let (sender, receiver) = mpsc::channel(64);
+        Self {
+            smartctl: smartctl
+                .unwrap_or_else(|| Path::new("smartctl"))
+                .to_path_buf(),
+            queue,
+            sender,
+            receiver,
+        }
+    }
+
+    /// Asks smartctl about `identity` in the background, for a device whose
+    /// sysfs capacity is known.
+    pub fn check(&self, identity: &DeviceIdentity) {
+        let sysfs = match identity.capacity {
+            Some(capacity) => capacity,
+            None => return,
+        };
+        let node = match identity.paths.first() {
+            Some(node) if node.exists() => node.clone(),
+            _ => return,
+        };
+
+        let identity = identity.clone();
+        let smartctl = self.smartctl.clone();
+        let sender = self.sender.clone();
+        let queue = self.queue.clone();
+        tokio::spawn(async move {
+            let _permit = queue.acquire(Slot::lookup(&identity.name)).await;
+            let checked = tokio::task::spawn_blocking(move || smartctl_capacity(&smartctl, &node));
+            match checked.await {
+                Ok(Ok(capacity)) => {
+                    let check = CapacityCheck {
+                        sysfs,
+                        smartctl: capacity,
+                    };
+                    // Only fails once we're shutting down.
+                    let _ = sender.send((identity, check)).await;
+                }
+                Ok(Err(e)) => debug!(
+                    "Not cross-checking the capacity of {}: {:#}",
+                    identity.name, e
+                ),
+                // Panicked, or cancelled on the way out.
+                Err(e) => debug!("The capacity check of {} failed: {}", identity.name, e),
+            }
+        });
+    }
+
+    /// The next device that was checked, in the order they finish.
+    pub async fn next(&mut self) -> Option<(DeviceIdentity, CapacityCheck)> {
+        self.receiver.recv().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    // A sysfs tree, or a directory with a stand-in smartctl in it.
+    struct TempDir(TestDir);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            Self(TestDir::new(&format!("capacity-{}", name)))
+        }
+
+        fn write(&self, file: &str, contents: &str) -> PathBuf {
+            let path = self.0.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, contents).unwrap();
+            path
+        }
+
+        // Prints `json` and exits with `status`, whatever it's asked.
+        fn smartctl(&self, json: &str, status: u8) -> PathBuf {
+            self.write("smartctl.json", json);
+            let smartctl = self.write(
+                "smartctl",
+                &format!(
+                    "#!/bin/sh\ncat {}\nexit {}\n",
+                    self.0.join("smartctl.json").display(),
+                    status
+                ),
+            );
+            fs::set_permissions(&smartctl, fs::Permissions::from_mode(0o755)).unwrap();
+            smartctl
+        }
+    }
+
+    fn capacity(size_bytes: u64, logical: u64, physical: u64) -> Capacity {
+        Capacity {
+            size_bytes,
+            logical_block_size: logical,
+            physical_block_size: physical,
+        }
+    }
+
+    // Trimmed from what smartctl 7.3 prints with `-i -j`.
+    const WD_512E: &str = r#"{
+      "device": { "name": "/dev/sda", "type": "sat" },
+      "model_name": "WDC WD40EFRX-68N32N0",
+      "user_capacity": { "blocks": 7814037168, "bytes": 4000787030016 },
+      "logical_block_size": 512,
+      "physical_block_size": 4096,
+      "smart_status": { "passed": true }
+    }"#;
+    const HGST_4KN: &str = r#"{
+      "device": { "name": "/dev/sdb", "type": "scsi" },
+      "model_name": "HGST HUH721010AL4200",
+      "user_capacity": { "blocks": 2441609216, "bytes": 10000831348736 },
+      "logical_block_size": 4096,
+      "physical_block_size": 4096
+    }"#;
+    const NO_BLOCK_SIZES: &str = r#"{
+      "user_capacity": { "blocks": 976773168, "bytes": 500107862016 }
+    }"#;
+    const NO_CAPACITY: &str = r#"{
+      "device": { "name": "/dev/sdc", "type": "sat" },
+      "smartctl": { "exit_status": 2, "messages": [
+        { "string": "Read Device Identity failed: scsi error unsupported field in scsi command", "severity": "error" }
+      ] }
+    }"#;
+
+    #[test]
+    fn capacities_from_sysfs() {
+        type Files = &'static [(&'static str, &'static str)];
+        let cases: &[(&str, Files, Option<Capacity>)] = &[
+            (
+                "512n",
+                &[
+                    ("size", "976773168\n"),
+                    ("queue/logical_block_size", "512\n"),
+                    ("queue/physical_block_size", "512\n"),
+                ],
+                Some(capacity(500107862016, 512, 512)),
+            ),
+            (
+                "512e",
+                &[
+                    ("size", "7814037168\n"),
+                    ("queue/logical_block_size", "512\n"),
+                    ("queue/physical_block_size", "4096\n"),
+                ],
+                Some(capacity(4000787030016, 512, 4096)),
+            ),
+            (
+                // `size` is in 512 byte sectors even here.
+                "4kn",
+                &[
+                    ("size", "19532873728\n"),
+                    ("queue/logical_block_size", "4096\n"),
+                    ("queue/physical_block_size", "4096\n"),
+                ],
+                Some(capacity(10000831348736, 4096, 4096)),
+            ),
+            (
+                "no-queue",
+                &[("size", "2048\n")],
+                Some(capacity(1 << 20, 512, 512)),
+            ),
+            (
+                "no-physical",
+                &[("size", "2048\n"), ("queue/logical_block_size", "4096\n")],
+                Some(capacity(1 << 20, 4096, 4096)),
+            ),
+            (
+                "zero-block-sizes",
+                &[
+                    ("size", "2048\n"),
+                    ("queue/logical_block_size", "0\n"),
+                    ("queue/physical_block_size", "0\n"),
+                ],
+                Some(capacity(1 << 20, 512, 512)),
+            ),
+            (
+                "garbage-block-size",
+                &[("size", "2048\n"), ("queue/logical_block_size", "big\n")],
+                Some(capacity(1 << 20, 512, 512)),
+            ),
+            ("empty-size", &[("size", "")], None),
+            ("negative-size", &[("size", "-1\n")], None),
+            ("garbage-size", &[("size", "lots\n")], None),
+            ("no-size", &[("queue/logical_block_size", "512\n")], None),
+        ];
+
+        for (name, files, expected) in cases {
+            let sys = TempDir::new(name);
+            for (file, contents) in files.iter() {
+                sys.write(&format!("class/block/sdx/{}", file), contents);
+            }
+            assert_eq!(Capacity::from_sysfs(&sys.0, "sdx"), *expected, "{}", name);
+        }
+        assert_eq!(Capacity::from_sysfs(Path::new("/nonexistent"), "sdx"), None);
+    }
+
+    #[test]
+    fn capacities_from_smartctl() {
+        let node = Path::new("/dev/sdx");
+        for (name, json, status, expected) in [
+            ("512e", WD_512E, 0, capacity(4000787030016, 512, 4096)),
+            ("4kn", HGST_4KN, 0, capacity(10000831348736, 4096, 4096)),
+            (
+                "defaults",
+                NO_BLOCK_SIZES,
+                0,
+                capacity(500107862016, 512, 512),
+            ),
+            // Bit 6 of the exit status, errors in the drive's log, and the
+            // info is all there anyway.
+            ("unhappy", WD_512E, 64, capacity(4000787030016, 512, 4096)),
+        ] {
+            let dir = TempDir::new(&format!("smartctl-{}", name));
+            let smartctl = dir.smartctl(json, status);
+            assert_eq!(
+                smartctl_capacity(&smartctl, node).unwrap(),
+                expected,
+                "{}",
+                name
+            );
+        }
+
+        for (name, json, message) in [
+            (
+                "no-capacity",
+                NO_CAPACITY,
+                "smartctl doesn't know the capacity of /dev/sdx",
+            ),
+            (
+                "not-json",
+                "smartctl 7.3 2022-02-28\n",
+                "smartctl -i /dev/sdx didn't print JSON",
+            ),
+            ("empty", "", "smartctl -i /dev/sdx didn't print JSON"),
+        ] {
+            let dir = TempDir::new(&format!("smartctl-{}", name));
+            let smartctl = dir.smartctl(json, 2);
+            let error = smartctl_capacity(&smartctl, node).unwrap_err();
+            assert!(
+                error.to_string().starts_with(message),
+                "{}: {}",
+                name,
+                error
+            );
+        }
+
+        let error = smartctl_capacity(Path::new("/nonexistent/smartctl"), node).unwrap_err();
+        assert!(
+            matches!(error, SmartError::Run { ref smartctl, .. } if smartctl == Path::new("/nonexistent/smartctl")),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn a_bridge_that_gets_the_drive_wrong() {
+        // A 4 TB 512e drive behind a bridge with 32 bit LBAs and 4K
+        // sectors of its own, which is what the kernel sees through it.
+        let sys = TempDir::new("bridge");
+        sys.write("class/block/sdx/size", "17179869176\n");
+        sys.write("class/block/sdx/queue/logical_block_size", "4096\n");
+        sys.write("class/block/sdx/queue/physical_block_size", "4096\n");
+        let dir = TempDir::new("bridge-smartctl");
+        let smartctl = dir.smartctl(WD_512E, 0);
+
+        let check = CapacityCheck {
+            sysfs: Capacity::from_sysfs(&sys.0, "sdx").unwrap(),
+            smartctl: smartctl_capacity(&smartctl, Path::new("/dev/sdx")).unwrap(),
+        };
+        assert!(!check.agrees());
+        assert_eq!(
+            check.to_string(),
+            "the kernel says 8796093018112 bytes, 4096/4096 byte sectors, \
+             smartctl says 4000787030016 bytes, 512/4096 byte sectors"
+        );
+
+        // Only the size or the logical block size disagreeing is enough.
+        let drive = capacity(4000787030016, 512, 4096);
+        for (sysfs, agrees) in [
+            (drive, true),
+            (capacity(4000787030016, 512, 512), true),
+            (capacity(4000787030016, 4096, 4096), false),
+            (capacity(2199023255040, 512, 4096), false),
+        ] {
+            let check = CapacityCheck {
+                sysfs,
+                smartctl: drive,
+            };
+            assert_eq!(check.agrees(), agrees, "{}", check);
+        }
+    }
+}
@@ -0,0 +1,533 @@
+use std::{path::PathBuf, time::Duration};
+
+use clap::{Parser, Subcommand};
+
+use hddmond::{
+    config::{self, Backend, Config, StorageConfig},
+    export::ExportFormat,
+    logging::LogFormat,
+    storage::{self, AlertState},
+};
+
+pub const VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    " (",
+    env!("HDDMOND_GIT_HASH"),
+    ")"
+);
+
+// Command line flags. Every flag can also be set through the HDDMOND_*
+// environment variable next to it, and overrides the matching setting in
+// the config file.
+#[derive(Debug, Parser)]
+#[command(version = VERSION, about)]
+pub struct Args {
+    /// Config file to read
+    #[arg(long, env = "HDDMOND_CONFIG", default_value = config::DEFAULT_CONFIG_PATH)]
+    pub config: PathBuf,
+
+    /// Log level (off, error, warn, info, debug, trace), optionally with
+    /// per module overrides: info,hddmond::scanners=debug
+    #[arg(long, env = "HDDMOND_LOG_LEVEL")]
+    pub log_level: Option<String>,
+
+    /// Log output format
+    #[arg(long, env = "HDDMOND_LOG_FORMAT", value_enum)]
+    pub log_format: Option<LogFormat>,
+
+    /// Where device events come from
+    #[arg(long, env = "HDDMOND_BACKEND", value_enum)]
+    pub backend: Option<Backend>,
+
+    /// Play out the made up drives in this fleet file instead of watching
+    /// real ones, see examples/fleet.toml
+    #[arg(long, env = "HDDMOND_SIMULATE", value_name = "FLEET")]
+    pub simulate: Option<PathBuf>,
+
+    /// Fork into the background (needs a log file)
+    #[arg(long, env = "HDDMOND_DAEMONIZE")]
+    pub daemonize: bool,
+
+    /// Ask a daemon that's already running to shut down, and take over
+    /// once it has
+    #[arg(long)]
+    pub replace: bool,
+
+    /// Directory to load plugins from
+    #[arg(long, env = "HDDMOND_PLUGIN_DIR")]
+    pub plugin_dir: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Print TypeScript declarations for the plugin API
+    PluginTypes,
+    /// List the devices in the registry
+    Devices {
+        /// Include devices that aren't present right now
+        #[arg(long)]
+        all: bool,
+    },
+    /// Show one device from the registry and what happened to it lately
+    Show {
+        /// Its UUID, serial, WWN, current kernel name or alias
+        device: String,
+        /// Only show what happened in this session, by its number
+        #[arg(long)]
+        session: Option<i64>,
+    },
+    /// Give a device in the registry a name, like "bay 7", or show the
+    /// one it has
+    Alias {
+        /// Its UUID, serial, WWN, current kernel name or alias
+        device: String,
+        /// The name, which no other present device can have
+        alias: Option<String>,
+        /// Take its alias away
+        #[arg(long, conflicts_with = "alias")]
+        clear: bool,
+    },
+    /// Put a device in quarantine, so the daemon changes nothing about it
+    /// until it's lifted, or show why it's in there
+    Quarantine {
+        /// Its UUID, serial, WWN, current kernel name or alias
+        device: String,
+        /// Why, shown with the device everywhere. Needs the daemon running
+        #[arg(long, conflicts_with = "lift")]
+        reason: Option<String>,
+        /// Let it out again. Needs the daemon running
+        #[arg(long)]
+        lift: bool,
+    },
+    /// Send a test message through every configured email notifier
+    TestEmail,
+    /// Check what the daemon depends on, like smartctl, udev and
+    /// permissions, and say what to do about anything that's wrong
+    Doctor {
+        /// Print the checks as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Ask the running daemon how it's doing
+    Status {
+        /// Print its resource usage and the devices' health in the
+        /// Prometheus text format, for node_exporter's textfile collector
+        #[arg(long)]
+        prometheus: bool,
+    },
+    /// Have the running daemon look for devices right away, and print
+    /// what it found
+    Rescan,
+    /// Show which controller, port and enclosure slot each disk is on
+    Topology {
+        /// Print JSON instead of a tree
+        #[arg(long)]
+        json: bool,
+    },
+    /// Show the partitions and filesystems on a disk, read-only
+    Probe {
+        /// The disk, e.g. sda
+        device: String,
+    },
+    /// Read a disk back and check that it's all zeroes, e.g. after a wipe
+    VerifyBlank {
+        /// The disk, e.g. sda, or the path to an image
+        device: String,
+        /// Only read this percentage of the disk, spread evenly over it
+        #[arg(long, default_value_t = 100.0, value_parser = parse_percent)]
+        sample: f64,
+        /// Start at this byte offset, to pick up where an earlier run stopped
+        #[arg(long, default_value_t = 0)]
+        from: u64,
+        /// Print the result as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Copy a disk to an image, read-only, hashing it on the way. Sectors
+    /// that can't be read are zeroes in the image, and listed in the record
+    /// written next to it
+    Image {
+        /// The disk, e.g. sda, or the path to an image
+        device: String,
+        /// The directory to put the image in, which has to have room for all
+        /// of the disk and can't be on it. - writes the image to stdout and
+        /// the record to stderr, with neither checked
+        destination: PathBuf,
+        /// Write gzip members of 64 MiB of the disk each, instead of a raw
+        /// image
+        #[arg(long)]
+        gzip: bool,
+        /// Print the record as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Ask hdparm whether a disk is spun up, without waking it
+    PowerState {
+        /// The disk, e.g. sda
+        device: String,
+    },
+    /// Blink the locate LED of the enclosure slot a disk is in
+    Locate {
+        /// The disk, e.g. sda
+        device: String,
+        /// How long to blink for
+        #[arg(long, default_value_t = 60)]
+        secs: u64,
+        /// Turn the LED off instead
+        #[arg(long)]
+        off: bool,
+    },
+    /// List the health alerts, or acknowledge one to stop hearing about it
+    Alerts {
+        #[command(subcommand)]
+        command: AlertsCommand,
+    },
+    /// Work with the audit trail
+    Audit {
+        #[command(subcommand)]
+        command: AuditCommand,
+    },
+    /// Blink a disk's activity LED with bursts of reads, once a second,
+    /// to find it without an enclosure LED
+    Blink {
+        /// The disk, e.g. sda, or its serial or WWN
+        device: String,
+        /// How long to blink for
+        #[arg(long, default_value_t = 30)]
+        seconds: u64,
+    },
+    /// Put the config without its secrets, the daemon's status, recent
+    /// device events and the ends of the audit trail and log in one
+    /// .tar.gz, to send along with a bug report
+    SupportBundle {
+        /// How far back device events go, like 30m, 24h or 7d
+        #[arg(long, default_value = "24h", value_parser = parse_age)]
+        since: Duration,
+        /// File to write to, hddmond-support-<time>.tar.gz by default
+        #[arg(long)]
+        out: Option<PathBuf>,
+        /// How many lines of the log and the audit trail to put in
+        #[arg(long, default_value_t = 2000)]
+        lines: usize,
+        /// The most it may hold before compression, the log is cut down
+        /// first, then the audit trail, then the device events
+        #[arg(long, default_value_t = 20)]
+        max_size_mb: u64,
+    },
+    /// Convert a smartd.conf into a config fragment, saying which of its
+    /// directives hddmond has nothing like
+    ImportSmartd {
+        /// The smartd.conf to read
+        #[arg(default_value = "/etc/smartd.conf")]
+        file: PathBuf,
+        /// File to write the fragment to instead of stdout
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Export the devices in the registry as CSV or JSON
+    Export {
+        #[arg(long, value_enum, default_value = "csv")]
+        format: ExportFormat,
+        /// File to write to instead of stdout
+        #[arg(long)]
+        out: Option<PathBuf>,
+        /// Include devices that aren't present right now
+        #[arg(long)]
+        all: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum AuditCommand {
+    /// Check that an audit file's records are in sequence and its hash
+    /// chain is intact
+    Verify {
+        /// The audit file, the current one or a rotated one
+        file: PathBuf,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum AlertsCommand {
+    /// List alerts, oldest first, the ones that haven't cleared unless
+    /// asked for
+    List {
+        /// Only the device's, by UUID, serial, WWN, current kernel name or alias
+        #[arg(long)]
+        device: Option<String>,
+        /// Only the ones in this state
+        #[arg(long, value_enum)]
+        state: Option<AlertState>,
+        /// The cleared ones too
+        #[arg(long, conflicts_with = "state")]
+        all: bool,
+        /// Print JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Acknowledge an alert, so it isn't notified about again until it
+    /// clears or notifiers.realert_after_secs pass. Needs the daemon
+    /// running
+    Ack {
+        /// The alert's number
+        id: i64,
+        /// What was done about it, like "pulled and binned"
+        #[arg(long)]
+        note: String,
+    },
+}
+
+fn parse_percent(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(percent) if percent > 0.0 && percent <= 100.0 => Ok(percent),
+        _ => Err("has to be a percentage above 0 and up to 100".to_string()),
+    }
+}
+
+// 90s, 30m, 24h or 7d.
+fn parse_age(value: &str) -> Result<Duration, String> {
+    let split = value.len() - value.chars().last().map_or(0, char::len_utf8);
+    let (number, unit) = value.split_at(split);
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err("has to be a number with s, m, h or d after it, like 24h".to_string()),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(seconds))
+        .map(Duration::from_secs)
+        .ok_or_else(|| "has to be a number with s, m, h or d after it, like 24h".to_string())
+}
+
+impl Args {
+    // The one place settings from different sources are merged. The
+    // precedence is command line > environment > config file > defaults;
+    // clap already settles the first two, so all that's left is laying
+    // whatever it found over the config file.
+    pub fn apply(&self, config: &mut Config) {
+        if let Some(level) = &self.log_level {
+            config.logging.level = level.clone();
+        }
+        if let Some(format) = self.log_format {
+            config.logging.format = format;
+        }
+        if self.daemonize {
+            config.daemon.daemonize = true;
+        }
+        if let Some(backend) = self.backend {
+            config.monitor.backend = backend;
+        }
+        if let Some(fleet) = &self.simulate {
+            config.monitor.backend = Backend::Simulated;
+            config.monitor.fleet = Some(fleet.clone());
+            // Keep the made up drives out of the real registry, unless it
+            // was pointed somewhere else on purpose.
+            if config.storage.path == StorageConfig::default().path {
+                config.storage.path = PathBuf::from(storage::IN_MEMORY);
+            }
+        }
+        if let Some(dir) = &self.plugin_dir {
+            config.plugin_host.dir = dir.clone();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path::Path, sync::Mutex};
+
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    // Parsing reads the HDDMOND_* variables, which are the whole process's,
+    // so only one test touches them at a time.
+    static ENV: Mutex<()> = Mutex::new(());
+
+    const VARS: &[&str] = &[
+        "HDDMOND_CONFIG",
+        "HDDMOND_LOG_LEVEL",
+        "HDDMOND_LOG_FORMAT",
+        "HDDMOND_BACKEND",
+        "HDDMOND_SIMULATE",
+        "HDDMOND_DAEMONIZE",
+        "HDDMOND_PLUGIN_DIR",
+    ];
+
+    // The config `file` makes with `args` and `vars` laid over it, the way
+    // main does it.
+    fn merged(file: &str, args: &[&str], vars: &[(&str, &str)]) -> (Args, Config) {
+        let _env = ENV.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        for var in VARS {
+            env::remove_var(var);
+        }
+        for (var, value) in vars {
+            env::set_var(var, value);
+        }
+        let parsed = Args::try_parse_from([&["hddmond"], args].concat());
+        for var in VARS {
+            env::remove_var(var);
+        }
+
+        let dir = TestDir::new("cli");
+        let path = dir.join("hddmond.toml");
+        fs::write(&path, file).unwrap();
+        let mut config = Config::load(&path).unwrap();
+
+        let args = parsed.unwrap();
+        args.apply(&mut config);
+        (args, config)
+    }
+
+    const FILE: &str = "[logging]\nlevel = \"warn\"\nformat = \"json\"\n\
+                        [monitor]\nbackend = \"smartctl\"\n\
+                        [plugin_host]\ndir = \"/from/file\"\n";
+
+    #[test]
+    fn command_line_beats_environment_beats_file_beats_defaults() {
+        // Nothing but the file.
+        let (_, config) = merged(FILE, &[], &[]);
+        assert_eq!(config.logging.level, "warn");
+        assert_eq!(config.logging.format, LogFormat::Json);
+        assert_eq!(config.monitor.backend, Backend::Smartctl);
+        assert_eq!(config.plugin_host.dir, Path::new("/from/file"));
+        // Not in the file, so the default.
+        assert_eq!(config.udev.poll_interval_ms, 100);
+
+        // The environment over the file.
+        let vars = [
+            ("HDDMOND_LOG_LEVEL", "debug"),
+            ("HDDMOND_LOG_FORMAT", "text"),
+            ("HDDMOND_BACKEND", "udev"),
+            ("HDDMOND_PLUGIN_DIR", "/from/env"),
+        ];
+        let (_, config) = merged(FILE, &[], &vars);
+        assert_eq!(config.logging.level, "debug");
+        assert_eq!(config.logging.format, LogFormat::Text);
+        assert_eq!(config.monitor.backend, Backend::Udev);
+        assert_eq!(config.plugin_host.dir, Path::new("/from/env"));
+
+        // The command line over both.
+        let (_, config) = merged(
+            FILE,
+            &[
+                "--log-level",
+                "trace",
+                "--log-format",
+                "json",
+                "--backend",
+                "auto",
+                "--plugin-dir",
+                "/from/flag",
+            ],
+            &vars,
+        );
+        assert_eq!(config.logging.level, "trace");
+        assert_eq!(config.logging.format, LogFormat::Json);
+        assert_eq!(config.monitor.backend, Backend::Auto);
+        assert_eq!(config.plugin_host.dir, Path::new("/from/flag"));
+    }
+
+    #[test]
+    fn config_path_comes_from_the_flag_then_the_environment() {
+        let (args, _) = merged("", &[], &[]);
+        assert_eq!(args.config, Path::new(config::DEFAULT_CONFIG_PATH));
+        let (args, _) = merged("", &[], &[("HDDMOND_CONFIG", "/env.toml")]);
+        assert_eq!(args.config, Path::new("/env.toml"));
+        let (args, _) = merged(
+            "",
+            &["--config", "/flag.toml"],
+            &[("HDDMOND_CONFIG", "/env.toml")],
+        );
+        assert_eq!(args.config, Path::new("/flag.toml"));
+    }
+
+    #[test]
+    fn a_missing_flag_leaves_the_file_alone() {
+        // --daemonize can only turn it on, leaving it out isn't "off".
+        let (_, config) = merged(
+            "[daemon]\ndaemonize = true\n[logging.file]\npath = \"/tmp/hddmond.log\"\n",
+            &[],
+            &[],
+        );
+        assert!(config.daemon.daemonize);
+        let (_, config) = merged("", &[], &[("HDDMOND_DAEMONIZE", "true")]);
+        assert!(config.daemon.daemonize);
+        let (_, config) = merged("", &["--daemonize"], &[]);
+        assert!(config.daemon.daemonize);
+    }
+
+    #[test]
+    fn simulate_wins_over_backend_and_keeps_out_of_the_registry() {
+        let (_, config) = merged(
+            FILE,
+            &["--backend", "udev", "--simulate", "fleet.toml"],
+            &[],
+        );
+        assert_eq!(config.monitor.backend, Backend::Simulated);
+        assert_eq!(
+            config.monitor.fleet.as_deref(),
+            Some(Path::new("fleet.toml"))
+        );
+        assert_eq!(config.storage.path, Path::new(storage::IN_MEMORY));
+
+        // Unless the file points the registry somewhere on purpose.
+        let (_, config) = merged(
+            "[storage]\npath = \"/tmp/sim.db\"\n",
+            &[],
+            &[("HDDMOND_SIMULATE", "fleet.toml")],
+        );
+        assert_eq!(config.monitor.backend, Backend::Simulated);
+        assert_eq!(config.storage.path, Path::new("/tmp/sim.db"));
+    }
+
+    #[test]
+    fn bad_values_are_refused() {
+        let _env = ENV.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        for args in [
+            &["hddmond", "--backend", "scsi"][..],
+            &["hddmond", "--log-format", "xml"],
+            &["hddmond", "alias", "sda", "bay 1", "--clear"],
+            &["hddmond", "verify-blank", "sda", "--sample", "0"],
+            &["hddmond", "verify-blank", "sda", "--sample", "101"],
+            &["hddmond", "support-bundle", "--since", "1w"],
+            &["hddmond", "alerts", "list", "--all", "--state", "open"],
+        ] {
+            assert!(Args::try_parse_from(args).is_err(), "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn ages() {
+        assert_eq!(parse_age("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_age("30m"), Ok(Duration::from_secs(30 * 60)));
+        assert_eq!(parse_age("24h"), Ok(Duration::from_secs(24 * 60 * 60)));
+        assert_eq!(parse_age("7d"), Ok(Duration::from_secs(7 * 24 * 60 * 60)));
+        for bad in [
+            "",
+            "h",
+            "24",
+            "-1h",
+            "1.5h",
+            "24 h",
+            "99999999999999999999d",
+        ] {
+            assert!(parse_age(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn percentages() {
+        assert_eq!(parse_percent("100"), Ok(100.0));
+        assert_eq!(parse_percent("0.5"), Ok(0.5));
+        for bad in ["0", "-5", "100.1", "nan", "ten"] {
+            assert!(parse_percent(bad).is_err(), "{}", bad);
+        }
+    }
+}
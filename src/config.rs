@@ -0,0 +1,1658 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use serde::Deserialize;
+
+use crate::{
+    error::ConfigError,
+    export::Column,
+    identify_queue::BusClass,
+    logging::{self, LogFormat},
+    notifiers::{
+        chat::ChatKind,
+        email::{self, EmailTls},
+        notification::NotificationKind,
+        quiet_hours::QuietHours,
+        secret::Secret,
+        template::Template,
+        webhook,
+    },
+    plugins::{plugin_config::PluginConfig, plugin_limits::PluginLimits},
+    power,
+    storage::{self, IdentityBasis},
+};
+
+/// Where the config is read from when neither --config nor HDDMOND_CONFIG
+/// says otherwise.
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/hddmond/config.toml";
+
+/// The daemon config file. Every section is optional, anything left out
+/// keeps its default. Unknown keys are an error, so a typo doesn't silently
+/// leave a setting at its default. See examples/config.toml.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// How devices are found.
+    pub monitor: MonitorConfig,
+    /// Settings for the udev backend.
+    pub udev: UdevConfig,
+    /// Settings for the smartctl backend, and where smartctl is.
+    pub smartctl: SmartCtlConfig,
+    /// How many new devices can be identified at once.
+    pub identify: IdentifyConfig,
+    /// Devices to ignore, protect or name.
+    pub devices: DevicesConfig,
+    /// APM and spin down settings applied to devices as they appear.
+    pub power: PowerConfig,
+    /// Where plugins are loaded from, and what they're allowed to use.
+    pub plugin_host: PluginHostConfig,
+    /// Where logs go and how much of them.
+    pub logging: LoggingConfig,
+    /// How the daemon process runs.
+    pub daemon: DaemonConfig,
+    /// The device database.
+    pub storage: StorageConfig,
+    /// The audit trail.
+    pub audit: AuditConfig,
+    /// What happens when the disks the daemon writes to fill up.
+    pub disk_guard: DiskGuardConfig,
+    /// Watching the link error counters of SATA and SAS ports.
+    pub link_health: LinkHealthConfig,
+    /// What `hddmond export` writes.
+    pub export: ExportConfig,
+    /// Who's told when something happens to a device.
+    pub notifiers: NotifiersConfig,
+    /// Per plugin settings, keyed by the plugin's name (its file name
+    /// without the extension).
+    pub plugins: HashMap<String, PluginConfig>,
+}
+
+/// Which device monitor the daemon uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// udev if it's available, smartctl otherwise.
+    Auto,
+    /// udev events, so devices are found the moment they appear.
+    Udev,
+    /// `smartctl --scan` every smartctl.scan_interval_secs, for systems
+    /// without udev.
+    Smartctl,
+    /// udev and smartctl at once, a drive either of them sees is found once.
+    Composite,
+    /// Made up drives from monitor.fleet, see --simulate.
+    Simulated,
+}
+
+impl Backend {
+    /// The name the backend goes by in the config file and on the command line.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Backend::Auto => "auto",
+            Backend::Udev => "udev",
+            Backend::Smartctl => "smartctl",
+            Backend::Composite => "composite",
+            Backend::Simulated => "simulated",
+        }
+    }
+}
+
+/// The `[monitor]` section.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MonitorConfig {
+    /// Where device events come from.
+    pub backend: Backend,
+    /// The TOML or JSON fleet description the simulated backend plays out.
+    pub fleet: Option<PathBuf>,
+    /// How many events the monitor can get ahead of the main loop by.
+    pub queue_size: usize,
+    /// Have the kernel scan every SCSI host for new disks before each
+    /// `hddmond rescan`.
+    pub rescan_scsi_hosts: bool,
+}
+
+impl Default for MonitorConfig {
+    fn default() -> Self {
+        Self {
+            backend: Backend::Auto,
+            fleet: None,
+            queue_size: 1024,
+            rescan_scsi_hosts: false,
+        }
+    }
+}
+
+/// A subsystem / devtype pair to listen for udev events on.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UdevMatch {
+    /// The udev subsystem, like "block".
+    pub subsystem: String,
+    /// Only its events for this devtype, every devtype if unset.
+    pub devtype: Option<String>,
+}
+
+/// The `[udev]` section.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UdevConfig {
+    /// What to listen for, an event matching any of them is picked up.
+    pub matches: Vec<UdevMatch>,
+    /// How long to wait before checking the udev socket again after it had
+    /// nothing for us.
+    pub poll_interval_ms: u64,
+}
+
+impl UdevConfig {
+    /// poll_interval_ms as a Duration.
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_millis(self.poll_interval_ms)
+    }
+}
+
+impl Default for UdevConfig {
+    fn default() -> Self {
+        Self {
+            matches: vec![
+                UdevMatch {
+                    subsystem: "block".to_string(),
+                    devtype: Some("disk".to_string()),
+                },
+                UdevMatch {
+                    subsystem: "usb".to_string(),
+                    devtype: Some("disk".to_string()),
+                },
+            ],
+            poll_interval_ms: 100,
+        }
+    }
+}
+
+/// The `[smartctl]` section.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SmartCtlConfig {
+    /// Path to the smartctl binary, looked up in PATH when not set.
+    pub path: Option<PathBuf>,
+    /// How often the smartctl backend runs `smartctl --scan`.
+    pub scan_interval_secs: u64,
+}
+
+impl SmartCtlConfig {
+    /// scan_interval_secs as a Duration.
+    pub fn scan_interval(&self) -> Duration {
+        Duration::from_secs(self.scan_interval_secs)
+    }
+}
+
+impl Default for SmartCtlConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            scan_interval_secs: 1,
+        }
+    }
+}
+
+/// How many smartctl runs identifying new devices (their capacity and
+/// capabilities) can go on at once. A run has to fit under all three.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdentifyConfig {
+    /// Across every bus and controller.
+    pub max_parallel: usize,
+    /// On each kind of bus.
+    pub per_bus: BusLimits,
+    /// On any one PCI controller, an HBA or a USB host controller.
+    pub per_controller: usize,
+}
+
+impl Default for IdentifyConfig {
+    fn default() -> Self {
+        Self {
+            max_parallel: 16,
+            per_bus: BusLimits::default(),
+            per_controller: 8,
+        }
+    }
+}
+
+/// A limit for each kind of bus a device can be on.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BusLimits {
+    /// Behind USB bridges, which usually don't take many commands at once.
+    pub usb: usize,
+    /// On SATA ports.
+    pub sata: usize,
+    /// On SAS ports.
+    pub sas: usize,
+    /// NVMe drives.
+    pub nvme: usize,
+    /// Anything else, or a bus that couldn't be told.
+    pub other: usize,
+}
+
+impl BusLimits {
+    /// The limit for devices on `bus`.
+    pub fn get(&self, bus: BusClass) -> usize {
+        match bus {
+            BusClass::Usb => self.usb,
+            BusClass::Sata => self.sata,
+            BusClass::Sas => self.sas,
+            BusClass::Nvme => self.nvme,
+            BusClass::Other => self.other,
+        }
+    }
+}
+
+impl Default for BusLimits {
+    fn default() -> Self {
+        Self {
+            usb: 2,
+            sata: 4,
+            sas: 8,
+            nvme: 16,
+            other: 4,
+        }
+    }
+}
+
+/// Matches devices by identity. Every field that's given has to match;
+/// serial and WWN are compared exactly (ignoring case), model and path are
+/// globs. Path matches the device node or any of its /dev/disk/by-* links.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeviceMatch {
+    /// The serial number the drive reports.
+    pub serial: Option<String>,
+    /// A glob over the model name.
+    pub model: Option<String>,
+    /// The drive's World Wide Name.
+    pub wwn: Option<String>,
+    /// A glob over the device node and its /dev/disk/by-* links.
+    pub path: Option<String>,
+}
+
+/// The `[devices]` section.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DevicesConfig {
+    /// Devices the daemon acts as if weren't there.
+    pub ignore: Vec<DeviceMatch>,
+    /// Devices that are monitored, but never written to.
+    pub protect: Vec<DeviceMatch>,
+    /// Protect the disk(s) the root filesystem is on.
+    pub protect_root: bool,
+    /// Names devices after where they're plugged in, like
+    /// "bay-{{slot}}", unless they've been given an alias by hand.
+    pub alias_template: Option<String>,
+}
+
+impl Default for DevicesConfig {
+    fn default() -> Self {
+        Self {
+            ignore: vec![],
+            protect: vec![],
+            protect_root: true,
+            alias_template: None,
+        }
+    }
+}
+
+/// The `[power]` section.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PowerConfig {
+    /// Path to the hdparm binary, looked up in PATH when not set.
+    pub hdparm: Option<PathBuf>,
+    /// Applied to devices as they appear, the first one that matches wins.
+    pub policies: Vec<PowerPolicy>,
+}
+
+/// Power settings for the devices `device` matches.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PowerPolicy {
+    /// Which devices get these settings.
+    pub device: DeviceMatch,
+    /// Advanced power management level, 1 (most power saving, spins down)
+    /// to 254 (most performance), or 255 to turn APM off.
+    pub apm: Option<u8>,
+    /// How long the drive can sit idle before it spins down, 0 for never.
+    /// Rounded up to what the drive can be told, see power::standby_value.
+    pub standby_after_secs: Option<u64>,
+}
+
+/// The `[plugin_host]` section.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PluginHostConfig {
+    /// Where plugins are loaded from, relative to the working directory.
+    pub dir: PathBuf,
+    /// How often the plugin directory and this file are checked for changes.
+    pub reload_interval_secs: u64,
+    /// How long a hook can run, see PluginLimits::hook_timeout.
+    pub hook_timeout_ms: u64,
+    /// How big each plugin's V8 heap can grow.
+    pub max_heap_mb: usize,
+    /// How many async ops a plugin can have going at once.
+    pub max_pending_ops: usize,
+    /// How many times a plugin can go over a limit before it's quarantined.
+    pub max_violations: u32,
+}
+
+impl PluginHostConfig {
+    /// reload_interval_secs as a Duration.
+    pub fn reload_interval(&self) -> Duration {
+        Duration::from_secs(self.reload_interval_secs)
+    }
+
+    /// The limits every plugin runtime is held to.
+    pub fn limits(&self) -> PluginLimits {
+        PluginLimits {
+            hook_timeout: Duration::from_millis(self.hook_timeout_ms),
+            max_heap_bytes: self.max_heap_mb * 1024 * 1024,
+            max_pending_ops: self.max_pending_ops,
+            max_violations: self.max_violations,
+        }
+    }
+}
+
+impl Default for PluginHostConfig {
+    fn default() -> Self {
+        let limits = PluginLimits::default();
+
+        Self {
+            dir: PathBuf::from("plugins"),
+            reload_interval_secs: 2,
+            hook_timeout_ms: limits.hook_timeout.as_millis() as u64,
+            max_heap_mb: limits.max_heap_bytes / (1024 * 1024),
+            max_pending_ops: limits.max_pending_ops,
+            max_violations: limits.max_violations,
+        }
+    }
+}
+
+/// The `[logging]` section.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// A level, optionally followed by per module overrides, e.g.
+    /// `info,hddmond::scanners=debug`.
+    pub level: String,
+    /// Plain text or JSON, one object per line.
+    pub format: LogFormat,
+    /// Log to a file instead of stderr.
+    pub file: Option<LogFileConfig>,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            format: LogFormat::Text,
+            file: None,
+        }
+    }
+}
+
+/// The `[logging.file]` section.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogFileConfig {
+    /// The file to log to.
+    pub path: PathBuf,
+    /// Size at which the file is rotated.
+    pub max_size_mb: u64,
+    /// How many rotated files to keep around.
+    pub keep: usize,
+    /// Gzip rotated files.
+    pub compress: bool,
+}
+
+impl LogFileConfig {
+    /// max_size_mb in bytes.
+    pub fn max_size_bytes(&self) -> u64 {
+        self.max_size_mb * 1024 * 1024
+    }
+}
+
+impl Default for LogFileConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("/var/log/hddmond.log"),
+            max_size_mb: 10,
+            keep: 5,
+            compress: false,
+        }
+    }
+}
+
+/// The `[daemon]` section.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DaemonConfig {
+    /// Fork into the background. Only this process's stdio is closed, so
+    /// this needs logging.file to have any logs.
+    pub daemonize: bool,
+    /// Where the daemon writes its pid when daemonized.
+    pub pidfile: Option<PathBuf>,
+    /// The file mode creation mask the daemon runs with once daemonized.
+    pub umask: u32,
+    /// Who to run as once the privileged resources are open.
+    pub user: Option<String>,
+    /// The group to run as, the user's own group if unset.
+    pub group: Option<String>,
+    /// How long to give plugins and notifiers to finish up on SIGTERM or
+    /// SIGINT before exiting anyway.
+    pub shutdown_timeout_secs: u64,
+    /// Where `hddmond status` asks the daemon how it's doing. No socket if
+    /// unset.
+    pub control_socket: Option<PathBuf>,
+}
+
+impl DaemonConfig {
+    /// shutdown_timeout_secs as a Duration.
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout_secs)
+    }
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        Self {
+            daemonize: false,
+            pidfile: Some(PathBuf::from("/run/hddmond.pid")),
+            umask: 0o022,
+            user: None,
+            group: None,
+            shutdown_timeout_secs: 10,
+            control_socket: Some(PathBuf::from("/run/hddmond.sock")),
+        }
+    }
+}
+
+/// The `[storage]` section.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    /// The SQLite database, or ":memory:" to keep nothing across restarts.
+    pub path: PathBuf,
+    /// Devices that haven't been seen for this many days are forgotten.
+    /// Kept forever if unset.
+    pub prune_absent_after_days: Option<u64>,
+    /// How many of the latest events are kept for each device, 0 for none.
+    pub events_per_device: u32,
+    /// A device lost and found again within this long stays in the same
+    /// session.
+    pub session_grace_secs: u64,
+    /// Hour of the day (local time, 0-23) to do maintenance in.
+    pub maintenance_hour: u8,
+    /// Compact the database once this much of it is free pages.
+    pub vacuum_threshold_percent: u8,
+    /// What devices are told apart by, tried in this order. A device is the
+    /// record the first one it has leads to.
+    pub identity: Vec<IdentityBasis>,
+    /// Serials a bridge reports for every drive behind it, which can't tell
+    /// them apart. Ones two present devices have at once are found without
+    /// being listed here.
+    pub shared_serials: Vec<String>,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("/var/lib/hddmond/hddmond.db"),
+            prune_absent_after_days: None,
+            events_per_device: storage::DEFAULT_EVENT_LIMIT,
+            session_grace_secs: storage::DEFAULT_SESSION_GRACE.as_secs(),
+            maintenance_hour: 3,
+            vacuum_threshold_percent: 20,
+            identity: storage::DEFAULT_IDENTITY.to_vec(),
+            shared_serials: vec![],
+        }
+    }
+}
+
+impl StorageConfig {
+    /// session_grace_secs as a Duration.
+    pub fn session_grace(&self) -> Duration {
+        Duration::from_secs(self.session_grace_secs)
+    }
+}
+
+/// The `[audit]` section.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    /// The append-only JSONL audit trail, none if unset. Rotated daily to
+    /// `<path>.YYYY-MM-DD`.
+    pub path: Option<PathBuf>,
+    /// How often what was written is fsynced, 0 for after every record.
+    pub fsync_interval_secs: u64,
+    /// Have every record carry the SHA-256 of the one before it.
+    pub chain: bool,
+}
+
+impl AuditConfig {
+    /// fsync_interval_secs as a Duration.
+    pub fn fsync_interval(&self) -> Duration {
+        Duration::from_secs(self.fsync_interval_secs)
+    }
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            fsync_interval_secs: 5,
+            chain: true,
+        }
+    }
+}
+
+/// The `[disk_guard]` section.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiskGuardConfig {
+    /// Once a filesystem with the database, the log file or the audit trail
+    /// on it has less than this free, the daemon stops writing what it can
+    /// do without. 0 turns the guard off.
+    pub min_free_mb: u64,
+    /// And starts again once every one of them has this much free, more
+    /// than min_free_mb so it doesn't flap right at the edge.
+    pub resume_free_mb: u64,
+    /// How often the free space is looked at.
+    pub check_interval_secs: u64,
+}
+
+impl DiskGuardConfig {
+    /// check_interval_secs as a Duration.
+    pub fn check_interval(&self) -> Duration {
+        Duration::from_secs(self.check_interval_secs)
+    }
+}
+
+impl Default for DiskGuardConfig {
+    fn default() -> Self {
+        Self {
+            min_free_mb: 256,
+            resume_free_mb: 512,
+            check_interval_secs: 60,
+        }
+    }
+}
+
+/// The `[link_health]` section.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LinkHealthConfig {
+    /// How often the link error counters of every present disk are read.
+    pub check_interval_secs: u64,
+    /// A port's counters growing faster than this, together and measured
+    /// over an hour at least, sends link_errors_growing. 0 sends it for any
+    /// error at all.
+    pub max_errors_per_hour: f64,
+}
+
+impl LinkHealthConfig {
+    /// check_interval_secs as a Duration.
+    pub fn check_interval(&self) -> Duration {
+        Duration::from_secs(self.check_interval_secs)
+    }
+}
+
+impl Default for LinkHealthConfig {
+    fn default() -> Self {
+        Self {
+            check_interval_secs: 600,
+            max_errors_per_hour: 0.0,
+        }
+    }
+}
+
+/// The `[export]` section.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExportConfig {
+    /// Columns of `hddmond export`, in order.
+    pub columns: Vec<Column>,
+}
+
+impl Default for ExportConfig {
+    fn default() -> Self {
+        Self {
+            columns: Column::ALL.to_vec(),
+        }
+    }
+}
+
+/// The `[notifiers]` section.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotifiersConfig {
+    /// How many notifications can wait for each target before new ones are
+    /// dropped.
+    pub queue_size: usize,
+    /// Notifications of the same kind within this long of each other go out
+    /// as one digest. 0 sends each one right away.
+    pub group_window_secs: u64,
+    /// The same thing happening to the same drive again within this long
+    /// isn't notified again. 0 turns this off.
+    pub dedup_window_secs: u64,
+    /// An acknowledged alert is notified about again when it's raised this
+    /// long after it was acknowledged. 0 keeps it quiet until it clears.
+    pub realert_after_secs: u64,
+    /// HTTP endpoints notifications are POSTed to.
+    pub webhooks: Vec<WebhookConfig>,
+    /// Mail servers notifications are sent through.
+    pub emails: Vec<EmailConfig>,
+    /// Slack, Discord and Matrix rooms notifications are posted in.
+    pub chats: Vec<ChatConfig>,
+    /// Structured entries in the journal, or syslog without systemd.
+    pub journald: Option<JournaldConfig>,
+}
+
+impl Default for NotifiersConfig {
+    fn default() -> Self {
+        Self {
+            queue_size: 100,
+            group_window_secs: 10,
+            dedup_window_secs: 5 * 60,
+            realert_after_secs: 7 * 24 * 60 * 60,
+            webhooks: vec![],
+            emails: vec![],
+            chats: vec![],
+            journald: None,
+        }
+    }
+}
+
+/// A `[[notifiers.webhooks]]` entry.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    /// Where notifications are POSTed to.
+    pub url: String,
+    /// The kinds of notification sent here, every kind if unset.
+    #[serde(default = "all_notifications")]
+    pub events: Vec<NotificationKind>,
+    /// Only critical notifications go out during these, the rest wait
+    /// for them to end.
+    #[serde(default)]
+    pub quiet_hours: Option<QuietHours>,
+    /// Signs every request, see webhook::SIGNATURE_HEADER.
+    #[serde(default)]
+    pub secret: Option<Secret>,
+    /// The body, with `{{field}}` placeholders. The notification's JSON if
+    /// unset.
+    #[serde(default)]
+    pub template: Option<String>,
+    /// The Content-Type of every request.
+    #[serde(default = "default_content_type")]
+    pub content_type: String,
+    /// How long a request can take before it counts as failed.
+    #[serde(default = "default_webhook_timeout_ms")]
+    pub timeout_ms: u64,
+    /// How many times a notification is tried before it's given up on.
+    #[serde(default = "default_webhook_max_attempts")]
+    pub max_attempts: u32,
+}
+
+/// A `[[notifiers.emails]]` entry.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EmailConfig {
+    /// The SMTP server's host name.
+    pub server: String,
+    /// The default for the TLS mode if unset.
+    #[serde(default)]
+    pub port: Option<u16>,
+    /// How the connection to the server is encrypted.
+    #[serde(default = "default_email_tls")]
+    pub tls: EmailTls,
+    /// Who to log in as. Nothing is logged in to if unset.
+    #[serde(default)]
+    pub username: Option<String>,
+    /// The password to log in with.
+    #[serde(default)]
+    pub password: Option<Secret>,
+    /// The From address.
+    pub from: String,
+    /// Who every email goes to.
+    pub to: Vec<String>,
+    /// The kinds of notification sent here, every kind if unset.
+    #[serde(default = "all_notifications")]
+    pub events: Vec<NotificationKind>,
+    /// Only critical notifications go out during these, the rest wait
+    /// for them to end.
+    #[serde(default)]
+    pub quiet_hours: Option<QuietHours>,
+    /// At most one email per device and event in this long.
+    #[serde(default = "default_email_rate_limit_secs")]
+    pub rate_limit_secs: u64,
+    /// How long delivering an email can take before it counts as failed.
+    #[serde(default = "default_email_timeout_ms")]
+    pub timeout_ms: u64,
+    /// How many times an email is tried before it's given up on.
+    #[serde(default = "default_email_max_attempts")]
+    pub max_attempts: u32,
+}
+
+fn default_email_tls() -> EmailTls {
+    EmailTls::Starttls
+}
+
+fn default_email_rate_limit_secs() -> u64 {
+    60 * 60
+}
+
+fn default_email_timeout_ms() -> u64 {
+    30_000
+}
+
+fn default_email_max_attempts() -> u32 {
+    3
+}
+
+/// A `[[notifiers.chats]]` entry.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChatConfig {
+    /// Slack, Discord or Matrix.
+    pub kind: ChatKind,
+    /// The webhook URL for Slack and Discord, the homeserver for Matrix.
+    pub url: String,
+    /// Matrix only: the room ID (`!abc:example.org`) and the token of the
+    /// account posting in it.
+    #[serde(default)]
+    pub room: Option<String>,
+    /// Matrix only, see room.
+    #[serde(default)]
+    pub access_token: Option<Secret>,
+    /// Linked from every message, with `{{field}}` placeholders like
+    /// webhook templates, but percent encoded instead of JSON.
+    #[serde(default)]
+    pub dashboard_url: Option<String>,
+    /// The kinds of notification posted here, every kind if unset.
+    #[serde(default = "all_notifications")]
+    pub events: Vec<NotificationKind>,
+    /// Only critical notifications go out during these, the rest wait
+    /// for them to end.
+    #[serde(default)]
+    pub quiet_hours: Option<QuietHours>,
+    /// At most one message per device and event in this long. Not rate
+    /// limited if unset.
+    #[serde(default)]
+    pub rate_limit_secs: Option<u64>,
+    /// How long posting a message can take before it counts as failed.
+    #[serde(default = "default_webhook_timeout_ms")]
+    pub timeout_ms: u64,
+    /// How many times a message is tried before it's given up on.
+    #[serde(default = "default_webhook_max_attempts")]
+    pub max_attempts: u32,
+}
+
+/// The `[notifiers.journald]` section.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JournaldConfig {
+    /// The kinds of notification logged, every kind if unset.
+    #[serde(default = "all_notifications")]
+    pub events: Vec<NotificationKind>,
+    /// Only critical notifications go out during these, the rest wait
+    /// for them to end.
+    #[serde(default)]
+    pub quiet_hours: Option<QuietHours>,
+}
+
+fn all_notifications() -> Vec<NotificationKind> {
+    NotificationKind::ALL.to_vec()
+}
+
+fn default_content_type() -> String {
+    "application/json".to_string()
+}
+
+fn default_webhook_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_webhook_max_attempts() -> u32 {
+    5
+}
+
+impl Config {
+    /// Reads and validates the config at `path`. A missing file isn't an
+    /// error, the daemon runs on defaults then. This can run before logging
+    /// is set up, so it's up to the caller to mention that.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(Self::default());
+            }
+            Err(error) => {
+                return Err(ConfigError::Read {
+                    path: path.to_path_buf(),
+                    error,
+                });
+            }
+        };
+
+        // toml's errors already name the offending key and its line and
+        // column, we only need to add which file it was.
+        let config: Self = toml::from_str(&contents).map_err(|error| ConfigError::Parse {
+            path: path.to_path_buf(),
+            error,
+        })?;
+
+        config.validate().map_err(|e| match e {
+            ConfigError::Invalid { problems, .. } => ConfigError::Invalid {
+                path: Some(path.to_path_buf()),
+                problems,
+            },
+            e => e,
+        })?;
+
+        Ok(config)
+    }
+
+    /// Checks that can't be expressed in the types alone. Reports every
+    /// problem at once rather than one per restart.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = vec![];
+
+        let backend = self.monitor.backend;
+        if matches!(backend, Backend::Auto | Backend::Udev | Backend::Composite)
+            && self.udev.matches.is_empty()
+        {
+            problems.push(format!(
+                "udev.matches can't be empty with the {} backend",
+                backend.as_str()
+            ));
+        }
+        if matches!(backend, Backend::Smartctl | Backend::Composite) {
+            if let Some(path) = self.smartctl.path.as_ref().filter(|path| !path.is_file()) {
+                problems.push(format!(
+                    "smartctl.path {} doesn't exist, but the {} backend needs it",
+                    path.display(),
+                    backend.as_str()
+                ));
+            }
+        }
+        if backend == Backend::Simulated {
+            match &self.monitor.fleet {
+                Some(fleet) if !fleet.is_file() => problems.push(format!(
+                    "monitor.fleet {} doesn't exist, but the simulated backend needs it",
+                    fleet.display()
+                )),
+                Some(_) => {}
+                None => problems
+                    .push("monitor.fleet has to be set with the simulated backend".to_string()),
+            }
+        }
+
+        if let Err(e) = logging::parse_filter(&self.logging.level) {
+            problems.push(format!("logging.level: {}", e));
+        }
+
+        let rules = self
+            .devices
+            .ignore
+            .iter()
+            .enumerate()
+            .map(|(index, rule)| (format!("devices.ignore[{}]", index), rule))
+            .chain(
+                self.devices
+                    .protect
+                    .iter()
+                    .enumerate()
+                    .map(|(index, rule)| (format!("devices.protect[{}]", index), rule)),
+            )
+            .chain(
+                self.power
+                    .policies
+                    .iter()
+                    .enumerate()
+                    .map(|(index, policy)| {
+                        (format!("power.policies[{}].device", index), &policy.device)
+                    }),
+            );
+        for (key, rule) in rules {
+            let DeviceMatch {
+                serial,
+                model,
+                wwn,
+                path,
+            } = rule;
+
+            // An empty rule would match every device.
+            if [serial, model, wwn, path]
+                .iter()
+                .all(|field| field.is_none())
+            {
+                problems.push(format!("{} doesn't match on anything", key));
+            }
+
+            for (field, pattern) in [("model", model), ("path", path)] {
+                if let Some(Err(e)) = pattern.as_deref().map(glob::Pattern::new) {
+                    problems.push(format!("{}.{} isn't a valid pattern: {}", key, field, e));
+                }
+            }
+        }
+
+        if let Some(template) = &self.devices.alias_template {
+            if let Err(e) = Template::parse(template) {
+                problems.push(format!("devices.alias_template: {}", e));
+            }
+        }
+
+        for (index, policy) in self.power.policies.iter().enumerate() {
+            if policy.apm == Some(0) {
+                problems.push(format!(
+                    "power.policies[{}].apm has to be between 1 and 255",
+                    index
+                ));
+            }
+            if let Some(secs) = policy.standby_after_secs {
+                if power::standby_value(secs).is_none() {
+                    problems.push(format!(
+                        "power.policies[{}].standby_after_secs can't be more than {}",
+                        index,
+                        power::MAX_STANDBY_SECS
+                    ));
+                }
+            }
+        }
+
+        if self.daemon.daemonize && self.logging.file.is_none() {
+            problems.push(
+                "daemon.daemonize needs logging.file, stderr goes nowhere once daemonized"
+                    .to_string(),
+            );
+        }
+        if self.daemon.umask > 0o777 {
+            problems.push(format!(
+                "daemon.umask {:#o} isn't a umask",
+                self.daemon.umask
+            ));
+        }
+
+        if let Some(file) = &self.logging.file {
+            if file.max_size_mb == 0 {
+                problems.push("logging.file.max_size_mb must be greater than 0".to_string());
+            }
+        }
+
+        if self.storage.maintenance_hour > 23 {
+            problems.push(format!(
+                "storage.maintenance_hour must be between 0 and 23, not {}",
+                self.storage.maintenance_hour
+            ));
+        }
+        if self.storage.vacuum_threshold_percent > 100 {
+            problems.push(format!(
+                "storage.vacuum_threshold_percent must be at most 100, not {}",
+                self.storage.vacuum_threshold_percent
+            ));
+        }
+
+        for (index, target) in self.notifiers.webhooks.iter().enumerate() {
+            if let Err(e) = webhook::parse_url(&target.url) {
+                problems.push(format!("notifiers.webhooks[{}]: {}", index, e));
+            }
+            if let Some(template) = &target.template {
+                if let Err(e) = Template::parse(template) {
+                    problems.push(format!("notifiers.webhooks[{}]: {}", index, e));
+                }
+            }
+            if target.events.is_empty() {
+                problems.push(format!(
+                    "notifiers.webhooks[{}].events can't be empty",
+                    index
+                ));
+            }
+            if target.timeout_ms == 0 || target.max_attempts == 0 {
+                problems.push(format!(
+                    "notifiers.webhooks[{}].timeout_ms and max_attempts must be greater than 0",
+                    index
+                ));
+            }
+        }
+
+        for (index, target) in self.notifiers.emails.iter().enumerate() {
+            if let Err(e) = email::parse_addresses(target) {
+                problems.push(format!("notifiers.emails[{}]: {:#}", index, e));
+            }
+            if target.to.is_empty() {
+                problems.push(format!("notifiers.emails[{}].to can't be empty", index));
+            }
+            if target.events.is_empty() {
+                problems.push(format!("notifiers.emails[{}].events can't be empty", index));
+            }
+            if target.password.is_some() && target.username.is_none() {
+                problems.push(format!(
+                    "notifiers.emails[{}].password needs a username",
+                    index
+                ));
+            }
+            if target.timeout_ms == 0 || target.max_attempts == 0 {
+                problems.push(format!(
+                    "notifiers.emails[{}].timeout_ms and max_attempts must be greater than 0",
+                    index
+                ));
+            }
+        }
+
+        for (index, target) in self.notifiers.chats.iter().enumerate() {
+            if let Err(e) = webhook::parse_url(&target.url) {
+                problems.push(format!("notifiers.chats[{}]: {}", index, e));
+            }
+            if let Some(template) = &target.dashboard_url {
+                if let Err(e) = Template::parse(template) {
+                    problems.push(format!("notifiers.chats[{}]: {}", index, e));
+                }
+            }
+            let matrix = target.kind == ChatKind::Matrix;
+            if matrix != (target.room.is_some() && target.access_token.is_some()) {
+                problems.push(format!(
+                    "notifiers.chats[{}]: room and access_token are needed for matrix, and only for matrix",
+                    index
+                ));
+            }
+            if target.events.is_empty() {
+                problems.push(format!("notifiers.chats[{}].events can't be empty", index));
+            }
+            if target.timeout_ms == 0 || target.max_attempts == 0 {
+                problems.push(format!(
+                    "notifiers.chats[{}].timeout_ms and max_attempts must be greater than 0",
+                    index
+                ));
+            }
+        }
+
+        if let Some(journald) = &self.notifiers.journald {
+            if !cfg!(feature = "journald") {
+                problems.push(
+                    "notifiers.journald is set, but hddmond was built without the journald feature"
+                        .to_string(),
+                );
+            }
+            if journald.events.is_empty() {
+                problems.push("notifiers.journald.events can't be empty".to_string());
+            }
+        }
+
+        if self.export.columns.is_empty() {
+            problems.push("export.columns can't be empty".to_string());
+        }
+        if self.storage.identity.is_empty() {
+            problems
+                .push("storage.identity can't be empty, no device would be recorded".to_string());
+        }
+
+        let nonzero = [
+            ("udev.poll_interval_ms", self.udev.poll_interval_ms),
+            ("monitor.queue_size", self.monitor.queue_size as u64),
+            ("identify.max_parallel", self.identify.max_parallel as u64),
+            (
+                "identify.per_controller",
+                self.identify.per_controller as u64,
+            ),
+            ("identify.per_bus.usb", self.identify.per_bus.usb as u64),
+            ("identify.per_bus.sata", self.identify.per_bus.sata as u64),
+            ("identify.per_bus.sas", self.identify.per_bus.sas as u64),
+            ("identify.per_bus.nvme", self.identify.per_bus.nvme as u64),
+            ("identify.per_bus.other", self.identify.per_bus.other as u64),
+            ("notifiers.queue_size", self.notifiers.queue_size as u64),
+            (
+                "smartctl.scan_interval_secs",
+                self.smartctl.scan_interval_secs,
+            ),
+            (
+                "plugin_host.reload_interval_secs",
+                self.plugin_host.reload_interval_secs,
+            ),
+            (
+                "plugin_host.hook_timeout_ms",
+                self.plugin_host.hook_timeout_ms,
+            ),
+            (
+                "plugin_host.max_heap_mb",
+                self.plugin_host.max_heap_mb as u64,
+            ),
+            (
+                "plugin_host.max_pending_ops",
+                self.plugin_host.max_pending_ops as u64,
+            ),
+            (
+                "plugin_host.max_violations",
+                self.plugin_host.max_violations as u64,
+            ),
+            (
+                "disk_guard.check_interval_secs",
+                self.disk_guard.check_interval_secs,
+            ),
+            (
+                "link_health.check_interval_secs",
+                self.link_health.check_interval_secs,
+            ),
+        ];
+        for (key, value) in nonzero {
+            if value == 0 {
+                problems.push(format!("{} must be greater than 0", key));
+            }
+        }
+
+        if self.disk_guard.resume_free_mb < self.disk_guard.min_free_mb {
+            problems.push(format!(
+                "disk_guard.resume_free_mb can't be less than disk_guard.min_free_mb ({})",
+                self.disk_guard.min_free_mb
+            ));
+        }
+        let max_errors_per_hour = self.link_health.max_errors_per_hour;
+        if max_errors_per_hour.is_nan() || max_errors_per_hour < 0.0 {
+            problems.push("link_health.max_errors_per_hour must be 0 or more".to_string());
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid {
+                path: None,
+                problems,
+            })
+        }
+    }
+
+    /// Resolves the relative paths in the config against `base`, so they
+    /// keep pointing at the same place after daemonizing moves us to /.
+    pub fn make_paths_absolute(&mut self, base: &Path) {
+        if let Some(fleet) = &mut self.monitor.fleet {
+            make_absolute(fleet, base);
+        }
+        make_absolute(&mut self.plugin_host.dir, base);
+        if let Some(file) = &mut self.logging.file {
+            make_absolute(&mut file.path, base);
+        }
+        if let Some(pidfile) = &mut self.daemon.pidfile {
+            make_absolute(pidfile, base);
+        }
+        if let Some(socket) = &mut self.daemon.control_socket {
+            make_absolute(socket, base);
+        }
+        if self.storage.path != Path::new(storage::IN_MEMORY) {
+            make_absolute(&mut self.storage.path, base);
+        }
+        if let Some(audit) = &mut self.audit.path {
+            make_absolute(audit, base);
+        }
+    }
+
+    /// Settings that are only read at startup, by their name in the config
+    /// file, that differ between `self` and `new`. Everything else can be
+    /// applied to the running daemon.
+    pub fn restart_required(&self, new: &Config) -> Vec<&'static str> {
+        let mut keys = vec![];
+
+        if self.monitor != new.monitor {
+            keys.push("monitor");
+        }
+        // The poll intervals are set on the running monitor.
+        if self.udev.matches != new.udev.matches {
+            keys.push("udev.matches");
+        }
+        if self.smartctl.path != new.smartctl.path {
+            keys.push("smartctl.path");
+        }
+        if self.identify != new.identify {
+            keys.push("identify");
+        }
+        if self.devices != new.devices {
+            keys.push("devices");
+        }
+        if self.power != new.power {
+            keys.push("power");
+        }
+        if self.plugin_host.dir != new.plugin_host.dir {
+            keys.push("plugin_host.dir");
+        }
+        if self.logging.format != new.logging.format {
+            keys.push("logging.format");
+        }
+        if self.logging.file != new.logging.file {
+            keys.push("logging.file");
+        }
+        if self.daemon != new.daemon {
+            keys.push("daemon");
+        }
+        if self.storage != new.storage {
+            keys.push("storage");
+        }
+        if self.audit != new.audit {
+            keys.push("audit");
+        }
+        if self.disk_guard != new.disk_guard {
+            keys.push("disk_guard");
+        }
+        if self.link_health != new.link_health {
+            keys.push("link_health");
+        }
+        if self.notifiers != new.notifiers {
+            keys.push("notifiers");
+        }
+
+        keys
+    }
+}
+
+/// Makes a relative `path` relative to `base` instead.
+pub fn make_absolute(path: &mut PathBuf, base: &Path) {
+    if path.is_relative() {
+        *path = base.join(&path);
+    }
+}
+
+/// Modification time of the config file, used to notice when it needs to be
+/// loaded again. None if the file doesn't exist.
+pub fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    const EXAMPLE: &str = include_str!("../examples/config.toml");
+    const FULL: &str = include_str!("../tests/fixtures/config/full.toml");
+
+    fn parse(text: &str) -> Config {
+        toml::from_str(text).unwrap()
+    }
+
+    // The problems validate() finds in `text`, in order.
+    fn problems(text: &str) -> Vec<String> {
+        match parse(text).validate() {
+            Ok(()) => vec![],
+            Err(e) => e.to_string().split("; ").map(str::to_string).collect(),
+        }
+    }
+
+    #[test]
+    fn example_config_is_the_defaults() {
+        let config = parse(EXAMPLE);
+        config.validate().unwrap();
+
+        // Apart from the example plugin's settings, everything it sets is
+        // what you get without it.
+        assert_eq!(
+            config.plugins["log_devices"].to_json(),
+            serde_json::json!({ "greeting": "hello" })
+        );
+        let expected = Config {
+            plugins: config.plugins.clone(),
+            ..Config::default()
+        };
+        assert_eq!(config, expected);
+    }
+
+    #[test]
+    fn empty_config_is_the_defaults() {
+        assert_eq!(parse(""), Config::default());
+        Config::default().validate().unwrap();
+    }
+
+    #[test]
+    fn full_config_sets_everything() {
+        let config = parse(FULL);
+        config.validate().unwrap();
+        assert_ne!(config.restart_required(&Config::default()).len(), 0);
+
+        assert_eq!(config.monitor.backend, Backend::Udev);
+        assert_eq!(config.monitor.queue_size, 64);
+        assert!(config.monitor.rescan_scsi_hosts);
+        assert_eq!(config.udev.matches[1].devtype, None);
+        assert_eq!(config.udev.poll_interval(), Duration::from_millis(250));
+        assert_eq!(config.smartctl.scan_interval(), Duration::from_secs(30));
+        assert_eq!(config.identify.per_bus.get(BusClass::Nvme), 4);
+        assert_eq!(config.identify.per_controller, 3);
+
+        assert!(!config.devices.protect_root);
+        assert_eq!(
+            config.devices.ignore,
+            [DeviceMatch {
+                path: Some("/dev/disk/by-id/usb-*".to_string()),
+                ..Default::default()
+            }]
+        );
+        assert_eq!(
+            config.devices.protect[1].wwn.as_deref(),
+            Some("0x50014ee2b5e7a1f3")
+        );
+        assert_eq!(config.power.policies[0].standby_after_secs, Some(1800));
+        assert_eq!(config.power.policies[1].apm, Some(255));
+
+        let limits = config.plugin_host.limits();
+        assert_eq!(limits.hook_timeout, Duration::from_millis(1500));
+        assert_eq!(limits.max_heap_bytes, 32 * 1024 * 1024);
+        assert_eq!(limits.max_violations, 9);
+        assert_eq!(config.plugin_host.reload_interval(), Duration::from_secs(7));
+
+        assert_eq!(config.logging.format, LogFormat::Json);
+        let file = config.logging.file.as_ref().unwrap();
+        assert_eq!(file.max_size_bytes(), 50 * 1024 * 1024);
+        assert!(file.compress);
+
+        assert!(config.daemon.daemonize);
+        assert_eq!(config.daemon.umask, 0o077);
+        assert_eq!(config.daemon.user.as_deref(), Some("hddmond"));
+        assert_eq!(config.daemon.shutdown_timeout(), Duration::from_secs(30));
+
+        assert_eq!(config.storage.path, Path::new(storage::IN_MEMORY));
+        assert_eq!(config.storage.prune_absent_after_days, Some(730));
+        assert_eq!(config.storage.session_grace(), Duration::from_secs(5));
+        assert_eq!(
+            config.storage.identity,
+            [IdentityBasis::SerialModel, IdentityBasis::Wwn]
+        );
+        assert_eq!(config.audit.fsync_interval(), Duration::ZERO);
+        assert!(!config.audit.chain);
+        assert_eq!(config.disk_guard.check_interval(), Duration::from_secs(15));
+        assert_eq!(config.link_health.max_errors_per_hour, 2.5);
+        assert_eq!(config.export.columns.len(), 2);
+
+        let notifiers = &config.notifiers;
+        assert_eq!(notifiers.realert_after_secs, 0);
+        let webhook = &notifiers.webhooks[0];
+        assert_eq!(
+            webhook.events,
+            [NotificationKind::DeviceFound, NotificationKind::DeviceLost]
+        );
+        assert_eq!(format!("{:?}", webhook.quiet_hours), "Some(22:00-07:00)");
+        assert_eq!(webhook.secret.as_ref().unwrap().expose(), "webhook secret");
+        assert_eq!(webhook.content_type, "text/plain");
+        let email = &notifiers.emails[0];
+        assert_eq!(email.tls, EmailTls::Tls);
+        assert_eq!(email.port, Some(465));
+        assert_eq!(email.password.as_ref().unwrap().expose(), "smtp password");
+        assert_eq!(email.to.len(), 2);
+        assert_eq!(notifiers.chats[0].kind, ChatKind::Discord);
+        assert_eq!(notifiers.chats[0].rate_limit_secs, Some(120));
+        // Left out, so the defaults.
+        let matrix = &notifiers.chats[1];
+        assert_eq!(matrix.events, NotificationKind::ALL);
+        assert_eq!((matrix.timeout_ms, matrix.max_attempts), (5000, 5));
+        assert_eq!(
+            notifiers.journald.as_ref().unwrap().events,
+            [
+                NotificationKind::DeviceBecameReadOnly,
+                NotificationKind::LinkErrorsGrowing
+            ]
+        );
+    }
+
+    #[test]
+    fn secrets_stay_out_of_debug_output() {
+        let debug = format!("{:?}", parse(FULL));
+        for secret in ["webhook secret", "smtp password", "matrix token"] {
+            assert!(!debug.contains(secret), "{} is in {}", secret, debug);
+        }
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        for text in [
+            "[monitor]\nbackedn = \"udev\"",
+            "[mointor]\nbackend = \"udev\"",
+            "[[devices.ignore]]\nserail = \"X\"",
+            "[[notifiers.webhooks]]\nurl = \"https://example.com\"\nsecert = \"x\"",
+        ] {
+            assert!(toml::from_str::<Config>(text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn invalid_values_dont_deserialize() {
+        for text in [
+            "[monitor]\nbackend = \"scsi\"",
+            "[logging]\nformat = \"xml\"",
+            "[[notifiers.webhooks]]\nurl = \"https://example.com\"\nquiet_hours = \"22:00\"",
+            "[[notifiers.webhooks]]\nurl = \"https://example.com\"\nquiet_hours = \"07:00-07:00\"",
+            "[[notifiers.webhooks]]\nurl = \"https://example.com\"\nevents = [\"device_exploded\"]",
+            "[storage]\nidentity = [\"uuid\"]",
+            "[[power.policies]]\ndevice = { model = \"X\" }\napm = 256",
+        ] {
+            assert!(toml::from_str::<Config>(text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn cross_field_checks() {
+        let cases = [
+            (
+                "[monitor]\nbackend = \"udev\"\n[udev]\nmatches = []",
+                "udev.matches can't be empty with the udev backend",
+            ),
+            (
+                "[monitor]\nbackend = \"smartctl\"\n[smartctl]\npath = \"/nonexistent/smartctl\"",
+                "smartctl.path /nonexistent/smartctl doesn't exist, but the smartctl backend needs it",
+            ),
+            (
+                "[monitor]\nbackend = \"composite\"\n[smartctl]\npath = \"/nonexistent/smartctl\"",
+                "smartctl.path /nonexistent/smartctl doesn't exist, but the composite backend needs it",
+            ),
+            (
+                "[monitor]\nbackend = \"composite\"\n[udev]\nmatches = []",
+                "udev.matches can't be empty with the composite backend",
+            ),
+            (
+                "[monitor]\nbackend = \"simulated\"",
+                "monitor.fleet has to be set with the simulated backend",
+            ),
+            (
+                "[monitor]\nbackend = \"simulated\"\nfleet = \"/nonexistent/fleet.toml\"",
+                "monitor.fleet /nonexistent/fleet.toml doesn't exist, but the simulated backend needs it",
+            ),
+            (
+                "[daemon]\ndaemonize = true",
+                "daemon.daemonize needs logging.file, stderr goes nowhere once daemonized",
+            ),
+            ("[daemon]\numask = 0o1777", "daemon.umask 0o1777 isn't a umask"),
+            (
+                "[logging.file]\nmax_size_mb = 0",
+                "logging.file.max_size_mb must be greater than 0",
+            ),
+            (
+                "[[devices.ignore]]\n",
+                "devices.ignore[0] doesn't match on anything",
+            ),
+            (
+                "[[devices.protect]]\nmodel = \"WDC[\"",
+                "devices.protect[0].model isn't a valid pattern",
+            ),
+            (
+                "[[power.policies]]\ndevice = {}\napm = 1",
+                "power.policies[0].device doesn't match on anything",
+            ),
+            (
+                "[[power.policies]]\ndevice = { model = \"X\" }\napm = 0",
+                "power.policies[0].apm has to be between 1 and 255",
+            ),
+            (
+                "[[power.policies]]\ndevice = { model = \"X\" }\nstandby_after_secs = 19801",
+                "power.policies[0].standby_after_secs can't be more than 19800",
+            ),
+            (
+                "[storage]\nmaintenance_hour = 24",
+                "storage.maintenance_hour must be between 0 and 23, not 24",
+            ),
+            (
+                "[storage]\nvacuum_threshold_percent = 101",
+                "storage.vacuum_threshold_percent must be at most 100, not 101",
+            ),
+            (
+                "[storage]\nidentity = []",
+                "storage.identity can't be empty, no device would be recorded",
+            ),
+            ("[export]\ncolumns = []", "export.columns can't be empty"),
+            (
+                "[disk_guard]\nmin_free_mb = 512\nresume_free_mb = 256",
+                "disk_guard.resume_free_mb can't be less than disk_guard.min_free_mb (512)",
+            ),
+            (
+                "[link_health]\nmax_errors_per_hour = -1.0",
+                "link_health.max_errors_per_hour must be 0 or more",
+            ),
+            (
+                "[link_health]\nmax_errors_per_hour = nan",
+                "link_health.max_errors_per_hour must be 0 or more",
+            ),
+            (
+                "[[notifiers.webhooks]]\nurl = \"https://example.com\"\nevents = []",
+                "notifiers.webhooks[0].events can't be empty",
+            ),
+            (
+                "[[notifiers.webhooks]]\nurl = \"https://example.com\"\nmax_attempts = 0",
+                "notifiers.webhooks[0].timeout_ms and max_attempts must be greater than 0",
+            ),
+            (
+                "[[notifiers.emails]]\nserver = \"s\"\nfrom = \"a@example.com\"\nto = []",
+                "notifiers.emails[0].to can't be empty",
+            ),
+            (
+                "[[notifiers.emails]]\nserver = \"s\"\nfrom = \"not an address\"\nto = [\"a@example.com\"]",
+                "notifiers.emails[0]: Invalid from address not an address",
+            ),
+            (
+                "[[notifiers.emails]]\nserver = \"s\"\nfrom = \"a@example.com\"\nto = [\"a@example.com\"]\npassword = \"p\"",
+                "notifiers.emails[0].password needs a username",
+            ),
+            (
+                "[[notifiers.chats]]\nkind = \"matrix\"\nurl = \"https://matrix.example.org\"\nroom = \"!a:example.org\"",
+                "notifiers.chats[0]: room and access_token are needed for matrix, and only for matrix",
+            ),
+            (
+                "[[notifiers.chats]]\nkind = \"slack\"\nurl = \"https://hooks.slack.com/x\"\nroom = \"!a:example.org\"\naccess_token = \"t\"",
+                "notifiers.chats[0]: room and access_token are needed for matrix, and only for matrix",
+            ),
+            (
+                "[notifiers.journald]\nevents = []",
+                "notifiers.journald.events can't be empty",
+            ),
+            (
+                "[identify.per_bus]\nsas = 0",
+                "identify.per_bus.sas must be greater than 0",
+            ),
+            (
+                "[plugin_host]\nhook_timeout_ms = 0",
+                "plugin_host.hook_timeout_ms must be greater than 0",
+            ),
+        ];
+
+        for (text, problem) in cases {
+            let found = problems(text);
+            assert!(
+                found.iter().any(|found| found.starts_with(problem)),
+                "{:?} doesn't have {:?} for\n{}",
+                found,
+                problem,
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn every_problem_is_reported_at_once() {
+        let found = problems(
+            "[storage]\nmaintenance_hour = 24\nidentity = []\n[export]\ncolumns = []\n\
+             [smartctl]\nscan_interval_secs = 0",
+        );
+        assert_eq!(
+            found,
+            [
+                "storage.maintenance_hour must be between 0 and 23, not 24",
+                "export.columns can't be empty",
+                "storage.identity can't be empty, no device would be recorded",
+                "smartctl.scan_interval_secs must be greater than 0",
+            ]
+        );
+    }
+
+    #[test]
+    fn load_names_the_file() {
+        let dir = TestDir::new("config");
+
+        // Missing is the defaults.
+        assert_eq!(
+            Config::load(&dir.join("missing.toml")).unwrap(),
+            Config::default()
+        );
+
+        let path = dir.join("bad.toml");
+        fs::write(&path, "[storage]\nmaintenance_hour = 30\n").unwrap();
+        let e = format!("{:#}", Config::load(&path).unwrap_err());
+        assert!(e.starts_with(&format!("Invalid config in {}", path.display())));
+        assert!(e.contains("storage.maintenance_hour"));
+
+        fs::write(&path, "[storage\n").unwrap();
+        let e = format!("{:#}", Config::load(&path).unwrap_err());
+        assert!(e.starts_with(&format!("Invalid config in {}", path.display())));
+    }
+
+    #[test]
+    fn restart_required_only_names_startup_settings() {
+        let config = Config::default();
+        let mut new = config.clone();
+        new.logging.level = "debug".to_string();
+        new.plugin_host.hook_timeout_ms = 1;
+        new.plugins
+            .insert("log_devices".to_string(), PluginConfig::default());
+        assert!(config.restart_required(&new).is_empty());
+
+        new.udev.poll_interval_ms = 5;
+        new.smartctl.scan_interval_secs = 60;
+        assert!(config.restart_required(&new).is_empty());
+
+        new.udev.matches.clear();
+        new.smartctl.path = Some(PathBuf::from("/opt/smartctl"));
+        new.plugin_host.dir = PathBuf::from("elsewhere");
+        new.storage.events_per_device = 1;
+        assert_eq!(
+            config.restart_required(&new),
+            [
+                "udev.matches",
+                "smartctl.path",
+                "plugin_host.dir",
+                "storage"
+            ]
+        );
+    }
+
+    #[test]
+    fn relative_paths_are_made_absolute() {
+        let mut config = parse(
+            "[monitor]\nfleet = \"fleet.toml\"\n[plugin_host]\ndir = \"plugins\"\n\
+             [storage]\npath = \":memory:\"\n[audit]\npath = \"/var/log/audit.jsonl\"",
+        );
+        config.make_paths_absolute(Path::new("/etc/hddmond"));
+
+        assert_eq!(
+            config.monitor.fleet.as_deref(),
+            Some(Path::new("/etc/hddmond/fleet.toml"))
+        );
+        assert_eq!(config.plugin_host.dir, Path::new("/etc/hddmond/plugins"));
+        assert_eq!(config.storage.path, Path::new(":memory:"));
+        assert_eq!(
+            config.audit.path.as_deref(),
+            Some(Path::new("/var/log/audit.jsonl"))
+        );
+    }
+}
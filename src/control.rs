@@ -0,0 +1,289 @@
+use std::{
+    fs,
+    io::{self, Read, Write},
+    os::unix::{
+        fs::{FileTypeExt, PermissionsExt},
+        net::UnixStream as StdUnixStream,
+    },
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+    time::timeout,
+};
+
+use crate::error::ControlError;
+
+// How long a client gets to send its command, so one that connects and
+// says nothing doesn't hold a task forever.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A command from a client, waiting for whoever owns the daemon's state to
+/// answer it.
+pub struct ControlRequest {
+    /// What the client sent, like `status` or `quarantine sdb`.
+    pub command: String,
+    /// Where the answer goes, JSON.
+    pub reply: oneshot::Sender<String>,
+}
+
+/// The daemon's end of the control socket. Clients send one command per
+/// connection, a line like `status`, and get one JSON document back before
+/// the connection is closed. Commands are handed over as ControlRequests so
+/// the main loop can answer them from the state it already owns.
+pub struct ControlSocket {
+    path: PathBuf,
+    task: JoinHandle<()>,
+}
+
+impl ControlSocket {
+    /// Has to be called from within the tokio runtime, and before dropping
+    /// privileges if the socket lives somewhere only root can write.
+    pub fn bind(path: &Path) -> Result<(Self, mpsc::Receiver<ControlRequest>), ControlError> {
+        remove_stale(path)?;
+
+        let listener = UnixListener::bind(path).map_err(socket("listen on", path))?;
+        // Owner and group only, the socket can tell anyone who connects about
+        // every drive on the machine.
+        fs::set_permissions(path, fs::Permissions::from_mode(0o660))
+            .map_err(socket("set the permissions of", path))?;
+
+        let (sender, receiver) = mpsc::channel(16);
+        let task = tokio::spawn(accept_loop(listener, sender));
+
+        Ok((
+            Self {
+                path: path.to_path_buf(),
+                task,
+            },
+            receiver,
+        ))
+    }
+}
+
+// A socket left behind by a daemon that didn't get to clean up is removed,
+// as long as nothing answers on it. One something's listening on belongs to
+// someone else, and taking the path would leave them unreachable.
+fn remove_stale(path: &Path) -> Result<(), ControlError> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(socket("look at", path)(e)),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(ControlError::NotASocket {
+            path: path.to_path_buf(),
+        });
+    }
+
+    match StdUnixStream::connect(path) {
+        Ok(_) => {
+            return Err(ControlError::InUse {
+                path: path.to_path_buf(),
+            })
+        }
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            warn!("Removing stale socket {}", path.display());
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(socket("tell whether anything listens on", path)(e)),
+    }
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            Err(socket("remove stale socket", path)(e))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn socket<'a>(
+    operation: &'static str,
+    path: &'a Path,
+) -> impl FnOnce(io::Error) -> ControlError + 'a {
+    move |error| ControlError::Socket {
+        operation,
+        path: path.to_path_buf(),
+        error,
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        self.task.abort();
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+async fn accept_loop(listener: UnixListener, sender: mpsc::Sender<ControlRequest>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(handle_connection(stream, sender.clone()));
+            }
+            Err(e) => {
+                warn!("Failed to accept a control connection: {}", e);
+                // Usually out of file descriptors, give it a moment.
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+async fn handle_connection(stream: UnixStream, sender: mpsc::Sender<ControlRequest>) {
+    let (reader, mut writer) = stream.into_split();
+
+    let mut command = String::new();
+    let mut reader = BufReader::new(reader);
+    match timeout(REQUEST_TIMEOUT, reader.read_line(&mut command)).await {
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) => {
+            debug!("Failed to read a control command: {}", e);
+            return;
+        }
+        Err(_) => {
+            debug!("Control client didn't send a command in time");
+            return;
+        }
+    }
+
+    let (reply, response) = oneshot::channel();
+    let request = ControlRequest {
+        command: command.trim().to_string(),
+        reply,
+    };
+    if sender.send(request).await.is_err() {
+        // The daemon is shutting down.
+        return;
+    }
+
+    if let Ok(response) = response.await {
+        if let Err(e) = writer.write_all(response.as_bytes()).await {
+            debug!("Failed to answer a control command: {}", e);
+        }
+        let _ = writer.shutdown().await;
+    }
+}
+
+/// The client's end, for the `hddmond status` and such commands. Sends
+/// `command` and returns the daemon's answer.
+pub fn query(path: &Path, command: &str) -> Result<String, ControlError> {
+    query_waiting(path, command, REQUEST_TIMEOUT * 2)
+}
+
+/// For commands that take the daemon a while to answer.
+pub fn query_waiting(path: &Path, command: &str, wait: Duration) -> Result<String, ControlError> {
+    let mut stream = StdUnixStream::connect(path).map_err(|error| ControlError::Connect {
+        path: path.to_path_buf(),
+        error,
+    })?;
+
+    let mut response = String::new();
+    stream
+        .set_read_timeout(Some(wait))
+        .and_then(|()| writeln!(stream, "{}", command))
+        .and_then(|()| stream.read_to_string(&mut response))
+        .map_err(|error| ControlError::Query {
+            path: path.to_path_buf(),
+            error,
+        })?;
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixListener as StdUnixListener;
+
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    // Answers every command with itself, until `commands` have been.
+    fn echo(mut receiver: mpsc::Receiver<ControlRequest>, commands: usize) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            for _ in 0..commands {
+                let request = receiver.recv().await.unwrap();
+                let _ = request.reply.send(request.command);
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn a_stale_socket_is_taken_over() {
+        let dir = TestDir::new("control-stale");
+        let path = dir.join("hddmond.sock");
+        // What a daemon that was killed leaves behind: the socket file with
+        // nobody listening on it.
+        drop(StdUnixListener::bind(&path).unwrap());
+        assert!(fs::symlink_metadata(&path).unwrap().file_type().is_socket());
+
+        let (socket, receiver) = ControlSocket::bind(&path).unwrap();
+        let answered = echo(receiver, 1);
+        let query = tokio::task::spawn_blocking({
+            let path = path.clone();
+            move || query(&path, "status")
+        });
+        assert_eq!(query.await.unwrap().unwrap(), "status");
+        answered.await.unwrap();
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o660
+        );
+
+        drop(socket);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn a_socket_someone_listens_on_is_left_alone() {
+        let dir = TestDir::new("control-live");
+        let path = dir.join("hddmond.sock");
+        let _listener = StdUnixListener::bind(&path).unwrap();
+
+        let error = ControlSocket::bind(&path).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            format!("Something is already listening on {}", path.display())
+        );
+        assert!(path.exists());
+    }
+
+    #[tokio::test]
+    async fn a_file_that_isnt_a_socket_is_left_alone() {
+        let dir = TestDir::new("control-not-a-socket");
+        let path = dir.join("hddmond.sock");
+        fs::write(&path, "notes").unwrap();
+
+        let error = ControlSocket::bind(&path).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "{} is there and isn't a socket, not removing it",
+                path.display()
+            )
+        );
+        assert_eq!(fs::read_to_string(&path).unwrap(), "notes");
+    }
+
+    #[test]
+    fn nothing_to_clean_up() {
+        let dir = TestDir::new("control-nothing");
+        remove_stale(&dir.join("hddmond.sock")).unwrap();
+    }
+
+    #[test]
+    fn no_daemon_to_query() {
+        let dir = TestDir::new("control-no-daemon");
+        let path = dir.join("hddmond.sock");
+        let error = query(&path, "status").unwrap_err();
+        assert!(matches!(error, ControlError::Connect { .. }));
+        assert!(error.to_string().starts_with(&format!(
+            "Can't connect to {}, is hddmond running?",
+            path.display()
+        )));
+    }
+}
@@ -0,0 +1,417 @@
+use std::{
+    ffi::{CString, OsString},
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    os::unix::{
+        fs::{FileExt, OpenOptionsExt},
+        io::AsRawFd,
+    },
+    path::{Path, PathBuf},
+    process,
+};
+
+use nix::{
+    errno::Errno,
+    fcntl::{flock, FlockArg},
+    sys::{
+        signal::kill,
+        stat::{umask, Mode},
+    },
+    unistd::{self, fork, setsid, ForkResult, Gid, Group, Pid, Uid, User},
+};
+
+use crate::{
+    config::{DaemonConfig, StorageConfig},
+    error::DaemonError,
+    storage,
+};
+
+/// Classic double fork daemonization: detaches from the terminal and the
+/// session, moves to / and points stdio at /dev/null. Has to run before any
+/// threads are started, which means before the tokio runtime exists.
+///
+/// The pidfile is checked before forking so "already running" still makes it
+/// to the terminal, and written by the final process. There's no logger yet,
+/// so a stale one being replaced is left to the caller to tell, see
+/// PidFile::replaced_stale.
+pub fn daemonize(config: &DaemonConfig) -> Result<Option<PidFile>, DaemonError> {
+    let stale = match &config.pidfile {
+        Some(path) => PidFile::check(path)?,
+        None => false,
+    };
+
+    // Safe as long as there's only one thread, see above.
+    if let ForkResult::Parent { .. } = unsafe { fork() }.map_err(system("fork"))? {
+        process::exit(0);
+    }
+
+    setsid().map_err(system("start a new session"))?;
+
+    // Fork again so we're no longer a session leader and can never get a
+    // controlling terminal back.
+    if let ForkResult::Parent { .. } = unsafe { fork() }.map_err(system("fork"))? {
+        process::exit(0);
+    }
+
+    unistd::chdir("/").map_err(system("change to /"))?;
+    umask(Mode::from_bits_truncate(config.umask));
+
+    let null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")
+        .map_err(file("open", Path::new("/dev/null")))?;
+    for fd in [0, 1, 2] {
+        unistd::dup2(null.as_raw_fd(), fd).map_err(system("point stdio at /dev/null"))?;
+    }
+
+    let mut pidfile = config.pidfile.as_deref().map(PidFile::create).transpose()?;
+    if let Some(pidfile) = &mut pidfile {
+        pidfile.replaced_stale = stale;
+    }
+    Ok(pidfile)
+}
+
+/// Removes itself when dropped, which covers every ordinary exit.
+pub struct PidFile {
+    path: PathBuf,
+    replaced_stale: bool,
+}
+
+impl PidFile {
+    // Fails if the pidfile belongs to a running process. A pidfile left
+    // behind by a process that's gone is removed, and says so by returning
+    // true.
+    fn check(path: &Path) -> Result<bool, DaemonError> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(file("read", path)(e)),
+        };
+
+        if let Ok(pid) = contents.trim().parse::<i32>() {
+            // Signal 0 only checks whether the process exists.
+            if kill(Pid::from_raw(pid), None).is_ok() {
+                return Err(DaemonError::AlreadyRunning {
+                    pid,
+                    pidfile: path.to_path_buf(),
+                });
+            }
+        }
+
+        fs::remove_file(path).map_err(file("remove", path))?;
+        Ok(true)
+    }
+
+    /// Whether it took the place of one a process that's gone left behind.
+    pub fn replaced_stale(&self) -> bool {
+        self.replaced_stale
+    }
+
+    fn create(path: &Path) -> Result<Self, DaemonError> {
+        // create_new, so two daemons racing past check() can't both win.
+        let mut pidfile = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o644)
+            .open(path)
+            .map_err(file("create pidfile", path))?;
+        writeln!(pidfile, "{}", process::id()).map_err(file("write to pidfile", path))?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            replaced_stale: false,
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Failed to remove pidfile {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Keeps a second daemon from running against the same registry: the udev
+/// monitor would run twice, and both would be probing the same drives. A
+/// lock on a file next to the database, held for as long as the daemon
+/// runs. Unlike the pidfile, the kernel lets go of it however the daemon
+/// exits, so there's never a stale one to clean up, and it's held across
+/// daemonize()'s forks.
+pub struct InstanceLock {
+    file: File,
+}
+
+impl InstanceLock {
+    /// Next to the database, like hddmond.db.lock. None for a database in
+    /// memory, two of those can't get in each other's way.
+    pub fn path(storage: &StorageConfig) -> Option<PathBuf> {
+        if storage.path == Path::new(storage::IN_MEMORY) {
+            return None;
+        }
+        let mut path = OsString::from(storage.path.as_os_str());
+        path.push(".lock");
+        Some(PathBuf::from(path))
+    }
+
+    /// None if another process holds it.
+    pub fn try_lock(path: &Path) -> Result<Option<Self>, DaemonError> {
+        let lock = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o644)
+            .open(path)
+            .map_err(file("open lock file", path))?;
+
+        match flock(lock.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+            Ok(()) => {}
+            Err(Errno::EWOULDBLOCK) => return Ok(None),
+            Err(e) => return Err(file("lock", path)(e.into())),
+        }
+
+        let lock = Self { file: lock };
+        lock.write_pid().map_err(file("write to lock file", path))?;
+        Ok(Some(lock))
+    }
+
+    /// The pid written by whoever holds it, if it can be read.
+    pub fn holder(path: &Path) -> Option<i32> {
+        fs::read_to_string(path).ok()?.trim().parse().ok()
+    }
+
+    /// Once more after daemonize(), the process holding it isn't the one
+    /// that took it anymore.
+    pub fn write_pid(&self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file
+            .write_all_at(format!("{}\n", process::id()).as_bytes(), 0)
+    }
+}
+
+/// What to tell whoever tried to start a second daemon.
+pub fn already_running(lock_path: &Path) -> DaemonError {
+    DaemonError::Locked {
+        pid: InstanceLock::holder(lock_path),
+        lock: lock_path.to_path_buf(),
+    }
+}
+
+/// Switches to the configured user and group. Anything that needs root
+/// (the udev socket, the log file, the pidfile) has to be opened before
+/// this.
+pub fn drop_privileges(config: &DaemonConfig) -> Result<(), DaemonError> {
+    let no_such = |kind, name: &str| DaemonError::NoSuchUser {
+        kind,
+        name: name.to_string(),
+    };
+    let user = match &config.user {
+        Some(name) => Some(
+            User::from_name(name)
+                .map_err(system("look up the user"))?
+                .ok_or_else(|| no_such("user", name))?,
+        ),
+        None => None,
+    };
+    let group = match &config.group {
+        Some(name) => Some(
+            Group::from_name(name)
+                .map_err(system("look up the group"))?
+                .ok_or_else(|| no_such("group", name))?,
+        ),
+        None => None,
+    };
+
+    if user.is_none() && group.is_none() {
+        return Ok(());
+    }
+
+    let gid = group
+        .as_ref()
+        .map(|group| group.gid)
+        .or_else(|| user.as_ref().map(|user| user.gid))
+        .unwrap_or_else(Gid::current);
+
+    // Group first, we can't change it anymore once we're not root.
+    match &user {
+        Some(user) => {
+            // Names out of the user database never have a NUL in them.
+            let name = CString::new(user.name.as_str()).map_err(|_| no_such("user", &user.name))?;
+            unistd::initgroups(&name, gid).map_err(system("set the supplementary groups"))?
+        }
+        None => unistd::setgroups(&[gid]).map_err(system("set the supplementary groups"))?,
+    }
+    unistd::setgid(gid).map_err(system("set the group"))?;
+    if let Some(user) = &user {
+        unistd::setuid(user.uid).map_err(system("set the user"))?;
+    }
+
+    info!(
+        "Dropped privileges to uid {} gid {}",
+        Uid::current(),
+        Gid::current()
+    );
+
+    Ok(())
+}
+
+/// Whether we can still open block devices for writing, which anything
+/// destructive will need. That takes root or the disk group.
+pub fn can_write_devices() -> bool {
+    if Uid::effective().is_root() {
+        return true;
+    }
+
+    let disk = match Group::from_name("disk") {
+        Ok(Some(disk)) => disk.gid,
+        _ => return false,
+    };
+
+    unistd::getgroups()
+        .map(|groups| groups.contains(&disk) || Gid::effective() == disk)
+        .unwrap_or(false)
+}
+
+fn system(operation: &'static str) -> impl FnOnce(nix::Error) -> DaemonError {
+    move |error| DaemonError::System { operation, error }
+}
+
+fn file<'a>(operation: &'static str, path: &'a Path) -> impl FnOnce(io::Error) -> DaemonError + 'a {
+    move |error| DaemonError::File {
+        operation,
+        path: path.to_path_buf(),
+        error,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    // No process ever has this pid, it's past the kernel's pid_max.
+    const GONE: i32 = i32::MAX;
+
+    #[test]
+    fn no_pidfile_is_fine() {
+        let dir = TestDir::new("daemon-no-pidfile");
+        assert!(!PidFile::check(&dir.join("hddmond.pid")).unwrap());
+    }
+
+    #[test]
+    fn running_pid_is_refused() {
+        let dir = TestDir::new("daemon-running");
+        let path = dir.join("hddmond.pid");
+        fs::write(&path, format!("{}\n", process::id())).unwrap();
+
+        let error = PidFile::check(&path).unwrap_err().to_string();
+        assert_eq!(
+            error,
+            format!(
+                "hddmond is already running as pid {} (see {})",
+                process::id(),
+                path.display()
+            )
+        );
+        // Left alone, it's not ours.
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn stale_pidfiles_are_removed() {
+        let dir = TestDir::new("daemon-stale");
+        let path = dir.join("hddmond.pid");
+        for contents in [format!("{}\n", GONE), String::new(), "garbage".to_string()] {
+            fs::write(&path, contents).unwrap();
+            assert!(PidFile::check(&path).unwrap());
+            assert!(!path.exists());
+        }
+    }
+
+    #[test]
+    fn pidfile_holds_our_pid_until_dropped() {
+        let dir = TestDir::new("daemon-create");
+        let path = dir.join("hddmond.pid");
+
+        let pidfile = PidFile::create(&path).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{}\n", process::id())
+        );
+        // Someone who got past check() at the same time loses.
+        assert!(PidFile::create(&path).is_err());
+        assert!(PidFile::check(&path).is_err());
+
+        drop(pidfile);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn instance_lock_is_next_to_the_database() {
+        let mut storage = StorageConfig {
+            path: PathBuf::from("/var/lib/hddmond/hddmond.db"),
+            ..Default::default()
+        };
+        assert_eq!(
+            InstanceLock::path(&storage),
+            Some(PathBuf::from("/var/lib/hddmond/hddmond.db.lock"))
+        );
+        storage.path = PathBuf::from(storage::IN_MEMORY);
+        assert_eq!(InstanceLock::path(&storage), None);
+    }
+
+    #[test]
+    fn contended_instance_lock() {
+        let dir = TestDir::new("daemon-lock");
+        let path = dir.join("hddmond.db.lock");
+
+        let lock = InstanceLock::try_lock(&path).unwrap().unwrap();
+        assert_eq!(InstanceLock::holder(&path), Some(process::id() as i32));
+        // flock locks belong to the open file, so a second open in the same
+        // process is turned away like another process would be.
+        assert!(InstanceLock::try_lock(&path).unwrap().is_none());
+        assert_eq!(
+            already_running(&path).to_string(),
+            format!(
+                "hddmond is already running as pid {} (it holds {})",
+                process::id(),
+                path.display()
+            )
+        );
+
+        drop(lock);
+        assert!(InstanceLock::try_lock(&path).unwrap().is_some());
+    }
+
+    #[test]
+    fn lock_file_left_behind_is_taken_over() {
+        let dir = TestDir::new("daemon-left-behind");
+        let path = dir.join("hddmond.db.lock");
+        // A crashed daemon's pid, longer than ours, has to be cut off.
+        fs::write(&path, format!("{}\n", GONE)).unwrap();
+
+        let _lock = InstanceLock::try_lock(&path).unwrap().unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{}\n", process::id())
+        );
+    }
+
+    #[test]
+    fn unreadable_holder() {
+        let dir = TestDir::new("daemon-holder");
+        let path = dir.join("hddmond.db.lock");
+        assert_eq!(InstanceLock::holder(&path), None);
+        fs::write(&path, "").unwrap();
+        assert_eq!(InstanceLock::holder(&path), None);
+        assert_eq!(
+            already_running(&path).to_string(),
+            format!(
+                "hddmond is already running (something holds {})",
+                path.display()
+            )
+        );
+    }
+}
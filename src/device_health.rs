@@ -0,0 +1,599 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    capabilities::{DeviceCapabilities, ReadOnly},
+    mmc::{MmcHealth, PreEol},
+    storage::DeviceRecord,
+};
+
+/// How many devices get their own series in each per-device metric. Past it
+/// the healthiest are left out, and counted in
+/// hddmond_device_health_dropped, so a big fleet can't swamp whatever
+/// stores the metrics. With three states and six reason classes that's at
+/// most 3072 and 6144 series.
+pub const MAX_DEVICE_LABELS: usize = 1024;
+
+// Label values are cut to this many characters, models can be anything the
+// drive says.
+const MAX_LABEL_LEN: usize = 64;
+
+/// How a device is doing, worst last so the worst of several is their max.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthState {
+    /// Nothing wrong that we know of.
+    Ok,
+    /// Worth keeping an eye on, or replacing when it's convenient.
+    Warning,
+    /// Replace it.
+    Failing,
+}
+
+impl HealthState {
+    /// Every state, in order.
+    pub const ALL: &'static [HealthState] =
+        &[HealthState::Ok, HealthState::Warning, HealthState::Failing];
+
+    /// The name it goes by in JSON and metric labels.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HealthState::Ok => "ok",
+            HealthState::Warning => "warning",
+            HealthState::Failing => "failing",
+        }
+    }
+}
+
+/// What a health reason is about. Fixed, so alert rules can match on it and
+/// it's never more than six label values. The SMART attribute classes are
+/// here for when SMART data is read, nothing reports them yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReasonClass {
+    /// Sectors the drive had to remap.
+    Reallocated,
+    /// Sectors waiting to be remapped.
+    Pending,
+    /// The drive running hot.
+    Temperature,
+    /// Flash or eMMC life used up.
+    Wear,
+    /// The drive's own health check saying it failed.
+    SmartFailed,
+    /// Errors on the link to the drive, most often the cable or backplane.
+    LinkErrors,
+}
+
+impl ReasonClass {
+    /// The name it goes by in JSON and metric labels.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReasonClass::Reallocated => "reallocated",
+            ReasonClass::Pending => "pending",
+            ReasonClass::Temperature => "temperature",
+            ReasonClass::Wear => "wear",
+            ReasonClass::SmartFailed => "smart_failed",
+            ReasonClass::LinkErrors => "link_errors",
+        }
+    }
+}
+
+/// One thing that's wrong with a device.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HealthReason {
+    /// What it's about.
+    pub class: ReasonClass,
+    /// How many of it, like link errors in the last day. 1 for the ones that
+    /// are there or not.
+    pub count: u64,
+    /// For humans, anything goes here. Never a label.
+    pub detail: String,
+}
+
+/// A present device's health, from what the registry knows about it and the
+/// link errors the daemon saw lately.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceHealth {
+    /// Kernel name, e.g. `sda`.
+    pub device: String,
+    /// The serial, or the WWN for the devices without one, which the
+    /// registry tells devices apart by.
+    pub serial: String,
+    /// The model it reported.
+    pub model: Option<String>,
+    /// The worst of its reasons.
+    pub state: HealthState,
+    /// Everything found wrong with it, none for an ok device.
+    pub reasons: Vec<HealthReason>,
+    /// Why it's in quarantine, if it is. Not a health state, it's whoever
+    /// put it there who says.
+    pub quarantine: Option<String>,
+}
+
+impl DeviceHealth {
+    /// What `record` and its `link_errors` of the last day say about it.
+    pub fn verdict(record: &DeviceRecord, link_errors: u64) -> Self {
+        let mut reasons = vec![];
+        let mut state = HealthState::Ok;
+
+        if let Ok(Some(health)) =
+            serde_json::from_value::<Option<MmcHealth>>(record.mmc_health.clone())
+        {
+            if let Some(concern) = health.concern() {
+                let worn_out = health.life_time_a.max(health.life_time_b) == Some(0x0B)
+                    || health.pre_eol == Some(PreEol::Urgent);
+                state = state.max(if worn_out {
+                    HealthState::Failing
+                } else {
+                    HealthState::Warning
+                });
+                reasons.push(HealthReason {
+                    class: ReasonClass::Wear,
+                    count: 1,
+                    detail: concern,
+                });
+            }
+        }
+
+        // The media going read-only is the drive's own SMART / health log
+        // saying it gave up. The kernel's ro flag can be anything, a
+        // write-protect switch included.
+        if let Ok(Some(capabilities)) =
+            serde_json::from_value::<Option<DeviceCapabilities>>(record.capabilities.clone())
+        {
+            if capabilities.read_only == Some(ReadOnly::NvmeMedia) {
+                state = HealthState::Failing;
+                reasons.push(HealthReason {
+                    class: ReasonClass::SmartFailed,
+                    count: 1,
+                    detail: ReadOnly::NvmeMedia.to_string(),
+                });
+            }
+        }
+
+        if link_errors > 0 {
+            state = state.max(HealthState::Warning);
+            reasons.push(HealthReason {
+                class: ReasonClass::LinkErrors,
+                count: link_errors,
+                detail: format!("{} link errors in the last day", link_errors),
+            });
+        }
+
+        Self {
+            device: record.name.clone(),
+            serial: record
+                .serial
+                .clone()
+                .or_else(|| record.wwn.clone())
+                .unwrap_or_else(|| record.name.clone()),
+            model: record.model.clone(),
+            state,
+            reasons,
+            quarantine: record.quarantine.clone(),
+        }
+    }
+}
+
+/// The present devices' health, `link_errors` by kernel name.
+pub fn verdicts(records: &[DeviceRecord], link_errors: &HashMap<String, u64>) -> Vec<DeviceHealth> {
+    records
+        .iter()
+        .filter(|record| record.present)
+        .map(|record| {
+            DeviceHealth::verdict(
+                record,
+                link_errors.get(&record.name).copied().unwrap_or_default(),
+            )
+        })
+        .collect()
+}
+
+/// Gauges in the Prometheus text format, to go with usage::prometheus. Only
+/// serial, model, state and reason_class are ever labels, and there are at
+/// most MAX_DEVICE_LABELS serials.
+pub fn prometheus(devices: &[DeviceHealth]) -> String {
+    let mut text = String::new();
+
+    let mut states = BTreeMap::new();
+    for device in devices {
+        *states.entry(device.state).or_insert(0) += 1;
+    }
+    let quarantined = devices
+        .iter()
+        .filter(|device| device.quarantine.is_some())
+        .count();
+
+    // Two devices with the same serial would have the same reason series,
+    // only the worse off one is kept. Then the worst off first, so they're
+    // the last to be dropped.
+    let mut devices = devices.iter().collect::<Vec<_>>();
+    devices.sort_by(|a, b| a.serial.cmp(&b.serial).then_with(|| b.state.cmp(&a.state)));
+    devices.dedup_by(|a, b| a.serial == b.serial);
+    devices.sort_by(|a, b| b.state.cmp(&a.state).then_with(|| a.serial.cmp(&b.serial)));
+    let dropped = devices.len().saturating_sub(MAX_DEVICE_LABELS);
+    devices.truncate(MAX_DEVICE_LABELS);
+
+    // Writing to a String can't fail.
+    let _ = writeln!(
+        text,
+        "# HELP hddmond_device_health Whether a present device is in this state, 1 for the one \
+         it's in.\n\
+         # TYPE hddmond_device_health gauge"
+    );
+    for device in &devices {
+        for state in HealthState::ALL {
+            let _ = writeln!(
+                text,
+                "hddmond_device_health{{serial=\"{}\",model=\"{}\",state=\"{}\"}} {}",
+                label(&device.serial),
+                label(device.model.as_deref().unwrap_or("")),
+                state.as_str(),
+                u8::from(*state == device.state)
+            );
+        }
+    }
+
+    let _ = writeln!(
+        text,
+        "# HELP hddmond_device_health_reason_count What a present device's health is down to, \
+         by class.\n\
+         # TYPE hddmond_device_health_reason_count gauge"
+    );
+    for device in &devices {
+        for reason in &device.reasons {
+            let _ = writeln!(
+                text,
+                "hddmond_device_health_reason_count{{serial=\"{}\",reason_class=\"{}\"}} {}",
+                label(&device.serial),
+                reason.class.as_str(),
+                reason.count
+            );
+        }
+    }
+
+    let _ = writeln!(
+        text,
+        "# HELP hddmond_devices Present devices in each health state.\n\
+         # TYPE hddmond_devices gauge"
+    );
+    for state in HealthState::ALL {
+        let _ = writeln!(
+            text,
+            "hddmond_devices{{state=\"{}\"}} {}",
+            state.as_str(),
+            states.get(state).copied().unwrap_or(0)
+        );
+    }
+
+    let _ = writeln!(
+        text,
+        "# HELP hddmond_devices_quarantined Present devices in quarantine.\n\
+         # TYPE hddmond_devices_quarantined gauge\n\
+         hddmond_devices_quarantined {}",
+        quarantined
+    );
+
+    let _ = writeln!(
+        text,
+        "# HELP hddmond_device_health_dropped Present devices left out of the per-device \
+         metrics, past the {} there's room for.\n\
+         # TYPE hddmond_device_health_dropped gauge\n\
+         hddmond_device_health_dropped {}",
+        MAX_DEVICE_LABELS, dropped
+    );
+
+    text
+}
+
+// Escaped the way the text format wants, and cut short.
+fn label(value: &str) -> String {
+    let mut label = String::new();
+    for c in value.chars().take(MAX_LABEL_LEN) {
+        match c {
+            '\\' => label.push_str("\\\\"),
+            '"' => label.push_str("\\\""),
+            '\n' => label.push_str("\\n"),
+            c => label.push(c),
+        }
+    }
+    label
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, path::Path};
+
+    use super::*;
+    use crate::{
+        capabilities::Capability,
+        device_policy::DeviceIdentity,
+        storage::{Registry, IN_MEMORY},
+    };
+
+    fn device(serial: &str, state: HealthState, reasons: &[ReasonClass]) -> DeviceHealth {
+        DeviceHealth {
+            device: format!("sd-{}", serial),
+            serial: serial.to_string(),
+            model: Some("WDC WD40EFRX-68N32N0".to_string()),
+            state,
+            reasons: reasons
+                .iter()
+                .map(|&class| HealthReason {
+                    class,
+                    count: 1,
+                    detail: "anything, it's never a label".to_string(),
+                })
+                .collect(),
+            quarantine: None,
+        }
+    }
+
+    // Every line of `metric`, as its labels and its value.
+    fn series(text: &str, metric: &str) -> Vec<(BTreeMap<String, String>, String)> {
+        text.lines()
+            .filter_map(|line| {
+                let rest = line.strip_prefix(metric)?;
+                let (labels, value) = match rest.strip_prefix('{') {
+                    Some(rest) => rest.split_once("} ")?,
+                    None => ("", rest.strip_prefix(' ')?),
+                };
+                let labels = labels
+                    .split("\",")
+                    .filter(|pair| !pair.is_empty())
+                    .map(|pair| {
+                        let (name, value) = pair.split_once("=\"").unwrap();
+                        (name.to_string(), value.trim_end_matches('"').to_string())
+                    })
+                    .collect();
+                Some((labels, value.to_string()))
+            })
+            .collect()
+    }
+
+    fn values<'a>(
+        series: &'a [(BTreeMap<String, String>, String)],
+        label: &str,
+    ) -> HashSet<&'a str> {
+        series
+            .iter()
+            .map(|(labels, _)| labels[label].as_str())
+            .collect()
+    }
+
+    #[test]
+    fn a_fleet_past_the_cap_keeps_the_worst_off() {
+        let fleet = MAX_DEVICE_LABELS + 500;
+        let mut devices = (0..fleet)
+            .map(|n| {
+                let (state, reasons): (_, &[ReasonClass]) = match n % 10 {
+                    0 => (
+                        HealthState::Failing,
+                        &[ReasonClass::Wear, ReasonClass::SmartFailed],
+                    ),
+                    1 | 2 => (HealthState::Warning, &[ReasonClass::LinkErrors]),
+                    _ => (HealthState::Ok, &[]),
+                };
+                device(&format!("WD-{:05}", n), state, reasons)
+            })
+            .collect::<Vec<_>>();
+        // Every reason class there is, on one device.
+        devices[0].reasons = [
+            ReasonClass::Reallocated,
+            ReasonClass::Pending,
+            ReasonClass::Temperature,
+            ReasonClass::Wear,
+            ReasonClass::SmartFailed,
+            ReasonClass::LinkErrors,
+        ]
+        .iter()
+        .map(|&class| HealthReason {
+            class,
+            count: 3,
+            detail: String::new(),
+        })
+        .collect();
+        let text = prometheus(&devices);
+
+        let health = series(&text, "hddmond_device_health");
+        let serials = values(&health, "serial");
+        assert_eq!(serials.len(), MAX_DEVICE_LABELS);
+        assert_eq!(health.len(), MAX_DEVICE_LABELS * HealthState::ALL.len());
+        assert_eq!(
+            values(&health, "state"),
+            HashSet::from(["ok", "warning", "failing"])
+        );
+        assert_eq!(values(&health, "model").len(), 1);
+        // Everything that isn't ok made it in.
+        for device in devices
+            .iter()
+            .filter(|device| device.state != HealthState::Ok)
+        {
+            assert!(
+                serials.contains(device.serial.as_str()),
+                "{}",
+                device.serial
+            );
+        }
+
+        let reasons = series(&text, "hddmond_device_health_reason_count");
+        assert!(values(&reasons, "serial").len() <= MAX_DEVICE_LABELS);
+        assert_eq!(values(&reasons, "reason_class").len(), 6);
+        assert!(reasons.len() <= MAX_DEVICE_LABELS * 6);
+        for (labels, _) in &reasons {
+            assert_eq!(
+                labels.keys().collect::<Vec<_>>(),
+                ["reason_class", "serial"]
+            );
+        }
+
+        // The totals still count every device, and say how many were left
+        // out.
+        let count = |state| {
+            devices
+                .iter()
+                .filter(|device| device.state == state)
+                .count()
+                .to_string()
+        };
+        assert_eq!(
+            series(&text, "hddmond_devices")
+                .into_iter()
+                .map(|(labels, value)| (labels["state"].clone(), value))
+                .collect::<Vec<_>>(),
+            HealthState::ALL
+                .iter()
+                .map(|&state| (state.as_str().to_string(), count(state)))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            series(&text, "hddmond_device_health_dropped"),
+            [(BTreeMap::new(), "500".to_string())]
+        );
+    }
+
+    #[test]
+    fn a_small_fleet_is_all_there() {
+        let devices = [
+            device("WD-A", HealthState::Ok, &[]),
+            device("WD-B", HealthState::Warning, &[ReasonClass::LinkErrors]),
+        ];
+        let text = prometheus(&devices);
+        assert_eq!(series(&text, "hddmond_device_health").len(), 6);
+        assert_eq!(
+            series(&text, "hddmond_device_health_dropped"),
+            [(BTreeMap::new(), "0".to_string())]
+        );
+        assert!(text.contains(
+            "hddmond_device_health{serial=\"WD-B\",model=\"WDC WD40EFRX-68N32N0\",\
+             state=\"warning\"} 1\n"
+        ));
+        assert!(text.contains(
+            "hddmond_device_health_reason_count{serial=\"WD-B\",reason_class=\"link_errors\"} 1\n"
+        ));
+    }
+
+    #[test]
+    fn a_serial_seen_twice_is_one_series() {
+        let devices = [
+            device("WD-A", HealthState::Ok, &[]),
+            device("WD-A", HealthState::Failing, &[ReasonClass::Wear]),
+            device("WD-A", HealthState::Warning, &[ReasonClass::LinkErrors]),
+        ];
+        let text = prometheus(&devices);
+        let health = series(&text, "hddmond_device_health");
+        assert_eq!(health.len(), 3);
+        assert!(health
+            .iter()
+            .any(|(labels, value)| labels["state"] == "failing" && value == "1"));
+        assert_eq!(
+            series(&text, "hddmond_device_health_reason_count")
+                .iter()
+                .map(|(labels, _)| labels["reason_class"].as_str())
+                .collect::<Vec<_>>(),
+            ["wear"]
+        );
+    }
+
+    #[test]
+    fn labels_are_escaped_and_cut_short() {
+        assert_eq!(label("plain"), "plain");
+        assert_eq!(label("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+        let long = "x".repeat(MAX_LABEL_LEN * 4);
+        assert_eq!(label(&long).len(), MAX_LABEL_LEN);
+        // Characters, not bytes, so nothing is cut in half.
+        assert_eq!(label(&"é".repeat(100)).chars().count(), MAX_LABEL_LEN);
+    }
+
+    #[test]
+    fn verdicts_from_the_registry() {
+        let mut registry = Registry::open(Path::new(IN_MEMORY)).unwrap();
+        let drive = |name: &str, serial: Option<&str>| DeviceIdentity {
+            name: name.to_string(),
+            serial: serial.map(String::from),
+            wwn: Some(format!("0x5000{}", name)),
+            model: Some("WDC WD40EFRX-68N32N0".to_string()),
+            ..Default::default()
+        };
+        for identity in [
+            drive("sda", Some("WD-A")),
+            drive("sdb", Some("WD-B")),
+            drive("mmcblk0", Some("0x5b1c2a9e")),
+            drive("nvme0n1", None),
+            drive("sdc", Some("WD-C")),
+        ] {
+            registry.device_found(&identity).unwrap();
+        }
+        registry.device_lost("sdc").unwrap();
+        registry
+            .set_mmc_health(
+                &drive("mmcblk0", Some("0x5b1c2a9e")),
+                &MmcHealth {
+                    life_time_b: Some(0x0B),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        registry
+            .set_capabilities(
+                &drive("nvme0n1", None),
+                &DeviceCapabilities {
+                    read_only: Some(ReadOnly::NvmeMedia),
+                    write: Capability::No,
+                    ata_secure_erase: Capability::No,
+                    discard: Capability::Unknown,
+                    write_zeroes: Capability::Unknown,
+                    self_test: Capability::Yes,
+                },
+            )
+            .unwrap();
+
+        let records = registry.devices(true).unwrap();
+        let verdicts = verdicts(&records, &HashMap::from([("sdb".to_string(), 4)]));
+        let mut verdicts = verdicts
+            .iter()
+            .map(|verdict| {
+                (
+                    verdict.device.as_str(),
+                    verdict.serial.as_str(),
+                    verdict.state,
+                    verdict
+                        .reasons
+                        .iter()
+                        .map(|reason| (reason.class, reason.count))
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .collect::<Vec<_>>();
+        verdicts.sort();
+        assert_eq!(
+            verdicts,
+            [
+                (
+                    "mmcblk0",
+                    "0x5b1c2a9e",
+                    HealthState::Failing,
+                    vec![(ReasonClass::Wear, 1)]
+                ),
+                (
+                    "nvme0n1",
+                    "0x5000nvme0n1",
+                    HealthState::Failing,
+                    vec![(ReasonClass::SmartFailed, 1)]
+                ),
+                ("sda", "WD-A", HealthState::Ok, vec![]),
+                (
+                    "sdb",
+                    "WD-B",
+                    HealthState::Warning,
+                    vec![(ReasonClass::LinkErrors, 4)]
+                ),
+            ]
+        );
+    }
+}
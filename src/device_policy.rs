@@ -0,0 +1,1095 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use glob::Pattern;
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{
+    capacity::Capacity,
+    config::{DeviceMatch, DevicesConfig},
+    error::PolicyError,
+    mmc,
+    notifiers::template::Template,
+    scanners::scanner::ScanEventType,
+    topology::DeviceLocation,
+};
+
+/// What we know about a device to match it against the policy.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DeviceIdentity {
+    /// Kernel name, e.g. `sda`.
+    pub name: String,
+    /// The device node and every udev symlink to it, e.g. /dev/sda and
+    /// /dev/disk/by-id/ata-...
+    pub paths: Vec<PathBuf>,
+    /// The serial the drive reports.
+    pub serial: Option<String>,
+    /// The model the drive reports.
+    pub model: Option<String>,
+    /// The drive's World Wide Name.
+    pub wwn: Option<String>,
+    /// From sysfs, None if the device isn't there.
+    pub capacity: Option<Capacity>,
+    /// The firmware revision the drive reports.
+    pub firmware: Option<String>,
+    /// The USB port of the bridge it's behind, like `2-1.4`.
+    pub usb_port: Option<String>,
+}
+
+impl DeviceIdentity {
+    /// Looks the device up in udev. Anything udev doesn't know is left out,
+    /// which means matches on it won't apply.
+    pub fn lookup(name: &str) -> Self {
+        let mut identity = Self {
+            name: name.to_string(),
+            paths: vec![Path::new("/dev").join(name)],
+            capacity: Capacity::lookup(name),
+            firmware: sysfs_firmware(Path::new("/sys"), name),
+            usb_port: DeviceLocation::lookup(name)
+                .and_then(|location| location.usb)
+                .map(|usb| usb.to_string()),
+            ..Default::default()
+        };
+        let sys = Path::new("/sys");
+        if mmc::is_mmc(sys, name) {
+            identity.serial = mmc::sysfs_attribute(sys, name, "serial");
+            identity.model = mmc::sysfs_attribute(sys, name, "name");
+        }
+
+        let device = Path::new("/sys/class/block")
+            .join(name)
+            .canonicalize()
+            .ok()
+            .and_then(|syspath| udev::Device::from_syspath(&syspath).ok());
+
+        let device = match device {
+            Some(device) => device,
+            None => {
+                debug!("No udev device for {}, matching on its name only", name);
+                return identity;
+            }
+        };
+
+        let property = |key: &str| {
+            device
+                .property_value(key)
+                .map(|value| value.to_string_lossy().into_owned())
+        };
+
+        identity.serial = property("ID_SERIAL_SHORT").or(identity.serial);
+        identity.model = property("ID_MODEL").or(identity.model);
+        identity.wwn = property("ID_WWN");
+        if let Some(revision) = property("ID_REVISION") {
+            identity.firmware = Some(revision);
+        }
+        if let Some(links) = property("DEVLINKS") {
+            identity
+                .paths
+                .extend(links.split_whitespace().map(PathBuf::from));
+        }
+
+        identity
+    }
+
+    /// The disk with this kernel name, or with this serial or WWN, which
+    /// takes looking up every disk in udev.
+    pub fn find(key: &str) -> Option<Self> {
+        let name = device_name(key);
+        let disks = Path::new("/sys/class/block");
+        if disks.join(name).exists() {
+            return Some(Self::lookup(name));
+        }
+
+        fs::read_dir(disks)
+            .ok()?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| !entry.path().join("partition").exists())
+            .filter(|entry| !mmc::is_hardware_partition(&entry.file_name().to_string_lossy()))
+            .map(|entry| Self::lookup(&entry.file_name().to_string_lossy()))
+            .find(|identity| {
+                [&identity.serial, &identity.wwn]
+                    .into_iter()
+                    .flatten()
+                    .any(|id| id.eq_ignore_ascii_case(key))
+            })
+    }
+}
+
+// What the kernel read off the drive when it was attached, for when udev
+// doesn't have ID_REVISION. NVMe controllers call it firmware_rev, SCSI
+// and ATA disks rev, MMC devices fwrev.
+fn sysfs_firmware(sys: &Path, name: &str) -> Option<String> {
+    let device = sys.join("class/block").join(name).join("device");
+    ["firmware_rev", "rev", "fwrev"]
+        .into_iter()
+        .filter_map(|file| fs::read_to_string(device.join(file)).ok())
+        .map(|revision| revision.trim().to_string())
+        .find(|revision| !revision.is_empty())
+}
+
+/// Whether `rule` matches the device `identity`.
+pub fn matches(rule: &DeviceMatch, identity: &DeviceIdentity) -> bool {
+    // Every field given in the rule has to match, and a device we don't
+    // know that field for doesn't. Fields left out match anything.
+    let field =
+        |rule: &Option<String>, matches: &dyn Fn(&str) -> bool| rule.as_deref().is_none_or(matches);
+
+    field(&rule.serial, &|serial| {
+        identity
+            .serial
+            .as_deref()
+            .is_some_and(|actual| actual.eq_ignore_ascii_case(serial))
+    }) && field(&rule.wwn, &|wwn| {
+        identity
+            .wwn
+            .as_deref()
+            .is_some_and(|actual| actual.eq_ignore_ascii_case(wwn))
+    }) && field(&rule.model, &|model| {
+        identity
+            .model
+            .as_deref()
+            .is_some_and(|actual| glob_matches(model, actual))
+    }) && field(&rule.path, &|path| {
+        identity
+            .paths
+            .iter()
+            .filter_map(|actual| actual.to_str())
+            .any(|actual| glob_matches(path, actual))
+    })
+}
+
+// Patterns are checked when the config is validated, one that still
+// doesn't parse here matches nothing.
+fn glob_matches(pattern: &str, value: &str) -> bool {
+    Pattern::new(pattern).is_ok_and(|pattern| pattern.matches(value))
+}
+
+/// Decides which devices the daemon sees at all and which ones it must
+/// never write to.
+///
+/// Ignored devices are dropped from the event stream as if they weren't
+/// there. Protected devices are reported like any other, but nothing
+/// destructive may run against them. The disk(s) backing / are protected
+/// unless `protect_root` is turned off. Quarantined devices are protected
+/// and then some, the daemon doesn't change anything about them on its own,
+/// not even their power settings, until they're let out.
+///
+/// Only device found events carry enough to look a device up, so the
+/// decision is remembered by name until the device is lost.
+pub struct DevicePolicy {
+    config: DevicesConfig,
+    root_disks: HashSet<String>,
+    // Devices the monitor told us about, by name, that udev can't.
+    known: HashMap<String, DeviceIdentity>,
+    ignored: HashSet<String>,
+    protected: HashSet<String>,
+    // In quarantine, by name. From the registry when they're found, and from
+    // whoever quarantines one while it's there.
+    quarantined: HashSet<String>,
+    alias_template: Option<Template>,
+}
+
+impl DevicePolicy {
+    /// Works out the root disks now, when devices.protect_root is on.
+    pub fn new(config: DevicesConfig) -> Self {
+        let root_disks = if config.protect_root {
+            match root_disks() {
+                Ok(disks) => {
+                    info!(
+                        "Protecting root disk(s): {}",
+                        disks.iter().cloned().collect::<Vec<_>>().join(", ")
+                    );
+                    disks
+                }
+                Err(e) => {
+                    warn!(
+                        "Couldn't find the disk backing /, it won't be protected unless it \
+                         matches devices.protect: {}",
+                        e
+                    );
+                    HashSet::new()
+                }
+            }
+        } else {
+            HashSet::new()
+        };
+
+        Self::with_root_disks(config, root_disks)
+    }
+
+    fn with_root_disks(config: DevicesConfig, root_disks: HashSet<String>) -> Self {
+        // Checked when the config was validated.
+        let alias_template = config
+            .alias_template
+            .as_deref()
+            .and_then(|template| Template::parse(template).ok());
+
+        Self {
+            config,
+            alias_template,
+            root_disks,
+            known: HashMap::new(),
+            ignored: HashSet::new(),
+            protected: HashSet::new(),
+            quarantined: HashSet::new(),
+        }
+    }
+
+    /// Takes the monitor's word for these devices instead of looking them up.
+    pub fn add_identities(&mut self, identities: Vec<DeviceIdentity>) {
+        self.known.extend(
+            identities
+                .into_iter()
+                .map(|identity| (identity.name.clone(), identity)),
+        );
+    }
+
+    /// What's known about a device, by its kernel name or device path.
+    pub fn identity(&self, name: &str) -> DeviceIdentity {
+        let name = device_name(name);
+        match self.known.get(name) {
+            Some(identity) => identity.clone(),
+            None => DeviceIdentity::lookup(name),
+        }
+    }
+
+    /// What devices.alias_template calls the device. None without a template,
+    /// or when it uses something that isn't known for the device, like the
+    /// slot of one that isn't in an enclosure.
+    pub fn templated_alias(&self, identity: &DeviceIdentity) -> Option<String> {
+        self.alias_at(identity, DeviceLocation::lookup(&identity.name))
+    }
+
+    fn alias_at(
+        &self,
+        identity: &DeviceIdentity,
+        location: Option<DeviceLocation>,
+    ) -> Option<String> {
+        let template = self.alias_template.as_ref()?;
+        let enclosure = location
+            .as_ref()
+            .and_then(|location| location.enclosure.as_ref());
+        template.render_text(&json!({
+            "name": identity.name,
+            "serial": identity.serial,
+            "model": identity.model,
+            "wwn": identity.wwn,
+            "enclosure": enclosure.map(|enclosure| &enclosure.enclosure),
+            "slot": enclosure.map(|enclosure| &enclosure.slot),
+            "controller": location
+                .as_ref()
+                .and_then(|location| location.controller.as_ref())
+                .map(|controller| &controller.pci_address),
+            "location": location,
+        }))
+    }
+
+    /// Applies the policy to an event, returning None if the event is for an
+    /// ignored device.
+    pub fn filter(&mut self, event: ScanEventType) -> Option<ScanEventType> {
+        match &event {
+            ScanEventType::DeviceFound(name) => {
+                let identity = self.identity(name);
+                let name = device_name(name);
+
+                if self
+                    .config
+                    .ignore
+                    .iter()
+                    .any(|rule| matches(rule, &identity))
+                {
+                    debug!("Ignoring device {}", name);
+                    self.ignored.insert(name.to_string());
+                    return None;
+                }
+
+                if self.root_disks.contains(name)
+                    || self
+                        .config
+                        .protect
+                        .iter()
+                        .any(|rule| matches(rule, &identity))
+                {
+                    self.protected.insert(name.to_string());
+                }
+            }
+            ScanEventType::DeviceLost(name) => {
+                let name = device_name(name);
+                self.protected.remove(name);
+                self.quarantined.remove(name);
+                if self.ignored.remove(name) {
+                    return None;
+                }
+            }
+            ScanEventType::DeviceChanged(name) | ScanEventType::Unknown(name) => {
+                if self.ignored.contains(device_name(name)) {
+                    return None;
+                }
+            }
+        }
+
+        Some(event)
+    }
+
+    /// Whether nothing may write to the device: it's listed in
+    /// devices.protect, holds the root filesystem, or is in quarantine.
+    pub fn is_protected(&self, name: &str) -> bool {
+        let name = device_name(name);
+        self.protected.contains(name)
+            || self.root_disks.contains(name)
+            || self.quarantined.contains(name)
+    }
+
+    /// Puts the device in quarantine, or takes it out.
+    pub fn set_quarantined(&mut self, name: &str, quarantined: bool) {
+        let name = device_name(name);
+        if quarantined {
+            self.quarantined.insert(name.to_string());
+        } else {
+            self.quarantined.remove(name);
+        }
+    }
+
+    /// Whether the device is in quarantine.
+    pub fn is_quarantined(&self, name: &str) -> bool {
+        self.quarantined.contains(device_name(name))
+    }
+
+    /// Whether the daemon may change a device's settings, like its power
+    /// management, without being asked to. Reading from it is always fine.
+    pub fn may_change_settings(&self, name: &str) -> bool {
+        !self.is_quarantined(name)
+    }
+}
+
+/// The udev backend reports kernel names, the smartctl one device paths.
+pub fn device_name(name: &str) -> &str {
+    name.strip_prefix("/dev/").unwrap_or(name)
+}
+
+/// Finds the whole disk(s) the root filesystem lives on, following
+/// partitions, device mapper and md down to the physical devices.
+pub fn root_disks() -> Result<HashSet<String>, PolicyError> {
+    let disks = filesystem_disks(Path::new("/"))?;
+    if disks.is_empty() {
+        return Err(PolicyError::NotOnADisk {
+            path: PathBuf::from("/"),
+        });
+    }
+    Ok(disks)
+}
+
+/// The same for whatever filesystem `path` is on. Empty for one that isn't
+/// on a block device at all, like tmpfs or NFS.
+pub fn filesystem_disks(path: &Path) -> Result<HashSet<String>, PolicyError> {
+    let path = canonicalize(path)?;
+    let mountinfo = read_to_string(Path::new("/proc/self/mountinfo"))?;
+    let (dev, source) = mount_of(&mountinfo, &path)
+        .ok_or_else(|| PolicyError::NotMounted { path: path.clone() })?;
+
+    // Filesystems like btrfs report an anonymous device number for /, but
+    // still name the real device as the mount source.
+    let dev = if Path::new("/sys/dev/block").join(&dev).exists() {
+        dev
+    } else {
+        match source
+            .filter(|source| source.starts_with("/dev/"))
+            .and_then(|source| fs::metadata(source).ok())
+        {
+            Some(metadata) => {
+                let rdev = metadata.rdev();
+                let major = ((rdev >> 8) & 0xfff) | ((rdev >> 32) & !0xfff);
+                let minor = (rdev & 0xff) | ((rdev >> 12) & !0xff);
+                format!("{}:{}", major, minor)
+            }
+            None => return Ok(HashSet::new()),
+        }
+    };
+
+    let sys_path = canonicalize(&Path::new("/sys/dev/block").join(&dev))?;
+    let mut disks = HashSet::new();
+    backing_disks(&sys_path, &mut disks)?;
+
+    if disks.is_empty() {
+        return Err(PolicyError::NoDisk { path: sys_path });
+    }
+
+    Ok(disks)
+}
+
+// The device number (`major:minor`) and source of the mount `path` is on,
+// out of /proc/self/mountinfo.
+fn mount_of(mountinfo: &str, path: &Path) -> Option<(String, Option<String>)> {
+    // `id parent major:minor root mount_point options... - fstype source
+    // super_options`. The mount with the longest mount point the path is
+    // under is the one it's on, and the last of those is the one that's
+    // visible.
+    mountinfo
+        .lines()
+        .filter_map(|line| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let mount_point = PathBuf::from(unescape_mount(fields.get(4)?));
+            if !path.starts_with(&mount_point) {
+                return None;
+            }
+            let source = fields
+                .iter()
+                .position(|field| *field == "-")
+                .and_then(|separator| fields.get(separator + 2))
+                .map(|source| unescape_mount(source));
+            Some((mount_point, fields.get(2)?.to_string(), source))
+        })
+        .fold(
+            None,
+            |best: Option<(PathBuf, String, Option<String>)>, mount| match best {
+                Some(best) if best.0.as_os_str().len() > mount.0.as_os_str().len() => Some(best),
+                _ => Some(mount),
+            },
+        )
+        .map(|(_, dev, source)| (dev, source))
+}
+
+/// The whole disk(s) behind a block device, by kernel name: the disk
+/// itself, the one a partition is on, or what device mapper or md built it
+/// from.
+pub fn block_device_disks(name: &str) -> Result<HashSet<String>, PolicyError> {
+    let mut disks = HashSet::new();
+    backing_disks(
+        &canonicalize(&Path::new("/sys/class/block").join(device_name(name)))?,
+        &mut disks,
+    )?;
+    Ok(disks)
+}
+
+fn canonicalize(path: &Path) -> Result<PathBuf, PolicyError> {
+    path.canonicalize().map_err(|error| PolicyError::Read {
+        path: path.to_path_buf(),
+        error,
+    })
+}
+
+fn read_to_string(path: &Path) -> Result<String, PolicyError> {
+    fs::read_to_string(path).map_err(|error| PolicyError::Read {
+        path: path.to_path_buf(),
+        error,
+    })
+}
+
+// Mountinfo writes spaces, tabs, newlines and backslashes as \ and three
+// octal digits.
+fn unescape_mount(field: &str) -> String {
+    let mut out = String::new();
+    let mut rest = field;
+    while let Some(at) = rest.find('\\') {
+        out.push_str(&rest[..at]);
+        match rest
+            .get(at + 1..at + 4)
+            .and_then(|octal| u8::from_str_radix(octal, 8).ok())
+        {
+            Some(byte) => {
+                out.push(byte as char);
+                rest = &rest[at + 4..];
+            }
+            None => {
+                out.push('\\');
+                rest = &rest[at + 1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn backing_disks(sys_path: &Path, disks: &mut HashSet<String>) -> Result<(), PolicyError> {
+    // Device mapper and md devices list what they're built on.
+    let slaves = fs::read_dir(sys_path.join("slaves"))
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .filter_map(|entry| entry.path().canonicalize().ok())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+
+    if !slaves.is_empty() {
+        for slave in slaves {
+            backing_disks(&slave, disks)?;
+        }
+        return Ok(());
+    }
+
+    // A partition's directory sits inside its disk's.
+    let disk = if sys_path.join("partition").exists() {
+        sys_path.parent()
+    } else {
+        Some(sys_path)
+    };
+
+    let name = disk
+        .and_then(|disk| disk.file_name())
+        .ok_or_else(|| PolicyError::NoDisk {
+            path: sys_path.to_path_buf(),
+        })?;
+    disks.insert(name.to_string_lossy().into_owned());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    fn disk() -> DeviceIdentity {
+        DeviceIdentity {
+            name: "sda".to_string(),
+            paths: vec![
+                PathBuf::from("/dev/sda"),
+                PathBuf::from("/dev/disk/by-id/ata-WDC_WD40EFRX-68N32N0_WD-WCC7K1234567"),
+                PathBuf::from("/dev/disk/by-path/pci-0000:00:17.0-ata-1"),
+            ],
+            serial: Some("WD-WCC7K1234567".to_string()),
+            model: Some("WDC WD40EFRX-68N32N0".to_string()),
+            wwn: Some("0x50014ee2b5a1c3d4".to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn rule(
+        serial: Option<&str>,
+        model: Option<&str>,
+        wwn: Option<&str>,
+        path: Option<&str>,
+    ) -> DeviceMatch {
+        DeviceMatch {
+            serial: serial.map(str::to_string),
+            model: model.map(str::to_string),
+            wwn: wwn.map(str::to_string),
+            path: path.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn each_field_matches_on_its_own() {
+        let disk = disk();
+        let cases = [
+            // An empty rule matches everything.
+            (rule(None, None, None, None), true),
+            (rule(Some("WD-WCC7K1234567"), None, None, None), true),
+            (rule(Some("WD-WCC7K7654321"), None, None, None), false),
+            (rule(None, Some("WDC WD40EFRX-68N32N0"), None, None), true),
+            (rule(None, Some("ST4000*"), None, None), false),
+            (rule(None, None, Some("0x50014ee2b5a1c3d4"), None), true),
+            (rule(None, None, Some("0x5000c500a1b2c3d4"), None), false),
+            (rule(None, None, None, Some("/dev/sda")), true),
+            (rule(None, None, None, Some("/dev/sdb")), false),
+            // Any of the links will do.
+            (
+                rule(None, None, None, Some("/dev/disk/by-path/*-ata-1")),
+                true,
+            ),
+        ];
+        for (rule, expected) in cases {
+            assert_eq!(matches(&rule, &disk), expected, "{:?}", rule);
+        }
+    }
+
+    #[test]
+    fn every_field_given_has_to_match() {
+        let disk = disk();
+        assert!(matches(
+            &rule(
+                Some("WD-WCC7K1234567"),
+                Some("WDC*"),
+                None,
+                Some("/dev/sd?")
+            ),
+            &disk
+        ));
+        assert!(!matches(
+            &rule(Some("WD-WCC7K1234567"), Some("ST*"), None, None),
+            &disk
+        ));
+        assert!(!matches(
+            &rule(None, Some("WDC*"), None, Some("/dev/nvme*")),
+            &disk
+        ));
+    }
+
+    #[test]
+    fn unknown_fields_dont_match() {
+        let bare = DeviceIdentity {
+            name: "sdz".to_string(),
+            paths: vec![PathBuf::from("/dev/sdz")],
+            ..Default::default()
+        };
+        assert!(!matches(&rule(Some("*"), None, None, None), &bare));
+        assert!(!matches(&rule(None, Some("*"), None, None), &bare));
+        assert!(!matches(&rule(None, None, Some("*"), None), &bare));
+        assert!(matches(&rule(None, None, None, Some("/dev/*")), &bare));
+    }
+
+    #[test]
+    fn globs_and_case() {
+        let disk = disk();
+        let cases = [
+            // Serials and WWNs are compared whole, ignoring case, never as
+            // globs.
+            (rule(Some("wd-wcc7k1234567"), None, None, None), true),
+            (rule(Some("WD-*"), None, None, None), false),
+            (rule(None, None, Some("0X50014EE2B5A1C3D4"), None), true),
+            (rule(None, None, Some("0x50014ee2*"), None), false),
+            // Models and paths are case sensitive globs.
+            (rule(None, Some("WDC WD40EF??-*"), None, None), true),
+            (rule(None, Some("wdc*"), None, None), false),
+            (rule(None, Some("WDC WD[0-9]0EFRX-*"), None, None), true),
+            (rule(None, Some("WDC"), None, None), false),
+            (rule(None, None, None, Some("/dev/SDA")), false),
+            (
+                rule(None, None, None, Some("/dev/disk/by-id/ata-WDC_*")),
+                true,
+            ),
+            // * goes across slashes, into by-id.
+            (
+                rule(None, None, None, Some("/dev/disk/*WD-WCC7K1234567")),
+                true,
+            ),
+            // One that doesn't parse matches nothing.
+            (rule(None, Some("WDC[*"), None, None), false),
+        ];
+        for (rule, expected) in cases {
+            assert_eq!(matches(&rule, &disk), expected, "{:?}", rule);
+        }
+    }
+
+    #[test]
+    fn device_paths_name_the_device() {
+        assert_eq!(device_name("/dev/sda"), "sda");
+        assert_eq!(device_name("sda"), "sda");
+        assert_eq!(device_name("/dev/disk/by-id/x"), "disk/by-id/x");
+    }
+
+    const MOUNTINFO: &str = "\
+22 1 259:2 / / rw,relatime shared:1 - ext4 /dev/nvme0n1p2 rw,errors=remount-ro
+23 22 0:21 / /proc rw,nosuid,nodev,noexec,relatime shared:12 - proc proc rw
+24 22 259:1 / /boot rw,relatime shared:29 - vfat /dev/nvme0n1p1 rw,fmask=0077
+25 22 253:0 / /mnt/backup\\040disk rw,relatime shared:31 - ext4 /dev/mapper/vg-backup\\040vol rw
+26 22 0:45 /@home /home rw,relatime shared:33 - btrfs /dev/sdb1 rw,space_cache=v2
+27 26 0:46 / /home rw,relatime shared:34 - tmpfs tmpfs rw,size=1024k
+28 22 0:47 / /srv/data rw,relatime - nfs4 nas:/export/data rw
+broken line
+";
+
+    fn mount(path: &str) -> Option<(String, Option<String>)> {
+        mount_of(MOUNTINFO, Path::new(path))
+    }
+
+    fn found(dev: &str, source: &str) -> Option<(String, Option<String>)> {
+        Some((dev.to_string(), Some(source.to_string())))
+    }
+
+    #[test]
+    fn mountinfo_finds_the_longest_mount_point() {
+        assert_eq!(mount("/"), found("259:2", "/dev/nvme0n1p2"));
+        assert_eq!(mount("/etc/hddmond"), found("259:2", "/dev/nvme0n1p2"));
+        assert_eq!(mount("/boot/efi"), found("259:1", "/dev/nvme0n1p1"));
+        // Mount points are compared by component.
+        assert_eq!(mount("/bootstrap"), found("259:2", "/dev/nvme0n1p2"));
+        assert_eq!(mount("/srv/data/x"), found("0:47", "nas:/export/data"));
+    }
+
+    #[test]
+    fn mountinfo_takes_the_last_of_stacked_mounts() {
+        assert_eq!(mount("/home/user"), found("0:46", "tmpfs"));
+    }
+
+    #[test]
+    fn mountinfo_unescapes_mount_points_and_sources() {
+        assert_eq!(
+            mount("/mnt/backup disk/2024"),
+            found("253:0", "/dev/mapper/vg-backup vol")
+        );
+        // Escaped, the mount point doesn't have a space in it.
+        assert_eq!(mount("/mnt/backup"), found("259:2", "/dev/nvme0n1p2"));
+    }
+
+    #[test]
+    fn mountinfo_without_a_match() {
+        assert_eq!(mount_of("", Path::new("/")), None);
+        assert_eq!(mount_of("broken\n\n1 2 3\n", Path::new("/")), None);
+        // No separator, so no source.
+        assert_eq!(
+            mount_of("1 0 8:1 / / rw", Path::new("/")),
+            Some(("8:1".to_string(), None))
+        );
+    }
+
+    #[test]
+    fn octal_escapes() {
+        let cases = [
+            ("plain", "plain"),
+            ("a\\040b", "a b"),
+            ("tab\\011here", "tab\there"),
+            ("new\\012line", "new\nline"),
+            ("back\\134slash", "back\\slash"),
+            ("\\040\\040", "  "),
+            // Not an escape, left as it is.
+            ("trailing\\", "trailing\\"),
+            ("short\\04", "short\\04"),
+            ("not\\9octal", "not\\9octal"),
+            ("too\\777big", "too\\777big"),
+            ("", ""),
+        ];
+        for (field, expected) in cases {
+            assert_eq!(unescape_mount(field), expected, "{:?}", field);
+        }
+    }
+
+    // A sysfs with:
+    //
+    // - sda, partitioned into sda1 and sda2
+    // - sdb, partitioned into sdb1
+    // - sdc, not partitioned
+    // - dm-0 on sda2, like LUKS
+    // - md0, a mirror of sdb1 and sdc
+    // - dm-1 on md0 and dm-0, like an LVM volume group across both
+    struct FakeSysfs(TestDir);
+
+    impl FakeSysfs {
+        fn new() -> Self {
+            let sysfs = Self(TestDir::new("sysfs"));
+
+            for partition in ["sda/sda1", "sda/sda2", "sdb/sdb1"] {
+                let dir = sysfs.block(partition);
+                fs::create_dir_all(&dir).unwrap();
+                fs::write(dir.join("partition"), "1\n").unwrap();
+            }
+            fs::create_dir_all(sysfs.block("sdc")).unwrap();
+            sysfs.slaves("dm-0", &[sysfs.block("sda/sda2")]);
+            sysfs.slaves("md0", &[sysfs.block("sdb/sdb1"), sysfs.block("sdc")]);
+            sysfs.slaves(
+                "dm-1",
+                &[sysfs.virtual_block("md0"), sysfs.virtual_block("dm-0")],
+            );
+            sysfs
+        }
+
+        fn block(&self, path: &str) -> PathBuf {
+            self.0.join("devices/pci0000:00/host0/block").join(path)
+        }
+
+        fn virtual_block(&self, name: &str) -> PathBuf {
+            self.0.join("devices/virtual/block").join(name)
+        }
+
+        fn slaves(&self, name: &str, slaves: &[PathBuf]) {
+            let dir = self.virtual_block(name).join("slaves");
+            fs::create_dir_all(&dir).unwrap();
+            for slave in slaves {
+                symlink(slave, dir.join(slave.file_name().unwrap())).unwrap();
+            }
+        }
+
+        fn disks(&self, sys_path: PathBuf) -> Vec<String> {
+            let mut disks = HashSet::new();
+            backing_disks(&sys_path, &mut disks).unwrap();
+            let mut disks = disks.into_iter().collect::<Vec<_>>();
+            disks.sort();
+            disks
+        }
+    }
+
+    #[test]
+    fn backing_disks_walk_partitions_dm_and_md() {
+        let sysfs = FakeSysfs::new();
+        let cases = [
+            (sysfs.block("sda"), vec!["sda"]),
+            (sysfs.block("sdc"), vec!["sdc"]),
+            (sysfs.block("sda/sda1"), vec!["sda"]),
+            (sysfs.block("sdb/sdb1"), vec!["sdb"]),
+            (sysfs.virtual_block("dm-0"), vec!["sda"]),
+            (sysfs.virtual_block("md0"), vec!["sdb", "sdc"]),
+            (sysfs.virtual_block("dm-1"), vec!["sda", "sdb", "sdc"]),
+        ];
+        for (sys_path, expected) in cases {
+            assert_eq!(
+                sysfs.disks(sys_path.clone()),
+                expected,
+                "{}",
+                sys_path.display()
+            );
+        }
+    }
+
+    #[test]
+    fn broken_slave_links_are_skipped() {
+        let sysfs = FakeSysfs::new();
+        let dir = sysfs.virtual_block("dm-2/slaves");
+        fs::create_dir_all(&dir).unwrap();
+        symlink(sysfs.block("sdq"), dir.join("sdq")).unwrap();
+        symlink(sysfs.block("sdc"), dir.join("sdc")).unwrap();
+        assert_eq!(sysfs.disks(sysfs.virtual_block("dm-2")), ["sdc"]);
+    }
+
+    fn policy(config: DevicesConfig, root: &[&str]) -> DevicePolicy {
+        let mut policy = DevicePolicy::with_root_disks(
+            config,
+            root.iter().map(|disk| disk.to_string()).collect(),
+        );
+        let mut sdb = disk();
+        sdb.name = "sdb".to_string();
+        sdb.paths = vec![PathBuf::from("/dev/sdb")];
+        sdb.serial = Some("WD-WCC7K7654321".to_string());
+        policy.add_identities(vec![disk(), sdb]);
+        policy
+    }
+
+    fn found_event(name: &str) -> ScanEventType {
+        ScanEventType::DeviceFound(name.to_string())
+    }
+
+    #[test]
+    fn ignored_devices_are_dropped_until_theyre_lost() {
+        let mut policy = policy(
+            DevicesConfig {
+                ignore: vec![rule(Some("WD-WCC7K7654321"), None, None, None)],
+                ..Default::default()
+            },
+            &[],
+        );
+        assert!(policy.filter(found_event("/dev/sdb")).is_none());
+        assert!(policy
+            .filter(ScanEventType::DeviceChanged("sdb".to_string()))
+            .is_none());
+        assert!(policy
+            .filter(ScanEventType::DeviceLost("sdb".to_string()))
+            .is_none());
+        assert!(policy.filter(found_event("sda")).is_some());
+        assert!(!policy.is_protected("sda"));
+    }
+
+    #[test]
+    fn protect_rules_protect_until_lost() {
+        let mut policy = policy(
+            DevicesConfig {
+                protect: vec![rule(None, Some("WDC*"), None, None)],
+                ..Default::default()
+            },
+            &[],
+        );
+        assert!(policy.filter(found_event("sdb")).is_some());
+        assert!(policy.is_protected("sdb"));
+        assert!(policy.is_protected("/dev/sdb"));
+        policy.filter(ScanEventType::DeviceLost("sdb".to_string()));
+        assert!(!policy.is_protected("sdb"));
+    }
+
+    #[test]
+    fn the_root_disk_is_never_a_target() {
+        // Nothing in the config says anything about sda.
+        let mut policy = policy(
+            DevicesConfig {
+                protect: vec![rule(Some("WD-WCC7K7654321"), None, None, None)],
+                ..Default::default()
+            },
+            &["sda"],
+        );
+        // Protected before it's even found, by name or path.
+        assert!(policy.is_protected("sda"));
+        assert!(policy.is_protected("/dev/sda"));
+
+        assert!(policy.filter(found_event("/dev/sda")).is_some());
+        assert!(policy.is_protected("sda"));
+        // Losing it and finding it again doesn't let it go.
+        policy.filter(ScanEventType::DeviceLost("sda".to_string()));
+        assert!(policy.is_protected("sda"));
+        policy.filter(found_event("sda"));
+        assert!(policy.is_protected("sda"));
+        // Nor does releasing it from quarantine.
+        policy.set_quarantined("sda", true);
+        policy.set_quarantined("sda", false);
+        assert!(policy.is_protected("sda"));
+
+        assert!(policy.filter(found_event("sdb")).is_some());
+        assert!(policy.is_protected("sdb"));
+    }
+
+    #[test]
+    fn quarantine_stops_everything_that_changes_a_device() {
+        let mut policy = policy(
+            DevicesConfig {
+                protect: vec![rule(Some("WD-WCC7K7654321"), None, None, None)],
+                ..Default::default()
+            },
+            &[],
+        );
+        policy.filter(found_event("sda"));
+        policy.filter(found_event("sdb"));
+        assert!(!policy.is_protected("sda"));
+        assert!(policy.may_change_settings("sda"));
+        // Protected doesn't keep its settings from being applied.
+        assert!(policy.is_protected("sdb"));
+        assert!(policy.may_change_settings("sdb"));
+
+        // Neither destructive tasks, nor the write probe, nor its power
+        // settings, by name or path.
+        policy.set_quarantined("/dev/sda", true);
+        assert!(policy.is_quarantined("sda"));
+        assert!(policy.is_protected("sda"));
+        assert!(policy.is_protected("/dev/sda"));
+        assert!(!policy.may_change_settings("sda"));
+        assert!(!policy.may_change_settings("/dev/sda"));
+        // Changes and rescans don't let it out.
+        policy.filter(ScanEventType::DeviceChanged("sda".to_string()));
+        policy.filter(found_event("sda"));
+        assert!(policy.is_quarantined("sda"));
+        // Others aren't touched.
+        assert!(!policy.is_quarantined("sdb"));
+        assert!(policy.may_change_settings("sdb"));
+
+        // Lost, it's up to the registry to say again when it's back.
+        policy.filter(ScanEventType::DeviceLost("sda".to_string()));
+        assert!(!policy.is_quarantined("sda"));
+        policy.filter(found_event("sda"));
+        policy.set_quarantined("sda", true);
+        policy.set_quarantined("sda", false);
+        assert!(!policy.is_protected("sda"));
+        assert!(policy.may_change_settings("sda"));
+
+        // Let out, a protected one stays protected.
+        policy.set_quarantined("sdb", true);
+        policy.set_quarantined("sdb", false);
+        assert!(policy.is_protected("sdb"));
+    }
+
+    #[test]
+    fn protect_root_can_be_turned_off() {
+        let policy = DevicePolicy::new(DevicesConfig {
+            protect_root: false,
+            ..Default::default()
+        });
+        assert!(policy.root_disks.is_empty());
+    }
+
+    #[test]
+    fn firmware_revisions_from_sysfs() {
+        let sys = TestDir::new("firmware");
+        let cases = [
+            (
+                "nvme0n1",
+                vec![("firmware_rev", "GXA7801Q\n")],
+                Some("GXA7801Q"),
+            ),
+            // SCSI pads it out to four characters.
+            ("sda", vec![("rev", "0A80    \n")], Some("0A80")),
+            (
+                "mmcblk0",
+                vec![("fwrev", "0x0200000000000000\n")],
+                Some("0x0200000000000000"),
+            ),
+            // An empty one says nothing, the next one is tried.
+            (
+                "sdb",
+                vec![("firmware_rev", "  \n"), ("rev", "MQ03\n")],
+                Some("MQ03"),
+            ),
+            ("sdc", vec![("rev", "")], None),
+            ("sdd", vec![], None),
+        ];
+        for (name, files, _) in &cases {
+            let device = sys.join("class/block").join(name).join("device");
+            fs::create_dir_all(&device).unwrap();
+            for (file, contents) in files {
+                fs::write(device.join(file), contents).unwrap();
+            }
+        }
+
+        for (name, _, expected) in cases {
+            assert_eq!(
+                sysfs_firmware(sys.path(), name).as_deref(),
+                expected,
+                "{}",
+                name
+            );
+        }
+        assert_eq!(sysfs_firmware(sys.path(), "sdz"), None);
+    }
+
+    #[test]
+    fn aliases_from_where_a_disk_is_plugged_in() {
+        let sys = TestDir::new("alias");
+        // sdc in slot 3 of the enclosure at 0:0:8:0, sdd right on the HBA.
+        let host = sys.join("devices/pci0000:00/0000:00:17.0/host0");
+        let slot = host.join("target0:0:8/0:0:8:0/enclosure/0:0:8:0/Slot03");
+        fs::create_dir_all(&slot).unwrap();
+        fs::create_dir_all(sys.join("class/block")).unwrap();
+        for (name, target) in [("sdc", 3), ("sdd", 4)] {
+            let scsi = host.join(format!("target0:0:{0}/0:0:{0}:0", target));
+            fs::create_dir_all(scsi.join("block").join(name)).unwrap();
+            symlink(
+                scsi.join("block").join(name),
+                sys.join("class/block").join(name),
+            )
+            .unwrap();
+        }
+        symlink(
+            &slot,
+            host.join("target0:0:3/0:0:3:0/enclosure_device:Slot03"),
+        )
+        .unwrap();
+
+        let policy = |template: Option<&str>| {
+            DevicePolicy::new(DevicesConfig {
+                protect_root: false,
+                alias_template: template.map(String::from),
+                ..Default::default()
+            })
+        };
+        let alias = |template: Option<&str>, name: &str| {
+            let identity = DeviceIdentity {
+                name: name.to_string(),
+                ..disk()
+            };
+            policy(template).alias_at(&identity, DeviceLocation::from_sysfs(sys.path(), name))
+        };
+        let cases = [
+            (Some("bay-{{slot}}"), "sdc", Some("bay-Slot03")),
+            (
+                Some("{{enclosure}}/{{ slot }}"),
+                "sdc",
+                Some("0:0:8:0/Slot03"),
+            ),
+            (
+                Some("{{controller}} {{name}}"),
+                "sdd",
+                Some("0000:00:17.0 sdd"),
+            ),
+            (Some("{{location.scsi.target}}"), "sdd", Some("4")),
+            (Some("{{serial}}"), "sdz", Some("WD-WCC7K1234567")),
+            // Not in an enclosure, or not there at all.
+            (Some("bay-{{slot}}"), "sdd", None),
+            (Some("bay-{{slot}}"), "sdz", None),
+            (Some("{{nothing}}"), "sdc", None),
+            (Some("  "), "sdc", None),
+            (None, "sdc", None),
+        ];
+        for (template, name, expected) in cases {
+            assert_eq!(
+                alias(template, name).as_deref(),
+                expected,
+                "{:?} for {}",
+                template,
+                name
+            );
+        }
+        // One that doesn't parse was turned away by validate, and names nothing.
+        assert_eq!(alias(Some("bay-{{slot"), "sdc"), None);
+    }
+}
@@ -0,0 +1,309 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use nix::sys::statvfs::statvfs;
+
+use crate::{config::Config, storage::IN_MEMORY};
+
+const MIB: u64 = 1024 * 1024;
+
+// How much space is free through a directory, free_bytes unless testing.
+type FreeSpace = Box<dyn Fn(&Path) -> nix::Result<u64> + Send>;
+
+/// A filesystem the guard found short on space.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LowSpace {
+    /// The directory it was checked through.
+    pub dir: PathBuf,
+    /// How much is free there, for unprivileged users.
+    pub free_bytes: u64,
+}
+
+impl fmt::Display for LowSpace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} MiB free for {}",
+            self.free_bytes / MIB,
+            self.dir.display()
+        )
+    }
+}
+
+/// What a check found changed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpaceChange {
+    /// A filesystem went under disk_guard.min_free_mb.
+    Low(LowSpace),
+    /// Every filesystem is back over disk_guard.resume_free_mb.
+    Recovered,
+}
+
+/// Keeps an eye on the free space where the daemon writes its own files, so
+/// it can stop writing what it can do without before SQLite runs out of
+/// room halfway through a transaction. Goes low when any of them drops under
+/// disk_guard.min_free_mb, and back only once all of them are over
+/// disk_guard.resume_free_mb.
+pub struct DiskGuard {
+    dirs: Vec<PathBuf>,
+    free_space: FreeSpace,
+    min_free_bytes: u64,
+    resume_free_bytes: u64,
+    low: bool,
+}
+
+impl DiskGuard {
+    /// Watches the directories of the database, the log file and the audit
+    /// trail, whichever of them there are.
+    pub fn new(config: &Config) -> Self {
+        Self::measuring_with(config, Box::new(free_bytes))
+    }
+
+    fn measuring_with(config: &Config, free_space: FreeSpace) -> Self {
+        let files = [
+            Some(config.storage.path.as_path()).filter(|path| *path != Path::new(IN_MEMORY)),
+            config.logging.file.as_ref().map(|file| file.path.as_path()),
+            config.audit.path.as_deref(),
+        ];
+        let mut dirs: Vec<PathBuf> = vec![];
+        for dir in files.into_iter().flatten().filter_map(Path::parent) {
+            if !dirs.iter().any(|known| known == dir) {
+                dirs.push(dir.to_path_buf());
+            }
+        }
+
+        Self {
+            dirs,
+            free_space,
+            min_free_bytes: config.disk_guard.min_free_mb * MIB,
+            resume_free_bytes: config.disk_guard.resume_free_mb * MIB,
+            low: false,
+        }
+    }
+
+    /// Measures again, returning whether that changed anything.
+    pub fn check(&mut self) -> Option<SpaceChange> {
+        if self.min_free_bytes == 0 {
+            return None;
+        }
+
+        let tightest = self
+            .dirs
+            .iter()
+            .filter_map(|dir| match (self.free_space)(dir) {
+                Ok(free_bytes) => Some(LowSpace {
+                    dir: dir.clone(),
+                    free_bytes,
+                }),
+                Err(e) => {
+                    debug!(
+                        "Can't tell how much space is free for {}: {}",
+                        dir.display(),
+                        e
+                    );
+                    None
+                }
+            })
+            .min_by_key(|space| space.free_bytes)?;
+
+        if !self.low && tightest.free_bytes < self.min_free_bytes {
+            self.low = true;
+            Some(SpaceChange::Low(tightest))
+        } else if self.low && tightest.free_bytes >= self.resume_free_bytes {
+            self.low = false;
+            Some(SpaceChange::Recovered)
+        } else {
+            None
+        }
+    }
+}
+
+/// What an unprivileged process could still write, the daemon may well not
+/// be root.
+pub fn free_bytes(dir: &Path) -> nix::Result<u64> {
+    let stats = statvfs(dir)?;
+    Ok(stats.blocks_available() as u64 * stats.fragment_size() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+
+    use nix::errno::Errno;
+
+    use super::*;
+    use crate::storage::Registry;
+
+    const CONFIG: &str = r#"
+        [storage]
+        path = "/var/lib/hddmond/registry.db"
+        [logging.file]
+        path = "/var/log/hddmond/hddmond.log"
+        [audit]
+        path = "/var/lib/hddmond/audit.jsonl"
+        [disk_guard]
+        min_free_mb = 100
+        resume_free_mb = 200
+    "#;
+
+    // statvfs as the test says, in MiB free by directory. Directories it
+    // says nothing about can't be measured.
+    #[derive(Clone, Default)]
+    struct Statvfs(Arc<Mutex<HashMap<PathBuf, u64>>>);
+
+    impl Statvfs {
+        fn set(&self, dir: &str, free_mb: u64) {
+            self.0.lock().unwrap().insert(PathBuf::from(dir), free_mb);
+        }
+
+        fn guard(&self, config: &str) -> DiskGuard {
+            let free = self.clone();
+            DiskGuard::measuring_with(
+                &toml::from_str(config).unwrap(),
+                Box::new(move |dir| {
+                    free.0
+                        .lock()
+                        .unwrap()
+                        .get(dir)
+                        .map(|free_mb| free_mb * MIB)
+                        .ok_or(Errno::ENOENT)
+                }),
+            )
+        }
+    }
+
+    fn low(dir: &str, free_mb: u64) -> Option<SpaceChange> {
+        Some(SpaceChange::Low(LowSpace {
+            dir: PathBuf::from(dir),
+            free_bytes: free_mb * MIB,
+        }))
+    }
+
+    #[test]
+    fn every_directory_the_daemon_writes_to_is_watched_once() {
+        let guard = Statvfs::default().guard(CONFIG);
+        assert_eq!(
+            guard.dirs,
+            [
+                PathBuf::from("/var/lib/hddmond"),
+                PathBuf::from("/var/log/hddmond")
+            ]
+        );
+
+        let guard = Statvfs::default().guard("[storage]\npath = \":memory:\"\n");
+        assert_eq!(guard.dirs, [] as [PathBuf; 0]);
+    }
+
+    #[test]
+    fn low_under_the_minimum_and_back_over_the_resume_level() {
+        let statvfs = Statvfs::default();
+        statvfs.set("/var/lib/hddmond", 500);
+        statvfs.set("/var/log/hddmond", 1000);
+        let mut guard = statvfs.guard(CONFIG);
+
+        // Free space by the check, for the registry's filesystem, and what
+        // the guard made of it. In between the two levels, nothing changes
+        // either way.
+        for (free_mb, change) in [
+            (500, None),
+            (100, None),
+            (99, low("/var/lib/hddmond", 99)),
+            (50, None),
+            (150, None),
+            (99, None),
+            (199, None),
+            (200, Some(SpaceChange::Recovered)),
+            (150, None),
+            (100, None),
+            (0, low("/var/lib/hddmond", 0)),
+            (u64::MAX / MIB, Some(SpaceChange::Recovered)),
+        ] {
+            statvfs.set("/var/lib/hddmond", free_mb);
+            assert_eq!(guard.check(), change, "{} MiB", free_mb);
+        }
+    }
+
+    #[test]
+    fn the_tightest_directory_decides() {
+        let statvfs = Statvfs::default();
+        statvfs.set("/var/lib/hddmond", 500);
+        statvfs.set("/var/log/hddmond", 50);
+        let mut guard = statvfs.guard(CONFIG);
+        assert_eq!(guard.check(), low("/var/log/hddmond", 50));
+
+        // All of them have to have room again.
+        statvfs.set("/var/log/hddmond", 300);
+        statvfs.set("/var/lib/hddmond", 150);
+        assert_eq!(guard.check(), None);
+        statvfs.set("/var/lib/hddmond", 250);
+        assert_eq!(guard.check(), Some(SpaceChange::Recovered));
+
+        // One that can't be measured is left out, and with none left
+        // there's nothing to go on.
+        statvfs
+            .0
+            .lock()
+            .unwrap()
+            .remove(Path::new("/var/log/hddmond"));
+        statvfs.set("/var/lib/hddmond", 10);
+        assert_eq!(guard.check(), low("/var/lib/hddmond", 10));
+        statvfs.0.lock().unwrap().clear();
+        assert_eq!(guard.check(), None);
+    }
+
+    #[test]
+    fn a_minimum_of_zero_turns_it_off() {
+        let statvfs = Statvfs::default();
+        statvfs.set("/var/lib/hddmond", 0);
+        let mut guard = statvfs.guard(&CONFIG.replace("min_free_mb = 100", "min_free_mb = 0"));
+        assert_eq!(guard.check(), None);
+    }
+
+    #[test]
+    fn device_events_pause_while_low_and_resume_after() {
+        let statvfs = Statvfs::default();
+        statvfs.set("/var/lib/hddmond", 1000);
+        statvfs.set("/var/log/hddmond", 1000);
+        let mut guard = statvfs.guard(CONFIG);
+        let mut registry = Registry::open(Path::new(IN_MEMORY)).unwrap();
+        let drive = crate::device_policy::DeviceIdentity {
+            name: "sda".to_string(),
+            serial: Some("WD-1".to_string()),
+            model: Some("WDC WD40EFRX-68N32N0".to_string()),
+            ..Default::default()
+        };
+        registry.device_found(&drive).unwrap();
+
+        // What the main loop does with each change.
+        let mut check = |registry: &mut Registry| match guard.check() {
+            Some(SpaceChange::Low(_)) => registry.set_low_on_space(true),
+            Some(SpaceChange::Recovered) => registry.set_low_on_space(false),
+            None => {}
+        };
+        let mut log_at = |registry: &mut Registry, free_mb, summary| {
+            statvfs.set("/var/lib/hddmond", free_mb);
+            check(registry);
+            registry.log_event(&drive, summary).unwrap();
+        };
+        log_at(&mut registry, 1000, "kept");
+        log_at(&mut registry, 90, "dropped while low");
+        log_at(&mut registry, 150, "dropped, not recovered yet");
+        log_at(&mut registry, 250, "kept again");
+
+        let id = registry.device("WD-1").unwrap().unwrap().id;
+        let summaries = registry
+            .events(id, None)
+            .unwrap()
+            .into_iter()
+            .map(|event| event.summary)
+            .collect::<Vec<_>>();
+        assert_eq!(summaries, ["Found as sda", "kept", "kept again"]);
+        // The device itself is still tracked throughout.
+        assert!(registry.device("WD-1").unwrap().unwrap().present);
+    }
+}
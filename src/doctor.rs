@@ -0,0 +1,957 @@
+use std::{
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+use serde::Serialize;
+
+use crate::{
+    config::{Backend, Config},
+    mmc, storage,
+};
+
+// smartctl learned --json in 7.0, the capacity and capability checks read
+// nothing else.
+const SMARTCTL_JSON_SINCE: (u32, u32) = (7, 0);
+
+/// How a check went, worst last.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Verdict {
+    /// Nothing to worry about.
+    Pass,
+    /// Something that's on won't work, or will work less well, but the
+    /// daemon runs.
+    Warn,
+    /// Something that's on can't work, the daemon won't start.
+    Fail,
+}
+
+impl Verdict {
+    /// What `hddmond doctor` prints for it.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Verdict::Pass => "PASS",
+            Verdict::Warn => "WARN",
+            Verdict::Fail => "FAIL",
+        }
+    }
+}
+
+/// What one check found.
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    /// What was checked, like "smartctl".
+    pub name: &'static str,
+    /// How it went.
+    pub verdict: Verdict,
+    /// What was found, whatever the verdict.
+    pub detail: String,
+    /// What to do about it, for anything but a pass.
+    pub hint: Option<String>,
+}
+
+impl Check {
+    fn pass(name: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            verdict: Verdict::Pass,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    fn problem(
+        name: &'static str,
+        verdict: Verdict,
+        detail: impl Into<String>,
+        hint: impl Into<String>,
+    ) -> Self {
+        Self {
+            name,
+            verdict,
+            detail: detail.into(),
+            hint: Some(hint.into()),
+        }
+    }
+}
+
+/// Whatever the checks need to find out about the machine, so each one can
+/// be run against a made up one.
+pub trait Probes {
+    /// Runs a program to the end. NotFound if there's no such program.
+    fn run(&self, program: &Path, args: &[&str]) -> io::Result<Output>;
+    /// Opens a udev monitor socket and closes it again.
+    fn listen_udev(&self) -> io::Result<()>;
+    /// Kernel names of the whole disks, partitions left out.
+    fn block_devices(&self) -> Vec<String>;
+    /// Opens it read-only and closes it again, reading nothing.
+    fn open_read(&self, path: &Path) -> io::Result<()>;
+    /// Creates a file in `dir` and removes it again.
+    fn create_file(&self, dir: &Path) -> io::Result<()>;
+    /// None if nothing is there.
+    fn is_dir(&self, path: &Path) -> Option<bool>;
+    /// What's in a directory.
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>>;
+}
+
+/// The machine we're on.
+pub struct System;
+
+impl Probes for System {
+    fn run(&self, program: &Path, args: &[&str]) -> io::Result<Output> {
+        Command::new(program).args(args).output()
+    }
+
+    fn listen_udev(&self) -> io::Result<()> {
+        udev::MonitorBuilder::new()?.listen().map(drop)
+    }
+
+    fn block_devices(&self) -> Vec<String> {
+        let mut names = fs::read_dir("/sys/class/block")
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| !entry.path().join("partition").exists())
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .filter(|name| !mmc::is_hardware_partition(name))
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    fn open_read(&self, path: &Path) -> io::Result<()> {
+        File::open(path).map(drop)
+    }
+
+    fn create_file(&self, dir: &Path) -> io::Result<()> {
+        let path = dir.join(format!(".hddmond-doctor-{}", std::process::id()));
+        File::create(&path)?;
+        fs::remove_file(&path)
+    }
+
+    fn is_dir(&self, path: &Path) -> Option<bool> {
+        fs::metadata(path).ok().map(|metadata| metadata.is_dir())
+    }
+
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        fs::read_dir(dir)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect()
+    }
+}
+
+/// Everything but the config, which has to have loaded for these to run.
+pub fn checks(config: &Config, probes: &impl Probes) -> Vec<Check> {
+    vec![
+        smartctl(config, probes),
+        hdparm(config, probes),
+        mmc_utils(probes),
+        udev(config, probes),
+        block_read(config, probes),
+        database(config, probes),
+        plugins(config, probes),
+    ]
+}
+
+/// `problem` is why the config couldn't be loaded, with everything that led
+/// to it.
+pub fn config(path: &Path, exists: bool, problem: Option<&str>) -> Check {
+    match problem {
+        Some(problem) => Check::problem(
+            "config",
+            Verdict::Fail,
+            problem.to_string(),
+            format!(
+                "Fix {}, or move it aside to run on the defaults",
+                path.display()
+            ),
+        ),
+        None if exists => Check::pass("config", format!("{} is valid", path.display())),
+        None => Check::pass(
+            "config",
+            format!("There's no {}, running on the defaults", path.display()),
+        ),
+    }
+}
+
+/// The smartctl and composite backends can't do without it. Otherwise it's
+/// only the capacity and capability checks that go without.
+pub fn smartctl(config: &Config, probes: &impl Probes) -> Check {
+    const NAME: &str = "smartctl";
+    if config.monitor.backend == Backend::Simulated {
+        return Check::pass(NAME, "Not needed with the simulated backend");
+    }
+    let (verdict, without) = if matches!(
+        config.monitor.backend,
+        Backend::Smartctl | Backend::Composite
+    ) {
+        (
+            Verdict::Fail,
+            format!("the {} backend can't run", config.monitor.backend.as_str()),
+        )
+    } else {
+        (
+            Verdict::Warn,
+            "drives' capacity and capabilities won't be checked".to_string(),
+        )
+    };
+
+    let path = config
+        .smartctl
+        .path
+        .clone()
+        .unwrap_or_else(|| PathBuf::from("smartctl"));
+    let version = match probes.run(&path, &["--version"]) {
+        Ok(output) if output.status.success() => {
+            smartctl_version(&String::from_utf8_lossy(&output.stdout))
+        }
+        Ok(output) => {
+            return Check::problem(
+                NAME,
+                verdict,
+                format!(
+                    "{} --version failed ({}), {}",
+                    path.display(),
+                    output.status,
+                    without
+                ),
+                "Check that smartmontools is installed properly",
+            )
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Check::problem(
+                NAME,
+                verdict,
+                format!("{} isn't there, {}", path.display(), without),
+                "Install smartmontools, or point smartctl.path at smartctl",
+            )
+        }
+        Err(e) => {
+            return Check::problem(
+                NAME,
+                verdict,
+                format!("Can't run {}: {}, {}", path.display(), e, without),
+                "Make smartctl executable by the user hddmond runs as",
+            )
+        }
+    };
+
+    // Asked for rather than worked out from the version, distributions
+    // patch these things.
+    let json = probes
+        .run(&path, &["--json", "--version"])
+        .ok()
+        .filter(|output| output.status.success())
+        .is_some_and(|output| serde_json::from_slice::<serde_json::Value>(&output.stdout).is_ok());
+    let version = match version {
+        Some((major, minor)) => format!("smartctl {}.{}", major, minor),
+        None => "smartctl of an unknown version".to_string(),
+    };
+    if json {
+        Check::pass(
+            NAME,
+            format!("{} at {}, with JSON output", version, path.display()),
+        )
+    } else {
+        Check::problem(
+            NAME,
+            verdict,
+            format!("{} can't give JSON output, {}", version, without),
+            format!(
+                "Install smartmontools {}.{} or later",
+                SMARTCTL_JSON_SINCE.0, SMARTCTL_JSON_SINCE.1
+            ),
+        )
+    }
+}
+
+// From the first line of `smartctl --version`, like
+// `smartctl 7.3 2022-02-28 r5338 [x86_64-linux-6.1.0] (local build)`.
+fn smartctl_version(output: &str) -> Option<(u32, u32)> {
+    let version = output.lines().next()?.split_whitespace().nth(1)?;
+    let (major, minor) = version.split_once('.')?;
+    Some((major.parse().ok()?, minor.parse().ok()?))
+}
+
+/// Only needed when there are power policies to apply.
+pub fn hdparm(config: &Config, probes: &impl Probes) -> Check {
+    const NAME: &str = "hdparm";
+    if config.power.policies.is_empty() {
+        return Check::pass(NAME, "Not needed, there are no power.policies");
+    }
+
+    let path = config
+        .power
+        .hdparm
+        .clone()
+        .unwrap_or_else(|| PathBuf::from("hdparm"));
+    match probes.run(&path, &["-V"]) {
+        Ok(output) => Check::pass(
+            NAME,
+            format!(
+                "{} at {}",
+                String::from_utf8_lossy(&output.stdout).trim(),
+                path.display()
+            ),
+        ),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Check::problem(
+            NAME,
+            Verdict::Fail,
+            format!(
+                "{} isn't there, power.policies can't be applied",
+                path.display()
+            ),
+            "Install hdparm, or point power.hdparm at it",
+        ),
+        Err(e) => Check::problem(
+            NAME,
+            Verdict::Fail,
+            format!("Can't run {}: {}", path.display(), e),
+            "Make hdparm executable by the user hddmond runs as",
+        ),
+    }
+}
+
+/// eMMC wear is read from sysfs where the kernel has it, mmc-utils is only
+/// for the ones where it doesn't.
+pub fn mmc_utils(probes: &impl Probes) -> Check {
+    const NAME: &str = "mmc-utils";
+    if !probes
+        .block_devices()
+        .iter()
+        .any(|name| name.starts_with("mmcblk"))
+    {
+        return Check::pass(NAME, "Not needed, there are no eMMC devices");
+    }
+    match probes.run(Path::new("mmc"), &["--version"]) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Check::problem(
+            NAME,
+            Verdict::Warn,
+            "mmc isn't there, eMMC wear is only known where the kernel shows it",
+            "Install mmc-utils",
+        ),
+        Err(e) => Check::problem(
+            NAME,
+            Verdict::Warn,
+            format!("Can't run mmc: {}", e),
+            "Make mmc executable by the user hddmond runs as",
+        ),
+        Ok(_) => Check::pass(NAME, "mmc is there"),
+    }
+}
+
+/// The udev and composite backends can't do without it, auto falls back
+/// to smartctl.
+pub fn udev(config: &Config, probes: &impl Probes) -> Check {
+    const NAME: &str = "udev";
+    let verdict = match config.monitor.backend {
+        Backend::Udev | Backend::Composite => Verdict::Fail,
+        Backend::Auto => Verdict::Warn,
+        Backend::Smartctl | Backend::Simulated => {
+            return Check::pass(
+                NAME,
+                format!(
+                    "Not needed with the {} backend",
+                    config.monitor.backend.as_str()
+                ),
+            )
+        }
+    };
+
+    match probes.listen_udev() {
+        Ok(()) => Check::pass(NAME, "Can listen for device events"),
+        Err(e) => Check::problem(
+            NAME,
+            verdict,
+            if verdict == Verdict::Fail {
+                format!("Can't listen for device events: {}", e)
+            } else {
+                format!(
+                    "Can't listen for device events ({}), falling back to polling smartctl, \
+                     which doesn't see USB devices",
+                    e
+                )
+            },
+            "Run as root, and in a container bind mount /run/udev into it",
+        ),
+    }
+}
+
+/// Probing and identifying drives reads them, on one drive goes for all.
+pub fn block_read(config: &Config, probes: &impl Probes) -> Check {
+    const NAME: &str = "block devices";
+    if config.monitor.backend == Backend::Simulated {
+        return Check::pass(NAME, "Not needed with the simulated backend");
+    }
+
+    let devices = probes.block_devices();
+    let sample = devices
+        .iter()
+        .find(|name| {
+            !["loop", "ram", "zram"]
+                .iter()
+                .any(|skip| name.starts_with(skip))
+        })
+        .or(devices.first());
+    let sample = match sample {
+        Some(sample) => Path::new("/dev").join(sample),
+        None => {
+            return Check::problem(
+                NAME,
+                Verdict::Warn,
+                "There are no block devices to try reading",
+                "Nothing to do unless drives should be here, like in a container without /sys",
+            )
+        }
+    };
+
+    match probes.open_read(&sample) {
+        Ok(()) => Check::pass(NAME, format!("Can read {}", sample.display())),
+        Err(e) => Check::problem(
+            NAME,
+            Verdict::Fail,
+            format!("Can't read {}: {}", sample.display(), e),
+            "Run as root, or as a user in the disk group",
+        ),
+    }
+}
+
+/// The registry's directory has to be there, or be possible to create, and
+/// be writable.
+pub fn database(config: &Config, probes: &impl Probes) -> Check {
+    const NAME: &str = "database";
+    let path = &config.storage.path;
+    if path == Path::new(storage::IN_MEMORY) {
+        return Check::pass(NAME, "Kept in memory");
+    }
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+
+    // It's created along with any missing parents, which takes being able
+    // to write to the closest one that's there.
+    let existing = dir
+        .ancestors()
+        .find(|ancestor| probes.is_dir(ancestor).is_some())
+        .unwrap_or(dir);
+    if probes.is_dir(existing) == Some(false) {
+        return Check::problem(
+            NAME,
+            Verdict::Fail,
+            format!("{} isn't a directory", existing.display()),
+            "Point storage.path somewhere else",
+        );
+    }
+
+    let hint = format!(
+        "Give the user hddmond runs as write access to {}, or point storage.path somewhere \
+         writable",
+        existing.display()
+    );
+    match probes.create_file(existing) {
+        Ok(()) if existing == dir => Check::pass(NAME, format!("Can write to {}", dir.display())),
+        Ok(()) => Check::pass(
+            NAME,
+            format!(
+                "{} will be created in {}",
+                dir.display(),
+                existing.display()
+            ),
+        ),
+        Err(e) => Check::problem(
+            NAME,
+            Verdict::Fail,
+            format!("Can't write to {}: {}", existing.display(), e),
+            hint,
+        ),
+    }
+}
+
+/// A missing plugin directory only means no plugins, an unreadable one
+/// keeps the daemon from loading them.
+pub fn plugins(config: &Config, probes: &impl Probes) -> Check {
+    const NAME: &str = "plugins";
+    let dir = &config.plugin_host.dir;
+    match probes.is_dir(dir) {
+        None => return Check::pass(NAME, format!("No {}, no plugins", dir.display())),
+        Some(false) => {
+            return Check::problem(
+                NAME,
+                Verdict::Warn,
+                format!("{} isn't a directory, no plugins are loaded", dir.display()),
+                "Point plugin_host.dir at the plugin directory",
+            )
+        }
+        Some(true) => {}
+    }
+
+    match probes.read_dir(dir) {
+        Ok(paths) => {
+            let plugins = paths
+                .iter()
+                .filter(|path| {
+                    matches!(
+                        path.extension().and_then(|ext| ext.to_str()),
+                        Some("js") | Some("mjs")
+                    )
+                })
+                .count();
+            Check::pass(NAME, format!("{} plugin(s) in {}", plugins, dir.display()))
+        }
+        Err(e) => Check::problem(
+            NAME,
+            Verdict::Fail,
+            format!("Can't read {}: {}", dir.display(), e),
+            format!(
+                "Make {} readable by the user hddmond runs as",
+                dir.display()
+            ),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, os::unix::process::ExitStatusExt, process::ExitStatus};
+
+    use super::*;
+
+    // A program's arguments, the code it exits with for them, and what it
+    // prints to stdout.
+    type Run = (&'static [&'static str], i32, &'static str);
+
+    // A made up machine. Programs that aren't listed aren't there, paths
+    // that aren't listed don't exist.
+    #[derive(Default)]
+    struct Machine {
+        // Anything a program isn't listed with here, it exits 1 for.
+        programs: HashMap<&'static str, Vec<Run>>,
+        // Programs that are there but can't be run.
+        not_executable: Vec<&'static str>,
+        udev: Option<io::ErrorKind>,
+        devices: Vec<&'static str>,
+        unreadable: Vec<&'static str>,
+        // Directories, and files that aren't.
+        dirs: Vec<&'static str>,
+        files: Vec<&'static str>,
+        read_only: Vec<&'static str>,
+        entries: HashMap<&'static str, Vec<&'static str>>,
+    }
+
+    fn denied() -> io::Error {
+        io::Error::from(io::ErrorKind::PermissionDenied)
+    }
+
+    impl Probes for Machine {
+        fn run(&self, program: &Path, args: &[&str]) -> io::Result<Output> {
+            let program = program.to_str().unwrap();
+            if self.not_executable.contains(&program) {
+                return Err(denied());
+            }
+            let runs = self
+                .programs
+                .get(program)
+                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+            let (_, code, stdout) = runs
+                .iter()
+                .find(|(takes, _, _)| *takes == args)
+                .copied()
+                .unwrap_or((&[], 1, ""));
+            Ok(Output {
+                status: ExitStatus::from_raw(code << 8),
+                stdout: stdout.as_bytes().to_vec(),
+                stderr: vec![],
+            })
+        }
+
+        fn listen_udev(&self) -> io::Result<()> {
+            self.udev.map_or(Ok(()), |kind| Err(kind.into()))
+        }
+
+        fn block_devices(&self) -> Vec<String> {
+            self.devices.iter().map(|name| name.to_string()).collect()
+        }
+
+        fn open_read(&self, path: &Path) -> io::Result<()> {
+            match self.unreadable.contains(&path.to_str().unwrap()) {
+                true => Err(denied()),
+                false => Ok(()),
+            }
+        }
+
+        fn create_file(&self, dir: &Path) -> io::Result<()> {
+            match self.read_only.contains(&dir.to_str().unwrap()) {
+                true => Err(io::Error::from(io::ErrorKind::ReadOnlyFilesystem)),
+                false => Ok(()),
+            }
+        }
+
+        fn is_dir(&self, path: &Path) -> Option<bool> {
+            let path = path.to_str().unwrap();
+            if self.dirs.contains(&path) {
+                Some(true)
+            } else if self.files.contains(&path) {
+                Some(false)
+            } else {
+                None
+            }
+        }
+
+        fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+            let entries = self.entries.get(dir.to_str().unwrap()).ok_or_else(denied)?;
+            Ok(entries.iter().map(|entry| dir.join(entry)).collect())
+        }
+    }
+
+    const SMARTCTL_7_3: &str = "smartctl 7.3 2022-02-28 r5338 [x86_64-linux-6.1.0] (local build)\n\
+                                Copyright (C) 2002-22, Bruce Allen, Christian Franke";
+    const SMARTCTL_6_6: &str = "smartctl 6.6 2017-11-05 r4594 [x86_64-linux-4.19.0] (local build)";
+
+    // Everything there and working, as on a well set up server.
+    fn healthy() -> Machine {
+        Machine {
+            programs: HashMap::from([
+                (
+                    "smartctl",
+                    vec![
+                        (&["--version"][..], 0, SMARTCTL_7_3),
+                        (&["--json", "--version"][..], 0, r#"{"smartctl": {}}"#),
+                    ],
+                ),
+                ("hdparm", vec![(&["-V"][..], 0, "hdparm v9.65\n")]),
+                ("mmc", vec![(&["--version"][..], 0, "mmc-utils 1.0")]),
+            ]),
+            devices: vec!["loop0", "mmcblk0", "sda"],
+            dirs: vec![
+                "/",
+                "/var",
+                "/var/lib",
+                "/var/lib/hddmond",
+                "/etc/hddmond/plugins",
+            ],
+            entries: HashMap::from([(
+                "/etc/hddmond/plugins",
+                vec!["tag.js", "notes.txt", "slack.mjs"],
+            )]),
+            ..Default::default()
+        }
+    }
+
+    fn config(toml: &str) -> Config {
+        let mut config: Config = toml::from_str(toml).unwrap();
+        config.storage.path = PathBuf::from("/var/lib/hddmond/hddmond.db");
+        config.plugin_host.dir = PathBuf::from("/etc/hddmond/plugins");
+        config
+    }
+
+    fn backend(backend: &str) -> Config {
+        config(&format!("[monitor]\nbackend = {:?}", backend))
+    }
+
+    #[test]
+    fn a_healthy_machine_passes_everything() {
+        let config = config("[[power.policies]]\ndevice = { model = \"WDC*\" }\napm = 128\n");
+        let checks = checks(&config, &healthy());
+        assert!(
+            checks.iter().all(|check| check.verdict == Verdict::Pass),
+            "{:#?}",
+            checks
+        );
+        assert!(checks.iter().all(|check| check.hint.is_none()));
+        let detail = |name| {
+            checks
+                .iter()
+                .find(|check| check.name == name)
+                .unwrap()
+                .detail
+                .clone()
+        };
+        assert_eq!(
+            detail("smartctl"),
+            "smartctl 7.3 at smartctl, with JSON output"
+        );
+        assert_eq!(detail("hdparm"), "hdparm v9.65 at hdparm");
+        assert_eq!(detail("block devices"), "Can read /dev/mmcblk0");
+        assert_eq!(detail("database"), "Can write to /var/lib/hddmond");
+        assert_eq!(detail("plugins"), "2 plugin(s) in /etc/hddmond/plugins");
+    }
+
+    // What keeps the daemon from starting is a failure, and only of
+    // something that's on.
+    #[test]
+    fn only_what_a_backend_needs_fails() {
+        let bare = Machine {
+            udev: Some(io::ErrorKind::PermissionDenied),
+            ..Default::default()
+        };
+        let names = |checks: Vec<Check>, verdict| {
+            checks
+                .into_iter()
+                .filter(|check| check.verdict == verdict)
+                .map(|check| check.name)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            names(checks(&backend("auto"), &bare), Verdict::Fail),
+            Vec::<&str>::new()
+        );
+        assert_eq!(
+            names(checks(&backend("auto"), &bare), Verdict::Warn),
+            ["smartctl", "udev", "block devices"]
+        );
+        assert_eq!(
+            names(checks(&backend("udev"), &bare), Verdict::Fail),
+            ["udev"]
+        );
+        assert_eq!(
+            names(checks(&backend("smartctl"), &bare), Verdict::Fail),
+            ["smartctl"]
+        );
+        assert_eq!(
+            names(checks(&backend("simulated"), &bare), Verdict::Fail),
+            Vec::<&str>::new()
+        );
+    }
+
+    #[test]
+    fn smartctl_without_json_output() {
+        let mut machine = healthy();
+        machine.programs.insert(
+            "smartctl",
+            vec![
+                (&["--version"][..], 0, SMARTCTL_6_6),
+                (
+                    &["--json", "--version"][..],
+                    1,
+                    "=======> UNRECOGNIZED OPTION: json",
+                ),
+            ],
+        );
+
+        let check = smartctl(&backend("smartctl"), &machine);
+        assert_eq!(check.verdict, Verdict::Fail);
+        assert_eq!(
+            check.detail,
+            "smartctl 6.6 can't give JSON output, the smartctl backend can't run"
+        );
+        assert_eq!(
+            check.hint.as_deref(),
+            Some("Install smartmontools 7.0 or later")
+        );
+        let check = smartctl(&backend("auto"), &machine);
+        assert_eq!(check.verdict, Verdict::Warn);
+        assert!(check
+            .detail
+            .ends_with("capacity and capabilities won't be checked"));
+    }
+
+    #[test]
+    fn smartctl_that_isnt_there_or_wont_run() {
+        let mut config = backend("smartctl");
+        config.smartctl.path = Some(PathBuf::from("/opt/smartmontools/sbin/smartctl"));
+
+        let check = smartctl(&config, &healthy());
+        assert_eq!(check.verdict, Verdict::Fail);
+        assert_eq!(
+            check.detail,
+            "/opt/smartmontools/sbin/smartctl isn't there, the smartctl backend can't run"
+        );
+        assert!(check.hint.unwrap().contains("smartctl.path"));
+
+        let machine = Machine {
+            not_executable: vec!["/opt/smartmontools/sbin/smartctl"],
+            ..healthy()
+        };
+        let check = smartctl(&config, &machine);
+        assert!(check
+            .detail
+            .starts_with("Can't run /opt/smartmontools/sbin/smartctl"));
+
+        let mut machine = healthy();
+        machine
+            .programs
+            .insert("smartctl", vec![(&["--version"][..], 127, "")]);
+        let check = smartctl(&backend("auto"), &machine);
+        assert_eq!(check.verdict, Verdict::Warn);
+        assert!(check.detail.starts_with("smartctl --version failed"));
+    }
+
+    #[test]
+    fn smartctl_versions() {
+        assert_eq!(smartctl_version(SMARTCTL_7_3), Some((7, 3)));
+        assert_eq!(smartctl_version(SMARTCTL_6_6), Some((6, 6)));
+        assert_eq!(smartctl_version("smartctl pre-7.4 2023-01-01"), None);
+        assert_eq!(smartctl_version(""), None);
+    }
+
+    #[test]
+    fn hdparm_only_with_power_policies() {
+        let machine = Machine::default();
+        assert_eq!(hdparm(&backend("auto"), &machine).verdict, Verdict::Pass);
+
+        let config = config("[[power.policies]]\ndevice = { serial = \"WD-1\" }\napm = 128\n");
+        let check = hdparm(&config, &machine);
+        assert_eq!(check.verdict, Verdict::Fail);
+        assert_eq!(
+            check.detail,
+            "hdparm isn't there, power.policies can't be applied"
+        );
+    }
+
+    #[test]
+    fn mmc_utils_only_with_emmc() {
+        let machine = Machine {
+            devices: vec!["sda", "nvme0n1"],
+            ..Default::default()
+        };
+        assert_eq!(mmc_utils(&machine).verdict, Verdict::Pass);
+
+        let machine = Machine {
+            devices: vec!["mmcblk0"],
+            ..Default::default()
+        };
+        let check = mmc_utils(&machine);
+        assert_eq!(check.verdict, Verdict::Warn);
+        assert_eq!(check.hint.as_deref(), Some("Install mmc-utils"));
+    }
+
+    #[test]
+    fn udev_in_a_container_without_it() {
+        let machine = Machine {
+            udev: Some(io::ErrorKind::NotFound),
+            ..Default::default()
+        };
+        let check = udev(&backend("auto"), &machine);
+        assert_eq!(check.verdict, Verdict::Warn);
+        assert!(check.detail.contains("falling back to polling smartctl"));
+        assert!(check.hint.unwrap().contains("/run/udev"));
+        assert_eq!(udev(&backend("udev"), &machine).verdict, Verdict::Fail);
+        assert_eq!(udev(&backend("smartctl"), &machine).verdict, Verdict::Pass);
+    }
+
+    #[test]
+    fn reading_a_block_device() {
+        // A real drive rather than a loop device, if there is one.
+        let machine = Machine {
+            devices: vec!["loop0", "ram0", "sda"],
+            unreadable: vec!["/dev/sda"],
+            ..Default::default()
+        };
+        let check = block_read(&backend("auto"), &machine);
+        assert_eq!(check.verdict, Verdict::Fail);
+        assert!(check.detail.starts_with("Can't read /dev/sda: "));
+        assert_eq!(
+            check.hint.as_deref(),
+            Some("Run as root, or as a user in the disk group")
+        );
+
+        let machine = Machine {
+            devices: vec!["loop0", "loop1"],
+            ..Default::default()
+        };
+        assert_eq!(
+            block_read(&backend("auto"), &machine).detail,
+            "Can read /dev/loop0"
+        );
+
+        let check = block_read(&backend("auto"), &Machine::default());
+        assert_eq!(check.verdict, Verdict::Warn);
+        assert_eq!(
+            block_read(&backend("simulated"), &Machine::default()).verdict,
+            Verdict::Pass
+        );
+    }
+
+    #[test]
+    fn the_database_directory() {
+        let mut config = backend("auto");
+
+        // Created, along with its parents, in the closest one that's there.
+        let machine = Machine {
+            dirs: vec!["/", "/var"],
+            ..Default::default()
+        };
+        let check = database(&config, &machine);
+        assert_eq!(check.verdict, Verdict::Pass);
+        assert_eq!(check.detail, "/var/lib/hddmond will be created in /var");
+
+        let machine = Machine {
+            dirs: vec!["/", "/var"],
+            read_only: vec!["/var"],
+            ..Default::default()
+        };
+        let check = database(&config, &machine);
+        assert_eq!(check.verdict, Verdict::Fail);
+        assert!(check.detail.starts_with("Can't write to /var: "));
+        assert!(check.hint.unwrap().contains("write access to /var"));
+
+        let machine = Machine {
+            dirs: vec!["/", "/var"],
+            files: vec!["/var/lib"],
+            ..Default::default()
+        };
+        let check = database(&config, &machine);
+        assert_eq!(check.verdict, Verdict::Fail);
+        assert_eq!(check.detail, "/var/lib isn't a directory");
+
+        config.storage.path = PathBuf::from(storage::IN_MEMORY);
+        assert_eq!(
+            database(&config, &Machine::default()).detail,
+            "Kept in memory"
+        );
+    }
+
+    #[test]
+    fn the_plugin_directory() {
+        let config = backend("auto");
+        let check = plugins(&config, &Machine::default());
+        assert_eq!(check.verdict, Verdict::Pass);
+        assert_eq!(check.detail, "No /etc/hddmond/plugins, no plugins");
+
+        let machine = Machine {
+            files: vec!["/etc/hddmond/plugins"],
+            ..Default::default()
+        };
+        assert_eq!(plugins(&config, &machine).verdict, Verdict::Warn);
+
+        let machine = Machine {
+            dirs: vec!["/etc/hddmond/plugins"],
+            ..Default::default()
+        };
+        let check = plugins(&config, &machine);
+        assert_eq!(check.verdict, Verdict::Fail);
+        assert!(check
+            .detail
+            .starts_with("Can't read /etc/hddmond/plugins: "));
+    }
+
+    #[test]
+    fn the_config_file() {
+        let path = Path::new("/etc/hddmond/hddmond.toml");
+        assert_eq!(
+            super::config(path, true, None).detail,
+            "/etc/hddmond/hddmond.toml is valid"
+        );
+        assert_eq!(
+            super::config(path, false, None).detail,
+            "There's no /etc/hddmond/hddmond.toml, running on the defaults"
+        );
+
+        let check = super::config(
+            path,
+            true,
+            Some("Can't load the config: unknown field `sqlite`"),
+        );
+        assert_eq!(check.verdict, Verdict::Fail);
+        assert_eq!(
+            check.detail,
+            "Can't load the config: unknown field `sqlite`"
+        );
+        assert!(check.hint.unwrap().contains("move it aside"));
+    }
+}
@@ -0,0 +1,780 @@
+use std::{io, path::PathBuf, process::ExitStatus};
+
+use thiserror::Error;
+use tokio::task::JoinError;
+
+/// Errors from the crates we wrap whose types we don't get to pick.
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+// Every subsystem returns one of these so callers can tell failures apart
+// by variant instead of by their messages. anyhow is only for main, which
+// just reports them, and the plugins, whose errors are the JavaScript
+// runtime's. Each message says what was being done and to which device or
+// file, and includes the underlying error, so they read fine on their own
+// in a log line.
+
+/// Why a device monitor couldn't be set up or stopped working.
+#[derive(Debug, Error)]
+pub enum ScanError {
+    /// udev turned us away, usually because we aren't running as root.
+    #[error("No permission to {operation} the udev monitor socket, is hddmond running as root? ({error})")]
+    UdevPermissionDenied {
+        /// What we were doing with the socket.
+        operation: &'static str,
+        /// The error from udev.
+        error: io::Error,
+    },
+    /// Any other udev failure, like the socket not existing in a container.
+    #[error("Can't {operation} the udev monitor socket: {error}")]
+    Udev {
+        /// What we were doing with the socket.
+        operation: &'static str,
+        /// The error from udev.
+        error: io::Error,
+    },
+    /// There's no usable smartctl binary.
+    #[error("Can't find smartctl{}: {error}", path.as_ref().map(|path| format!(" at {}", path)).unwrap_or_default())]
+    SmartctlNotFound {
+        /// Where it was configured to be, if anywhere.
+        path: Option<String>,
+        /// The error from smartctl-wrapper.
+        error: BoxError,
+    },
+    /// Running `smartctl --scan` failed, so which devices are there is
+    /// unknown. Not the same as a scan that found nothing.
+    #[error("smartctl --scan failed: {error}")]
+    SmartctlScan {
+        /// The error from smartctl-wrapper.
+        error: BoxError,
+    },
+    /// The blocking task running a scan panicked or was cancelled.
+    #[error("The smartctl scan task failed: {error}")]
+    ScanTask {
+        /// The task's error.
+        error: JoinError,
+    },
+    /// The fleet for the simulated backend can't be read, or doesn't make
+    /// sense.
+    #[error("Can't simulate the fleet in {}: {error}", path.display())]
+    Fleet {
+        /// The fleet file.
+        path: PathBuf,
+        /// What was wrong with it.
+        error: BoxError,
+    },
+    /// The monitor's thread, or its runtime, couldn't be started.
+    #[error("Can't start the device monitor's thread: {error}")]
+    MonitorThread {
+        /// Why it couldn't be.
+        error: io::Error,
+    },
+    /// The monitor's thread went away before saying whether it's watching.
+    #[error("The device monitor's thread stopped while starting")]
+    MonitorGone,
+    /// The `auto` backend couldn't set up either monitor.
+    #[error("No backend available, udev failed with \"{udev}\" and smartctl with \"{smartctl}\"")]
+    NoBackend {
+        /// Why udev failed.
+        udev: Box<ScanError>,
+        /// Why smartctl failed.
+        smartctl: Box<ScanError>,
+    },
+}
+
+impl ScanError {
+    /// A udev error, told apart by whether it was about permissions.
+    pub fn udev(operation: &'static str, error: io::Error) -> Self {
+        if error.kind() == io::ErrorKind::PermissionDenied {
+            ScanError::UdevPermissionDenied { operation, error }
+        } else {
+            ScanError::Udev { operation, error }
+        }
+    }
+}
+
+/// Why the device registry couldn't be opened, read or written.
+#[derive(Debug, Error)]
+pub enum StorageError {
+    /// The database's directory doesn't exist and can't be made.
+    #[error("Can't create {}: {error}", path.display())]
+    CreateDir {
+        /// The directory.
+        path: PathBuf,
+        /// Why it couldn't be created.
+        error: io::Error,
+    },
+    /// The database can't be opened.
+    #[error("Can't open database {}: {error}", path.display())]
+    Open {
+        /// The database file.
+        path: PathBuf,
+        /// The error from SQLite.
+        error: rusqlite::Error,
+    },
+    /// A schema migration failed, the database is left at the version
+    /// before it.
+    #[error("Can't migrate database {} to version {version}: {error}", path.display())]
+    Migrate {
+        /// The database file.
+        path: PathBuf,
+        /// The version that couldn't be reached.
+        version: usize,
+        /// The error from SQLite.
+        error: rusqlite::Error,
+    },
+    /// A corrupt database couldn't be renamed out of the way.
+    #[error("Can't move {} aside: {error}", path.display())]
+    MoveAside {
+        /// The file being moved.
+        path: PathBuf,
+        /// Why it couldn't be.
+        error: io::Error,
+    },
+    /// A query that isn't about one device failed.
+    #[error("Can't {operation}: {error}")]
+    Query {
+        /// What the query was for.
+        operation: &'static str,
+        /// The error from SQLite.
+        error: rusqlite::Error,
+    },
+    /// Recording a device coming or going failed.
+    #[error("Can't record {device} as {operation}: {error}")]
+    Device {
+        /// `found` or `lost`.
+        operation: &'static str,
+        /// The device's kernel name.
+        device: String,
+        /// The error from SQLite.
+        error: rusqlite::Error,
+    },
+    /// No device in the registry goes by the serial, WWN, name or alias
+    /// given.
+    #[error("No device with serial, WWN, name or alias {key} in the registry")]
+    NoSuchDevice {
+        /// What it was looked up by.
+        key: String,
+    },
+    /// There's no alert with this number.
+    #[error("No alert {id}")]
+    NoSuchAlert {
+        /// The alert's number.
+        id: i64,
+    },
+    /// The alert's condition cleared, there's nothing left to acknowledge.
+    #[error("Alert {id} has cleared already")]
+    AlertCleared {
+        /// The alert's number.
+        id: i64,
+    },
+    /// Another device that's present already has the alias.
+    #[error("{holder} is already called {alias}")]
+    AliasTaken {
+        /// The alias.
+        alias: String,
+        /// The device that has it, by kernel name and serial.
+        holder: String,
+    },
+    /// A device's identity couldn't be turned into JSON for storing.
+    #[error("Can't serialize what's known about {device}: {error}")]
+    Serialize {
+        /// The device's kernel name.
+        device: String,
+        /// The error from serde_json.
+        error: serde_json::Error,
+    },
+}
+
+/// Why smartctl couldn't tell us something about a device.
+#[derive(Debug, Error)]
+pub enum SmartError {
+    /// smartctl couldn't be run at all.
+    #[error("Can't run {}: {error}", smartctl.display())]
+    Run {
+        /// The smartctl binary.
+        smartctl: PathBuf,
+        /// Why it couldn't be run.
+        error: io::Error,
+    },
+    /// smartctl printed something other than JSON, usually because it
+    /// couldn't open the device.
+    #[error("smartctl {options} {} didn't print JSON: {error}", node.display())]
+    NotJson {
+        /// What smartctl was asked, like `-i`.
+        options: &'static str,
+        /// The device node.
+        node: PathBuf,
+        /// The error from serde_json.
+        error: serde_json::Error,
+    },
+    /// smartctl answered, but without the one thing we asked it for.
+    #[error("smartctl doesn't know the {what} of {}", node.display())]
+    Unknown {
+        /// What it was asked for, like `capacity`.
+        what: &'static str,
+        /// The device node.
+        node: PathBuf,
+    },
+}
+
+/// Why a tool like hdparm or mmc that's run for a device failed.
+#[derive(Debug, Error)]
+pub enum ToolError {
+    /// The tool couldn't be run at all.
+    #[error("Can't run {}: {error}", tool.display())]
+    Run {
+        /// The tool's binary.
+        tool: PathBuf,
+        /// Why it couldn't be run.
+        error: io::Error,
+    },
+    /// The tool ran and exited with an error.
+    #[error("{command} failed ({status}): {stderr}")]
+    Failed {
+        /// The command line, tool and device included.
+        command: String,
+        /// How it exited.
+        status: ExitStatus,
+        /// What it printed to stderr, trimmed.
+        stderr: String,
+    },
+}
+
+/// Why a power setting couldn't be applied or read back.
+#[derive(Debug, Error)]
+pub enum PowerError {
+    /// hdparm failed.
+    #[error(transparent)]
+    Hdparm(ToolError),
+    /// The drive doesn't do APM. hdparm doesn't fail for these, it only
+    /// says so.
+    #[error("{} doesn't support APM", device.display())]
+    NoApm {
+        /// The device node.
+        device: PathBuf,
+    },
+    /// A standby timeout past what hdparm -S can encode.
+    #[error("A standby timeout of {secs}s is longer than a drive can be told")]
+    StandbyTooLong {
+        /// The timeout asked for.
+        secs: u64,
+    },
+    /// hdparm -C printed something with no drive state in it.
+    #[error("Can't tell the power state of {} from hdparm's output: {output}", device.display())]
+    UnknownState {
+        /// The device node.
+        device: PathBuf,
+        /// What hdparm printed, trimmed.
+        output: String,
+    },
+}
+
+/// Why the daemon couldn't detach, claim its pidfile or lock, or drop
+/// root.
+#[derive(Debug, Error)]
+pub enum DaemonError {
+    /// A file the daemon keeps, like its pidfile or instance lock, can't be
+    /// read, written or made.
+    #[error("Can't {operation} {}: {error}", path.display())]
+    File {
+        /// What was being done to it, like `create pidfile`.
+        operation: &'static str,
+        /// The file.
+        path: PathBuf,
+        /// Why it couldn't be.
+        error: io::Error,
+    },
+    /// The pidfile belongs to a process that's still running.
+    #[error("hddmond is already running as pid {pid} (see {})", pidfile.display())]
+    AlreadyRunning {
+        /// The process in the pidfile.
+        pid: i32,
+        /// The pidfile.
+        pidfile: PathBuf,
+    },
+    /// Another daemon holds the instance lock.
+    #[error(
+        "hddmond is already running{} ({} holds {})",
+        pid.map(|pid| format!(" as pid {}", pid)).unwrap_or_default(),
+        if pid.is_some() { "it" } else { "something" },
+        lock.display()
+    )]
+    Locked {
+        /// The pid written in the lock file, if it can be read.
+        pid: Option<i32>,
+        /// The lock file.
+        lock: PathBuf,
+    },
+    /// There's no such user or group to switch to.
+    #[error("No {kind} named {name}")]
+    NoSuchUser {
+        /// `user` or `group`.
+        kind: &'static str,
+        /// The name in the config.
+        name: String,
+    },
+    /// A system call failed on the way, like fork or setuid.
+    #[error("Can't {operation}: {error}")]
+    System {
+        /// What was being done.
+        operation: &'static str,
+        /// The error from the kernel.
+        error: nix::Error,
+    },
+}
+
+/// Why the control socket couldn't be set up or talked to.
+#[derive(Debug, Error)]
+pub enum ControlError {
+    /// Something went wrong with the socket file itself.
+    #[error("Can't {operation} {}: {error}", path.display())]
+    Socket {
+        /// What was being done, like `listen on`.
+        operation: &'static str,
+        /// The socket.
+        path: PathBuf,
+        /// Why it couldn't be.
+        error: io::Error,
+    },
+    /// Something other than a socket is where the socket goes.
+    #[error("{} is there and isn't a socket, not removing it", path.display())]
+    NotASocket {
+        /// Where the socket goes.
+        path: PathBuf,
+    },
+    /// Another process is listening on the socket.
+    #[error("Something is already listening on {}", path.display())]
+    InUse {
+        /// The socket.
+        path: PathBuf,
+    },
+    /// No daemon answered, most often because none is running.
+    #[error("Can't connect to {}, is hddmond running? ({error})", path.display())]
+    Connect {
+        /// The socket.
+        path: PathBuf,
+        /// Why the connection failed.
+        error: io::Error,
+    },
+    /// The daemon took the connection but the command didn't make it
+    /// there and back.
+    #[error("Can't talk to the daemon on {}: {error}", path.display())]
+    Query {
+        /// The socket.
+        path: PathBuf,
+        /// What went wrong.
+        error: io::Error,
+    },
+}
+
+/// Why the audit trail couldn't be opened, written or verified.
+#[derive(Debug, Error)]
+pub enum AuditError {
+    /// The trail can't be opened or read.
+    #[error("Can't {operation} the audit trail {}: {error}", path.display())]
+    File {
+        /// What was being done, like `open`.
+        operation: &'static str,
+        /// The trail.
+        path: PathBuf,
+        /// Why it couldn't be.
+        error: io::Error,
+    },
+    /// The file being verified isn't UTF-8, so it isn't one.
+    #[error("{} isn't text", path.display())]
+    NotText {
+        /// The file.
+        path: PathBuf,
+    },
+    /// The last line of the trail doesn't parse, so the sequence and the
+    /// chain can't be carried on from it.
+    #[error("The last record in {} isn't one: {error}", path.display())]
+    LastRecord {
+        /// The trail.
+        path: PathBuf,
+        /// The error from serde_json.
+        error: serde_json::Error,
+    },
+    /// A record in the trail doesn't parse, is out of sequence or doesn't
+    /// chain up.
+    #[error("{problem}")]
+    Broken {
+        /// Which line and what's wrong with it.
+        problem: String,
+    },
+    /// Something other than an object was given as a record's fields.
+    #[error("{fields} isn't an object of fields")]
+    NotFields {
+        /// What was given.
+        fields: serde_json::Value,
+    },
+}
+
+/// Why the config file can't be used.
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    /// The file is there but can't be read.
+    #[error("Can't read {}: {error}", path.display())]
+    Read {
+        /// The config file.
+        path: PathBuf,
+        /// Why it couldn't be.
+        error: io::Error,
+    },
+    /// The file isn't valid TOML, or doesn't fit the config's types.
+    #[error("Invalid config in {}: {error}", path.display())]
+    Parse {
+        /// The config file.
+        path: PathBuf,
+        /// The error from toml, which names the key, line and column.
+        error: toml::de::Error,
+    },
+    /// Settings that parse but don't make sense, every one of them.
+    #[error("{}{}", path.as_ref().map(|path| format!("Invalid config in {}: ", path.display())).unwrap_or_default(), problems.join("; "))]
+    Invalid {
+        /// The config file, if it came from one.
+        path: Option<PathBuf>,
+        /// What's wrong, by key.
+        problems: Vec<String>,
+    },
+}
+
+/// Why a smartd.conf couldn't be read for converting.
+#[derive(Debug, Error)]
+pub enum SmartdImportError {
+    /// A line starts with a directive where the device should be.
+    #[error("Line {line}: {token} where a device name should be")]
+    NoDevice {
+        /// The line, counting from 1.
+        line: usize,
+        /// What was there instead.
+        token: String,
+    },
+    /// Something after the device that isn't a `-x` directive.
+    #[error("Line {line}: {token} isn't a directive")]
+    NotADirective {
+        /// The line, counting from 1.
+        line: usize,
+        /// What was there instead.
+        token: String,
+    },
+    /// A directive that takes an argument is the last thing on the line.
+    #[error("Line {line}: -{flag}{} needs {}", if *exec { " exec" } else { "" }, if *exec { "a path" } else { "an argument" })]
+    MissingArgument {
+        /// The line, counting from 1.
+        line: usize,
+        /// The directive.
+        flag: char,
+        /// Whether it's `-M exec`, which is missing its path.
+        exec: bool,
+    },
+}
+
+/// Why the disks behind a filesystem or device can't be found.
+#[derive(Debug, Error)]
+pub enum PolicyError {
+    /// Something in /proc or /sys couldn't be read.
+    #[error("Can't read {}: {error}", path.display())]
+    Read {
+        /// What was being read.
+        path: PathBuf,
+        /// Why it couldn't be.
+        error: io::Error,
+    },
+    /// No mount in /proc/self/mountinfo has the path under it.
+    #[error("{} isn't on a mounted filesystem", path.display())]
+    NotMounted {
+        /// The path.
+        path: PathBuf,
+    },
+    /// The filesystem isn't on a block device, like tmpfs or NFS.
+    #[error("{} isn't on a disk", path.display())]
+    NotOnADisk {
+        /// The path.
+        path: PathBuf,
+    },
+    /// A block device with nothing under it in sysfs that's a disk.
+    #[error("No disk found behind {}", path.display())]
+    NoDisk {
+        /// The device's directory in sysfs.
+        path: PathBuf,
+    },
+}
+
+/// Why a device couldn't be probed for what's on it.
+#[derive(Debug, Error)]
+#[error("Can't probe what's on {device}: {error}")]
+pub struct ProbeError {
+    /// The device's kernel name.
+    pub device: String,
+    /// Why reading it failed.
+    pub error: io::Error,
+}
+
+/// Why a topology setting couldn't be changed.
+#[derive(Debug, Error)]
+pub enum TopologyError {
+    /// The enclosure didn't take the locate LED setting.
+    #[error(
+        "Can't turn the locate LED of enclosure {enclosure} slot {slot} {}: {error}",
+        if *on { "on" } else { "off" }
+    )]
+    Locate {
+        /// The enclosure's SCSI address.
+        enclosure: String,
+        /// The slot.
+        slot: String,
+        /// Whether it was being turned on.
+        on: bool,
+        /// Why it couldn't be.
+        error: io::Error,
+    },
+}
+
+/// Why logging couldn't be set up or changed.
+#[derive(Debug, Error)]
+pub enum LoggingError {
+    /// A filter that doesn't parse.
+    #[error("Invalid log filter \"{filter}\": {error}")]
+    Filter {
+        /// The filter given.
+        filter: String,
+        /// The error from tracing-subscriber.
+        error: BoxError,
+    },
+    /// The log file can't be opened or reopened.
+    #[error("Can't open the log file: {error}")]
+    File {
+        /// Why it couldn't be.
+        error: io::Error,
+    },
+    /// Logging was set up already, or the new filter can't be swapped in.
+    #[error("Can't set up logging: {error}")]
+    Subscriber {
+        /// The error from tracing-subscriber.
+        error: BoxError,
+    },
+}
+
+/// Why the registry couldn't be exported.
+#[derive(Debug, Error)]
+pub enum ExportError {
+    /// Writing the output failed.
+    #[error("Can't write the export: {error}")]
+    Write {
+        /// Why it couldn't be.
+        error: io::Error,
+    },
+}
+
+/// Why a support bundle couldn't be put together.
+#[derive(Debug, Error)]
+pub enum SupportBundleError {
+    /// The config file can't be read or parsed for redacting.
+    #[error("Can't {operation} {}: {error}", path.display())]
+    Config {
+        /// `read` or `parse`.
+        operation: &'static str,
+        /// The config file.
+        path: PathBuf,
+        /// Why it couldn't be.
+        error: BoxError,
+    },
+    /// The device history can't be read out of the registry.
+    #[error(transparent)]
+    Registry(StorageError),
+    /// Something couldn't be turned into what goes in the bundle.
+    #[error("Can't write out {part}: {error}")]
+    Serialize {
+        /// The part of the bundle.
+        part: &'static str,
+        /// The error from serde_json or toml.
+        error: BoxError,
+    },
+    /// Writing the bundle failed.
+    #[error("Can't write the support bundle: {error}")]
+    Write {
+        /// Why it couldn't be.
+        error: io::Error,
+    },
+}
+
+/// Why a notification target couldn't be set up, or a notification didn't
+/// go out.
+#[derive(Debug, Error)]
+pub enum NotifierError {
+    /// A URL that doesn't parse.
+    #[error("Invalid URL {url}: {error}")]
+    InvalidUrl {
+        /// The URL given.
+        url: String,
+        /// The error from url.
+        error: BoxError,
+    },
+    /// A URL that can't be posted to.
+    #[error("Webhook URLs have to be http or https, not {scheme}")]
+    NotHttp {
+        /// The URL's scheme.
+        scheme: String,
+    },
+    /// A template with a `{{` that isn't closed, or nothing between the
+    /// braces.
+    #[error("{problem} in template: {template}")]
+    Template {
+        /// What's wrong with it.
+        problem: &'static str,
+        /// The template.
+        template: String,
+    },
+    /// Quiet hours that aren't HH:MM-HH:MM.
+    #[error("Invalid quiet hours \"{text}\", expected HH:MM-HH:MM")]
+    QuietHours {
+        /// What was given.
+        text: String,
+    },
+    /// Quiet hours that start and end at the same time.
+    #[error("Quiet hours \"{text}\" start and end at the same time")]
+    EmptyQuietHours {
+        /// What was given.
+        text: String,
+    },
+    /// An email address that doesn't parse.
+    #[error("Invalid {field} address {address}: {error}")]
+    Address {
+        /// `from` or `to`.
+        field: &'static str,
+        /// The address given.
+        address: String,
+        /// The error from lettre.
+        error: lettre::address::AddressError,
+    },
+    /// A Matrix target without a room or an access token.
+    #[error("Matrix needs a room and an access token")]
+    MatrixIncomplete,
+    /// A URL that can't have a path added to it.
+    #[error("{url} can't be a homeserver URL")]
+    NotAHomeserver {
+        /// The URL given.
+        url: String,
+    },
+    /// Neither the journal's nor syslog's socket is there.
+    #[error("Neither {journal} nor {syslog} exist, is there a journal or syslog daemon?")]
+    NoJournal {
+        /// Where the journal's socket would be.
+        journal: &'static str,
+        /// Where syslog's would be.
+        syslog: &'static str,
+    },
+    /// The HTTP client or SMTP transport can't be set up.
+    #[error("Can't set up {target}: {error}")]
+    Setup {
+        /// The target, like `email via smtp.example.com`.
+        target: String,
+        /// The error from reqwest or lettre.
+        error: BoxError,
+    },
+    /// The notification didn't make it to the target.
+    #[error("Can't reach {target}: {error}")]
+    Send {
+        /// The target's name.
+        target: String,
+        /// The error from reqwest, lettre or the socket.
+        error: BoxError,
+    },
+    /// The target took the notification and said no.
+    #[error("{target} answered {status}")]
+    Rejected {
+        /// The target's name.
+        target: String,
+        /// The HTTP status it answered with.
+        status: reqwest::StatusCode,
+    },
+}
+
+/// Why the plugin directory couldn't be loaded. A plugin that fails to load
+/// is only logged, the others still run.
+#[derive(Debug, Error)]
+pub enum PluginError {
+    /// The plugin directory can't be listed.
+    #[error("Can't read the plugin directory {}: {error}", path.display())]
+    ReadDir {
+        /// The plugin directory.
+        path: PathBuf,
+        /// Why it couldn't be.
+        error: io::Error,
+    },
+}
+
+/// Why the daemon couldn't start. Once it's running, failures are logged
+/// and it carries on.
+#[derive(Debug, Error)]
+pub enum PipelineError {
+    /// The device monitor couldn't be started.
+    #[error(transparent)]
+    Scan(ScanError),
+    /// The registry couldn't be opened or read.
+    #[error(transparent)]
+    Registry(StorageError),
+    /// The audit trail couldn't be opened.
+    #[error(transparent)]
+    Audit(AuditError),
+    /// Root couldn't be dropped.
+    #[error(transparent)]
+    Daemon(DaemonError),
+    /// A notification target couldn't be set up.
+    #[error(transparent)]
+    Notifier(NotifierError),
+    /// The plugin directory couldn't be loaded.
+    #[error(transparent)]
+    Plugin(PluginError),
+    /// A signal handler couldn't be installed.
+    #[error("Can't handle {signal}: {error}")]
+    Signal {
+        /// The signal, like `SIGHUP`.
+        signal: &'static str,
+        /// Why it couldn't be.
+        error: io::Error,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use nix::errno::Errno;
+
+    use super::*;
+
+    #[test]
+    fn udev_permission_errors_are_their_own_variant() {
+        let denied = ScanError::udev(
+            "listen on",
+            io::Error::from_raw_os_error(Errno::EACCES as i32),
+        );
+        assert!(matches!(
+            denied,
+            ScanError::UdevPermissionDenied {
+                operation: "listen on",
+                ..
+            }
+        ));
+        assert_eq!(
+            denied.to_string(),
+            "No permission to listen on the udev monitor socket, is hddmond running as root? \
+             (Permission denied (os error 13))"
+        );
+
+        for errno in [Errno::ENOENT, Errno::EPROTONOSUPPORT] {
+            let error = ScanError::udev("create", io::Error::from_raw_os_error(errno as i32));
+            assert!(
+                matches!(
+                    error,
+                    ScanError::Udev {
+                        operation: "create",
+                        ..
+                    }
+                ),
+                "{}",
+                error
+            );
+        }
+    }
+}
@@ -0,0 +1,294 @@
+use std::thread;
+
+use tokio::sync::{
+    mpsc::{self, error::TrySendError},
+    oneshot,
+};
+use tokio_stream::StreamExt;
+
+use crate::{
+    config::{Backend, Config},
+    device_policy::DeviceIdentity,
+    error::ScanError,
+    scanners::{
+        backend::create_monitor,
+        rescan::Rescanner,
+        scanner::{DeviceMonitor, DeviceStream, PollInterval, ScanEventType},
+    },
+};
+
+/// Makes the monitor, on the thread it's read on.
+pub type CreateMonitor =
+    Box<dyn FnOnce(&Config) -> Result<(Backend, Box<dyn DeviceMonitor>), ScanError> + Send>;
+
+/// The device monitor, read on a thread of its own.
+///
+/// The main loop writes to the registry synchronously, and a slow fsync there
+/// used to keep the udev socket from being drained until the kernel's buffer
+/// overflowed and events were lost. Now the thread does nothing but pass
+/// events on, and they wait in a queue of monitor.queue_size for the main
+/// loop to get to them. Should the queue fill up anyway, the thread waits
+/// for room rather than dropping anything, hotplug events are few and every
+/// one matters. The kernel buffers what comes in meanwhile, as it did before.
+///
+/// Everything downstream of the main loop already has queues of its own that
+/// never make it wait: each plugin's hook calls and each notifier's
+/// notifications are dropped, with a warning, when that queue is full.
+pub struct EventReader {
+    /// The backend the monitor ended up being.
+    pub backend: Backend,
+    /// What the monitor knows about its devices, see DeviceMonitor::identities.
+    pub identities: Vec<DeviceIdentity>,
+    /// For `hddmond rescan`, if the backend can.
+    pub rescanner: Option<Rescanner>,
+    // For config reloads, if the backend polls.
+    poll_interval: Option<PollInterval>,
+    events: mpsc::Receiver<ScanEventType>,
+}
+
+impl EventReader {
+    /// Sets up the monitor on the new thread, since monitors generally can't
+    /// be moved between threads once made. Returns once it's watching, so
+    /// whatever it needs root for is done by then.
+    pub async fn spawn(config: &Config) -> Result<Self, ScanError> {
+        Self::spawn_with(config, Box::new(create_monitor)).await
+    }
+
+    /// The same, but with the monitor `create` makes rather than the
+    /// configured backend's.
+    pub async fn spawn_with(config: &Config, create: CreateMonitor) -> Result<Self, ScanError> {
+        let config = config.clone();
+        let (sender, events) = mpsc::channel(config.monitor.queue_size);
+        let (ready_sender, ready) = oneshot::channel();
+
+        thread::Builder::new()
+            .name("monitor".to_string())
+            .spawn(move || read(config, create, sender, ready_sender))
+            .map_err(|error| ScanError::MonitorThread { error })?;
+
+        let (backend, identities, rescanner, poll_interval) =
+            ready.await.map_err(|_| ScanError::MonitorGone)??;
+        Ok(Self {
+            backend,
+            identities,
+            rescanner,
+            poll_interval,
+            events,
+        })
+    }
+
+    /// Has the monitor poll as often as `config` says for its backend.
+    pub fn set_poll_interval(&self, config: &Config) {
+        let interval = match self.backend {
+            Backend::Udev => config.udev.poll_interval(),
+            Backend::Smartctl => config.smartctl.scan_interval(),
+            // Each of its monitors keeps the interval it started with.
+            Backend::Composite => return,
+            Backend::Auto | Backend::Simulated => return,
+        };
+        if let Some(poll_interval) = &self.poll_interval {
+            poll_interval.set(interval);
+        }
+    }
+
+    /// None once the monitor can't go on.
+    pub async fn next(&mut self) -> Option<ScanEventType> {
+        self.events.recv().await
+    }
+}
+
+type Ready = Result<
+    (
+        Backend,
+        Vec<DeviceIdentity>,
+        Option<Rescanner>,
+        Option<PollInterval>,
+    ),
+    ScanError,
+>;
+
+fn read(
+    config: Config,
+    create: CreateMonitor,
+    sender: mpsc::Sender<ScanEventType>,
+    ready: oneshot::Sender<Ready>,
+) {
+    let rt = match tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+    {
+        Ok(rt) => rt,
+        Err(error) => {
+            let _ = ready.send(Err(ScanError::MonitorThread { error }));
+            return;
+        }
+    };
+
+    rt.block_on(async move {
+        let watching = create(&config).and_then(|(backend, monitor)| {
+            let stream = monitor.watch_events()?;
+            Ok((backend, monitor, stream))
+        });
+        let (backend, monitor, stream) = match watching {
+            Ok(watching) => watching,
+            Err(e) => {
+                let _ = ready.send(Err(e));
+                return;
+            }
+        };
+        let watching = (
+            backend,
+            monitor.identities(),
+            monitor.rescanner(),
+            monitor.poll_interval(),
+        );
+        if ready.send(Ok(watching)).is_err() {
+            return;
+        }
+
+        forward(stream, sender).await;
+        // The monitor lives as long as its stream, udev's socket is shared
+        // between them.
+        drop(monitor);
+    });
+}
+
+async fn forward(mut stream: DeviceStream, sender: mpsc::Sender<ScanEventType>) {
+    let mut behind = false;
+
+    while let Some(event) = stream.next().await {
+        let event = match sender.try_send(event) {
+            Ok(()) => {
+                if behind && sender.capacity() > sender.max_capacity() / 2 {
+                    info!("The main loop caught up with device events.");
+                    behind = false;
+                }
+                continue;
+            }
+            // The main loop is done.
+            Err(TrySendError::Closed(_)) => return,
+            Err(TrySendError::Full(event)) => event,
+        };
+
+        if !behind {
+            warn!(
+                "The main loop is {} device events behind, holding off on reading more \
+                 until it catches up.",
+                sender.max_capacity()
+            );
+            behind = true;
+        }
+        if sender.send(event).await.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            mpsc as std_mpsc, Arc,
+        },
+        time::Duration,
+    };
+
+    use super::*;
+
+    const QUEUE: usize = 4;
+
+    // As many events as it's asked for, as fast as they're taken, counting
+    // how many were. Says so when it's dropped, which is once its thread is
+    // done with it.
+    struct Flood {
+        events: usize,
+        taken: Arc<AtomicUsize>,
+        dropped: std_mpsc::Sender<()>,
+    }
+
+    impl DeviceMonitor for Flood {
+        fn watch_events(&self) -> Result<DeviceStream, ScanError> {
+            let taken = self.taken.clone();
+            Ok(Box::pin(tokio_stream::iter(0..self.events).map(move |n| {
+                taken.fetch_add(1, Ordering::SeqCst);
+                ScanEventType::DeviceFound(format!("sd{}", n))
+            })))
+        }
+    }
+
+    impl Drop for Flood {
+        fn drop(&mut self) {
+            let _ = self.dropped.send(());
+        }
+    }
+
+    async fn flooded(events: usize) -> (EventReader, Arc<AtomicUsize>, std_mpsc::Receiver<()>) {
+        let mut config = Config::default();
+        config.monitor.queue_size = QUEUE;
+        let taken = Arc::new(AtomicUsize::new(0));
+        let (dropped, monitor_dropped) = std_mpsc::channel();
+        let monitor = Flood {
+            events,
+            taken: taken.clone(),
+            dropped,
+        };
+        let reader = EventReader::spawn_with(
+            &config,
+            Box::new(move |_: &Config| {
+                Ok((
+                    Backend::Simulated,
+                    Box::new(monitor) as Box<dyn DeviceMonitor>,
+                ))
+            }),
+        )
+        .await
+        .unwrap();
+        (reader, taken, monitor_dropped)
+    }
+
+    // How many events were taken once the thread stopped taking more.
+    async fn settled(taken: &AtomicUsize) -> usize {
+        let mut last = usize::MAX;
+        loop {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let now = taken.load(Ordering::SeqCst);
+            if now == last {
+                return now;
+            }
+            last = now;
+        }
+    }
+
+    #[tokio::test]
+    async fn a_stalled_main_loop_holds_events_back_without_losing_any() {
+        let (mut reader, taken, monitor_dropped) = flooded(100).await;
+
+        // What fits in the queue, and the one waiting for room.
+        assert_eq!(settled(&taken).await, QUEUE + 1);
+
+        let mut events = vec![];
+        while let Some(event) = reader.next().await {
+            events.push(event);
+        }
+        let all = (0..100)
+            .map(|n| ScanEventType::DeviceFound(format!("sd{}", n)))
+            .collect::<Vec<_>>();
+        assert_eq!(events, all);
+        monitor_dropped
+            .recv_timeout(Duration::from_secs(5))
+            .expect("the monitor thread to finish");
+    }
+
+    #[tokio::test]
+    async fn the_reader_stops_if_the_main_loop_goes_while_it_waits_for_room() {
+        let (reader, taken, monitor_dropped) = flooded(100).await;
+        assert_eq!(settled(&taken).await, QUEUE + 1);
+
+        drop(reader);
+        monitor_dropped
+            .recv_timeout(Duration::from_secs(5))
+            .expect("the monitor thread to finish");
+        assert_eq!(taken.load(Ordering::SeqCst), QUEUE + 1);
+    }
+}
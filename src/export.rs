@@ -0,0 +1,323 @@
+use std::io::{self, Write};
+
+use serde::Deserialize;
+
+use crate::{error::ExportError, storage::DeviceRecord};
+
+/// What `hddmond export` writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ExportFormat {
+    /// A header row, then a row per device.
+    Csv,
+    /// An array with one object per device, keys in column order.
+    Json,
+}
+
+/// A column of the export. The order they're configured in is the order
+/// they come out in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Column {
+    /// The registry's id for the device.
+    Id,
+    /// Kernel name it was last seen under.
+    Name,
+    /// The serial it reported.
+    Serial,
+    /// The model it reported.
+    Model,
+    /// Its World Wide Name.
+    Wwn,
+    /// Every /dev path of the device, space separated.
+    Paths,
+    /// Whether it's plugged in now.
+    Present,
+    /// When the registry first saw it, RFC 3339.
+    FirstSeen,
+    /// When it was last seen, RFC 3339.
+    LastSeen,
+    /// How many times it was found.
+    TimesSeen,
+}
+
+impl Column {
+    /// Every column, the default.
+    pub const ALL: &'static [Column] = &[
+        Column::Id,
+        Column::Name,
+        Column::Serial,
+        Column::Model,
+        Column::Wwn,
+        Column::Paths,
+        Column::Present,
+        Column::FirstSeen,
+        Column::LastSeen,
+        Column::TimesSeen,
+    ];
+
+    /// The name it goes by in the config and the header row.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Column::Id => "id",
+            Column::Name => "name",
+            Column::Serial => "serial",
+            Column::Model => "model",
+            Column::Wwn => "wwn",
+            Column::Paths => "paths",
+            Column::Present => "present",
+            Column::FirstSeen => "first_seen",
+            Column::LastSeen => "last_seen",
+            Column::TimesSeen => "times_seen",
+        }
+    }
+
+    fn value(&self, device: &DeviceRecord) -> serde_json::Value {
+        use serde_json::Value;
+
+        let string = |value: &Option<String>| value.clone().map_or(Value::Null, Value::String);
+
+        match self {
+            Column::Id => device.id.into(),
+            Column::Name => device.name.clone().into(),
+            Column::Serial => string(&device.serial),
+            Column::Model => string(&device.model),
+            Column::Wwn => string(&device.wwn),
+            Column::Paths => device.info["paths"]
+                .as_array()
+                .map(|paths| {
+                    paths
+                        .iter()
+                        .filter_map(Value::as_str)
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                })
+                .unwrap_or_default()
+                .into(),
+            Column::Present => device.present.into(),
+            Column::FirstSeen => device.first_seen.clone().into(),
+            Column::LastSeen => device.last_seen.clone().into(),
+            Column::TimesSeen => device.times_seen.into(),
+        }
+    }
+}
+
+/// Writes one device at a time, so the output never has to be held in
+/// memory as a whole.
+pub fn export(
+    out: &mut impl Write,
+    format: ExportFormat,
+    columns: &[Column],
+    devices: impl IntoIterator<Item = DeviceRecord>,
+) -> Result<(), ExportError> {
+    write(out, format, columns, devices).map_err(|error| ExportError::Write { error })
+}
+
+fn write(
+    out: &mut impl Write,
+    format: ExportFormat,
+    columns: &[Column],
+    devices: impl IntoIterator<Item = DeviceRecord>,
+) -> io::Result<()> {
+    match format {
+        ExportFormat::Csv => {
+            let header: Vec<_> = columns.iter().map(|column| column.as_str()).collect();
+            write_csv_row(out, header)?;
+
+            for device in devices {
+                let fields: Vec<_> = columns
+                    .iter()
+                    .map(|column| match column.value(&device) {
+                        serde_json::Value::Null => String::new(),
+                        serde_json::Value::String(value) => value,
+                        value => value.to_string(),
+                    })
+                    .collect();
+                write_csv_row(out, fields)?;
+            }
+        }
+        ExportFormat::Json => {
+            out.write_all(b"[")?;
+            for (index, device) in devices.into_iter().enumerate() {
+                if index > 0 {
+                    out.write_all(b",")?;
+                }
+                out.write_all(b"\n  {")?;
+                for (index, column) in columns.iter().enumerate() {
+                    if index > 0 {
+                        out.write_all(b", ")?;
+                    }
+                    serde_json::to_writer(&mut *out, column.as_str())?;
+                    out.write_all(b": ")?;
+                    serde_json::to_writer(&mut *out, &column.value(&device))?;
+                }
+                out.write_all(b"}")?;
+            }
+            out.write_all(b"\n]\n")?;
+        }
+    }
+
+    out.flush()?;
+    Ok(())
+}
+
+// RFC 4180: fields with a comma, quote, or line break in them are quoted,
+// and quotes inside are doubled.
+fn write_csv_row<S: AsRef<str>>(
+    out: &mut impl Write,
+    fields: impl IntoIterator<Item = S>,
+) -> io::Result<()> {
+    for (index, field) in fields.into_iter().enumerate() {
+        if index > 0 {
+            out.write_all(b",")?;
+        }
+
+        let field = field.as_ref();
+        if field.contains([',', '"', '\n', '\r']) {
+            write!(out, "\"{}\"", field.replace('"', "\"\""))?;
+        } else {
+            out.write_all(field.as_bytes())?;
+        }
+    }
+    out.write_all(b"\r\n")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn device(id: i64, name: &str) -> DeviceRecord {
+        DeviceRecord {
+            id,
+            uuid: format!("00000000-0000-4000-8000-00000000000{}", id),
+            identity_basis: None,
+            serial: None,
+            model: None,
+            wwn: None,
+            name: name.to_string(),
+            info: json!({}),
+            first_seen: "2026-01-05T10:00:00Z".to_string(),
+            last_seen: "2026-10-01T08:30:00Z".to_string(),
+            times_seen: 1,
+            present: false,
+            contents: serde_json::Value::Null,
+            capacity_mismatch: false,
+            firmware: None,
+            capabilities: serde_json::Value::Null,
+            mmc_health: serde_json::Value::Null,
+            alias: None,
+            alias_from_template: false,
+            link_health: serde_json::Value::Null,
+            quarantine: None,
+            quarantined_since: None,
+        }
+    }
+
+    // A present drive with everything, an absent one whose serial needs
+    // quoting, and a USB stick with a line break in its model and no paths.
+    fn fleet() -> Vec<DeviceRecord> {
+        let mut sda = device(1, "sda");
+        sda.serial = Some("WD-WCC7K1234567".to_string());
+        sda.model = Some("WDC WD40EFRX-68N32N0".to_string());
+        sda.wwn = Some("0x50014ee2b5a1c3d4".to_string());
+        sda.info = json!({
+            "paths": ["/dev/sda", "/dev/disk/by-id/ata-WDC_WD40EFRX-68N32N0_WD-WCC7K1234567"]
+        });
+        sda.times_seen = 3;
+        sda.present = true;
+
+        let mut sdb = device(2, "sdb");
+        sdb.serial = Some("ZA1\"B,C".to_string());
+        sdb.model = Some("ST4000NM0033-9ZM170".to_string());
+        sdb.info = json!({ "paths": ["/dev/sdb"] });
+        sdb.last_seen = "2026-03-14T15:09:26Z".to_string();
+
+        let mut sdc = device(7, "sdc");
+        sdc.model = Some("Generic\nUSB".to_string());
+
+        vec![sda, sdb, sdc]
+    }
+
+    fn exported(format: ExportFormat, columns: &[Column]) -> String {
+        let mut out = vec![];
+        export(&mut out, format, columns, fleet()).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn csv_row(fields: &[&str]) -> String {
+        let mut out = vec![];
+        write_csv_row(&mut out, fields).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn csv_quoting() {
+        let cases: &[(&[&str], &str)] = &[
+            (&["plain", "fields"], "plain,fields\r\n"),
+            (&["a,b"], "\"a,b\"\r\n"),
+            (&["say \"hi\""], "\"say \"\"hi\"\"\"\r\n"),
+            (&["two\nlines"], "\"two\nlines\"\r\n"),
+            (&["carriage\rreturn"], "\"carriage\rreturn\"\r\n"),
+            (&["\""], "\"\"\"\"\r\n"),
+            (&["", "", ""], ",,\r\n"),
+            (&[" spaces ", "tab\there"], " spaces ,tab\there\r\n"),
+            (&["ünïcödé"], "ünïcödé\r\n"),
+            (&[], "\r\n"),
+        ];
+        for (fields, expected) in cases {
+            assert_eq!(csv_row(fields), *expected, "{:?}", fields);
+        }
+    }
+
+    #[test]
+    fn csv_golden() {
+        assert_eq!(
+            exported(ExportFormat::Csv, Column::ALL),
+            include_str!("../tests/fixtures/export/fleet.csv")
+        );
+    }
+
+    #[test]
+    fn json_golden() {
+        let json = exported(ExportFormat::Json, Column::ALL);
+        assert_eq!(json, include_str!("../tests/fixtures/export/fleet.json"));
+        // It's one document, with the same fields as the CSV.
+        let parsed: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed[1]["serial"], "ZA1\"B,C");
+        assert_eq!(parsed[2]["paths"], "");
+        assert_eq!(parsed[2]["serial"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn columns_come_out_in_the_order_given() {
+        let columns = [Column::TimesSeen, Column::Serial, Column::Present];
+        assert_eq!(
+            exported(ExportFormat::Csv, &columns),
+            "times_seen,serial,present\r\n3,WD-WCC7K1234567,true\r\n1,\"ZA1\"\"B,C\",false\r\n\
+             1,,false\r\n"
+        );
+        assert_eq!(
+            exported(ExportFormat::Json, &columns[..1]),
+            "[\n  {\"times_seen\": 3},\n  {\"times_seen\": 1},\n  {\"times_seen\": 1}\n]\n"
+        );
+    }
+
+    #[test]
+    fn no_devices() {
+        let mut out = vec![];
+        export(&mut out, ExportFormat::Json, Column::ALL, vec![]).unwrap();
+        assert_eq!(out, b"[\n]\n");
+        let mut out = vec![];
+        export(
+            &mut out,
+            ExportFormat::Csv,
+            &[Column::Id, Column::Name],
+            vec![],
+        )
+        .unwrap();
+        assert_eq!(out, b"id,name\r\n");
+    }
+}
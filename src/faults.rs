@@ -0,0 +1,232 @@
+// Failures injected on purpose, to watch the error handling do what it
+// says it does: smartctl failing or hanging, read errors at an offset,
+// SQLite refusing writes, notifiers failing to deliver. Only built with the
+// `faults` feature. Without it every check is an empty inline function and
+// the seams compile away.
+//
+// Faults are set at runtime over the control socket, see control().
+
+use std::{io, path::Path, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+/// Where a fault can be injected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FaultPoint {
+    /// Running smartctl, for scans and for identifying drives.
+    SmartctlExec,
+    /// Reading through BlockIo.
+    BlockRead,
+    /// Recording drives found and lost, and their events.
+    SqliteWrite,
+    /// Sending a notification, once per attempt.
+    NotifierDelivery,
+}
+
+/// A fault to inject, as `faults set` takes it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FaultSpec {
+    /// Where it's injected.
+    pub point: FaultPoint,
+    /// Only for this device, by kernel name, or this notifier, by its name
+    /// in the logs. Everything at the point when not set.
+    #[serde(default)]
+    pub scope: Option<String>,
+    /// The chance of failing each time the point is passed.
+    #[serde(default = "always")]
+    pub probability: f64,
+    /// Fail once, then go away.
+    #[serde(default)]
+    pub once: bool,
+    /// Hang this long before failing, to look like a timeout.
+    #[serde(default)]
+    pub delay_ms: u64,
+    /// For block reads, only fail the ones covering this byte offset.
+    #[serde(default)]
+    pub offset: Option<u64>,
+}
+
+fn always() -> f64 {
+    1.0
+}
+
+/// A fault that fired.
+#[derive(Debug, Clone)]
+pub struct Fault {
+    /// How long to hang before failing.
+    pub delay: Duration,
+    /// What the failure says.
+    pub message: String,
+}
+
+impl Fault {
+    /// The fault as the io::Error it stands in for.
+    pub fn into_io_error(self) -> io::Error {
+        io::Error::other(self.message)
+    }
+}
+
+#[cfg(feature = "faults")]
+mod injected {
+    use std::{
+        sync::{Mutex, MutexGuard},
+        time::{SystemTime, UNIX_EPOCH},
+    };
+
+    use super::*;
+
+    struct Faults {
+        specs: Vec<FaultSpec>,
+        // xorshift, seeded from the clock on first use.
+        state: u64,
+    }
+
+    static FAULTS: Mutex<Faults> = Mutex::new(Faults {
+        specs: Vec::new(),
+        state: 0,
+    });
+
+    // Nothing panics while holding it, a poisoned lock is as good as any.
+    fn lock() -> MutexGuard<'static, Faults> {
+        FAULTS
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    impl Faults {
+        // Uniform in [0, 1).
+        fn roll(&mut self) -> f64 {
+            if self.state == 0 {
+                self.state = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |since| since.as_nanos() as u64)
+                    | 1;
+            }
+            self.state ^= self.state << 13;
+            self.state ^= self.state >> 7;
+            self.state ^= self.state << 17;
+            (self.state >> 11) as f64 / (1u64 << 53) as f64
+        }
+    }
+
+    /// The fault to inject at `point`, if any fires. `read` is the offset
+    /// and length of a block read.
+    pub fn check(
+        point: FaultPoint,
+        scope: Option<&str>,
+        read: Option<(u64, u64)>,
+    ) -> Option<Fault> {
+        let mut faults = lock();
+        let index = (0..faults.specs.len()).find(|&index| {
+            let spec = &faults.specs[index];
+            spec.point == point
+                && spec
+                    .scope
+                    .as_deref()
+                    .is_none_or(|wanted| Some(wanted) == scope)
+                && match (spec.offset, read) {
+                    (Some(offset), Some((start, len))) => {
+                        (start..start.saturating_add(len)).contains(&offset)
+                    }
+                    (Some(_), None) => false,
+                    (None, _) => true,
+                }
+        })?;
+
+        let probability = faults.specs[index].probability;
+        if faults.roll() >= probability {
+            return None;
+        }
+        let spec = if faults.specs[index].once {
+            faults.specs.remove(index)
+        } else {
+            faults.specs[index].clone()
+        };
+
+        warn!(
+            "Injecting a {:?} fault{}",
+            spec.point,
+            scope
+                .map(|scope| format!(" for {}", scope))
+                .unwrap_or_default()
+        );
+        Some(Fault {
+            delay: Duration::from_millis(spec.delay_ms),
+            message: format!("Injected {:?} fault", spec.point),
+        })
+    }
+
+    /// `faults set {"point": "block_read", "scope": "sdb", "offset": 4096}`,
+    /// `faults clear` or `faults list`. Answers with JSON, like every
+    /// control command.
+    pub fn control(args: &str) -> String {
+        let (verb, rest) = args.trim().split_once(' ').unwrap_or((args.trim(), ""));
+        let result = match verb {
+            "set" => serde_json::from_str::<FaultSpec>(rest)
+                .map_err(|e| format!("Not a fault: {}", e))
+                .and_then(|spec| {
+                    if (0.0..=1.0).contains(&spec.probability) {
+                        Ok(spec)
+                    } else {
+                        Err("probability has to be between 0 and 1".to_string())
+                    }
+                })
+                .map(|spec| {
+                    info!("Injecting {:?} faults from now on", spec.point);
+                    lock().specs.push(spec);
+                }),
+            "clear" => {
+                lock().specs.clear();
+                Ok(())
+            }
+            "list" => Ok(()),
+            verb => Err(format!("Unknown faults command \"{}\"", verb)),
+        };
+
+        match result {
+            Ok(()) => serde_json::json!({ "faults": lock().specs }).to_string(),
+            Err(error) => serde_json::json!({ "error": error }).to_string(),
+        }
+    }
+}
+
+#[cfg(feature = "faults")]
+pub use injected::{check, control};
+
+/// Never fires without the faults feature.
+#[cfg(not(feature = "faults"))]
+#[inline(always)]
+pub fn check(_point: FaultPoint, _scope: Option<&str>, _read: Option<(u64, u64)>) -> Option<Fault> {
+    None
+}
+
+/// For seams on blocking threads.
+#[inline(always)]
+pub fn fail_blocking(point: FaultPoint, scope: Option<&str>) -> io::Result<()> {
+    match check(point, scope, None) {
+        Some(fault) => {
+            std::thread::sleep(fault.delay);
+            Err(fault.into_io_error())
+        }
+        None => Ok(()),
+    }
+}
+
+/// For seams in async code.
+#[inline(always)]
+pub async fn fail(point: FaultPoint, scope: Option<&str>) -> io::Result<()> {
+    match check(point, scope, None) {
+        Some(fault) => {
+            tokio::time::sleep(fault.delay).await;
+            Err(fault.into_io_error())
+        }
+        None => Ok(()),
+    }
+}
+
+/// The scope of a seam that has a device node, its kernel name.
+pub fn node_name(node: &Path) -> Option<&str> {
+    node.file_name().and_then(|name| name.to_str())
+}
@@ -0,0 +1,710 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt::Write,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+
+use crate::{config::IdentifyConfig, topology::DeviceLocation};
+
+/// What kind of bus a device is on, which is what decides how many smartctl
+/// runs it takes at once. A USB bridge chokes on a few, an HBA takes a dozen
+/// without noticing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BusClass {
+    /// Behind a USB bridge.
+    Usb,
+    /// On a SATA port, or an HBA running the disk as SATA.
+    Sata,
+    /// On a SAS HBA.
+    Sas,
+    /// An NVMe drive.
+    Nvme,
+    /// Virtual disks, virtio and whatever else doesn't fit the rest.
+    Other,
+}
+
+impl BusClass {
+    /// Every class, in the order `hddmond status` lists them.
+    pub const ALL: &'static [BusClass] = &[
+        BusClass::Usb,
+        BusClass::Sata,
+        BusClass::Sas,
+        BusClass::Nvme,
+        BusClass::Other,
+    ];
+
+    /// The name it goes by in the config and in metric labels.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BusClass::Usb => "usb",
+            BusClass::Sata => "sata",
+            BusClass::Sas => "sas",
+            BusClass::Nvme => "nvme",
+            BusClass::Other => "other",
+        }
+    }
+
+    /// USB first, a USB bridge is the bottleneck whatever's behind it. SAS
+    /// HBAs are told by their driver, disks straight on one have no
+    /// expander in their path.
+    pub fn of(location: &DeviceLocation) -> Self {
+        let driver = location
+            .controller
+            .as_ref()
+            .and_then(|controller| controller.driver.as_deref())
+            .unwrap_or_default();
+
+        if location.usb.is_some() {
+            BusClass::Usb
+        } else if location.nvme.is_some() {
+            BusClass::Nvme
+        } else if location.sas.is_some() || SAS_DRIVERS.contains(&driver) {
+            BusClass::Sas
+        } else if location.scsi.is_some()
+            && (driver == "ahci"
+                || ["ata_", "sata_", "pata_"]
+                    .iter()
+                    .any(|p| driver.starts_with(p)))
+        {
+            BusClass::Sata
+        } else {
+            BusClass::Other
+        }
+    }
+}
+
+const SAS_DRIVERS: &[&str] = &[
+    "mpt3sas",
+    "mpt2sas",
+    "megaraid_sas",
+    "aacraid",
+    "hpsa",
+    "smartpqi",
+    "pm80xx",
+    "isci",
+    "mvsas",
+];
+
+/// Where a run is going, for its limits.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Slot {
+    /// Its bus, for the per-bus limit.
+    pub bus: BusClass,
+    /// The PCI address of the controller, for the per-controller limit.
+    pub controller: Option<String>,
+}
+
+impl Slot {
+    /// From sysfs, devices without a location count as Other.
+    pub fn lookup(name: &str) -> Self {
+        match DeviceLocation::lookup(name) {
+            Some(location) => Self {
+                bus: BusClass::of(&location),
+                controller: location.controller.map(|controller| controller.pci_address),
+            },
+            None => Self {
+                bus: BusClass::Other,
+                controller: None,
+            },
+        }
+    }
+}
+
+/// Lets smartctl runs identifying devices go ahead once every limit they
+/// fall under has room: the total, their bus class's and their controller's.
+/// Runs go in the order they were asked for, except that one that has to
+/// wait for its class or controller doesn't hold up those behind it that
+/// don't. Every run that's waiting goes before any that came after it on the
+/// same class and controller, so none can wait forever.
+#[derive(Clone)]
+pub struct IdentifyQueue {
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    limits: IdentifyConfig,
+    in_flight: usize,
+    per_bus: HashMap<BusClass, usize>,
+    per_controller: HashMap<String, usize>,
+    waiting: VecDeque<(Slot, oneshot::Sender<Permit>)>,
+}
+
+/// Held for as long as the run goes on, the next one can go once it's
+/// dropped.
+pub struct Permit {
+    // None for one that was never handed out.
+    state: Option<Arc<Mutex<State>>>,
+    slot: Slot,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(state) = self.state.take() {
+            let mut locked = lock(&state);
+            locked.release(&self.slot);
+            locked.dispatch(&state);
+        }
+    }
+}
+
+impl State {
+    fn fits(&self, slot: &Slot) -> bool {
+        let bus = self.per_bus.get(&slot.bus).copied().unwrap_or(0);
+        let controller = slot
+            .controller
+            .as_ref()
+            .and_then(|controller| self.per_controller.get(controller))
+            .copied()
+            .unwrap_or(0);
+        self.in_flight < self.limits.max_parallel
+            && bus < self.limits.per_bus.get(slot.bus)
+            && (slot.controller.is_none() || controller < self.limits.per_controller)
+    }
+
+    fn take(&mut self, slot: &Slot) {
+        self.in_flight += 1;
+        *self.per_bus.entry(slot.bus).or_default() += 1;
+        if let Some(controller) = &slot.controller {
+            *self.per_controller.entry(controller.clone()).or_default() += 1;
+        }
+    }
+
+    fn release(&mut self, slot: &Slot) {
+        self.in_flight -= 1;
+        if let Some(count) = self.per_bus.get_mut(&slot.bus) {
+            *count -= 1;
+        }
+        if let Some(controller) = &slot.controller {
+            if let Some(count) = self.per_controller.get_mut(controller) {
+                *count -= 1;
+                if *count == 0 {
+                    self.per_controller.remove(controller);
+                }
+            }
+        }
+    }
+
+    // Hands out permits to everything waiting that fits, oldest first.
+    fn dispatch(&mut self, state: &Arc<Mutex<State>>) {
+        let mut index = 0;
+        while index < self.waiting.len() && self.in_flight < self.limits.max_parallel {
+            let (slot, grant) = &self.waiting[index];
+            // Whoever asked for it gave up, or it has to wait.
+            let closed = grant.is_closed();
+            if !closed && !self.fits(slot) {
+                index += 1;
+                continue;
+            }
+            let (slot, grant) = match self.waiting.remove(index) {
+                Some(waiting) if !closed => waiting,
+                _ => continue,
+            };
+
+            self.take(&slot);
+            let permit = Permit {
+                state: Some(state.clone()),
+                slot,
+            };
+            // Gone in between, dropping the permit here would take the
+            // lock we're holding.
+            if let Err(mut permit) = grant.send(permit) {
+                permit.state = None;
+                self.release(&permit.slot);
+            }
+        }
+    }
+}
+
+impl IdentifyQueue {
+    /// A queue with nothing running, held to `limits`.
+    pub fn new(limits: &IdentifyConfig) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State {
+                limits: limits.clone(),
+                in_flight: 0,
+                per_bus: HashMap::new(),
+                per_controller: HashMap::new(),
+                waiting: VecDeque::new(),
+            })),
+        }
+    }
+
+    /// Waits for room for a run on `slot`.
+    pub async fn acquire(&self, slot: Slot) -> Permit {
+        let (grant, permit) = oneshot::channel();
+        {
+            let mut state = lock(&self.state);
+            state.waiting.push_back((slot.clone(), grant));
+            state.dispatch(&self.state);
+        }
+        // The sender is only dropped after sending, or with the queue.
+        permit.await.unwrap_or(Permit { state: None, slot })
+    }
+
+    /// How many runs are going on and waiting right now.
+    pub fn stats(&self) -> IdentifyStats {
+        let state = lock(&self.state);
+        IdentifyStats {
+            in_flight: BusClass::ALL
+                .iter()
+                .map(|bus| {
+                    (
+                        bus.as_str().to_string(),
+                        state.per_bus.get(bus).copied().unwrap_or(0),
+                    )
+                })
+                .collect(),
+            waiting: state.waiting.len(),
+        }
+    }
+}
+
+// Nothing panics while holding it, a poisoned lock is as good as any.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// For `hddmond status`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct IdentifyStats {
+    /// Runs going on right now, by bus class.
+    pub in_flight: BTreeMap<String, usize>,
+    /// How many runs are waiting for room.
+    pub waiting: usize,
+}
+
+/// Gauges in the Prometheus text format, to go with usage::prometheus.
+pub fn prometheus(stats: &IdentifyStats) -> String {
+    let mut text = String::new();
+    // Writing to a String can't fail.
+    let _ = writeln!(
+        text,
+        "# HELP hddmond_identify_in_flight smartctl runs identifying devices right now.\n\
+         # TYPE hddmond_identify_in_flight gauge"
+    );
+    for (bus, count) in &stats.in_flight {
+        let _ = writeln!(
+            text,
+            "hddmond_identify_in_flight{{bus=\"{}\"}} {}",
+            bus, count
+        );
+    }
+    let _ = writeln!(
+        text,
+        "# HELP hddmond_identify_waiting smartctl runs waiting for room under their limits.\n\
+         # TYPE hddmond_identify_waiting gauge\n\
+         hddmond_identify_waiting {}",
+        stats.waiting
+    );
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use tokio::time::timeout;
+
+    use super::*;
+    use crate::{
+        config::BusLimits,
+        topology::{Controller, SasPort, ScsiAddress, UsbPort},
+    };
+
+    fn limits(
+        max_parallel: usize,
+        per_bus: &[(BusClass, usize)],
+        per_controller: usize,
+    ) -> IdentifyConfig {
+        let mut limits = IdentifyConfig {
+            max_parallel,
+            per_bus: BusLimits {
+                usb: max_parallel,
+                sata: max_parallel,
+                sas: max_parallel,
+                nvme: max_parallel,
+                other: max_parallel,
+            },
+            per_controller,
+        };
+        for (bus, limit) in per_bus {
+            match bus {
+                BusClass::Usb => limits.per_bus.usb = *limit,
+                BusClass::Sata => limits.per_bus.sata = *limit,
+                BusClass::Sas => limits.per_bus.sas = *limit,
+                BusClass::Nvme => limits.per_bus.nvme = *limit,
+                BusClass::Other => limits.per_bus.other = *limit,
+            }
+        }
+        limits
+    }
+
+    fn slot(bus: BusClass, controller: Option<&str>) -> Slot {
+        Slot {
+            bus,
+            controller: controller.map(String::from),
+        }
+    }
+
+    // Whether `acquire` has been handed its permit yet, without waiting.
+    async fn granted(queue: &IdentifyQueue, slot: Slot) -> Option<Permit> {
+        timeout(Duration::from_millis(20), queue.acquire(slot))
+            .await
+            .ok()
+    }
+
+    fn spawn_acquire(queue: &IdentifyQueue, slot: Slot) -> tokio::task::JoinHandle<Permit> {
+        let queue = queue.clone();
+        tokio::spawn(async move { queue.acquire(slot).await })
+    }
+
+    fn location(
+        driver: Option<&str>,
+        scsi: bool,
+        nvme: bool,
+        sas: bool,
+        usb: bool,
+    ) -> DeviceLocation {
+        DeviceLocation {
+            device: "sdx".to_string(),
+            controller: driver.map(|driver| Controller {
+                pci_address: "0000:00:17.0".to_string(),
+                driver: Some(driver.to_string()),
+            }),
+            scsi: scsi.then_some(ScsiAddress {
+                host: 0,
+                channel: 0,
+                target: 0,
+                lun: 0,
+            }),
+            nvme: nvme.then(|| "nvme0".to_string()),
+            sas: sas.then(|| SasPort {
+                expander: "expander-0:0".to_string(),
+                phys: vec!["phy-0:0:5".to_string()],
+            }),
+            usb: usb.then(|| UsbPort {
+                bus: 2,
+                ports: vec![1, 4],
+            }),
+            enclosure: None,
+        }
+    }
+
+    #[test]
+    fn bus_classes_of_topologies() {
+        for (location, bus) in [
+            (
+                location(Some("ahci"), true, false, false, false),
+                BusClass::Sata,
+            ),
+            (
+                location(Some("ata_piix"), true, false, false, false),
+                BusClass::Sata,
+            ),
+            (
+                location(Some("sata_nv"), true, false, false, false),
+                BusClass::Sata,
+            ),
+            (
+                location(Some("mpt3sas"), true, false, false, false),
+                BusClass::Sas,
+            ),
+            (
+                location(Some("megaraid_sas"), true, false, false, false),
+                BusClass::Sas,
+            ),
+            // Behind an expander, whatever the driver.
+            (
+                location(Some("unknown"), true, false, true, false),
+                BusClass::Sas,
+            ),
+            (
+                location(Some("nvme"), false, true, false, false),
+                BusClass::Nvme,
+            ),
+            // A SATA disk in a USB dock, and an NVMe one in a USB enclosure.
+            (
+                location(Some("xhci_hcd"), true, false, false, true),
+                BusClass::Usb,
+            ),
+            (
+                location(Some("xhci_hcd"), false, true, false, true),
+                BusClass::Usb,
+            ),
+            (
+                location(Some("virtio-pci"), false, false, false, false),
+                BusClass::Other,
+            ),
+            (
+                location(Some("ahci"), false, false, false, false),
+                BusClass::Other,
+            ),
+            (location(None, true, false, false, false), BusClass::Other),
+            (DeviceLocation::default(), BusClass::Other),
+        ] {
+            assert_eq!(BusClass::of(&location), bus, "{:?}", location);
+        }
+    }
+
+    // Counts of runs going at once, and the most there ever were.
+    #[derive(Default)]
+    struct Meter {
+        now: AtomicUsize,
+        most: AtomicUsize,
+    }
+
+    impl Meter {
+        fn enter(&self) {
+            let now = self.now.fetch_add(1, Ordering::SeqCst) + 1;
+            self.most.fetch_max(now, Ordering::SeqCst);
+        }
+
+        fn leave(&self) {
+            self.now.fetch_sub(1, Ordering::SeqCst);
+        }
+
+        fn most(&self) -> usize {
+            self.most.load(Ordering::SeqCst)
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn limits_hold_over_a_busy_topology() {
+        // Two USB docks on one controller, an AHCI controller, an HBA
+        // with its disks, NVMe drives each on their own controller, and
+        // some virtio disks.
+        let topology = [
+            (BusClass::Usb, Some("0000:00:14.0")),
+            (BusClass::Usb, Some("0000:00:14.0")),
+            (BusClass::Sata, Some("0000:00:17.0")),
+            (BusClass::Sas, Some("0000:03:00.0")),
+            (BusClass::Sas, Some("0000:03:00.0")),
+            (BusClass::Sas, Some("0000:03:00.0")),
+            (BusClass::Nvme, Some("0000:01:00.0")),
+            (BusClass::Nvme, Some("0000:02:00.0")),
+            (BusClass::Other, None),
+        ];
+        let limits = limits(
+            6,
+            &[(BusClass::Usb, 1), (BusClass::Sata, 3), (BusClass::Sas, 4)],
+            2,
+        );
+        let queue = IdentifyQueue::new(&limits);
+
+        let total = Arc::new(Meter::default());
+        let buses = Arc::new(
+            BusClass::ALL
+                .iter()
+                .map(|bus| (*bus, Meter::default()))
+                .collect::<HashMap<_, _>>(),
+        );
+        let controllers = Arc::new(Mutex::new(HashMap::<String, Arc<Meter>>::new()));
+
+        let mut runs = vec![];
+        for n in 0..300 {
+            let (bus, controller) = topology[n * 7 % topology.len()];
+            let slot = slot(bus, controller);
+            let (queue, total, buses, controllers) = (
+                queue.clone(),
+                total.clone(),
+                buses.clone(),
+                controllers.clone(),
+            );
+            runs.push(tokio::spawn(async move {
+                let controller = slot.controller.as_ref().map(|controller| {
+                    lock(&controllers)
+                        .entry(controller.clone())
+                        .or_default()
+                        .clone()
+                });
+                let _permit = queue.acquire(slot.clone()).await;
+                total.enter();
+                buses[&slot.bus].enter();
+                if let Some(controller) = &controller {
+                    controller.enter();
+                }
+
+                tokio::time::sleep(Duration::from_micros(100 * (n % 5) as u64)).await;
+
+                if let Some(controller) = &controller {
+                    controller.leave();
+                }
+                buses[&slot.bus].leave();
+                total.leave();
+            }));
+        }
+
+        // None of them left waiting.
+        timeout(Duration::from_secs(30), async {
+            for run in runs {
+                run.await.unwrap();
+            }
+        })
+        .await
+        .expect("Runs were left waiting");
+
+        assert!(total.most() <= 6, "{} at once", total.most());
+        for bus in BusClass::ALL {
+            let most = buses[bus].most();
+            assert!(
+                most <= limits.per_bus.get(*bus),
+                "{} {} at once",
+                most,
+                bus.as_str()
+            );
+        }
+        for (address, meter) in lock(&controllers).iter() {
+            assert!(meter.most() <= 2, "{} {} at once", meter.most(), address);
+        }
+        // Busy enough to have reached them.
+        assert_eq!(total.most(), 6);
+        assert_eq!(buses[&BusClass::Usb].most(), 1);
+
+        let stats = queue.stats();
+        assert_eq!(stats.waiting, 0);
+        assert!(stats.in_flight.values().all(|count| *count == 0));
+    }
+
+    #[tokio::test]
+    async fn a_run_waiting_on_its_bus_doesnt_hold_up_the_others() {
+        let queue = IdentifyQueue::new(&limits(4, &[(BusClass::Usb, 1)], 8));
+        let usb = granted(&queue, slot(BusClass::Usb, Some("usb")))
+            .await
+            .unwrap();
+        let waiting = spawn_acquire(&queue, slot(BusClass::Usb, Some("usb")));
+        tokio::task::yield_now().await;
+
+        let sata = granted(&queue, slot(BusClass::Sata, Some("ahci"))).await;
+        assert!(sata.is_some());
+        assert!(!waiting.is_finished());
+
+        drop(usb);
+        timeout(Duration::from_secs(1), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn the_oldest_run_goes_first() {
+        let queue = IdentifyQueue::new(&limits(2, &[(BusClass::Usb, 1)], 8));
+
+        // Blocked on its bus: the next USB run is the one that waited
+        // longest, not the newest.
+        let usb = granted(&queue, slot(BusClass::Usb, None)).await.unwrap();
+        let first = spawn_acquire(&queue, slot(BusClass::Usb, None));
+        tokio::task::yield_now().await;
+        let second = spawn_acquire(&queue, slot(BusClass::Usb, None));
+        tokio::task::yield_now().await;
+        drop(usb);
+        let first = timeout(Duration::from_secs(1), first)
+            .await
+            .unwrap()
+            .unwrap();
+        tokio::task::yield_now().await;
+        assert!(!second.is_finished());
+        drop(first);
+        drop(
+            timeout(Duration::from_secs(1), second)
+                .await
+                .unwrap()
+                .unwrap(),
+        );
+
+        // Blocked on the total: a run on a bus with room doesn't overtake
+        // one that was there first, or a steady stream of them would keep
+        // it waiting forever.
+        let held = [
+            granted(&queue, slot(BusClass::Sata, None)).await.unwrap(),
+            granted(&queue, slot(BusClass::Sata, None)).await.unwrap(),
+        ];
+        let sas = spawn_acquire(&queue, slot(BusClass::Sas, None));
+        tokio::task::yield_now().await;
+        let sata = spawn_acquire(&queue, slot(BusClass::Sata, None));
+        tokio::task::yield_now().await;
+        let [one, other] = held;
+        drop(one);
+        let sas = timeout(Duration::from_secs(1), sas).await.unwrap().unwrap();
+        tokio::task::yield_now().await;
+        assert!(!sata.is_finished());
+        drop(other);
+        timeout(Duration::from_secs(1), sata)
+            .await
+            .unwrap()
+            .unwrap();
+        drop(sas);
+    }
+
+    #[tokio::test]
+    async fn the_controller_limit_spans_bus_classes() {
+        // One controller with both USB and, through a bridge, a disk that
+        // counts as Other.
+        let queue = IdentifyQueue::new(&limits(8, &[], 1));
+        let usb = granted(&queue, slot(BusClass::Usb, Some("0000:00:14.0")))
+            .await
+            .unwrap();
+        assert!(granted(&queue, slot(BusClass::Other, Some("0000:00:14.0")))
+            .await
+            .is_none());
+        // Runs without a controller only count towards the rest.
+        assert!(granted(&queue, slot(BusClass::Usb, None)).await.is_some());
+        assert!(granted(&queue, slot(BusClass::Usb, Some("0000:00:15.0")))
+            .await
+            .is_some());
+        drop(usb);
+        assert!(granted(&queue, slot(BusClass::Other, Some("0000:00:14.0")))
+            .await
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn runs_given_up_on_dont_take_room() {
+        let queue = IdentifyQueue::new(&limits(1, &[], 8));
+        let held = granted(&queue, slot(BusClass::Sata, None)).await.unwrap();
+        // Waits, then gives up.
+        assert!(granted(&queue, slot(BusClass::Sata, None)).await.is_none());
+        let waiting = spawn_acquire(&queue, slot(BusClass::Nvme, None));
+        tokio::task::yield_now().await;
+        assert_eq!(queue.stats().waiting, 2);
+        assert_eq!(queue.stats().in_flight["sata"], 1);
+
+        drop(held);
+        let nvme = timeout(Duration::from_secs(1), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+        let stats = queue.stats();
+        assert_eq!(stats.waiting, 0);
+        assert_eq!(stats.in_flight["sata"], 0);
+        assert_eq!(stats.in_flight["nvme"], 1);
+        drop(nvme);
+        assert_eq!(queue.stats().in_flight["nvme"], 0);
+    }
+
+    #[test]
+    fn stats_as_prometheus_gauges() {
+        let stats = IdentifyStats {
+            in_flight: BTreeMap::from([("sata".to_string(), 2), ("usb".to_string(), 1)]),
+            waiting: 3,
+        };
+        assert_eq!(
+            prometheus(&stats),
+            "# HELP hddmond_identify_in_flight smartctl runs identifying devices right now.\n\
+             # TYPE hddmond_identify_in_flight gauge\n\
+             hddmond_identify_in_flight{bus=\"sata\"} 2\n\
+             hddmond_identify_in_flight{bus=\"usb\"} 1\n\
+             # HELP hddmond_identify_waiting smartctl runs waiting for room under their limits.\n\
+             # TYPE hddmond_identify_waiting gauge\n\
+             hddmond_identify_waiting 3\n"
+        );
+    }
+}
@@ -0,0 +1,435 @@
+use std::{
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use chrono::{SecondsFormat, Utc};
+use flate2::{write::GzEncoder, Compression};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::block_io::{AlignedBuf, BlockIo, CacheBypass};
+
+// How much is read at a time. A chunk that can't be read is gone over
+// again a sector at a time, to lose only the sectors that are bad.
+const CHUNK_BYTES: usize = 1 << 20;
+
+// Compressed images are gzip members of this much of the device each, one
+// after the other. gunzip reads them as one stream, and a damaged one only
+// loses its own part.
+const MEMBER_BYTES: u64 = 64 << 20;
+
+/// Sectors that couldn't be read, which are zeroes in the image.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BadRange {
+    /// Where they start on the device.
+    pub offset: u64,
+    /// How many bytes in a row couldn't be read.
+    pub bytes: u64,
+    /// What reading the first of them failed with.
+    pub error: String,
+}
+
+/// What went into an image, for keeping next to it and for the audit trail.
+#[derive(Debug, Clone, Serialize)]
+pub struct ImageRecord {
+    /// The device that was imaged.
+    pub source: PathBuf,
+    /// The serial it reported, if it has one.
+    pub serial: Option<String>,
+    /// None when it went to a pipe.
+    pub image: Option<PathBuf>,
+    /// Whether the image is gzipped.
+    pub compressed: bool,
+    /// How much of the device was read.
+    pub size_bytes: u64,
+    /// The device's logical sector size.
+    pub sector_size: u64,
+    /// Whether what was read came off the disk for certain.
+    pub cache_bypass: CacheBypass,
+    /// When reading started, RFC 3339.
+    pub started: String,
+    /// When it finished, RFC 3339.
+    pub finished: String,
+    /// How long it took.
+    pub duration_secs: f64,
+    /// Of the raw image, the zeroes in place of bad sectors included, so
+    /// decompressing a compressed one gives the same hash.
+    pub sha256: String,
+    /// How much of the image is zeroes for bad sectors.
+    pub bad_bytes: u64,
+    /// Where those are, in order.
+    pub bad_ranges: Vec<BadRange>,
+}
+
+/// Copies all of `source` to `out`, bypassing the page cache, hashing it on
+/// the way. Never writes to the source. Sectors that can't be read are
+/// written as zeroes and listed in the record. `progress` is called with
+/// how far it got after every chunk.
+///
+/// Errors are for not being able to open the source or write the image,
+/// read errors are bad ranges.
+pub fn image<W: Write>(
+    source: &Path,
+    sector_size: u64,
+    out: W,
+    compress: bool,
+    mut progress: impl FnMut(u64, u64),
+) -> io::Result<(ImageRecord, W)> {
+    let started = Utc::now();
+    let clock = Instant::now();
+    let io = BlockIo::open(source, sector_size)?;
+    let size = io.size()?;
+
+    let (sha256, bad_ranges, out) = copy(
+        size,
+        sector_size,
+        out,
+        compress,
+        |buf, offset, len| io.read_exact_at(buf, offset, len),
+        &mut progress,
+    )?;
+
+    let record = ImageRecord {
+        source: source.to_path_buf(),
+        serial: None,
+        image: None,
+        compressed: compress,
+        size_bytes: size,
+        sector_size,
+        cache_bypass: io.bypass(),
+        started: started.to_rfc3339_opts(SecondsFormat::Secs, true),
+        finished: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        duration_secs: clock.elapsed().as_secs_f64(),
+        sha256,
+        bad_bytes: bad_ranges.iter().map(|range| range.bytes).sum(),
+        bad_ranges,
+    };
+    Ok((record, out))
+}
+
+// The copying itself, of `size` bytes that `read` fills a buffer with one
+// piece at a time. Returns the hash and the bad ranges.
+fn copy<W: Write>(
+    size: u64,
+    sector_size: u64,
+    out: W,
+    compress: bool,
+    mut read: impl FnMut(&mut AlignedBuf, u64, usize) -> io::Result<()>,
+    mut progress: impl FnMut(u64, u64),
+) -> io::Result<(String, Vec<BadRange>, W)> {
+    let mut sink = if compress {
+        Sink::Gzip(Some(GzEncoder::new(out, Compression::default())), 0)
+    } else {
+        Sink::Raw(out)
+    };
+    let mut hash = Sha256::new();
+    let mut bad_ranges: Vec<BadRange> = vec![];
+    let mut buf = AlignedBuf::new(CHUNK_BYTES);
+
+    let mut offset = 0;
+    while offset < size {
+        let len = (size - offset).min(CHUNK_BYTES as u64) as usize;
+        if read(&mut buf, offset, len).is_ok() {
+            let data = buf.get(len);
+            hash.update(&*data);
+            sink.write_all(data)?;
+        } else {
+            let mut sector = offset;
+            while sector < offset + len as u64 {
+                let want = (offset + len as u64 - sector).min(sector_size) as usize;
+                if let Err(e) = read(&mut buf, sector, want) {
+                    buf.get(want).fill(0);
+                    match bad_ranges.last_mut() {
+                        Some(last) if last.offset + last.bytes == sector => {
+                            last.bytes += want as u64
+                        }
+                        _ => bad_ranges.push(BadRange {
+                            offset: sector,
+                            bytes: want as u64,
+                            error: e.to_string(),
+                        }),
+                    }
+                }
+                let data = buf.get(want);
+                hash.update(&*data);
+                sink.write_all(data)?;
+                sector += want as u64;
+            }
+        }
+        offset += len as u64;
+        progress(offset, size);
+    }
+    let out = sink.finish()?;
+
+    let sha256 = hash
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    Ok((sha256, bad_ranges, out))
+}
+
+// Where the image goes. A gzipped one is the member being written and how
+// much of the device is in it so far.
+enum Sink<W: Write> {
+    Raw(W),
+    Gzip(Option<GzEncoder<W>>, u64),
+}
+
+impl<W: Write> Sink<W> {
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        match self {
+            Sink::Raw(out) => out.write_all(data),
+            Sink::Gzip(member, written) => {
+                if *written >= MEMBER_BYTES {
+                    let out = finish_member(member)?;
+                    *member = Some(GzEncoder::new(out, Compression::default()));
+                    *written = 0;
+                }
+                *written += data.len() as u64;
+                match member {
+                    Some(member) => member.write_all(data),
+                    None => Err(finished()),
+                }
+            }
+        }
+    }
+
+    fn finish(self) -> io::Result<W> {
+        let mut out = match self {
+            Sink::Raw(out) => out,
+            Sink::Gzip(mut member, _) => finish_member(&mut member)?,
+        };
+        out.flush()?;
+        Ok(out)
+    }
+}
+
+fn finish_member<W: Write>(member: &mut Option<GzEncoder<W>>) -> io::Result<W> {
+    member.take().ok_or_else(finished)?.finish()
+}
+
+// After finishing a member failed, which already ended the image.
+fn finished() -> io::Error {
+    io::Error::other("the image was already finished")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeSet, fs, io::Read, process::Command};
+
+    use flate2::read::MultiGzDecoder;
+    use nix::errno::Errno;
+
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    // Never repeats on a sector boundary, so a sector in the wrong place
+    // shows.
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn sha256(data: &[u8]) -> String {
+        Sha256::digest(data)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    // `data` as a disk whose `bad` sectors can't be read, failing any read
+    // that touches one the way the kernel does.
+    fn disk(
+        data: &[u8],
+        sector_size: u64,
+        bad: BTreeSet<u64>,
+    ) -> impl FnMut(&mut AlignedBuf, u64, usize) -> io::Result<()> + '_ {
+        move |buf, offset, len| {
+            let first = offset / sector_size;
+            let last = (offset + len as u64).div_ceil(sector_size);
+            if bad.range(first..last).next().is_some() {
+                return Err(Errno::EIO.into());
+            }
+            buf.get(len)
+                .copy_from_slice(&data[offset as usize..offset as usize + len]);
+            Ok(())
+        }
+    }
+
+    fn range(offset: u64, bytes: u64) -> BadRange {
+        BadRange {
+            offset,
+            bytes,
+            error: "Input/output error (os error 5)".to_string(),
+        }
+    }
+
+    #[test]
+    fn bad_sectors_are_zeroes_and_listed() {
+        // Three chunks and a bit, ending half way into a sector.
+        let size = 3 * CHUNK_BYTES + 1000;
+        let data = pattern(size);
+        let last = (size as u64) / 512;
+        let bad = BTreeSet::from([
+            // Next to each other, one range.
+            10, 11, // Either side of the end of the first chunk, one range too.
+            2047, 2048, 5000, // The part of a sector the disk ends in.
+            last,
+        ]);
+
+        let mut seen = vec![];
+        let (sha, bad_ranges, out) = copy(
+            size as u64,
+            512,
+            Vec::new(),
+            false,
+            disk(&data, 512, bad),
+            |done, of| seen.push((done, of)),
+        )
+        .unwrap();
+
+        assert_eq!(
+            bad_ranges,
+            [
+                range(10 * 512, 1024),
+                range(2047 * 512, 1024),
+                range(5000 * 512, 512),
+                range(last * 512, 488),
+            ]
+        );
+        let mut expected = data.clone();
+        for range in &bad_ranges {
+            expected[range.offset as usize..(range.offset + range.bytes) as usize].fill(0);
+        }
+        assert_eq!(out.len(), size);
+        assert!(out == expected);
+        assert_eq!(sha, sha256(&expected));
+        assert_ne!(sha, sha256(&data));
+
+        let chunk = CHUNK_BYTES as u64;
+        assert_eq!(
+            seen,
+            [
+                (chunk, size as u64),
+                (2 * chunk, size as u64),
+                (3 * chunk, size as u64),
+                (size as u64, size as u64),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_disk_with_nothing_readable() {
+        let data = pattern(64 * 4096);
+        let bad = (0..64).collect();
+        let (sha, bad_ranges, out) = copy(
+            data.len() as u64,
+            4096,
+            Vec::new(),
+            false,
+            disk(&data, 4096, bad),
+            |_, _| {},
+        )
+        .unwrap();
+        assert_eq!(bad_ranges, [range(0, data.len() as u64)]);
+        assert!(out.iter().all(|&byte| byte == 0));
+        assert_eq!(sha, sha256(&vec![0; data.len()]));
+    }
+
+    #[test]
+    fn compressed_images_hash_the_same_as_raw_ones() {
+        let data = pattern(2 * CHUNK_BYTES + 512);
+        let bad = BTreeSet::from([3, 2100]);
+        let (raw_sha, raw_bad, raw) = copy(
+            data.len() as u64,
+            512,
+            Vec::new(),
+            false,
+            disk(&data, 512, bad.clone()),
+            |_, _| {},
+        )
+        .unwrap();
+        let (sha, bad_ranges, gzipped) = copy(
+            data.len() as u64,
+            512,
+            Vec::new(),
+            true,
+            disk(&data, 512, bad),
+            |_, _| {},
+        )
+        .unwrap();
+
+        assert_eq!(sha, raw_sha);
+        assert_eq!(bad_ranges, raw_bad);
+        assert!(gzipped.len() < raw.len());
+        let mut unpacked = vec![];
+        MultiGzDecoder::new(&gzipped[..])
+            .read_to_end(&mut unpacked)
+            .unwrap();
+        assert!(unpacked == raw);
+    }
+
+    #[test]
+    fn an_image_that_cant_be_written_is_an_error() {
+        struct Full;
+
+        impl Write for Full {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(Errno::ENOSPC.into())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let data = pattern(4096);
+        let error = copy(
+            4096,
+            512,
+            Full,
+            false,
+            disk(&data, 512, BTreeSet::new()),
+            |_, _| {},
+        )
+        .err()
+        .unwrap();
+        assert_eq!(error.raw_os_error(), Some(Errno::ENOSPC as i32));
+    }
+
+    #[test]
+    #[ignore = "needs a loop device (root)"]
+    fn a_loop_device_imaged_whole() {
+        let dir = TestDir::new("image-loop");
+        let path = dir.join("image");
+        let data = pattern(CHUNK_BYTES + 8 * 4096);
+        fs::write(&path, &data).unwrap();
+        let output = Command::new("losetup")
+            .args(["--find", "--show", "--sector-size", "4096"])
+            .arg(&path)
+            .output()
+            .expect("losetup to run");
+        assert!(
+            output.status.success(),
+            "No loop device: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        let node = PathBuf::from(String::from_utf8_lossy(&output.stdout).trim());
+
+        let imaged = image(&node, 4096, Vec::new(), false, |_, _| {});
+        let _ = Command::new("losetup").arg("-d").arg(&node).status();
+
+        let (record, out) = imaged.unwrap();
+        assert!(out == data);
+        assert_eq!(record.sha256, sha256(&data));
+        assert_eq!(record.size_bytes, data.len() as u64);
+        assert_eq!(record.sector_size, 4096);
+        assert_eq!(record.cache_bypass, CacheBypass::Direct);
+        assert!(record.bad_ranges.is_empty());
+        assert_eq!(record.bad_bytes, 0);
+        assert_eq!(record.source, node);
+        assert!(!record.compressed);
+    }
+}
@@ -0,0 +1,90 @@
+//! The pieces the hddmond daemon is built from, usable on their own: the
+//! device monitors, the device registry, and the typed errors they return.
+//! [`pipeline::Pipeline`] wires them together into the daemon, the binary
+//! only parses the command line, loads the config and runs one.
+//!
+//! Everything public is documented, and the build fails on anything that
+//! isn't. [`scanners`], [`storage`], [`pipeline`] and [`error`](mod@error) are the
+//! stable surface. The other modules are public so the binary can use them,
+//! but they're still shaped around it and may change without notice.
+
+#![deny(missing_docs)]
+
+#[macro_use]
+extern crate log;
+
+/// The append-only audit trail of everything the daemon did.
+pub mod audit;
+/// Blinking a drive's activity LED to find it.
+pub mod blink;
+/// Reading disks past the page cache.
+pub mod block_io;
+/// What can be done to each device, like whether it can be secure erased.
+pub mod capabilities;
+/// Device sizes and block sizes, and cross-checking them with smartctl.
+pub mod capacity;
+/// The config file and its defaults.
+pub mod config;
+/// The socket `hddmond status` talks to the daemon over.
+pub mod control;
+/// Forking into the background and dropping root.
+pub mod daemon;
+/// Whether each device is ok, and why not.
+pub mod device_health;
+/// Which devices hddmond may touch, and what's known about each one.
+pub mod device_policy;
+/// Pausing the daemon's own writes when its disk fills up.
+pub mod disk_guard;
+/// Checking what the daemon depends on, for `hddmond doctor`.
+pub mod doctor;
+/// Typed errors for every subsystem.
+pub mod error;
+/// Reading device events on a thread of their own.
+pub mod event_reader;
+/// Writing the registry out as CSV or JSON.
+pub mod export;
+/// Failures injected on purpose, with the `faults` feature.
+pub mod faults;
+/// Spreading the smartctl runs that identify devices over their buses.
+pub mod identify_queue;
+/// Copying a disk to an image, read-only.
+pub mod image;
+/// Watching the kernel's SATA and SAS link error counters.
+pub mod link_health;
+mod log_file;
+/// Setting up log output.
+pub mod logging;
+/// eMMC and SD devices, and how worn out eMMC is.
+pub mod mmc;
+/// Sending device events to webhooks, email, chat and the journal.
+pub mod notifiers;
+/// The daemon's main loop, from device events to everything done about them.
+pub mod pipeline;
+/// The JavaScript plugin runtime.
+pub mod plugins;
+/// Spindown and APM settings through hdparm.
+pub mod power;
+/// Reading partition tables and filesystem signatures off a drive.
+pub mod probe;
+/// Watching for devices coming and going.
+pub mod scanners;
+/// Sequencing shutdown on SIGTERM and SIGINT.
+pub mod shutdown;
+/// Turning a smartd.conf into a config fragment, for `hddmond import-smartd`.
+pub mod smartd_import;
+/// Putting together what `hddmond status` shows.
+pub mod status;
+/// The registry of every device ever seen.
+pub mod storage;
+/// Restarting internal tasks that panic, and keeping track of how they do.
+pub mod supervisor;
+/// Putting together `hddmond support-bundle`, for bug reports.
+pub mod support_bundle;
+#[cfg(test)]
+pub(crate) mod test_dir;
+/// Where each disk is plugged in, down to the enclosure slot.
+pub mod topology;
+/// Counting where the daemon's own time goes.
+pub mod usage;
+/// Reading a disk back to check that it's blank.
+pub mod verify;
@@ -0,0 +1,936 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use crate::{device_policy::DeviceIdentity, notifiers::notification, topology::DeviceLocation};
+
+// Growth is measured over at least this long, so one error in a short
+// check interval doesn't look like a storm.
+const RATE_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+// How long link errors count against a device's health.
+const HEALTH_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+// The sas_phy attributes that count errors on the link, as opposed to the
+// phy's settings.
+const SAS_COUNTERS: &[&str] = &[
+    "invalid_dword_count",
+    "running_disparity_error_count",
+    "loss_of_dword_sync_count",
+    "phy_reset_problem_count",
+];
+
+// Of what libata logs in a device's error ring, what points at the link or
+// the controller rather than the drive: CRC errors on the cable show up as
+// BusError, SError bits as HostBusError. Timeouts and media errors are
+// left out, they're more often the drive.
+const ATA_LINK_ERRORS: &[&str] = &["BusError", "HostBusError", "HostStateMachineError"];
+
+/// The kernel's error counters for the links a disk is on, as read at one
+/// time. Counters only mean anything against an earlier reading from the
+/// same boot, the kernel starts them over when it comes up.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LinkHealth {
+    /// Unix time, in seconds.
+    pub at: u64,
+    /// The kernel's boot_id, to tell whether the counters started over since.
+    pub boot_id: Option<String>,
+    /// Every link the disk is on, more than one for a multipath SAS disk.
+    pub ports: Vec<PortCounters>,
+}
+
+/// One link's counters.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PortCounters {
+    /// What the kernel calls the link in its log, `ata3`, `ata5.01` behind
+    /// a port multiplier, or the SAS phy, `phy-0:0:5`.
+    pub port: String,
+    /// What the port belongs to, the expander for a SAS phy behind one, the
+    /// controller otherwise.
+    pub upstream: String,
+    /// Every counter by the kernel's name for it.
+    pub counters: BTreeMap<String, u64>,
+    /// When the link errors still in libata's error ring happened, in
+    /// seconds since boot. The ring only holds the last few and is cleared
+    /// when libata slows the link down, so it's read by what's new rather
+    /// than by how much is in it.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ering: Vec<f64>,
+}
+
+/// A port's errors growing faster than link_health.max_errors_per_hour.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LinkErrorGrowth {
+    /// The port that grew, see PortCounters::port.
+    pub port: String,
+    /// What the port belongs to, see PortCounters::upstream.
+    pub upstream: String,
+    /// Where the disk is plugged in, from topology, e.g. `0:0:5:0,
+    /// expander-0:0 phy-0:0:5, enclosure 0:0:8:0 slot 5`.
+    pub location: String,
+    /// How many more errors there are.
+    pub errors: u64,
+    /// The same split by counter.
+    pub counters: BTreeMap<String, u64>,
+    /// How fast they grew, measured over an hour at least.
+    pub per_hour: f64,
+    /// Other devices whose links behind the same upstream also grew lately.
+    /// More than one points at the backplane, expander or cable they share
+    /// rather than at the drives.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub also_growing: Vec<String>,
+}
+
+impl LinkHealth {
+    /// None for a disk without any link counters, like NVMe, USB or virtio.
+    pub fn read(name: &str) -> Option<Self> {
+        let mut health = Self::from_sysfs(Path::new("/sys"), name)?;
+        health.boot_id = fs::read_to_string("/proc/sys/kernel/random/boot_id")
+            .ok()
+            .map(|id| id.trim().to_string());
+        Some(health)
+    }
+
+    /// Same as read, but with sysfs mounted at `sys`, and without the boot.
+    pub fn from_sysfs(sys: &Path, name: &str) -> Option<Self> {
+        let path = sys.join("class/block").join(name).canonicalize().ok()?;
+
+        // Walks the disk's path down from the root. A SATA disk has its ata
+        // port above the SCSI host. A SAS disk's end device sits under the
+        // port it's on, which holds the phys, under an expander if there is
+        // one. A SATA disk behind libsas has both.
+        let mut dir = PathBuf::new();
+        let mut controller = None;
+        let mut upstream = None;
+        let mut ata = None;
+        let mut sas_port = None;
+        let mut last_port = None;
+        let mut scsi = None;
+        for component in path.components() {
+            dir.push(component);
+            let component = component.as_os_str().to_string_lossy();
+
+            if is_pci_address(&component) {
+                let driver = fs::read_link(dir.join("driver"))
+                    .ok()
+                    .and_then(|driver| Some(driver.file_name()?.to_string_lossy().into_owned()));
+                controller = Some(match driver {
+                    Some(driver) => format!("{} ({})", component, driver),
+                    None => component.to_string(),
+                });
+            } else if let Some(number) = numbered(&component, "ata") {
+                ata = Some((number, dir.clone()));
+            } else if component.starts_with("expander-") {
+                upstream = Some(component.to_string());
+            } else if component.starts_with("port-") {
+                last_port = Some(dir.clone());
+            } else if component.starts_with("end_device-") {
+                sas_port = last_port.take();
+            } else if let Some(address) = scsi_address(&component) {
+                scsi = Some(address);
+            }
+        }
+
+        let controller = controller.unwrap_or_else(|| "-".to_string());
+        let mut ports = vec![];
+        if let (Some((number, ata_dir)), Some((_, channel, target))) = (ata, scsi) {
+            if let Some(port) = ata_counters(sys, number, &ata_dir, channel, target, &controller) {
+                ports.push(port);
+            }
+        }
+        if let Some(port_dir) = sas_port {
+            let upstream = upstream.unwrap_or_else(|| controller.clone());
+            ports.extend(sas_counters(sys, &port_dir, &upstream));
+        }
+
+        if ports.is_empty() {
+            return None;
+        }
+        Some(Self {
+            at: notification::now(),
+            boot_id: None,
+            ports,
+        })
+    }
+
+    /// Each port's errors since `earlier`, the ones that grew faster than
+    /// `max_per_hour`. Counters that went down were started over, by a
+    /// reboot or a controller reset, and count as none.
+    pub fn growth(&self, earlier: &LinkHealth, max_per_hour: f64) -> Vec<LinkErrorGrowth> {
+        if self.boot_id != earlier.boot_id {
+            return vec![];
+        }
+        let hours = self
+            .at
+            .saturating_sub(earlier.at)
+            .max(RATE_WINDOW.as_secs()) as f64
+            / 3600.0;
+
+        let mut growth = vec![];
+        for port in &self.ports {
+            let before = match earlier.ports.iter().find(|before| before.port == port.port) {
+                Some(before) => before,
+                None => continue,
+            };
+
+            let mut counters = BTreeMap::new();
+            for (name, &value) in &port.counters {
+                match before.counters.get(name) {
+                    Some(&was) if value > was => {
+                        counters.insert(name.clone(), value - was);
+                    }
+                    _ => {}
+                }
+            }
+            let last_error = before.ering.iter().copied().fold(f64::MIN, f64::max);
+            let new_errors = port.ering.iter().filter(|&&at| at > last_error).count() as u64;
+            if new_errors > 0 {
+                counters.insert("ering".to_string(), new_errors);
+            }
+
+            let errors: u64 = counters.values().sum();
+            let per_hour = errors as f64 / hours;
+            if errors > 0 && per_hour > max_per_hour {
+                growth.push(LinkErrorGrowth {
+                    port: port.port.clone(),
+                    upstream: port.upstream.clone(),
+                    location: String::new(),
+                    errors,
+                    counters,
+                    per_hour,
+                    also_growing: vec![],
+                });
+            }
+        }
+        growth
+    }
+}
+
+impl LinkErrorGrowth {
+    /// "12 link errors on phy-0:0:5 behind expander-0:0 (invalid_dword_count
+    /// 12), 12.0 an hour".
+    pub fn describe(&self) -> String {
+        format!(
+            "{} link error{} on {} behind {} ({}), {:.1} an hour",
+            self.errors,
+            if self.errors == 1 { "" } else { "s" },
+            self.port,
+            self.upstream,
+            self.counters
+                .iter()
+                .map(|(name, count)| format!("{} {}", name, count))
+                .collect::<Vec<_>>()
+                .join(", "),
+            self.per_hour
+        )
+    }
+
+    /// Whether to look at the drive or at what it's plugged into.
+    pub fn blame(&self) -> String {
+        if self.also_growing.is_empty() {
+            format!(
+                "Nothing else behind {} is getting them, the drive or its own slot and cable \
+                 are more likely than anything it shares with others.",
+                self.upstream
+            )
+        } else {
+            format!(
+                "{} behind {} too, look at what they share first: the backplane, expander or \
+                 cable.",
+                self.also_growing.join(", "),
+                self.upstream
+            )
+        }
+    }
+}
+
+// The ata device a SCSI disk is, without a port multiplier `devN.<target>`
+// on `linkN`, behind one `devN.<pmp port>.0` on `linkN.<pmp port>`, libata
+// puts the PMP port in the SCSI channel.
+fn ata_counters(
+    sys: &Path,
+    number: u32,
+    ata_dir: &Path,
+    channel: u32,
+    target: u32,
+    controller: &str,
+) -> Option<PortCounters> {
+    let pmp_link = format!("link{}.{}", number, channel);
+    let (link, device, port) = if ata_dir.join(&pmp_link).is_dir() {
+        (
+            pmp_link,
+            format!("dev{}.{}.0", number, channel),
+            format!("ata{}.{:02}", number, channel),
+        )
+    } else {
+        (
+            format!("link{}", number),
+            format!("dev{}.{}", number, target),
+            format!("ata{}", number),
+        )
+    };
+
+    // The ata_device class is linked from /sys/class everywhere it exists,
+    // kernels before 2.6.37 don't have it at all.
+    let attributes = [
+        sys.join("class/ata_device").join(&device),
+        ata_dir
+            .join(&link)
+            .join(&device)
+            .join("ata_device")
+            .join(&device),
+    ]
+    .into_iter()
+    .find(|dir| dir.is_dir())?;
+
+    let mut counters = BTreeMap::new();
+    // How many times libata slowed the link down over errors.
+    if let Some(value) = counter(&attributes.join("spdn_cnt")) {
+        counters.insert("spdn_cnt".to_string(), value);
+    }
+    let ering = fs::read_to_string(attributes.join("ering"))
+        .map(|ering| link_errors(&ering))
+        .unwrap_or_default();
+
+    Some(PortCounters {
+        port,
+        upstream: controller.to_string(),
+        counters,
+        ering,
+    })
+}
+
+// The phys of a SAS port, a wide port has more than one. Reading an
+// expander's phy counters has the HBA ask the expander, some drivers can't
+// and fail them, those are left out.
+fn sas_counters(sys: &Path, port_dir: &Path, upstream: &str) -> Vec<PortCounters> {
+    let mut phys = fs::read_dir(port_dir)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                .filter(|name| name.starts_with("phy-"))
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    phys.sort();
+
+    phys.into_iter()
+        .filter_map(|phy| {
+            let attributes = [
+                sys.join("class/sas_phy").join(&phy),
+                port_dir.join(&phy).join("sas_phy").join(&phy),
+            ]
+            .into_iter()
+            .find(|dir| dir.is_dir())?;
+
+            let counters = SAS_COUNTERS
+                .iter()
+                .filter_map(|&name| Some((name.to_string(), counter(&attributes.join(name))?)))
+                .collect::<BTreeMap<_, _>>();
+            if counters.is_empty() {
+                return None;
+            }
+            Some(PortCounters {
+                port: phy,
+                upstream: upstream.to_string(),
+                counters,
+                ering: vec![],
+            })
+        })
+        .collect()
+}
+
+// Decimal, or hex with 0x like the SCSI device counters.
+fn counter(path: &Path) -> Option<u64> {
+    let text = fs::read_to_string(path).ok()?;
+    let text = text.trim();
+    match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+// libata's error ring, a line per error like `[  812.345678901]BusError
+// Timeout `, the oldest first. Older kernels print microseconds.
+fn link_errors(ering: &str) -> Vec<f64> {
+    ering
+        .lines()
+        .filter_map(|line| {
+            let (at, names) = line.trim_start().strip_prefix('[')?.split_once(']')?;
+            names
+                .split_whitespace()
+                .any(|name| ATA_LINK_ERRORS.contains(&name))
+                .then_some(())?;
+            at.trim().parse().ok()
+        })
+        .collect()
+}
+
+// `0000:00:1f.2`, domain:bus:slot.function in hex.
+fn is_pci_address(name: &str) -> bool {
+    let fields = name.split([':', '.']).collect::<Vec<_>>();
+    fields.len() == 4
+        && fields
+            .iter()
+            .zip([4, 2, 2, 1])
+            .all(|(field, len)| field.len() == len && field.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+// `ata3` for prefix `ata`.
+fn numbered(name: &str, prefix: &str) -> Option<u32> {
+    let number = name.strip_prefix(prefix)?;
+    if number.is_empty() || !number.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    number.parse().ok()
+}
+
+// `2:0:1:0`, host:channel:target:lun, as (host, channel, target).
+fn scsi_address(name: &str) -> Option<(u32, u32, u32)> {
+    let fields = name
+        .split(':')
+        .map(|field| field.parse().ok())
+        .collect::<Option<Vec<u32>>>()?;
+    match fields.as_slice() {
+        [host, channel, target, _lun] => Some((*host, *channel, *target)),
+        _ => None,
+    }
+}
+
+/// Reads link counters on a blocking thread, sysfs reads of an expander's
+/// phys go out to the expander. Also keeps the reading each device's growth
+/// is measured from.
+pub struct LinkHealthReader {
+    sender: mpsc::Sender<(DeviceIdentity, LinkHealth)>,
+    receiver: mpsc::Receiver<(DeviceIdentity, LinkHealth)>,
+    baselines: HashMap<String, LinkHealth>,
+    // Recent growth by upstream, as (device, when).
+    recent: HashMap<String, Vec<(String, u64)>>,
+    // Each device's growth, as (when, how many errors).
+    reported: HashMap<String, Vec<(u64, u64)>>,
+}
+
+impl LinkHealthReader {
+    /// A reader with no baselines yet.
+    pub fn new() -> Self {
+        let (sender, receiver) = mpsc::channel(64);
+        Self {
+            sender,
+            receiver,
+            baselines: HashMap::new(),
+            recent: HashMap::new(),
+            reported: HashMap::new(),
+        }
+    }
+
+    /// Reads `identity`'s counters in the background.
+    pub fn read(&self, identity: &DeviceIdentity) {
+        let identity = identity.clone();
+        let sender = self.sender.clone();
+        tokio::task::spawn_blocking(move || {
+            let health = match LinkHealth::read(&identity.name) {
+                Some(health) => health,
+                None => return,
+            };
+            // Only fails once we're shutting down.
+            let _ = sender.blocking_send((identity, health));
+        });
+    }
+
+    /// The next device whose counters were read, in the order they finish.
+    pub async fn next(&mut self) -> Option<(DeviceIdentity, LinkHealth)> {
+        self.receiver.recv().await
+    }
+
+    /// Forgets the baseline and growth of a device that went away.
+    pub fn forget(&mut self, name: &str) {
+        self.baselines.remove(name);
+        self.reported.remove(name);
+    }
+
+    /// What grew too fast since the reading the device is measured from,
+    /// `stored` (the one the registry had) when there's none yet. That
+    /// reading moves up to `health` once it's RATE_WINDOW old, or once
+    /// growth was reported, so the same errors aren't reported twice.
+    pub fn check(
+        &mut self,
+        name: &str,
+        health: &LinkHealth,
+        stored: Option<LinkHealth>,
+        max_per_hour: f64,
+    ) -> Vec<LinkErrorGrowth> {
+        let baseline = self
+            .baselines
+            .entry(name.to_string())
+            .or_insert_with(|| stored.unwrap_or_else(|| health.clone()));
+        let mut growth = health.growth(baseline, max_per_hour);
+        if !growth.is_empty()
+            || baseline.boot_id != health.boot_id
+            || health.at.saturating_sub(baseline.at) >= RATE_WINDOW.as_secs()
+        {
+            *baseline = health.clone();
+        }
+        if growth.is_empty() {
+            return growth;
+        }
+
+        let location = DeviceLocation::lookup(name)
+            .map(|location| location.describe())
+            .unwrap_or_else(|| "-".to_string());
+        for port in &mut growth {
+            port.location = location.clone();
+            let recent = self.recent.entry(port.upstream.clone()).or_default();
+            recent.retain(|(device, at)| {
+                device != name && health.at.saturating_sub(*at) < RATE_WINDOW.as_secs()
+            });
+            port.also_growing = recent.iter().map(|(device, _)| device.clone()).collect();
+            recent.push((name.to_string(), health.at));
+        }
+
+        let reported = self.reported.entry(name.to_string()).or_default();
+        reported.retain(|(at, _)| health.at.saturating_sub(*at) < HEALTH_WINDOW.as_secs());
+        reported.push((
+            health.at,
+            growth.iter().map(|port| port.errors).sum::<u64>(),
+        ));
+        growth
+    }
+
+    /// The link errors reported for each device over the last day, since
+    /// the daemon started.
+    pub fn recent_errors(&self) -> HashMap<String, u64> {
+        let now = notification::now();
+        self.reported
+            .iter()
+            .map(|(name, reported)| {
+                let errors = reported
+                    .iter()
+                    .filter(|(at, _)| now.saturating_sub(*at) < HEALTH_WINDOW.as_secs())
+                    .map(|(_, errors)| errors)
+                    .sum();
+                (name.clone(), errors)
+            })
+            .filter(|(_, errors)| *errors > 0)
+            .collect()
+    }
+}
+
+impl Default for LinkHealthReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    // A sysfs tree with just what reading link counters looks at, laid out
+    // the way each driver has the kernel lay it out.
+    struct Sys(TestDir);
+
+    impl Sys {
+        fn new(name: &str) -> Self {
+            let root = TestDir::new(&format!("link-health-{}", name));
+            fs::create_dir_all(root.join("class/block")).unwrap();
+            Self(root)
+        }
+
+        fn dir(&self, path: &str) -> PathBuf {
+            let dir = self.0.join("devices").join(path);
+            fs::create_dir_all(&dir).unwrap();
+            dir
+        }
+
+        // A disk at devices/`path`, ending in its name.
+        fn block(&self, path: &str) {
+            let dir = self.dir(path);
+            symlink(
+                &dir,
+                self.0.join("class/block").join(dir.file_name().unwrap()),
+            )
+            .unwrap();
+        }
+
+        fn driver(&self, path: &str, driver: &str) {
+            let target = self.0.join("bus/pci/drivers").join(driver);
+            fs::create_dir_all(&target).unwrap();
+            symlink(target, self.dir(path).join("driver")).unwrap();
+        }
+
+        // The attributes at devices/`path`, linked from class/`class`
+        // unless that's None, like on kernels without the class.
+        fn attributes(&self, path: &str, class: Option<&str>, files: &[(&str, &str)]) {
+            let dir = self.dir(path);
+            for (file, contents) in files {
+                fs::write(dir.join(file), contents).unwrap();
+            }
+            if let Some(class) = class {
+                let link = self.0.join("class").join(class);
+                fs::create_dir_all(&link).unwrap();
+                symlink(&dir, link.join(dir.file_name().unwrap())).unwrap();
+            }
+        }
+
+        fn read(&self, name: &str) -> Option<LinkHealth> {
+            LinkHealth::from_sysfs(self.0.path(), name)
+        }
+    }
+
+    fn counters(counters: &[(&str, u64)]) -> BTreeMap<String, u64> {
+        counters
+            .iter()
+            .map(|(name, value)| (name.to_string(), *value))
+            .collect()
+    }
+
+    const ERING: &str = "[  812.345678901]BusError Timeout \n\
+                         [  900.100000]media error\n\
+                         [ 1012.5]HostBusError\n";
+
+    #[test]
+    fn a_sata_disk_on_ahci() {
+        let sys = Sys::new("ahci");
+        let ata = "pci0000:00/0000:00:17.0/ata3";
+        sys.driver("pci0000:00/0000:00:17.0", "ahci");
+        sys.block(&format!("{}/host2/target2:0:0/2:0:0:0/block/sda", ata));
+        sys.attributes(
+            &format!("{}/link3/dev3.0/ata_device/dev3.0", ata),
+            Some("ata_device"),
+            &[("spdn_cnt", "2\n"), ("ering", ERING)],
+        );
+
+        let health = sys.read("sda").unwrap();
+        assert_eq!(health.boot_id, None);
+        assert_eq!(
+            health.ports,
+            [PortCounters {
+                port: "ata3".to_string(),
+                upstream: "0000:00:17.0 (ahci)".to_string(),
+                counters: counters(&[("spdn_cnt", 2)]),
+                ering: vec![812.345678901, 1012.5],
+            }]
+        );
+    }
+
+    #[test]
+    fn a_sata_disk_behind_a_port_multiplier_on_an_older_kernel() {
+        let sys = Sys::new("libata-pmp");
+        // sata_sil24, no ata_device class, and the disk on the PMP's port 1.
+        let ata = "pci0000:00/0000:00:1c.0/0000:02:00.0/ata5";
+        sys.driver("pci0000:00/0000:00:1c.0/0000:02:00.0", "sata_sil24");
+        sys.block(&format!("{}/host4/target4:1:0/4:1:0:0/block/sdc", ata));
+        sys.attributes(
+            &format!("{}/link5.1/dev5.1.0/ata_device/dev5.1.0", ata),
+            None,
+            &[("spdn_cnt", "0\n")],
+        );
+        // Port 0's disk isn't the one asked about.
+        sys.attributes(
+            &format!("{}/link5.0/dev5.0.0/ata_device/dev5.0.0", ata),
+            None,
+            &[("spdn_cnt", "9\n")],
+        );
+
+        assert_eq!(
+            sys.read("sdc").unwrap().ports,
+            [PortCounters {
+                port: "ata5.01".to_string(),
+                upstream: "0000:02:00.0 (sata_sil24)".to_string(),
+                counters: counters(&[("spdn_cnt", 0)]),
+                ering: vec![],
+            }]
+        );
+    }
+
+    #[test]
+    fn sas_disks_on_mpt3sas() {
+        let sys = Sys::new("mpt3sas");
+        let hba = "pci0000:00/0000:00:01.0/0000:01:00.0";
+        sys.driver(hba, "mpt3sas");
+        let sas_phy = |port: &str, phy: &str, files: &[(&str, &str)]| {
+            sys.attributes(
+                &format!("{}/{}/sas_phy/{}", port, phy, phy),
+                Some("sas_phy"),
+                files,
+            );
+        };
+        let all = |value: &'static str| {
+            SAS_COUNTERS
+                .iter()
+                .map(|&name| (name, value))
+                .collect::<Vec<_>>()
+        };
+
+        // sdf behind the expander, on phy 5.
+        let expander = format!("{}/host0/port-0:0/expander-0:0", hba);
+        let port = format!("{}/port-0:0:5", expander);
+        sys.block(&format!(
+            "{}/end_device-0:0:5/target0:0:5/0:0:5:0/block/sdf",
+            port
+        ));
+        sas_phy(
+            &port,
+            "phy-0:0:5",
+            &[
+                ("invalid_dword_count", "12\n"),
+                ("running_disparity_error_count", "0x1f\n"),
+                ("loss_of_dword_sync_count", "3\n"),
+                ("phy_reset_problem_count", "0\n"),
+                ("negotiated_linkrate", "12.0 Gbit\n"),
+            ],
+        );
+        // The HBA's own wide port to the expander isn't sdf's.
+        sas_phy(&format!("{}/host0/port-0:0", hba), "phy-0:0", &all("99\n"));
+
+        assert_eq!(
+            sys.read("sdf").unwrap().ports,
+            [PortCounters {
+                port: "phy-0:0:5".to_string(),
+                upstream: "expander-0:0".to_string(),
+                counters: counters(&[
+                    ("invalid_dword_count", 12),
+                    ("loss_of_dword_sync_count", 3),
+                    ("phy_reset_problem_count", 0),
+                    ("running_disparity_error_count", 31),
+                ]),
+                ering: vec![],
+            }]
+        );
+
+        // sdg right on the HBA, on a wide port of two phys, plus one whose
+        // counters the driver can't read.
+        let port = format!("{}/host0/port-0:1", hba);
+        sys.block(&format!(
+            "{}/end_device-0:1/target0:0:1/0:0:1:0/block/sdg",
+            port
+        ));
+        sas_phy(&port, "phy-0:5", &all("1\n"));
+        sas_phy(&port, "phy-0:4", &all("0\n"));
+        sas_phy(&port, "phy-0:6", &[]);
+
+        let ports = sys.read("sdg").unwrap().ports;
+        assert_eq!(
+            ports
+                .iter()
+                .map(|port| (port.port.as_str(), port.upstream.as_str()))
+                .collect::<Vec<_>>(),
+            [
+                ("phy-0:4", "0000:01:00.0 (mpt3sas)"),
+                ("phy-0:5", "0000:01:00.0 (mpt3sas)"),
+            ]
+        );
+        assert_eq!(ports[1].counters.values().sum::<u64>(), 4);
+    }
+
+    #[test]
+    fn disks_without_link_counters() {
+        let sys = Sys::new("none");
+        sys.block("virtual/block/loop0");
+        sys.block("pci0000:00/0000:00:1d.0/nvme/nvme0/nvme0n1");
+        // An ata port whose device attributes aren't there.
+        sys.block("pci0000:00/0000:00:17.0/ata1/host0/target0:0:0/0:0:0:0/block/sda");
+        // An end device whose phys have no counters at all.
+        let port = "pci0000:00/0000:00:01.0/0000:01:00.0/host0/port-0:2";
+        sys.block(&format!(
+            "{}/end_device-0:2/target0:0:2/0:0:2:0/block/sdb",
+            port
+        ));
+        sys.attributes(&format!("{}/phy-0:2/sas_phy/phy-0:2", port), None, &[]);
+
+        for name in ["loop0", "nvme0n1", "sda", "sdb", "sdz"] {
+            assert_eq!(sys.read(name), None, "{}", name);
+        }
+    }
+
+    #[test]
+    fn malformed_empty_and_out_of_range_counters() {
+        let sys = Sys::new("counters");
+        let ata = "pci0000:00/0000:00:17.0/ata1";
+        let cases = [
+            ("7\n", Some(7)),
+            ("  42  \n", Some(42)),
+            ("0x1F\n", Some(31)),
+            ("0\n", Some(0)),
+            ("18446744073709551615\n", Some(u64::MAX)),
+            ("18446744073709551616\n", None),
+            ("0x10000000000000000\n", None),
+            ("-1\n", None),
+            ("", None),
+            ("\n", None),
+            ("0x", None),
+            ("twelve\n", None),
+            ("1 2\n", None),
+            ("1.5\n", None),
+        ];
+        for (n, (contents, expected)) in cases.iter().enumerate() {
+            let name = format!("sd{}", (b'a' + n as u8) as char);
+            sys.block(&format!(
+                "{}/host0/target0:0:{1}/0:0:{1}:0/block/{2}",
+                ata, n, name
+            ));
+            sys.attributes(
+                &format!("{0}/link1/dev1.{1}/ata_device/dev1.{1}", ata, n),
+                None,
+                &[("spdn_cnt", contents)],
+            );
+
+            // A counter that doesn't read is left out, the port isn't.
+            let health = sys.read(&name).unwrap();
+            assert_eq!(
+                health.ports[0].counters.get("spdn_cnt").copied(),
+                *expected,
+                "{:?}",
+                contents
+            );
+        }
+    }
+
+    #[test]
+    fn link_errors_from_the_error_ring() {
+        let cases = [
+            (ERING, vec![812.345678901, 1012.5]),
+            ("", vec![]),
+            ("\n\n", vec![]),
+            ("[  1.0]HostStateMachineError\n", vec![1.0]),
+            // Neither is the link's.
+            ("[  1.0]Timeout MediaError\n", vec![]),
+            // Nothing that can be read as a time.
+            ("BusError\n", vec![]),
+            ("[]BusError\n", vec![]),
+            ("[soon]BusError\n", vec![]),
+            ("[  2.0 BusError\n", vec![]),
+            // A line cut off, and one that isn't.
+            ("[  3.0]Bus\n[  4.0]BusError", vec![4.0]),
+        ];
+        for (ering, expected) in cases {
+            assert_eq!(link_errors(ering), expected, "{:?}", ering);
+        }
+    }
+
+    #[test]
+    fn the_names_in_a_path() {
+        for (name, expected) in [
+            ("0000:00:1f.2", true),
+            ("0000:0a:00.0", true),
+            ("pci0000:00", false),
+            ("0000:00:1f", false),
+            ("0000:00:1g.2", false),
+            ("000:00:1f.2", false),
+        ] {
+            assert_eq!(is_pci_address(name), expected, "{}", name);
+        }
+        for (name, expected) in [
+            ("ata3", Some(3)),
+            ("ata12", Some(12)),
+            ("ata", None),
+            ("ata3.01", None),
+            ("ata99999999999", None),
+            ("link3", None),
+        ] {
+            assert_eq!(numbered(name, "ata"), expected, "{}", name);
+        }
+        for (name, expected) in [
+            ("2:0:1:0", Some((2, 0, 1))),
+            ("2:0:1", None),
+            ("2:0:1:0:0", None),
+            ("2:x:1:0", None),
+            ("target2:0:1", None),
+        ] {
+            assert_eq!(scsi_address(name), expected, "{}", name);
+        }
+    }
+
+    fn reading(at: u64, boot: &str, values: &[(&str, u64)], ering: &[f64]) -> LinkHealth {
+        LinkHealth {
+            at,
+            boot_id: Some(boot.to_string()),
+            ports: vec![PortCounters {
+                port: "phy-0:0:5".to_string(),
+                upstream: "expander-0:0".to_string(),
+                counters: counters(values),
+                ering: ering.to_vec(),
+            }],
+        }
+    }
+
+    #[test]
+    fn growth_between_two_readings() {
+        let hour = 3600;
+        let earlier = reading(0, "a", &[("invalid_dword_count", 10)], &[5.0]);
+
+        let grown = reading(hour, "a", &[("invalid_dword_count", 22)], &[5.0, 9.0]);
+        let growth = grown.growth(&earlier, 10.0);
+        assert_eq!(growth.len(), 1);
+        assert_eq!(growth[0].errors, 13);
+        assert_eq!(
+            growth[0].counters,
+            counters(&[("ering", 1), ("invalid_dword_count", 12)])
+        );
+        assert_eq!(
+            growth[0].describe(),
+            "13 link errors on phy-0:0:5 behind expander-0:0 (ering 1, invalid_dword_count \
+             12), 13.0 an hour"
+        );
+        // Not over the limit.
+        assert!(grown.growth(&earlier, 13.0).is_empty());
+
+        // Measured over an hour at least, a burst in a minute isn't 60
+        // times worse.
+        let burst = reading(60, "a", &[("invalid_dword_count", 15)], &[5.0]);
+        assert_eq!(burst.growth(&earlier, 0.0)[0].per_hour, 5.0);
+        assert!(burst.growth(&earlier, 5.0).is_empty());
+
+        // Counters that went down were started over, and count as none.
+        let reset = reading(hour, "a", &[("invalid_dword_count", 2)], &[]);
+        assert!(reset.growth(&earlier, 0.0).is_empty());
+        // Nothing compares across a reboot.
+        let rebooted = reading(hour, "b", &[("invalid_dword_count", 500)], &[]);
+        assert!(rebooted.growth(&earlier, 0.0).is_empty());
+        // A port the earlier reading didn't have has nothing to grow from.
+        let mut other = grown.clone();
+        other.ports[0].port = "phy-0:0:6".to_string();
+        assert!(other.growth(&earlier, 0.0).is_empty());
+    }
+
+    #[test]
+    fn growth_is_blamed_on_what_the_devices_share() {
+        let mut reader = LinkHealthReader::new();
+        let hour = 3600;
+        let earlier = reading(0, "a", &[("invalid_dword_count", 0)], &[]);
+        let grown = |at, count| reading(at, "a", &[("invalid_dword_count", count)], &[]);
+
+        // The first reading is the baseline, from the registry if it had one.
+        assert!(reader
+            .check("sdf", &grown(hour, 50), Some(earlier.clone()), 10.0)
+            .first()
+            .is_some_and(|growth| growth.also_growing.is_empty()
+                && growth
+                    .blame()
+                    .starts_with("Nothing else behind expander-0:0")));
+        // The same errors aren't reported twice.
+        assert!(reader.check("sdf", &grown(hour, 50), None, 10.0).is_empty());
+
+        let growth = reader.check("sdg", &grown(hour, 50), Some(earlier), 10.0);
+        assert_eq!(growth[0].also_growing, ["sdf"]);
+        assert_eq!(
+            growth[0].blame(),
+            "sdf behind expander-0:0 too, look at what they share first: the backplane, \
+             expander or cable."
+        );
+        assert_eq!(growth[0].location, "-");
+
+        reader.forget("sdg");
+        assert!(!reader.recent_errors().contains_key("sdg"));
+    }
+}
@@ -0,0 +1,367 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use flate2::{write::GzEncoder, Compression};
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::config::LogFileConfig;
+
+// A log file that rotates itself once it grows past a size limit:
+// `hddmond.log` becomes `hddmond.log.1`, `.1` becomes `.2` and so on, and
+// whatever falls off the end of `keep` is deleted.
+//
+// Every event is written while holding the file's lock, and rotation only
+// happens between events, so lines from concurrent tasks never interleave
+// or get cut in half at the rotation boundary. Logging can't log its own
+// problems, so those go to stderr.
+#[derive(Clone)]
+pub struct LogFile {
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    config: LogFileConfig,
+    file: File,
+    size: u64,
+}
+
+impl LogFile {
+    pub fn open(config: LogFileConfig) -> io::Result<Self> {
+        let (file, size) = open(&config.path)?;
+        Ok(Self {
+            inner: Arc::new(Mutex::new(Inner { config, file, size })),
+        })
+    }
+
+    // Reopens the file at its path, for when something else (logrotate)
+    // moved it away.
+    pub fn reopen(&self) -> io::Result<()> {
+        let mut inner = self.lock();
+        let (file, size) = open(&inner.config.path)?;
+        inner.file = file;
+        inner.size = size;
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        // A panic while writing a line doesn't leave anything inconsistent
+        // behind, keep logging.
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+fn open(path: &Path) -> io::Result<(File, u64)> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+    Ok((file, size))
+}
+
+impl Inner {
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.config.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        if self.config.compress {
+            path.push(".gz");
+        }
+        PathBuf::from(path)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        if self.config.keep == 0 {
+            fs::remove_file(&self.config.path)?;
+        } else {
+            let oldest = self.rotated_path(self.config.keep);
+            if oldest.exists() {
+                fs::remove_file(&oldest)?;
+            }
+            for index in (1..self.config.keep).rev() {
+                let from = self.rotated_path(index);
+                if from.exists() {
+                    fs::rename(&from, self.rotated_path(index + 1))?;
+                }
+            }
+
+            if self.config.compress {
+                compress(&self.config.path, &self.rotated_path(1))?;
+                fs::remove_file(&self.config.path)?;
+            } else {
+                fs::rename(&self.config.path, self.rotated_path(1))?;
+            }
+        }
+
+        let (file, size) = open(&self.config.path)?;
+        self.file = file;
+        self.size = size;
+
+        Ok(())
+    }
+}
+
+// Compressing happens with the lock held, which holds up logging for a
+// moment on every rotation, but keeps a half written .gz from ever being
+// rotated itself.
+fn compress(from: &Path, to: &Path) -> io::Result<()> {
+    let mut encoder = GzEncoder::new(File::create(to)?, Compression::default());
+    io::copy(&mut File::open(from)?, &mut encoder)?;
+    encoder.finish()?.sync_all()
+}
+
+pub struct LogFileWriter<'a>(MutexGuard<'a, Inner>);
+
+impl Write for LogFileWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let inner = &mut self.0;
+
+        // An event is formatted in full before it's written, so each call
+        // here is one complete event. Never leave the file empty though,
+        // a single event bigger than the limit still has to go somewhere.
+        if inner.size > 0 && inner.size + buf.len() as u64 > inner.config.max_size_bytes() {
+            if let Err(e) = inner.rotate() {
+                eprintln!(
+                    "Failed to rotate {}, still writing to it: {}",
+                    inner.config.path.display(),
+                    e
+                );
+            }
+        }
+
+        inner.file.write_all(buf)?;
+        inner.size += buf.len() as u64;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.file.flush()
+    }
+}
+
+impl<'a> MakeWriter<'a> for LogFile {
+    type Writer = LogFileWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        LogFileWriter(self.lock())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Read, thread};
+
+    use flate2::read::GzDecoder;
+
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    // Every line is the same length, so a 1 MB file holds exactly this many.
+    const LINE: usize = 64;
+    const LINES_PER_FILE: usize = 1024 * 1024 / LINE;
+
+    struct Dir(TestDir);
+
+    impl Dir {
+        fn new(name: &str) -> Self {
+            Self(TestDir::new(&format!("log-{}", name)))
+        }
+
+        fn open(&self, keep: usize, compress: bool) -> LogFile {
+            LogFile::open(LogFileConfig {
+                path: self.0.join("hddmond.log"),
+                max_size_mb: 1,
+                keep,
+                compress,
+            })
+            .unwrap()
+        }
+
+        fn files(&self) -> Vec<String> {
+            let mut files = fs::read_dir(&self.0)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+                .collect::<Vec<_>>();
+            files.sort();
+            files
+        }
+
+        // The lines of every file, oldest first.
+        fn lines(&self) -> Vec<String> {
+            let mut files = self.files();
+            files.sort_by_key(|file| std::cmp::Reverse(rotation(file)));
+
+            let mut lines = vec![];
+            for file in files {
+                let mut text = String::new();
+                let mut reader = File::open(self.0.join(&file)).unwrap();
+                if file.ends_with(".gz") {
+                    GzDecoder::new(reader).read_to_string(&mut text).unwrap();
+                } else {
+                    reader.read_to_string(&mut text).unwrap();
+                }
+                lines.extend(text.lines().map(str::to_string));
+            }
+            lines
+        }
+    }
+
+    // 0 for hddmond.log, 1 for hddmond.log.1(.gz) and so on.
+    fn rotation(file: &str) -> usize {
+        file.trim_end_matches(".gz")
+            .rsplit_once(".log.")
+            .map_or(0, |(_, index)| index.parse().unwrap())
+    }
+
+    fn line(writer: &str, seq: usize) -> String {
+        let mut line = format!("{} {:08}", writer, seq);
+        line.push_str(&".".repeat(LINE - 1 - line.len()));
+        line
+    }
+
+    fn write(file: &LogFile, writer: &str, lines: std::ops::Range<usize>) {
+        for seq in lines {
+            let line = line(writer, seq) + "\n";
+            assert_eq!(file.make_writer().write(line.as_bytes()).unwrap(), LINE);
+        }
+    }
+
+    fn expected(writer: &str, lines: std::ops::Range<usize>) -> Vec<String> {
+        lines.map(|seq| line(writer, seq)).collect()
+    }
+
+    #[test]
+    fn rotates_at_the_size_limit_without_dropping_lines() {
+        let dir = Dir::new("rotate");
+        let file = dir.open(10, false);
+        let total = LINES_PER_FILE * 3 + 5;
+        write(&file, "main", 0..total);
+
+        assert_eq!(
+            dir.files(),
+            [
+                "hddmond.log",
+                "hddmond.log.1",
+                "hddmond.log.2",
+                "hddmond.log.3"
+            ]
+        );
+        for rotated in ["hddmond.log.1", "hddmond.log.2", "hddmond.log.3"] {
+            let size = fs::metadata(dir.0.join(rotated)).unwrap().len();
+            assert_eq!(size, 1024 * 1024, "{}", rotated);
+        }
+        assert_eq!(dir.lines(), expected("main", 0..total));
+    }
+
+    #[test]
+    fn keeps_only_the_newest_rotations() {
+        let dir = Dir::new("keep");
+        let file = dir.open(2, false);
+        let total = LINES_PER_FILE * 4 + 100;
+        write(&file, "main", 0..total);
+
+        assert_eq!(
+            dir.files(),
+            ["hddmond.log", "hddmond.log.1", "hddmond.log.2"]
+        );
+        // The oldest two files' worth are gone, the rest are all there.
+        assert_eq!(dir.lines(), expected("main", LINES_PER_FILE * 2..total));
+    }
+
+    #[test]
+    fn compressed_rotations() {
+        let dir = Dir::new("compress");
+        let file = dir.open(3, true);
+        let total = LINES_PER_FILE * 2 + 1;
+        write(&file, "main", 0..total);
+
+        assert_eq!(
+            dir.files(),
+            ["hddmond.log", "hddmond.log.1.gz", "hddmond.log.2.gz"]
+        );
+        assert!(fs::metadata(dir.0.join("hddmond.log.1.gz")).unwrap().len() < 1024 * 1024);
+        assert_eq!(dir.lines(), expected("main", 0..total));
+    }
+
+    #[test]
+    fn keeping_none_starts_the_file_over() {
+        let dir = Dir::new("keep-none");
+        let file = dir.open(0, false);
+        let total = LINES_PER_FILE * 2 + 7;
+        write(&file, "main", 0..total);
+
+        assert_eq!(dir.files(), ["hddmond.log"]);
+        assert_eq!(dir.lines(), expected("main", LINES_PER_FILE * 2..total));
+    }
+
+    #[test]
+    fn an_event_over_the_limit_still_gets_written() {
+        let dir = Dir::new("huge");
+        let file = dir.open(5, false);
+        write(&file, "main", 0..1);
+        let huge = "x".repeat(2 * 1024 * 1024) + "\n";
+        file.make_writer().write_all(huge.as_bytes()).unwrap();
+        write(&file, "main", 1..2);
+
+        // The huge event got a file of its own.
+        assert_eq!(
+            dir.files(),
+            ["hddmond.log", "hddmond.log.1", "hddmond.log.2"]
+        );
+        let lines = dir.lines();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], line("main", 0));
+        assert_eq!(lines[1].len(), 2 * 1024 * 1024);
+        assert_eq!(lines[2], line("main", 1));
+    }
+
+    #[test]
+    fn concurrent_writers_never_interleave() {
+        let dir = Dir::new("concurrent");
+        let file = dir.open(10, false);
+        let per_writer = LINES_PER_FILE / 2 + 3;
+        let writers = ["a", "b", "c", "d"];
+        thread::scope(|scope| {
+            for writer in writers {
+                let file = file.clone();
+                scope.spawn(move || write(&file, writer, 0..per_writer));
+            }
+        });
+
+        assert_eq!(dir.files().len(), 3);
+        let lines = dir.lines();
+        assert_eq!(lines.len(), writers.len() * per_writer);
+        // Each writer's lines are whole and in order, wherever the others'
+        // fell between them.
+        for writer in writers {
+            let own = lines
+                .iter()
+                .filter(|line| line.starts_with(&format!("{} ", writer)))
+                .cloned()
+                .collect::<Vec<_>>();
+            assert_eq!(own, expected(writer, 0..per_writer));
+        }
+    }
+
+    #[test]
+    fn reopen_after_logrotate_moved_the_file() {
+        let dir = Dir::new("reopen");
+        let file = dir.open(5, false);
+        write(&file, "main", 0..10);
+        fs::rename(dir.0.join("hddmond.log"), dir.0.join("moved.log")).unwrap();
+        file.reopen().unwrap();
+        write(&file, "main", 10..20);
+
+        assert_eq!(dir.files(), ["hddmond.log", "moved.log"]);
+        let current = fs::read_to_string(dir.0.join("hddmond.log")).unwrap();
+        assert_eq!(
+            current.lines().collect::<Vec<_>>(),
+            expected("main", 10..20)
+        );
+    }
+}
@@ -0,0 +1,280 @@
+use serde::Deserialize;
+use tracing::Subscriber;
+use tracing_log::AsLog;
+use tracing_subscriber::{
+    filter::LevelFilter,
+    fmt::{self, writer::BoxMakeWriter},
+    layer::SubscriberExt,
+    registry::LookupSpan,
+    reload,
+    util::SubscriberInitExt,
+    EnvFilter, Layer, Registry,
+};
+
+use crate::{config::LoggingConfig, error::LoggingError, log_file::LogFile};
+
+/// How log lines look.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// One human readable line per event.
+    Text,
+    /// One JSON object per line, with the fields of every enclosing span.
+    Json,
+}
+
+/// The installed subscriber. The format and output are fixed once logging
+/// starts, but the filter can be swapped out while the daemon runs.
+pub struct Logging {
+    filter: reload::Handle<EnvFilter, Registry>,
+    file: Option<LogFile>,
+}
+
+impl Logging {
+    /// Installs the global subscriber. Records from the `log` macros are
+    /// forwarded to it, and pick up whatever spans are entered at the time.
+    pub fn init(config: &LoggingConfig) -> Result<Self, LoggingError> {
+        let filter = parse_filter(&config.level)?;
+        let max_level = filter.max_level_hint();
+        let (filter, handle) = reload::Layer::new(filter);
+
+        let file = config
+            .file
+            .clone()
+            .map(LogFile::open)
+            .transpose()
+            .map_err(|error| LoggingError::File { error })?;
+        let writer = match &file {
+            Some(file) => BoxMakeWriter::new(file.clone()),
+            None => BoxMakeWriter::new(std::io::stderr),
+        };
+        let ansi = file.is_none();
+
+        tracing_subscriber::registry()
+            .with(filter)
+            .with(output(config.format, ansi, writer))
+            .try_init()
+            .map_err(|error| LoggingError::Subscriber {
+                error: Box::new(error),
+            })?;
+
+        set_log_max_level(max_level);
+
+        Ok(Self {
+            filter: handle,
+            file,
+        })
+    }
+
+    /// Reopens the log file, if there is one, after an external logrotate.
+    pub fn reopen(&self) -> Result<(), LoggingError> {
+        if let Some(file) = &self.file {
+            file.reopen()
+                .map_err(|error| LoggingError::File { error })?;
+        }
+        Ok(())
+    }
+
+    /// Swaps the level filter, like logging.level, for a reloaded config.
+    pub fn set_filter(&self, filter: &str) -> Result<(), LoggingError> {
+        let filter = parse_filter(filter)?;
+        let max_level = filter.max_level_hint();
+        self.filter
+            .reload(filter)
+            .map_err(|error| LoggingError::Subscriber {
+                error: Box::new(error),
+            })?;
+        set_log_max_level(max_level);
+        Ok(())
+    }
+}
+
+// Writes events out in `format`. Colors only make sense on a terminal.
+fn output<S>(
+    format: LogFormat,
+    ansi: bool,
+    writer: BoxMakeWriter,
+) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    match format {
+        LogFormat::Text => fmt::layer().with_ansi(ansi).with_writer(writer).boxed(),
+        LogFormat::Json => fmt::layer()
+            .json()
+            .with_span_list(true)
+            .with_writer(writer)
+            .boxed(),
+    }
+}
+
+/// Filters are comma separated directives like `info,hddmond::scanners=debug`.
+pub fn parse_filter(filter: &str) -> Result<EnvFilter, LoggingError> {
+    EnvFilter::builder()
+        .parse(filter)
+        .map_err(|error| LoggingError::Filter {
+            filter: filter.to_string(),
+            error: Box::new(error),
+        })
+}
+
+// The `log` crate has its own global level check that runs before anything
+// reaches the subscriber, keep it in line with the filter.
+fn set_log_max_level(level: Option<LevelFilter>) {
+    log::set_max_level(level.unwrap_or(LevelFilter::TRACE).as_log());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{self, Write},
+        sync::{Arc, Mutex},
+    };
+
+    use serde_json::{json, Value};
+    use tracing::{debug, info, info_span, warn};
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Everything logged while `log` runs, the way the daemon would write it.
+    fn capture(format: LogFormat, filter: &str, log: impl FnOnce()) -> String {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::registry()
+            .with(parse_filter(filter).unwrap())
+            .with(output(
+                format,
+                false,
+                BoxMakeWriter::new(move || writer.clone()),
+            ));
+        tracing::subscriber::with_default(subscriber, log);
+
+        let output = captured.0.lock().unwrap().clone();
+        String::from_utf8(output).unwrap()
+    }
+
+    fn json_lines(output: &str) -> Vec<Value> {
+        output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn json_lines_carry_the_span_fields() {
+        let output = capture(LogFormat::Json, "info", || {
+            let _device = info_span!("device", device = "sda").entered();
+            let _smartctl = info_span!("smartctl", command = "scan").entered();
+            info!(
+                target: "hddmond::scanners::smartctl_scanner",
+                serial = "WD-WCC7K1234567",
+                "Scanning for devices"
+            );
+        });
+
+        let lines = json_lines(&output);
+        assert_eq!(lines.len(), 1, "{}", output);
+        let line = &lines[0];
+        assert!(line["timestamp"].is_string());
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["target"], "hddmond::scanners::smartctl_scanner");
+        assert_eq!(
+            line["fields"],
+            json!({ "message": "Scanning for devices", "serial": "WD-WCC7K1234567" })
+        );
+        assert_eq!(
+            line["span"],
+            json!({ "name": "smartctl", "command": "scan" })
+        );
+        assert_eq!(
+            line["spans"],
+            json!([
+                { "name": "device", "device": "sda" },
+                { "name": "smartctl", "command": "scan" },
+            ])
+        );
+    }
+
+    #[test]
+    fn log_records_pick_up_the_spans_they_are_in() {
+        let _ = tracing_log::LogTracer::init();
+        log::set_max_level(log::LevelFilter::Trace);
+
+        let output = capture(LogFormat::Json, "info", || {
+            let _plugin = info_span!("plugin", plugin = "log_devices").entered();
+            let _hook = info_span!("hook", hook = "onDeviceFound").entered();
+            log::info!(target: "plugin::log_devices", "found sda");
+        });
+
+        let lines = json_lines(&output);
+        assert_eq!(lines.len(), 1, "{}", output);
+        assert_eq!(lines[0]["level"], "INFO");
+        assert_eq!(lines[0]["fields"]["message"], "found sda");
+        assert_eq!(lines[0]["fields"]["log.target"], "plugin::log_devices");
+        assert_eq!(
+            lines[0]["spans"],
+            json!([
+                { "name": "plugin", "plugin": "log_devices" },
+                { "name": "hook", "hook": "onDeviceFound" },
+            ])
+        );
+    }
+
+    #[test]
+    fn text_lines_name_the_spans_without_colors() {
+        let output = capture(LogFormat::Text, "info", || {
+            let _device = info_span!("device", device = "sda").entered();
+            warn!(target: "hddmond::power", "Standby timer rejected");
+        });
+
+        assert_eq!(output.lines().count(), 1, "{}", output);
+        assert!(output.contains(" WARN "), "{}", output);
+        assert!(
+            output.contains("device{device=\"sda\"}: hddmond::power: Standby timer rejected"),
+            "{}",
+            output
+        );
+        assert!(!output.contains('\x1b'), "{}", output);
+    }
+
+    #[test]
+    fn per_module_levels() {
+        let output = capture(LogFormat::Json, "warn,hddmond::scanners=debug", || {
+            debug!(target: "hddmond::scanners::udev_scanner", "udev debug");
+            debug!(target: "hddmond::notifiers::email", "email debug");
+            info!(target: "hddmond", "daemon info");
+            warn!(target: "hddmond::notifiers::email", "email warning");
+        });
+
+        let messages = json_lines(&output)
+            .into_iter()
+            .map(|line| line["fields"]["message"].as_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(messages, ["udev debug", "email warning"]);
+    }
+
+    #[test]
+    fn bad_filters_are_refused() {
+        assert!(parse_filter("info,hddmond::scanners=debug").is_ok());
+        let error = parse_filter("hddmond=loud").unwrap_err().to_string();
+        assert!(
+            error.starts_with("Invalid log filter \"hddmond=loud\""),
+            "{}",
+            error
+        );
+    }
+}
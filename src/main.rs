@@ -1,46 +1,1228 @@
-mod scanners;
+mod cli;
+#[cfg(test)]
+mod test_dir;
 
 #[macro_use]
 extern crate log;
 
-use anyhow::Error;
-use scanners::{
-    scanner::{DeviceMonitor, ScanEventType},
-    smartctl_scanner::SmartCtlMonitor,
-    udev_scanner::UdevMonitor,
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter},
+    path::{Path, PathBuf},
+    time::Duration,
 };
-use simple_logger::SimpleLogger;
-use tokio_stream::StreamExt;
 
-#[tokio::main]
-async fn main() -> Result<(), Error> {
+use anyhow::{bail, Context, Error};
+use clap::Parser;
+use cli::{AlertsCommand, Args, AuditCommand, Command};
+use hddmond::{
+    audit, blink,
+    block_io::{BlockIo, CacheBypass},
+    capabilities::DeviceCapabilities,
+    capacity::Capacity,
+    config::{self, Config},
+    control,
+    daemon::{self, InstanceLock},
+    device_health::{self, DeviceHealth},
+    device_policy::{self, DeviceIdentity},
+    disk_guard,
+    doctor::{self, Verdict},
+    error::ConfigError,
+    export::{self, ExportFormat},
+    identify_queue::{self, IdentifyStats},
+    image,
+    link_health::LinkHealth,
+    logging::Logging,
+    mmc::MmcHealth,
+    notifiers::email::Email,
+    pipeline::Pipeline,
+    plugins::plugin_ops::TYPE_DECLARATIONS,
+    power::Hdparm,
+    probe, smartd_import,
+    storage::{AlertState, Registry},
+    supervisor,
+    support_bundle::{self, BundleOptions},
+    topology::{self, DeviceLocation},
+    usage::{self, Usage},
+    verify,
+};
+use serde_json::json;
+use tokio::time::Instant;
+
+// How many of a device's sessions `hddmond show` lists.
+const SHOWN_SESSIONS: usize = 10;
+// How long `hddmond rescan` waits for the daemon's answer.
+const RESCAN_WAIT: Duration = Duration::from_secs(5 * 60);
+// How much longer than its shutdown timeout `--replace` waits for the
+// running daemon to exit.
+const REPLACE_MARGIN: Duration = Duration::from_secs(10);
+
+fn main() -> Result<(), Error> {
+    let mut args = Args::parse();
+
+    match args.command {
+        // `hddmond plugin-types > hddmond.d.ts` gives plugin authors the
+        // types of the plugin API.
+        Some(Command::PluginTypes) => {
+            print!("{}", TYPE_DECLARATIONS);
+            return Ok(());
+        }
+        // Straight from sysfs, no config involved.
+        Some(Command::Topology { json }) => return print_topology(json),
+        Some(Command::Probe { ref device }) => {
+            let contents = probe::probe_device(device_policy::device_name(device))
+                .with_context(|| format!("Can't probe {}", device))?;
+            println!("{}", serde_json::to_string_pretty(&contents)?);
+            return Ok(());
+        }
+        Some(Command::Blink {
+            ref device,
+            seconds,
+        }) => return blink_device(device, Duration::from_secs(seconds)),
+        Some(Command::Audit {
+            command: AuditCommand::Verify { ref file },
+        }) => return verify_audit(file),
+        Some(Command::VerifyBlank {
+            ref device,
+            sample,
+            from,
+            json,
+        }) => return verify_blank(device, sample, from, json),
+        Some(Command::Image {
+            ref device,
+            ref destination,
+            gzip,
+            json,
+        }) => return image_device(device, destination, gzip, json),
+        Some(Command::Locate {
+            ref device,
+            secs,
+            off,
+        }) => return locate(device, Duration::from_secs(secs), off),
+        Some(Command::ImportSmartd { ref file, ref out }) => {
+            return import_smartd(file, out.as_deref())
+        }
+        // Loads the config itself, a broken one is one of the things it
+        // reports.
+        Some(Command::Doctor { json }) => return doctor(&mut args, json),
+        Some(
+            Command::Devices { .. }
+            | Command::Show { .. }
+            | Command::Alias { .. }
+            | Command::Quarantine { .. }
+            | Command::Alerts { .. }
+            | Command::Export { .. }
+            | Command::SupportBundle { .. }
+            | Command::TestEmail
+            | Command::Status { .. }
+            | Command::Rescan
+            | Command::PowerState { .. },
+        )
+        | None => {}
+    }
+
+    // Logging is set up from the config, so a broken config can only be
+    // reported through main's error.
+    // Daemonizing moves us to /, so every path is pinned down relative to
+    // where we were started, now and on every reload.
+    let base_dir = std::env::current_dir()?;
+    config::make_absolute(&mut args.config, &base_dir);
+
+    let config_modified = config::modified(&args.config);
+    let config = load_config(&args, &base_dir)?;
+
+    match &args.command {
+        Some(Command::Devices { all }) => return print_devices(&config, *all),
+        Some(Command::Show { device, session }) => return show_device(&config, device, *session),
+        Some(Command::Alias {
+            device,
+            alias,
+            clear,
+        }) => return set_alias(&config, device, alias.as_deref(), *clear),
+        Some(Command::Quarantine {
+            device,
+            reason,
+            lift,
+        }) => return set_quarantine(&config, device, reason.as_deref(), *lift),
+        Some(Command::Alerts { command }) => return alerts(&config, command),
+        Some(Command::TestEmail) => return test_email(&config),
+        Some(Command::Status { prometheus }) => return print_status(&config, *prometheus),
+        Some(Command::Rescan) => return print_rescan(&config),
+        Some(Command::PowerState { device }) => return print_power_state(&config, device),
+        Some(Command::Export { format, out, all }) => {
+            return export_devices(&config, *format, out.as_deref(), *all)
+        }
+        Some(Command::SupportBundle {
+            since,
+            out,
+            lines,
+            max_size_mb,
+        }) => {
+            let options = BundleOptions {
+                since: *since,
+                lines: *lines,
+                max_bytes: max_size_mb.saturating_mul(1024 * 1024),
+            };
+            return support_bundle(&config, &args.config, out.as_deref(), &options);
+        }
+        _ => {}
+    }
+
+    // Before forking, so "already running" makes it to the terminal.
+    let instance = match InstanceLock::path(&config.storage) {
+        Some(path) => Some(match InstanceLock::try_lock(&path)? {
+            Some(lock) => lock,
+            None if args.replace => replace_running(&config, &path)?,
+            None => return Err(daemon::already_running(&path).into()),
+        }),
+        None => None,
+    };
+
+    let pidfile = if config.daemon.daemonize {
+        daemon::daemonize(&config.daemon)?
+    } else {
+        None
+    };
+    if let (Some(instance), true) = (&instance, config.daemon.daemonize) {
+        instance.write_pid()?;
+    }
+
+    let logging = Logging::init(&config.logging)?;
+    supervisor::install_panic_hook();
+    if let (Some(pidfile), Some(path)) = (&pidfile, &config.daemon.pidfile) {
+        if pidfile.replaced_stale() {
+            info!("Replaced a stale pidfile {}", path.display());
+        }
+    }
+
     info!("Starting...");
+    check_dependencies(&config)?;
+
+    if config_modified.is_none() {
+        info!(
+            "Config file {} doesn't exist, using defaults.",
+            args.config.display()
+        );
+    }
+
+    let config_path = args.config.clone();
+    let pipeline = Pipeline::new(cli::VERSION, config, config_path, move || {
+        load_config(&args, &base_dir)
+    })
+    .with_logging(logging);
+
+    // Only now, forking is off the table once there are runtime threads.
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(pipeline.run())?;
+    Ok(())
+}
+
+// `hddmond status`, the daemon's answer pretty printed.
+fn print_status(config: &Config, prometheus: bool) -> Result<(), Error> {
+    let path = match &config.daemon.control_socket {
+        Some(path) => path,
+        None => bail!("No daemon.control_socket configured"),
+    };
+
+    let response = control::query(path, "status")?;
+    let status: serde_json::Value =
+        serde_json::from_str(&response).context("The daemon's answer isn't JSON")?;
+    if let Some(error) = status.get("error").and_then(|error| error.as_str()) {
+        bail!("The daemon says: {}", error);
+    }
+
+    if prometheus {
+        let usage: Usage = serde_json::from_value(status["usage"].clone())
+            .context("The daemon's status doesn't say how much it's using")?;
+        let uptime_secs = status["uptime_secs"].as_u64().unwrap_or(0);
+        print!("{}", usage::prometheus(&usage, uptime_secs));
+        // Missing from daemons older than the identify queue.
+        if let Ok(identify) = serde_json::from_value::<IdentifyStats>(status["identify"].clone()) {
+            print!("{}", identify_queue::prometheus(&identify));
+        }
+        // And older than device health.
+        if let Ok(devices) = serde_json::from_value::<Vec<DeviceHealth>>(status["devices"].clone())
+        {
+            print!("{}", device_health::prometheus(&devices));
+        }
+        return Ok(());
+    }
+
+    println!("{}", serde_json::to_string_pretty(&status)?);
+    Ok(())
+}
+
+// `hddmond power-state sda`, through the same hdparm the power policies
+// use.
+fn print_power_state(config: &Config, device: &str) -> Result<(), Error> {
+    let node = Path::new("/dev").join(device_policy::device_name(device));
+    let state = Hdparm::new(config.power.hdparm.as_deref()).power_state(&node)?;
+    println!("{}", state);
+    Ok(())
+}
+
+// `hddmond rescan`, which devices the daemon newly found and lost.
+fn print_rescan(config: &Config) -> Result<(), Error> {
+    let path = match &config.daemon.control_socket {
+        Some(path) => path,
+        None => bail!("No daemon.control_socket configured"),
+    };
+
+    // Scanning SCSI hosts can take a good while.
+    let response = control::query_waiting(path, "rescan", RESCAN_WAIT)?;
+    let result: serde_json::Value =
+        serde_json::from_str(&response).context("The daemon's answer isn't JSON")?;
+    if let Some(error) = result.get("error").and_then(|error| error.as_str()) {
+        bail!("The daemon says: {}", error);
+    }
+
+    let names = |key: &str| {
+        result[key]
+            .as_array()
+            .map(|names| {
+                names
+                    .iter()
+                    .filter_map(|name| name.as_str())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default()
+    };
+    let (found, lost) = (names("found"), names("lost"));
+    if found.is_empty() && lost.is_empty() {
+        println!("Nothing came or went.");
+    }
+    if !found.is_empty() {
+        println!("Found {}", found.join(", "));
+    }
+    if !lost.is_empty() {
+        println!("Lost {}", lost.join(", "));
+    }
+    Ok(())
+}
+
+// For --replace: has the daemon holding the lock shut down, and takes the
+// lock once it has let go of it. Before logging is set up, so it talks on
+// stderr.
+fn replace_running(config: &Config, lock_path: &Path) -> Result<InstanceLock, Error> {
+    let socket = match &config.daemon.control_socket {
+        Some(path) => path,
+        None => bail!("--replace needs daemon.control_socket, to ask the running daemon to stop"),
+    };
+
+    eprintln!(
+        "Asking the running hddmond{} to shut down...",
+        InstanceLock::holder(lock_path)
+            .map(|pid| format!(" (pid {})", pid))
+            .unwrap_or_default()
+    );
+    let response = control::query(socket, "shutdown")?;
+    let answer: serde_json::Value =
+        serde_json::from_str(&response).context("The daemon's answer isn't JSON")?;
+    if let Some(error) = answer.get("error").and_then(|error| error.as_str()) {
+        bail!("The daemon says: {}", error);
+    }
+
+    // Going by our shutdown timeout, the running daemon's is usually from
+    // the same file.
+    let deadline = std::time::Instant::now() + config.daemon.shutdown_timeout() + REPLACE_MARGIN;
+    loop {
+        if let Some(lock) = InstanceLock::try_lock(lock_path)? {
+            return Ok(lock);
+        }
+        if std::time::Instant::now() >= deadline {
+            return Err(Error::new(daemon::already_running(lock_path))
+                .context("It didn't shut down in time"));
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+// `hddmond audit verify /var/log/hddmond/audit.jsonl`, exits with an error
+// at the first record that doesn't add up.
+fn verify_audit(file: &Path) -> Result<(), Error> {
+    let verified = audit::verify(file)?;
+    match (verified.first_seq, verified.last_seq) {
+        (Some(first), Some(last)) => println!(
+            "OK: records {} to {}, {} of {} chained",
+            first, last, verified.chained, verified.records
+        ),
+        _ => println!("OK: no records"),
+    }
+    if verified.partial_tail {
+        println!("The last record was cut short, the daemon drops it when it starts next.");
+    }
+    Ok(())
+}
+
+// `hddmond verify-blank sda`, reads the whole disk unless sampling. Takes
+// a path too, anything with a / in it is one.
+fn verify_blank(device: &str, sample: f64, from: u64, json: bool) -> Result<(), Error> {
+    let (path, sector_size) = disk_or_file(device);
+
+    let mut last_report = Instant::now();
+    let verification = verify::verify_blank(&path, sector_size, from, sample, |offset, size| {
+        if last_report.elapsed() >= Duration::from_secs(10) {
+            eprintln!(
+                "Checked up to {} of {} bytes ({:.1}%), --from {} picks up from here",
+                offset,
+                size,
+                offset as f64 * 100.0 / size as f64,
+                offset
+            );
+            last_report = Instant::now();
+        }
+    })
+    .with_context(|| format!("Can't read {}", path.display()))?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&verification)?);
+    } else {
+        match &verification.verdict {
+            verify::Verdict::Blank => println!(
+                "PASS: {} is blank, {} bytes checked",
+                path.display(),
+                verification.checked_bytes
+            ),
+            verify::Verdict::NotBlank { offset } => println!(
+                "FAIL: {} isn't blank, the first byte that isn't zero is at offset {}",
+                path.display(),
+                offset
+            ),
+            verify::Verdict::ReadError { lba, error } => println!(
+                "FAIL: Can't read LBA {} of {}: {}",
+                lba,
+                path.display(),
+                error
+            ),
+        }
+        if verification.cache_bypass != CacheBypass::Direct {
+            println!(
+                "O_DIRECT isn't supported for {}, it was read {}",
+                path.display(),
+                verification.cache_bypass.as_str()
+            );
+        }
+    }
+
+    if !verification.passed() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+// A disk by name, or anything with a / in it that isn't under /dev as a
+// file, and the sector size to read it in.
+fn disk_or_file(device: &str) -> (PathBuf, u64) {
+    if device.contains('/') && !device.starts_with("/dev/") {
+        (PathBuf::from(device), 512)
+    } else {
+        let name = device_policy::device_name(device);
+        let sector_size =
+            Capacity::lookup(name).map_or(512, |capacity| capacity.logical_block_size);
+        (Path::new("/dev").join(name), sector_size)
+    }
+}
+
+// `hddmond image sda /srv/images`, the image and its record are named
+// after the disk's serial and when imaging started.
+fn image_device(device: &str, destination: &Path, gzip: bool, json: bool) -> Result<(), Error> {
+    let (path, sector_size) = disk_or_file(device);
+    let is_disk = path.starts_with("/dev");
+    let identity = is_disk.then(|| DeviceIdentity::lookup(device_policy::device_name(device)));
+    let size = BlockIo::open(&path, sector_size)
+        .and_then(|io| io.size())
+        .with_context(|| format!("Can't read {}", path.display()))?;
+
+    let mut last_report = (Instant::now(), 0);
+    let started = Instant::now();
+    let progress = |offset: u64, size: u64| {
+        if last_report.0.elapsed() >= Duration::from_secs(10) {
+            eprintln!(
+                "Imaged {} of {} bytes ({:.1}%), {:.1} MB/s",
+                offset,
+                size,
+                offset as f64 * 100.0 / size as f64,
+                (offset - last_report.1) as f64 / last_report.0.elapsed().as_secs_f64() / 1e6
+            );
+            last_report = (Instant::now(), offset);
+        }
+    };
+
+    if destination == Path::new("-") {
+        let (mut record, _) = image::image(&path, sector_size, io::stdout().lock(), gzip, progress)
+            .with_context(|| format!("Imaging {} failed", path.display()))?;
+        record.serial = identity.and_then(|identity| identity.serial);
+        eprintln!("{}", serde_json::to_string_pretty(&record)?);
+        if !record.bad_ranges.is_empty() {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if !destination.is_dir() {
+        bail!("{} isn't a directory", destination.display());
+    }
+    let free = disk_guard::free_bytes(destination)
+        .with_context(|| format!("Can't tell how much room {} has", destination.display()))?;
+    // A compressed image can be as big as the disk too.
+    if free < size {
+        bail!(
+            "{} only has room for {} of the {} bytes on {}",
+            destination.display(),
+            free,
+            size,
+            path.display()
+        );
+    }
+    if is_disk {
+        let name = path
+            .canonicalize()
+            .ok()
+            .and_then(|node| {
+                node.file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+            })
+            .unwrap_or_else(|| device_policy::device_name(device).to_string());
+        let source = device_policy::block_device_disks(&name)
+            .with_context(|| format!("Can't tell which disk {} is", path.display()))?;
+        let target = device_policy::filesystem_disks(destination)
+            .with_context(|| format!("Can't tell which disk {} is on", destination.display()))?;
+        if let Some(disk) = source.intersection(&target).next() {
+            bail!(
+                "{} is on {}, the disk being imaged. Put the image somewhere else.",
+                destination.display(),
+                disk
+            );
+        }
+    }
+
+    let stem = identity
+        .as_ref()
+        .and_then(|identity| identity.serial.clone())
+        .or_else(|| {
+            path.file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+        })
+        .unwrap_or_else(|| "image".to_string())
+        .chars()
+        .map(|c| match c {
+            'A'..='Z' | 'a'..='z' | '0'..='9' | '.' | '-' | '_' => c,
+            _ => '_',
+        })
+        .collect::<String>();
+    let stem = format!("{}-{}", stem, chrono::Utc::now().format("%Y%m%d-%H%M%S"));
+    let image_path = destination.join(format!("{}.img{}", stem, if gzip { ".gz" } else { "" }));
+    let record_path = destination.join(format!("{}.json", stem));
+    // Only named what it is once it's all there.
+    let partial = image_path.with_extension(format!(
+        "{}.partial",
+        image_path.extension().unwrap_or_default().to_string_lossy()
+    ));
+
+    let file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&partial)
+        .with_context(|| format!("Can't create {}", partial.display()))?;
+    let (mut record, out) = image::image(&path, sector_size, BufWriter::new(file), gzip, progress)
+        .with_context(|| format!("Imaging {} to {} failed", path.display(), partial.display()))?;
+    out.into_inner()
+        .map_err(|e| e.into_error())
+        .and_then(|file| file.sync_all())
+        .and_then(|()| fs::rename(&partial, &image_path))
+        .with_context(|| format!("Can't finish {}", image_path.display()))?;
+    record.serial = identity.and_then(|identity| identity.serial);
+    record.image = Some(image_path.clone());
+    fs::write(&record_path, serde_json::to_vec_pretty(&record)?)
+        .with_context(|| format!("Can't write {}", record_path.display()))?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&record)?);
+    } else {
+        println!(
+            "Imaged {} to {} in {:.0}s, {:.1} MB/s",
+            path.display(),
+            image_path.display(),
+            record.duration_secs,
+            size as f64 / started.elapsed().as_secs_f64().max(f64::MIN_POSITIVE) / 1e6
+        );
+        println!("SHA-256: {}", record.sha256);
+        if record.bad_ranges.is_empty() {
+            println!("Every sector read fine.");
+        } else {
+            println!(
+                "{} bytes in {} ranges couldn't be read and are zeroes, see {}",
+                record.bad_bytes,
+                record.bad_ranges.len(),
+                record_path.display()
+            );
+        }
+        if record.cache_bypass != CacheBypass::Direct {
+            println!(
+                "O_DIRECT isn't supported for {}, it was read {}",
+                path.display(),
+                record.cache_bypass.as_str()
+            );
+        }
+    }
+
+    if !record.bad_ranges.is_empty() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+// `hddmond topology`, the disks grouped under the controller they're on.
+fn print_topology(json: bool) -> Result<(), Error> {
+    let disks = topology::disks(Path::new("/sys"));
+    if json {
+        println!("{}", serde_json::to_string_pretty(&disks)?);
+        return Ok(());
+    }
+
+    let mut controllers: BTreeMap<_, Vec<_>> = BTreeMap::new();
+    for disk in &disks {
+        controllers
+            .entry(disk.controller.as_ref())
+            .or_default()
+            .push(disk);
+    }
+
+    for (controller, disks) in controllers {
+        match controller {
+            Some(controller) => println!(
+                "{} ({})",
+                controller.pci_address,
+                controller.driver.as_deref().unwrap_or("no driver")
+            ),
+            None => println!("No PCI controller"),
+        }
+        for disk in disks {
+            println!("  {}\t{}", disk.device, disk.describe());
+        }
+    }
+
+    Ok(())
+}
 
-    SimpleLogger::new()
-        .with_level(log::LevelFilter::Trace)
-        .init()?;
+// `hddmond locate sda`, blinks the slot's LED for a while and turns it off
+// again, or just turns it off with --off.
+fn locate(device: &str, duration: Duration, off: bool) -> Result<(), Error> {
+    let name = device_policy::device_name(device);
+    let location = match DeviceLocation::lookup(name) {
+        Some(location) => location,
+        None => bail!("No disk {} in /sys/class/block", name),
+    };
+    let slot = match &location.enclosure {
+        Some(slot) => slot,
+        None => bail!(
+            "{} isn't in an enclosure hddmond can see, there's no LED to blink. \
+             `hddmond blink {}` blinks its activity LED instead.",
+            name,
+            name
+        ),
+    };
 
-    let monitor = UdevMonitor::new()?;
+    if off {
+        return Ok(slot.set_locate(false)?);
+    }
+
+    slot.set_locate(true)?;
+    println!(
+        "Blinking enclosure {} slot {} for {:?}, `hddmond locate {} --off` stops it",
+        slot.enclosure, slot.slot, duration, name
+    );
+    std::thread::sleep(duration);
+    Ok(slot.set_locate(false)?)
+}
+
+// `hddmond blink <serial>`, until the time is up or it's interrupted.
+fn blink_device(device: &str, duration: Duration) -> Result<(), Error> {
+    let identity = match DeviceIdentity::find(device) {
+        Some(identity) => identity,
+        None => bail!("No disk with name, serial or WWN {}", device),
+    };
+    let node = Path::new("/dev").join(&identity.name);
+
+    println!(
+        "Blinking the activity LED of {} ({}) for {:?}, Ctrl-C stops it",
+        identity.name,
+        identity.serial.as_deref().unwrap_or("no serial"),
+        duration
+    );
+    blink::blink(&node, duration).with_context(|| format!("Can't read {}", node.display()))
+}
+
+// `hddmond devices`, one tab separated line per device so it can be piped
+// into `column -t` or cut.
+fn print_devices(config: &Config, all: bool) -> Result<(), Error> {
+    let registry = Registry::open(&config.storage.path)?;
+
+    println!(
+        "NAME\tKERNEL NAME\tSERIAL\tMODEL\tWWN\tPRESENT\tFIRST SEEN\tLAST SEEN\tTIMES SEEN\t\
+         QUARANTINED"
+    );
+    for device in registry.devices(all)? {
+        println!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            device.display_name(),
+            device.name,
+            device.serial.as_deref().unwrap_or("-"),
+            device.model.as_deref().unwrap_or("-"),
+            device.wwn.as_deref().unwrap_or("-"),
+            if device.present { "yes" } else { "no" },
+            device.first_seen,
+            device.last_seen,
+            device.times_seen,
+            if device.quarantine.is_some() {
+                "yes"
+            } else {
+                "no"
+            }
+        );
+    }
+
+    Ok(())
+}
+
+// `hddmond quarantine <device> --reason ...`, or --lift. Through the daemon
+// so it leaves the device alone right away, and the audit trail has it.
+fn set_quarantine(
+    config: &Config,
+    key: &str,
+    reason: Option<&str>,
+    lift: bool,
+) -> Result<(), Error> {
+    if reason.is_none() && !lift {
+        let device = match Registry::open(&config.storage.path)?.device(key)? {
+            Some(device) => device,
+            None => bail!(
+                "No device with UUID, serial, WWN, name or alias {} in the registry",
+                key
+            ),
+        };
+        match &device.quarantine {
+            Some(reason) => println!(
+                "{} has been in quarantine since {}: {}",
+                device.display_name(),
+                device.quarantined_since.as_deref().unwrap_or("-"),
+                reason
+            ),
+            None => println!("{} isn't in quarantine", device.display_name()),
+        }
+        return Ok(());
+    }
+
+    let reason = reason.map(str::trim);
+    if reason == Some("") {
+        bail!("Say why it's in quarantine, or --lift it");
+    }
+    let path = match &config.daemon.control_socket {
+        Some(path) => path,
+        None => bail!("No daemon.control_socket configured"),
+    };
+    let command = format!("quarantine {}", json!({ "device": key, "reason": reason }));
+    let response = control::query(path, &command)?;
+    let result: serde_json::Value =
+        serde_json::from_str(&response).context("The daemon's answer isn't JSON")?;
+    if let Some(error) = result.get("error").and_then(|error| error.as_str()) {
+        bail!("The daemon says: {}", error);
+    }
+    let device = &result["device"];
+    let name = device["alias"]
+        .as_str()
+        .or_else(|| device["name"].as_str())
+        .unwrap_or(key);
+    match device["quarantine"].as_str() {
+        Some(reason) => println!(
+            "{} ({}) is in quarantine: {}",
+            name,
+            device["serial"].as_str().unwrap_or("no serial"),
+            reason
+        ),
+        None => println!("{} is out of quarantine", name),
+    }
+    Ok(())
+}
+
+// `hddmond alerts list` straight from the registry, `hddmond alerts ack`
+// through the daemon.
+fn alerts(config: &Config, command: &AlertsCommand) -> Result<(), Error> {
+    let (id, note) = match command {
+        AlertsCommand::List {
+            device,
+            state,
+            all,
+            json,
+        } => return print_alerts(config, device.as_deref(), *state, *all, *json),
+        AlertsCommand::Ack { id, note } => (*id, note.trim()),
+    };
+
+    if note.is_empty() {
+        bail!("Say what was done about it with --note");
+    }
+    let path = match &config.daemon.control_socket {
+        Some(path) => path,
+        None => bail!("No daemon.control_socket configured"),
+    };
+    let command = format!("alerts ack {}", json!({ "id": id, "note": note }));
+    let response = control::query(path, &command)?;
+    let result: serde_json::Value =
+        serde_json::from_str(&response).context("The daemon's answer isn't JSON")?;
+    if let Some(error) = result.get("error").and_then(|error| error.as_str()) {
+        bail!("The daemon says: {}", error);
+    }
+    let alert = &result["alert"];
+    println!(
+        "Alert {} for {} ({}) acknowledged, {} isn't notified again until it clears \
+         or the acknowledgement runs out",
+        id,
+        alert["alias"]
+            .as_str()
+            .or_else(|| alert["device"].as_str())
+            .unwrap_or("-"),
+        alert["serial"].as_str().unwrap_or("no serial"),
+        alert["reason"].as_str().unwrap_or("it"),
+    );
+    Ok(())
+}
+
+// One tab separated line per alert, like `hddmond devices`.
+fn print_alerts(
+    config: &Config,
+    device: Option<&str>,
+    state: Option<AlertState>,
+    all: bool,
+    json: bool,
+) -> Result<(), Error> {
+    let registry = Registry::open(&config.storage.path)?;
+    let device_id = match device {
+        Some(key) => match registry.device(key)? {
+            Some(device) => Some(device.id),
+            None => bail!(
+                "No device with UUID, serial, WWN, name or alias {} in the registry",
+                key
+            ),
+        },
+        None => None,
+    };
+    let alerts = registry
+        .alerts(device_id, state)?
+        .into_iter()
+        .filter(|alert| all || state.is_some() || alert.state() != AlertState::Cleared)
+        .collect::<Vec<_>>();
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&alerts)?);
+        return Ok(());
+    }
+    println!("ID\tDEVICE\tSERIAL\tREASON\tSTATE\tOPENED\tLAST RAISED\tTIMES\tNOTE");
+    for alert in alerts {
+        println!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            alert.id,
+            alert.alias.as_deref().unwrap_or(&alert.device),
+            alert.serial.as_deref().unwrap_or("-"),
+            alert.reason,
+            alert.state().as_str(),
+            alert.opened,
+            alert.last_raised,
+            alert.times,
+            alert.ack_note.as_deref().unwrap_or("-")
+        );
+    }
+    Ok(())
+}
 
-    info!("Created udev monitor.");
+// `hddmond alias <device> [alias]`. The registry is written directly, the
+// daemon picks the alias up the next time it notifies about the device.
+fn set_alias(config: &Config, key: &str, alias: Option<&str>, clear: bool) -> Result<(), Error> {
+    let mut registry = Registry::open(&config.storage.path)?;
+    if alias.is_none() && !clear {
+        let device = match registry.device(key)? {
+            Some(device) => device,
+            None => bail!(
+                "No device with UUID, serial, WWN, name or alias {} in the registry",
+                key
+            ),
+        };
+        match (&device.alias, device.alias_from_template) {
+            (Some(alias), true) => println!("{} (from devices.alias_template)", alias),
+            (Some(alias), false) => println!("{}", alias),
+            (None, _) => println!("{} has no alias", device.name),
+        }
+        return Ok(());
+    }
+
+    let alias = alias.map(str::trim);
+    if alias == Some("") {
+        bail!("An alias can't be empty, use --clear to take it away");
+    }
+    let device = registry.set_alias(key, alias)?;
+    match &device.alias {
+        Some(alias) => println!(
+            "{} ({}) is now called {}",
+            device.name,
+            device.serial.as_deref().unwrap_or("no serial"),
+            alias
+        ),
+        None => println!("{} has no alias anymore", device.name),
+    }
+    Ok(())
+}
 
-    let mut stream = monitor.watch_events()?;
+// `hddmond show <serial>`, one device and its events, oldest first.
+fn show_device(config: &Config, key: &str, session: Option<i64>) -> Result<(), Error> {
+    let registry = Registry::open(&config.storage.path)?;
+    let device = match registry.device(key)? {
+        Some(device) => device,
+        None => bail!(
+            "No device with UUID, serial, WWN, name or alias {} in the registry",
+            key
+        ),
+    };
 
-    while let Some(event) = stream.next().await {
-        match event {
-            ScanEventType::DeviceFound(device) => {
-                info!("Found device: {}", device);
+    println!(
+        "Name:       {}{}",
+        device.display_name(),
+        if device.present { "" } else { " (not present)" }
+    );
+    if device.alias.is_some() {
+        println!("Kernel:     {}", device.name);
+    }
+    if let Some(reason) = &device.quarantine {
+        println!(
+            "QUARANTINED since {}: {}",
+            device.quarantined_since.as_deref().unwrap_or("-"),
+            reason
+        );
+    }
+    println!(
+        "UUID:       {}{}",
+        device.uuid,
+        match device.identity_basis {
+            Some(basis) => format!(" (by {})", basis.as_str()),
+            None => String::new(),
+        }
+    );
+    println!("Serial:     {}", device.serial.as_deref().unwrap_or("-"));
+    println!("Model:      {}", device.model.as_deref().unwrap_or("-"));
+    println!("WWN:        {}", device.wwn.as_deref().unwrap_or("-"));
+    println!("Firmware:   {}", device.firmware.as_deref().unwrap_or("-"));
+    println!("First seen: {}", device.first_seen);
+    println!("Last seen:  {}", device.last_seen);
+    println!("Times seen: {}", device.times_seen);
+    match serde_json::from_value::<Option<Capacity>>(device.info["capacity"].clone()) {
+        Ok(Some(capacity)) => println!(
+            "Capacity:   {}{}",
+            capacity,
+            if device.capacity_mismatch {
+                " (smartctl disagrees)"
+            } else {
+                ""
             }
-            ScanEventType::DeviceLost(device) => {
-                info!("Lost device: {}", device);
+        ),
+        _ => println!("Capacity:   -"),
+    }
+    match serde_json::from_value::<Option<DeviceCapabilities>>(device.capabilities.clone()) {
+        Ok(Some(capabilities)) => {
+            println!("Write:      {}", capabilities.write);
+            println!("ATA erase:  {}", capabilities.ata_secure_erase);
+            println!("Discard:    {}", capabilities.discard);
+            println!("Zeroing:    {}", capabilities.write_zeroes);
+            println!("Self-test:  {}", capabilities.self_test);
+        }
+        _ => println!("Capabilities: not worked out yet"),
+    }
+    if let Ok(Some(health)) = serde_json::from_value::<Option<MmcHealth>>(device.mmc_health.clone())
+    {
+        println!("Health:     {}", health);
+    }
+    if let Ok(Some(health)) =
+        serde_json::from_value::<Option<LinkHealth>>(device.link_health.clone())
+    {
+        for port in &health.ports {
+            let mut counters = port
+                .counters
+                .iter()
+                .map(|(name, count)| format!("{} {}", name, count))
+                .collect::<Vec<_>>();
+            if !port.ering.is_empty() {
+                counters.push(format!("{} in the error ring", port.ering.len()));
             }
-            ScanEventType::Unknown(device) => {
-                info!("Unknown action for device: {}", device);
+            println!(
+                "Link:       {} behind {}: {}",
+                port.port,
+                port.upstream,
+                counters.join(", ")
+            );
+        }
+    }
+
+    if session.is_none() {
+        let sessions = registry.sessions(device.id)?;
+        if !sessions.is_empty() {
+            println!();
+            // The latest few, --session shows what happened in any of them.
+            for session in sessions
+                .iter()
+                .skip(sessions.len().saturating_sub(SHOWN_SESSIONS))
+            {
+                println!(
+                    "Session {}\t{} to {}, as {}{}",
+                    session.id,
+                    session.started,
+                    session.ended.as_deref().unwrap_or("now"),
+                    session.name,
+                    session
+                        .duration_secs
+                        .map(|secs| format!(" ({})", duration_text(secs)))
+                        .unwrap_or_default()
+                );
+            }
+        }
+    }
+
+    let events = registry.events(device.id, session)?;
+    if !events.is_empty() {
+        println!();
+        for event in events {
+            println!("{}\t{}", event.at, event.summary);
+        }
+    } else if let Some(session) = session {
+        println!();
+        println!("Nothing recorded in session {}", session);
+    }
+
+    Ok(())
+}
+
+// Like 3d 4h, 2h 5m or 40s, down to the two biggest units.
+fn duration_text(secs: i64) -> String {
+    let secs = secs.max(0);
+    let (days, hours, minutes) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60);
+    if days > 0 {
+        format!("{}d {}h", days, hours)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, minutes)
+    } else if minutes > 0 {
+        format!("{}m {}s", minutes, secs % 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+// `hddmond test-email`, goes through exactly what real notifications would,
+// minus the event filters and rate limits.
+fn test_email(config: &Config) -> Result<(), Error> {
+    if config.notifiers.emails.is_empty() {
+        bail!("No email notifiers configured in [[notifiers.emails]]");
+    }
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+
+    let mut failed = 0;
+    for email in &config.notifiers.emails {
+        let email = Email::new(email.clone())?;
+        match runtime.block_on(email.send_test()) {
+            Ok(()) => println!("Test email sent ({})", email.name()),
+            Err(e) => {
+                eprintln!("Test email failed ({}): {:#}", email.name(), e);
+                failed += 1;
             }
         }
     }
 
-    info!("Exiting...");
+    if failed > 0 {
+        bail!("{} of the email notifiers failed", failed);
+    }
+    Ok(())
+}
+
+fn export_devices(
+    config: &Config,
+    format: ExportFormat,
+    out: Option<&Path>,
+    all: bool,
+) -> Result<(), Error> {
+    let registry = Registry::open(&config.storage.path)?;
+    let devices = registry.devices(all)?;
+
+    match out {
+        Some(path) => {
+            let file =
+                File::create(path).with_context(|| format!("Can't create {}", path.display()))?;
+            export::export(
+                &mut BufWriter::new(file),
+                format,
+                &config.export.columns,
+                devices,
+            )?
+        }
+        None => export::export(
+            &mut io::stdout().lock(),
+            format,
+            &config.export.columns,
+            devices,
+        )?,
+    }
+    Ok(())
+}
+
+fn support_bundle(
+    config: &Config,
+    config_path: &Path,
+    out: Option<&Path>,
+    options: &BundleOptions,
+) -> Result<(), Error> {
+    let out = out.map(Path::to_path_buf).unwrap_or_else(|| {
+        PathBuf::from(format!(
+            "hddmond-support-{}.tar.gz",
+            chrono::Utc::now().format("%Y%m%d-%H%M%S")
+        ))
+    });
+    let file = File::create(&out).with_context(|| format!("Can't create {}", out.display()))?;
+    let manifest = support_bundle::write(
+        BufWriter::new(file),
+        cli::VERSION,
+        config,
+        config_path,
+        options,
+    )?;
+
+    for file in &manifest.files {
+        match file.truncated_bytes {
+            0 => println!("{}: {} bytes", file.name, file.bytes),
+            cut => println!(
+                "{}: {} bytes, {} cut off its start",
+                file.name, file.bytes, cut
+            ),
+        }
+    }
+    for missing in &manifest.missing {
+        println!("Not in it: {}", missing);
+    }
+    println!("Wrote {}", out.display());
+    Ok(())
+}
+
+// `hddmond doctor`, everything the daemon depends on checked, with what
+// to do about what's wrong. Exits non-zero if anything failed.
+fn doctor(args: &mut Args, json: bool) -> Result<(), Error> {
+    let base_dir = std::env::current_dir()?;
+    config::make_absolute(&mut args.config, &base_dir);
+    let exists = config::modified(&args.config).is_some();
+    let (config, problem) = match load_config(args, &base_dir) {
+        Ok(config) => (config, None),
+        Err(e) => {
+            let mut config = Config::default();
+            args.apply(&mut config);
+            (config, Some(format!("{:#}", e)))
+        }
+    };
+
+    let mut checks = vec![doctor::config(&args.config, exists, problem.as_deref())];
+    checks.extend(doctor::checks(&config, &doctor::System));
+    if json {
+        println!("{}", serde_json::to_string_pretty(&checks)?);
+    } else {
+        println!("CHECK\tRESULT\tDETAIL\tHINT");
+        for check in &checks {
+            println!(
+                "{}\t{}\t{}\t{}",
+                check.name,
+                check.verdict.as_str(),
+                check.detail,
+                check.hint.as_deref().unwrap_or("-")
+            );
+        }
+    }
 
+    if checks.iter().any(|check| check.verdict == Verdict::Fail) {
+        std::process::exit(1);
+    }
     Ok(())
 }
+
+fn import_smartd(file: &Path, out: Option<&Path>) -> Result<(), Error> {
+    let text =
+        fs::read_to_string(file).with_context(|| format!("Can't read {}", file.display()))?;
+    let entries =
+        smartd_import::parse(&text).with_context(|| format!("Can't parse {}", file.display()))?;
+    let import = smartd_import::convert(&entries);
+
+    // Anything that doesn't load is a bug here, not in the smartd.conf.
+    toml::from_str::<Config>(&import.fragment)
+        .map_err(Error::new)
+        .and_then(|config| config.validate().map_err(Error::new))
+        .context("The converted config doesn't load")?;
+
+    match out {
+        Some(out) => {
+            fs::write(out, &import.fragment)
+                .with_context(|| format!("Can't write {}", out.display()))?;
+            eprintln!("Wrote {}", out.display());
+        }
+        None => print!("{}", import.fragment),
+    }
+
+    for skipped in &import.skipped {
+        let at = match skipped.line {
+            0 => skipped.device.clone(),
+            line => format!("Line {}, {}", line, skipped.device),
+        };
+        let what = match skipped.directives.as_str() {
+            "" => String::new(),
+            directives => format!(" {}", directives),
+        };
+        let left_out = if skipped.not_needed {
+            "not needed"
+        } else {
+            "left out"
+        };
+        eprintln!("{}:{} {}, {}", at, what, left_out, skipped.reason);
+    }
+    Ok(())
+}
+
+// The checks `hddmond doctor` makes, logged. Only a failure, of something
+// that's on, keeps the daemon from starting.
+fn check_dependencies(config: &Config) -> Result<(), Error> {
+    let mut failed = vec![];
+    for check in doctor::checks(config, &doctor::System) {
+        let hint = check
+            .hint
+            .map(|hint| format!(". {}.", hint))
+            .unwrap_or_default();
+        match check.verdict {
+            Verdict::Pass => debug!("{}: {}", check.name, check.detail),
+            Verdict::Warn => warn!("{}: {}{}", check.name, check.detail, hint),
+            Verdict::Fail => {
+                error!("{}: {}{}", check.name, check.detail, hint);
+                failed.push(check.name);
+            }
+        }
+    }
+
+    if !failed.is_empty() {
+        bail!(
+            "Not starting, the {} check(s) failed. `hddmond doctor` lists them all.",
+            failed.join(", ")
+        );
+    }
+    Ok(())
+}
+
+// Loads the config file and lays the command line over it.
+fn load_config(args: &Args, base_dir: &Path) -> Result<Config, ConfigError> {
+    let mut config = Config::load(&args.config)?;
+    args.apply(&mut config);
+    config.make_paths_absolute(base_dir);
+    config.validate()?;
+    Ok(config)
+}
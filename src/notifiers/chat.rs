@@ -0,0 +1,520 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use reqwest::{header::CONTENT_TYPE, Client, RequestBuilder, Url};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{config::ChatConfig, error::NotifierError};
+
+use super::{
+    notification::{GroupMember, Notification, Severity},
+    template::Template,
+    webhook,
+};
+
+/// The chat service, and what its `url` is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatKind {
+    /// An incoming webhook URL.
+    Slack,
+    /// A channel webhook URL.
+    Discord,
+    /// The homeserver URL, plus a room and an access token.
+    Matrix,
+}
+
+impl ChatKind {
+    /// The name it goes by in the config.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChatKind::Slack => "slack",
+            ChatKind::Discord => "discord",
+            ChatKind::Matrix => "matrix",
+        }
+    }
+}
+
+/// Posts notifications to a chat room, formatted the way each service
+/// likes them. Everything but the formatting is shared with the other
+/// notifiers.
+pub struct Chat {
+    config: ChatConfig,
+    url: Url,
+    dashboard_url: Option<Template>,
+    client: Client,
+    // Matrix wants a unique transaction ID per message.
+    transactions: AtomicU64,
+}
+
+impl Chat {
+    /// Checks the URL and dashboard template, nothing is sent yet.
+    pub fn new(config: ChatConfig) -> Result<Self, NotifierError> {
+        let url = webhook::parse_url(&config.url)?;
+        let dashboard_url = config
+            .dashboard_url
+            .as_deref()
+            .map(Template::parse)
+            .transpose()?;
+        let client = Client::builder()
+            .timeout(Duration::from_millis(config.timeout_ms))
+            .build()
+            .map_err(|error| NotifierError::Setup {
+                target: name(config.kind, &url),
+                error: Box::new(error),
+            })?;
+
+        Ok(Self {
+            config,
+            url,
+            dashboard_url,
+            client,
+            transactions: AtomicU64::new(0),
+        })
+    }
+
+    /// What it goes by in logs and `hddmond status`, credentials left out.
+    pub fn name(&self) -> String {
+        name(self.config.kind, &self.url)
+    }
+
+    /// Posts a message about `notification`, once.
+    pub async fn send(&self, notification: &Notification) -> Result<(), NotifierError> {
+        let dashboard = self.dashboard_url.as_ref().map(|template| {
+            template.render_url(&serde_json::to_value(notification).unwrap_or_default())
+        });
+
+        let (request, payload) = match self.config.kind {
+            ChatKind::Slack => (
+                self.client.post(self.url.clone()),
+                slack_payload(notification, dashboard.as_deref()),
+            ),
+            ChatKind::Discord => (
+                self.client.post(self.url.clone()),
+                discord_payload(notification, dashboard.as_deref()),
+            ),
+            ChatKind::Matrix => (
+                self.matrix_request(notification)?,
+                matrix_payload(notification, dashboard.as_deref()),
+            ),
+        };
+
+        let status = request
+            .header(CONTENT_TYPE, "application/json")
+            .body(payload.to_string())
+            .send()
+            .await
+            .map_err(|error| NotifierError::Send {
+                target: self.name(),
+                error: Box::new(error),
+            })?
+            .status();
+        if !status.is_success() {
+            return Err(NotifierError::Rejected {
+                target: self.name(),
+                status,
+            });
+        }
+
+        Ok(())
+    }
+
+    fn matrix_request(&self, notification: &Notification) -> Result<RequestBuilder, NotifierError> {
+        let (room, token) = match (&self.config.room, &self.config.access_token) {
+            (Some(room), Some(token)) => (room, token),
+            _ => return Err(NotifierError::MatrixIncomplete),
+        };
+
+        let transaction = format!(
+            "hddmond-{}-{}",
+            notification.timestamp,
+            self.transactions.fetch_add(1, Ordering::Relaxed)
+        );
+        let mut url = self.url.clone();
+        url.path_segments_mut()
+            .map_err(|_| NotifierError::NotAHomeserver {
+                url: self.url.to_string(),
+            })?
+            .pop_if_empty()
+            .extend([
+                "_matrix",
+                "client",
+                "v3",
+                "rooms",
+                room.as_str(),
+                "send",
+                "m.room.message",
+                transaction.as_str(),
+            ]);
+
+        Ok(self.client.put(url).bearer_auth(token.expose()))
+    }
+}
+
+// Just the host, the rest of a Slack or Discord URL is a secret.
+fn name(kind: ChatKind, url: &Url) -> String {
+    format!("{} {}", kind.as_str(), url.host_str().unwrap_or_default())
+}
+
+// Slack and Discord both color the edge of the message.
+fn color(severity: Severity) -> u32 {
+    match severity {
+        Severity::Info => 0x2eb886,
+        Severity::Warning => 0xdaa038,
+        Severity::Critical => 0xa30200,
+    }
+}
+
+// (label, value) pairs describing the device, for the fields of a message.
+fn fields(notification: &Notification) -> Vec<(&'static str, String)> {
+    if !notification.group.is_empty() {
+        let devices = notification
+            .group
+            .iter()
+            .map(GroupMember::describe)
+            .collect::<Vec<_>>()
+            .join("\n");
+        return vec![("Devices", devices)];
+    }
+
+    let mut fields = match &notification.alias {
+        Some(alias) => vec![
+            ("Device", alias.clone()),
+            ("Name", notification.device.clone()),
+        ],
+        None => vec![("Device", notification.device.clone())],
+    };
+    for (label, value) in [
+        ("Serial", &notification.serial),
+        ("Model", &notification.model),
+        ("WWN", &notification.wwn),
+    ] {
+        if let Some(value) = value {
+            fields.push((label, value.clone()));
+        }
+    }
+    if let Some(change) = &notification.firmware {
+        fields.push(("Firmware", format!("{} to {}", change.from, change.to)));
+    }
+    if let Some(growth) = &notification.link_errors {
+        fields.push(("Link errors", growth.describe()));
+        fields.push(("Location", growth.location.clone()));
+        fields.push(("Likely", growth.blame()));
+    }
+    if let Some(alert) = notification.alert {
+        fields.push(("Alert", alert.to_string()));
+    }
+    fields
+}
+
+fn slack_payload(notification: &Notification, dashboard: Option<&str>) -> Value {
+    let summary = escape_slack(&notification.summary());
+
+    let mut blocks = vec![
+        json!({
+            "type": "section",
+            "text": { "type": "mrkdwn", "text": format!("*{}*", summary) },
+        }),
+        json!({
+            "type": "section",
+            "fields": fields(notification)
+                .into_iter()
+                .map(|(label, value)| json!({
+                    "type": "mrkdwn",
+                    "text": format!("*{}*\n{}", label, escape_slack(&value)),
+                }))
+                .collect::<Vec<_>>(),
+        }),
+    ];
+    if let Some(dashboard) = dashboard {
+        blocks.push(json!({
+            "type": "context",
+            "elements": [{ "type": "mrkdwn", "text": format!("<{}|Open in dashboard>", dashboard) }],
+        }));
+    }
+
+    json!({
+        // Shown in notifications, where blocks aren't.
+        "text": summary,
+        "attachments": [{
+            "color": format!("#{:06x}", color(notification.event.severity())),
+            "blocks": blocks,
+        }],
+    })
+}
+
+fn discord_payload(notification: &Notification, dashboard: Option<&str>) -> Value {
+    let mut embed = json!({
+        "title": notification.summary(),
+        "color": color(notification.event.severity()),
+        "fields": fields(notification)
+            .into_iter()
+            .map(|(label, value)| json!({ "name": label, "value": value, "inline": true }))
+            .collect::<Vec<_>>(),
+    });
+    if let Some(dashboard) = dashboard {
+        embed["url"] = dashboard.into();
+    }
+
+    json!({ "embeds": [embed] })
+}
+
+fn matrix_payload(notification: &Notification, dashboard: Option<&str>) -> Value {
+    let fields = fields(notification);
+
+    let mut body = notification.summary();
+    for (label, value) in &fields {
+        body.push_str(&format!("\n{}: {}", label, value));
+    }
+
+    let mut html = format!("<b>{}</b>", escape_html(&notification.summary()));
+    for (label, value) in &fields {
+        html.push_str(&format!(
+            "<br>{}: <code>{}</code>",
+            label,
+            escape_html(value)
+        ));
+    }
+
+    if let Some(dashboard) = dashboard {
+        body.push_str(&format!("\n{}", dashboard));
+        html.push_str(&format!(
+            "<br><a href=\"{}\">Open in dashboard</a>",
+            escape_html(dashboard)
+        ));
+    }
+
+    json!({
+        "msgtype": "m.text",
+        "body": body,
+        "format": "org.matrix.custom.html",
+        "formatted_body": html,
+    })
+}
+
+// Slack reads <...> as links and mentions in any text, these three are all
+// it wants escaped.
+fn escape_slack(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::link_health::LinkErrorGrowth;
+
+    use super::{
+        super::{notification::NotificationKind, test_server::Server},
+        *,
+    };
+
+    fn chat(kind: &str, url: &str, extra: &str) -> Chat {
+        let config = toml::from_str(&format!(
+            "kind = \"{}\"\nurl = \"{}\"\n{}",
+            kind, url, extra
+        ))
+        .unwrap();
+        Chat::new(config).unwrap()
+    }
+
+    // Everything a message can show.
+    fn link_errors() -> Notification {
+        Notification {
+            event: NotificationKind::LinkErrorsGrowing,
+            device: "sdh".to_string(),
+            alias: Some("bay-7".to_string()),
+            serial: Some("WD-WX12".to_string()),
+            model: Some("WDC WD40EFRX-68N32N0".to_string()),
+            wwn: Some("0x50014ee2b5b1c7a1".to_string()),
+            timestamp: 1_700_000_000,
+            firmware: None,
+            link_errors: Some(LinkErrorGrowth {
+                port: "phy-0:0:5".to_string(),
+                upstream: "expander-0:0".to_string(),
+                location: "0:0:5:0, expander-0:0 phy-0:0:5, enclosure 0:0:8:0 slot 5".to_string(),
+                errors: 12,
+                counters: BTreeMap::from([
+                    ("invalid_dword_count".to_string(), 9),
+                    ("running_disparity_error_count".to_string(), 3),
+                ]),
+                per_hour: 4.0,
+                also_growing: vec!["sdi".to_string()],
+            }),
+            alert: Some(12),
+            group: vec![],
+        }
+    }
+
+    fn digest() -> Notification {
+        let lost = |device: &str, serial: Option<&str>| Notification {
+            serial: serial.map(String::from),
+            timestamp: 1_700_000_000,
+            ..Notification::device_lost(device, None)
+        };
+        Notification::digest(vec![
+            lost("sda", Some("WD-WX12")),
+            Notification {
+                alias: Some("bay-<2>".to_string()),
+                ..lost("sdb", None)
+            },
+        ])
+    }
+
+    const DASHBOARD: &str = "https://grafana.example/d/drives?serial={{serial}}&name={{alias}}";
+
+    fn dashboard(notification: &Notification) -> String {
+        Template::parse(DASHBOARD)
+            .unwrap()
+            .render_url(&serde_json::to_value(notification).unwrap())
+    }
+
+    fn golden(fixture: &str) -> Value {
+        serde_json::from_str(fixture).unwrap()
+    }
+
+    #[test]
+    fn slack_golden() {
+        let link = link_errors();
+        assert_eq!(
+            slack_payload(&link, Some(&dashboard(&link))),
+            golden(include_str!("../../tests/fixtures/chat/slack.json"))
+        );
+        // Anything that looks like a link or mention is escaped.
+        assert_eq!(
+            slack_payload(&digest(), None),
+            golden(include_str!("../../tests/fixtures/chat/slack_digest.json"))
+        );
+    }
+
+    #[test]
+    fn discord_golden() {
+        let link = link_errors();
+        assert_eq!(
+            discord_payload(&link, Some(&dashboard(&link))),
+            golden(include_str!("../../tests/fixtures/chat/discord.json"))
+        );
+        assert_eq!(
+            discord_payload(&digest(), None),
+            golden(include_str!(
+                "../../tests/fixtures/chat/discord_digest.json"
+            ))
+        );
+    }
+
+    #[test]
+    fn matrix_golden() {
+        let link = link_errors();
+        assert_eq!(
+            matrix_payload(&link, Some(&dashboard(&link))),
+            golden(include_str!("../../tests/fixtures/chat/matrix.json"))
+        );
+        assert_eq!(
+            matrix_payload(&digest(), None),
+            golden(include_str!("../../tests/fixtures/chat/matrix_digest.json"))
+        );
+    }
+
+    #[test]
+    fn colors_follow_the_severity() {
+        let found = Notification::device_found(&Default::default());
+        assert_eq!(
+            slack_payload(&found, None)["attachments"][0]["color"],
+            "#2eb886"
+        );
+        assert_eq!(
+            discord_payload(&found, None)["embeds"][0]["color"],
+            0x2eb886
+        );
+        assert_eq!(
+            discord_payload(&Notification::device_lost("sda", None), None)["embeds"][0]["color"],
+            0xdaa038
+        );
+        assert_eq!(
+            discord_payload(&link_errors(), None)["embeds"][0]["color"],
+            0xa30200
+        );
+    }
+
+    #[tokio::test]
+    async fn slack_and_discord_post_to_the_webhook() {
+        let server = Server::start(&[200]).await;
+        for kind in ["slack", "discord"] {
+            let chat = chat(kind, &format!("{}/services/T0/B0/x", server.url), "");
+            chat.send(&link_errors()).await.unwrap();
+        }
+
+        let requests = server.wait_for(2).await;
+        for request in &requests {
+            assert_eq!(request.method, "POST");
+            assert_eq!(request.path, "/services/T0/B0/x");
+            assert_eq!(request.header("content-type"), Some("application/json"));
+        }
+        assert_eq!(requests[0].json(), slack_payload(&link_errors(), None));
+        assert_eq!(requests[1].json(), discord_payload(&link_errors(), None));
+    }
+
+    #[tokio::test]
+    async fn matrix_puts_each_message_in_its_own_transaction() {
+        let server = Server::start(&[200]).await;
+        let chat = chat(
+            "matrix",
+            &format!("{}/", server.url),
+            "room = \"!bench:example.org\"\naccess_token = \"syt_secret\"",
+        );
+
+        chat.send(&link_errors()).await.unwrap();
+        chat.send(&link_errors()).await.unwrap();
+
+        let requests = server.wait_for(2).await;
+        let paths = requests
+            .iter()
+            .map(|request| request.path.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            [
+                "/_matrix/client/v3/rooms/!bench:example.org/send/m.room.message/hddmond-1700000000-0",
+                "/_matrix/client/v3/rooms/!bench:example.org/send/m.room.message/hddmond-1700000000-1",
+            ]
+        );
+        assert_eq!(requests[0].method, "PUT");
+        assert_eq!(
+            requests[0].header("authorization"),
+            Some("Bearer syt_secret")
+        );
+        assert_eq!(requests[0].json(), matrix_payload(&link_errors(), None));
+    }
+
+    #[tokio::test]
+    async fn matrix_needs_a_room_and_a_token() {
+        let chat = chat(
+            "matrix",
+            "https://matrix.example.org",
+            "room = \"!bench:example.org\"",
+        );
+        let e = chat.send(&link_errors()).await.unwrap_err().to_string();
+        assert_eq!(e, "Matrix needs a room and an access token");
+    }
+
+    #[tokio::test]
+    async fn errors_from_the_service_fail_the_send() {
+        let server = Server::start(&[429]).await;
+        let chat = chat("slack", &server.url, "");
+        let e = chat.send(&digest()).await.unwrap_err().to_string();
+        assert!(e.ends_with("answered 429 Too Many Requests"), "{}", e);
+        assert_eq!(chat.name(), "slack 127.0.0.1");
+    }
+}
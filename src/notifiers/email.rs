@@ -0,0 +1,351 @@
+use std::time::Duration;
+
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use serde::Deserialize;
+
+use crate::{
+    config::EmailConfig,
+    error::{BoxError, NotifierError},
+};
+
+use super::notification::Notification;
+
+/// How the connection to the mail server is encrypted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EmailTls {
+    /// TLS from the start, usually port 465.
+    Tls,
+    /// Plain connection upgraded with STARTTLS, usually port 587. Fails if
+    /// the server doesn't offer it.
+    Starttls,
+    /// No encryption at all, only for a relay on the same machine.
+    None,
+}
+
+/// Sends plain text emails through an SMTP server, like smartd's -m.
+pub struct Email {
+    config: EmailConfig,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+    to: Vec<Mailbox>,
+}
+
+impl Email {
+    /// Checks the addresses and sets up the connection, nothing is sent yet.
+    pub fn new(config: EmailConfig) -> Result<Self, NotifierError> {
+        let (from, to) = parse_addresses(&config)?;
+
+        let setup = |error| NotifierError::Setup {
+            target: name(&config),
+            error: Box::new(error),
+        };
+        let mut transport = match config.tls {
+            EmailTls::Tls => {
+                AsyncSmtpTransport::<Tokio1Executor>::relay(&config.server).map_err(setup)?
+            }
+            EmailTls::Starttls => {
+                AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.server)
+                    .map_err(setup)?
+            }
+            EmailTls::None => {
+                AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(config.server.as_str())
+            }
+        }
+        .timeout(Some(Duration::from_millis(config.timeout_ms)));
+
+        if let Some(port) = config.port {
+            transport = transport.port(port);
+        }
+        if let Some(username) = &config.username {
+            let password = config
+                .password
+                .as_ref()
+                .map(|password| password.expose().to_string())
+                .unwrap_or_default();
+            transport = transport.credentials(Credentials::new(username.clone(), password));
+        }
+
+        Ok(Self {
+            transport: transport.build(),
+            config,
+            from,
+            to,
+        })
+    }
+
+    /// What it goes by in logs and `hddmond status`.
+    pub fn name(&self) -> String {
+        name(&self.config)
+    }
+
+    /// Sends an email about `notification`, once.
+    pub async fn send(&self, notification: &Notification) -> Result<(), NotifierError> {
+        let (subject, body) = describe(notification);
+        self.deliver(subject, body).await
+    }
+
+    /// For `hddmond test-email`.
+    pub async fn send_test(&self) -> Result<(), NotifierError> {
+        self.deliver(
+            "hddmond test email".to_string(),
+            "This is a test email from hddmond. If you can read it, email \
+             notifications work.\n"
+                .to_string(),
+        )
+        .await
+    }
+
+    async fn deliver(&self, subject: String, body: String) -> Result<(), NotifierError> {
+        let failed = |error: BoxError| NotifierError::Send {
+            target: self.name(),
+            error,
+        };
+
+        let mut message = Message::builder().from(self.from.clone()).subject(subject);
+        for to in &self.to {
+            message = message.to(to.clone());
+        }
+        let message = message
+            .header(ContentType::TEXT_PLAIN)
+            .body(body)
+            .map_err(|e| failed(Box::new(e)))?;
+
+        self.transport
+            .send(message)
+            .await
+            .map_err(|e| failed(Box::new(e)))?;
+        Ok(())
+    }
+}
+
+fn name(config: &EmailConfig) -> String {
+    format!("email via {}", config.server)
+}
+
+/// The From address and every To address, parsed.
+pub fn parse_addresses(config: &EmailConfig) -> Result<(Mailbox, Vec<Mailbox>), NotifierError> {
+    let parse = |field, address: &String| {
+        address.parse().map_err(|error| NotifierError::Address {
+            field,
+            address: address.clone(),
+            error,
+        })
+    };
+    let from = parse("from", &config.from)?;
+    let to = config
+        .to
+        .iter()
+        .map(|to| parse("to", to))
+        .collect::<Result<_, _>>()?;
+
+    Ok((from, to))
+}
+
+fn describe(notification: &Notification) -> (String, String) {
+    let subject = format!("hddmond: {}", notification.summary());
+
+    let unknown = |value: &Option<String>| value.clone().unwrap_or_else(|| "unknown".to_string());
+
+    if !notification.group.is_empty() {
+        let mut body = format!(
+            "{} devices were {}:\n\n",
+            notification.group.len(),
+            notification.event.verb()
+        );
+        for member in &notification.group {
+            let device = match &member.alias {
+                Some(alias) => format!("{} ({})", alias, member.device),
+                None => member.device.clone(),
+            };
+            body.push_str(&format!("{}  serial {}\n", device, unknown(&member.serial)));
+        }
+        return (subject, body);
+    }
+
+    let mut body = format!(
+        "Device {} was {}.\n\n\
+         Serial: {}\n\
+         Model:  {}\n\
+         WWN:    {}\n",
+        notification.describe(),
+        notification.event.verb(),
+        unknown(&notification.serial),
+        unknown(&notification.model),
+        unknown(&notification.wwn),
+    );
+    if let Some(change) = &notification.firmware {
+        body.push_str(&format!("Firmware: {} to {}\n", change.from, change.to));
+    }
+    if let Some(growth) = &notification.link_errors {
+        body.push_str(&format!("Links:  {}\n", growth.describe()));
+        body.push_str(&format!("Where:  {}\n", growth.location));
+        body.push_str(&format!("\n{}\n\n", growth.blame()));
+    }
+    if let Some(alert) = notification.alert {
+        body.push_str(&format!(
+            "Alert:  {}, `hddmond alerts ack {} --note ...` stops these\n",
+            alert, alert
+        ));
+    }
+    body.push_str(&format!("Time:   {} (unix)\n", notification.timestamp));
+
+    (subject, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::FirmwareChange;
+
+    use super::{
+        super::{notification::NotificationKind, test_server::Smtp},
+        *,
+    };
+
+    fn config(port: u16, extra: &str) -> EmailConfig {
+        toml::from_str(&format!(
+            "server = \"127.0.0.1\"\nport = {}\ntls = \"none\"\n\
+             from = \"hddmond <hddmond@bench.example>\"\n\
+             to = [\"ops@bench.example\", \"Bench Lead <lead@bench.example>\"]\n{}",
+            port, extra
+        ))
+        .unwrap()
+    }
+
+    fn lost() -> Notification {
+        Notification {
+            alias: Some("bay-7".to_string()),
+            serial: Some("WD-WX12".to_string()),
+            model: Some("WDC WD40EFRX".to_string()),
+            timestamp: 1_700_000_000,
+            ..Notification::device_lost("sda", None)
+        }
+    }
+
+    #[tokio::test]
+    async fn mails_the_notification() {
+        let smtp = Smtp::start(true).await;
+        let email = Email::new(config(smtp.port, "")).unwrap();
+
+        email.send(&lost()).await.unwrap();
+
+        let mail = &smtp.wait_for(1).await[0];
+        assert_eq!(mail.from, "hddmond@bench.example");
+        assert_eq!(mail.to, ["ops@bench.example", "lead@bench.example"]);
+        assert_eq!(
+            mail.header("Subject"),
+            Some("hddmond: Device bay-7 (sda, WD-WX12) lost")
+        );
+        assert_eq!(mail.header("From"), Some("hddmond <hddmond@bench.example>"));
+        assert_eq!(
+            mail.header("Content-Type"),
+            Some("text/plain; charset=utf-8")
+        );
+        assert_eq!(
+            mail.body(),
+            "Device bay-7 (sda, WD-WX12) was lost.\r\n\r\n\
+             Serial: WD-WX12\r\n\
+             Model:  WDC WD40EFRX\r\n\
+             WWN:    unknown\r\n\
+             Time:   1700000000 (unix)\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_emails_go_the_same_way() {
+        let smtp = Smtp::start(true).await;
+        let email = Email::new(config(smtp.port, "")).unwrap();
+
+        email.send_test().await.unwrap();
+
+        let mail = &smtp.wait_for(1).await[0];
+        assert_eq!(mail.to, ["ops@bench.example", "lead@bench.example"]);
+        assert_eq!(mail.header("Subject"), Some("hddmond test email"));
+        assert!(mail
+            .body()
+            .starts_with("This is a test email from hddmond."));
+    }
+
+    #[tokio::test]
+    async fn rejected_mail_fails_the_send() {
+        let smtp = Smtp::start(false).await;
+        let email = Email::new(config(smtp.port, "")).unwrap();
+
+        let e = format!("{:#}", email.send(&lost()).await.unwrap_err());
+        assert!(e.contains("No such user"), "{}", e);
+        assert!(smtp.mails().is_empty());
+    }
+
+    #[tokio::test]
+    async fn unreachable_servers_fail_the_send() {
+        // Nothing listens on the discard port.
+        let email = Email::new(config(9, "timeout_ms = 2000")).unwrap();
+        assert!(email.send_test().await.is_err());
+    }
+
+    #[test]
+    fn addresses_are_checked_up_front() {
+        let mut bad = config(25, "");
+        bad.to.push("not an address".to_string());
+        let e = Email::new(bad).err().unwrap();
+        assert!(matches!(
+            &e,
+            NotifierError::Address { field: "to", address, .. } if address == "not an address"
+        ));
+        assert!(e
+            .to_string()
+            .starts_with("Invalid to address not an address: "));
+
+        let mut bad = config(25, "");
+        bad.from = "hddmond@".to_string();
+        assert!(Email::new(bad).is_err());
+    }
+
+    #[test]
+    fn bodies_say_what_changed() {
+        let notification = Notification {
+            event: NotificationKind::FirmwareChanged,
+            firmware: Some(FirmwareChange {
+                from: "80.00A80".to_string(),
+                to: "82.00A82".to_string(),
+            }),
+            alert: Some(12),
+            ..lost()
+        };
+        let (subject, body) = describe(&notification);
+        assert_eq!(
+            subject,
+            "hddmond: Device bay-7 (sda, WD-WX12) given new firmware, 80.00A80 to 82.00A82"
+        );
+        assert_eq!(
+            body,
+            "Device bay-7 (sda, WD-WX12) was given new firmware.\n\n\
+             Serial: WD-WX12\n\
+             Model:  WDC WD40EFRX\n\
+             WWN:    unknown\n\
+             Firmware: 80.00A80 to 82.00A82\n\
+             Alert:  12, `hddmond alerts ack 12 --note ...` stops these\n\
+             Time:   1700000000 (unix)\n"
+        );
+    }
+
+    #[test]
+    fn digests_list_every_device() {
+        let digest = Notification::digest(vec![lost(), Notification::device_lost("sdb", None)]);
+        let (subject, body) = describe(&digest);
+        assert_eq!(
+            subject,
+            "hddmond: 2 devices lost: bay-7 (sda, WD-WX12), sdb"
+        );
+        assert_eq!(
+            body,
+            "2 devices were lost:\n\n\
+             bay-7 (sda)  serial WD-WX12\n\
+             sdb  serial unknown\n"
+        );
+    }
+}
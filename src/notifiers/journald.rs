@@ -0,0 +1,379 @@
+use std::{
+    io::Write,
+    os::unix::net::UnixDatagram,
+    path::{Path, PathBuf},
+    process,
+};
+
+use crate::error::NotifierError;
+
+use super::notification::{Notification, NotificationKind, Severity};
+
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+const SYSLOG_SOCKET: &str = "/dev/log";
+
+// syslog's LOG_DAEMON.
+const FACILITY_DAEMON: u8 = 3;
+
+// syslog priorities, which the journal uses too.
+const PRIORITY_CRIT: u8 = 2;
+const PRIORITY_WARNING: u8 = 4;
+const PRIORITY_INFO: u8 = 6;
+
+enum Sink {
+    // The native protocol: KEY=value fields, searchable with
+    // `journalctl MESSAGE_ID=...` or `journalctl HDDMOND_SERIAL=...`.
+    Journal,
+    // Plain syslog(3) style lines, for systems without systemd. Only the
+    // message and priority survive.
+    Syslog,
+}
+
+impl Sink {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Sink::Journal => "journald",
+            Sink::Syslog => "syslog",
+        }
+    }
+}
+
+/// Writes notifications to the journal as structured entries, separate from
+/// the ordinary log lines, so whatever watches the journal can pick them out
+/// by MESSAGE_ID.
+pub struct Journald {
+    socket: UnixDatagram,
+    sink: Sink,
+    // Where the sink's socket is.
+    path: PathBuf,
+}
+
+impl Journald {
+    /// The journal if it's there, syslog otherwise.
+    pub fn new() -> Result<Self, NotifierError> {
+        if Path::new(JOURNAL_SOCKET).exists() {
+            Self::with_socket(Sink::Journal, JOURNAL_SOCKET)
+        } else if Path::new(SYSLOG_SOCKET).exists() {
+            Self::with_socket(Sink::Syslog, SYSLOG_SOCKET)
+        } else {
+            Err(NotifierError::NoJournal {
+                journal: JOURNAL_SOCKET,
+                syslog: SYSLOG_SOCKET,
+            })
+        }
+    }
+
+    fn with_socket(sink: Sink, path: impl Into<PathBuf>) -> Result<Self, NotifierError> {
+        let socket = UnixDatagram::unbound().map_err(|error| NotifierError::Setup {
+            target: sink.as_str().to_string(),
+            error: Box::new(error),
+        })?;
+        Ok(Self {
+            socket,
+            sink,
+            path: path.into(),
+        })
+    }
+
+    /// "journald" or "syslog", whichever it's sending to.
+    pub fn name(&self) -> String {
+        self.sink.as_str().to_string()
+    }
+
+    /// Logs `notification`, once.
+    pub fn send(&self, notification: &Notification) -> Result<(), NotifierError> {
+        let sent = match self.sink {
+            Sink::Journal => self
+                .socket
+                .send_to(&journal_entry(notification), &self.path),
+            Sink::Syslog => {
+                let line = format!(
+                    "<{}>hddmond[{}]: {}",
+                    FACILITY_DAEMON * 8 + priority(notification),
+                    process::id(),
+                    notification.summary()
+                );
+                self.socket.send_to(line.as_bytes(), &self.path)
+            }
+        };
+
+        sent.map(|_| ()).map_err(|error| NotifierError::Send {
+            target: self.name(),
+            error: Box::new(error),
+        })
+    }
+}
+
+// Stable IDs for each kind of entry, so they can be searched for across
+// versions. Never change one.
+fn message_id(event: NotificationKind) -> &'static str {
+    match event {
+        NotificationKind::DeviceFound => "3c1f5e0a9d8b4b2e8f6a7c4d2e1b0a91",
+        NotificationKind::DeviceLost => "7a2d4c6e8f0b4d1a9c3e5f7a9b1d3f52",
+        NotificationKind::FirmwareChanged => "b84e1d2f6c0a4e7b9d3f8a5c2e6b1d07",
+        NotificationKind::DeviceBecameReadOnly => "e5a93c7d1f2b4806a4c8d0e6b3f9a215",
+        NotificationKind::LinkErrorsGrowing => "2f6d8b0e4a1c4f93b7e5d9a3c1f8e640",
+    }
+}
+
+fn priority(notification: &Notification) -> u8 {
+    match notification.event.severity() {
+        Severity::Info => PRIORITY_INFO,
+        Severity::Warning => PRIORITY_WARNING,
+        Severity::Critical => PRIORITY_CRIT,
+    }
+}
+
+fn journal_entry(notification: &Notification) -> Vec<u8> {
+    let mut entry = vec![];
+
+    let mut fields = vec![
+        ("MESSAGE", notification.summary()),
+        ("MESSAGE_ID", message_id(notification.event).to_string()),
+        ("PRIORITY", priority(notification).to_string()),
+        ("SYSLOG_IDENTIFIER", "hddmond".to_string()),
+        ("HDDMOND_EVENT", notification.event.as_str().to_string()),
+        ("HDDMOND_DEVICE", notification.device.clone()),
+    ];
+    if !notification.group.is_empty() {
+        fields.push(("HDDMOND_COUNT", notification.group.len().to_string()));
+    }
+    for (key, value) in [
+        ("HDDMOND_ALIAS", &notification.alias),
+        ("HDDMOND_SERIAL", &notification.serial),
+        ("HDDMOND_MODEL", &notification.model),
+        ("HDDMOND_WWN", &notification.wwn),
+    ] {
+        if let Some(value) = value {
+            fields.push((key, value.clone()));
+        }
+    }
+    if let Some(change) = &notification.firmware {
+        fields.push(("HDDMOND_FIRMWARE_FROM", change.from.clone()));
+        fields.push(("HDDMOND_FIRMWARE_TO", change.to.clone()));
+    }
+    if let Some(growth) = &notification.link_errors {
+        fields.push(("HDDMOND_LINK_PORT", growth.port.clone()));
+        fields.push(("HDDMOND_LINK_UPSTREAM", growth.upstream.clone()));
+        fields.push(("HDDMOND_LINK_ERRORS", growth.errors.to_string()));
+    }
+    if let Some(alert) = notification.alert {
+        fields.push(("HDDMOND_ALERT", alert.to_string()));
+    }
+
+    for (key, value) in fields {
+        write_field(&mut entry, key, &value);
+    }
+
+    entry
+}
+
+// Values with a newline in them need the binary form: the key, a newline,
+// the value's length as a little endian u64, then the value.
+fn write_field(entry: &mut Vec<u8>, key: &str, value: &str) {
+    if value.contains('\n') {
+        entry.extend_from_slice(key.as_bytes());
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        entry.extend_from_slice(value.as_bytes());
+        entry.push(b'\n');
+    } else {
+        // Writing to a Vec can't fail.
+        let _ = writeln!(entry, "{}={}", key, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{link_health::LinkErrorGrowth, storage::FirmwareChange, test_dir::TestDir};
+
+    use super::*;
+
+    // Stands in for journald or syslogd, removed when dropped.
+    struct Listener {
+        path: PathBuf,
+        socket: UnixDatagram,
+        _dir: TestDir,
+    }
+
+    impl Listener {
+        fn new(name: &str) -> Self {
+            let dir = TestDir::new(name);
+            let path = dir.join("socket");
+            let socket = UnixDatagram::bind(&path).unwrap();
+            Self {
+                path,
+                socket,
+                _dir: dir,
+            }
+        }
+
+        fn receive(&self) -> Vec<u8> {
+            let mut buffer = vec![0; 65536];
+            let read = self.socket.recv(&mut buffer).unwrap();
+            buffer.truncate(read);
+            buffer
+        }
+    }
+
+    // Only undoes the plain KEY=value form.
+    fn parse(entry: &[u8]) -> Vec<(String, String)> {
+        String::from_utf8(entry.to_vec())
+            .unwrap()
+            .lines()
+            .map(|line| {
+                let (key, value) = line.split_once('=').unwrap();
+                (key.to_string(), value.to_string())
+            })
+            .collect()
+    }
+
+    fn lost() -> Notification {
+        Notification {
+            serial: Some("WD-WX12".to_string()),
+            ..Notification::device_lost("sda", None)
+        }
+    }
+
+    #[test]
+    fn entries_carry_the_notification_as_fields() {
+        let listener = Listener::new("journal");
+        let journald = Journald::with_socket(Sink::Journal, &listener.path).unwrap();
+        assert_eq!(journald.name(), "journald");
+
+        journald.send(&lost()).unwrap();
+
+        assert_eq!(
+            parse(&listener.receive()),
+            [
+                ("MESSAGE", "Device sda (WD-WX12) lost"),
+                ("MESSAGE_ID", "7a2d4c6e8f0b4d1a9c3e5f7a9b1d3f52"),
+                ("PRIORITY", "4"),
+                ("SYSLOG_IDENTIFIER", "hddmond"),
+                ("HDDMOND_EVENT", "device_lost"),
+                ("HDDMOND_DEVICE", "sda"),
+                ("HDDMOND_SERIAL", "WD-WX12"),
+            ]
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+        );
+    }
+
+    #[test]
+    fn every_detail_gets_a_field() {
+        let firmware = Notification {
+            event: NotificationKind::FirmwareChanged,
+            alias: Some("bay-7".to_string()),
+            model: Some("WDC WD40EFRX".to_string()),
+            wwn: Some("0x50014ee2b5b1c7a1".to_string()),
+            firmware: Some(FirmwareChange {
+                from: "80.00A80".to_string(),
+                to: "82.00A82".to_string(),
+            }),
+            ..lost()
+        };
+        let fields = parse(&journal_entry(&firmware));
+        let keys = fields
+            .iter()
+            .map(|(key, _)| key.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            keys[6..],
+            [
+                "HDDMOND_ALIAS",
+                "HDDMOND_SERIAL",
+                "HDDMOND_MODEL",
+                "HDDMOND_WWN",
+                "HDDMOND_FIRMWARE_FROM",
+                "HDDMOND_FIRMWARE_TO",
+            ]
+        );
+        assert_eq!(fields[2].1, "6");
+
+        let link = Notification {
+            event: NotificationKind::LinkErrorsGrowing,
+            link_errors: Some(LinkErrorGrowth {
+                port: "phy-0:0:5".to_string(),
+                upstream: "expander-0:0".to_string(),
+                location: String::new(),
+                errors: 12,
+                counters: Default::default(),
+                per_hour: 1.0,
+                also_growing: vec![],
+            }),
+            alert: Some(3),
+            ..lost()
+        };
+        let fields = parse(&journal_entry(&link));
+        assert_eq!(fields[2].1, "2");
+        assert_eq!(
+            fields[7..],
+            [
+                ("HDDMOND_LINK_PORT", "phy-0:0:5"),
+                ("HDDMOND_LINK_UPSTREAM", "expander-0:0"),
+                ("HDDMOND_LINK_ERRORS", "12"),
+                ("HDDMOND_ALERT", "3"),
+            ]
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+        );
+
+        let digest = Notification::digest(vec![lost(), Notification::device_lost("sdb", None)]);
+        let fields = parse(&journal_entry(&digest));
+        assert!(fields.contains(&("HDDMOND_COUNT".to_string(), "2".to_string())));
+    }
+
+    #[test]
+    fn every_kind_has_its_own_message_id() {
+        let mut ids = NotificationKind::ALL
+            .iter()
+            .map(|event| message_id(*event))
+            .collect::<Vec<_>>();
+        assert!(ids
+            .iter()
+            .all(|id| id.len() == 32 && id.chars().all(|c| c.is_ascii_hexdigit())));
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), NotificationKind::ALL.len());
+    }
+
+    #[test]
+    fn multiline_values_use_the_binary_form() {
+        let mut entry = vec![];
+        write_field(&mut entry, "MESSAGE", "two\nlines");
+        write_field(&mut entry, "PRIORITY", "6");
+
+        let mut expected = b"MESSAGE\n".to_vec();
+        expected.extend_from_slice(&9u64.to_le_bytes());
+        expected.extend_from_slice(b"two\nlines\nPRIORITY=6\n");
+        assert_eq!(entry, expected);
+    }
+
+    #[test]
+    fn syslog_gets_a_plain_line() {
+        let listener = Listener::new("syslog");
+        let syslog = Journald::with_socket(Sink::Syslog, &listener.path).unwrap();
+        assert_eq!(syslog.name(), "syslog");
+
+        syslog.send(&lost()).unwrap();
+        // LOG_DAEMON at LOG_WARNING.
+        assert_eq!(
+            String::from_utf8(listener.receive()).unwrap(),
+            format!("<28>hddmond[{}]: Device sda (WD-WX12) lost", process::id())
+        );
+
+        let found = Notification {
+            event: NotificationKind::DeviceFound,
+            ..lost()
+        };
+        syslog.send(&found).unwrap();
+        assert!(String::from_utf8(listener.receive())
+            .unwrap()
+            .starts_with("<30>hddmond["));
+    }
+
+    #[test]
+    fn nobody_listening_is_an_error() {
+        let dir = TestDir::new("nobody");
+        let journald = Journald::with_socket(Sink::Journal, dir.join("socket")).unwrap();
+        assert!(journald.send(&lost()).is_err());
+    }
+}
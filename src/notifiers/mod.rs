@@ -0,0 +1,21 @@
+/// Slack, Discord and Matrix rooms.
+pub mod chat;
+/// Email over SMTP.
+pub mod email;
+/// Structured entries in the systemd journal, or syslog without it.
+#[cfg(feature = "journald")]
+pub mod journald;
+/// What a notification says, and the JSON targets get.
+pub mod notification;
+/// Queueing and sending notifications to every target.
+pub mod notifier_host;
+/// When only critical notifications go out.
+pub mod quiet_hours;
+/// Passwords and keys from the config, kept out of logs.
+pub mod secret;
+/// `{{field}}` placeholders in notification bodies.
+pub mod template;
+#[cfg(test)]
+pub(crate) mod test_server;
+/// HTTP POSTs to any endpoint.
+pub mod webhook;
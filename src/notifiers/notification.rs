@@ -0,0 +1,312 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    device_policy::DeviceIdentity,
+    link_health::LinkErrorGrowth,
+    storage::{DeviceRecord, FirmwareChange},
+};
+
+/// What a notification is about, and what targets pick which ones they
+/// get by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationKind {
+    /// A device appeared.
+    DeviceFound,
+    /// A device went away.
+    DeviceLost,
+    /// A device came back with other firmware than it last had.
+    FirmwareChanged,
+    /// The kernel stopped letting a device be written to.
+    DeviceBecameReadOnly,
+    /// The link error counters of a device's port are going up too fast.
+    LinkErrorsGrowing,
+}
+
+impl NotificationKind {
+    /// How urgent this kind is.
+    pub fn severity(&self) -> Severity {
+        match self {
+            NotificationKind::DeviceFound => Severity::Info,
+            // Drives going away on their own is how a lot of failures
+            // first show up.
+            NotificationKind::DeviceLost => Severity::Warning,
+            // Usually someone updating it on purpose, but worth knowing
+            // about when a drive starts acting up afterwards.
+            NotificationKind::FirmwareChanged => Severity::Info,
+            // Whatever was going to be written to it won't be, and it's
+            // often a drive on its way out.
+            NotificationKind::DeviceBecameReadOnly => Severity::Critical,
+            // Drives fail from bad cables and backplanes too, and take
+            // their neighbours with them.
+            NotificationKind::LinkErrorsGrowing => Severity::Critical,
+        }
+    }
+
+    /// For sentences, "Device sda was ...".
+    pub fn verb(&self) -> &'static str {
+        match self {
+            NotificationKind::DeviceFound => "found",
+            NotificationKind::DeviceLost => "lost",
+            NotificationKind::FirmwareChanged => "given new firmware",
+            NotificationKind::DeviceBecameReadOnly => "made read-only",
+            NotificationKind::LinkErrorsGrowing => "hit by link errors",
+        }
+    }
+
+    /// Every kind, which is what a target gets unless it says otherwise.
+    pub const ALL: &'static [NotificationKind] = &[
+        NotificationKind::DeviceFound,
+        NotificationKind::DeviceLost,
+        NotificationKind::FirmwareChanged,
+        NotificationKind::DeviceBecameReadOnly,
+        NotificationKind::LinkErrorsGrowing,
+    ];
+
+    /// The name it goes by in the config and in JSON.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NotificationKind::DeviceFound => "device_found",
+            NotificationKind::DeviceLost => "device_lost",
+            NotificationKind::FirmwareChanged => "firmware_changed",
+            NotificationKind::DeviceBecameReadOnly => "device_became_read_only",
+            NotificationKind::LinkErrorsGrowing => "link_errors_growing",
+        }
+    }
+}
+
+/// How urgent a notification is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    /// Nothing needs doing.
+    Info,
+    /// Worth a look soon.
+    Warning,
+    /// Goes out even during quiet hours.
+    Critical,
+}
+
+/// Something worth telling a human about. This is also the JSON that
+/// webhooks get by default, and what templates can refer to.
+#[derive(Debug, Clone, Serialize)]
+pub struct Notification {
+    /// What happened.
+    pub event: NotificationKind,
+    /// Kernel name, e.g. `sda`.
+    pub device: String,
+    /// What people call it, like `bay-7`, shown before the kernel name.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+    /// The identity the device reported, where it has one.
+    pub serial: Option<String>,
+    /// The model it reported.
+    pub model: Option<String>,
+    /// Its World Wide Name.
+    pub wwn: Option<String>,
+    /// Unix time, in seconds.
+    pub timestamp: u64,
+    /// What it was and is now, for firmware_changed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub firmware: Option<FirmwareChange>,
+    /// Which port and how much, for link_errors_growing.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub link_errors: Option<LinkErrorGrowth>,
+    /// The registry's alert for it, for health problems, which is what
+    /// gets acknowledged to stop hearing about it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alert: Option<i64>,
+    /// Set for a digest of several events like this one, and then lists
+    /// every device in it, this one included.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub group: Vec<GroupMember>,
+}
+
+/// One of the devices in a digest.
+#[derive(Debug, Clone, Serialize)]
+pub struct GroupMember {
+    /// Kernel name, e.g. `sda`.
+    pub device: String,
+    /// What people call it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+    /// The serial it reported.
+    pub serial: Option<String>,
+    /// The registry's alert for it, for health problems.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alert: Option<i64>,
+}
+
+impl GroupMember {
+    /// The device, by alias first if it has one, with its serial.
+    pub fn describe(&self) -> String {
+        describe(&self.device, self.alias.as_deref(), self.serial.as_deref())
+    }
+}
+
+// "bay-7 (sda, SERIAL)", "sda (SERIAL)" or just "sda".
+fn describe(device: &str, alias: Option<&str>, serial: Option<&str>) -> String {
+    match (alias, serial) {
+        (Some(alias), Some(serial)) => format!("{} ({}, {})", alias, device, serial),
+        (Some(alias), None) => format!("{} ({})", alias, device),
+        (None, Some(serial)) => format!("{} ({})", device, serial),
+        (None, None) => device.to_string(),
+    }
+}
+
+impl Notification {
+    /// "Device sda (SERIAL) lost", or "3 devices lost: sda, sdb (SERIAL),
+    /// sdc" for a digest, for anything with a subject line.
+    pub fn summary(&self) -> String {
+        if !self.group.is_empty() {
+            return format!(
+                "{} devices {}: {}",
+                self.group.len(),
+                self.event.verb(),
+                self.group
+                    .iter()
+                    .map(GroupMember::describe)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+
+        let mut summary = format!("Device {} {}", self.describe(), self.event.verb());
+        if let Some(change) = &self.firmware {
+            summary.push_str(&format!(", {} to {}", change.from, change.to));
+        }
+        if let Some(growth) = &self.link_errors {
+            summary.push_str(&format!(
+                ", {} on {} behind {}",
+                growth.errors, growth.port, growth.upstream
+            ));
+        }
+        summary
+    }
+
+    /// The device, by alias first if it has one, with its serial.
+    pub fn describe(&self) -> String {
+        describe(&self.device, self.alias.as_deref(), self.serial.as_deref())
+    }
+
+    /// The same notification, naming the device by `alias`.
+    pub fn with_alias(self, alias: Option<String>) -> Self {
+        Self { alias, ..self }
+    }
+
+    /// The same notification, for the registry alert `alert`.
+    pub fn with_alert(self, alert: i64) -> Self {
+        Self {
+            alert: Some(alert),
+            ..self
+        }
+    }
+
+    /// One notification standing in for several of the same kind. The
+    /// first one's identity is kept for whatever only shows one device.
+    pub fn digest(mut notifications: Vec<Notification>) -> Self {
+        if notifications.len() == 1 {
+            return notifications.remove(0);
+        }
+
+        let group = notifications
+            .iter()
+            .map(|notification| GroupMember {
+                device: notification.device.clone(),
+                alias: notification.alias.clone(),
+                serial: notification.serial.clone(),
+                alert: notification.alert,
+            })
+            .collect();
+        let mut digest = notifications.remove(0);
+        digest.group = group;
+        digest
+    }
+
+    /// Identical notifications are ones about the same thing happening to
+    /// the same drive. Drives are told apart by serial where there is one,
+    /// a flapping drive might come back under another name.
+    pub fn key(&self) -> (String, NotificationKind) {
+        let device = self.serial.clone().unwrap_or_else(|| self.device.clone());
+        (device, self.event)
+    }
+
+    /// A device appeared with this identity.
+    pub fn device_found(identity: &DeviceIdentity) -> Self {
+        Self {
+            event: NotificationKind::DeviceFound,
+            device: identity.name.clone(),
+            alias: None,
+            serial: identity.serial.clone(),
+            model: identity.model.clone(),
+            wwn: identity.wwn.clone(),
+            timestamp: now(),
+            firmware: None,
+            link_errors: None,
+            alert: None,
+            group: vec![],
+        }
+    }
+
+    /// What the device was is only known if the registry had it.
+    pub fn device_lost(name: &str, record: Option<&DeviceRecord>) -> Self {
+        Self {
+            event: NotificationKind::DeviceLost,
+            device: name.to_string(),
+            alias: record.and_then(|record| record.alias.clone()),
+            serial: record.and_then(|record| record.serial.clone()),
+            model: record.and_then(|record| record.model.clone()),
+            wwn: record.and_then(|record| record.wwn.clone()),
+            timestamp: now(),
+            firmware: None,
+            link_errors: None,
+            alert: None,
+            group: vec![],
+        }
+    }
+
+    /// A device came back with other firmware.
+    pub fn firmware_changed(identity: &DeviceIdentity, change: &FirmwareChange) -> Self {
+        Self {
+            event: NotificationKind::FirmwareChanged,
+            firmware: Some(change.clone()),
+            ..Self::device_found(identity)
+        }
+    }
+
+    /// A device was made read-only.
+    pub fn device_became_read_only(identity: &DeviceIdentity) -> Self {
+        Self {
+            event: NotificationKind::DeviceBecameReadOnly,
+            ..Self::device_found(identity)
+        }
+    }
+
+    /// Health problems, which the registry keeps an alert for until they
+    /// clear.
+    pub fn is_alert(&self) -> bool {
+        matches!(
+            self.event,
+            NotificationKind::DeviceBecameReadOnly | NotificationKind::LinkErrorsGrowing
+        )
+    }
+
+    /// A device's link errors are growing.
+    pub fn link_errors_growing(identity: &DeviceIdentity, growth: &LinkErrorGrowth) -> Self {
+        Self {
+            event: NotificationKind::LinkErrorsGrowing,
+            link_errors: Some(growth.clone()),
+            ..Self::device_found(identity)
+        }
+    }
+}
+
+/// The current Unix time, in seconds.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or_default()
+}
@@ -0,0 +1,1155 @@
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    sync::{Arc, Mutex as StdMutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tokio::{
+    sync::{
+        mpsc::{self, error::TrySendError},
+        Mutex,
+    },
+    task::JoinHandle,
+    time::{sleep_until, Instant as TokioInstant},
+};
+
+use crate::{
+    config::NotifiersConfig,
+    error::NotifierError,
+    faults::{self, FaultPoint},
+    supervisor::{self, Health, RestartPolicy},
+};
+
+#[cfg(feature = "journald")]
+use super::journald::Journald;
+use super::{
+    chat::Chat,
+    email::Email,
+    notification::{self, Notification, NotificationKind, Severity},
+    quiet_hours::QuietHours,
+    webhook::Webhook,
+};
+
+// Retries back off from this, doubling every attempt...
+const RETRY_BASE: Duration = Duration::from_secs(1);
+// ...up to this.
+const RETRY_MAX: Duration = Duration::from_secs(60);
+
+// Notifications that were acknowledged, by Notification::key, which no
+// target sends even if it was holding them when they were.
+type Silenced = Arc<StdMutex<HashSet<(String, NotificationKind)>>>;
+
+// Everything a notification can be sent through.
+enum Notifier {
+    Webhook(Webhook),
+    Email(Email),
+    Chat(Chat),
+    #[cfg(feature = "journald")]
+    Journald(Journald),
+}
+
+impl Notifier {
+    fn name(&self) -> String {
+        match self {
+            Notifier::Webhook(webhook) => webhook.name(),
+            Notifier::Email(email) => email.name(),
+            Notifier::Chat(chat) => chat.name(),
+            #[cfg(feature = "journald")]
+            Notifier::Journald(journald) => journald.name(),
+        }
+    }
+
+    async fn send(&self, notification: &Notification) -> Result<(), NotifierError> {
+        faults::fail(FaultPoint::NotifierDelivery, Some(&self.name()))
+            .await
+            .map_err(|error| NotifierError::Send {
+                target: self.name(),
+                error: Box::new(error),
+            })?;
+        match self {
+            Notifier::Webhook(webhook) => webhook.send(&webhook.body(notification)).await,
+            Notifier::Email(email) => email.send(notification).await,
+            Notifier::Chat(chat) => chat.send(notification).await,
+            #[cfg(feature = "journald")]
+            Notifier::Journald(journald) => journald.send(notification),
+        }
+    }
+}
+
+// What every kind of target has in common.
+struct TargetOptions {
+    events: Vec<NotificationKind>,
+    // At most one notification per device and event in this long.
+    rate_limit: Option<Duration>,
+    max_attempts: u32,
+    quiet_hours: Option<QuietHours>,
+}
+
+struct Target {
+    name: String,
+    events: Vec<NotificationKind>,
+    rate_limit: Option<Suppressor>,
+    sender: mpsc::Sender<Notification>,
+    deliveries: Arc<StdMutex<Deliveries>>,
+    task: JoinHandle<()>,
+}
+
+// How a target's deliveries went, kept up to date by its task.
+#[derive(Debug, Clone, Default)]
+struct Deliveries {
+    delivered: u64,
+    failed: u64,
+    last_error: Option<String>,
+    last_error_at: Option<u64>,
+}
+
+/// One target, for `hddmond status`.
+#[derive(Debug, Clone, Serialize)]
+pub struct NotifierStats {
+    /// What the target goes by, like `webhook https://example.org`, with
+    /// nothing secret in it.
+    pub name: String,
+    /// Notifications waiting in its queue, not counting a group that's
+    /// being held.
+    pub queued: usize,
+    /// Notifications it sent.
+    pub delivered: u64,
+    /// Notifications it gave up on after its last attempt.
+    pub failed: u64,
+    /// What the last failed attempt failed with.
+    pub last_error: Option<String>,
+    /// When that was, in Unix time.
+    pub last_error_at: Option<u64>,
+}
+
+impl Target {
+    // The delivery task is supervised: if it panics, it starts over with
+    // the same notifier and queue. Whatever it was grouping at the time is
+    // lost.
+    fn spawn(
+        notifier: Notifier,
+        options: TargetOptions,
+        config: &NotifiersConfig,
+        health: &Health,
+        silenced: &Silenced,
+    ) -> Self {
+        let name = notifier.name();
+        let (sender, receiver) = mpsc::channel(config.queue_size);
+
+        let notifier = Arc::new(notifier);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let deliveries = Arc::new(StdMutex::new(Deliveries::default()));
+        let task_deliveries = deliveries.clone();
+        let max_attempts = options.max_attempts;
+        let quiet_hours = options.quiet_hours;
+        let group_window = Duration::from_secs(config.group_window_secs);
+        let silenced = silenced.clone();
+        let task = supervisor::spawn(
+            format!("Notifier {}", name),
+            RestartPolicy::default(),
+            health.register(&format!("notifier {}", name)),
+            move || {
+                let notifier = notifier.clone();
+                let receiver = receiver.clone();
+                let deliveries = task_deliveries.clone();
+                let silenced = silenced.clone();
+                async move {
+                    run_target(
+                        &notifier,
+                        &deliveries,
+                        &silenced,
+                        max_attempts,
+                        || quiet_hours.and_then(|quiet_hours| quiet_hours.remaining()),
+                        group_window,
+                        &mut *receiver.lock().await,
+                    )
+                    .await
+                }
+            },
+        );
+
+        Self {
+            name,
+            events: options.events,
+            rate_limit: options.rate_limit.map(Suppressor::new),
+            sender,
+            deliveries,
+            task,
+        }
+    }
+}
+
+// Remembers which notifications went out recently, by Notification::key.
+struct Suppressor {
+    window: Duration,
+    last_sent: HashMap<(String, NotificationKind), Instant>,
+}
+
+impl Suppressor {
+    fn new(window: Duration) -> Self {
+        Self {
+            window,
+            last_sent: HashMap::new(),
+        }
+    }
+
+    // Whether the same notification went out within the window. If not,
+    // this one counts as sent from now on.
+    fn suppress(&mut self, notification: &Notification) -> bool {
+        let now = Instant::now();
+        let window = self.window;
+        self.last_sent
+            .retain(|_, sent| now.duration_since(*sent) < window);
+
+        match self.last_sent.entry(notification.key()) {
+            Entry::Occupied(_) => true,
+            Entry::Vacant(entry) => {
+                entry.insert(now);
+                false
+            }
+        }
+    }
+}
+
+/// Hands notifications to every configured target whose events match.
+/// Identical notifications within dedup_window_secs are dropped for all of
+/// them. Each target delivers from its own task and queue, so a slow or
+/// unreachable one never holds up the scan stream or the other targets; if
+/// its queue fills up, new notifications for it are dropped.
+///
+/// Acknowledged alerts are kept from going out by whoever raises them,
+/// only those a target held on to from before the acknowledgement are
+/// dropped here.
+pub struct NotifierHost {
+    targets: Vec<Target>,
+    dedup: Option<Suppressor>,
+    silenced: Silenced,
+    realert_after: Option<Duration>,
+}
+
+impl NotifierHost {
+    /// Has to be called from within the tokio runtime.
+    pub fn new(config: &NotifiersConfig, health: &Health) -> Result<Self, NotifierError> {
+        let mut targets = vec![];
+        let silenced = Silenced::default();
+
+        for webhook in &config.webhooks {
+            let options = TargetOptions {
+                events: webhook.events.clone(),
+                rate_limit: None,
+                max_attempts: webhook.max_attempts,
+                quiet_hours: webhook.quiet_hours,
+            };
+            let notifier = Notifier::Webhook(Webhook::new(webhook.clone())?);
+            targets.push(Target::spawn(notifier, options, config, health, &silenced));
+        }
+        for chat in &config.chats {
+            let options = TargetOptions {
+                events: chat.events.clone(),
+                rate_limit: chat.rate_limit_secs.map(Duration::from_secs),
+                max_attempts: chat.max_attempts,
+                quiet_hours: chat.quiet_hours,
+            };
+            let notifier = Notifier::Chat(Chat::new(chat.clone())?);
+            targets.push(Target::spawn(notifier, options, config, health, &silenced));
+        }
+        #[cfg(feature = "journald")]
+        if let Some(journald) = &config.journald {
+            let options = TargetOptions {
+                events: journald.events.clone(),
+                rate_limit: None,
+                // A local socket either takes the entry or it doesn't,
+                // there's nothing to retry.
+                max_attempts: 1,
+                quiet_hours: journald.quiet_hours,
+            };
+            let notifier = Notifier::Journald(Journald::new()?);
+            targets.push(Target::spawn(notifier, options, config, health, &silenced));
+        }
+        for email in &config.emails {
+            let options = TargetOptions {
+                events: email.events.clone(),
+                rate_limit: Some(Duration::from_secs(email.rate_limit_secs)),
+                max_attempts: email.max_attempts,
+                quiet_hours: email.quiet_hours,
+            };
+            let notifier = Notifier::Email(Email::new(email.clone())?);
+            targets.push(Target::spawn(notifier, options, config, health, &silenced));
+        }
+
+        let dedup = match config.dedup_window_secs {
+            0 => None,
+            secs => Some(Suppressor::new(Duration::from_secs(secs))),
+        };
+
+        let realert_after = match config.realert_after_secs {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
+
+        Ok(Self {
+            targets,
+            dedup,
+            silenced,
+            realert_after,
+        })
+    }
+
+    /// How long an acknowledged alert stays quiet, None for until it clears.
+    pub fn realert_after(&self) -> Option<Duration> {
+        self.realert_after
+    }
+
+    /// Drops the notifications with this key that targets are holding, and
+    /// any they're handed until it's unsilenced.
+    pub fn silence(&mut self, key: (String, NotificationKind)) {
+        lock(&self.silenced).insert(key);
+    }
+
+    /// Lets notifications with this key through again.
+    pub fn unsilence(&mut self, key: &(String, NotificationKind)) {
+        lock(&self.silenced).remove(key);
+    }
+
+    /// Hands `notification` to every target that wants it, unless it was just
+    /// sent or is silenced.
+    pub fn notify(&mut self, notification: Notification) {
+        if let Some(dedup) = &mut self.dedup {
+            if dedup.suppress(&notification) {
+                debug!(
+                    "Dropping {} for {}, it was just notified",
+                    notification.event.as_str(),
+                    notification.device
+                );
+                return;
+            }
+        }
+
+        for target in &mut self.targets {
+            if !target.events.contains(&notification.event) {
+                continue;
+            }
+            if let Some(rate_limit) = &mut target.rate_limit {
+                if rate_limit.suppress(&notification) {
+                    debug!(
+                        "Not sending {} for {} to {} again so soon",
+                        notification.event.as_str(),
+                        notification.device,
+                        target.name
+                    );
+                    continue;
+                }
+            }
+
+            match target.sender.try_send(notification.clone()) {
+                Ok(()) => {}
+                Err(TrySendError::Full(notification)) => warn!(
+                    "Notifications for {} are backed up, dropped {} for {}",
+                    target.name,
+                    notification.event.as_str(),
+                    notification.device
+                ),
+                Err(TrySendError::Closed(_)) => {
+                    error!("Notifier {} stopped, dropping it", target.name)
+                }
+            }
+        }
+
+        // Its supervisor gave up on it, there's no point queueing more.
+        self.targets.retain(|target| !target.sender.is_closed());
+    }
+
+    /// How every target is doing.
+    pub fn stats(&self) -> Vec<NotifierStats> {
+        self.targets
+            .iter()
+            .map(|target| {
+                let deliveries = lock(&target.deliveries).clone();
+                NotifierStats {
+                    name: target.name.clone(),
+                    queued: target.sender.max_capacity() - target.sender.capacity(),
+                    delivered: deliveries.delivered,
+                    failed: deliveries.failed,
+                    last_error: deliveries.last_error,
+                    last_error_at: deliveries.last_error_at,
+                }
+            })
+            .collect()
+    }
+
+    /// Closes every target's queue and waits for them to deliver what's
+    /// left in it. All queues are closed first so the targets drain side by
+    /// side, not one after the other.
+    pub async fn shutdown(self) {
+        let tasks: Vec<_> = self
+            .targets
+            .into_iter()
+            .map(|target| (target.name, target.task))
+            .collect();
+
+        // Panics are the supervisor's to deal with, its task doesn't.
+        for (name, task) in tasks {
+            if task.await.is_err() {
+                error!("Notifier {} was cancelled while shutting down", name);
+            }
+        }
+    }
+}
+
+// Notifications of one kind waiting to go out together.
+struct Group {
+    event: NotificationKind,
+    notifications: Vec<Notification>,
+    due: TokioInstant,
+}
+
+impl Group {
+    // A drive that's in the group already only takes the newer one's place,
+    // a group held through a night of quiet hours would otherwise grow by
+    // every flap of a flapping drive.
+    fn add(&mut self, notification: Notification) {
+        let key = notification.key();
+        match self.notifications.iter_mut().find(|held| held.key() == key) {
+            Some(held) => *held = notification,
+            None => self.notifications.push(notification),
+        }
+    }
+}
+
+// A target's delivery task. Notifications of the same kind that arrive
+// within the group window of the first one go out as one digest when the
+// window closes. A group that comes due during quiet hours waits for them
+// to end, and anything that arrives in the meantime joins it, so a night
+// of events is one message in the morning. Critical ones don't wait. `quiet` is how long the current
+// quiet hours have left, None outside of them.
+async fn run_target(
+    notifier: &Notifier,
+    deliveries: &StdMutex<Deliveries>,
+    silenced: &Silenced,
+    max_attempts: u32,
+    quiet: impl Fn() -> Option<Duration>,
+    group_window: Duration,
+    receiver: &mut mpsc::Receiver<Notification>,
+) {
+    let mut groups: Vec<Group> = vec![];
+
+    loop {
+        let next_due = groups.iter().map(|group| group.due).min();
+
+        tokio::select! {
+            notification = receiver.recv() => {
+                let notification = match notification {
+                    Some(notification) => notification,
+                    None => {
+                        // Shutting down. Whatever is waiting goes out now,
+                        // quiet hours or not, it would be lost otherwise.
+                        for group in groups {
+                            if let Some(digest) = unsilenced(group, silenced) {
+                                deliver(notifier, deliveries, max_attempts, digest).await;
+                            }
+                        }
+                        break;
+                    }
+                };
+
+                match groups.iter_mut().find(|group| group.event == notification.event) {
+                    Some(group) => group.add(notification),
+                    None => groups.push(Group {
+                        event: notification.event,
+                        notifications: vec![notification],
+                        due: TokioInstant::now() + group_window,
+                    }),
+                }
+            }
+            _ = sleep_until(next_due.unwrap_or_else(TokioInstant::now)), if next_due.is_some() => {
+                let now = TokioInstant::now();
+                let quiet = quiet();
+
+                let mut due = vec![];
+                for group in &mut groups {
+                    if group.due > now {
+                        continue;
+                    }
+                    match quiet {
+                        Some(remaining) if group.event.severity() < Severity::Critical => {
+                            group.due = now + remaining
+                        }
+                        _ => due.push(group.event),
+                    }
+                }
+
+                for event in due {
+                    let index = groups.iter().position(|group| group.event == event);
+                    if let Some(index) = index {
+                        let group = groups.remove(index);
+                        if let Some(digest) = unsilenced(group, silenced) {
+                            deliver(notifier, deliveries, max_attempts, digest).await;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+// What's left of a group once the acknowledged ones are taken out, as one
+// notification. None if that's all of them.
+fn unsilenced(group: Group, silenced: &Silenced) -> Option<Notification> {
+    let silenced = lock(silenced);
+    let notifications = group
+        .notifications
+        .into_iter()
+        .filter(|notification| !silenced.contains(&notification.key()))
+        .collect::<Vec<_>>();
+    drop(silenced);
+
+    if notifications.is_empty() {
+        debug!(
+            "Dropping a {} digest, it was all acknowledged",
+            group.event.as_str()
+        );
+        return None;
+    }
+    Some(Notification::digest(notifications))
+}
+
+async fn deliver(
+    notifier: &Notifier,
+    deliveries: &StdMutex<Deliveries>,
+    max_attempts: u32,
+    notification: Notification,
+) {
+    for attempt in 1..=max_attempts {
+        match notifier.send(&notification).await {
+            Ok(()) => {
+                lock(deliveries).delivered += 1;
+                break;
+            }
+            Err(e) if attempt == max_attempts => {
+                let mut deliveries = lock(deliveries);
+                deliveries.failed += 1;
+                deliveries.last_error = Some(format!("{:#}", e));
+                deliveries.last_error_at = Some(notification::now());
+                drop(deliveries);
+                // The dead letter log: the notification is gone after
+                // this, but what it would have said isn't.
+                error!(
+                    "Giving up on {} after {} attempts: {:#}. Undelivered: {}",
+                    notifier.name(),
+                    attempt,
+                    e,
+                    serde_json::to_string(&notification).unwrap_or_default()
+                );
+            }
+            Err(e) => {
+                {
+                    let mut deliveries = lock(deliveries);
+                    deliveries.last_error = Some(format!("{:#}", e));
+                    deliveries.last_error_at = Some(notification::now());
+                }
+                let delay = backoff(attempt);
+                warn!(
+                    "Failed to notify {} (attempt {}/{}), retrying in {:?}: {:#}",
+                    notifier.name(),
+                    attempt,
+                    max_attempts,
+                    delay,
+                    e
+                );
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+}
+
+fn backoff(attempt: u32) -> Duration {
+    RETRY_BASE
+        .saturating_mul(2u32.saturating_pow(attempt - 1))
+        .min(RETRY_MAX)
+}
+
+// Nothing holding it can panic halfway through an update.
+fn lock<T>(mutex: &StdMutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{config::WebhookConfig, device_policy::DeviceIdentity};
+
+    use super::{
+        super::test_server::{Request, Server, Smtp},
+        *,
+    };
+
+    fn webhook(server: &Server, extra: &str) -> WebhookConfig {
+        toml::from_str(&format!("url = \"{}\"\n{}", server.url, extra)).unwrap()
+    }
+
+    // Notifications go out as soon as a target gets to them, none are dropped
+    // as duplicates.
+    fn config(webhooks: Vec<WebhookConfig>) -> NotifiersConfig {
+        NotifiersConfig {
+            group_window_secs: 0,
+            dedup_window_secs: 0,
+            webhooks,
+            ..Default::default()
+        }
+    }
+
+    fn lost(name: &str) -> Notification {
+        Notification::device_lost(name, None)
+    }
+
+    fn devices(requests: &[Request]) -> Vec<String> {
+        requests
+            .iter()
+            .map(|request| request.json()["device"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    // Deliveries are counted once the response is in, which is a little
+    // after the server got the request.
+    async fn stats_once(
+        host: &NotifierHost,
+        done: impl Fn(&NotifierStats) -> bool,
+    ) -> NotifierStats {
+        for _ in 0..1000 {
+            let stats = host.stats().remove(0);
+            if done(&stats) {
+                return stats;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("Never got there: {:?}", host.stats());
+    }
+
+    #[tokio::test]
+    async fn retries_until_it_gets_through() {
+        let server = Server::start(&[500, 502, 200]).await;
+        let mut host = NotifierHost::new(
+            &config(vec![webhook(&server, "max_attempts = 3")]),
+            &Health::new(),
+        )
+        .unwrap();
+
+        host.notify(lost("sda"));
+
+        let stats = stats_once(&host, |stats| stats.delivered == 1).await;
+        assert_eq!(stats.failed, 0);
+        // The last failure is kept for `hddmond status`.
+        assert!(stats
+            .last_error
+            .unwrap()
+            .ends_with("answered 502 Bad Gateway"));
+        let requests = server.requests();
+        assert_eq!(devices(&requests), ["sda", "sda", "sda"]);
+        // It's the same request every time.
+        assert!(requests
+            .iter()
+            .all(|request| request.body == requests[0].body));
+        host.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_its_last_attempt() {
+        let server = Server::start(&[500]).await;
+        let mut host = NotifierHost::new(
+            &config(vec![webhook(&server, "max_attempts = 2")]),
+            &Health::new(),
+        )
+        .unwrap();
+
+        host.notify(lost("sda"));
+
+        let stats = stats_once(&host, |stats| stats.failed == 1).await;
+        assert_eq!(stats.delivered, 0);
+        assert!(stats.last_error_at.is_some());
+        assert_eq!(server.requests().len(), 2);
+        host.shutdown().await;
+        // Not tried again after.
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn full_queues_drop_new_notifications() {
+        let server = Server::holding(&[200]).await;
+        let mut host = NotifierHost::new(
+            &NotifiersConfig {
+                queue_size: 1,
+                ..config(vec![webhook(&server, "max_attempts = 1")])
+            },
+            &Health::new(),
+        )
+        .unwrap();
+
+        // Taken off the queue, and stuck waiting for an answer.
+        host.notify(lost("sda"));
+        server.wait_for(1).await;
+        host.notify(lost("sdb"));
+        host.notify(lost("sdc"));
+        assert_eq!(host.stats()[0].queued, 1);
+
+        server.release();
+        stats_once(&host, |stats| stats.delivered == 2).await;
+        host.shutdown().await;
+        assert_eq!(devices(&server.requests()), ["sda", "sdb"]);
+    }
+
+    #[tokio::test]
+    async fn a_failing_target_doesnt_hold_up_the_others() {
+        let down = Server::holding(&[200]).await;
+        let up = Server::start(&[200]).await;
+        let mut host = NotifierHost::new(
+            &config(vec![webhook(&down, ""), webhook(&up, "")]),
+            &Health::new(),
+        )
+        .unwrap();
+
+        // One at a time, or they'd go out as a digest.
+        host.notify(lost("sda"));
+        up.wait_for(1).await;
+        host.notify(lost("sdb"));
+
+        assert_eq!(devices(&up.wait_for(2).await), ["sda", "sdb"]);
+        assert_eq!(down.requests().len(), 1);
+        down.release();
+        host.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn targets_only_get_their_events() {
+        let server = Server::start(&[200]).await;
+        let mut host = NotifierHost::new(
+            &config(vec![webhook(&server, "events = [\"device_found\"]")]),
+            &Health::new(),
+        )
+        .unwrap();
+
+        host.notify(lost("sda"));
+        host.shutdown().await;
+        assert!(server.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn emails_are_rate_limited_per_drive() {
+        let smtp = Smtp::start(true).await;
+        let email = toml::from_str(&format!(
+            "server = \"127.0.0.1\"\nport = {}\ntls = \"none\"\n\
+             from = \"hddmond@bench.example\"\nto = [\"ops@bench.example\"]",
+            smtp.port
+        ))
+        .unwrap();
+        let mut host = NotifierHost::new(
+            &NotifiersConfig {
+                emails: vec![email],
+                ..config(vec![])
+            },
+            &Health::new(),
+        )
+        .unwrap();
+
+        host.notify(lost("sda"));
+        smtp.wait_for(1).await;
+        // Within the hour, only sdb's goes out.
+        host.notify(lost("sda"));
+        host.notify(lost("sdb"));
+        host.shutdown().await;
+
+        let subjects = smtp
+            .mails()
+            .iter()
+            .map(|mail| mail.header("Subject").unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            subjects,
+            ["hddmond: Device sda lost", "hddmond: Device sdb lost"]
+        );
+    }
+
+    #[tokio::test]
+    async fn chats_are_rate_limited_when_asked() {
+        let server = Server::start(&[200]).await;
+        let chat = toml::from_str(&format!(
+            "kind = \"slack\"\nurl = \"{}\"\nrate_limit_secs = 600",
+            server.url
+        ))
+        .unwrap();
+        let mut host = NotifierHost::new(
+            &NotifiersConfig {
+                chats: vec![chat],
+                ..config(vec![])
+            },
+            &Health::new(),
+        )
+        .unwrap();
+
+        host.notify(lost("sda"));
+        server.wait_for(1).await;
+        host.notify(lost("sda"));
+        host.notify(lost("sdb"));
+        host.shutdown().await;
+
+        let texts = server
+            .requests()
+            .iter()
+            .map(|request| request.json()["text"].as_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(texts, ["Device sda lost", "Device sdb lost"]);
+    }
+
+    // A target's task on its own, delivering to the server.
+    fn run(
+        server: &Server,
+        group_window: Duration,
+        quiet: impl Fn() -> Option<Duration> + Send + 'static,
+    ) -> (mpsc::Sender<Notification>, JoinHandle<()>) {
+        let webhook = Webhook::new(webhook(server, "max_attempts = 1")).unwrap();
+        let notifier = Notifier::Webhook(webhook);
+        let (sender, mut receiver) = mpsc::channel(100);
+        let task = tokio::spawn(async move {
+            run_target(
+                &notifier,
+                &Default::default(),
+                &Silenced::default(),
+                1,
+                quiet,
+                group_window,
+                &mut receiver,
+            )
+            .await
+        });
+        (sender, task)
+    }
+
+    fn never_quiet() -> Option<Duration> {
+        None
+    }
+
+    // Quiet hours from `from` to `to` after `start`.
+    fn quiet_between(
+        start: Instant,
+        from: Duration,
+        to: Duration,
+    ) -> impl Fn() -> Option<Duration> + Send {
+        move || {
+            let elapsed = start.elapsed();
+            (from <= elapsed && elapsed < to).then(|| to - elapsed)
+        }
+    }
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn members(request: &Request) -> Vec<String> {
+        request.json()["group"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|member| member["device"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn notifications_of_a_kind_are_grouped_within_the_window() {
+        let server = Server::start(&[200]).await;
+        let start = TokioInstant::now();
+        let (sender, task) = run(&server, ms(300), never_quiet);
+
+        sender.send(lost("sda")).await.unwrap();
+        sender.send(lost("sdb")).await.unwrap();
+        let found = Notification {
+            event: NotificationKind::DeviceFound,
+            ..lost("sdc")
+        };
+        sender.send(found).await.unwrap();
+        // The same drive again only replaces what it sent before.
+        let again = Notification {
+            timestamp: 2,
+            ..lost("sda")
+        };
+        sender.send(again).await.unwrap();
+
+        sleep_until(start + ms(200)).await;
+        assert!(server.requests().is_empty());
+
+        let mut requests = server.wait_for(2).await;
+        assert!(start.elapsed() >= ms(300));
+        requests.sort_by_key(|request| request.json()["event"].as_str().unwrap().to_string());
+        assert_eq!(requests[0].json()["event"], "device_found");
+        assert_eq!(requests[0].json()["device"], "sdc");
+        assert_eq!(requests[0].json().get("group"), None);
+        assert_eq!(requests[1].json()["event"], "device_lost");
+        assert_eq!(members(&requests[1]), ["sda", "sdb"]);
+        assert_eq!(requests[1].json()["timestamp"], 2);
+
+        drop(sender);
+        task.await.unwrap();
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn each_window_starts_with_its_first_notification() {
+        let server = Server::start(&[200]).await;
+        let (sender, task) = run(&server, ms(200), never_quiet);
+
+        sender.send(lost("sda")).await.unwrap();
+        server.wait_for(1).await;
+        sender.send(lost("sdb")).await.unwrap();
+        let requests = server.wait_for(2).await;
+
+        assert_eq!(requests[0].json()["device"], "sda");
+        assert_eq!(requests[1].json()["device"], "sdb");
+        drop(sender);
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_window_closing_in_quiet_hours_waits_for_them_to_end() {
+        let server = Server::start(&[200]).await;
+        let start = Instant::now();
+        let (sender, task) = run(&server, ms(300), quiet_between(start, ms(100), ms(700)));
+
+        sender.send(lost("sda")).await.unwrap();
+        // After the window closed, but still quiet: it joins the group.
+        tokio::time::sleep(ms(500)).await;
+        sender.send(lost("sdb")).await.unwrap();
+        tokio::time::sleep(ms(100)).await;
+        assert!(server.requests().is_empty());
+
+        let requests = server.wait_for(1).await;
+        assert!(start.elapsed() >= ms(700));
+        assert_eq!(members(&requests[0]), ["sda", "sdb"]);
+        drop(sender);
+        task.await.unwrap();
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn a_window_closing_after_quiet_hours_isnt_held() {
+        let server = Server::start(&[200]).await;
+        let start = Instant::now();
+        // Quiet from the start until 400ms, the window ends at 200ms.
+        let (sender, task) = run(&server, ms(200), quiet_between(start, ms(0), ms(400)));
+
+        sender.send(lost("sda")).await.unwrap();
+        tokio::time::sleep(ms(300)).await;
+        assert!(server.requests().is_empty());
+
+        let requests = server.wait_for(1).await;
+        let elapsed = start.elapsed();
+        assert!(elapsed >= ms(400), "{:?}", elapsed);
+        assert_eq!(requests[0].json()["device"], "sda");
+        drop(sender);
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn critical_notifications_dont_wait_for_quiet_hours() {
+        let server = Server::start(&[200]).await;
+        let start = Instant::now();
+        let (sender, task) = run(&server, ms(100), quiet_between(start, ms(0), ms(600)));
+
+        sender.send(lost("sda")).await.unwrap();
+        let read_only = Notification {
+            event: NotificationKind::DeviceBecameReadOnly,
+            ..lost("sdb")
+        };
+        sender.send(read_only).await.unwrap();
+
+        let requests = server.wait_for(1).await;
+        assert!(start.elapsed() < ms(600));
+        assert_eq!(requests[0].json()["event"], "device_became_read_only");
+        assert_eq!(requests[0].json()["device"], "sdb");
+
+        // The warning is still held for the end of quiet hours.
+        let requests = server.wait_for(2).await;
+        assert!(start.elapsed() >= ms(600));
+        assert_eq!(requests[1].json()["event"], "device_lost");
+        assert_eq!(requests[1].json()["device"], "sda");
+        drop(sender);
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn shutting_down_sends_what_quiet_hours_held() {
+        let server = Server::start(&[200]).await;
+        let (sender, task) = run(&server, ms(0), || Some(Duration::from_secs(3600)));
+
+        sender.send(lost("sda")).await.unwrap();
+        sender.send(lost("sdb")).await.unwrap();
+        tokio::time::sleep(ms(100)).await;
+        assert!(server.requests().is_empty());
+
+        drop(sender);
+        task.await.unwrap();
+        assert_eq!(members(&server.requests()[0]), ["sda", "sdb"]);
+    }
+
+    #[test]
+    fn duplicates_are_told_apart_by_serial() {
+        let mut dedup = Suppressor::new(ms(100));
+        let with_serial = |name: &str| Notification {
+            serial: Some("WD-WX12".to_string()),
+            ..lost(name)
+        };
+
+        assert!(!dedup.suppress(&with_serial("sda")));
+        // Back under another name, it's still the same drive.
+        assert!(dedup.suppress(&with_serial("sdb")));
+        // Another kind of event isn't a duplicate.
+        let found = Notification {
+            event: NotificationKind::DeviceFound,
+            ..with_serial("sda")
+        };
+        assert!(!dedup.suppress(&found));
+        // Without a serial, all there is to go by is the name.
+        assert!(!dedup.suppress(&lost("sdc")));
+        assert!(dedup.suppress(&lost("sdc")));
+        assert!(!dedup.suppress(&lost("sdd")));
+
+        std::thread::sleep(ms(150));
+        assert!(!dedup.suppress(&with_serial("sda")));
+        assert!(dedup.suppress(&with_serial("sda")));
+    }
+
+    #[tokio::test]
+    async fn the_host_drops_duplicates_for_every_target() {
+        let first = Server::start(&[200]).await;
+        let second = Server::start(&[200]).await;
+        let mut host = NotifierHost::new(
+            &NotifiersConfig {
+                dedup_window_secs: 60,
+                ..config(vec![webhook(&first, ""), webhook(&second, "")])
+            },
+            &Health::new(),
+        )
+        .unwrap();
+
+        host.notify(lost("sda"));
+        first.wait_for(1).await;
+        second.wait_for(1).await;
+        host.notify(lost("sda"));
+        host.shutdown().await;
+
+        assert_eq!(first.requests().len(), 1);
+        assert_eq!(second.requests().len(), 1);
+    }
+
+    fn read_only(name: &str, serial: &str) -> Notification {
+        Notification::device_became_read_only(&DeviceIdentity {
+            name: name.to_string(),
+            serial: Some(serial.to_string()),
+            ..Default::default()
+        })
+    }
+
+    // Held for a second, so there's time to acknowledge what's waiting.
+    fn grouping(server: &Server) -> NotifierHost {
+        NotifierHost::new(
+            &NotifiersConfig {
+                group_window_secs: 1,
+                ..config(vec![webhook(server, "")])
+            },
+            &Health::new(),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn an_acknowledged_alert_is_taken_out_of_its_digest() {
+        let server = Server::start(&[200]).await;
+        let mut host = grouping(&server);
+
+        host.notify(read_only("sda", "WD-A").with_alert(1));
+        host.notify(read_only("sdb", "WD-B").with_alert(2));
+        host.notify(read_only("sdc", "WD-C").with_alert(3));
+        host.silence(read_only("sdb", "WD-B").key());
+
+        let requests = server.wait_for(1).await;
+        let members = requests[0].json()["group"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|member| (member["device"].clone(), member["alert"].clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            members,
+            [("sda".into(), 1.into()), ("sdc".into(), 3.into())]
+        );
+        host.shutdown().await;
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn a_digest_thats_all_acknowledged_isnt_sent() {
+        let server = Server::start(&[200]).await;
+        let mut host = grouping(&server);
+
+        host.notify(read_only("sda", "WD-A"));
+        host.silence(read_only("sda", "WD-A").key());
+        tokio::time::sleep(ms(1500)).await;
+        assert!(server.requests().is_empty());
+
+        // Nor when shutting down sends what's left.
+        host.notify(read_only("sdb", "WD-B"));
+        host.silence(read_only("sdb", "WD-B").key());
+        host.shutdown().await;
+        assert!(server.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn an_alert_is_notified_about_again_once_unsilenced() {
+        let server = Server::start(&[200]).await;
+        let mut host =
+            NotifierHost::new(&config(vec![webhook(&server, "")]), &Health::new()).unwrap();
+
+        // Acknowledged, anything more about it is dropped, other drives and
+        // other kinds of events aren't.
+        host.silence(read_only("sda", "WD-A").key());
+        host.notify(read_only("sda", "WD-A"));
+        host.notify(Notification {
+            event: NotificationKind::LinkErrorsGrowing,
+            ..read_only("sda", "WD-A")
+        });
+        host.notify(read_only("sdb", "WD-B"));
+        let requests = server.wait_for(2).await;
+        let mut sent = requests
+            .iter()
+            .map(|request| {
+                let json = request.json();
+                format!("{} {}", json["event"], json["device"])
+            })
+            .collect::<Vec<_>>();
+        sent.sort();
+        assert_eq!(
+            sent,
+            [
+                "\"device_became_read_only\" \"sdb\"",
+                "\"link_errors_growing\" \"sda\"",
+            ]
+        );
+
+        // Cleared or run out, it's notified about again.
+        host.unsilence(&read_only("sda", "WD-A").key());
+        host.notify(read_only("sda", "WD-A"));
+        let requests = server.wait_for(3).await;
+        assert_eq!(requests[2].json()["device"], "sda");
+        host.shutdown().await;
+        assert_eq!(server.requests().len(), 3);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_a_minute() {
+        let delays = (1..=8).map(backoff).collect::<Vec<_>>();
+        assert_eq!(
+            delays,
+            [1, 2, 4, 8, 16, 32, 60, 60].map(Duration::from_secs)
+        );
+        assert_eq!(backoff(u32::MAX), RETRY_MAX);
+    }
+}
@@ -0,0 +1,186 @@
+use std::{fmt, str::FromStr, time::Duration};
+
+use chrono::{Local, Timelike};
+use serde::Deserialize;
+
+use crate::error::NotifierError;
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// A daily stretch of local time like "22:00-07:00", which may wrap past
+/// midnight, during which a target only gets critical notifications.
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct QuietHours {
+    // Minutes since midnight.
+    start: u32,
+    end: u32,
+}
+
+impl QuietHours {
+    /// How long until quiet hours end, or None if it isn't quiet right now.
+    pub fn remaining(&self) -> Option<Duration> {
+        let now = Local::now();
+        self.remaining_at(now.hour() * 60 + now.minute(), now.second())
+    }
+
+    fn remaining_at(&self, minute: u32, second: u32) -> Option<Duration> {
+        let quiet = if self.start <= self.end {
+            self.start <= minute && minute < self.end
+        } else {
+            minute >= self.start || minute < self.end
+        };
+        if !quiet {
+            return None;
+        }
+
+        let minutes = (self.end + MINUTES_PER_DAY - minute) % MINUTES_PER_DAY;
+        Some(Duration::from_secs(
+            (minutes as u64 * 60).saturating_sub(second as u64),
+        ))
+    }
+}
+
+impl FromStr for QuietHours {
+    type Err = NotifierError;
+
+    fn from_str(text: &str) -> Result<Self, NotifierError> {
+        let invalid = || NotifierError::QuietHours {
+            text: text.to_string(),
+        };
+
+        let parse = |time: &str| -> Result<u32, NotifierError> {
+            let (hours, minutes) = time.trim().split_once(':').ok_or_else(invalid)?;
+            let hours: u32 = hours.parse().map_err(|_| invalid())?;
+            let minutes: u32 = minutes.parse().map_err(|_| invalid())?;
+            if hours > 23 || minutes > 59 {
+                return Err(invalid());
+            }
+            Ok(hours * 60 + minutes)
+        };
+
+        let (start, end) = text.split_once('-').ok_or_else(invalid)?;
+        let (start, end) = (parse(start)?, parse(end)?);
+        if start == end {
+            return Err(NotifierError::EmptyQuietHours {
+                text: text.to_string(),
+            });
+        }
+
+        Ok(Self { start, end })
+    }
+}
+
+impl TryFrom<String> for QuietHours {
+    type Error = NotifierError;
+
+    fn try_from(text: String) -> Result<Self, NotifierError> {
+        text.parse()
+    }
+}
+
+impl fmt::Debug for QuietHours {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hours(text: &str) -> QuietHours {
+        text.parse().unwrap()
+    }
+
+    fn at(quiet_hours: &str, time: &str) -> Option<u64> {
+        let (hour, rest) = time.split_once(':').unwrap();
+        let (minute, second) = rest.split_once(':').unwrap_or((rest, "0"));
+        let minute = hour.parse::<u32>().unwrap() * 60 + minute.parse::<u32>().unwrap();
+        hours(quiet_hours)
+            .remaining_at(minute, second.parse().unwrap())
+            .map(|remaining| remaining.as_secs())
+    }
+
+    #[test]
+    fn quiet_hours_past_midnight() {
+        let cases = [
+            ("21:59:59", None),
+            // Quiet from the start of their first minute...
+            ("22:00", Some(9 * 3600)),
+            ("23:59:30", Some(7 * 3600 + 30)),
+            ("00:00", Some(7 * 3600)),
+            ("06:59:59", Some(1)),
+            // ...to the start of their last.
+            ("07:00", None),
+            ("12:00", None),
+        ];
+        for (time, remaining) in cases {
+            assert_eq!(at("22:00-07:00", time), remaining, "{}", time);
+        }
+    }
+
+    #[test]
+    fn quiet_hours_within_a_day() {
+        let cases = [
+            ("00:00", None),
+            ("08:59:59", None),
+            ("09:00", Some(8 * 3600)),
+            ("16:59:59", Some(1)),
+            ("17:00", None),
+            ("23:59:59", None),
+        ];
+        for (time, remaining) in cases {
+            assert_eq!(at("09:00-17:00", time), remaining, "{}", time);
+        }
+    }
+
+    #[test]
+    fn quiet_hours_up_to_midnight() {
+        assert_eq!(at("22:00-00:00", "23:59:59"), Some(1));
+        assert_eq!(at("22:00-00:00", "00:00"), None);
+        assert_eq!(at("00:00-06:00", "00:00"), Some(6 * 3600));
+        assert_eq!(at("00:00-06:00", "23:59:59"), None);
+    }
+
+    #[test]
+    fn parsing() {
+        assert_eq!(format!("{:?}", hours("22:00-07:00")), "22:00-07:00");
+        assert_eq!(format!("{:?}", hours(" 9:05 - 17:30 ")), "09:05-17:30");
+
+        for bad in [
+            "",
+            "22:00",
+            "22-07",
+            "24:00-07:00",
+            "22:60-07:00",
+            "aa:bb-cc:dd",
+            "22:00-07:00-08:00",
+        ] {
+            assert!(bad.parse::<QuietHours>().is_err(), "{}", bad);
+        }
+        let e = "08:00-08:00".parse::<QuietHours>().unwrap_err().to_string();
+        assert_eq!(
+            e,
+            "Quiet hours \"08:00-08:00\" start and end at the same time"
+        );
+    }
+
+    #[test]
+    fn deserializes_from_a_string() {
+        #[derive(Deserialize)]
+        struct Target {
+            quiet_hours: QuietHours,
+        }
+        let target: Target = toml::from_str("quiet_hours = \"23:30-06:15\"").unwrap();
+        assert_eq!(target.quiet_hours, hours("23:30-06:15"));
+        assert!(toml::from_str::<Target>("quiet_hours = \"late\"").is_err());
+    }
+}
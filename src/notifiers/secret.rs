@@ -0,0 +1,22 @@
+use std::fmt;
+
+use serde::Deserialize;
+
+/// A password or key from the config. Deserializes like a plain string, but
+/// never shows up in Debug output, which is what ends up in logs.
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    /// The secret itself, for handing to whatever needs it.
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<redacted>")
+    }
+}
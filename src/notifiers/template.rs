@@ -0,0 +1,119 @@
+use serde_json::Value;
+
+use crate::error::NotifierError;
+
+/// A notification body with `{{field}}` placeholders, like a very small
+/// Handlebars. Fields are looked up in the notification's JSON, nested ones
+/// with dots (`{{a.b}}`), and are inserted as JSON: strings come out quoted
+/// and escaped, so a JSON template stays valid JSON whatever the values
+/// are. A missing field is `null`.
+#[derive(Debug, Clone)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone)]
+enum Part {
+    Text(String),
+    Field(Vec<String>),
+}
+
+impl Template {
+    /// Splits `template` into text and placeholders, failing on an unclosed
+    /// or empty one.
+    pub fn parse(template: &str) -> Result<Self, NotifierError> {
+        let invalid = |problem| NotifierError::Template {
+            problem,
+            template: template.to_string(),
+        };
+        let mut parts = vec![];
+        let mut rest = template;
+
+        while let Some(start) = rest.find("{{") {
+            if start > 0 {
+                parts.push(Part::Text(rest[..start].to_string()));
+            }
+
+            let after = &rest[start + 2..];
+            let end = match after.find("}}") {
+                Some(end) => end,
+                None => return Err(invalid("Unclosed {{")),
+            };
+
+            let field = after[..end].trim();
+            if field.is_empty() {
+                return Err(invalid("Empty {{}}"));
+            }
+            parts.push(Part::Field(field.split('.').map(String::from).collect()));
+
+            rest = &after[end + 2..];
+        }
+
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.to_string()));
+        }
+
+        Ok(Self { parts })
+    }
+
+    /// Fills in the placeholders with their JSON, a missing field is null.
+    pub fn render(&self, values: &Value) -> String {
+        self.render_with(values, |value| value.to_string())
+    }
+
+    /// For URL templates: values are percent encoded, strings without their
+    /// quotes, and a missing field is empty.
+    pub fn render_url(&self, values: &Value) -> String {
+        self.render_with(values, |value| match value {
+            Value::Null => String::new(),
+            Value::String(value) => percent_encode(value),
+            value => percent_encode(&value.to_string()),
+        })
+    }
+
+    /// For plain text, like device aliases: strings go in as they are. None
+    /// if a field is missing or null, or it all comes out empty.
+    pub fn render_text(&self, values: &Value) -> Option<String> {
+        let mut missing = false;
+        let text = self.render_with(values, |value| match value {
+            Value::Null => {
+                missing = true;
+                String::new()
+            }
+            Value::String(value) => value.clone(),
+            value => value.to_string(),
+        });
+        (!missing && !text.trim().is_empty()).then_some(text)
+    }
+
+    fn render_with(&self, values: &Value, mut format: impl FnMut(&Value) -> String) -> String {
+        let mut out = String::new();
+
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => out.push_str(text),
+                Part::Field(path) => {
+                    let value = path
+                        .iter()
+                        .try_fold(values, |value, key| value.get(key))
+                        .unwrap_or(&Value::Null);
+                    out.push_str(&format(value));
+                }
+            }
+        }
+
+        out
+    }
+}
+
+// Everything but RFC 3986's unreserved characters.
+fn percent_encode(text: &str) -> String {
+    text.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            byte => format!("%{:02X}", byte),
+        })
+        .collect()
+}
@@ -0,0 +1,307 @@
+// Local HTTP and SMTP servers for the notifier tests, which keep what they
+// were sent.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::{watch, Semaphore},
+    time::timeout,
+};
+
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    // Names lowercased.
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_str(&self.body).unwrap()
+    }
+}
+
+// Answers every request with the next of a list of statuses.
+pub struct Server {
+    pub url: String,
+    requests: Arc<Mutex<Vec<Request>>>,
+    received: watch::Receiver<usize>,
+    answers: Arc<Semaphore>,
+}
+
+impl Server {
+    // The last status is repeated once the others are used up.
+    pub async fn start(statuses: &[u16]) -> Self {
+        Self::with_answers(statuses, Semaphore::MAX_PERMITS).await
+    }
+
+    // Takes requests but doesn't answer them until it's released.
+    pub async fn holding(statuses: &[u16]) -> Self {
+        Self::with_answers(statuses, 0).await
+    }
+
+    async fn with_answers(statuses: &[u16], answers: usize) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(vec![]));
+        let (count, received) = watch::channel(0);
+        let answers = Arc::new(Semaphore::new(answers));
+
+        let statuses = statuses.to_vec();
+        let task_requests = requests.clone();
+        let task_answers = answers.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(_) => break,
+                };
+                let (request, stream) = match read_request(stream).await {
+                    Some(read) => read,
+                    None => continue,
+                };
+
+                let status = {
+                    let mut requests = task_requests.lock().unwrap();
+                    requests.push(request);
+                    count.send_replace(requests.len());
+                    statuses[(requests.len() - 1).min(statuses.len() - 1)]
+                };
+
+                let answers = task_answers.clone();
+                tokio::spawn(async move {
+                    let _answer = answers.acquire().await;
+                    respond(stream, status).await;
+                });
+            }
+        });
+
+        Self {
+            url,
+            requests,
+            received,
+            answers,
+        }
+    }
+
+    pub fn release(&self) {
+        self.answers
+            .add_permits(Semaphore::MAX_PERMITS - self.answers.available_permits());
+    }
+
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    // Panics if it takes unreasonably long.
+    pub async fn wait_for(&self, requests: usize) -> Vec<Request> {
+        wait_for(&self.received, requests).await;
+        self.requests()
+    }
+}
+
+async fn wait_for(received: &watch::Receiver<usize>, count: usize) {
+    let mut received = received.clone();
+    timeout(Duration::from_secs(10), async {
+        while *received.borrow_and_update() < count {
+            received.changed().await.unwrap();
+        }
+    })
+    .await
+    .unwrap_or_else(|_| panic!("Never got {}", count));
+}
+
+async fn read_request(mut stream: TcpStream) -> Option<(Request, TcpStream)> {
+    let mut data = vec![];
+    let mut buffer = [0; 4096];
+
+    let head_end = loop {
+        if let Some(end) = data.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end;
+        }
+        match stream.read(&mut buffer).await {
+            Ok(0) | Err(_) => return None,
+            Ok(read) => data.extend_from_slice(&buffer[..read]),
+        }
+    };
+
+    let head = String::from_utf8_lossy(&data[..head_end]).to_string();
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next()?.split(' ');
+    let method = request_line.next()?.to_string();
+    let path = request_line.next()?.to_string();
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_string()))
+        .collect::<Vec<_>>();
+
+    let length = headers
+        .iter()
+        .find(|(name, _)| name == "content-length")
+        .and_then(|(_, value)| value.parse().ok())
+        .unwrap_or(0);
+    let mut body = data[head_end + 4..].to_vec();
+    while body.len() < length {
+        match stream.read(&mut buffer).await {
+            Ok(0) | Err(_) => return None,
+            Ok(read) => body.extend_from_slice(&buffer[..read]),
+        }
+    }
+
+    let request = Request {
+        method,
+        path,
+        headers,
+        body: String::from_utf8_lossy(&body).to_string(),
+    };
+    Some((request, stream))
+}
+
+async fn respond(mut stream: TcpStream, status: u16) {
+    let response = format!(
+        "HTTP/1.1 {} Test\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+        status
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+#[derive(Debug, Clone)]
+pub struct Mail {
+    pub from: String,
+    pub to: Vec<String>,
+    // Headers and body, as sent.
+    pub data: String,
+}
+
+impl Mail {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.data
+            .lines()
+            .take_while(|line| !line.is_empty())
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(": "))
+    }
+
+    pub fn body(&self) -> &str {
+        self.data
+            .split_once("\r\n\r\n")
+            .map(|(_, body)| body)
+            .unwrap_or_default()
+    }
+}
+
+// Takes every mail without TLS or authentication, or rejects every
+// recipient.
+pub struct Smtp {
+    pub port: u16,
+    mails: Arc<Mutex<Vec<Mail>>>,
+    received: watch::Receiver<usize>,
+}
+
+impl Smtp {
+    pub async fn start(accept: bool) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mails = Arc::new(Mutex::new(vec![]));
+        let (count, received) = watch::channel(0);
+
+        let task_mails = mails.clone();
+        tokio::spawn(async move {
+            let count = Arc::new(count);
+            while let Ok((stream, _)) = listener.accept().await {
+                let mails = task_mails.clone();
+                let count = count.clone();
+                tokio::spawn(async move {
+                    if let Some(mail) = smtp_session(stream, accept).await {
+                        let mut mails = mails.lock().unwrap();
+                        mails.push(mail);
+                        count.send_replace(mails.len());
+                    }
+                });
+            }
+        });
+
+        Self {
+            port,
+            mails,
+            received,
+        }
+    }
+
+    pub fn mails(&self) -> Vec<Mail> {
+        self.mails.lock().unwrap().clone()
+    }
+
+    pub async fn wait_for(&self, mails: usize) -> Vec<Mail> {
+        wait_for(&self.received, mails).await;
+        self.mails()
+    }
+}
+
+async fn smtp_session(stream: TcpStream, accept: bool) -> Option<Mail> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    let mut mail = Mail {
+        from: String::new(),
+        to: vec![],
+        data: String::new(),
+    };
+    let mut done = false;
+
+    writer.write_all(b"220 test ESMTP\r\n").await.ok()?;
+    while let Some(line) = lines.next_line().await.ok()? {
+        let command = line.to_uppercase();
+        let reply = if command.starts_with("EHLO") || command.starts_with("HELO") {
+            "250 test".to_string()
+        } else if let Some(from) = line.get(10..).filter(|_| command.starts_with("MAIL FROM:")) {
+            mail.from = from.trim_matches(|c| c == '<' || c == '>').to_string();
+            "250 OK".to_string()
+        } else if let Some(to) = line.get(8..).filter(|_| command.starts_with("RCPT TO:")) {
+            if !accept {
+                "550 No such user".to_string()
+            } else {
+                mail.to
+                    .push(to.trim_matches(|c| c == '<' || c == '>').to_string());
+                "250 OK".to_string()
+            }
+        } else if command == "DATA" {
+            writer.write_all(b"354 Go ahead\r\n").await.ok()?;
+            let mut data = vec![];
+            while let Some(line) = lines.next_line().await.ok()? {
+                if line == "." {
+                    break;
+                }
+                // Undoes dot stuffing.
+                data.push(line.strip_prefix('.').map(String::from).unwrap_or(line));
+            }
+            mail.data = data.join("\r\n");
+            done = true;
+            "250 Queued".to_string()
+        } else if command == "QUIT" {
+            writer.write_all(b"221 Bye\r\n").await.ok()?;
+            break;
+        } else {
+            "250 OK".to_string()
+        };
+        writer
+            .write_all(format!("{}\r\n", reply).as_bytes())
+            .await
+            .ok()?;
+    }
+
+    done.then_some(mail)
+}
@@ -0,0 +1,1077 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use serde::Deserialize;
+use serde_json::json;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::{mpsc, oneshot},
+    time::{interval, interval_at, Instant, Interval},
+};
+
+use crate::{
+    audit::{self, AuditLog},
+    capabilities::{CapabilityProber, ReadOnlyChange, ReadOnlyWatch},
+    capacity::CapacityChecker,
+    config::{self, Backend, Config, LoggingConfig, PluginHostConfig},
+    control::{ControlRequest, ControlSocket},
+    daemon,
+    device_policy::{self, DeviceIdentity, DevicePolicy},
+    disk_guard::{DiskGuard, SpaceChange},
+    error::{ConfigError, PipelineError, ScanError},
+    event_reader::{CreateMonitor, EventReader},
+    identify_queue::IdentifyQueue,
+    link_health::{LinkErrorGrowth, LinkHealth, LinkHealthReader},
+    logging::Logging,
+    mmc::MmcHealthReader,
+    notifiers::{
+        notification::{Notification, NotificationKind},
+        notifier_host::NotifierHost,
+    },
+    plugins::plugin_host::PluginHost,
+    power::PowerManager,
+    probe::{self, Prober},
+    scanners::{
+        backend,
+        rescan::{Rescanner, ScsiHosts},
+        scanner::{DeviceMonitor, ScanEventType},
+    },
+    shutdown::Shutdown,
+    status::StatusCollector,
+    storage::{self, Alert, AlertState, Registry},
+    supervisor::Health,
+};
+
+// How often the drives that are present are looked up again.
+const REIDENTIFY_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+// How often present devices' ro flag is checked, to catch them going
+// read-only.
+const READ_ONLY_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+type ConfigLoader = Box<dyn Fn() -> Result<Config, ConfigError> + Send>;
+
+/// The daemon itself: device events come in from the monitor, and are
+/// recorded, audited, notified about and handed to plugins. The binary
+/// loads the config, forks and sets up logging, then builds one of these
+/// and runs it.
+pub struct Pipeline {
+    version: &'static str,
+    config: Config,
+    config_path: PathBuf,
+    load_config: ConfigLoader,
+    config_modified: Option<SystemTime>,
+    logging: Option<Logging>,
+    create_monitor: CreateMonitor,
+}
+
+impl Pipeline {
+    /// A daemon that runs on `config`, watching with its configured
+    /// backend. `load_config` loads the file at `config_path` again the way
+    /// `config` was loaded, for SIGHUP and whenever the file changes.
+    /// `version` is what `hddmond status` and the audit trail say is
+    /// running.
+    pub fn new(
+        version: &'static str,
+        config: Config,
+        config_path: impl Into<PathBuf>,
+        load_config: impl Fn() -> Result<Config, ConfigError> + Send + 'static,
+    ) -> Self {
+        let config_path = config_path.into();
+        Self {
+            version,
+            config,
+            config_modified: config::modified(&config_path),
+            config_path,
+            load_config: Box::new(load_config),
+            logging: None,
+            create_monitor: Box::new(backend::create_monitor),
+        }
+    }
+
+    /// Reopens `logging`'s file on SIGUSR1, and changes its filter when the
+    /// log level is reloaded. Without it, logging is left to whoever set it
+    /// up.
+    pub fn with_logging(mut self, logging: Logging) -> Self {
+        self.logging = Some(logging);
+        self
+    }
+
+    /// Watches with the monitor `create` makes, on the monitor's thread,
+    /// rather than the configured backend's. For driving the daemon with
+    /// something like a
+    /// [`MockDeviceMonitor`](crate::scanners::mock_scanner::MockDeviceMonitor).
+    pub fn with_monitor(
+        mut self,
+        create: impl FnOnce(&Config) -> Result<(Backend, Box<dyn DeviceMonitor>), ScanError>
+            + Send
+            + 'static,
+    ) -> Self {
+        self.create_monitor = Box::new(create);
+        self
+    }
+
+    /// Runs the daemon until the monitor stops, a SIGTERM or SIGINT, or a
+    /// `shutdown` over the control socket, then shuts everything down in
+    /// order. Only fails if something it needs can't be set up at the
+    /// start.
+    pub async fn run(self) -> Result<(), PipelineError> {
+        let Self {
+            version,
+            mut config,
+            config_path,
+            load_config,
+            mut config_modified,
+            logging,
+            create_monitor,
+        } = self;
+
+        let mut events = EventReader::spawn_with(&config, create_monitor)
+            .await
+            .map_err(PipelineError::Scan)?;
+
+        let mut registry = Registry::open(&config.storage.path).map_err(PipelineError::Registry)?;
+        registry.set_event_limit(config.storage.events_per_device);
+        registry.set_session_grace(config.storage.session_grace());
+        registry.set_identity(&config.storage.identity, &config.storage.shared_serials);
+        registry.reset_presence().map_err(PipelineError::Registry)?;
+
+        let mut audit = AuditLog::open(&config.audit).map_err(PipelineError::Audit)?;
+        audit.record(
+            "daemon_started",
+            json!({ "version": version, "backend": events.backend.as_str() }),
+        );
+
+        // Not being able to answer `hddmond status` is no reason not to run.
+        let (_control_socket, mut control_requests) = config
+            .daemon
+            .control_socket
+            .as_deref()
+            .and_then(|path| {
+                ControlSocket::bind(path)
+                    .map_err(|e| warn!("No control socket, `hddmond status` won't work: {:#}", e))
+                    .ok()
+            })
+            .unzip();
+
+        // Opened while we're root, see ScsiHosts.
+        let scsi_hosts = config.monitor.rescan_scsi_hosts.then(|| {
+            let hosts = ScsiHosts::open(Path::new("/sys"));
+            if hosts.is_empty() {
+                warn!("monitor.rescan_scsi_hosts is set, but there are no SCSI hosts to rescan.");
+            }
+            Arc::new(hosts)
+        });
+
+        // Everything that needs root is open by now. Plugins, the only
+        // thing running untrusted code, start after this.
+        daemon::drop_privileges(&config.daemon).map_err(PipelineError::Daemon)?;
+        if !daemon::can_write_devices() {
+            warn!(
+                "Running without write access to block devices (not root or in the disk group), \
+                 nothing destructive can run."
+            );
+        }
+
+        let mut device_policy = DevicePolicy::new(config.devices.clone());
+        device_policy.add_identities(std::mem::take(&mut events.identities));
+        let health = Health::new();
+        let mut notifier_host =
+            NotifierHost::new(&config.notifiers, &health).map_err(PipelineError::Notifier)?;
+        let acknowledged = registry
+            .alerts(None, Some(AlertState::Acknowledged))
+            .map_err(PipelineError::Registry)?;
+        for alert in acknowledged {
+            if let Some(key) = alert_key(&alert) {
+                notifier_host.silence(key);
+            }
+        }
+        let power = PowerManager::new(&config.power);
+        let mut prober = Prober::new();
+        let identify_queue = IdentifyQueue::new(&config.identify);
+        let mut capacity_checker =
+            CapacityChecker::new(config.smartctl.path.as_deref(), identify_queue.clone());
+        let mut capability_prober =
+            CapabilityProber::new(config.smartctl.path.as_deref(), identify_queue.clone());
+        let mut read_only_watch = ReadOnlyWatch::new();
+        let mut mmc_health_reader = MmcHealthReader::new();
+        let mut link_health_reader = LinkHealthReader::new();
+        let status = StatusCollector::new(version, events.backend, health.clone(), identify_queue);
+
+        let mut plugin_host = PluginHost::load_dir(
+            &config.plugin_host.dir,
+            config.plugin_host.limits(),
+            config.plugins.clone(),
+        )
+        .await
+        .map_err(PipelineError::Plugin)?;
+
+        // Config file changes are picked up on the same interval.
+        let mut plugin_reload_interval = reload_interval(&config);
+
+        let mut maintenance_interval = interval(storage::MAINTENANCE_CHECK_INTERVAL);
+        // Firmware can be flashed without the drive ever leaving, and not every
+        // update ends in a change event.
+        let mut reidentify_interval =
+            interval_at(Instant::now() + REIDENTIFY_INTERVAL, REIDENTIFY_INTERVAL);
+        let mut read_only_interval = interval(READ_ONLY_CHECK_INTERVAL);
+        let mut audit_sync_interval = interval(audit::sync_interval(&config.audit));
+        let mut disk_guard = DiskGuard::new(&config);
+        let mut disk_guard_interval = interval(config.disk_guard.check_interval());
+        let mut link_health_interval = interval_at(
+            Instant::now() + config.link_health.check_interval(),
+            config.link_health.check_interval(),
+        );
+
+        let signal_error = |signal| move |error| PipelineError::Signal { signal, error };
+        let mut shutdown = Shutdown::new().map_err(signal_error("SIGTERM and SIGINT"))?;
+        let mut sighup = signal(SignalKind::hangup()).map_err(signal_error("SIGHUP"))?;
+        let mut sigusr1 = signal(SignalKind::user_defined1()).map_err(signal_error("SIGUSR1"))?;
+
+        loop {
+            tokio::select! {
+                event = events.next() => {
+                    let event = match event {
+                        Some(event) => event,
+                        None => {
+                            error!("The device monitor stopped, shutting down.");
+                            break;
+                        }
+                    };
+
+                    let span = tracing::info_span!("device", device = event.device());
+                    let _span = span.enter();
+
+                    let event = match device_policy.filter(event) {
+                        Some(event) => event,
+                        None => continue,
+                    };
+
+                    plugin_host.dispatch(&event);
+
+                    match event {
+                        ScanEventType::DeviceFound(device) => {
+                            let identity = device_policy.identity(&device);
+                            let uuid = registry.device_found(&identity).unwrap_or_else(|e| {
+                                error!("{}", e);
+                                None
+                            });
+                            let quarantine = registry.quarantine(&identity).unwrap_or_else(|e| {
+                                error!("{}", e);
+                                None
+                            });
+                            device_policy.set_quarantined(&device, quarantine.is_some());
+                            let alias = registry
+                                .template_alias(&identity, device_policy.templated_alias(&identity).as_deref())
+                                .unwrap_or_else(|e| {
+                                    warn!("Not naming {} after the alias template: {}", identity.name, e);
+                                    None
+                                });
+                            audit.record(
+                                "device_found",
+                                json!({
+                                    "device": identity.name,
+                                    "uuid": uuid,
+                                    "alias": alias,
+                                    "serial": identity.serial,
+                                    "model": identity.model,
+                                    "wwn": identity.wwn,
+                                    "protected": device_policy.is_protected(&device),
+                                    "quarantine": quarantine,
+                                }),
+                            );
+                            notifier_host.notify(Notification::device_found(&identity).with_alias(alias));
+                            firmware_seen(&identity, &mut registry, &mut notifier_host, &mut audit, &power);
+                            if device_policy.may_change_settings(&device) {
+                                power.device_found(&identity);
+                            }
+                            prober.probe(&identity);
+                            capacity_checker.check(&identity);
+                            capability_prober.probe(&identity, device_policy.is_protected(&device));
+                            mmc_health_reader.read(&identity);
+                            link_health_reader.read(&identity);
+
+                            if let Some(reason) = &quarantine {
+                                info!("Found device: {} (quarantined, {})", device, reason);
+                            } else if device_policy.is_protected(&device) {
+                                info!("Found device: {} (protected)", device);
+                            } else {
+                                info!("Found device: {}", device);
+                            }
+                        }
+                        ScanEventType::DeviceLost(device) => {
+                            let name = device_policy::device_name(&device);
+                            let record = registry.device_lost(name).unwrap_or_else(|e| {
+                                error!("{}", e);
+                                None
+                            });
+                            audit.record(
+                                "device_lost",
+                                json!({
+                                    "device": name,
+                                    "serial": record.as_ref().and_then(|record| record.serial.as_deref()),
+                                }),
+                            );
+                            notifier_host.notify(Notification::device_lost(name, record.as_ref()));
+                            read_only_watch.forget(name);
+                            link_health_reader.forget(name);
+
+                            info!("Lost device: {}", device);
+                        }
+                        ScanEventType::DeviceChanged(device) => {
+                            // Most often a new partition table.
+                            debug!("Device changed: {}", device);
+                            let identity = device_policy.identity(&device);
+                            if let Err(e) = registry.log_event(&identity, "Changed") {
+                                error!("{}", e);
+                            }
+                            audit.record(
+                                "device_changed",
+                                json!({ "device": identity.name, "serial": identity.serial }),
+                            );
+                            firmware_seen(&identity, &mut registry, &mut notifier_host, &mut audit, &power);
+                            prober.probe(&identity);
+                            // The kernel says when it flips the ro flag with a
+                            // change event, no need to wait for the next check.
+                            if let Some(change) = read_only_watch.check(Path::new("/sys"), &identity.name) {
+                                read_only_changed(
+                                    &identity,
+                                    change,
+                                    device_policy.is_protected(&device),
+                                    &mut registry,
+                                    &mut notifier_host,
+                                    &mut audit,
+                                    &capability_prober,
+                                );
+                            }
+                        }
+                        ScanEventType::Unknown(device) => {
+                            info!("Unknown action for device: {}", device);
+                        }
+                    }
+                }
+                Some((identity, contents)) = prober.next() => {
+                    let summary = match &contents {
+                        Ok(contents) => probe::summarize(contents),
+                        Err(e) => format!("Probe failed: {}", e.error),
+                    };
+                    if let Err(e) = registry.log_event(&identity, &summary) {
+                        error!("{}", e);
+                    }
+                    audit.record(
+                        "device_probed",
+                        json!({ "device": identity.name, "serial": identity.serial, "result": summary }),
+                    );
+
+                    match contents {
+                        Ok(contents) => {
+                            if !contents.os_hints.is_empty() {
+                                info!(
+                                    "{} looks like it has an OS on it: {}",
+                                    identity.name,
+                                    contents.os_hints.join(", ")
+                                );
+                            }
+                            if let Err(e) = registry.set_contents(&identity, &contents) {
+                                error!("{}", e);
+                            }
+                        }
+                        Err(e) => warn!("{:#}", e),
+                    }
+                }
+                Some((identity, check)) = capacity_checker.next() => {
+                    if !check.agrees() {
+                        warn!(
+                            "The kernel and smartctl disagree about the capacity of {}, {}. Going by the kernel.",
+                            identity.name, check
+                        );
+                        if let Err(e) = registry.log_event(&identity, &format!("Capacity mismatch: {}", check)) {
+                            error!("{}", e);
+                        }
+                        audit.record(
+                            "capacity_mismatch",
+                            json!({
+                                "device": identity.name,
+                                "serial": identity.serial,
+                                "detail": check.to_string(),
+                            }),
+                        );
+                    }
+                    if let Err(e) = registry.set_capacity_mismatch(&identity, !check.agrees()) {
+                        error!("{}", e);
+                    }
+                }
+                Some((identity, capabilities)) = capability_prober.next() => {
+                    debug!("{} can: {}", identity.name, capabilities);
+                    let known = read_only_watch.set(&identity.name, capabilities.read_only);
+                    // Going read-only later is warned about as it happens.
+                    if let (Some(read_only), None) = (capabilities.read_only, known) {
+                        warn!("{} is read-only, {}.", identity.name, read_only);
+                    }
+                    if let Err(e) = registry.set_capabilities(&identity, &capabilities) {
+                        error!("{}", e);
+                    }
+                }
+                Some((identity, health)) = mmc_health_reader.next() => {
+                    debug!("{}'s health: {}", identity.name, health);
+                    if let Some(concern) = health.concern() {
+                        warn!("{} is wearing out, {}: {}", identity.name, concern, health);
+                    }
+                    if let Err(e) = registry.set_mmc_health(&identity, &health) {
+                        error!("{}", e);
+                    }
+                }
+                Some((identity, health)) = link_health_reader.next() => {
+                    link_health_read(
+                        &identity,
+                        &health,
+                        config.link_health.max_errors_per_hour,
+                        &mut link_health_reader,
+                        &mut registry,
+                        &mut notifier_host,
+                        &mut audit,
+                    );
+                }
+                Some(request) = next_request(&mut control_requests) => {
+                    let response = match request.command.as_str() {
+                        "status" => {
+                            let status = status.collect(
+                                &registry,
+                                &notifier_host,
+                                &plugin_host,
+                                &link_health_reader.recent_errors(),
+                            );
+                            serde_json::to_string(&status).unwrap_or_default()
+                        }
+                        "rescan" => {
+                            rescan(
+                                events.rescanner.clone(),
+                                events.backend.as_str(),
+                                scsi_hosts.clone(),
+                                request.reply,
+                            );
+                            continue;
+                        }
+                        "shutdown" => {
+                            info!("Asked to shut down over the control socket.");
+                            audit.record("daemon_stopped", json!({ "command": "shutdown" }));
+                            let _ = request.reply.send(json!({ "shutting_down": true }).to_string());
+                            break;
+                        }
+                        command if command.starts_with("alerts ack ") => ack_alert(
+                            &command["alerts ack ".len()..],
+                            &mut registry,
+                            &mut notifier_host,
+                            &mut audit,
+                        ),
+                        command if command.starts_with("quarantine ") => quarantine(
+                            &command["quarantine ".len()..],
+                            &mut registry,
+                            &mut device_policy,
+                            &mut audit,
+                        ),
+                        #[cfg(feature = "faults")]
+                        command if command.starts_with("faults ") => {
+                            crate::faults::control(&command["faults ".len()..])
+                        }
+                        command => json!({
+                            "error": format!("Unknown command \"{}\"", command),
+                        })
+                        .to_string(),
+                    };
+                    // The client may have given up already.
+                    let _ = request.reply.send(response);
+                }
+                signal = shutdown.requested() => {
+                    info!("Got {}, shutting down.", signal);
+                    audit.record("daemon_stopped", json!({ "signal": signal }));
+                    break;
+                }
+                _ = audit_sync_interval.tick() => audit.sync(),
+                _ = disk_guard_interval.tick() => match disk_guard.check() {
+                    Some(SpaceChange::Low(space)) => {
+                        error!(
+                            "Running out of disk space, {}. Not keeping device event history or \
+                             compacting the database until there's more.",
+                            space
+                        );
+                        audit.record(
+                            "daemon_storage_low",
+                            json!({ "dir": space.dir, "free_bytes": space.free_bytes }),
+                        );
+                        registry.set_low_on_space(true);
+                    }
+                    Some(SpaceChange::Recovered) => {
+                        info!("There's enough disk space again, keeping device event history again.");
+                        audit.record("daemon_storage_recovered", json!({}));
+                        registry.set_low_on_space(false);
+                    }
+                    None => {}
+                },
+                _ = maintenance_interval.tick() => {
+                    if let Err(e) = registry.maintain(&config.storage) {
+                        error!("{}", e);
+                    }
+                }
+                _ = read_only_interval.tick() => {
+                    for name in read_only_watch.names() {
+                        if let Some(change) = read_only_watch.check(Path::new("/sys"), &name) {
+                            read_only_changed(
+                                &device_policy.identity(&name),
+                                change,
+                                device_policy.is_protected(&name),
+                                &mut registry,
+                                &mut notifier_host,
+                                &mut audit,
+                                &capability_prober,
+                            );
+                        }
+                    }
+                }
+                _ = link_health_interval.tick() => {
+                    let devices = registry.devices(false).unwrap_or_else(|e| {
+                        error!("{}", e);
+                        vec![]
+                    });
+                    for record in devices {
+                        link_health_reader.read(&device_policy.identity(&record.name));
+                    }
+                }
+                _ = reidentify_interval.tick() => {
+                    let devices = registry.devices(false).unwrap_or_else(|e| {
+                        error!("{}", e);
+                        vec![]
+                    });
+                    for record in devices {
+                        // Fresh from udev, identities the scanner handed over
+                        // at startup would never change.
+                        let identity = DeviceIdentity::lookup(&record.name);
+                        firmware_seen(&identity, &mut registry, &mut notifier_host, &mut audit, &power);
+                        // Wear only goes one way, but slowly.
+                        mmc_health_reader.read(&identity);
+                    }
+                }
+                _ = sigusr1.recv() => {
+                    match logging.as_ref().map(Logging::reopen) {
+                        Some(Ok(())) => info!("Got SIGUSR1, reopened the log file."),
+                        Some(Err(e)) => error!("Failed to reopen the log file: {}", e),
+                        None => debug!("Got SIGUSR1, but logging isn't ours to reopen."),
+                    }
+                }
+                _ = sighup.recv() => {
+                    info!("Got SIGHUP, reloading config.");
+                    config_modified = config::modified(&config_path);
+                    if reload_config(
+                        &load_config,
+                        &config_path,
+                        logging.as_ref(),
+                        &mut config,
+                        &mut plugin_host,
+                        &events,
+                    ) {
+                        audit.record("config_reloaded", json!({ "path": config_path }));
+                        plugin_reload_interval = reload_interval(&config);
+                    }
+
+                    if let Err(e) = plugin_host.reload().await {
+                        error!("Failed to reload plugins: {}", e);
+                    }
+                }
+                _ = plugin_reload_interval.tick() => {
+                    let modified = config::modified(&config_path);
+                    if modified != config_modified {
+                        config_modified = modified;
+                        if reload_config(
+                            &load_config,
+                            &config_path,
+                            logging.as_ref(),
+                            &mut config,
+                            &mut plugin_host,
+                            &events,
+                        ) {
+                            audit.record("config_reloaded", json!({ "path": config_path }));
+                            plugin_reload_interval = reload_interval(&config);
+                        }
+                    }
+
+                    if let Err(e) = plugin_host.reload().await {
+                        error!("Failed to reload plugins: {}", e);
+                    }
+                }
+            }
+        }
+
+        // No new events from here on. Plugins and notifiers get to finish what
+        // they were handed, the registry has nothing in flight, its writes are
+        // synchronous.
+        drop(events);
+        shutdown
+            .run(config.daemon.shutdown_timeout(), async {
+                plugin_host.shutdown().await;
+                notifier_host.shutdown().await;
+            })
+            .await;
+        drop(registry);
+        audit.close();
+
+        info!("Exiting...");
+
+        Ok(())
+    }
+}
+
+// Records the firmware the drive was seen with, and tells everyone once it
+// has changed.
+fn firmware_seen(
+    identity: &DeviceIdentity,
+    registry: &mut Registry,
+    notifier_host: &mut NotifierHost,
+    audit: &mut AuditLog,
+    power: &PowerManager,
+) {
+    let change = match registry.firmware_seen(identity) {
+        Ok(Some(change)) => change,
+        Ok(None) => return,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+
+    info!(
+        "The firmware of {} changed from {} to {}.",
+        identity.name, change.from, change.to
+    );
+    audit.record(
+        "firmware_changed",
+        json!({
+            "device": identity.name,
+            "serial": identity.serial,
+            "from": change.from,
+            "to": change.to,
+        }),
+    );
+    notifier_host.notify(
+        Notification::firmware_changed(identity, &change).with_alias(alias(registry, identity)),
+    );
+    power.firmware_changed(identity);
+}
+
+// `quarantine {"device": "bay-7", "reason": "..."}` over the control socket,
+// a null reason lets it out. Through the daemon rather than straight to the
+// registry, so it stops touching a present device right away.
+fn quarantine(
+    args: &str,
+    registry: &mut Registry,
+    device_policy: &mut DevicePolicy,
+    audit: &mut AuditLog,
+) -> String {
+    #[derive(Deserialize)]
+    struct Args {
+        device: String,
+        reason: Option<String>,
+    }
+
+    let args: Args = match serde_json::from_str(args) {
+        Ok(args) => args,
+        Err(e) => return json!({ "error": format!("Bad arguments: {}", e) }).to_string(),
+    };
+    let device = match registry.set_quarantine(&args.device, args.reason.as_deref()) {
+        Ok(device) => device,
+        Err(e) => return json!({ "error": e.to_string() }).to_string(),
+    };
+
+    if device.present {
+        device_policy.set_quarantined(&device.name, device.quarantine.is_some());
+    }
+    match &device.quarantine {
+        Some(reason) => {
+            warn!("{} is in quarantine: {}", device.name, reason);
+            audit.record(
+                "device_quarantined",
+                json!({ "device": device.name, "serial": device.serial, "reason": reason }),
+            );
+        }
+        None => {
+            info!("{} is out of quarantine.", device.name);
+            audit.record(
+                "device_released",
+                json!({ "device": device.name, "serial": device.serial }),
+            );
+        }
+    }
+    json!({ "device": device }).to_string()
+}
+
+// What the registry calls the device, for its notifications.
+fn alias(registry: &Registry, identity: &DeviceIdentity) -> Option<String> {
+    registry.alias(identity).unwrap_or_else(|e| {
+        error!("{}", e);
+        None
+    })
+}
+
+// A present device's ro flag flipped. Its capabilities are worked out again,
+// everything that writes is blocked or unblocked along with it.
+fn read_only_changed(
+    identity: &DeviceIdentity,
+    change: ReadOnlyChange,
+    protected: bool,
+    registry: &mut Registry,
+    notifier_host: &mut NotifierHost,
+    audit: &mut AuditLog,
+    capability_prober: &CapabilityProber,
+) {
+    let (summary, kind) = match change {
+        ReadOnlyChange::BecameReadOnly => {
+            warn!(
+                "{} became read-only, the kernel may have given up on writing to it.",
+                identity.name
+            );
+            notify_alert(
+                identity,
+                Notification::device_became_read_only(identity)
+                    .with_alias(alias(registry, identity)),
+                registry,
+                notifier_host,
+            );
+            ("Became read-only", "device_became_read_only")
+        }
+        ReadOnlyChange::BecameWritable => {
+            info!("{} is writable again.", identity.name);
+            clear_alert(
+                identity,
+                NotificationKind::DeviceBecameReadOnly,
+                registry,
+                notifier_host,
+                audit,
+            );
+            ("Became writable", "device_became_writable")
+        }
+    };
+    if let Err(e) = registry.log_event(identity, summary) {
+        error!("{}", e);
+    }
+    audit.record(
+        kind,
+        json!({ "device": identity.name, "serial": identity.serial }),
+    );
+    capability_prober.probe(identity, protected);
+}
+
+// Records a device's link error counters, and tells everyone about the
+// ports whose errors grew too fast.
+fn link_health_read(
+    identity: &DeviceIdentity,
+    health: &LinkHealth,
+    max_errors_per_hour: f64,
+    link_health_reader: &mut LinkHealthReader,
+    registry: &mut Registry,
+    notifier_host: &mut NotifierHost,
+    audit: &mut AuditLog,
+) {
+    let stored = registry
+        .set_link_health(identity, health)
+        .unwrap_or_else(|e| {
+            error!("{}", e);
+            None
+        });
+    let growing = link_health_reader.check(&identity.name, health, stored, max_errors_per_hour);
+    // Quiet for a day, whatever it was is over.
+    if growing.is_empty()
+        && !link_health_reader
+            .recent_errors()
+            .contains_key(&identity.name)
+    {
+        clear_alert(
+            identity,
+            NotificationKind::LinkErrorsGrowing,
+            registry,
+            notifier_host,
+            audit,
+        );
+    }
+    for growth in growing {
+        link_errors_growing(identity, &growth, registry, notifier_host, audit);
+    }
+}
+
+fn link_errors_growing(
+    identity: &DeviceIdentity,
+    growth: &LinkErrorGrowth,
+    registry: &mut Registry,
+    notifier_host: &mut NotifierHost,
+    audit: &mut AuditLog,
+) {
+    warn!(
+        "{} is getting link errors, {}. {}",
+        identity.name,
+        growth.describe(),
+        growth.blame()
+    );
+    if let Err(e) = registry.log_event(identity, &format!("Link errors: {}", growth.describe())) {
+        error!("{}", e);
+    }
+    audit.record(
+        "link_errors_growing",
+        json!({
+            "device": identity.name,
+            "serial": identity.serial,
+            "port": growth.port,
+            "upstream": growth.upstream,
+            "location": growth.location,
+            "errors": growth.errors,
+            "counters": growth.counters,
+            "also_growing": growth.also_growing,
+        }),
+    );
+    notify_alert(
+        identity,
+        Notification::link_errors_growing(identity, growth).with_alias(alias(registry, identity)),
+        registry,
+        notifier_host,
+    );
+}
+
+// Notifies about a health problem, unless someone acknowledged its alert.
+fn notify_alert(
+    identity: &DeviceIdentity,
+    notification: Notification,
+    registry: &mut Registry,
+    notifier_host: &mut NotifierHost,
+) {
+    let alert = registry
+        .raise_alert(
+            identity,
+            notification.event.as_str(),
+            &notification.summary(),
+            notifier_host.realert_after(),
+        )
+        .unwrap_or_else(|e| {
+            error!("{}", e);
+            None
+        });
+    match alert {
+        Some(alert) if alert.acked_at.is_some() => debug!(
+            "Not notifying {} for {}, alert {} was acknowledged",
+            notification.event.as_str(),
+            identity.name,
+            alert.id
+        ),
+        Some(alert) => {
+            notifier_host.unsilence(&notification.key());
+            notifier_host.notify(notification.with_alert(alert.id));
+        }
+        None => notifier_host.notify(notification),
+    }
+}
+
+// A health problem went away, its alert is closed along with any
+// acknowledgement.
+fn clear_alert(
+    identity: &DeviceIdentity,
+    kind: NotificationKind,
+    registry: &mut Registry,
+    notifier_host: &mut NotifierHost,
+    audit: &mut AuditLog,
+) {
+    let alert = match registry.clear_alert(identity, kind.as_str()) {
+        Ok(Some(alert)) => alert,
+        Ok(None) => return,
+        Err(e) => return error!("{}", e),
+    };
+    info!(
+        "Alert {} for {} cleared, {}",
+        alert.id, identity.name, alert.reason
+    );
+    audit.record(
+        "alert_cleared",
+        json!({
+            "alert": alert.id,
+            "device": identity.name,
+            "serial": identity.serial,
+            "reason": alert.reason,
+        }),
+    );
+    if let Some(key) = alert_key(&alert) {
+        notifier_host.unsilence(&key);
+    }
+}
+
+// The Notification::key of the notifications an alert was raised by.
+fn alert_key(alert: &Alert) -> Option<(String, NotificationKind)> {
+    let kind = NotificationKind::ALL
+        .iter()
+        .find(|kind| kind.as_str() == alert.reason)?;
+    let device = alert.serial.clone().unwrap_or_else(|| alert.device.clone());
+    Some((device, *kind))
+}
+
+// `alerts ack {"id": 12, "note": "..."}` over the control socket. Through
+// the daemon, so whatever its notifiers are holding for the alert is
+// dropped too.
+fn ack_alert(
+    args: &str,
+    registry: &mut Registry,
+    notifier_host: &mut NotifierHost,
+    audit: &mut AuditLog,
+) -> String {
+    #[derive(Deserialize)]
+    struct Args {
+        id: i64,
+        note: String,
+    }
+
+    let args: Args = match serde_json::from_str(args) {
+        Ok(args) => args,
+        Err(e) => return json!({ "error": format!("Bad arguments: {}", e) }).to_string(),
+    };
+    let alert = match registry.ack_alert(args.id, &args.note) {
+        Ok(alert) => alert,
+        Err(e) => return json!({ "error": e.to_string() }).to_string(),
+    };
+
+    info!(
+        "Alert {} for {} acknowledged: {}",
+        alert.id, alert.device, args.note
+    );
+    audit.record(
+        "alert_acknowledged",
+        json!({
+            "alert": alert.id,
+            "device": alert.device,
+            "serial": alert.serial,
+            "reason": alert.reason,
+            "note": args.note,
+        }),
+    );
+    if let Some(key) = alert_key(&alert) {
+        notifier_host.silence(key);
+    }
+    json!({ "alert": alert }).to_string()
+}
+
+// Waits for the next command on the control socket, or forever without one.
+async fn next_request(
+    requests: &mut Option<mpsc::Receiver<ControlRequest>>,
+) -> Option<ControlRequest> {
+    match requests {
+        Some(requests) => requests.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+// Answers `hddmond rescan` once the monitor has scanned, without holding
+// up the main loop meanwhile. The events it finds come through the main
+// loop like any others.
+fn rescan(
+    rescanner: Option<Rescanner>,
+    backend: &str,
+    scsi_hosts: Option<Arc<ScsiHosts>>,
+    reply: oneshot::Sender<String>,
+) {
+    let rescanner = match rescanner {
+        Some(rescanner) => rescanner,
+        None => {
+            let error = format!("The {} backend can't rescan", backend);
+            let _ = reply.send(json!({ "error": error }).to_string());
+            return;
+        }
+    };
+
+    tokio::spawn(async move {
+        if let Some(scsi_hosts) = scsi_hosts {
+            debug!("Rescanning {} SCSI hosts", scsi_hosts.len());
+            let _ = tokio::task::spawn_blocking(move || scsi_hosts.scan()).await;
+        }
+        let response = match rescanner.rescan().await {
+            Some(result) => serde_json::to_string(&result).unwrap_or_default(),
+            None => json!({ "error": "The monitor has stopped" }).to_string(),
+        };
+        let _ = reply.send(response);
+    });
+}
+
+// Re-reads the config file and applies whatever can change while the daemon
+// runs. If the new config doesn't load or validate, the current one stays in
+// effect untouched. Returns whether the config changed.
+fn reload_config(
+    load_config: &ConfigLoader,
+    config_path: &Path,
+    logging: Option<&Logging>,
+    config: &mut Config,
+    plugin_host: &mut PluginHost,
+    events: &EventReader,
+) -> bool {
+    if config::modified(config_path).is_none() {
+        info!(
+            "Config file {} doesn't exist, using defaults.",
+            config_path.display()
+        );
+    }
+
+    let new_config = match load_config() {
+        Ok(new_config) => new_config,
+        Err(e) => {
+            error!("Failed to reload config, keeping the old one: {:#}", e);
+            return false;
+        }
+    };
+
+    if new_config == *config {
+        debug!("Config didn't change.");
+        return false;
+    }
+
+    let restart_required = config.restart_required(&new_config);
+    if !restart_required.is_empty() {
+        warn!(
+            "Changes to {} require a restart, they were not applied.",
+            restart_required.join(", ")
+        );
+    }
+
+    // Already validated, this can't fail on the filter itself.
+    if let Some(Err(e)) = logging.map(|logging| logging.set_filter(&new_config.logging.level)) {
+        error!("Failed to apply the new log level: {}", e);
+    }
+    plugin_host.set_limits(new_config.plugin_host.limits());
+    plugin_host.set_configs(new_config.plugins.clone());
+    if new_config.udev.poll_interval_ms != config.udev.poll_interval_ms
+        || new_config.smartctl.scan_interval_secs != config.smartctl.scan_interval_secs
+    {
+        events.set_poll_interval(&new_config);
+    }
+
+    // Keep the settings that weren't applied as they are, so they're still
+    // reported as changed next time and `config` reflects what's running.
+    let Config {
+        logging: new_logging,
+        plugin_host: new_plugin_host,
+        plugins,
+        ..
+    } = new_config;
+    config.logging = LoggingConfig {
+        format: config.logging.format,
+        file: config.logging.file.clone(),
+        ..new_logging
+    };
+    config.plugins = plugins;
+    config.plugin_host = PluginHostConfig {
+        dir: config.plugin_host.dir.clone(),
+        ..new_plugin_host
+    };
+    config.udev.poll_interval_ms = new_config.udev.poll_interval_ms;
+    config.smartctl.scan_interval_secs = new_config.smartctl.scan_interval_secs;
+
+    info!("Reloaded config.");
+
+    true
+}
+
+fn reload_interval(config: &Config) -> Interval {
+    let period = config.plugin_host.reload_interval();
+    interval_at(Instant::now() + period, period)
+}
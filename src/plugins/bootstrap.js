@@ -0,0 +1,12 @@
+// Glue module the plugin host evaluates as the entrypoint of every plugin
+// runtime. It imports the plugin itself and hands its hooks to the host.
+import * as plugin from "{plugin_url}";
+
+globalThis.__hddmond = {
+  async dispatch(hook, payload) {
+    const fn = plugin[hook];
+    if (typeof fn === "function") {
+      await fn(payload);
+    }
+  },
+};
@@ -0,0 +1,43 @@
+// Type declarations for the hddmond plugin API, printed by
+// `hddmond plugin-types`. Hand maintained: when anything crossing the
+// Rust/JS boundary changes (PluginDevice, the hook names in plugin_host,
+// the ops in ops.js) this file has to change with it.
+
+/** A device as plugins see it, both as a hook argument and from getDevices(). */
+export interface Device {
+  /** Kernel name of the device, e.g. `sda`. */
+  name: string;
+}
+
+/** Log levels accepted by `hddmond.log()`, case insensitive. */
+export type LogLevel = "error" | "warn" | "info" | "debug" | "trace";
+
+/** Permissions a plugin can be granted. */
+export type Permission = "devices";
+
+/** A value from the plugin's `[plugins.<name>]` config table. */
+export type ConfigValue =
+  | string
+  | number
+  | boolean
+  | readonly ConfigValue[]
+  | { readonly [key: string]: ConfigValue };
+
+export interface Hddmond {
+  /** Logs `message` under the `plugin::<name>` target. */
+  log(level: LogLevel, message: unknown): void;
+  /** The plugin's `[plugins.<name>]` table from the daemon config, frozen. */
+  config(): { readonly [key: string]: ConfigValue };
+  /** Lists the devices currently present. Needs the `devices` permission. */
+  getDevices(): Promise<Device[]>;
+}
+
+/** The hooks a plugin module may export. All of them are optional. */
+export interface PluginHooks {
+  onDeviceFound?(device: Device): void | Promise<void>;
+  onDeviceLost?(device: Device): void | Promise<void>;
+}
+
+declare global {
+  const hddmond: Hddmond;
+}
@@ -0,0 +1,9 @@
+/// Each plugin's settings from the config.
+pub mod plugin_config;
+/// Loading plugins and dispatching device events to them.
+pub mod plugin_host;
+/// How much time and memory a plugin gets.
+pub mod plugin_limits;
+/// The hddmond API plugins call.
+pub mod plugin_ops;
+mod plugin_runtime;
@@ -0,0 +1,26 @@
+// The API surface plugins get from the daemon. Everything goes through the
+// hddmond ops registered by the plugin host, which do their own argument
+// and permission checks.
+((globalThis) => {
+  const { ops } = globalThis.Deno.core;
+
+  const deepFreeze = (value) => {
+    if (value !== null && typeof value === "object") {
+      Object.values(value).forEach(deepFreeze);
+      Object.freeze(value);
+    }
+    return value;
+  };
+
+  globalThis.hddmond = Object.freeze({
+    log(level, message) {
+      ops.op_hddmond_log(level, String(message));
+    },
+    config() {
+      return deepFreeze(ops.op_hddmond_config());
+    },
+    getDevices() {
+      return Deno.core.opAsync("op_hddmond_get_devices");
+    },
+  });
+})(globalThis);
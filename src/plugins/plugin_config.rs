@@ -0,0 +1,69 @@
+use std::fmt;
+
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+// Key fragments that mark a setting as a secret. Secrets are still handed
+// to the plugin as-is, they're only hidden when the config is printed.
+const SECRET_KEYS: &[&str] = &["key", "password", "secret", "token"];
+
+const REDACTED: &str = "<redacted>";
+
+/// The `[plugins.<name>]` table from the daemon config, exposed to the
+/// plugin through hddmond.config().
+#[derive(Clone, Default, PartialEq, Deserialize)]
+#[serde(transparent)]
+pub struct PluginConfig(toml::value::Table);
+
+impl PluginConfig {
+    /// The config as plugins see it. TOML datetimes don't have a JSON
+    /// equivalent, so they're passed along as strings.
+    pub fn to_json(&self) -> Value {
+        table_to_json(&self.0, false)
+    }
+}
+
+// Debug output is what ends up in logs, so secrets are redacted there.
+impl fmt::Debug for PluginConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", table_to_json(&self.0, true))
+    }
+}
+
+fn is_secret(key: &str) -> bool {
+    let key = key.to_lowercase();
+    SECRET_KEYS.iter().any(|secret| key.contains(secret))
+}
+
+fn table_to_json(table: &toml::value::Table, redact: bool) -> Value {
+    let map = table
+        .iter()
+        .map(|(key, value)| {
+            let value = if redact && is_secret(key) {
+                Value::String(REDACTED.to_string())
+            } else {
+                toml_to_json(value, redact)
+            };
+            (key.clone(), value)
+        })
+        .collect::<Map<_, _>>();
+
+    Value::Object(map)
+}
+
+fn toml_to_json(value: &toml::Value, redact: bool) -> Value {
+    match value {
+        toml::Value::String(s) => Value::String(s.clone()),
+        toml::Value::Integer(i) => Value::from(*i),
+        toml::Value::Float(f) => Value::from(*f),
+        toml::Value::Boolean(b) => Value::Bool(*b),
+        toml::Value::Datetime(dt) => Value::String(dt.to_string()),
+        toml::Value::Array(values) => Value::Array(
+            values
+                .iter()
+                .map(|value| toml_to_json(value, redact))
+                .collect(),
+        ),
+        toml::Value::Table(table) => table_to_json(table, redact),
+    }
+}
@@ -0,0 +1,399 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    thread::{self, JoinHandle},
+    time::SystemTime,
+};
+
+use deno_core::error::AnyError;
+use serde::Serialize;
+use serde_json::Value;
+use tokio::sync::{
+    mpsc::{self, error::TrySendError},
+    oneshot,
+};
+
+use crate::{error::PluginError, scanners::scanner::ScanEventType};
+
+use super::{
+    plugin_config::PluginConfig,
+    plugin_limits::PluginLimits,
+    plugin_ops::{PluginContext, PluginDevice, PluginPermissions, SharedDeviceList},
+    plugin_runtime::{self, HookCall},
+};
+
+// How many hook calls can pile up for one plugin before we start dropping
+// them. Each plugin has its own queue, so a slow plugin only loses its own
+// events and never holds up the scan stream.
+const PLUGIN_QUEUE_SIZE: usize = 64;
+
+struct LoadedPlugin {
+    name: String,
+    // The path as listed in the plugin directory, used to match the plugin
+    // up with its file on the next reload.
+    source: PathBuf,
+    sender: mpsc::Sender<HookCall>,
+    thread: JoinHandle<()>,
+}
+
+/// One plugin file, for `hddmond status`.
+#[derive(Debug, Clone, Serialize)]
+pub struct PluginStatus {
+    /// The plugin's name, its file name without the extension.
+    pub name: String,
+    /// The plugin file.
+    pub path: PathBuf,
+    /// "running", "stopped" if its runtime just exited, or "failed" if it
+    /// didn't load or was unloaded for stopping. A failed plugin is tried
+    /// again once its file changes.
+    pub state: &'static str,
+    /// Hook calls waiting for it.
+    pub queued: usize,
+}
+
+/// PluginHost loads every `.js` / `.mjs` file in the plugin directory as an
+/// ES module, each in its own JsRuntime, and forwards device events to the
+/// hooks those modules export:
+///
+///   export function onDeviceFound(device) {}
+///   export function onDeviceLost(device) {}
+///
+/// Plugins can call back into the daemon through the `hddmond` global, see
+/// plugin_ops. A plugin that fails to load, or throws inside a hook, is
+/// logged under its name and doesn't affect the other plugins or the daemon.
+///
+/// Calling reload() picks up changes to the directory: new files are loaded,
+/// modified files are reloaded into a fresh runtime, and deleted files are
+/// unloaded, all without touching the plugins that didn't change. The same
+/// goes for a plugin whose config changed, see set_configs().
+pub struct PluginHost {
+    dir: PathBuf,
+    limits: PluginLimits,
+    configs: HashMap<String, PluginConfig>,
+    plugins: Vec<LoadedPlugin>,
+    devices: SharedDeviceList,
+    // Modification time of every plugin file as of the last time we tried
+    // to load it, whether that worked or not. This keeps a broken plugin
+    // from being retried on every reload until the file changes again.
+    seen: HashMap<PathBuf, Option<SystemTime>>,
+}
+
+impl PluginHost {
+    /// Loads every plugin in `dir`. A missing directory is no plugins, one
+    /// that doesn't load is logged and marked failed.
+    pub async fn load_dir(
+        dir: &Path,
+        limits: PluginLimits,
+        configs: HashMap<String, PluginConfig>,
+    ) -> Result<Self, PluginError> {
+        let mut host = Self {
+            dir: dir.to_path_buf(),
+            limits,
+            configs,
+            plugins: vec![],
+            devices: SharedDeviceList::default(),
+            seen: HashMap::new(),
+        };
+
+        if !dir.is_dir() {
+            info!(
+                "Plugin directory {} doesn't exist, no plugins loaded.",
+                dir.display()
+            );
+            return Ok(host);
+        }
+
+        host.reload().await?;
+
+        Ok(host)
+    }
+
+    /// Brings the loaded plugins in line with the plugin directory. A plugin
+    /// that fails to reload keeps running its previous version.
+    pub async fn reload(&mut self) -> Result<(), PluginError> {
+        let paths = if self.dir.is_dir() {
+            plugin_paths(&self.dir)?
+        } else {
+            vec![]
+        };
+
+        self.seen.retain(|path, _| paths.contains(path));
+
+        let removed = self
+            .plugins
+            .iter()
+            .filter(|plugin| !paths.contains(&plugin.source))
+            .map(|plugin| plugin.source.clone())
+            .collect::<Vec<_>>();
+
+        for path in removed {
+            if let Some(index) = self.plugins.iter().position(|p| p.source == path) {
+                let plugin = self.plugins.remove(index);
+                info!("Unloading plugin {}", plugin.name);
+                retire(plugin).await;
+            }
+        }
+
+        for path in paths {
+            let modified = fs::metadata(&path).and_then(|m| m.modified()).ok();
+            if self.seen.get(&path) == Some(&modified) {
+                continue;
+            }
+            self.seen.insert(path.clone(), modified);
+
+            let config = self
+                .configs
+                .get(&plugin_name(&path))
+                .cloned()
+                .unwrap_or_default();
+
+            let new_plugin =
+                match Self::start_plugin(&path, config, self.devices.clone(), self.limits).await {
+                    Ok(plugin) => plugin,
+                    Err(e) => {
+                        error!("Failed to load plugin {}: {}", path.display(), e);
+                        continue;
+                    }
+                };
+
+            match self.plugins.iter().position(|p| p.source == path) {
+                Some(index) => {
+                    let old_plugin = std::mem::replace(&mut self.plugins[index], new_plugin);
+                    retire(old_plugin).await;
+                    info!("Reloaded plugin {}", self.plugins[index].name);
+                }
+                None => {
+                    info!("Loaded plugin {}", new_plugin.name);
+                    self.plugins.push(new_plugin);
+                }
+            }
+        }
+
+        // Keep dispatch order stable no matter what order things got
+        // loaded in.
+        self.plugins.sort_by(|a, b| a.source.cmp(&b.source));
+
+        Ok(())
+    }
+
+    /// Swaps in new plugin configs. Plugins whose config changed are picked
+    /// up again on the next reload(), the rest keep running untouched.
+    pub fn set_configs(&mut self, configs: HashMap<String, PluginConfig>) {
+        let changed = self
+            .seen
+            .keys()
+            .filter(|path| {
+                let name = plugin_name(path);
+                configs.get(&name) != self.configs.get(&name)
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for path in changed {
+            debug!("Config for plugin {} changed", plugin_name(&path));
+            self.seen.remove(&path);
+        }
+
+        self.configs = configs;
+    }
+
+    /// Swaps in new limits. Runtimes are created with their limits, so every
+    /// plugin is restarted on the next reload() to pick them up.
+    pub fn set_limits(&mut self, limits: PluginLimits) {
+        if limits != self.limits {
+            debug!("Plugin limits changed");
+            self.limits = limits;
+            self.seen.clear();
+        }
+    }
+
+    async fn start_plugin(
+        source: &Path,
+        config: PluginConfig,
+        devices: SharedDeviceList,
+        limits: PluginLimits,
+    ) -> Result<LoadedPlugin, AnyError> {
+        let path = source.canonicalize()?;
+        // Named after the file in the plugin directory rather than whatever
+        // it links to, that's the name the config refers to it by.
+        let name = plugin_name(source);
+        let context = PluginContext {
+            name: name.clone(),
+            permissions: PluginPermissions::default(),
+            config,
+            devices,
+        };
+
+        let (sender, receiver) = mpsc::channel(PLUGIN_QUEUE_SIZE);
+        let (ready_sender, ready_receiver) = oneshot::channel();
+
+        let thread = thread::Builder::new()
+            .name(format!("plugin-{}", name))
+            .spawn(move || plugin_runtime::run(context, limits, path, receiver, ready_sender))?;
+
+        ready_receiver.await??;
+
+        Ok(LoadedPlugin {
+            name,
+            source: source.to_path_buf(),
+            sender,
+            thread,
+        })
+    }
+
+    /// Every plugin file, loaded or not.
+    pub fn statuses(&self) -> Vec<PluginStatus> {
+        let mut statuses = self
+            .plugins
+            .iter()
+            .map(|plugin| PluginStatus {
+                name: plugin.name.clone(),
+                path: plugin.source.clone(),
+                state: if plugin.sender.is_closed() {
+                    "stopped"
+                } else {
+                    "running"
+                },
+                queued: plugin.sender.max_capacity() - plugin.sender.capacity(),
+            })
+            .collect::<Vec<_>>();
+
+        // Tried, but not running.
+        for path in self.seen.keys() {
+            if !self.plugins.iter().any(|plugin| &plugin.source == path) {
+                statuses.push(PluginStatus {
+                    name: plugin_name(path),
+                    path: path.clone(),
+                    state: "failed",
+                    queued: 0,
+                });
+            }
+        }
+
+        statuses.sort_by(|a, b| a.path.cmp(&b.path));
+        statuses
+    }
+
+    /// Queues the matching hook call on every plugin. This never waits on
+    /// a plugin; if a plugin's queue is full the event is dropped for it.
+    pub fn dispatch(&mut self, event: &ScanEventType) {
+        self.update_devices(event);
+
+        let (hook, payload) = match hook_for_event(event) {
+            Some(call) => call,
+            None => return,
+        };
+
+        self.plugins.retain(|plugin| {
+            let call = HookCall {
+                hook,
+                payload: payload.clone(),
+            };
+
+            match plugin.sender.try_send(call) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    warn!(
+                        "Plugin {} is falling behind, dropped {} call",
+                        plugin.name, hook
+                    );
+                    true
+                }
+                Err(TrySendError::Closed(_)) => {
+                    error!(
+                        "Plugin {} stopped, unloading it until it changes",
+                        plugin.name
+                    );
+                    false
+                }
+            }
+        });
+    }
+
+    fn update_devices(&self, event: &ScanEventType) {
+        let mut devices = self
+            .devices
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        match event {
+            ScanEventType::DeviceFound(name) => {
+                devices.insert(name.clone());
+            }
+            ScanEventType::DeviceLost(name) => {
+                devices.remove(name);
+            }
+            ScanEventType::DeviceChanged(_) | ScanEventType::Unknown(_) => {}
+        }
+    }
+
+    /// Closes every plugin's queue and waits for the plugins to finish the
+    /// calls they already have queued.
+    pub async fn shutdown(self) {
+        for plugin in self.plugins {
+            retire(plugin).await;
+        }
+    }
+}
+
+// Closes a plugin's queue and waits for its runtime to work through
+// whatever hook calls were already queued before it exits.
+async fn retire(plugin: LoadedPlugin) {
+    let LoadedPlugin {
+        name,
+        sender,
+        thread,
+        ..
+    } = plugin;
+
+    drop(sender);
+
+    let joined = tokio::task::spawn_blocking(move || thread.join()).await;
+    if !matches!(joined, Ok(Ok(()))) {
+        error!("Plugin {} panicked while shutting down", name);
+    }
+}
+
+fn plugin_paths(dir: &Path) -> Result<Vec<PathBuf>, PluginError> {
+    let mut paths = fs::read_dir(dir)
+        .map_err(|error| PluginError::ReadDir {
+            path: dir.to_path_buf(),
+            error,
+        })?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| is_plugin_file(path))
+        .collect::<Vec<_>>();
+
+    paths.sort();
+
+    Ok(paths)
+}
+
+fn hook_for_event(event: &ScanEventType) -> Option<(&'static str, Value)> {
+    let (hook, name) = match event {
+        ScanEventType::DeviceFound(name) => ("onDeviceFound", name),
+        ScanEventType::DeviceLost(name) => ("onDeviceLost", name),
+        ScanEventType::DeviceChanged(_) | ScanEventType::Unknown(_) => return None,
+    };
+
+    let device = PluginDevice { name: name.clone() };
+    serde_json::to_value(device)
+        .ok()
+        .map(|payload| (hook, payload))
+}
+
+fn is_plugin_file(path: &Path) -> bool {
+    path.is_file()
+        && matches!(
+            path.extension().and_then(|ext| ext.to_str()),
+            Some("js") | Some("mjs")
+        )
+}
+
+fn plugin_name(path: &Path) -> String {
+    path.file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.display().to_string())
+}
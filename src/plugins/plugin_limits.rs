@@ -0,0 +1,93 @@
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use deno_core::v8::IsolateHandle;
+
+/// Resource limits applied to every plugin runtime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PluginLimits {
+    /// Wall-clock time a single hook call (or loading the module) may take,
+    /// including any promises it awaits.
+    pub hook_timeout: Duration,
+    /// Maximum V8 heap size of the plugin's isolate, in bytes.
+    pub max_heap_bytes: usize,
+    /// How many async hddmond ops a plugin can have in flight at once.
+    pub max_pending_ops: usize,
+    /// How many times a plugin can hit one of the limits above before it's
+    /// quarantined (unloaded until its file changes).
+    pub max_violations: u32,
+}
+
+impl Default for PluginLimits {
+    fn default() -> Self {
+        Self {
+            hook_timeout: Duration::from_secs(5),
+            max_heap_bytes: 64 * 1024 * 1024,
+            max_pending_ops: 128,
+            max_violations: 3,
+        }
+    }
+}
+
+/// V8 has no notion of a timeout for running script, so the watchdog sits on
+/// its own thread and terminates whatever the isolate is executing once an
+/// armed deadline passes. This is what gets us out of a `while (true) {}`.
+pub struct Watchdog {
+    sender: mpsc::Sender<Option<Instant>>,
+    fired: Arc<AtomicBool>,
+}
+
+impl Watchdog {
+    /// Starts the watchdog thread for the isolate `isolate`, unarmed.
+    pub fn spawn(name: &str, isolate: IsolateHandle) -> io::Result<Self> {
+        let (sender, receiver) = mpsc::channel::<Option<Instant>>();
+        let fired = Arc::new(AtomicBool::new(false));
+
+        let thread_fired = fired.clone();
+        thread::Builder::new()
+            .name(format!("plugin-{}-watchdog", name))
+            .spawn(move || {
+                let mut deadline: Option<Instant> = None;
+                loop {
+                    let message = match deadline {
+                        Some(deadline) => receiver
+                            .recv_timeout(deadline.saturating_duration_since(Instant::now())),
+                        None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                    };
+
+                    match message {
+                        Ok(next_deadline) => deadline = next_deadline,
+                        Err(RecvTimeoutError::Timeout) => {
+                            thread_fired.store(true, Ordering::SeqCst);
+                            isolate.terminate_execution();
+                            deadline = None;
+                        }
+                        // The runtime is gone, so are we.
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+            })?;
+
+        Ok(Self { sender, fired })
+    }
+
+    /// Terminates the isolate unless disarmed within `timeout`.
+    pub fn arm(&self, timeout: Duration) {
+        self.fired.store(false, Ordering::SeqCst);
+        let _ = self.sender.send(Some(Instant::now() + timeout));
+    }
+
+    /// Returns whether the watchdog terminated execution since it was armed.
+    pub fn disarm(&self) -> bool {
+        let _ = self.sender.send(None);
+        self.fired.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,225 @@
+use std::{
+    cell::RefCell,
+    collections::{BTreeSet, HashSet},
+    future::Future,
+    rc::Rc,
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
+
+use deno_core::{
+    error::{generic_error, type_error, AnyError},
+    op, Extension, OpState,
+};
+use serde::Serialize;
+use serde_json::Value;
+
+use super::plugin_config::PluginConfig;
+
+/// TypeScript declarations for everything below that plugins can see,
+/// printed by `hddmond plugin-types`.
+pub const TYPE_DECLARATIONS: &str = include_str!("hddmond.d.ts");
+
+/// What a plugin sees of a device, both as a hook argument and from
+/// hddmond.getDevices().
+#[derive(Debug, Clone, Serialize)]
+pub struct PluginDevice {
+    /// Kernel name, e.g. `sda`.
+    pub name: String,
+}
+
+/// The devices currently present, kept up to date by the plugin host as it
+/// dispatches events and read by the plugins' getDevices() op.
+pub type SharedDeviceList = Arc<RwLock<BTreeSet<String>>>;
+
+/// Something a plugin has to be allowed to do by an op.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PluginPermission {
+    /// Read the list of present devices.
+    Devices,
+}
+
+impl PluginPermission {
+    /// The name it goes by in error messages and docs.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PluginPermission::Devices => "devices",
+        }
+    }
+}
+
+impl FromStr for PluginPermission {
+    type Err = AnyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "devices" => Ok(PluginPermission::Devices),
+            _ => Err(generic_error(format!(
+                "Unknown plugin permission \"{}\"",
+                s
+            ))),
+        }
+    }
+}
+
+/// What a plugin is allowed to do.
+#[derive(Debug, Clone)]
+pub struct PluginPermissions(HashSet<PluginPermission>);
+
+impl PluginPermissions {
+    /// Allows `permissions` and nothing else.
+    pub fn new(permissions: impl IntoIterator<Item = PluginPermission>) -> Self {
+        Self(permissions.into_iter().collect())
+    }
+
+    /// Whether `permission` is allowed.
+    pub fn contains(&self, permission: PluginPermission) -> bool {
+        self.0.contains(&permission)
+    }
+}
+
+impl Default for PluginPermissions {
+    // Every op we have so far only reads daemon state, so plugins get all
+    // of them unless they're explicitly restricted.
+    fn default() -> Self {
+        Self::new([PluginPermission::Devices])
+    }
+}
+
+/// Everything the hddmond ops need to know about the plugin calling them.
+#[derive(Debug, Clone)]
+pub struct PluginContext {
+    /// The plugin's name, its file name without the extension.
+    pub name: String,
+    /// What it's allowed to do.
+    pub permissions: PluginPermissions,
+    /// Its settings from the config, what hddmond.config() returns.
+    pub config: PluginConfig,
+    /// The devices hddmond.getDevices() lists.
+    pub devices: SharedDeviceList,
+}
+
+impl PluginContext {
+    fn require(&self, permission: PluginPermission) -> Result<(), AnyError> {
+        if self.permissions.contains(permission) {
+            Ok(())
+        } else {
+            Err(generic_error(format!(
+                "Plugin {} is missing the \"{}\" permission",
+                self.name,
+                permission.as_str()
+            )))
+        }
+    }
+}
+
+/// Bookkeeping for the async ops a plugin has in flight. A plugin calling
+/// ops in a loop without ever yielding would otherwise queue futures without
+/// bound.
+#[derive(Debug)]
+pub struct PendingOps {
+    count: usize,
+    max: usize,
+    /// Set whenever an op was refused for going over `max`, so the runtime
+    /// can count it as a limit violation.
+    pub rejected: bool,
+}
+
+impl PendingOps {
+    /// Nothing in flight, and at most `max` at once.
+    pub fn new(max: usize) -> Self {
+        Self {
+            count: 0,
+            max,
+            rejected: false,
+        }
+    }
+
+    fn start(&mut self, plugin: &str) -> Result<(), AnyError> {
+        if self.count >= self.max {
+            self.rejected = true;
+            return Err(generic_error(format!(
+                "Plugin {} has too many hddmond calls in flight (max {})",
+                plugin, self.max
+            )));
+        }
+
+        self.count += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) {
+        self.count = self.count.saturating_sub(1);
+    }
+}
+
+/// The hddmond ops and their JS bindings, for the plugin `context`.
+pub fn extension(context: PluginContext, max_pending_ops: usize) -> Extension {
+    Extension::builder()
+        .js(vec![("hddmond:ops.js", include_str!("ops.js"))])
+        .ops(vec![
+            op_hddmond_log::decl(),
+            op_hddmond_config::decl(),
+            op_hddmond_get_devices::decl(),
+        ])
+        .state(move |state| {
+            state.put(context.clone());
+            state.put(PendingOps::new(max_pending_ops));
+            Ok(())
+        })
+        .build()
+}
+
+#[op]
+fn op_hddmond_log(state: &mut OpState, level: String, message: String) -> Result<(), AnyError> {
+    let context = state.borrow::<PluginContext>();
+    let level = log::Level::from_str(&level)
+        .map_err(|_| type_error(format!("Invalid log level \"{}\"", level)))?;
+
+    log!(
+        target: &format!("plugin::{}", context.name),
+        level,
+        "{}",
+        message
+    );
+
+    Ok(())
+}
+
+#[op]
+fn op_hddmond_config(state: &mut OpState) -> Result<Value, AnyError> {
+    Ok(state.borrow::<PluginContext>().config.to_json())
+}
+
+/// Not an async fn on purpose: the permission and in-flight checks run when
+/// the plugin makes the call, not whenever the event loop gets to it.
+#[op]
+fn op_hddmond_get_devices(
+    state: Rc<RefCell<OpState>>,
+) -> Result<impl Future<Output = Result<Vec<PluginDevice>, AnyError>> + 'static, AnyError> {
+    let devices = {
+        let mut op_state = state.borrow_mut();
+
+        let context = op_state.borrow::<PluginContext>().clone();
+        context.require(PluginPermission::Devices)?;
+        op_state.borrow_mut::<PendingOps>().start(&context.name)?;
+
+        // Don't let a poisoned lock take the plugin down with it. Every
+        // update is a single insert or remove, so whatever is in there is
+        // consistent.
+        let devices = context
+            .devices
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        devices
+            .iter()
+            .map(|name| PluginDevice { name: name.clone() })
+            .collect::<Vec<_>>()
+    };
+
+    Ok(async move {
+        state.borrow_mut().borrow_mut::<PendingOps>().finish();
+        Ok(devices)
+    })
+}
@@ -0,0 +1,261 @@
+use std::{
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use deno_core::{
+    error::{generic_error, AnyError},
+    v8, FsModuleLoader, JsRuntime, ModuleSpecifier, RuntimeOptions,
+};
+use serde_json::Value;
+use tokio::sync::{mpsc, oneshot};
+use tracing::Instrument;
+
+use crate::usage;
+
+use super::{
+    plugin_limits::{PluginLimits, Watchdog},
+    plugin_ops::{self, PendingOps, PluginContext},
+};
+
+const BOOTSTRAP_JS: &str = include_str!("bootstrap.js");
+
+// A single hook invocation queued for a plugin, with the payload already
+// serialized so the plugin thread only has to hand it to JS.
+#[derive(Debug, Clone)]
+pub struct HookCall {
+    pub hook: &'static str,
+    pub payload: Value,
+}
+
+// Entrypoint of a plugin's thread. JsRuntime is !Send, so each plugin gets
+// its own thread and single-threaded tokio runtime, and is only ever talked
+// to through `receiver`. The result of loading the module is reported back
+// through `ready` before any hooks are dispatched.
+//
+// The thread exits when the host closes the queue, or when the plugin has
+// run into its limits too many times, which the host sees as the queue
+// being closed on its end.
+pub fn run(
+    context: PluginContext,
+    limits: PluginLimits,
+    path: PathBuf,
+    mut receiver: mpsc::Receiver<HookCall>,
+    ready: oneshot::Sender<Result<(), AnyError>>,
+) {
+    let rt = match tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+    {
+        Ok(rt) => rt,
+        Err(e) => {
+            let _ = ready.send(Err(e.into()));
+            return;
+        }
+    };
+
+    // Everything the plugin logs, and everything logged about it, carries
+    // its name.
+    let _span = tracing::info_span!("plugin", plugin = %context.name).entered();
+
+    rt.block_on(async move {
+        let name = context.name.clone();
+        let mut runtime = match PluginRuntime::load(context, limits, &path).await {
+            Ok(runtime) => {
+                let _ = ready.send(Ok(()));
+                runtime
+            }
+            Err(e) => {
+                let _ = ready.send(Err(e));
+                return;
+            }
+        };
+
+        let mut violations = 0;
+        while let Some(call) = receiver.recv().await {
+            let span = tracing::info_span!("hook", hook = call.hook);
+            let started = Instant::now();
+            let result = runtime.call_hook(&call).instrument(span).await;
+            usage::PLUGIN_HOOKS.record(started.elapsed());
+            match result {
+                Ok(()) => {}
+                Err(HookError::Failed(e)) => {
+                    error!("Plugin {} failed in {}: {}", name, call.hook, e);
+                }
+                Err(HookError::LimitExceeded(limit)) => {
+                    violations += 1;
+                    warn!(
+                        "Plugin {} hit its {} limit in {} ({}/{})",
+                        name, limit, call.hook, violations, limits.max_violations
+                    );
+
+                    if violations >= limits.max_violations {
+                        error!(
+                            "Plugin {} keeps exceeding its limits, quarantining it until it changes",
+                            name
+                        );
+                        break;
+                    }
+                }
+            }
+        }
+
+        debug!("Plugin {} runtime stopped", name);
+    });
+}
+
+enum HookError {
+    // The plugin itself threw or rejected.
+    Failed(AnyError),
+    // We cut the plugin off for going over one of its limits.
+    LimitExceeded(&'static str),
+}
+
+struct PluginRuntime {
+    runtime: JsRuntime,
+    limits: PluginLimits,
+    watchdog: Watchdog,
+    heap_exceeded: Arc<AtomicBool>,
+}
+
+impl PluginRuntime {
+    async fn load(
+        context: PluginContext,
+        limits: PluginLimits,
+        path: &Path,
+    ) -> Result<Self, AnyError> {
+        let name = context.name.clone();
+        let runtime = JsRuntime::new(RuntimeOptions {
+            module_loader: Some(Rc::new(FsModuleLoader)),
+            extensions: vec![plugin_ops::extension(context, limits.max_pending_ops)],
+            create_params: Some(v8::CreateParams::default().heap_limits(0, limits.max_heap_bytes)),
+            ..Default::default()
+        });
+
+        let mut plugin_runtime = Self::with_limits(&name, runtime, limits)?;
+
+        let plugin_url = ModuleSpecifier::from_file_path(path).map_err(|_| {
+            generic_error(format!("Can't build a module URL for {}", path.display()))
+        })?;
+
+        // The bootstrap module doesn't exist on disk, we hand its source
+        // to the runtime directly and let it import the actual plugin file.
+        let bootstrap_url = ModuleSpecifier::parse("hddmond:bootstrap")?;
+        let bootstrap_code = BOOTSTRAP_JS.replace(
+            "\"{plugin_url}\"",
+            &serde_json::to_string(plugin_url.as_str())?,
+        );
+
+        // Top level plugin code gets the same limits as a hook does.
+        plugin_runtime
+            .limited(|runtime| async move {
+                let module_id = runtime
+                    .load_main_module(&bootstrap_url, Some(bootstrap_code))
+                    .await?;
+                let evaluation = runtime.mod_evaluate(module_id);
+                runtime.run_event_loop(false).await?;
+                evaluation.await?
+            })
+            .await
+            .map_err(|e| match e {
+                HookError::Failed(e) => e,
+                HookError::LimitExceeded(limit) => {
+                    generic_error(format!("exceeded its {} limit while loading", limit))
+                }
+            })?;
+
+        Ok(plugin_runtime)
+    }
+
+    fn with_limits(
+        name: &str,
+        mut runtime: JsRuntime,
+        limits: PluginLimits,
+    ) -> Result<Self, AnyError> {
+        let isolate = runtime.v8_isolate().thread_safe_handle();
+        let watchdog = Watchdog::spawn(name, isolate.clone())?;
+
+        let heap_exceeded = Arc::new(AtomicBool::new(false));
+        let callback_heap_exceeded = heap_exceeded.clone();
+        runtime.add_near_heap_limit_callback(move |current_limit, _initial_limit| {
+            callback_heap_exceeded.store(true, Ordering::SeqCst);
+            isolate.terminate_execution();
+            // Give V8 enough room to unwind the terminated script instead
+            // of taking the whole process down with an OOM.
+            current_limit * 2
+        });
+
+        Ok(Self {
+            runtime,
+            limits,
+            watchdog,
+            heap_exceeded,
+        })
+    }
+
+    async fn call_hook(&mut self, call: &HookCall) -> Result<(), HookError> {
+        let script = format!(
+            "globalThis.__hddmond.dispatch({}, {})",
+            serde_json::to_string(call.hook).map_err(|e| HookError::Failed(e.into()))?,
+            call.payload
+        );
+
+        self.limited(|runtime| async move {
+            // dispatch() is async, so this is always a promise we need to
+            // drive to completion on the runtime's event loop.
+            let promise = runtime.execute_script(call.hook, &script)?;
+            runtime.resolve_value(promise).await?;
+            Ok(())
+        })
+        .await
+    }
+
+    // Runs `f` against the runtime with every limit enforced, and works out
+    // afterwards whether a failure was the plugin's own or one of ours.
+    async fn limited<'a, F, Fut>(&'a mut self, f: F) -> Result<(), HookError>
+    where
+        F: FnOnce(&'a mut JsRuntime) -> Fut,
+        Fut: std::future::Future<Output = Result<(), AnyError>> + 'a,
+    {
+        let Self {
+            runtime,
+            limits,
+            watchdog,
+            heap_exceeded,
+        } = self;
+
+        let isolate = runtime.v8_isolate().thread_safe_handle();
+        let op_state = runtime.op_state();
+
+        // A watchdog that fired right as the last call finished could have
+        // left a termination pending, don't let it hit this call.
+        isolate.cancel_terminate_execution();
+        heap_exceeded.store(false, Ordering::SeqCst);
+        op_state.borrow_mut().borrow_mut::<PendingOps>().rejected = false;
+
+        watchdog.arm(limits.hook_timeout);
+        let result = tokio::time::timeout(limits.hook_timeout, f(runtime)).await;
+        let watchdog_fired = watchdog.disarm();
+
+        let heap_exceeded = heap_exceeded.load(Ordering::SeqCst);
+        let ops_rejected = op_state.borrow().borrow::<PendingOps>().rejected;
+
+        if watchdog_fired || heap_exceeded {
+            isolate.cancel_terminate_execution();
+        }
+
+        match result {
+            _ if heap_exceeded => Err(HookError::LimitExceeded("heap")),
+            _ if watchdog_fired => Err(HookError::LimitExceeded("time")),
+            Err(_) => Err(HookError::LimitExceeded("time")),
+            _ if ops_rejected => Err(HookError::LimitExceeded("pending ops")),
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e)) => Err(HookError::Failed(e)),
+        }
+    }
+}
@@ -0,0 +1,524 @@
+use std::{
+    collections::HashSet,
+    fmt,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use serde::Serialize;
+
+use crate::{
+    config::{PowerConfig, PowerPolicy},
+    device_policy::{self, DeviceIdentity},
+    error::{PowerError, ToolError},
+    usage,
+};
+
+/// The longest standby timeout hdparm -S can set, 5.5 hours.
+pub const MAX_STANDBY_SECS: u64 = 11 * 30 * 60;
+
+/// What hdparm -C says the drive is doing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PowerState {
+    /// Spinning, whether or not it's doing anything.
+    ActiveIdle,
+    /// Spun down, wakes up on the next access.
+    Standby,
+    /// Spun down harder, needs a reset to wake up.
+    Sleeping,
+    /// hdparm said something we don't know.
+    Unknown,
+}
+
+impl PowerState {
+    /// What `hddmond power-state` prints for it.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PowerState::ActiveIdle => "active/idle",
+            PowerState::Standby => "standby",
+            PowerState::Sleeping => "sleeping",
+            PowerState::Unknown => "unknown",
+        }
+    }
+}
+
+impl fmt::Display for PowerState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// ATA power management through hdparm. Every call runs hdparm and waits
+/// for it, so keep them off the runtime's threads.
+#[derive(Debug, Clone)]
+pub struct Hdparm {
+    path: PathBuf,
+}
+
+impl Hdparm {
+    /// Looks hdparm up in PATH when `path` isn't given.
+    pub fn new(path: Option<&Path>) -> Self {
+        Self {
+            path: path.unwrap_or_else(|| Path::new("hdparm")).to_path_buf(),
+        }
+    }
+
+    /// Sets the drive's APM level, see PowerPolicy::apm.
+    pub fn set_apm(&self, device: &Path, level: u8) -> Result<(), PowerError> {
+        let output = self.run(&[format!("-B{}", level)], device)?;
+        // Drives without APM don't make hdparm fail, it only says so.
+        if output.contains("not supported") {
+            return Err(PowerError::NoApm {
+                device: device.to_path_buf(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Has the drive spin down after `secs` idle, rounded up to what it
+    /// can be told.
+    pub fn set_standby_timeout(&self, device: &Path, secs: u64) -> Result<(), PowerError> {
+        let value = standby_value(secs).ok_or(PowerError::StandbyTooLong { secs })?;
+        self.run(&[format!("-S{}", value)], device)?;
+        Ok(())
+    }
+
+    /// Asking doesn't wake a drive that's spun down.
+    pub fn power_state(&self, device: &Path) -> Result<PowerState, PowerError> {
+        let output = self.run(&["-C".to_string()], device)?;
+        parse_power_state(&output).ok_or_else(|| PowerError::UnknownState {
+            device: device.to_path_buf(),
+            output: output.trim().to_string(),
+        })
+    }
+
+    fn run(&self, args: &[String], device: &Path) -> Result<String, PowerError> {
+        let output = usage::HDPARM_WAIT
+            .time(|| Command::new(&self.path).args(args).arg(device).output())
+            .map_err(|error| {
+                PowerError::Hdparm(ToolError::Run {
+                    tool: self.path.clone(),
+                    error,
+                })
+            })?;
+
+        if !output.status.success() {
+            return Err(PowerError::Hdparm(ToolError::Failed {
+                command: format!("hdparm {} {}", args.join(" "), device.display()),
+                status: output.status,
+                stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            }));
+        }
+
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+}
+
+/// Turns a standby timeout into hdparm -S's encoding: 1 to 240 count 5
+/// second units, 241 to 251 count 30 minute ones from there. Anything in
+/// between is rounded up to the next step. None past MAX_STANDBY_SECS.
+pub fn standby_value(secs: u64) -> Option<u8> {
+    match secs {
+        0 => Some(0),
+        1..=1200 => Some(secs.div_ceil(5) as u8),
+        1201..=MAX_STANDBY_SECS => Some(240 + secs.div_ceil(30 * 60) as u8),
+        _ => None,
+    }
+}
+
+// Looks for the ` drive state is:  standby` line.
+fn parse_power_state(output: &str) -> Option<PowerState> {
+    let state = output
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("drive state is:"))?
+        .trim();
+
+    Some(match state {
+        "active/idle" | "active" | "idle" => PowerState::ActiveIdle,
+        "standby" => PowerState::Standby,
+        "sleeping" => PowerState::Sleeping,
+        _ => PowerState::Unknown,
+    })
+}
+
+/// Applies the power policies in the config to devices as they appear.
+///
+/// A setting that fails on a drive, most often because the drive doesn't
+/// support it, is logged once and not tried on that drive again, or every
+/// hotplug would log the same warning. Drives are told apart by serial where
+/// there is one, so that holds across names too.
+pub struct PowerManager {
+    hdparm: Hdparm,
+    policies: Vec<PowerPolicy>,
+    failed: Arc<Mutex<HashSet<(String, &'static str)>>>,
+}
+
+impl PowerManager {
+    /// Nothing has failed on any drive yet.
+    pub fn new(config: &PowerConfig) -> Self {
+        Self {
+            hdparm: Hdparm::new(config.hdparm.as_deref()),
+            policies: config.policies.clone(),
+            failed: Arc::default(),
+        }
+    }
+
+    /// Runs hdparm in the background, the event loop doesn't wait for it.
+    pub fn device_found(&self, identity: &DeviceIdentity) {
+        let policy = match self
+            .policies
+            .iter()
+            .find(|policy| device_policy::matches(&policy.device, identity))
+        {
+            Some(policy) => policy.clone(),
+            None => return,
+        };
+
+        // Simulated drives have a device node that doesn't exist, and
+        // there's nothing to do for those.
+        let node = match identity.paths.first() {
+            Some(node) if node.exists() => node.clone(),
+            _ => return,
+        };
+
+        let hdparm = self.hdparm.clone();
+        let failed = self.failed.clone();
+        let drive = drive_key(identity);
+        tokio::task::spawn_blocking(move || {
+            let failed = FailedSettings { failed, drive };
+            if let Some(level) = policy.apm {
+                failed.attempt("APM level", &node, || hdparm.set_apm(&node, level));
+            }
+            if let Some(secs) = policy.standby_after_secs {
+                failed.attempt("standby timeout", &node, || {
+                    hdparm.set_standby_timeout(&node, secs)
+                });
+            }
+        });
+    }
+
+    /// New firmware may well support what the old one didn't, everything
+    /// that failed on the drive gets tried again the next time it's found.
+    pub fn firmware_changed(&self, identity: &DeviceIdentity) {
+        let drive = drive_key(identity);
+        lock(&self.failed).retain(|(failed, _)| *failed != drive);
+    }
+}
+
+fn drive_key(identity: &DeviceIdentity) -> String {
+    identity
+        .serial
+        .clone()
+        .unwrap_or_else(|| identity.name.clone())
+}
+
+// The settings that failed on one drive.
+struct FailedSettings {
+    failed: Arc<Mutex<HashSet<(String, &'static str)>>>,
+    drive: String,
+}
+
+impl FailedSettings {
+    fn attempt(
+        &self,
+        setting: &'static str,
+        node: &Path,
+        set: impl FnOnce() -> Result<(), PowerError>,
+    ) {
+        let key = (self.drive.clone(), setting);
+        if lock(&self.failed).contains(&key) {
+            debug!(
+                "Not setting the {} of {}, it failed before",
+                setting,
+                node.display()
+            );
+            return;
+        }
+
+        match set() {
+            Ok(()) => info!("Set the {} of {}", setting, node.display()),
+            Err(e) => {
+                warn!(
+                    "Failed to set the {} of {}, not trying again: {:#}",
+                    setting,
+                    node.display(),
+                    e
+                );
+                lock(&self.failed).insert(key);
+            }
+        }
+    }
+}
+
+// Nothing panics while holding it, a poisoned lock is as good as any.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        os::unix::fs::PermissionsExt,
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    // A stand-in hdparm: a shell script that writes down how it was called
+    // and then runs `body`, and a file to play the device node.
+    struct FakeHdparm {
+        dir: TestDir,
+    }
+
+    impl FakeHdparm {
+        fn new(name: &str, body: &str) -> Self {
+            let dir = TestDir::new(&format!("hdparm-{}", name));
+
+            let script = dir.join("hdparm");
+            fs::write(
+                &script,
+                format!(
+                    "#!/bin/sh\necho \"$*\" >> {}\n{}\n",
+                    dir.join("calls").display(),
+                    body
+                ),
+            )
+            .unwrap();
+            fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+            fs::write(dir.join("sdx"), "").unwrap();
+            Self { dir }
+        }
+
+        fn hdparm(&self) -> Hdparm {
+            Hdparm::new(Some(&self.dir.join("hdparm")))
+        }
+
+        fn node(&self) -> PathBuf {
+            self.dir.join("sdx")
+        }
+
+        // Every call's arguments, the device node shortened to `sdx`.
+        fn calls(&self) -> Vec<String> {
+            fs::read_to_string(self.dir.join("calls"))
+                .unwrap_or_default()
+                .lines()
+                .map(|line| line.replace(&self.node().display().to_string(), "sdx"))
+                .collect()
+        }
+
+        fn wait_for_calls(&self, count: usize) -> Vec<String> {
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while self.calls().len() < count {
+                assert!(Instant::now() < deadline, "Only {:?}", self.calls());
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            self.calls()
+        }
+    }
+
+    #[test]
+    fn standby_values() {
+        for (secs, value) in [
+            (0, Some(0)),
+            (1, Some(1)),
+            (5, Some(1)),
+            (6, Some(2)),
+            (600, Some(120)),
+            (1200, Some(240)),
+            // Past 20 minutes it's half hours.
+            (1201, Some(241)),
+            (1800, Some(241)),
+            (1801, Some(242)),
+            (3600, Some(242)),
+            (MAX_STANDBY_SECS, Some(251)),
+            (MAX_STANDBY_SECS + 1, None),
+            (u64::MAX, None),
+        ] {
+            assert_eq!(standby_value(secs), value, "{}s", secs);
+        }
+    }
+
+    #[test]
+    fn power_states_from_hdparm_output() {
+        for (output, state) in [
+            (
+                "\n/dev/sda:\n drive state is:  active/idle\n",
+                Some(PowerState::ActiveIdle),
+            ),
+            (
+                "\n/dev/sda:\n drive state is:  standby\n",
+                Some(PowerState::Standby),
+            ),
+            (
+                "\n/dev/sda:\n drive state is:  sleeping\n",
+                Some(PowerState::Sleeping),
+            ),
+            (
+                "\n/dev/sda:\n drive state is:  idle\n",
+                Some(PowerState::ActiveIdle),
+            ),
+            // What it says for drives that don't answer CHECK POWER MODE.
+            (
+                "\n/dev/sdb:\n drive state is:  unknown\n",
+                Some(PowerState::Unknown),
+            ),
+            (
+                "\n/dev/sdb:\n drive state is:  NVMe\n",
+                Some(PowerState::Unknown),
+            ),
+            ("\n/dev/sdb:\n", None),
+            ("", None),
+            ("drive state:  standby", None),
+        ] {
+            assert_eq!(parse_power_state(output), state, "{:?}", output);
+        }
+    }
+
+    #[test]
+    fn commands_hdparm_is_run_with() {
+        let fake = FakeHdparm::new(
+            "commands",
+            "[ \"$1\" = -C ] && printf '\\n%s:\\n drive state is:  standby\\n' \"$2\"\nexit 0",
+        );
+        let hdparm = fake.hdparm();
+
+        hdparm.set_apm(&fake.node(), 127).unwrap();
+        hdparm.set_standby_timeout(&fake.node(), 1800).unwrap();
+        hdparm.set_standby_timeout(&fake.node(), 0).unwrap();
+        assert_eq!(
+            hdparm.power_state(&fake.node()).unwrap(),
+            PowerState::Standby
+        );
+        // Too long to tell a drive, hdparm isn't even asked.
+        let error = hdparm
+            .set_standby_timeout(&fake.node(), MAX_STANDBY_SECS + 1)
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "A standby timeout of 19801s is longer than a drive can be told"
+        );
+
+        assert_eq!(
+            fake.calls(),
+            ["-B127 sdx", "-S241 sdx", "-S0 sdx", "-C sdx"]
+        );
+    }
+
+    #[test]
+    fn hdparm_failing_or_saying_no() {
+        let fake = FakeHdparm::new(
+            "failing",
+            "case \"$1\" in\n\
+             -B*) printf ' setting Advanced Power Management level to 0x7f (127)\\n \
+             APM_level\t= not supported\\n' ;;\n\
+             -S*) echo 'SG_IO: bad/missing sense data' >&2; exit 5 ;;\n\
+             -C) echo ' nothing to see here' ;;\n\
+             esac",
+        );
+        let hdparm = fake.hdparm();
+        let node = fake.node();
+
+        assert_eq!(
+            hdparm.set_apm(&node, 127).unwrap_err().to_string(),
+            format!("{} doesn't support APM", node.display())
+        );
+        assert_eq!(
+            hdparm
+                .set_standby_timeout(&node, 60)
+                .unwrap_err()
+                .to_string(),
+            format!(
+                "hdparm -S12 {} failed (exit status: 5): SG_IO: bad/missing sense data",
+                node.display()
+            )
+        );
+        assert_eq!(
+            hdparm.power_state(&node).unwrap_err().to_string(),
+            format!(
+                "Can't tell the power state of {} from hdparm's output: nothing to see here",
+                node.display()
+            )
+        );
+
+        let missing = Hdparm::new(Some(Path::new("/nonexistent/hdparm")));
+        assert!(matches!(
+            missing.set_apm(&node, 1),
+            Err(PowerError::Hdparm(ToolError::Run { .. }))
+        ));
+    }
+
+    fn manager(fake: &FakeHdparm, policies: &str) -> PowerManager {
+        let mut config: PowerConfig = toml::from_str(policies).unwrap();
+        config.hdparm = Some(fake.dir.join("hdparm"));
+        PowerManager::new(&config)
+    }
+
+    fn drive(fake: &FakeHdparm, serial: &str, model: &str) -> DeviceIdentity {
+        DeviceIdentity {
+            name: "sdx".to_string(),
+            paths: vec![fake.node()],
+            serial: Some(serial.to_string()),
+            model: Some(model.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn the_first_matching_policy_is_applied() {
+        let fake = FakeHdparm::new("policies", "exit 0");
+        let manager = manager(
+            &fake,
+            "[[policies]]\ndevice = { model = \"ST*\" }\nstandby_after_secs = 600\n\
+             [[policies]]\ndevice = { model = \"WDC*\" }\napm = 127\nstandby_after_secs = 1200\n\
+             [[policies]]\ndevice = {}\napm = 254\n",
+        );
+
+        manager.device_found(&drive(&fake, "WD-1", "WDC WD40EFRX-68N32N0"));
+        assert_eq!(fake.wait_for_calls(2), ["-B127 sdx", "-S240 sdx"]);
+
+        // No policy, or a node that isn't there, and hdparm isn't run.
+        let none = PowerManager::new(&PowerConfig::default());
+        none.device_found(&drive(&fake, "WD-2", "WDC WD40EFRX-68N32N0"));
+        let mut simulated = drive(&fake, "SIM-1", "WDC WD40EFRX-68N32N0");
+        simulated.paths = vec![PathBuf::from("/dev/hddmond-sim/sim0")];
+        manager.device_found(&simulated);
+
+        manager.device_found(&drive(&fake, "ST-1", "ST8000VN004-2M2101"));
+        assert_eq!(fake.wait_for_calls(3)[2], "-S120 sdx");
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(fake.calls().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn failed_settings_arent_retried_until_the_firmware_changes() {
+        let fake = FakeHdparm::new(
+            "retries",
+            "case \"$1\" in -B*) echo ' APM_level\t= not supported' ;; esac",
+        );
+        let manager = manager(
+            &fake,
+            "[[policies]]\ndevice = {}\napm = 127\nstandby_after_secs = 60\n",
+        );
+        let drive = drive(&fake, "WD-1", "WDC WD40EFRX-68N32N0");
+
+        manager.device_found(&drive);
+        assert_eq!(fake.wait_for_calls(2), ["-B127 sdx", "-S12 sdx"]);
+        // Found again, under another name: only what worked is set again.
+        let renamed = DeviceIdentity {
+            name: "sdy".to_string(),
+            ..drive.clone()
+        };
+        manager.device_found(&renamed);
+        assert_eq!(fake.wait_for_calls(3)[2], "-S12 sdx");
+
+        manager.firmware_changed(&drive);
+        manager.device_found(&drive);
+        assert_eq!(fake.wait_for_calls(5)[3..], ["-B127 sdx", "-S12 sdx"]);
+    }
+}
@@ -0,0 +1,849 @@
+use std::{collections::BTreeSet, io, path::Path};
+
+use serde::Serialize;
+use tokio::sync::mpsc;
+
+use crate::{
+    block_io::BlockIo, capacity::Capacity, device_policy::DeviceIdentity, error::ProbeError, usage,
+};
+
+// Every filesystem signature we look for is within this much of the start
+// of a partition, btrfs's superblock at 64 KiB being the furthest out.
+// Nothing past it is ever read.
+const SUPERBLOCK_BYTES: usize = 0x11000;
+
+// GPT allows more, nothing real uses more than 128.
+const MAX_GPT_ENTRIES: u32 = 256;
+const MAX_GPT_ENTRY_SIZE: u32 = 4096;
+
+/// What's on a drive, as far as its partition table and superblocks tell.
+/// For triage before wiping, so it only has to be right about the common
+/// cases, and says nothing rather than guessing about the rest.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct DeviceContents {
+    /// None if there's no partition table we recognize.
+    pub partition_table: Option<PartitionTable>,
+    /// In the order they're in the table.
+    pub partitions: Vec<Partition>,
+    /// A filesystem on the whole device, with no partition table.
+    pub filesystem: Option<Filesystem>,
+    /// Why the drive looks like it has an operating system on it, empty if
+    /// it doesn't.
+    pub os_hints: Vec<String>,
+}
+
+/// The kind of partition table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PartitionTable {
+    /// A GUID partition table.
+    Gpt,
+    /// An MBR, or DOS, partition table.
+    Mbr,
+}
+
+/// One entry of the partition table.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Partition {
+    /// As the kernel would number it, sda1 is 1.
+    pub number: u32,
+    /// Where it starts on the device.
+    pub start_bytes: u64,
+    /// How big it is.
+    pub size_bytes: u64,
+    /// The GPT type GUID, or the MBR type byte in hex.
+    pub type_id: String,
+    /// What that type is, for the common ones.
+    pub type_name: Option<&'static str>,
+    /// GPT partitions can have a name.
+    pub name: Option<String>,
+    /// The MBR boot flag, or GPT's legacy BIOS bootable attribute.
+    pub bootable: bool,
+    /// What's on it, if we recognize it.
+    pub filesystem: Option<Filesystem>,
+}
+
+/// What a superblock says is there.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Filesystem {
+    /// Like ext4, ntfs or luks. Not every one is a filesystem strictly
+    /// speaking, LVM and md members are in here too.
+    pub kind: &'static str,
+    /// The label it was given, if any.
+    pub label: Option<String>,
+}
+
+/// Reads what's on `path`, a block device or an image of one, with
+/// `sector_size` byte logical sectors. Opens it read-only and only ever
+/// reads the partition table and the first SUPERBLOCK_BYTES of each
+/// partition.
+pub fn probe(path: &Path, sector_size: u64) -> io::Result<DeviceContents> {
+    let io = BlockIo::open(path, sector_size)?;
+    let mut contents = DeviceContents::default();
+
+    let start = read(&io, 0, SUPERBLOCK_BYTES)?;
+    let gpt_header = read(&io, sector_size, 512)?;
+
+    if gpt_header.starts_with(b"EFI PART") {
+        contents.partition_table = Some(PartitionTable::Gpt);
+        contents.partitions = gpt_partitions(&io, &gpt_header, sector_size)?;
+    } else if let Some(filesystem) = detect_filesystem(&start) {
+        // Checked before the MBR, FAT and NTFS boot sectors end in the same
+        // signature a partition table does.
+        contents.filesystem = Some(filesystem);
+    } else if let Some(partitions) = mbr_partitions(&start, sector_size) {
+        contents.partition_table = Some(PartitionTable::Mbr);
+        contents.partitions = partitions;
+    }
+
+    for partition in &mut contents.partitions {
+        // Extended partitions only hold more partition tables.
+        if matches!(partition.type_id.as_str(), "05" | "0f") {
+            continue;
+        }
+        let superblock = read(&io, partition.start_bytes, SUPERBLOCK_BYTES)?;
+        partition.filesystem = detect_filesystem(&superblock);
+    }
+
+    contents.os_hints = os_hints(&contents);
+    Ok(contents)
+}
+
+/// Probes a device by its kernel name, with its sector size from sysfs.
+pub fn probe_device(name: &str) -> io::Result<DeviceContents> {
+    let sector_size = Capacity::lookup(name).map_or(512, |capacity| capacity.logical_block_size);
+    probe(&Path::new("/dev").join(name), sector_size)
+}
+
+// Reads up to `len` bytes at `offset`, fewer if the device ends first.
+fn read(io: &BlockIo, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+    let buf = usage::PROBE_READ.time(|| io.read_vec(offset, len))?;
+    usage::PROBE_READ.add_bytes(buf.len() as u64);
+    Ok(buf)
+}
+
+fn u16_at(buf: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        buf.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        buf.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn u64_at(buf: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        buf.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+fn has(buf: &[u8], offset: usize, magic: &[u8]) -> bool {
+    buf.get(offset..offset + magic.len()) == Some(magic)
+}
+
+// A fixed size, NUL or space padded label. None if it's empty, or FAT's
+// placeholder for no label.
+fn label(buf: &[u8], offset: usize, len: usize) -> Option<String> {
+    let bytes = buf.get(offset..offset + len)?;
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(len);
+    let label = String::from_utf8_lossy(&bytes[..end]).trim().to_string();
+    (!label.is_empty() && label != "NO NAME").then_some(label)
+}
+
+// Looks for the signatures blkid would, for the filesystems and volume
+// formats drives coming in for triage tend to have on them.
+fn detect_filesystem(buf: &[u8]) -> Option<Filesystem> {
+    let filesystem = |kind, label| Some(Filesystem { kind, label });
+
+    if has(buf, 0, b"LUKS\xba\xbe") {
+        return filesystem("luks", None);
+    }
+    if has(buf, 0, b"XFSB") {
+        return filesystem("xfs", label(buf, 108, 12));
+    }
+    if has(buf, 3, b"NTFS    ") {
+        // The label lives in the MFT, not the boot sector.
+        return filesystem("ntfs", None);
+    }
+    if has(buf, 3, b"EXFAT   ") {
+        return filesystem("exfat", None);
+    }
+    if has(buf, 510, b"\x55\xaa") {
+        if has(buf, 82, b"FAT32   ") {
+            return filesystem("vfat", label(buf, 71, 11));
+        }
+        if has(buf, 54, b"FAT12   ") || has(buf, 54, b"FAT16   ") {
+            return filesystem("vfat", label(buf, 43, 11));
+        }
+    }
+    if has(buf, 512, b"LABELONE") && has(buf, 536, b"LVM2 001") {
+        return filesystem("lvm2_member", None);
+    }
+    if u32_at(buf, 4096) == Some(0xa92b_4efc) {
+        return filesystem("linux_raid_member", None);
+    }
+    if has(buf, 4086, b"SWAPSPACE2") || has(buf, 4086, b"SWAP-SPACE") {
+        return filesystem("swap", label(buf, 1024 + 28, 16));
+    }
+    if u16_at(buf, 1024 + 56) == Some(0xef53) {
+        let compat = u32_at(buf, 1024 + 92).unwrap_or(0);
+        let incompat = u32_at(buf, 1024 + 96).unwrap_or(0);
+        // Extents, 64bit or flex_bg make it ext4, a journal ext3.
+        let kind = if incompat & (0x40 | 0x80 | 0x200) != 0 {
+            "ext4"
+        } else if compat & 0x4 != 0 {
+            "ext3"
+        } else {
+            "ext2"
+        };
+        return filesystem(kind, label(buf, 1024 + 120, 16));
+    }
+    if has(buf, 0x10040, b"_BHRfS_M") {
+        return filesystem("btrfs", label(buf, 0x10000 + 0x12b, 256));
+    }
+    if has(buf, 0x8001, b"CD001") {
+        return filesystem("iso9660", label(buf, 0x8028, 32));
+    }
+
+    None
+}
+
+fn gpt_partitions(io: &BlockIo, header: &[u8], sector_size: u64) -> io::Result<Vec<Partition>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "GPT header is cut short");
+    let entries_lba = u64_at(header, 72).ok_or_else(invalid)?;
+    let count = u32_at(header, 80).ok_or_else(invalid)?.min(MAX_GPT_ENTRIES);
+    let entry_size = u32_at(header, 84).ok_or_else(invalid)?;
+    if !(128..=MAX_GPT_ENTRY_SIZE).contains(&entry_size) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("GPT entries of {} bytes don't make sense", entry_size),
+        ));
+    }
+
+    let entries = read(
+        io,
+        entries_lba.saturating_mul(sector_size),
+        (count * entry_size) as usize,
+    )?;
+
+    let partitions = entries
+        .chunks_exact(entry_size as usize)
+        .enumerate()
+        .filter(|(_, entry)| entry[..16].iter().any(|b| *b != 0))
+        .filter_map(|(index, entry)| {
+            let first = u64_at(entry, 32)?;
+            let last = u64_at(entry, 40)?;
+            let attributes = u64_at(entry, 48)?;
+            // Ends before it starts, nothing sane wrote that.
+            if last < first {
+                return None;
+            }
+            let type_id = guid(&entry[..16]);
+
+            let name = entry[56..128]
+                .chunks_exact(2)
+                .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+                .take_while(|c| *c != 0)
+                .collect::<Vec<_>>();
+            let name = String::from_utf16_lossy(&name);
+
+            Some(Partition {
+                number: index as u32 + 1,
+                start_bytes: first.saturating_mul(sector_size),
+                size_bytes: last
+                    .saturating_sub(first)
+                    .saturating_add(1)
+                    .saturating_mul(sector_size),
+                type_name: gpt_type_name(&type_id),
+                type_id,
+                name: (!name.is_empty()).then_some(name),
+                bootable: attributes & 0x4 != 0,
+                filesystem: None,
+            })
+        })
+        .collect();
+
+    Ok(partitions)
+}
+
+// GUIDs are stored with their first three fields little endian.
+fn guid(bytes: &[u8]) -> String {
+    format!(
+        "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-{}",
+        u32_at(bytes, 0).unwrap_or(0),
+        u16_at(bytes, 4).unwrap_or(0),
+        u16_at(bytes, 6).unwrap_or(0),
+        bytes[8],
+        bytes[9],
+        bytes[10..16]
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<String>()
+    )
+}
+
+fn gpt_type_name(type_id: &str) -> Option<&'static str> {
+    Some(match type_id {
+        "C12A7328-F81F-11D2-BA4B-00A0C93EC93B" => "EFI system",
+        "21686148-6449-6E6F-744E-656564454649" => "BIOS boot",
+        "E3C9E316-0B5C-4DB8-817D-F92DF00215AE" => "Microsoft reserved",
+        "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7" => "Microsoft basic data",
+        "DE94BBA4-06D1-4D40-A16A-BFD50179D6AC" => "Windows recovery",
+        "0FC63DAF-8483-4772-8E79-3D69D8477DE4" => "Linux filesystem",
+        "4F68BCE3-E8CD-4DB1-96E7-FBCAF984B709" => "Linux root (x86-64)",
+        "0657FD6D-A4AB-43C4-84E5-0933C84B4F4F" => "Linux swap",
+        "E6D6D379-F507-44C2-A23C-238F2A3DF928" => "Linux LVM",
+        "A19D880F-05FC-4D3B-A006-743F0F84911E" => "Linux RAID",
+        "7C3457EF-0000-11AA-AA11-00306543ECAC" => "Apple APFS",
+        "48465300-0000-11AA-AA11-00306543ECAC" => "Apple HFS+",
+        "6A898CC3-1DD2-11B2-99A6-080020736631" => "ZFS",
+        _ => return None,
+    })
+}
+
+// The four primary partitions. Logical partitions inside an extended one
+// aren't followed, the extended partition itself is listed.
+fn mbr_partitions(sector: &[u8], sector_size: u64) -> Option<Vec<Partition>> {
+    if !has(sector, 510, b"\x55\xaa") {
+        return None;
+    }
+
+    let mut partitions = vec![];
+    for index in 0..4 {
+        let entry = sector.get(446 + index * 16..446 + (index + 1) * 16)?;
+        let boot_flag = entry[0];
+        // Anything else there means this isn't a partition table after all.
+        if boot_flag != 0 && boot_flag != 0x80 {
+            return None;
+        }
+
+        let kind = entry[4];
+        let start = u32_at(entry, 8)? as u64;
+        let sectors = u32_at(entry, 12)? as u64;
+        if kind == 0 || sectors == 0 {
+            continue;
+        }
+
+        let type_id = format!("{:02x}", kind);
+        partitions.push(Partition {
+            number: index as u32 + 1,
+            start_bytes: start * sector_size,
+            size_bytes: sectors * sector_size,
+            type_name: mbr_type_name(kind),
+            type_id,
+            name: None,
+            bootable: boot_flag == 0x80,
+            filesystem: None,
+        });
+    }
+
+    (!partitions.is_empty()).then_some(partitions)
+}
+
+fn mbr_type_name(kind: u8) -> Option<&'static str> {
+    Some(match kind {
+        0x05 | 0x0f => "extended",
+        0x07 => "NTFS/exFAT",
+        0x0b | 0x0c => "FAT32",
+        0x0e => "FAT16",
+        0x27 => "Windows recovery",
+        0x82 => "Linux swap",
+        0x83 => "Linux",
+        0x8e => "Linux LVM",
+        0xee => "GPT protective",
+        0xef => "EFI system",
+        0xfd => "Linux RAID",
+        _ => return None,
+    })
+}
+
+/// One line for the device's events, like `Probed: gpt, vfat "ESP", ext4`.
+pub fn summarize(contents: &DeviceContents) -> String {
+    let describe = |filesystem: &Option<Filesystem>| match filesystem {
+        Some(Filesystem {
+            kind,
+            label: Some(label),
+        }) => format!("{} \"{}\"", kind, label),
+        Some(Filesystem { kind, label: None }) => kind.to_string(),
+        None => "unknown".to_string(),
+    };
+
+    let mut parts = vec![];
+    match contents.partition_table {
+        Some(PartitionTable::Gpt) => parts.push("gpt".to_string()),
+        Some(PartitionTable::Mbr) => parts.push("mbr".to_string()),
+        None if contents.filesystem.is_some() => parts.push(describe(&contents.filesystem)),
+        None => parts.push("nothing recognized".to_string()),
+    }
+    parts.extend(
+        contents
+            .partitions
+            .iter()
+            .map(|partition| describe(&partition.filesystem)),
+    );
+
+    format!("Probed: {}", parts.join(", "))
+}
+
+fn os_hints(contents: &DeviceContents) -> Vec<String> {
+    let mut hints = BTreeSet::new();
+
+    for partition in &contents.partitions {
+        let hint = match partition.type_name {
+            Some("EFI system") => Some("EFI system partition"),
+            Some("BIOS boot") => Some("BIOS boot partition"),
+            Some("Microsoft reserved" | "Windows recovery") => Some("Windows partitions"),
+            Some("Linux root (x86-64)") => Some("Linux root partition"),
+            Some("Linux swap") => Some("swap partition"),
+            Some("Apple APFS" | "Apple HFS+") => Some("macOS partition"),
+            _ => None,
+        };
+        hints.extend(hint.map(str::to_string));
+
+        if partition.bootable && contents.partition_table == Some(PartitionTable::Mbr) {
+            hints.insert(format!("partition {} is marked bootable", partition.number));
+        }
+    }
+
+    hints.into_iter().collect()
+}
+
+/// Probes devices off the event loop, a failing drive can take its time
+/// answering reads. Results come back through next().
+pub struct Prober {
+    sender: mpsc::Sender<(DeviceIdentity, Result<DeviceContents, ProbeError>)>,
+    receiver: mpsc::Receiver<(DeviceIdentity, Result<DeviceContents, ProbeError>)>,
+}
+
+impl Prober {
+    /// A prober with nothing to do yet.
+    pub fn new() -> Self {
+        let (sender, receiver) = mpsc::channel(64);
+        Self { sender, receiver }
+    }
+
+    /// Starts reading what's on `identity`'s drive, in the background.
+    pub fn probe(&self, identity: &DeviceIdentity) {
+        // Simulated drives have nothing to read.
+        if !identity.paths.first().is_some_and(|node| node.exists()) {
+            return;
+        }
+
+        let identity = identity.clone();
+        let sender = self.sender.clone();
+        tokio::task::spawn_blocking(move || {
+            let contents = probe_device(&identity.name).map_err(|error| ProbeError {
+                device: identity.name.clone(),
+                error,
+            });
+            // Only fails once we're shutting down.
+            let _ = sender.blocking_send((identity, contents));
+        });
+    }
+
+    /// The next device that was read, in the order they finish.
+    pub async fn next(&mut self) -> Option<(DeviceIdentity, Result<DeviceContents, ProbeError>)> {
+        self.receiver.recv().await
+    }
+}
+
+impl Default for Prober {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    const EFI_SYSTEM: &str = "C12A7328-F81F-11D2-BA4B-00A0C93EC93B";
+    const LINUX_FILESYSTEM: &str = "0FC63DAF-8483-4772-8E79-3D69D8477DE4";
+    const BASIC_DATA: &str = "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7";
+    const MICROSOFT_RESERVED: &str = "E3C9E316-0B5C-4DB8-817D-F92DF00215AE";
+
+    // A disk image put together a few signatures at a time, and probed from
+    // a file the way a drive would be.
+    struct Image {
+        path: PathBuf,
+        bytes: Vec<u8>,
+        _dir: TestDir,
+    }
+
+    impl Image {
+        fn new(name: &str) -> Self {
+            let dir = TestDir::new(&format!("probe-{}", name));
+            Self {
+                path: dir.join("disk.img"),
+                bytes: vec![],
+                _dir: dir,
+            }
+        }
+
+        fn put(&mut self, offset: usize, bytes: &[u8]) -> &mut Self {
+            if self.bytes.len() < offset + bytes.len() {
+                self.bytes.resize(offset + bytes.len(), 0);
+            }
+            self.bytes[offset..offset + bytes.len()].copy_from_slice(bytes);
+            self
+        }
+
+        fn probe(&self) -> io::Result<DeviceContents> {
+            fs::write(&self.path, &self.bytes).unwrap();
+            probe(&self.path, 512)
+        }
+
+        // The superblocks, where `offset` is the start of a partition.
+        fn fat32(&mut self, offset: usize, label: &[u8; 11]) -> &mut Self {
+            self.put(offset + 3, b"MSDOS5.0")
+                .put(offset + 71, label)
+                .put(offset + 82, b"FAT32   ")
+                .put(offset + 510, b"\x55\xaa")
+        }
+
+        fn ntfs(&mut self, offset: usize) -> &mut Self {
+            self.put(offset, b"\xeb\x52\x90NTFS    ")
+                .put(offset + 510, b"\x55\xaa")
+        }
+
+        fn ext(&mut self, offset: usize, compat: u32, incompat: u32, label: &str) -> &mut Self {
+            let superblock = offset + 1024;
+            self.put(superblock + 56, &0xef53u16.to_le_bytes())
+                .put(superblock + 92, &compat.to_le_bytes())
+                .put(superblock + 96, &incompat.to_le_bytes())
+                .put(superblock + 120, label.as_bytes())
+                .put(superblock + 1023, &[0])
+        }
+
+        fn mbr_entry(
+            &mut self,
+            index: usize,
+            boot: u8,
+            kind: u8,
+            start: u32,
+            sectors: u32,
+        ) -> &mut Self {
+            let entry = 446 + index * 16;
+            self.put(entry, &[boot])
+                .put(entry + 4, &[kind])
+                .put(entry + 8, &start.to_le_bytes())
+                .put(entry + 12, &sectors.to_le_bytes())
+                .put(510, b"\x55\xaa")
+        }
+
+        // A protective MBR and a GPT header with `count` entries of
+        // `entry_size` bytes from LBA 2.
+        fn gpt(&mut self, count: u32, entry_size: u32) -> &mut Self {
+            self.mbr_entry(0, 0, 0xee, 1, u32::MAX)
+                .put(512, b"EFI PART")
+                .put(512 + 72, &2u64.to_le_bytes())
+                .put(512 + 80, &count.to_le_bytes())
+                .put(512 + 84, &entry_size.to_le_bytes())
+        }
+
+        fn gpt_entry(
+            &mut self,
+            index: usize,
+            type_id: &str,
+            first: u64,
+            last: u64,
+            name: &str,
+        ) -> &mut Self {
+            let entry = 1024 + index * 128;
+            let name = name
+                .encode_utf16()
+                .flat_map(u16::to_le_bytes)
+                .collect::<Vec<_>>();
+            self.put(entry, &guid_bytes(type_id))
+                .put(entry + 16, &[0x11; 16])
+                .put(entry + 32, &first.to_le_bytes())
+                .put(entry + 40, &last.to_le_bytes())
+                .put(entry + 56, &name)
+        }
+    }
+
+    // guid() backwards.
+    fn guid_bytes(guid: &str) -> Vec<u8> {
+        let hex = guid.replace('-', "");
+        let mut bytes = (0..16)
+            .map(|i| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).unwrap())
+            .collect::<Vec<_>>();
+        bytes[..4].reverse();
+        bytes[4..6].reverse();
+        bytes[6..8].reverse();
+        bytes
+    }
+
+    fn filesystem(kind: &'static str, label: Option<&str>) -> Option<Filesystem> {
+        Some(Filesystem {
+            kind,
+            label: label.map(str::to_string),
+        })
+    }
+
+    // Number, start, size, type and filesystem.
+    type Layout = (u32, u64, u64, Option<&'static str>, Option<Filesystem>);
+
+    // What matters about each partition, to keep the tables readable.
+    fn layout(contents: &DeviceContents) -> Vec<Layout> {
+        contents
+            .partitions
+            .iter()
+            .map(|p| {
+                (
+                    p.number,
+                    p.start_bytes,
+                    p.size_bytes,
+                    p.type_name,
+                    p.filesystem.clone(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn a_linux_install_on_gpt() {
+        let mut image = Image::new("gpt-linux");
+        image
+            .gpt(128, 128)
+            .gpt_entry(0, EFI_SYSTEM, 34, 2047, "EFI System Partition")
+            .gpt_entry(2, LINUX_FILESYSTEM, 2048, 4095, "")
+            .fat32(34 * 512, b"ESP        ")
+            .ext(2048 * 512, 0x4, 0x40 | 0x200, "root");
+        let contents = image.probe().unwrap();
+
+        assert_eq!(contents.partition_table, Some(PartitionTable::Gpt));
+        assert_eq!(contents.filesystem, None);
+        assert_eq!(
+            layout(&contents),
+            [
+                (
+                    1,
+                    34 * 512,
+                    2014 * 512,
+                    Some("EFI system"),
+                    filesystem("vfat", Some("ESP"))
+                ),
+                // Numbered by their slot, the empty one in between skipped.
+                (
+                    3,
+                    2048 * 512,
+                    2048 * 512,
+                    Some("Linux filesystem"),
+                    filesystem("ext4", Some("root"))
+                ),
+            ]
+        );
+        assert_eq!(contents.partitions[0].type_id, EFI_SYSTEM);
+        assert_eq!(
+            contents.partitions[0].name.as_deref(),
+            Some("EFI System Partition")
+        );
+        assert_eq!(contents.partitions[1].name, None);
+        assert_eq!(contents.os_hints, ["EFI system partition"]);
+        assert_eq!(
+            summarize(&contents),
+            "Probed: gpt, vfat \"ESP\", ext4 \"root\""
+        );
+    }
+
+    #[test]
+    fn a_windows_install_on_gpt() {
+        let mut image = Image::new("gpt-windows");
+        image
+            .gpt(128, 128)
+            .gpt_entry(0, EFI_SYSTEM, 2048, 4095, "")
+            .gpt_entry(
+                1,
+                MICROSOFT_RESERVED,
+                4096,
+                4111,
+                "Microsoft reserved partition",
+            )
+            .gpt_entry(2, BASIC_DATA, 4112, 8191, "Basic data partition")
+            .fat32(2048 * 512, b"NO NAME    ")
+            .ntfs(4112 * 512);
+        let contents = image.probe().unwrap();
+
+        assert_eq!(
+            layout(&contents),
+            [
+                (
+                    1,
+                    2048 * 512,
+                    2048 * 512,
+                    Some("EFI system"),
+                    filesystem("vfat", None)
+                ),
+                (2, 4096 * 512, 16 * 512, Some("Microsoft reserved"), None),
+                (
+                    3,
+                    4112 * 512,
+                    4080 * 512,
+                    Some("Microsoft basic data"),
+                    filesystem("ntfs", None)
+                ),
+            ]
+        );
+        assert_eq!(
+            contents.os_hints,
+            ["EFI system partition", "Windows partitions"]
+        );
+        assert_eq!(summarize(&contents), "Probed: gpt, vfat, unknown, ntfs");
+    }
+
+    #[test]
+    fn an_mbr_disk() {
+        let mut image = Image::new("mbr");
+        image
+            .mbr_entry(0, 0x80, 0x07, 63, 1985)
+            .mbr_entry(1, 0, 0x05, 2048, 1024)
+            .mbr_entry(3, 0, 0x83, 4096, 4096)
+            .ntfs(63 * 512)
+            .ext(4096 * 512, 0x4, 0, "");
+        let contents = image.probe().unwrap();
+
+        assert_eq!(contents.partition_table, Some(PartitionTable::Mbr));
+        assert_eq!(
+            layout(&contents),
+            [
+                (
+                    1,
+                    63 * 512,
+                    1985 * 512,
+                    Some("NTFS/exFAT"),
+                    filesystem("ntfs", None)
+                ),
+                // Never read, it's only more partition tables.
+                (2, 2048 * 512, 1024 * 512, Some("extended"), None),
+                (
+                    4,
+                    4096 * 512,
+                    4096 * 512,
+                    Some("Linux"),
+                    filesystem("ext3", None)
+                ),
+            ]
+        );
+        assert_eq!(contents.partitions[0].type_id, "07");
+        assert_eq!(contents.os_hints, ["partition 1 is marked bootable"]);
+    }
+
+    #[test]
+    fn filesystems_on_the_whole_device() {
+        let mut ntfs = Image::new("whole-ntfs");
+        ntfs.ntfs(0).put(8191, &[0]);
+        let mut fat = Image::new("whole-fat");
+        fat.fat32(0, b"CAMERA     ");
+        let mut ext2 = Image::new("whole-ext2");
+        ext2.ext(0, 0, 0, "scratch");
+        let mut luks = Image::new("whole-luks");
+        luks.put(0, b"LUKS\xba\xbe\x00\x02");
+        let mut btrfs = Image::new("whole-btrfs");
+        btrfs
+            .put(0x10040, b"_BHRfS_M")
+            .put(0x1012b, b"pool")
+            .put(0x1022b, &[0]);
+
+        for (image, expected) in [
+            // NTFS and FAT boot sectors end like an MBR, and aren't one.
+            (&ntfs, filesystem("ntfs", None)),
+            (&fat, filesystem("vfat", Some("CAMERA"))),
+            (&ext2, filesystem("ext2", Some("scratch"))),
+            (&luks, filesystem("luks", None)),
+            (&btrfs, filesystem("btrfs", Some("pool"))),
+        ] {
+            let contents = image.probe().unwrap();
+            assert_eq!(contents.partition_table, None, "{:?}", image.path);
+            assert_eq!(contents.partitions, [], "{:?}", image.path);
+            assert_eq!(contents.filesystem, expected, "{:?}", image.path);
+        }
+        assert_eq!(summarize(&fat.probe().unwrap()), "Probed: vfat \"CAMERA\"");
+    }
+
+    #[test]
+    fn empty_and_unrecognized_images() {
+        let empty = Image::new("empty");
+        let mut zeroes = Image::new("zeroes");
+        zeroes.put(1 << 20, &[0]);
+        let mut bad_boot_flag = Image::new("bad-boot-flag");
+        bad_boot_flag.mbr_entry(0, 0x42, 0x83, 2048, 2048);
+        let mut empty_mbr = Image::new("empty-mbr");
+        empty_mbr.put(510, b"\x55\xaa");
+
+        for image in [&empty, &zeroes, &bad_boot_flag, &empty_mbr] {
+            let contents = image.probe().unwrap();
+            assert_eq!(contents, DeviceContents::default(), "{:?}", image.path);
+            assert_eq!(summarize(&contents), "Probed: nothing recognized");
+        }
+    }
+
+    #[test]
+    fn malformed_gpt_headers() {
+        let mut cut_short = Image::new("gpt-cut-short");
+        cut_short.gpt(128, 128).bytes.truncate(512 + 60);
+        let mut small_entries = Image::new("gpt-small-entries");
+        small_entries.gpt(128, 64);
+        let mut huge_entries = Image::new("gpt-huge-entries");
+        huge_entries.gpt(128, 1 << 20);
+
+        for (image, message) in [
+            (&cut_short, "GPT header is cut short"),
+            (&small_entries, "GPT entries of 64 bytes don't make sense"),
+            (
+                &huge_entries,
+                "GPT entries of 1048576 bytes don't make sense",
+            ),
+        ] {
+            let error = image.probe().unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(error.to_string(), message);
+        }
+    }
+
+    #[test]
+    fn gpt_entries_out_of_range() {
+        // Claims more entries than anything has, and has a partition that
+        // ends before it starts, which is skipped, and one well past the
+        // end of the image.
+        let mut image = Image::new("gpt-out-of-range");
+        image
+            .gpt(u32::MAX, 128)
+            .gpt_entry(0, LINUX_FILESYSTEM, 4096, 2048, "")
+            .gpt_entry(
+                1,
+                "01234567-89AB-CDEF-0123-456789ABCDEF",
+                1 << 40,
+                u64::MAX,
+                "",
+            )
+            .put(1024 + 256 * 128 + 4096, &[0]);
+        let contents = image.probe().unwrap();
+
+        assert_eq!(
+            layout(&contents),
+            [(2, (1 << 40) * 512, u64::MAX, None, None),]
+        );
+        assert_eq!(
+            contents.partitions[0].type_id,
+            "01234567-89AB-CDEF-0123-456789ABCDEF"
+        );
+    }
+
+    #[test]
+    fn guids_read_mixed_endian() {
+        for guid in [
+            EFI_SYSTEM,
+            LINUX_FILESYSTEM,
+            "00000000-0000-0000-0000-000000000000",
+        ] {
+            assert_eq!(super::guid(&guid_bytes(guid)), guid);
+        }
+    }
+}
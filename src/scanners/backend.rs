@@ -0,0 +1,311 @@
+use crate::{
+    config::{Backend, Config},
+    error::ScanError,
+};
+
+use super::{
+    composite_scanner::CompositeMonitor,
+    scanner::DeviceMonitor,
+    simulated_scanner::{Fleet, SimulatedMonitor},
+    smartctl_scanner::{find_smartctl, SmartCtlMonitor},
+    udev_scanner::UdevMonitor,
+};
+
+type MonitorResult = Result<Box<dyn DeviceMonitor>, ScanError>;
+
+/// Builds the monitor for the configured backend, and says which backend
+/// that turned out to be.
+pub fn create_monitor(config: &Config) -> Result<(Backend, Box<dyn DeviceMonitor>), ScanError> {
+    let udev = || -> MonitorResult { Ok(Box::new(UdevMonitor::new(&config.udev)?)) };
+    let smartctl = || -> MonitorResult {
+        Ok(Box::new(SmartCtlMonitor::new(
+            Some(find_smartctl(config.smartctl.path.as_deref())?),
+            config.smartctl.scan_interval(),
+        )?))
+    };
+
+    let simulated = || -> MonitorResult {
+        // Checked when the config is validated.
+        let path = config.monitor.fleet.clone().unwrap_or_default();
+        Ok(Box::new(SimulatedMonitor::new(&Fleet::load(&path)?)))
+    };
+
+    let (backend, monitor) = select_backend(config.monitor.backend, udev, smartctl, simulated)?;
+    // `auto` can go either way, make it obvious which one it was.
+    info!("Using the {} backend.", backend.as_str());
+
+    Ok((backend, monitor))
+}
+
+// `auto` prefers udev, and only falls back to polling smartctl when the
+// udev socket can't be had, like in a container without it or when not
+// running as root. Anything else, like udev.matches it won't take, is an
+// error whichever the backend. `composite` needs both of them.
+fn select_backend(
+    backend: Backend,
+    udev: impl FnOnce() -> MonitorResult,
+    smartctl: impl FnOnce() -> MonitorResult,
+    simulated: impl FnOnce() -> MonitorResult,
+) -> Result<(Backend, Box<dyn DeviceMonitor>), ScanError> {
+    match backend {
+        Backend::Simulated => Ok((Backend::Simulated, simulated()?)),
+        Backend::Udev => Ok((Backend::Udev, udev()?)),
+        Backend::Smartctl => Ok((Backend::Smartctl, smartctl()?)),
+        Backend::Composite => {
+            let monitors = vec![udev()?, smartctl()?];
+            Ok((
+                Backend::Composite,
+                Box::new(CompositeMonitor::new(monitors)),
+            ))
+        }
+        Backend::Auto => match udev() {
+            Ok(monitor) => Ok((Backend::Udev, monitor)),
+            Err(
+                udev_error @ (ScanError::UdevPermissionDenied { .. }
+                | ScanError::Udev {
+                    operation: "create" | "listen on",
+                    ..
+                }),
+            ) => {
+                warn!(
+                    "udev isn't available ({}), falling back to polling smartctl. Devices \
+                     will only be noticed once per scan interval, and USB devices won't be \
+                     seen at all.",
+                    udev_error
+                );
+                let monitor = smartctl().map_err(|smartctl_error| ScanError::NoBackend {
+                    udev: Box::new(udev_error),
+                    smartctl: Box::new(smartctl_error),
+                })?;
+                Ok((Backend::Smartctl, monitor))
+            }
+            Err(e) => Err(e),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, io};
+
+    use crate::{device_policy::DeviceIdentity, scanners::scanner::DeviceStream};
+
+    use super::*;
+
+    // Says which constructor made it through its one identity.
+    struct Fake(&'static str);
+
+    impl DeviceMonitor for Fake {
+        fn watch_events(&self) -> Result<DeviceStream, ScanError> {
+            Ok(Box::pin(tokio_stream::empty()))
+        }
+
+        fn identities(&self) -> Vec<DeviceIdentity> {
+            vec![DeviceIdentity {
+                name: self.0.to_string(),
+                ..Default::default()
+            }]
+        }
+    }
+
+    fn udev_error() -> ScanError {
+        ScanError::udev(
+            "listen on",
+            io::Error::new(io::ErrorKind::NotFound, "no udev socket"),
+        )
+    }
+
+    fn smartctl_error() -> ScanError {
+        ScanError::SmartctlNotFound {
+            path: None,
+            error: "not in PATH".into(),
+        }
+    }
+
+    // Runs select_backend with constructors that succeed or fail as told,
+    // and returns what it picked and which constructors it called.
+    fn select(
+        backend: Backend,
+        udev_works: bool,
+        smartctl_works: bool,
+    ) -> (Result<(Backend, String), ScanError>, Vec<&'static str>) {
+        let called = RefCell::new(vec![]);
+        let constructor = |name: &'static str, works: bool, error: fn() -> ScanError| {
+            let called = &called;
+            move || -> MonitorResult {
+                called.borrow_mut().push(name);
+                if works {
+                    Ok(Box::new(Fake(name)))
+                } else {
+                    Err(error())
+                }
+            }
+        };
+
+        let selected = select_backend(
+            backend,
+            constructor("udev", udev_works, udev_error),
+            constructor("smartctl", smartctl_works, smartctl_error),
+            constructor("simulated", true, udev_error),
+        )
+        .map(|(backend, monitor)| (backend, monitor.identities()[0].name.clone()));
+        (selected, called.into_inner())
+    }
+
+    #[test]
+    fn explicit_backends_only_try_themselves() {
+        for (backend, made) in [
+            (Backend::Udev, "udev"),
+            (Backend::Smartctl, "smartctl"),
+            (Backend::Simulated, "simulated"),
+        ] {
+            let (selected, called) = select(backend, true, true);
+            assert_eq!(selected.unwrap(), (backend, made.to_string()));
+            assert_eq!(called, [made]);
+        }
+    }
+
+    #[test]
+    fn explicit_backends_dont_fall_back() {
+        let (selected, called) = select(Backend::Udev, false, true);
+        assert!(matches!(selected, Err(ScanError::Udev { .. })));
+        assert_eq!(called, ["udev"]);
+
+        let (selected, called) = select(Backend::Smartctl, true, false);
+        assert!(matches!(selected, Err(ScanError::SmartctlNotFound { .. })));
+        assert_eq!(called, ["smartctl"]);
+    }
+
+    #[test]
+    fn composite_watches_with_udev_and_smartctl() {
+        let called = RefCell::new(vec![]);
+        let (backend, monitor) = select_backend(
+            Backend::Composite,
+            || {
+                called.borrow_mut().push("udev");
+                Ok(Box::new(Fake("udev")))
+            },
+            || {
+                called.borrow_mut().push("smartctl");
+                Ok(Box::new(Fake("smartctl")))
+            },
+            || unreachable!(),
+        )
+        .unwrap();
+        assert_eq!(backend, Backend::Composite);
+        let names: Vec<_> = monitor
+            .identities()
+            .into_iter()
+            .map(|identity| identity.name)
+            .collect();
+        assert_eq!(names, ["udev", "smartctl"]);
+        assert_eq!(called.into_inner(), ["udev", "smartctl"]);
+
+        // Without udev there's nothing to combine, so nothing falls back.
+        let (selected, called) = select(Backend::Composite, false, true);
+        assert!(matches!(selected, Err(ScanError::Udev { .. })));
+        assert_eq!(called, ["udev"]);
+        let (selected, called) = select(Backend::Composite, true, false);
+        assert!(matches!(selected, Err(ScanError::SmartctlNotFound { .. })));
+        assert_eq!(called, ["udev", "smartctl"]);
+    }
+
+    #[test]
+    fn auto_prefers_udev() {
+        let (selected, called) = select(Backend::Auto, true, true);
+        assert_eq!(selected.unwrap(), (Backend::Udev, "udev".to_string()));
+        assert_eq!(called, ["udev"]);
+    }
+
+    #[test]
+    fn auto_falls_back_to_smartctl() {
+        let (selected, called) = select(Backend::Auto, false, true);
+        assert_eq!(
+            selected.unwrap(),
+            (Backend::Smartctl, "smartctl".to_string())
+        );
+        assert_eq!(called, ["udev", "smartctl"]);
+    }
+
+    #[test]
+    fn auto_only_falls_back_for_the_socket() {
+        let denied = || {
+            ScanError::udev(
+                "listen on",
+                io::Error::from(io::ErrorKind::PermissionDenied),
+            )
+        };
+        let (selected, called) = select_failing(denied);
+        assert_eq!(selected.unwrap(), Backend::Smartctl);
+        assert_eq!(called, ["udev", "smartctl"]);
+
+        // A rule udev won't take is the config's fault, smartctl wouldn't
+        // go by it either.
+        let filter = || ScanError::udev("filter", io::Error::from_raw_os_error(22));
+        let (selected, called) = select_failing(filter);
+        assert!(matches!(
+            selected,
+            Err(ScanError::Udev {
+                operation: "filter",
+                ..
+            })
+        ));
+        assert_eq!(called, ["udev"]);
+
+        let unrelated = || ScanError::Fleet {
+            path: "fleet.toml".into(),
+            error: "not a fleet".into(),
+        };
+        let (selected, called) = select_failing(unrelated);
+        assert!(matches!(selected, Err(ScanError::Fleet { .. })));
+        assert_eq!(called, ["udev"]);
+    }
+
+    // `auto` with a udev that fails with `error`.
+    fn select_failing(error: fn() -> ScanError) -> (Result<Backend, ScanError>, Vec<&'static str>) {
+        let called = RefCell::new(vec![]);
+        let selected = select_backend(
+            Backend::Auto,
+            || {
+                called.borrow_mut().push("udev");
+                Err(error())
+            },
+            || {
+                called.borrow_mut().push("smartctl");
+                Ok(Box::new(Fake("smartctl")))
+            },
+            || unreachable!(),
+        )
+        .map(|(backend, _)| backend);
+        (selected, called.into_inner())
+    }
+
+    #[test]
+    fn a_missing_smartctl_is_not_found() {
+        let mut config = Config::default();
+        config.monitor.backend = Backend::Smartctl;
+        config.smartctl.path = Some("/nonexistent/smartctl".into());
+
+        match create_monitor(&config) {
+            Err(ScanError::SmartctlNotFound { path, error }) => {
+                assert_eq!(path.as_deref(), Some("/nonexistent/smartctl"));
+                let error = error.downcast::<io::Error>().unwrap();
+                assert_eq!(error.kind(), io::ErrorKind::NotFound);
+            }
+            other => panic!("Expected SmartctlNotFound, got {:?}", other.map(|(b, _)| b)),
+        }
+    }
+
+    #[test]
+    fn auto_reports_both_failures() {
+        let (selected, called) = select(Backend::Auto, false, false);
+        assert_eq!(called, ["udev", "smartctl"]);
+        match selected {
+            Err(ScanError::NoBackend { udev, smartctl }) => {
+                assert!(matches!(*udev, ScanError::Udev { .. }));
+                assert!(matches!(*smartctl, ScanError::SmartctlNotFound { .. }));
+            }
+            other => panic!("Expected NoBackend, got {:?}", other.map(|(b, _)| b)),
+        }
+    }
+}
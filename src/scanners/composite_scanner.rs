@@ -0,0 +1,200 @@
+use std::{
+    collections::{HashMap, HashSet},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio_stream::Stream;
+
+use crate::{device_policy::DeviceIdentity, error::ScanError};
+
+use super::scanner::{DeviceMonitor, DeviceStream, ScanEventType};
+
+/// Watches with several monitors as if they were one, like udev for USB
+/// devices next to smartctl for what udev can't tell apart. A device more
+/// than one of them sees is found once, and lost once the last of them
+/// loses it. Rescans and poll intervals are up to the monitors it's made
+/// of.
+#[derive(Default)]
+pub struct CompositeMonitor {
+    monitors: Vec<Box<dyn DeviceMonitor>>,
+}
+
+impl CompositeMonitor {
+    /// Watches with all of `monitors`.
+    pub fn new(monitors: Vec<Box<dyn DeviceMonitor>>) -> Self {
+        Self { monitors }
+    }
+
+    /// Watches with `monitor` as well, from the next watch_events on.
+    pub fn push(&mut self, monitor: Box<dyn DeviceMonitor>) {
+        self.monitors.push(monitor);
+    }
+}
+
+impl DeviceMonitor for CompositeMonitor {
+    /// Starts every monitor, or none if any of them can't be.
+    fn watch_events(&self) -> Result<DeviceStream, ScanError> {
+        let streams = self
+            .monitors
+            .iter()
+            .map(|monitor| monitor.watch_events().map(Some))
+            .collect::<Result<_, _>>()?;
+        Ok(Box::pin(CompositeStream {
+            streams,
+            next: 0,
+            seen_by: HashMap::new(),
+        }))
+    }
+
+    fn identities(&self) -> Vec<DeviceIdentity> {
+        self.monitors
+            .iter()
+            .flat_map(|monitor| monitor.identities())
+            .collect()
+    }
+}
+
+// Takes turns between the streams, starting after the one that last had an
+// event, so a busy one can't hold up the others. Ends once all of them have.
+struct CompositeStream {
+    streams: Vec<Option<DeviceStream>>,
+    next: usize,
+    // Which of the streams have a device right now, by its name.
+    seen_by: HashMap<String, HashSet<usize>>,
+}
+
+impl CompositeStream {
+    // None if the event only repeats what another stream already said.
+    fn merge(&mut self, stream: usize, event: ScanEventType) -> Option<ScanEventType> {
+        match &event {
+            ScanEventType::DeviceFound(device) => {
+                let seen_by = self.seen_by.entry(device.clone()).or_default();
+                let first = seen_by.is_empty();
+                seen_by.insert(stream);
+                first.then_some(event)
+            }
+            ScanEventType::DeviceLost(device) => match self.seen_by.get_mut(device) {
+                Some(seen_by) => {
+                    seen_by.remove(&stream);
+                    if !seen_by.is_empty() {
+                        return None;
+                    }
+                    self.seen_by.remove(device);
+                    Some(event)
+                }
+                // Not one we know of, but passed on like any monitor would.
+                None => Some(event),
+            },
+            ScanEventType::DeviceChanged(_) | ScanEventType::Unknown(_) => Some(event),
+        }
+    }
+}
+
+impl Stream for CompositeStream {
+    type Item = ScanEventType;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let count = self.streams.len();
+        let mut turn = 0;
+        while turn < count {
+            let index = (self.next + turn) % count;
+            let polled = match self.streams[index].as_mut() {
+                Some(stream) => stream.as_mut().poll_next(cx),
+                None => {
+                    turn += 1;
+                    continue;
+                }
+            };
+
+            match polled {
+                Poll::Ready(Some(event)) => {
+                    self.next = (index + 1) % count;
+                    if let Some(event) = self.merge(index, event) {
+                        return Poll::Ready(Some(event));
+                    }
+                    // Swallowed, this stream may well have more.
+                }
+                Poll::Ready(None) => {
+                    self.streams[index] = None;
+                    turn += 1;
+                }
+                Poll::Pending => turn += 1,
+            }
+        }
+
+        if self.streams.iter().all(Option::is_none) {
+            Poll::Ready(None)
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_stream::StreamExt;
+
+    use super::*;
+    use crate::scanners::mock_scanner::MockDeviceMonitor;
+
+    fn found(device: &str) -> ScanEventType {
+        ScanEventType::DeviceFound(device.to_string())
+    }
+
+    fn lost(device: &str) -> ScanEventType {
+        ScanEventType::DeviceLost(device.to_string())
+    }
+
+    async fn events(monitors: Vec<Vec<ScanEventType>>) -> Vec<ScanEventType> {
+        let monitors = monitors
+            .into_iter()
+            .map(|events| Box::new(MockDeviceMonitor::new(events)) as Box<dyn DeviceMonitor>)
+            .collect();
+        CompositeMonitor::new(monitors)
+            .watch_events()
+            .unwrap()
+            .collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn takes_turns_between_the_monitors() {
+        assert_eq!(
+            events(vec![
+                vec![found("sda"), found("sdb"), found("sdc")],
+                vec![found("sdd")],
+            ])
+            .await,
+            [found("sda"), found("sdd"), found("sdb"), found("sdc")]
+        );
+    }
+
+    #[tokio::test]
+    async fn devices_seen_twice_are_found_once_and_lost_with_the_last() {
+        assert_eq!(
+            events(vec![
+                vec![found("sda"), found("sdb"), lost("sda")],
+                vec![found("sda"), lost("sdb"), lost("sda")],
+            ])
+            .await,
+            // The second monitor never had sdb, the first still does. The
+            // one lost("sda") is the second monitor's, after the first's.
+            [found("sda"), found("sdb"), lost("sda")]
+        );
+    }
+
+    #[tokio::test]
+    async fn events_about_unknown_devices_are_passed_on() {
+        let changed = ScanEventType::DeviceChanged("sda".to_string());
+        assert_eq!(
+            events(vec![vec![lost("sdz"), changed.clone()], vec![]]).await,
+            [lost("sdz"), changed]
+        );
+    }
+
+    #[tokio::test]
+    async fn no_monitors_is_an_empty_stream() {
+        assert_eq!(events(vec![]).await, []);
+    }
+}
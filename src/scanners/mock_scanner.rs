@@ -0,0 +1,35 @@
+use std::collections::VecDeque;
+
+use tokio_stream::iter;
+
+use crate::error::ScanError;
+
+use super::scanner::{DeviceMonitor, DeviceStream, ScanEventType};
+
+/// Hands out a scripted list of events as fast as they're polled, then
+/// ends, the way a real monitor ends when it can't go on. Lets the rest of
+/// the pipeline be driven without udev or smartctl.
+#[derive(Debug, Clone, Default)]
+pub struct MockDeviceMonitor {
+    events: VecDeque<ScanEventType>,
+}
+
+impl MockDeviceMonitor {
+    /// A monitor whose every stream replays `events`.
+    pub fn new(events: impl IntoIterator<Item = ScanEventType>) -> Self {
+        Self {
+            events: events.into_iter().collect(),
+        }
+    }
+
+    /// Adds an event to the end of the script.
+    pub fn push(&mut self, event: ScanEventType) {
+        self.events.push_back(event);
+    }
+}
+
+impl DeviceMonitor for MockDeviceMonitor {
+    fn watch_events(&self) -> Result<DeviceStream, ScanError> {
+        Ok(Box::pin(iter(self.events.clone())))
+    }
+}
@@ -1,3 +1,16 @@
+/// Picking a monitor for the configured backend.
+pub mod backend;
+/// Several monitors watched as one.
+pub mod composite_scanner;
+/// A monitor that replays a fixed list of events, for tests.
+pub mod mock_scanner;
+/// Asking a monitor to look for devices out of turn.
+pub mod rescan;
+/// The events and the trait every monitor implements.
 pub mod scanner;
+/// A monitor that plays out a fleet of made up drives.
+pub mod simulated_scanner;
+/// A monitor that polls `smartctl --scan`.
 pub mod smartctl_scanner;
+/// A monitor that listens to udev.
 pub mod udev_scanner;
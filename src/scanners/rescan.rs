@@ -0,0 +1,255 @@
+use std::{
+    cell::RefCell,
+    fs::{self, File, OpenOptions},
+    mem,
+    os::unix::fs::FileExt,
+    path::Path,
+    task::{Context, Poll},
+};
+
+use serde::Serialize;
+use tokio::sync::{mpsc, oneshot};
+
+// Rescans asked for at once. More than this wait for room, they'd all be
+// answered by the same scan anyway.
+const QUEUED_REQUESTS: usize = 16;
+
+/// What a rescan turned up. The events for it go out the same way as any
+/// others.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RescanResult {
+    /// Devices that weren't known before the scan.
+    pub found: Vec<String>,
+    /// Known devices the scan didn't see anymore.
+    pub lost: Vec<String>,
+    /// Why the scan failed, if it did. Nothing was found or lost then.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Asks a monitor to scan right away, rather than when it next would.
+#[derive(Debug, Clone)]
+pub struct Rescanner {
+    sender: mpsc::Sender<oneshot::Sender<RescanResult>>,
+}
+
+impl Rescanner {
+    /// Waits for a scan that started after this was called, and returns
+    /// what it found. Asking again while one is on its way doesn't start
+    /// another, everyone waiting gets the same answer. None if the
+    /// monitor has stopped.
+    pub async fn rescan(&self) -> Option<RescanResult> {
+        let (reply, result) = oneshot::channel();
+        self.sender.send(reply).await.ok()?;
+        result.await.ok()
+    }
+}
+
+/// The monitor's end of a Rescanner, polled by its stream.
+///
+/// Requests are answered by the first scan that starts after they came in,
+/// never by one that was already running, which might have missed the
+/// device they were asked for.
+#[derive(Debug)]
+pub struct RescanRequests {
+    receiver: mpsc::Receiver<oneshot::Sender<RescanResult>>,
+    // Waiting for the next scan to start.
+    waiting: Vec<oneshot::Sender<RescanResult>>,
+    // Waiting for the running scan to finish.
+    scanning: Vec<oneshot::Sender<RescanResult>>,
+}
+
+impl RescanRequests {
+    /// A Rescanner and the requests it sends.
+    pub fn new() -> (Rescanner, Self) {
+        let (sender, receiver) = mpsc::channel(QUEUED_REQUESTS);
+        (
+            Rescanner { sender },
+            Self {
+                receiver,
+                waiting: vec![],
+                scanning: vec![],
+            },
+        )
+    }
+
+    /// Takes in every request that came in, and says whether any are
+    /// waiting for a scan to start. Registers the waker for more.
+    pub fn poll_requested(&mut self, cx: &mut Context<'_>) -> bool {
+        while let Poll::Ready(Some(reply)) = self.receiver.poll_recv(cx) {
+            self.waiting.push(reply);
+        }
+        // Whoever gave up waiting doesn't need a scan.
+        self.waiting.retain(|reply| !reply.is_closed());
+        !self.waiting.is_empty()
+    }
+
+    /// A scan is starting, everything waiting gets its result.
+    pub fn scan_started(&mut self) {
+        self.scanning.append(&mut self.waiting);
+    }
+
+    /// The scan that last started is done.
+    pub fn scan_finished(&mut self, result: &RescanResult) {
+        for reply in mem::take(&mut self.scanning) {
+            let _ = reply.send(result.clone());
+        }
+    }
+}
+
+/// For monitors that hand their RescanRequests to the stream they start.
+pub(crate) type PendingRequests = RefCell<Option<RescanRequests>>;
+
+/// The `scan` files of the SCSI hosts, which make the kernel look for disks
+/// that were plugged in without it noticing, like on a controller without
+/// hotplug interrupts.
+///
+/// They're opened up front since only root can write to them, and writing
+/// through a file opened while we were root still works after dropping
+/// privileges.
+#[derive(Debug, Default)]
+pub struct ScsiHosts {
+    hosts: Vec<(String, File)>,
+}
+
+impl ScsiHosts {
+    /// Opens every host under `sys`'s class/scsi_host. Hosts that can't be
+    /// opened are left out, with a warning.
+    pub fn open(sys: &Path) -> Self {
+        let dir = sys.join("class/scsi_host");
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Can't list the SCSI hosts in {}: {}", dir.display(), e);
+                return Self::default();
+            }
+        };
+
+        let mut hosts = vec![];
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            match OpenOptions::new()
+                .write(true)
+                .open(entry.path().join("scan"))
+            {
+                Ok(file) => hosts.push((name, file)),
+                Err(e) => warn!(
+                    "Can't open {}'s scan file, it won't be rescanned: {}",
+                    name, e
+                ),
+            }
+        }
+        hosts.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Self { hosts }
+    }
+
+    /// How many hosts there are to scan.
+    pub fn len(&self) -> usize {
+        self.hosts.len()
+    }
+
+    /// Whether there are none.
+    pub fn is_empty(&self) -> bool {
+        self.hosts.is_empty()
+    }
+
+    /// Has every host look for new disks on all of its channels, targets
+    /// and LUNs. Blocks until the kernel's done, which can take seconds on
+    /// a host with a lot behind it.
+    pub fn scan(&self) {
+        for (name, file) in &self.hosts {
+            if let Err(e) = file.write_at(b"- - -\n", 0) {
+                warn!("Can't rescan SCSI host {}: {}", name, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::poll_fn;
+
+    use tokio::task::JoinHandle;
+
+    use super::*;
+
+    fn rescan(rescanner: &Rescanner) -> JoinHandle<Option<RescanResult>> {
+        let rescanner = rescanner.clone();
+        tokio::spawn(async move { rescanner.rescan().await })
+    }
+
+    async fn requested(requests: &mut RescanRequests) -> bool {
+        // Lets whatever was spawned send its request first.
+        tokio::task::yield_now().await;
+        poll_fn(|cx| Poll::Ready(requests.poll_requested(cx))).await
+    }
+
+    fn found(names: &[&str]) -> RescanResult {
+        RescanResult {
+            found: names.iter().map(|name| name.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn rescans_asked_for_together_share_one_scan() {
+        let (rescanner, mut requests) = RescanRequests::new();
+        assert!(!requested(&mut requests).await);
+
+        let together = (0..5).map(|_| rescan(&rescanner)).collect::<Vec<_>>();
+        assert!(requested(&mut requests).await);
+        requests.scan_started();
+        // Nobody's waiting for a scan to start anymore.
+        assert!(!requested(&mut requests).await);
+
+        // Asked for after it started, it waits for the next one.
+        let later = rescan(&rescanner);
+        assert!(requested(&mut requests).await);
+        requests.scan_finished(&found(&["sda"]));
+        for result in together {
+            assert_eq!(result.await.unwrap().unwrap().found, ["sda"]);
+        }
+        assert!(!later.is_finished());
+
+        requests.scan_started();
+        requests.scan_finished(&found(&["sdb"]));
+        assert_eq!(later.await.unwrap().unwrap().found, ["sdb"]);
+        assert!(!requested(&mut requests).await);
+    }
+
+    #[tokio::test]
+    async fn more_than_the_queue_holds_wait_for_room() {
+        let (rescanner, mut requests) = RescanRequests::new();
+        let waiting = (0..QUEUED_REQUESTS * 2)
+            .map(|_| rescan(&rescanner))
+            .collect::<Vec<_>>();
+        // Each poll makes room for more, the scan answers them all.
+        while requested(&mut requests).await && requests.waiting.len() < QUEUED_REQUESTS * 2 {}
+        requests.scan_started();
+        requests.scan_finished(&found(&["sda"]));
+        for result in waiting {
+            assert_eq!(result.await.unwrap().unwrap().found, ["sda"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_rescan_given_up_on_doesnt_need_a_scan() {
+        let (rescanner, mut requests) = RescanRequests::new();
+        let given_up = rescan(&rescanner);
+        tokio::task::yield_now().await;
+        given_up.abort();
+        let _ = given_up.await;
+        assert!(!requested(&mut requests).await);
+    }
+
+    #[tokio::test]
+    async fn nothing_answers_once_the_monitor_stopped() {
+        let (rescanner, mut requests) = RescanRequests::new();
+        let waiting = rescan(&rescanner);
+        assert!(requested(&mut requests).await);
+        requests.scan_started();
+        drop(requests);
+        assert!(waiting.await.unwrap().is_none());
+        assert!(rescanner.rescan().await.is_none());
+    }
+}
@@ -1,17 +1,108 @@
-use anyhow::Error;
-use std::pin::Pin;
+use std::{
+    cell::RefCell,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::sync::mpsc;
 use tokio_stream::Stream;
 
-#[derive(Debug, Clone)]
+use crate::{device_policy::DeviceIdentity, error::ScanError};
+
+use super::rescan::Rescanner;
+
+/// A device coming or going, by its kernel name or device path.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ScanEventType {
+    /// The device showed up.
     DeviceFound(String),
+    /// The device went away.
     DeviceLost(String),
+    /// The device is still there, but something about it changed, like its
+    /// partition table.
+    DeviceChanged(String),
+    /// Something happened to the device, but the backend can't say what.
     Unknown(String),
 }
 
+impl ScanEventType {
+    /// The device the event is about.
+    pub fn device(&self) -> &str {
+        match self {
+            ScanEventType::DeviceFound(device)
+            | ScanEventType::DeviceLost(device)
+            | ScanEventType::DeviceChanged(device)
+            | ScanEventType::Unknown(device) => device,
+        }
+    }
+}
+
+/// Device events as they happen. Ends if the monitor can't go on.
 pub type DeviceStream = Pin<Box<dyn Stream<Item = ScanEventType>>>;
 
+/// Something that can tell when devices come and go.
 pub trait DeviceMonitor {
-    // Function that takes a callback
-    fn watch_events(&self) -> Result<DeviceStream, Error>;
+    /// Starts watching. Devices already there when this is called come
+    /// first as DeviceFound events, if the backend can list them.
+    fn watch_events(&self) -> Result<DeviceStream, ScanError>;
+
+    /// What the monitor knows about its devices that couldn't be looked up
+    /// in udev, like the made up drives of the simulated backend.
+    fn identities(&self) -> Vec<DeviceIdentity> {
+        Vec::new()
+    }
+
+    /// Asks the stream from watch_events to look for devices right away.
+    /// None if the backend has no way to, or no need to.
+    fn rescanner(&self) -> Option<Rescanner> {
+        None
+    }
+
+    /// Changes how often the stream from watch_events polls, while it runs.
+    /// None if the backend doesn't poll.
+    fn poll_interval(&self) -> Option<PollInterval> {
+        None
+    }
 }
+
+/// Changes how often a monitor's stream polls, for a config reload.
+#[derive(Debug, Clone)]
+pub struct PollInterval {
+    sender: mpsc::UnboundedSender<Duration>,
+}
+
+impl PollInterval {
+    /// The next poll is `interval` from now, and every `interval` after
+    /// that. Does nothing once the stream has stopped.
+    pub fn set(&self, interval: Duration) {
+        let _ = self.sender.send(interval);
+    }
+}
+
+/// The stream's end of a PollInterval.
+#[derive(Debug)]
+pub struct PollIntervalChanges {
+    receiver: mpsc::UnboundedReceiver<Duration>,
+}
+
+impl PollIntervalChanges {
+    /// A PollInterval and the changes it sends.
+    pub fn new() -> (PollInterval, Self) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        (PollInterval { sender }, Self { receiver })
+    }
+
+    /// The interval last set since this was last called, if any. Registers
+    /// the waker for more.
+    pub fn poll_changed(&mut self, cx: &mut Context<'_>) -> Option<Duration> {
+        let mut changed = None;
+        while let Poll::Ready(Some(interval)) = self.receiver.poll_recv(cx) {
+            changed = Some(interval);
+        }
+        changed
+    }
+}
+
+/// For monitors that hand their PollIntervalChanges to the stream they
+/// start.
+pub(crate) type PendingIntervalChanges = RefCell<Option<PollIntervalChanges>>;
@@ -0,0 +1,463 @@
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use deno_core::futures::stream::{self, StreamExt};
+use serde::Deserialize;
+use tokio::time::Instant;
+
+use crate::{device_policy::DeviceIdentity, error::ScanError};
+
+use super::scanner::{DeviceMonitor, DeviceStream, ScanEventType};
+
+// Where the made up device nodes of simulated drives live. Nothing is ever
+// created there, it only keeps path matches in the device policy from
+// picking up a simulated drive by a real drive's path.
+const SIMULATED_DEV_DIR: &str = "/dev/hddmond-sim";
+
+/// A fleet of made up drives for the simulated backend, read from a TOML or
+/// JSON file. See examples/fleet.toml.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Fleet {
+    /// How many simulated seconds pass per real second, so a schedule
+    /// spanning hours can play out in minutes.
+    #[serde(default = "default_speed")]
+    pub speed: f64,
+    /// The drives, each entry standing for `count` identical ones.
+    pub drives: Vec<FleetDrive>,
+}
+
+/// One kind of drive in a fleet.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FleetDrive {
+    /// The kernel name, or the prefix the drive's index is appended to when
+    /// there's more than one. `sim` followed by a number across the whole
+    /// fleet if not given.
+    pub name: Option<String>,
+    /// How many of this drive there are.
+    #[serde(default = "default_count")]
+    pub count: usize,
+    /// The model reported for the drive.
+    pub model: Option<String>,
+    /// The serial, with `-` and the drive's index appended when there's more
+    /// than one. Made up from its number in the fleet if not given.
+    pub serial: Option<String>,
+    /// Whether the drive is there when the daemon starts.
+    #[serde(default = "default_present")]
+    pub present: bool,
+    /// When the drive is inserted and removed, in simulated time.
+    #[serde(default)]
+    pub hotplug: Vec<Hotplug>,
+    /// How much later each copy's hotplug schedule starts than the one
+    /// before it, so they don't all come and go at once.
+    #[serde(default)]
+    pub stagger_secs: u64,
+}
+
+/// A drive being inserted or removed at some point.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Hotplug {
+    /// Simulated seconds since the daemon started.
+    pub at_secs: u64,
+    /// What happens to the drive.
+    pub action: HotplugAction,
+}
+
+/// What a hotplug does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HotplugAction {
+    /// The drive shows up.
+    Insert,
+    /// The drive goes away.
+    Remove,
+}
+
+fn default_speed() -> f64 {
+    1.0
+}
+
+fn default_count() -> usize {
+    1
+}
+
+fn default_present() -> bool {
+    true
+}
+
+impl Fleet {
+    /// Reads a fleet from `path`, as JSON if it ends in `.json` and as TOML
+    /// otherwise, and checks that it can be played out.
+    pub fn load(path: &Path) -> Result<Self, ScanError> {
+        let error = |error| ScanError::Fleet {
+            path: path.to_path_buf(),
+            error,
+        };
+
+        let contents = fs::read_to_string(path).map_err(|e| error(e.into()))?;
+        let fleet: Self = if path
+            .extension()
+            .is_some_and(|extension| extension == "json")
+        {
+            serde_json::from_str(&contents).map_err(|e| error(e.into()))?
+        } else {
+            toml::from_str(&contents).map_err(|e| error(e.into()))?
+        };
+
+        fleet.validate().map_err(|problem| error(problem.into()))?;
+        Ok(fleet)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if !self.speed.is_finite() || self.speed <= 0.0 {
+            return Err(format!("speed has to be above 0, not {}", self.speed));
+        }
+
+        for drive in &self.drives {
+            if drive.count == 0 {
+                return Err("count has to be at least 1".to_string());
+            }
+
+            // Every insert has to find the drive gone and every remove has
+            // to find it there, or the events wouldn't make sense.
+            let mut present = drive.present;
+            let mut last = 0;
+            for hotplug in &drive.hotplug {
+                if hotplug.at_secs < last {
+                    return Err("hotplug entries have to be in order of at_secs".to_string());
+                }
+                if present == (hotplug.action == HotplugAction::Insert) {
+                    return Err(format!(
+                        "a drive that's already {} can't be {} at {}s",
+                        if present { "there" } else { "gone" },
+                        if present { "inserted" } else { "removed" },
+                        hotplug.at_secs
+                    ));
+                }
+                present = !present;
+                last = hotplug.at_secs;
+            }
+        }
+
+        let mut names = HashSet::new();
+        for drive in self.expand() {
+            let name = &drive.identity.name;
+            if name.is_empty() || name.contains('/') {
+                return Err(format!("\"{}\" isn't a usable drive name", name));
+            }
+            if !names.insert(name.clone()) {
+                return Err(format!("there's more than one drive named {}", name));
+            }
+            // Otherwise the rest of the daemon would look the real drive up
+            // by that name, and take the simulated one for it.
+            if Path::new("/sys/class/block").join(name).exists() {
+                return Err(format!(
+                    "{} is a real block device on this machine, pick another name",
+                    name
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Every drive in the fleet on its own, with its schedule in real time.
+    fn expand(&self) -> Vec<SimulatedDrive> {
+        let mut drives = vec![];
+
+        for drive in &self.drives {
+            for copy in 0..drive.count {
+                let number = drives.len();
+                let name = match &drive.name {
+                    Some(name) if drive.count == 1 => name.clone(),
+                    Some(prefix) => format!("{}{}", prefix, copy),
+                    None => format!("sim{}", number),
+                };
+                let serial = match &drive.serial {
+                    Some(serial) if drive.count == 1 => serial.clone(),
+                    Some(serial) => format!("{}-{}", serial, copy),
+                    None => format!("SIM{:06}", number),
+                };
+
+                let stagger = drive.stagger_secs * copy as u64;
+                let hotplug = drive
+                    .hotplug
+                    .iter()
+                    .map(|hotplug| {
+                        let at = (hotplug.at_secs + stagger) as f64 / self.speed;
+                        (Duration::from_secs_f64(at), hotplug.action)
+                    })
+                    .collect();
+
+                drives.push(SimulatedDrive {
+                    identity: DeviceIdentity {
+                        paths: vec![PathBuf::from(SIMULATED_DEV_DIR).join(&name)],
+                        name,
+                        serial: Some(serial),
+                        model: drive.model.clone(),
+                        wwn: None,
+                        capacity: None,
+                        firmware: None,
+                        usb_port: None,
+                    },
+                    present: drive.present,
+                    hotplug,
+                });
+            }
+        }
+
+        drives
+    }
+}
+
+#[derive(Debug, Clone)]
+struct SimulatedDrive {
+    identity: DeviceIdentity,
+    present: bool,
+    hotplug: Vec<(Duration, HotplugAction)>,
+}
+
+/// Plays out a fleet of made up drives, for demos and for trying the daemon
+/// on more drives than there are at hand. The drives have names no real
+/// device on the machine has, so nothing that acts on a device by name can
+/// reach a real one.
+#[derive(Debug, Clone)]
+pub struct SimulatedMonitor {
+    drives: Vec<SimulatedDrive>,
+}
+
+impl SimulatedMonitor {
+    /// A monitor for the drives in `fleet`.
+    pub fn new(fleet: &Fleet) -> Self {
+        let drives = fleet.expand();
+        info!("Simulating {} drive(s).", drives.len());
+        Self { drives }
+    }
+}
+
+impl DeviceMonitor for SimulatedMonitor {
+    /// The drives present from the start come first, then every hotplug as
+    /// its time comes. Once the schedule is over the stream stays open with
+    /// nothing more to say, so the daemon keeps running.
+    fn watch_events(&self) -> Result<DeviceStream, ScanError> {
+        let mut timeline = vec![];
+        for drive in &self.drives {
+            let name = &drive.identity.name;
+            if drive.present {
+                timeline.push((Duration::ZERO, ScanEventType::DeviceFound(name.clone())));
+            }
+            timeline.extend(drive.hotplug.iter().map(|(at, action)| {
+                let event = match action {
+                    HotplugAction::Insert => ScanEventType::DeviceFound(name.clone()),
+                    HotplugAction::Remove => ScanEventType::DeviceLost(name.clone()),
+                };
+                (*at, event)
+            }));
+        }
+        // Stable, so each drive's own events stay in order.
+        timeline.sort_by_key(|(at, _)| *at);
+
+        let start = Instant::now();
+        let events = stream::unfold(timeline.into_iter(), move |mut timeline| async move {
+            let (at, event) = timeline.next()?;
+            tokio::time::sleep_until(start + at).await;
+            Some((event, timeline))
+        });
+
+        Ok(Box::pin(events.chain(stream::pending())))
+    }
+
+    fn identities(&self) -> Vec<DeviceIdentity> {
+        self.drives
+            .iter()
+            .map(|drive| drive.identity.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    fn fleet(toml: &str) -> Fleet {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn problem(toml: &str) -> String {
+        fleet(toml).validate().unwrap_err()
+    }
+
+    #[test]
+    fn copies_are_numbered_and_the_rest_made_up() {
+        let drives = fleet(
+            "[[drives]]\nname = \"nas\"\ncount = 2\nserial = \"WD\"\n\
+             [[drives]]\n\
+             [[drives]]\nname = \"spare\"\nserial = \"ST-1\"\n",
+        )
+        .expand();
+
+        let names = drives
+            .iter()
+            .map(|drive| {
+                (
+                    drive.identity.name.as_str(),
+                    drive.identity.serial.as_deref().unwrap(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                ("nas0", "WD-0"),
+                ("nas1", "WD-1"),
+                ("sim2", "SIM000002"),
+                ("spare", "ST-1"),
+            ]
+        );
+        assert_eq!(
+            drives[3].identity.paths,
+            [PathBuf::from("/dev/hddmond-sim/spare")]
+        );
+    }
+
+    #[test]
+    fn schedules_are_in_real_time_and_staggered() {
+        let drives = fleet(
+            "speed = 10.0\n[[drives]]\nname = \"bay\"\ncount = 3\nstagger_secs = 5\n\
+             hotplug = [{ at_secs = 20, action = \"remove\" }]\n",
+        )
+        .expand();
+
+        let at = drives
+            .iter()
+            .map(|drive| drive.hotplug[0].0)
+            .collect::<Vec<_>>();
+        let ms = Duration::from_millis;
+        assert_eq!(at, [ms(2000), ms(2500), ms(3000)]);
+    }
+
+    #[test]
+    fn fleets_that_cant_be_played_out() {
+        for (toml, expected) in [
+            (
+                "speed = 0.0\ndrives = []\n",
+                "speed has to be above 0, not 0",
+            ),
+            (
+                "speed = -1.0\ndrives = []\n",
+                "speed has to be above 0, not -1",
+            ),
+            ("[[drives]]\ncount = 0\n", "count has to be at least 1"),
+            (
+                "[[drives]]\nhotplug = [{ at_secs = 5, action = \"remove\" }, \
+                 { at_secs = 1, action = \"insert\" }]\n",
+                "hotplug entries have to be in order of at_secs",
+            ),
+            (
+                "[[drives]]\nhotplug = [{ at_secs = 5, action = \"insert\" }]\n",
+                "a drive that's already there can't be inserted at 5s",
+            ),
+            (
+                "[[drives]]\npresent = false\nhotplug = [{ at_secs = 5, action = \"remove\" }]\n",
+                "a drive that's already gone can't be removed at 5s",
+            ),
+            (
+                "[[drives]]\nname = \"a\"\n[[drives]]\nname = \"a\"\n",
+                "there's more than one drive named a",
+            ),
+            (
+                "[[drives]]\nname = \"\"\n",
+                "\"\" isn't a usable drive name",
+            ),
+            (
+                "[[drives]]\nname = \"../sda\"\n",
+                "\"../sda\" isn't a usable drive name",
+            ),
+        ] {
+            assert_eq!(problem(toml), expected, "{}", toml);
+        }
+    }
+
+    #[test]
+    fn real_block_devices_cant_be_simulated() {
+        let real = match fs::read_dir("/sys/class/block")
+            .ok()
+            .and_then(|mut entries| entries.next())
+        {
+            Some(Ok(entry)) => entry.file_name().to_string_lossy().into_owned(),
+            // No block devices to clash with.
+            _ => return,
+        };
+        assert_eq!(
+            problem(&format!("[[drives]]\nname = \"{}\"\n", real)),
+            format!(
+                "{} is a real block device on this machine, pick another name",
+                real
+            )
+        );
+    }
+
+    #[test]
+    fn loads_toml_and_json() {
+        let dir = TestDir::new("fleet");
+
+        let toml = dir.join("fleet.toml");
+        fs::write(&toml, "speed = 2.0\n[[drives]]\nname = \"simdisk\"\n").unwrap();
+        let json = dir.join("fleet.json");
+        fs::write(&json, r#"{"drives": [{"name": "simdisk", "count": 2}]}"#).unwrap();
+        let broken = dir.join("broken.json");
+        fs::write(&broken, "speed = 2.0\n").unwrap();
+
+        assert_eq!(Fleet::load(&toml).unwrap().speed, 2.0);
+        assert_eq!(Fleet::load(&json).unwrap().drives[0].count, 2);
+        assert!(matches!(
+            Fleet::load(&broken),
+            Err(ScanError::Fleet { path, .. }) if path == broken
+        ));
+        assert!(matches!(
+            Fleet::load(&dir.join("missing.toml")),
+            Err(ScanError::Fleet { .. })
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn plays_out_the_schedule_and_stays_open() {
+        let monitor = SimulatedMonitor::new(&fleet(
+            "speed = 10.0\n\
+             [[drives]]\nname = \"simshelf\"\ncount = 2\n\
+             [[drives]]\nname = \"simbay\"\npresent = false\nhotplug = [\
+             { at_secs = 10, action = \"insert\" }, { at_secs = 30, action = \"remove\" }]\n",
+        ));
+        assert_eq!(monitor.identities().len(), 3);
+        let mut stream = monitor.watch_events().unwrap();
+
+        let start = Instant::now();
+        let mut events = vec![];
+        for _ in 0..4 {
+            let event = stream.next().await.unwrap();
+            events.push((start.elapsed(), event));
+        }
+        let secs = Duration::from_secs;
+        assert_eq!(
+            events,
+            [
+                (secs(0), ScanEventType::DeviceFound("simshelf0".to_string())),
+                (secs(0), ScanEventType::DeviceFound("simshelf1".to_string())),
+                (secs(1), ScanEventType::DeviceFound("simbay".to_string())),
+                (secs(3), ScanEventType::DeviceLost("simbay".to_string())),
+            ]
+        );
+
+        // Nothing more, but not the end either.
+        assert!(tokio::time::timeout(secs(3600), stream.next())
+            .await
+            .is_err());
+    }
+}
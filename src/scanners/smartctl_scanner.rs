@@ -2,15 +2,16 @@ use std::{
     cell::RefCell,
     collections::{HashSet, VecDeque},
     future::Future,
+    io,
+    os::unix::fs::PermissionsExt,
+    path::Path,
     pin::Pin,
     rc::Rc,
     sync::Arc,
     task::Poll,
     time::Duration,
-    vec,
 };
 
-use anyhow::{Error, Ok};
 use deno_core::futures::FutureExt;
 use smartctl_wrapper::SmartCtl;
 use tokio::{
@@ -19,129 +20,169 @@ use tokio::{
 };
 use tokio_stream::Stream;
 
-use super::scanner::{DeviceMonitor, ScanEventType};
+use crate::{
+    error::{BoxError, ScanError},
+    faults::{self, FaultPoint},
+    usage,
+};
+
+use super::{
+    rescan::{PendingRequests, RescanRequests, RescanResult, Rescanner},
+    scanner::{
+        DeviceMonitor, PendingIntervalChanges, PollInterval, PollIntervalChanges, ScanEventType,
+    },
+};
+
+/// The smartctl at `path`, or the one on the PATH. A configured path that
+/// isn't an executable file is SmartctlNotFound up front, rather than a scan
+/// failing every interval later.
+pub fn find_smartctl(path: Option<&Path>) -> Result<SmartCtl, ScanError> {
+    let not_found = |error: BoxError| ScanError::SmartctlNotFound {
+        path: path.map(|path| path.display().to_string()),
+        error,
+    };
+
+    if let Some(path) = path {
+        let metadata = path.metadata().map_err(|error| not_found(error.into()))?;
+        if !metadata.is_file() || metadata.permissions().mode() & 0o111 == 0 {
+            return Err(not_found(
+                io::Error::new(io::ErrorKind::PermissionDenied, "not an executable file").into(),
+            ));
+        }
+    }
+
+    SmartCtl::new(path.map(|path| path.display().to_string()))
+        .map_err(|error| not_found(error.into()))
+}
+
+// Lists the devices there are, `smartctl --scan` outside of the tests.
+type ScanDevices = Arc<dyn Fn() -> Result<Vec<String>, BoxError> + Send + Sync>;
 
-// SmartCtlMonitor will poll the `smartctl` binary with `--scan` to
-// watch the list of devices. Unfortunately, this will not detect
-// USB devices, or any device that isn't a SMART / SCSI / ATA type
-// device. Using this implementation will effectively remove USB
-// device functionality.
+/// SmartCtlMonitor will poll the `smartctl` binary with `--scan` to
+/// watch the list of devices. Unfortunately, this will not detect
+/// USB devices, or any device that isn't a SMART / SCSI / ATA type
+/// device. Using this implementation will effectively remove USB
+/// device functionality.
 //
 // Not to mention, this implementation is so crappy since I'm a
 // beginner :)
 pub struct SmartCtlMonitor {
-    smartctl_bin_ref: Arc<SmartCtl>,
+    scan: ScanDevices,
+    poll_interval: Duration,
+    rescanner: Rescanner,
+    rescan_requests: PendingRequests,
+    interval_setter: PollInterval,
+    interval_changes: PendingIntervalChanges,
 }
 
 impl SmartCtlMonitor {
-    pub fn new(smart_ctl_bin_ref: Option<SmartCtl>) -> Result<Self, Error> {
+    /// Scans every `poll_interval`, with the given smartctl or the one on
+    /// the PATH.
+    pub fn new(
+        smart_ctl_bin_ref: Option<SmartCtl>,
+        poll_interval: Duration,
+    ) -> Result<Self, ScanError> {
         let smart_ctl_bin_ref = match smart_ctl_bin_ref {
             Some(smart_ctl_bin_ref) => smart_ctl_bin_ref,
-            None => SmartCtl::new(None)?,
+            None => find_smartctl(None)?,
         };
 
-        Ok(Self {
-            smartctl_bin_ref: Arc::new(smart_ctl_bin_ref),
-        })
+        Ok(Self::with_scan(
+            Arc::new(move || Ok(smart_ctl_bin_ref.scan()?)),
+            poll_interval,
+        ))
+    }
+
+    fn with_scan(scan: ScanDevices, poll_interval: Duration) -> Self {
+        let (rescanner, rescan_requests) = RescanRequests::new();
+        let (interval_setter, interval_changes) = PollIntervalChanges::new();
+        Self {
+            scan,
+            poll_interval,
+            rescanner,
+            rescan_requests: RefCell::new(Some(rescan_requests)),
+            interval_setter,
+            interval_changes: RefCell::new(Some(interval_changes)),
+        }
     }
 }
 
 impl DeviceMonitor for SmartCtlMonitor {
-    fn watch_events(&self) -> Result<super::scanner::DeviceStream, Error> {
-        let duration = Duration::from_secs(1);
-        let sleep = tokio::time::sleep(duration.clone());
-
-        let smartctl_bin_ref = self.smartctl_bin_ref.clone();
-
-        let current_dev_names = Arc::new(std::sync::Mutex::new(HashSet::new()));
+    fn watch_events(&self) -> Result<super::scanner::DeviceStream, ScanError> {
+        let duration = self.poll_interval;
+        let sleep = tokio::time::sleep(duration);
 
         Ok(Box::pin(SmartCtlMonitorStream {
             sleep_future: Rc::new(RefCell::new(Box::pin(sleep))),
-            smartctl_bin_ref,
+            scan: self.scan.clone(),
             smartctl_exec_fut: Rc::new(RefCell::new(None)),
             poll_interval: duration,
-            current_dev_names,
+            current_dev_names: HashSet::new(),
             event_queue: VecDeque::new(),
+            rescan_requests: self.rescan_requests.take(),
+            interval_changes: self.interval_changes.take(),
         }))
     }
+
+    fn rescanner(&self) -> Option<Rescanner> {
+        Some(self.rescanner.clone())
+    }
+
+    fn poll_interval(&self) -> Option<PollInterval> {
+        Some(self.interval_setter.clone())
+    }
 }
 
 type SharedJoinHandle<T> = Rc<RefCell<Option<JoinHandle<T>>>>;
 
-#[derive(Debug, Clone)]
+/// What changed between two scans.
+#[derive(Debug, Clone, Default)]
 pub struct SmartCtlDeviceListDiffResult {
-    added: Vec<String>,
-    removed: Vec<String>,
+    /// Devices that weren't there last time.
+    pub added: Vec<String>,
+    /// Devices that were there last time and aren't anymore.
+    pub removed: Vec<String>,
 }
 
-impl Default for SmartCtlDeviceListDiffResult {
-    fn default() -> Self {
-        Self {
-            added: vec![],
-            removed: vec![],
-        }
-    }
-}
-
-pub struct SmartCtlMonitorStream {
-    smartctl_bin_ref: Arc<SmartCtl>,
+pub(crate) struct SmartCtlMonitorStream {
+    scan: ScanDevices,
     sleep_future: Rc<RefCell<Pin<Box<Sleep>>>>,
-    smartctl_exec_fut: SharedJoinHandle<SmartCtlDeviceListDiffResult>,
-    current_dev_names: Arc<std::sync::Mutex<HashSet<String>>>,
+    smartctl_exec_fut: SharedJoinHandle<Result<Vec<String>, ScanError>>,
+    // The devices seen by the last successful scan. Only the stream has
+    // it, scan tasks just hand back what they found, so a task that fails
+    // or panics can't take it with it.
+    current_dev_names: HashSet<String>,
     poll_interval: Duration,
     event_queue: VecDeque<ScanEventType>,
+    // Only the first stream of a monitor gets these.
+    rescan_requests: Option<RescanRequests>,
+    interval_changes: Option<PollIntervalChanges>,
 }
 
 impl SmartCtlMonitorStream {
     fn _upsert_smartctl_exec_future(
         &mut self,
-    ) -> Result<SharedJoinHandle<SmartCtlDeviceListDiffResult>, Error> {
-        let smartctl_ref = self.smartctl_bin_ref.clone();
+    ) -> Result<SharedJoinHandle<Result<Vec<String>, ScanError>>, ScanError> {
+        let scan = self.scan.clone();
 
         let mut current_fut = self.smartctl_exec_fut.as_ref().borrow_mut();
 
-        if let Some(fut) = current_fut.as_mut() {
-            drop(fut);
+        if current_fut.is_some() {
             drop(current_fut);
             return Ok(self.smartctl_exec_fut.clone());
         }
 
-        let current_dev_names = self.current_dev_names.clone();
-
         let new_future = tokio::task::spawn_blocking(move || {
-            let mut current_dev_names = current_dev_names.lock().unwrap();
+            let _span = tracing::info_span!("smartctl", command = "scan").entered();
 
             trace!("Scanning for devices...");
 
-            let device_names = smartctl_ref.scan().unwrap_or(vec![]);
-
-            // Get difference in device names
-            let mut new_device_names = vec![];
-            for device_name in device_names.clone() {
-                if !current_dev_names.contains(&device_name) {
-                    new_device_names.push(device_name);
-                }
-            }
-
-            // Get missing device names
-            let mut missing_device_names = vec![];
-            for device_name in current_dev_names.iter() {
-                if !device_names.contains(device_name) {
-                    missing_device_names.push(device_name.clone());
-                }
-            }
-
-            current_dev_names.extend(new_device_names.clone());
-            for device_name in missing_device_names.iter() {
-                current_dev_names.remove(device_name);
-            }
-
-            drop(current_dev_names);
-
-            SmartCtlDeviceListDiffResult {
-                added: new_device_names,
-                removed: missing_device_names,
-            }
+            usage::SMARTCTL_WAIT
+                .time(|| {
+                    faults::fail_blocking(FaultPoint::SmartctlExec, None)?;
+                    scan()
+                })
+                .map_err(|error| ScanError::SmartctlScan { error })
         });
 
         current_fut.replace(new_future);
@@ -150,6 +191,41 @@ impl SmartCtlMonitorStream {
     }
 }
 
+/// Updates the known device names to the scanned ones, and returns which
+/// were added and removed. Public for the benchmarks.
+///
+/// Runs every scan interval over the whole fleet, so it only clones the
+/// names that changed: one copy goes into `current_dev_names`, the scanned
+/// one into the result.
+pub fn diff_device_names(
+    current_dev_names: &mut HashSet<String>,
+    device_names: Vec<String>,
+) -> SmartCtlDeviceListDiffResult {
+    let scanned: HashSet<&str> = device_names.iter().map(String::as_str).collect();
+
+    // Get missing device names
+    let removed: Vec<String> = current_dev_names
+        .iter()
+        .filter(|device_name| !scanned.contains(device_name.as_str()))
+        .cloned()
+        .collect();
+    for device_name in &removed {
+        current_dev_names.remove(device_name);
+    }
+    drop(scanned);
+
+    // Get new device names, in the order smartctl listed them
+    let mut added = vec![];
+    for device_name in device_names {
+        if !current_dev_names.contains(&device_name) {
+            current_dev_names.insert(device_name.clone());
+            added.push(device_name);
+        }
+    }
+
+    SmartCtlDeviceListDiffResult { added, removed }
+}
+
 impl Stream for SmartCtlMonitorStream {
     type Item = ScanEventType;
 
@@ -157,73 +233,596 @@ impl Stream for SmartCtlMonitorStream {
         mut self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Option<Self::Item>> {
+        // Whoever gets an event polls again for the next one, no need to
+        // wake anyone for the rest of the queue.
         if let Some(event) = self.event_queue.pop_front() {
-            cx.waker().wake_by_ref();
             return Poll::Ready(Some(event));
         }
 
+        // A rescan doesn't wait for the interval, but one asked for while
+        // a scan is running waits for the next, the running one may have
+        // missed what it's for.
+        let rescan = self
+            .rescan_requests
+            .as_mut()
+            .is_some_and(|requests| requests.poll_requested(cx));
+        let scanning = self.smartctl_exec_fut.borrow().is_some();
+
         let sleep_fut_pointer = self.sleep_future.clone();
         let mut sleep_future = RefCell::borrow_mut(&sleep_fut_pointer);
+
+        // A new interval counts from now. A running scan resets the sleep
+        // when it's done, with whatever the interval is by then.
+        let changed = self
+            .interval_changes
+            .as_mut()
+            .and_then(|changes| changes.poll_changed(cx));
+        if let Some(interval) = changed {
+            debug!("Scanning every {:?} from now on", interval);
+            self.poll_interval = interval;
+            if !scanning {
+                sleep_future.as_mut().reset(Instant::now() + interval);
+            }
+        }
         let interval_result = sleep_future.as_mut().poll(cx);
 
-        if Poll::Pending == interval_result {
+        if Poll::Pending == interval_result && !rescan && !scanning {
             return Poll::Pending;
         }
 
         let smartctl_exec_fut = self._upsert_smartctl_exec_future();
+        if !scanning {
+            if let Some(requests) = self.rescan_requests.as_mut() {
+                requests.scan_started();
+            }
+        }
 
         // If we error, return None to signify an end of stream.
-        if let Err(_) = smartctl_exec_fut {
+        if smartctl_exec_fut.is_err() {
             return Poll::Ready(None);
         }
 
         let fut_opt = smartctl_exec_fut.unwrap();
         let mut fut_opt = RefCell::borrow_mut(&fut_opt);
 
-        if fut_opt.is_none() {
-            let next_instant = Instant::now() + self.poll_interval.clone();
-            sleep_future.as_mut().reset(next_instant);
-            cx.waker().wake_by_ref();
-            return Poll::Pending;
-        }
-
-        let fut = fut_opt.as_mut().unwrap();
+        // The scan was just upserted, there's always one by now.
+        let fut = match fut_opt.as_mut() {
+            Some(fut) => fut,
+            None => return Poll::Ready(None),
+        };
 
+        // While the scan runs, the join handle wakes us when it's done.
         let result = fut.poll_unpin(cx);
 
         let poll_result = match result {
             Poll::Ready(r) => {
-                if r.is_err() {
-                    return Poll::Ready(None);
-                }
+                // A task that panicked is as good as a failed scan, the next
+                // one gets a fresh task.
+                let scanned = r.unwrap_or_else(|error| Err(ScanError::ScanTask { error }));
 
-                let next_instant = Instant::now() + self.poll_interval.clone();
-                cx.waker().wake_by_ref();
+                let next_instant = Instant::now() + self.poll_interval;
                 sleep_future.as_mut().reset(next_instant);
                 trace!("Reset interval timer");
 
                 // Set the future option to none so we can spawn a new one
                 fut_opt.take();
 
-                let r = r.unwrap_or(SmartCtlDeviceListDiffResult::default());
-
-                for dev_name in r.added.iter() {
-                    self.event_queue
-                        .push_back(ScanEventType::DeviceFound(dev_name.clone()));
-                }
-                for dev_name in r.removed.iter() {
-                    self.event_queue
-                        .push_back(ScanEventType::DeviceLost(dev_name.clone()));
+                // A failed scan says nothing about which devices are there,
+                // so it leaves the set alone rather than losing all of them.
+                let diff = scanned.map(|device_names| {
+                    diff_device_names(&mut self.current_dev_names, device_names)
+                });
+                let (r, error) = match diff {
+                    Ok(diff) => (diff, None),
+                    Err(e) => {
+                        warn!("{}, keeping the devices from the last scan", e);
+                        (SmartCtlDeviceListDiffResult::default(), Some(e.to_string()))
+                    }
+                };
+
+                if let Some(requests) = self.rescan_requests.as_mut() {
+                    requests.scan_finished(&RescanResult {
+                        found: r.added.clone(),
+                        lost: r.removed.clone(),
+                        error,
+                    });
+                    // Asked for while this one ran, it's their turn now.
+                    if requests.poll_requested(cx) {
+                        cx.waker().wake_by_ref();
+                    }
                 }
 
+                self.event_queue
+                    .extend(r.added.into_iter().map(ScanEventType::DeviceFound));
+                self.event_queue
+                    .extend(r.removed.into_iter().map(ScanEventType::DeviceLost));
+
                 match self.event_queue.pop_front() {
                     Some(event) => Poll::Ready(Some(event)),
-                    None => Poll::Pending,
+                    None => {
+                        // Nothing changed. Poll the freshly reset sleep so
+                        // it wakes us for the next scan; it's pending
+                        // unless the interval is zero.
+                        if sleep_future.as_mut().poll(cx).is_ready() {
+                            cx.waker().wake_by_ref();
+                        }
+                        Poll::Pending
+                    }
                 }
             }
             Poll::Pending => Poll::Pending,
         };
 
-        return poll_result;
+        poll_result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tokio_stream::StreamExt;
+
+    use super::*;
+    use crate::{scanners::scanner::DeviceStream, test_dir::TestDir};
+
+    // A scan that finds a different device every time, so each one shows up
+    // in the stream as a DeviceFound.
+    fn new_device_every_scan() -> ScanDevices {
+        let scans = AtomicUsize::new(0);
+        Arc::new(move || Ok(vec![format!("sd{}", scans.fetch_add(1, Ordering::SeqCst))]))
+    }
+
+    // When the next scan's device is found, after skipping the previous
+    // one's DeviceLost.
+    async fn next_scan(stream: &mut DeviceStream) -> Duration {
+        let start = Instant::now();
+        loop {
+            match stream.next().await {
+                Some(ScanEventType::DeviceFound(_)) => return start.elapsed(),
+                Some(ScanEventType::DeviceLost(_)) => continue,
+                event => panic!("Unexpected {:?}", event),
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reloaded_poll_interval_changes_the_cadence() {
+        let monitor = SmartCtlMonitor::with_scan(new_device_every_scan(), Duration::from_secs(10));
+        let poll_interval = monitor.poll_interval().unwrap();
+        let mut stream = monitor.watch_events().unwrap();
+
+        for _ in 0..3 {
+            assert_eq!(next_scan(&mut stream).await, Duration::from_secs(10));
+        }
+
+        poll_interval.set(Duration::from_secs(2));
+        for _ in 0..3 {
+            assert_eq!(next_scan(&mut stream).await, Duration::from_secs(2));
+        }
+
+        // Counts from when it's set, not from the last scan.
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        poll_interval.set(Duration::from_secs(30));
+        assert_eq!(next_scan(&mut stream).await, Duration::from_secs(30));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn interval_set_during_a_scan_applies_after_it() {
+        let (started, started_rx) = std::sync::mpsc::channel();
+        let (finish, finish_rx) = std::sync::mpsc::channel::<()>();
+        let finish_rx = std::sync::Mutex::new(finish_rx);
+        let scans = AtomicUsize::new(0);
+        let scan: ScanDevices = Arc::new(move || {
+            let _ = started.send(());
+            finish_rx.lock().unwrap().recv().unwrap();
+            Ok(vec![format!("sd{}", scans.fetch_add(1, Ordering::SeqCst))])
+        });
+        let monitor = SmartCtlMonitor::with_scan(scan, Duration::from_secs(10));
+        let poll_interval = monitor.poll_interval().unwrap();
+        let mut stream = monitor.watch_events().unwrap();
+
+        // Not on tokio's blocking threads, the clock won't move while
+        // anything's running there.
+        let scanning = std::thread::spawn(move || {
+            started_rx.recv().unwrap();
+            poll_interval.set(Duration::from_secs(3));
+            finish.send(()).unwrap();
+            // The second scan.
+            started_rx.recv().unwrap();
+            finish.send(()).unwrap();
+        });
+
+        assert_eq!(next_scan(&mut stream).await, Duration::from_secs(10));
+        assert_eq!(next_scan(&mut stream).await, Duration::from_secs(3));
+        scanning.join().unwrap();
+    }
+
+    // Polls the stream until `done`, counting every poll and keeping the
+    // events it hands out.
+    async fn poll_until(
+        stream: &mut DeviceStream,
+        events: &mut Vec<ScanEventType>,
+        done: impl Fn() -> bool,
+    ) -> usize {
+        let mut polls = 0;
+        std::future::poll_fn(|cx| loop {
+            if done() {
+                return Poll::Ready(());
+            }
+            polls += 1;
+            match stream.as_mut().poll_next(cx) {
+                Poll::Ready(Some(event)) => events.push(event),
+                Poll::Ready(None) => panic!("The stream ended"),
+                Poll::Pending => return Poll::Pending,
+            }
+        })
+        .await;
+        polls
+    }
+
+    fn counted(devices: &'static [&'static str]) -> (ScanDevices, Arc<AtomicUsize>) {
+        let scans = Arc::new(AtomicUsize::new(0));
+        let counter = scans.clone();
+        let scan: ScanDevices = Arc::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(devices.iter().map(|device| device.to_string()).collect())
+        });
+        (scan, scans)
+    }
+
+    // Every scan takes two polls: one to start it, and one when it's done.
+    // Anything waking the stream in between, or in a loop, shows up here.
+    #[tokio::test(start_paused = true)]
+    async fn a_quiet_second_takes_two_polls_a_scan() {
+        let (scan, scans) = counted(&["sda"]);
+        let mut stream = SmartCtlMonitor::with_scan(scan, Duration::from_millis(100))
+            .watch_events()
+            .unwrap();
+        assert_eq!(
+            stream.next().await,
+            Some(ScanEventType::DeviceFound("sda".to_string()))
+        );
+
+        let (start, first) = (Instant::now(), scans.load(Ordering::SeqCst));
+        let mut events = vec![];
+        let polls = poll_until(&mut stream, &mut events, || {
+            start.elapsed() >= Duration::from_secs(1)
+        });
+        let polls = tokio::time::timeout(Duration::from_secs(2), polls)
+            .await
+            .unwrap();
+
+        let scanned = scans.load(Ordering::SeqCst) - first;
+        assert!(events.is_empty());
+        assert!((9..=10).contains(&scanned), "{} scans", scanned);
+        assert!(
+            polls <= 2 * scanned + 2,
+            "{} polls for {} scans",
+            polls,
+            scanned
+        );
+    }
+
+    // Also where the freshly reset sleep is ready right away, and the stream
+    // wakes itself for the next scan.
+    #[tokio::test]
+    async fn a_zero_interval_still_takes_two_polls_a_scan() {
+        let (scan, scans) = counted(&["sda", "sdb"]);
+        let mut stream = SmartCtlMonitor::with_scan(scan, Duration::ZERO)
+            .watch_events()
+            .unwrap();
+
+        let mut events = vec![];
+        let polls = poll_until(&mut stream, &mut events, || {
+            scans.load(Ordering::SeqCst) >= 50
+        })
+        .await;
+
+        assert_eq!(events.len(), 2);
+        assert!(polls <= 2 * 50 + events.len() + 1, "{} polls", polls);
+    }
+
+    #[tokio::test]
+    async fn a_rescan_asked_for_mid_scan_runs_right_after_it() {
+        let (started, mut started_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (finish, finish_rx) = std::sync::mpsc::channel::<()>();
+        let finish_rx = std::sync::Mutex::new(finish_rx);
+        let scans = AtomicUsize::new(0);
+        let scan: ScanDevices = Arc::new(move || {
+            let _ = started.send(());
+            finish_rx.lock().unwrap().recv().unwrap();
+            match scans.fetch_add(1, Ordering::SeqCst) {
+                0 => Ok(vec!["sda".to_string()]),
+                _ => Ok(vec!["sda".to_string(), "sdb".to_string()]),
+            }
+        });
+        // Nothing but the rescans would start a scan within the test.
+        let monitor = SmartCtlMonitor::with_scan(scan, Duration::from_secs(3600));
+        let rescanner = monitor.rescanner().unwrap();
+        let mut stream = monitor.watch_events().unwrap();
+
+        let script = async {
+            let first = tokio::spawn({
+                let rescanner = rescanner.clone();
+                async move { rescanner.rescan().await.unwrap() }
+            });
+            started_rx.recv().await.unwrap();
+
+            let second = tokio::spawn({
+                let rescanner = rescanner.clone();
+                async move { rescanner.rescan().await.unwrap() }
+            });
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            finish.send(()).unwrap();
+            assert_eq!(first.await.unwrap().found, ["sda"]);
+
+            tokio::time::timeout(Duration::from_secs(5), started_rx.recv())
+                .await
+                .expect("The second rescan never started");
+            finish.send(()).unwrap();
+            assert_eq!(second.await.unwrap().found, ["sdb"]);
+        };
+
+        let mut events = vec![];
+        let done = std::cell::Cell::new(false);
+        let polls = {
+            let polls = poll_until(&mut stream, &mut events, || done.get());
+            tokio::pin!(polls);
+            tokio::select! {
+                _ = &mut polls => unreachable!(),
+                _ = script => done.set(true),
+            }
+            polls.await
+        };
+
+        assert_eq!(
+            events,
+            [
+                ScanEventType::DeviceFound("sda".to_string()),
+                ScanEventType::DeviceFound("sdb".to_string()),
+            ]
+        );
+        assert!(polls <= 12, "{} polls", polls);
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn diffs_against_the_last_scan() {
+        type Names = &'static [&'static str];
+        // (known before, scanned, added, removed, known after)
+        let cases: &[(Names, Names, Names, Names, Names)] = &[
+            (&[], &[], &[], &[], &[]),
+            (&[], &["sdb", "sda"], &["sdb", "sda"], &[], &["sda", "sdb"]),
+            (&["sda", "sdb"], &["sdb", "sda"], &[], &[], &["sda", "sdb"]),
+            (&["sda", "sdb"], &["sda"], &[], &["sdb"], &["sda"]),
+            (&["sda", "sdb"], &[], &[], &["sda", "sdb"], &[]),
+            (
+                &["sda"],
+                &["sdc", "sda", "sdb"],
+                &["sdc", "sdb"],
+                &[],
+                &["sda", "sdb", "sdc"],
+            ),
+            (&["sda"], &["sdb"], &["sdb"], &["sda"], &["sdb"]),
+            // smartctl listing a device twice still finds it once.
+            (&[], &["sda", "sda"], &["sda"], &[], &["sda"]),
+            (&["sda"], &["sdb", "sdb"], &["sdb"], &["sda"], &["sdb"]),
+        ];
+
+        for (known, scanned, added, removed, after) in cases {
+            let mut current = known.iter().map(|name| name.to_string()).collect();
+            let diff = diff_device_names(&mut current, names(scanned));
+
+            let mut diff_removed = diff.removed.clone();
+            diff_removed.sort();
+            let mut current = current.into_iter().collect::<Vec<_>>();
+            current.sort();
+            assert_eq!(diff.added, names(added), "{:?} then {:?}", known, scanned);
+            assert_eq!(
+                diff_removed,
+                names(removed),
+                "{:?} then {:?}",
+                known,
+                scanned
+            );
+            assert_eq!(current, names(after), "{:?} then {:?}", known, scanned);
+        }
+    }
+
+    enum Scan {
+        Finds(&'static [&'static str]),
+        Fails,
+        Panics,
+    }
+
+    // Goes through `scans` in order, one per scan.
+    fn scripted(scans: Vec<Scan>) -> ScanDevices {
+        let scans = std::sync::Mutex::new(VecDeque::from(scans));
+        Arc::new(move || {
+            // Not holding the lock when it panics, the script carries on.
+            let scan = scans.lock().unwrap().pop_front();
+            match scan.expect("Ran out of scans") {
+                Scan::Finds(devices) => Ok(names(devices)),
+                Scan::Fails => Err("smartctl exited with status 2".into()),
+                Scan::Panics => panic!("The scan blew up"),
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn failed_and_panicked_scans_keep_the_devices() {
+        let scan = scripted(vec![
+            Scan::Finds(&["sda", "sdb"]),
+            Scan::Fails,
+            Scan::Panics,
+            Scan::Finds(&["sda", "sdb", "sdc"]),
+            Scan::Finds(&["sda", "sdc"]),
+        ]);
+        // Nothing but the rescans would start a scan within the test.
+        let monitor = SmartCtlMonitor::with_scan(scan, Duration::from_secs(3600));
+        let rescanner = monitor.rescanner().unwrap();
+        let mut stream = monitor.watch_events().unwrap();
+
+        let script = async {
+            let mut results = vec![];
+            for _ in 0..5 {
+                let result = tokio::time::timeout(Duration::from_secs(5), rescanner.rescan())
+                    .await
+                    .expect("The rescan never finished")
+                    .unwrap();
+                results.push(result);
+            }
+            results
+        };
+
+        let mut events = vec![];
+        let done = std::cell::Cell::new(false);
+        let results = {
+            let polls = poll_until(&mut stream, &mut events, || done.get());
+            tokio::pin!(polls);
+            let results = tokio::select! {
+                _ = &mut polls => unreachable!(),
+                results = script => results,
+            };
+            done.set(true);
+            polls.await;
+            results
+        };
+
+        let outcome = |result: &RescanResult| {
+            (
+                result.found.clone(),
+                result.lost.clone(),
+                result.error.is_some(),
+            )
+        };
+        assert_eq!(
+            results.iter().map(outcome).collect::<Vec<_>>(),
+            [
+                (names(&["sda", "sdb"]), vec![], false),
+                (vec![], vec![], true),
+                (vec![], vec![], true),
+                // Still knows sda and sdb from before the failures.
+                (names(&["sdc"]), vec![], false),
+                (vec![], names(&["sdb"]), false),
+            ]
+        );
+        assert_eq!(
+            results[1].error.as_deref(),
+            Some("smartctl --scan failed: smartctl exited with status 2")
+        );
+        assert!(results[2].error.as_ref().unwrap().contains("panicked"));
+
+        assert_eq!(
+            events,
+            [
+                ScanEventType::DeviceFound("sda".to_string()),
+                ScanEventType::DeviceFound("sdb".to_string()),
+                ScanEventType::DeviceFound("sdc".to_string()),
+                ScanEventType::DeviceLost("sdb".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn rescans_share_scans_and_dont_repeat_known_devices() {
+        let (scan, scans) = counted(&["sda", "sdb"]);
+        // Nothing but the rescans would start a scan within the test.
+        let monitor = SmartCtlMonitor::with_scan(scan, Duration::from_secs(3600));
+        let rescanner = monitor.rescanner().unwrap();
+        let mut stream = monitor.watch_events().unwrap();
+
+        let script = async {
+            let rescans = (0..5).map(|_| {
+                let rescanner = rescanner.clone();
+                tokio::spawn(async move { rescanner.rescan().await.unwrap() })
+            });
+            let mut together = vec![];
+            for rescan in rescans.collect::<Vec<_>>() {
+                together.push(rescan.await.unwrap());
+            }
+            let scanned = scans.load(Ordering::SeqCst);
+
+            // The same devices again, nothing new to report.
+            let again = tokio::time::timeout(Duration::from_secs(5), rescanner.rescan())
+                .await
+                .expect("The rescan never finished")
+                .unwrap();
+            (together, scanned, again)
+        };
+
+        let mut events = vec![];
+        let done = std::cell::Cell::new(false);
+        let (together, scanned, again) = {
+            let polls = poll_until(&mut stream, &mut events, || done.get());
+            tokio::pin!(polls);
+            let results = tokio::select! {
+                _ = &mut polls => unreachable!(),
+                results = script => results,
+            };
+            done.set(true);
+            polls.await;
+            results
+        };
+
+        for result in &together {
+            assert_eq!(result.found, names(&["sda", "sdb"]));
+            assert!(result.lost.is_empty() && result.error.is_none());
+        }
+        // All five asked before the scan started, so it answered them all.
+        assert_eq!(scanned, 1);
+        assert!(
+            again.found.is_empty() && again.lost.is_empty(),
+            "{:?}",
+            again
+        );
+        assert_eq!(
+            events,
+            [
+                ScanEventType::DeviceFound("sda".to_string()),
+                ScanEventType::DeviceFound("sdb".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_configured_smartctl_must_be_an_executable_file() {
+        let dir = TestDir::new("smartctl");
+        let not_executable = dir.join("smartctl");
+        std::fs::write(&not_executable, "#!/bin/sh\n").unwrap();
+
+        for (path, kind) in [
+            (dir.join("missing"), io::ErrorKind::NotFound),
+            (dir.to_path_buf(), io::ErrorKind::PermissionDenied),
+            (not_executable.clone(), io::ErrorKind::PermissionDenied),
+        ] {
+            match find_smartctl(Some(&path)) {
+                Err(ScanError::SmartctlNotFound {
+                    path: Some(reported),
+                    error,
+                }) => {
+                    assert_eq!(reported, path.display().to_string());
+                    assert_eq!(error.downcast::<io::Error>().unwrap().kind(), kind);
+                }
+                other => panic!(
+                    "{}: expected SmartctlNotFound, got {:?}",
+                    path.display(),
+                    other.err()
+                ),
+            }
+        }
+
+        let error = find_smartctl(Some(&not_executable)).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "Can't find smartctl at {}: not an executable file",
+                not_executable.display()
+            )
+        );
     }
 }
@@ -1,30 +1,243 @@
-use anyhow::Error;
-use std::{rc::Rc, task::Poll, time::Duration};
-use tokio::time::{interval, Interval};
+use crate::error::ScanError;
+use std::{
+    cell::RefCell,
+    collections::{HashSet, VecDeque},
+    rc::Rc,
+    task::Poll,
+    time::Duration,
+};
+use tokio::time::{interval_at, Instant, Interval, MissedTickBehavior};
 use tokio_stream::Stream;
 
-use super::scanner::{DeviceMonitor, DeviceStream, ScanEventType};
+use crate::{
+    config::{UdevConfig, UdevMatch},
+    mmc,
+};
 
+use super::{
+    rescan::{PendingRequests, RescanRequests, RescanResult, Rescanner},
+    scanner::{
+        DeviceMonitor, DeviceStream, PendingIntervalChanges, PollInterval, PollIntervalChanges,
+        ScanEventType,
+    },
+    smartctl_scanner::diff_device_names,
+};
+
+/// Listens for udev events on the subsystems and device types in the
+/// config. Only whole disks come out of it, not partitions.
 pub struct UdevMonitor {
-    udev_socket: Rc<udev::MonitorSocket>,
+    udev_socket: Rc<dyn UdevSource>,
+    poll_interval: Duration,
+    matches: Vec<UdevMatch>,
+    rescanner: Rescanner,
+    rescan_requests: PendingRequests,
+    interval_setter: PollInterval,
+    interval_changes: PendingIntervalChanges,
 }
 
 impl UdevMonitor {
-    pub fn new() -> Result<Self, Error> {
-        let udev_socket = udev::MonitorBuilder::new()?
-            .match_subsystem_devtype("block", "disk")?
-            .match_subsystem_devtype("usb", "disk")?
-            .listen()?;
+    /// Opens the udev monitor socket, which usually takes root.
+    pub fn new(config: &UdevConfig) -> Result<Self, ScanError> {
+        let mut builder =
+            udev::MonitorBuilder::new().map_err(|error| ScanError::udev("create", error))?;
+        for rule in &config.matches {
+            builder = match &rule.devtype {
+                Some(devtype) => builder.match_subsystem_devtype(&rule.subsystem, devtype),
+                None => builder.match_subsystem(&rule.subsystem),
+            }
+            .map_err(|error| ScanError::udev("filter", error))?;
+        }
+        let udev_socket = builder
+            .listen()
+            .map_err(|error| ScanError::udev("listen on", error))?;
 
+        let (rescanner, rescan_requests) = RescanRequests::new();
+        let (interval_setter, interval_changes) = PollIntervalChanges::new();
         Ok(Self {
             udev_socket: Rc::new(udev_socket),
+            poll_interval: config.poll_interval(),
+            matches: config.matches.clone(),
+            rescanner,
+            rescan_requests: RefCell::new(Some(rescan_requests)),
+            interval_setter,
+            interval_changes: RefCell::new(Some(interval_changes)),
         })
     }
 }
 
-pub struct UdevMonitorStream {
-    udev_socket: Rc<udev::MonitorSocket>,
+/// What a udev event says, as far as the stream cares.
+#[derive(Debug, Clone, Default)]
+pub struct UdevEvent {
+    /// The kernel name, like `sda` or `sda1`.
+    pub name: Option<String>,
+    /// `disk` or `partition`, for block devices.
+    pub devtype: Option<String>,
+    /// `add`, `remove`, `change` and so on.
+    pub action: Option<String>,
+}
+
+impl From<&udev::Event> for UdevEvent {
+    fn from(event: &udev::Event) -> Self {
+        let to_string = |value: Option<&std::ffi::OsStr>| {
+            value.and_then(|value| value.to_str()).map(String::from)
+        };
+        let device = event.device();
+        Self {
+            name: to_string(Some(device.sysname())),
+            devtype: to_string(device.devtype()),
+            action: to_string(event.action()),
+        }
+    }
+}
+
+// Where the stream gets its events and rescans their disks from, udev
+// outside of the tests.
+trait UdevSource {
+    // The next event that's arrived, without blocking. None once there
+    // aren't any more for now, but there may be later.
+    fn next_event(&self) -> Option<UdevEvent>;
+
+    // Every whole disk there is in the subsystems we listen on, sorted.
+    fn list_disks(&self, matches: &[UdevMatch]) -> Result<Vec<String>, ScanError>;
+}
+
+impl UdevSource for udev::MonitorSocket {
+    fn next_event(&self) -> Option<UdevEvent> {
+        self.iter().next().map(|event| UdevEvent::from(&event))
+    }
+
+    fn list_disks(&self, matches: &[UdevMatch]) -> Result<Vec<String>, ScanError> {
+        list_disks(matches)
+    }
+}
+
+pub(crate) struct UdevMonitorStream {
+    udev_socket: Rc<dyn UdevSource>,
     interval_future: Interval,
+    // Set once a tick has fired, until the socket runs dry. Events that
+    // arrived together are handed out back to back instead of one per tick.
+    draining: bool,
+    matches: Vec<UdevMatch>,
+    // Only the first stream of a monitor gets these.
+    rescan_requests: Option<RescanRequests>,
+    interval_changes: Option<PollIntervalChanges>,
+    // The disks udev told us about or a rescan found, for the next rescan
+    // to tell what's new. Disks that were there before we started only
+    // get in here once something rescans.
+    known: HashSet<String>,
+    // Found by a rescan before udev got round to telling us. The add event
+    // that follows is dropped, the device was reported already.
+    rescan_found: HashSet<String>,
+    rescan_events: VecDeque<ScanEventType>,
+}
+
+impl UdevMonitorStream {
+    fn new(
+        udev_socket: Rc<dyn UdevSource>,
+        poll_interval: Duration,
+        matches: Vec<UdevMatch>,
+        rescan_requests: Option<RescanRequests>,
+        interval_changes: Option<PollIntervalChanges>,
+    ) -> Self {
+        Self {
+            udev_socket,
+            // The first tick is right away, for whatever's already waiting.
+            interval_future: self::poll_interval(Instant::now(), poll_interval),
+            draining: false,
+            matches,
+            rescan_requests,
+            interval_changes,
+            known: HashSet::new(),
+            rescan_found: HashSet::new(),
+            rescan_events: VecDeque::new(),
+        }
+    }
+
+    // Lists the disks udev has right now and reports the difference. Quick
+    // enough to do right here, it's all read out of udev's database and
+    // sysfs.
+    fn rescan(&mut self) -> RescanResult {
+        let disks = match self.udev_socket.list_disks(&self.matches) {
+            Ok(disks) => disks,
+            Err(e) => {
+                warn!("{}", e);
+                return RescanResult {
+                    error: Some(e.to_string()),
+                    ..Default::default()
+                };
+            }
+        };
+
+        let diff = diff_device_names(&mut self.known, disks);
+        for name in &diff.removed {
+            self.rescan_found.remove(name);
+        }
+        self.rescan_found.extend(diff.added.iter().cloned());
+        self.rescan_events
+            .extend(diff.added.iter().cloned().map(ScanEventType::DeviceFound));
+        self.rescan_events
+            .extend(diff.removed.iter().cloned().map(ScanEventType::DeviceLost));
+
+        RescanResult {
+            found: diff.added,
+            lost: diff.removed,
+            error: None,
+        }
+    }
+
+    // Keeps `known` up to date, and drops the adds a rescan beat udev to.
+    fn track(&mut self, event: ScanEventType) -> Option<ScanEventType> {
+        match &event {
+            ScanEventType::DeviceFound(name) => {
+                if self.rescan_found.remove(name) {
+                    return None;
+                }
+                self.known.insert(name.clone());
+            }
+            ScanEventType::DeviceLost(name) => {
+                self.rescan_found.remove(name);
+                self.known.remove(name);
+            }
+            _ => {}
+        }
+        Some(event)
+    }
+}
+
+// Every whole disk udev knows of in the subsystems we listen on, sorted.
+fn list_disks(matches: &[UdevMatch]) -> Result<Vec<String>, ScanError> {
+    let mut disks = vec![];
+    for rule in matches {
+        let mut enumerator =
+            udev::Enumerator::new().map_err(|error| ScanError::udev("enumerate", error))?;
+        enumerator
+            .match_subsystem(&rule.subsystem)
+            .map_err(|error| ScanError::udev("enumerate", error))?;
+        let devices = enumerator
+            .scan_devices()
+            .map_err(|error| ScanError::udev("enumerate", error))?;
+
+        for device in devices {
+            let devtype = device.devtype().and_then(|s| s.to_str());
+            // Same as for events, only whole disks.
+            if devtype != Some("disk")
+                || rule
+                    .devtype
+                    .as_deref()
+                    .is_some_and(|wanted| devtype != Some(wanted))
+            {
+                continue;
+            }
+            if let Some(name) = device.sysname().to_str() {
+                if !mmc::is_hardware_partition(name) {
+                    disks.push(name.to_string());
+                }
+            }
+        }
+    }
+    disks.sort();
+    disks.dedup();
+    Ok(disks)
 }
 
 impl Stream for UdevMonitorStream {
@@ -34,101 +247,331 @@ impl Stream for UdevMonitorStream {
         mut self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> Poll<Option<Self::Item>> {
-        // First, see if we still need to wait on our interval.
-        // Pass the context to our interval and see our result.
-
-        let interval_result = self.interval_future.poll_tick(cx);
-
-        // If the interval has elapsed, do the actual check on the
-        // udev socket. If we get a device, return it. If we get
-        // None, then we need to wait on the interval again.
-
-        // If the interval is still waiting, return now.
-        // Do not worry about alerting the waker, as the interval will do that for us.
-        if Poll::Pending == interval_result {
-            return Poll::Pending;
-        }
-
-        // This iterator is non-blocking, and will return Some even
-        // if it has once returned None.
-        let event = self.udev_socket.iter().next();
-
-        let result = if event.is_none() {
-            Poll::Pending
-        } else {
-            //trace!("New matched event: {:?}", event);
-
-            let result = if let Some(event) = event {
-                let device_name = event.device().sysname().to_str().map(|s| s.to_string());
-                let direction = event.action().map(|a| a.to_str()).flatten();
-
-                trace!(
-                    "Device name: {:?}\tDevice type: {:?}\tDevice action: {:?}",
-                    device_name,
-                    event.device().devtype(),
-                    direction
-                );
-
-                //We only want device types that are "disk"
-                let device_type = event
-                    .device()
-                    .devtype()
-                    .map(|s| s.to_str())
-                    .flatten()
-                    .map(|s| s.to_string());
-
-                if device_type.is_none() {
-                    return Poll::Pending;
-                }
+        // A rescan goes ahead of the socket, it's what was asked for.
+        if let Some(mut requests) = self.rescan_requests.take() {
+            if requests.poll_requested(cx) {
+                requests.scan_started();
+                let result = self.rescan();
+                requests.scan_finished(&result);
+            }
+            self.rescan_requests = Some(requests);
+        }
+        if let Some(event) = self.rescan_events.pop_front() {
+            return Poll::Ready(Some(event));
+        }
 
-                if let Some(dtype) = device_type {
-                    if dtype != "disk" {
-                        return Poll::Pending;
-                    }
-                }
+        let changed = self
+            .interval_changes
+            .as_mut()
+            .and_then(|changes| changes.poll_changed(cx));
+        if let Some(period) = changed {
+            debug!("Checking the udev socket every {:?} from now on", period);
+            self.interval_future = poll_interval(Instant::now() + period, period);
+        }
 
-                if let Some(device_name) = device_name {
-                    let outgoing_event = match direction {
-                        Some("add") => Poll::Ready(Some(ScanEventType::DeviceFound(device_name))),
-                        Some("remove") => Poll::Ready(Some(ScanEventType::DeviceLost(device_name))),
-                        Some("unknown") => Poll::Ready(Some(ScanEventType::Unknown(device_name))),
-                        _ => Poll::Pending,
-                    };
-
-                    outgoing_event
-                } else {
-                    Poll::Pending
-                }
-            } else {
-                Poll::Pending
-            };
+        // If the interval is still waiting, return now. Do not worry about
+        // alerting the waker, as the interval will do that for us.
+        if !self.draining {
+            if self.interval_future.poll_tick(cx).is_pending() {
+                return Poll::Pending;
+            }
+            self.draining = true;
+        }
 
-            result
-        };
+        // This is non-blocking, and will return Some even if it has once
+        // returned None. Skip past anything that isn't a disk, there may
+        // well be a disk event right behind it.
+        let socket = self.udev_socket.clone();
+        while let Some(event) = socket.next_event() {
+            if let Some(event) = disk_event(&event).and_then(|event| self.track(event)) {
+                return Poll::Ready(Some(event));
+            }
+        }
+
+        // The socket is drained. The tick we used up left nothing to wake
+        // us, so poll the interval again to have it wake us on the next
+        // one. It only comes back ready if that tick is overdue already.
+        self.draining = false;
+        if self.interval_future.poll_tick(cx).is_ready() {
+            self.draining = true;
+            cx.waker().wake_by_ref();
+        }
 
-        // Since we didn't early escape and actually polled the udev
-        // socket, our interval is now reset, and possibly didn't alert the
-        // waker to wake this task. I do it here for good measure.
-        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+/// The scan event for a udev event, or None if it isn't about a whole disk
+/// (partitions, eMMC boot areas and such) or doesn't say which one.
+pub fn disk_event(event: &UdevEvent) -> Option<ScanEventType> {
+    let device_name = event.name.as_deref();
+    let direction = event.action.as_deref();
+
+    trace!(
+        "Device name: {:?}\tDevice type: {:?}\tDevice action: {:?}",
+        device_name,
+        event.devtype,
+        direction
+    );
 
-        result
+    //We only want device types that are "disk"
+    if event.devtype.as_deref() != Some("disk") {
+        return None;
     }
+
+    let device_name = device_name?;
+    if mmc::is_hardware_partition(device_name) {
+        return None;
+    }
+    let event = match direction {
+        Some("add") => ScanEventType::DeviceFound,
+        Some("remove") => ScanEventType::DeviceLost,
+        Some("change") => ScanEventType::DeviceChanged,
+        Some("unknown") => ScanEventType::Unknown,
+        _ => return None,
+    };
+    // Only the events we pass on get a name of their own.
+    Some(event(device_name.to_string()))
 }
 
 impl DeviceMonitor for UdevMonitor {
-    fn watch_events(&self) -> Result<DeviceStream, Error> {
-        // Interval determines how long to wait before polling the udev socket
-        // again after a non-block / no-data event.
-        let mut interval = interval(Duration::from_millis(100));
-
-        // When the interval misses it's last tick (if we took too long to poll
-        // or compute) the next tick will be immediate. After that next tick, the
-        // interval will be normal again. This is desired.
-        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
-
-        Ok(Box::pin(UdevMonitorStream {
-            udev_socket: self.udev_socket.clone(),
-            interval_future: interval,
-        }))
+    fn watch_events(&self) -> Result<DeviceStream, ScanError> {
+        Ok(Box::pin(UdevMonitorStream::new(
+            self.udev_socket.clone(),
+            self.poll_interval,
+            self.matches.clone(),
+            self.rescan_requests.take(),
+            self.interval_changes.take(),
+        )))
+    }
+
+    fn rescanner(&self) -> Option<Rescanner> {
+        Some(self.rescanner.clone())
+    }
+
+    fn poll_interval(&self) -> Option<PollInterval> {
+        Some(self.interval_setter.clone())
+    }
+}
+
+// Interval determines how long to wait before polling the udev socket again
+// after a non-block / no-data event.
+fn poll_interval(start: Instant, period: Duration) -> Interval {
+    let mut interval = interval_at(start, period);
+
+    // When the interval misses it's last tick (if we took too long to poll
+    // or compute) the next tick will be immediate. After that next tick, the
+    // interval will be normal again. This is desired.
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    interval
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::Cell,
+        pin::Pin,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use tokio::time::timeout;
+    use tokio_stream::StreamExt;
+
+    use super::*;
+
+    // Hands out the events pushed onto it, like the socket would once
+    // they've arrived, and lists whatever disks it's given.
+    #[derive(Default)]
+    struct Script {
+        events: RefCell<VecDeque<UdevEvent>>,
+        disks: RefCell<Vec<String>>,
+        reads: Cell<usize>,
+    }
+
+    impl Script {
+        fn arrive(&self, events: impl IntoIterator<Item = UdevEvent>) {
+            self.events.borrow_mut().extend(events);
+        }
+    }
+
+    impl UdevSource for Script {
+        fn next_event(&self) -> Option<UdevEvent> {
+            self.reads.set(self.reads.get() + 1);
+            self.events.borrow_mut().pop_front()
+        }
+
+        fn list_disks(&self, _: &[UdevMatch]) -> Result<Vec<String>, ScanError> {
+            Ok(self.disks.borrow().clone())
+        }
+    }
+
+    fn event(action: &str, devtype: &str, name: &str) -> UdevEvent {
+        UdevEvent {
+            name: Some(name.to_string()),
+            devtype: Some(devtype.to_string()),
+            action: Some(action.to_string()),
+        }
+    }
+
+    fn disk(action: &str, name: &str) -> UdevEvent {
+        event(action, "disk", name)
+    }
+
+    fn partition(action: &str, name: &str) -> UdevEvent {
+        event(action, "partition", name)
+    }
+
+    fn found(name: &str) -> ScanEventType {
+        ScanEventType::DeviceFound(name.to_string())
+    }
+
+    fn lost(name: &str) -> ScanEventType {
+        ScanEventType::DeviceLost(name.to_string())
+    }
+
+    #[test]
+    fn only_whole_disks_with_known_actions_are_events() {
+        let changed = ScanEventType::DeviceChanged("sda".to_string());
+        let unknown = ScanEventType::Unknown("sda".to_string());
+        for (event, expected) in [
+            (disk("add", "sda"), Some(found("sda"))),
+            (disk("remove", "sda"), Some(lost("sda"))),
+            (disk("change", "sda"), Some(changed)),
+            (disk("unknown", "sda"), Some(unknown)),
+            (disk("bind", "sda"), None),
+            (partition("add", "sda1"), None),
+            (disk("add", "mmcblk0boot0"), None),
+            (disk("add", "mmcblk0rpmb"), None),
+            (disk("add", "mmcblk0"), Some(found("mmcblk0"))),
+            (
+                UdevEvent {
+                    name: None,
+                    ..disk("add", "")
+                },
+                None,
+            ),
+            (
+                UdevEvent {
+                    action: None,
+                    ..disk("", "sda")
+                },
+                None,
+            ),
+            (UdevEvent::default(), None),
+        ] {
+            assert_eq!(disk_event(&event), expected, "{:?}", event);
+        }
+    }
+
+    const PERIOD: Duration = Duration::from_millis(100);
+
+    fn stream(script: &Rc<Script>, requests: Option<RescanRequests>) -> UdevMonitorStream {
+        UdevMonitorStream::new(script.clone(), PERIOD, vec![], requests, None)
+    }
+
+    // Everything the stream has for us without waiting.
+    async fn ready(stream: &mut UdevMonitorStream) -> Vec<ScanEventType> {
+        let mut events = vec![];
+        while let Ok(Some(event)) = timeout(Duration::ZERO, stream.next()).await {
+            events.push(event);
+        }
+        events
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn partitions_dont_hold_up_the_disks_behind_them() {
+        let script = Rc::new(Script::default());
+        script.arrive([
+            partition("add", "sda1"),
+            disk("add", "sda"),
+            partition("add", "sda2"),
+            partition("change", "sdb1"),
+            partition("remove", "sdb2"),
+            disk("add", "sdb"),
+            // eMMC boot areas look like disks, but they're part of one.
+            disk("add", "mmcblk0boot0"),
+            disk("change", "sdb"),
+            UdevEvent::default(),
+            disk("remove", "sdc"),
+        ]);
+        let mut stream = stream(&script, None);
+
+        let start = Instant::now();
+        assert_eq!(
+            ready(&mut stream).await,
+            [
+                found("sda"),
+                found("sdb"),
+                ScanEventType::DeviceChanged("sdb".to_string()),
+                lost("sdc"),
+            ]
+        );
+        // All within the one tick.
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn events_after_the_socket_ran_dry_wait_for_the_next_tick() {
+        let script = Rc::new(Script::default());
+        script.arrive([partition("add", "sda1")]);
+        let mut stream = stream(&script, None);
+        assert!(ready(&mut stream).await.is_empty());
+
+        script.arrive([partition("add", "sdb1"), disk("add", "sdb")]);
+        let start = Instant::now();
+        assert_eq!(stream.next().await, Some(found("sdb")));
+        assert_eq!(start.elapsed(), PERIOD);
+        // Then back to waiting.
+        assert!(ready(&mut stream).await.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_quiet_second_reads_the_socket_once_a_tick() {
+        let script = Rc::new(Script::default());
+        let mut stream = stream(&script, None);
+
+        let polls = AtomicUsize::new(0);
+        let quiet = std::future::poll_fn(|cx| {
+            polls.fetch_add(1, Ordering::SeqCst);
+            Pin::new(&mut stream).poll_next(cx)
+        });
+        assert!(timeout(Duration::from_secs(1), quiet).await.is_err());
+
+        // The first tick is right away, then one every 100ms.
+        let polls = polls.load(Ordering::SeqCst);
+        assert!(polls <= 12, "{} polls", polls);
+        assert!(script.reads.get() <= 11, "{} reads", script.reads.get());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rescans_and_udev_agree_on_whats_there() {
+        let script = Rc::new(Script::default());
+        let (rescanner, requests) = RescanRequests::new();
+        let mut stream = stream(&script, Some(requests));
+        assert!(ready(&mut stream).await.is_empty());
+
+        // Found by a rescan before udev says anything.
+        *script.disks.borrow_mut() = vec!["sda".to_string(), "sdb".to_string()];
+        script.arrive([disk("add", "sda"), disk("add", "sdc")]);
+        let (result, events) = tokio::join!(rescanner.rescan(), ready(&mut stream));
+        assert_eq!(result.unwrap().found, ["sda", "sdb"]);
+        // Its events don't wait for the next tick...
+        assert_eq!(events, [found("sda"), found("sdb")]);
+        // ...and udev's add for sda is dropped, it was reported already.
+        assert_eq!(stream.next().await, Some(found("sdc")));
+
+        script.arrive([disk("remove", "sdb")]);
+        assert_eq!(stream.next().await, Some(lost("sdb")));
+
+        // Nothing new since, by either account.
+        *script.disks.borrow_mut() = vec!["sda".to_string(), "sdc".to_string()];
+        let (result, events) = tokio::join!(rescanner.rescan(), ready(&mut stream));
+        let result = result.unwrap();
+        assert!(
+            result.found.is_empty() && result.lost.is_empty(),
+            "{:?}",
+            result
+        );
+        assert!(events.is_empty());
     }
 }
@@ -0,0 +1,49 @@
+use std::{future::Future, io, process, time::Duration};
+
+use tokio::signal::unix::{signal, Signal, SignalKind};
+
+/// The one place shutdown is sequenced. The main loop waits on requested()
+/// alongside everything else, then hands the whole teardown to run(), which
+/// gives it the grace period to finish. A second SIGTERM or SIGINT at any
+/// point after the first one exits on the spot.
+pub struct Shutdown {
+    sigterm: Signal,
+    sigint: Signal,
+}
+
+impl Shutdown {
+    /// Catches SIGTERM and SIGINT from now on.
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {
+            sigterm: signal(SignalKind::terminate())?,
+            sigint: signal(SignalKind::interrupt())?,
+        })
+    }
+
+    /// Resolves with the signal's name when one arrives.
+    pub async fn requested(&mut self) -> &'static str {
+        tokio::select! {
+            _ = self.sigterm.recv() => "SIGTERM",
+            _ = self.sigint.recv() => "SIGINT",
+        }
+    }
+
+    /// Runs the teardown. If it takes longer than the grace period, whatever
+    /// is left of it is abandoned and we return anyway, so the pidfile and
+    /// such still get cleaned up on the way out.
+    pub async fn run(mut self, grace: Duration, teardown: impl Future<Output = ()>) {
+        tokio::select! {
+            _ = teardown => {}
+            _ = tokio::time::sleep(grace) => {
+                warn!(
+                    "Shutting down took longer than {:?}, giving up on the rest",
+                    grace
+                );
+            }
+            signal = self.requested() => {
+                error!("Got {} while shutting down, exiting immediately", signal);
+                process::exit(1);
+            }
+        }
+    }
+}
@@ -0,0 +1,784 @@
+use std::{collections::BTreeMap, fmt::Write};
+
+use crate::error::SmartdImportError;
+
+// The directives that take an argument. -H takes one only sometimes, the
+// health mask, and -M exec takes the path after it too.
+const WITH_ARGUMENT: &[char] = &[
+    'd', 'n', 'T', 'o', 'S', 'l', 'e', 's', 'm', 'M', 'i', 'I', 'r', 'R', 'C', 'U', 'W', 'v', 'P',
+    'F',
+];
+
+// The ones that can be given more than once and all count, the rest are
+// overridden by a later one, a device's own over the DEFAULT line's.
+const REPEATABLE: &[char] = &['e', 'i', 'I', 'r', 'R', 'C', 'U', 'v', 'l', 'M'];
+
+// What smartd mails about is a drive going bad, these are the closest.
+const EMAIL_EVENTS: &[&str] = &[
+    "device_lost",
+    "device_became_read_only",
+    "link_errors_growing",
+];
+
+/// One of an entry's directives, like `-s L/../../7/03`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Directive {
+    /// The letter after the dash.
+    pub flag: char,
+    /// Its arguments, two for `-M exec` and none for most.
+    pub args: Vec<String>,
+}
+
+impl Directive {
+    fn describe(&self) -> String {
+        let mut text = format!("-{}", self.flag);
+        for arg in &self.args {
+            text.push(' ');
+            text.push_str(arg);
+        }
+        text
+    }
+}
+
+/// A line of smartd.conf, continuations joined. `device` is a device node,
+/// DEFAULT or DEVICESCAN.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// Where it starts, counting from 1.
+    pub line: usize,
+    /// What it's for.
+    pub device: String,
+    /// In the order they were given.
+    pub directives: Vec<Directive>,
+}
+
+/// Splits smartd.conf into its entries, without looking at what the
+/// directives mean.
+pub fn parse(text: &str) -> Result<Vec<Entry>, SmartdImportError> {
+    let mut entries = vec![];
+    let mut pending: Option<(usize, String)> = None;
+
+    for (index, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim_end();
+        let (line, continues) = match line.strip_suffix('\\') {
+            Some(line) => (line, true),
+            None => (line, false),
+        };
+        let (start, mut joined) = pending.take().unwrap_or((index + 1, String::new()));
+        joined.push(' ');
+        joined.push_str(line);
+        if continues {
+            pending = Some((start, joined));
+            continue;
+        }
+        if let Some(entry) = parse_entry(start, &joined)? {
+            entries.push(entry);
+        }
+    }
+    if let Some((start, joined)) = pending {
+        if let Some(entry) = parse_entry(start, &joined)? {
+            entries.push(entry);
+        }
+    }
+
+    Ok(entries)
+}
+
+fn parse_entry(line: usize, text: &str) -> Result<Option<Entry>, SmartdImportError> {
+    let mut tokens = text.split_whitespace().peekable();
+    let Some(device) = tokens.next() else {
+        return Ok(None);
+    };
+    if device.starts_with('-') {
+        return Err(SmartdImportError::NoDevice {
+            line,
+            token: device.to_string(),
+        });
+    }
+    let missing = |flag, exec| SmartdImportError::MissingArgument { line, flag, exec };
+
+    let mut directives = vec![];
+    while let Some(token) = tokens.next() {
+        let mut chars = token.chars();
+        let flag = match (chars.next(), chars.next(), chars.next()) {
+            (Some('-'), Some(flag), None) => flag,
+            _ => {
+                return Err(SmartdImportError::NotADirective {
+                    line,
+                    token: token.to_string(),
+                })
+            }
+        };
+        let mut args = vec![];
+        if WITH_ARGUMENT.contains(&flag) {
+            let arg = tokens.next().ok_or_else(|| missing(flag, false))?;
+            args.push(arg.to_string());
+            if flag == 'M' && arg == "exec" {
+                let path = tokens.next().ok_or_else(|| missing(flag, true))?;
+                args.push(path.to_string());
+            }
+        } else if flag == 'H' {
+            if let Some(mask) = tokens.next_if(|token| !token.starts_with('-')) {
+                args.push(mask.to_string());
+            }
+        }
+        directives.push(Directive { flag, args });
+    }
+
+    Ok(Some(Entry {
+        line,
+        device: device.to_string(),
+        directives,
+    }))
+}
+
+/// A directive that didn't make it into the fragment, and why.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Skipped {
+    /// The line the entry starts on.
+    pub line: usize,
+    /// The entry's device.
+    pub device: String,
+    /// The directive(s), as they were written.
+    pub directives: String,
+    /// For humans.
+    pub reason: String,
+    /// Whether hddmond does without it anyway, rather than not having
+    /// anything like it.
+    pub not_needed: bool,
+}
+
+/// What importing a smartd.conf came to.
+#[derive(Debug, Clone, Default)]
+pub struct Import {
+    /// TOML, to merge into a config file.
+    pub fragment: String,
+    /// Everything that was left out.
+    pub skipped: Vec<Skipped>,
+}
+
+#[derive(Default)]
+struct Policy {
+    path: String,
+    apm: Option<u8>,
+    standby_after_secs: Option<u64>,
+}
+
+#[derive(Default)]
+struct Email {
+    devices: Vec<String>,
+    rate_limit_secs: Option<u64>,
+}
+
+/// What smartd would do with the entries, as far as hddmond can do the same.
+/// Lines after DEVICESCAN are left out, smartd doesn't read them.
+pub fn convert(entries: &[Entry]) -> Import {
+    let mut skipped = vec![];
+    let mut ignore = vec![];
+    let mut policies = vec![];
+    let mut emails: BTreeMap<Vec<String>, Email> = BTreeMap::new();
+    let mut defaults: Vec<Directive> = vec![];
+    let mut scanned = false;
+
+    for entry in entries {
+        let mut skip = |directives: &[&Directive], reason: &str, not_needed| {
+            skipped.push(Skipped {
+                line: entry.line,
+                device: entry.device.clone(),
+                directives: directives
+                    .iter()
+                    .map(|directive| directive.describe())
+                    .collect::<Vec<_>>()
+                    .join(" "),
+                reason: reason.to_string(),
+                not_needed,
+            })
+        };
+        if scanned {
+            skip(&[], "comes after DEVICESCAN, smartd doesn't read it", false);
+            continue;
+        }
+        if entry.device == "DEFAULT" {
+            defaults = entry.directives.clone();
+            continue;
+        }
+
+        let all = entry.device == "DEVICESCAN";
+        scanned = all;
+        // DEVICESCAN is every device, which no device rule can be, a path
+        // rule matching every device node is the nearest.
+        let path = if all {
+            "/dev/*".to_string()
+        } else {
+            entry.device.clone()
+        };
+
+        let mut directives = defaults
+            .iter()
+            .filter(|default| {
+                REPEATABLE.contains(&default.flag)
+                    || !entry
+                        .directives
+                        .iter()
+                        .any(|directive| directive.flag == default.flag)
+            })
+            .collect::<Vec<_>>();
+        directives.extend(&entry.directives);
+
+        // smartd doesn't look at anything else an ignored device has.
+        let ignored = directives
+            .iter()
+            .any(|directive| directive.flag == 'd' && directive.args[0] == "ignore");
+        if ignored && !all {
+            ignore.push(path);
+            continue;
+        }
+
+        let mut smart = vec![];
+        let mut settings = vec![];
+        let mut policy = Policy {
+            path: path.clone(),
+            ..Default::default()
+        };
+        let mut mail_to = None;
+        let mut rate_limit_secs = None;
+        for directive in directives {
+            let arg = directive
+                .args
+                .first()
+                .map(String::as_str)
+                .unwrap_or_default();
+            match directive.flag {
+                'd' if arg == "ignore" => skip(
+                    &[directive],
+                    "ignoring every device isn't monitoring",
+                    false,
+                ),
+                'd' if arg.contains(',') => skip(
+                    &[directive],
+                    "drives behind a RAID controller aren't reached through it, only by their own \
+                     device node",
+                    false,
+                ),
+                'd' => skip(&[directive], "the device type is found out anyway", true),
+                'n' => skip(
+                    &[directive],
+                    "drives are never woken up to be checked",
+                    true,
+                ),
+                'm' => mail_to = Some(&directive.args[0]),
+                'M' => match arg {
+                    "daily" => rate_limit_secs = Some(24 * 60 * 60),
+                    "once" => rate_limit_secs = Some(7 * 24 * 60 * 60),
+                    "exec" => skip(
+                        &[directive],
+                        "scripts aren't run, a plugin or a webhook can do what it does",
+                        false,
+                    ),
+                    "test" => skip(
+                        &[directive],
+                        "`hddmond test-email` sends a test through every email target",
+                        true,
+                    ),
+                    _ => skip(
+                        &[directive],
+                        "emails are rate limited the same way every time, rate_limit_secs",
+                        false,
+                    ),
+                },
+                'e' => match arg.split_once(',') {
+                    Some(("apm", "off")) => policy.apm = Some(255),
+                    Some(("apm", level)) => match level.parse() {
+                        Ok(level @ 1..=254) => policy.apm = Some(level),
+                        _ => skip(&[directive], "isn't an APM level", false),
+                    },
+                    Some(("standby", "off")) => policy.standby_after_secs = Some(0),
+                    Some(("standby", value)) => {
+                        match value.parse::<u64>().ok().and_then(standby_secs) {
+                            Some(secs) => policy.standby_after_secs = Some(secs),
+                            None => skip(
+                                &[directive],
+                                "only the plain timeouts, 0 to 251, can be set",
+                                false,
+                            ),
+                        }
+                    }
+                    _ => skip(
+                        &[directive],
+                        "only APM and standby settings are applied to drives",
+                        false,
+                    ),
+                },
+                's' => skip(&[directive], "self-tests aren't run on a schedule", false),
+                'W' => skip(&[directive], "temperatures aren't watched", false),
+                'o' | 'S' => settings.push(directive),
+                'a' | 'H' | 'l' | 'f' | 'p' | 'u' | 't' | 'i' | 'I' | 'r' | 'R' | 'C' | 'U'
+                | 'v' | 'F' | 'P' | 'T' => smart.push(directive),
+                _ => skip(&[directive], "isn't a smartd directive this knows", false),
+            }
+        }
+        if !smart.is_empty() {
+            skip(
+                &smart,
+                "SMART health, attributes and logs aren't watched",
+                false,
+            );
+        }
+        if !settings.is_empty() {
+            skip(
+                &settings,
+                "SMART settings on the drive aren't changed",
+                false,
+            );
+        }
+
+        if policy.apm.is_some() || policy.standby_after_secs.is_some() {
+            policies.push(policy);
+        }
+        if let Some(to) = mail_to {
+            let to = to
+                .split(',')
+                .filter(|address| *address != "<nomailer>")
+                .map(|address| {
+                    if address.contains('@') {
+                        address.to_string()
+                    } else {
+                        format!("{}@localhost", address)
+                    }
+                })
+                .collect::<Vec<_>>();
+            if !to.is_empty() {
+                let email = emails.entry(to).or_default();
+                email.devices.push(entry.device.clone());
+                email.rate_limit_secs = email.rate_limit_secs.max(rate_limit_secs);
+            }
+        }
+    }
+
+    for (to, email) in &emails {
+        if !email.devices.iter().any(|device| device == "DEVICESCAN") {
+            skipped.push(Skipped {
+                line: 0,
+                device: email.devices.join(", "),
+                directives: format!("-m {}", to.join(",")),
+                reason: "emails can't be kept to some devices, this target gets them about all of \
+                         them"
+                    .to_string(),
+                not_needed: false,
+            });
+        }
+    }
+
+    Import {
+        fragment: fragment(&ignore, &policies, &emails),
+        skipped,
+    }
+}
+
+// hdparm -S values, which smartd's -e standby takes, in seconds. The ones
+// above 251 are vendor specific or odd one-offs.
+fn standby_secs(value: u64) -> Option<u64> {
+    match value {
+        0..=240 => Some(value * 5),
+        241..=251 => Some((value - 240) * 30 * 60),
+        _ => None,
+    }
+}
+
+fn fragment(
+    ignore: &[String],
+    policies: &[Policy],
+    emails: &BTreeMap<Vec<String>, Email>,
+) -> String {
+    // Writing to a String can't fail.
+    let mut text = String::new();
+    let _ = writeln!(
+        text,
+        "# Converted from smartd.conf by `hddmond import-smartd`, to merge into the\n\
+         # config file."
+    );
+
+    if !ignore.is_empty() {
+        let _ = writeln!(text, "\n[devices]\nignore = [");
+        for path in ignore {
+            let _ = writeln!(text, "    {{ path = {} }},", quote(path));
+        }
+        let _ = writeln!(text, "]");
+    }
+
+    for policy in policies {
+        let _ = writeln!(
+            text,
+            "\n[[power.policies]]\ndevice = {{ path = {} }}",
+            quote(&policy.path)
+        );
+        if let Some(apm) = policy.apm {
+            let _ = writeln!(text, "apm = {}", apm);
+        }
+        if let Some(secs) = policy.standby_after_secs {
+            let _ = writeln!(text, "standby_after_secs = {}", secs);
+        }
+    }
+
+    for (to, email) in emails {
+        let _ = writeln!(
+            text,
+            "\n# smartd went through the local mailer, point this at an SMTP server.\n\
+             [[notifiers.emails]]\n\
+             server = \"localhost\"\n\
+             port = 25\n\
+             tls = \"none\"\n\
+             from = \"hddmond@localhost\"\n\
+             to = [{}]\n\
+             events = [{}]",
+            to.iter().map(|to| quote(to)).collect::<Vec<_>>().join(", "),
+            EMAIL_EVENTS
+                .iter()
+                .map(|event| quote(event))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        if let Some(secs) = email.rate_limit_secs {
+            let _ = writeln!(text, "rate_limit_secs = {}", secs);
+        }
+    }
+
+    text
+}
+
+fn quote(value: &str) -> String {
+    toml::Value::String(value.to_string()).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+
+    use super::*;
+
+    // Debian's, comments and all, trimmed.
+    const DEBIAN: &str = r#"# Sample configuration file for smartd.  See man smartd.conf.
+
+# Home page is: https://www.smartmontools.org
+
+# smartd will re-read the configuration file if it receives a HUP
+# signal
+
+# The file gives a list of devices to monitor using smartd, with one
+# device per line. Text after a hash (#) is ignored, and you may use
+# spaces and tabs for white space. You may use '\' to continue lines.
+
+# You can usually identify which hard disks are on your system by
+# looking in /proc/ide and in /proc/scsi.
+
+# The word DEVICESCAN will cause any remaining lines in this
+# configuration file to be ignored: it tells smartd to scan for all
+# ATA and SCSI devices.  DEVICESCAN may be followed by any of the
+# Directives listed below, which will be applied to all devices that
+# are found.  Most users should comment out DEVICESCAN and explicitly
+# list the devices that they wish to monitor.
+DEVICESCAN -d removable -n standby -m root -M exec /usr/share/smartmontools/smartd-runner
+
+# Alternative setting to ignore temperature and power-on hours reports
+# in syslog.
+#DEVICESCAN -I 194 -I 231 -I 9
+
+# First ATA/SATA or SCSI/SAS disk.  Monitor all attributes, enable
+# automatic online data collection, automatic Attribute autosave, and
+# start a short self-test every day between 2-3am, and a long self test
+# Saturdays between 3-4am.
+#/dev/sda -a -o on -S on -s (S/../.././02|L/../../6/03)
+"#;
+
+    // Fedora's, all of it that isn't a comment.
+    const FEDORA: &str = "DEVICESCAN -H -m root -M exec /usr/libexec/smartmontools/smartdnotify \
+                          -n standby,10,q\n";
+
+    // A NAS with its drives listed, sharing a DEFAULT.
+    const NAS: &str = r#"
+# Every drive gets these unless it says otherwise.
+DEFAULT -a -o on -S on -n standby,q -W 4,45,55 \
+        -m admin@example.com -M daily
+
+/dev/disk/by-id/ata-WDC_WD40EFRX-68N32N0_WD-WCC7K1234567 \
+    -s (S/../.././02|L/../../6/03) \
+    -e apm,128 -e standby,241    # spins down after half an hour
+/dev/sdb -d sat -e standby,off
+/dev/sdc -d ignore   # the boot SSD
+/dev/sdd -d megaraid,0 -a
+"#;
+
+    // Some drives set up by hand, the rest by DEVICESCAN.
+    const OVERRIDES: &str = r#"
+DEFAULT -m root -M daily
+/dev/sda -d ignore
+/dev/nvme0 -d nvme -W 5,60,70
+DEFAULT -a -e apm,254
+DEVICESCAN -d removable -n standby
+/dev/sdz -a   # smartd never gets this far
+"#;
+
+    fn directive(flag: char, args: &[&str]) -> Directive {
+        Directive {
+            flag,
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+        }
+    }
+
+    fn import(text: &str) -> Import {
+        let import = convert(&parse(text).unwrap());
+        // What main does with it before printing it.
+        toml::from_str::<Config>(&import.fragment)
+            .unwrap()
+            .validate()
+            .unwrap();
+        import
+    }
+
+    // Of what was skipped, what it was and whether it's needed.
+    fn skipped(import: &Import) -> Vec<(usize, &str, &str, bool)> {
+        import
+            .skipped
+            .iter()
+            .map(|skipped| {
+                (
+                    skipped.line,
+                    skipped.device.as_str(),
+                    skipped.directives.as_str(),
+                    skipped.not_needed,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn comments_and_continuations() {
+        let entries = parse(NAS).unwrap();
+        assert_eq!(
+            entries
+                .iter()
+                .map(|entry| (entry.line, entry.device.as_str()))
+                .collect::<Vec<_>>(),
+            [
+                (3, "DEFAULT"),
+                (
+                    6,
+                    "/dev/disk/by-id/ata-WDC_WD40EFRX-68N32N0_WD-WCC7K1234567"
+                ),
+                (9, "/dev/sdb"),
+                (10, "/dev/sdc"),
+                (11, "/dev/sdd"),
+            ]
+        );
+        assert_eq!(
+            entries[0].directives,
+            [
+                directive('a', &[]),
+                directive('o', &["on"]),
+                directive('S', &["on"]),
+                directive('n', &["standby,q"]),
+                directive('W', &["4,45,55"]),
+                directive('m', &["admin@example.com"]),
+                directive('M', &["daily"]),
+            ]
+        );
+        assert_eq!(
+            entries[1].directives,
+            [
+                directive('s', &["(S/../.././02|L/../../6/03)"]),
+                directive('e', &["apm,128"]),
+                directive('e', &["standby,241"]),
+            ]
+        );
+        assert_eq!(entries[3].directives, [directive('d', &["ignore"])]);
+
+        // Only comments is nothing.
+        assert!(parse("# nothing here\n\n   # or here \\\n")
+            .unwrap()
+            .is_empty());
+        // A continuation on the last line still counts.
+        assert_eq!(
+            parse("/dev/sda -a \\").unwrap()[0].directives,
+            [directive('a', &[])]
+        );
+    }
+
+    #[test]
+    fn arguments_that_are_optional_or_two() {
+        let entries = parse(FEDORA).unwrap();
+        assert_eq!(
+            entries[0].directives,
+            [
+                directive('H', &[]),
+                directive('m', &["root"]),
+                directive('M', &["exec", "/usr/libexec/smartmontools/smartdnotify"]),
+                directive('n', &["standby,10,q"]),
+            ]
+        );
+        let entries = parse("/dev/sda -H 0x08 -l error").unwrap();
+        assert_eq!(
+            entries[0].directives,
+            [directive('H', &["0x08"]), directive('l', &["error"])]
+        );
+    }
+
+    #[test]
+    fn what_doesnt_parse() {
+        for (text, error) in [
+            ("-a /dev/sda", "Line 1: -a where a device name should be"),
+            ("\n/dev/sda -m", "Line 2: -m needs an argument"),
+            ("/dev/sda -M exec", "Line 1: -M exec needs a path"),
+            ("/dev/sda \\\n  -a --all", "Line 1: --all isn't a directive"),
+            ("/dev/sda a", "Line 1: a isn't a directive"),
+        ] {
+            assert_eq!(parse(text).unwrap_err().to_string(), error, "{}", text);
+        }
+    }
+
+    #[test]
+    fn debian() {
+        let import = import(DEBIAN);
+        assert_eq!(
+            skipped(&import),
+            [
+                (21, "DEVICESCAN", "-d removable", true),
+                (21, "DEVICESCAN", "-n standby", true),
+                (
+                    21,
+                    "DEVICESCAN",
+                    "-M exec /usr/share/smartmontools/smartd-runner",
+                    false
+                ),
+            ]
+        );
+        let config: Config = toml::from_str(&import.fragment).unwrap();
+        let email = &config.notifiers.emails[0];
+        assert_eq!(email.to, ["root@localhost"]);
+        // Without -M daily or once, the default.
+        assert!(!import.fragment.contains("rate_limit_secs"));
+        assert_eq!(config.notifiers.emails.len(), 1);
+        assert!(config.power.policies.is_empty());
+        assert!(config.devices.ignore.is_empty());
+    }
+
+    #[test]
+    fn a_nas_with_a_default() {
+        let import = import(NAS);
+        let config: Config = toml::from_str(&import.fragment).unwrap();
+
+        assert_eq!(config.devices.ignore.len(), 1);
+        assert_eq!(config.devices.ignore[0].path.as_deref(), Some("/dev/sdc"));
+        let policies = config
+            .power
+            .policies
+            .iter()
+            .map(|policy| {
+                (
+                    policy.device.path.as_deref().unwrap(),
+                    policy.apm,
+                    policy.standby_after_secs,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            policies,
+            [
+                (
+                    "/dev/disk/by-id/ata-WDC_WD40EFRX-68N32N0_WD-WCC7K1234567",
+                    Some(128),
+                    Some(30 * 60)
+                ),
+                ("/dev/sdb", None, Some(0)),
+            ]
+        );
+        assert_eq!(config.notifiers.emails.len(), 1);
+        assert_eq!(config.notifiers.emails[0].to, ["admin@example.com"]);
+        assert_eq!(config.notifiers.emails[0].rate_limit_secs, 24 * 60 * 60);
+
+        // The DEFAULT line's directives are skipped for every device that
+        // has them, which is where smartd would have used them. sdd's own
+        // -a stands in for the DEFAULT's.
+        let by_id = "/dev/disk/by-id/ata-WDC_WD40EFRX-68N32N0_WD-WCC7K1234567";
+        assert_eq!(
+            skipped(&import),
+            [
+                (6, by_id, "-n standby,q", true),
+                (6, by_id, "-W 4,45,55", false),
+                (6, by_id, "-s (S/../.././02|L/../../6/03)", false),
+                (6, by_id, "-a", false),
+                (6, by_id, "-o on -S on", false),
+                (9, "/dev/sdb", "-n standby,q", true),
+                (9, "/dev/sdb", "-W 4,45,55", false),
+                (9, "/dev/sdb", "-d sat", true),
+                (9, "/dev/sdb", "-a", false),
+                (9, "/dev/sdb", "-o on -S on", false),
+                (11, "/dev/sdd", "-n standby,q", true),
+                (11, "/dev/sdd", "-W 4,45,55", false),
+                (11, "/dev/sdd", "-d megaraid,0", false),
+                (11, "/dev/sdd", "-a", false),
+                (11, "/dev/sdd", "-o on -S on", false),
+                (
+                    0,
+                    &format!("{}, /dev/sdb, /dev/sdd", by_id),
+                    "-m admin@example.com",
+                    false
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn devicescan_with_overrides() {
+        let import = import(OVERRIDES);
+        let config: Config = toml::from_str(&import.fragment).unwrap();
+
+        assert_eq!(config.devices.ignore[0].path.as_deref(), Some("/dev/sda"));
+        // The second DEFAULT replaces the first for everything after it.
+        assert_eq!(config.power.policies.len(), 1);
+        assert_eq!(
+            config.power.policies[0].device.path.as_deref(),
+            Some("/dev/*")
+        );
+        assert_eq!(config.power.policies[0].apm, Some(254));
+        assert_eq!(config.notifiers.emails.len(), 1);
+        assert_eq!(config.notifiers.emails[0].to, ["root@localhost"]);
+
+        assert_eq!(
+            skipped(&import),
+            [
+                (4, "/dev/nvme0", "-d nvme", true),
+                (4, "/dev/nvme0", "-W 5,60,70", false),
+                (6, "DEVICESCAN", "-d removable", true),
+                (6, "DEVICESCAN", "-n standby", true),
+                (6, "DEVICESCAN", "-a", false),
+                (7, "/dev/sdz", "", false),
+                (0, "/dev/nvme0", "-m root@localhost", false),
+            ]
+        );
+        assert_eq!(
+            import.skipped[5].reason,
+            "comes after DEVICESCAN, smartd doesn't read it"
+        );
+    }
+
+    #[test]
+    fn standby_timeouts_like_hdparm() {
+        assert_eq!(standby_secs(0), Some(0));
+        assert_eq!(standby_secs(12), Some(60));
+        assert_eq!(standby_secs(240), Some(20 * 60));
+        assert_eq!(standby_secs(242), Some(60 * 60));
+        assert_eq!(standby_secs(251), Some(11 * 30 * 60));
+        assert_eq!(standby_secs(252), None);
+
+        let import = import("/dev/sda -e standby,253 -e apm,0 -e lookahead,on\n");
+        assert_eq!(
+            skipped(&import),
+            [
+                (1, "/dev/sda", "-e standby,253", false),
+                (1, "/dev/sda", "-e apm,0", false),
+                (1, "/dev/sda", "-e lookahead,on", false),
+            ]
+        );
+        assert!(!import.fragment.contains("power.policies"));
+    }
+}
@@ -0,0 +1,205 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Instant,
+};
+
+use serde::Serialize;
+
+use crate::{
+    config::Backend,
+    device_health::{self, DeviceHealth},
+    identify_queue::{IdentifyQueue, IdentifyStats},
+    notifiers::notifier_host::{NotifierHost, NotifierStats},
+    plugins::plugin_host::{PluginHost, PluginStatus},
+    storage::{Registry, StorageStats},
+    supervisor::Health,
+    usage::{self, Usage},
+};
+
+/// What `hddmond status` prints. Every part comes from the stats its
+/// subsystem keeps about itself, this only puts them together.
+#[derive(Debug, Clone, Serialize)]
+pub struct Status {
+    /// The daemon's version.
+    pub version: &'static str,
+    /// How long it's been running.
+    pub uptime_secs: u64,
+    /// The backend it watches devices with.
+    pub backend: &'static str,
+    /// Supervised tasks, by name.
+    pub subsystems: BTreeMap<String, &'static str>,
+    /// None if the registry couldn't be read, the error is logged.
+    pub storage: Option<StorageStats>,
+    /// How each notification target is doing.
+    pub notifiers: Vec<NotifierStats>,
+    /// Every plugin loaded, and how it's doing.
+    pub plugins: Vec<PluginStatus>,
+    /// Where the daemon's own time went.
+    pub usage: Usage,
+    /// The smartctl runs identifying devices.
+    pub identify: IdentifyStats,
+    /// Every present device's health.
+    pub devices: Vec<DeviceHealth>,
+}
+
+/// Knows the parts of the status that don't change, and collects the rest
+/// from the subsystems when asked.
+pub struct StatusCollector {
+    version: &'static str,
+    started: Instant,
+    backend: Backend,
+    health: Health,
+    identify_queue: IdentifyQueue,
+}
+
+impl StatusCollector {
+    /// Starts counting uptime from now.
+    pub fn new(
+        version: &'static str,
+        backend: Backend,
+        health: Health,
+        identify_queue: IdentifyQueue,
+    ) -> Self {
+        Self {
+            version,
+            started: Instant::now(),
+            backend,
+            health,
+            identify_queue,
+        }
+    }
+
+    /// The status as of now, `link_errors` of the last day by kernel name.
+    pub fn collect(
+        &self,
+        registry: &Registry,
+        notifier_host: &NotifierHost,
+        plugin_host: &PluginHost,
+        link_errors: &HashMap<String, u64>,
+    ) -> Status {
+        let storage = registry
+            .stats()
+            .map_err(|e| error!("Can't get registry stats for the status: {}", e))
+            .ok();
+        let devices = registry
+            .devices(false)
+            .map(|records| device_health::verdicts(&records, link_errors))
+            .unwrap_or_else(|e| {
+                error!("Can't get the devices for the status: {}", e);
+                vec![]
+            });
+
+        Status {
+            version: self.version,
+            uptime_secs: self.started.elapsed().as_secs(),
+            backend: self.backend.as_str(),
+            subsystems: self
+                .health
+                .snapshot()
+                .into_iter()
+                .map(|(name, state)| (name, state.as_str()))
+                .collect(),
+            storage,
+            notifiers: notifier_host.stats(),
+            plugins: plugin_host.statuses(),
+            usage: usage::usage(),
+            identify: self.identify_queue.stats(),
+            devices,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use serde_json::Value;
+
+    use super::*;
+    use crate::{
+        config::{IdentifyConfig, NotifiersConfig},
+        device_policy::DeviceIdentity,
+        plugins::plugin_limits::PluginLimits,
+        storage::IN_MEMORY,
+        supervisor::SubsystemState,
+    };
+
+    fn drive(name: &str, serial: &str) -> DeviceIdentity {
+        DeviceIdentity {
+            name: name.to_string(),
+            serial: Some(serial.to_string()),
+            model: Some("WDC WD40EFRX-68N32N0".to_string()),
+            ..Default::default()
+        }
+    }
+
+    // Zeroes what changes from run to run, or with what other tests in the
+    // process did, leaving the keys.
+    fn settle(status: &mut Value) {
+        status["uptime_secs"] = 0.into();
+        status["storage"]["size_bytes"] = 0.into();
+        status["storage"]["last_write"] = 0.into();
+        status["usage"]["cpu_user_seconds"] = 0.0.into();
+        status["usage"]["cpu_system_seconds"] = 0.0.into();
+        for counter in status["usage"]["counters"]
+            .as_object_mut()
+            .unwrap()
+            .values_mut()
+        {
+            for value in counter.as_object_mut().unwrap().values_mut() {
+                *value = 0.into();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn status_json_shape() {
+        let mut registry = Registry::open(Path::new(IN_MEMORY)).unwrap();
+        for (name, serial) in [("sda", "WD-A"), ("sdb", "WD-B"), ("sdc", "WD-C")] {
+            registry.device_found(&drive(name, serial)).unwrap();
+        }
+        registry.device_lost("sdc").unwrap();
+        registry.set_quarantine("sdb", Some("clicking")).unwrap();
+
+        let health = Health::new();
+        health.register("event_reader");
+        health.register("disk_guard").set(SubsystemState::Failed);
+        let notifiers: NotifiersConfig =
+            toml::from_str("[[webhooks]]\nurl = \"http://127.0.0.1:9/hook\"\n").unwrap();
+        let notifier_host = NotifierHost::new(&notifiers, &health).unwrap();
+        // A directory that isn't there loads no plugins, and no runtime.
+        let plugin_host = PluginHost::load_dir(
+            Path::new("/nonexistent/plugins"),
+            PluginLimits::default(),
+            HashMap::new(),
+        )
+        .await
+        .unwrap();
+
+        let collector = StatusCollector::new(
+            "1.2.3",
+            Backend::Udev,
+            health,
+            IdentifyQueue::new(&IdentifyConfig::default()),
+        );
+        let status = collector.collect(
+            &registry,
+            &notifier_host,
+            &plugin_host,
+            &HashMap::from([("sda".to_string(), 3)]),
+        );
+        notifier_host.shutdown().await;
+        plugin_host.shutdown().await;
+
+        let mut status = serde_json::to_value(status).unwrap();
+        settle(&mut status);
+        let golden: Value =
+            serde_json::from_str(include_str!("../tests/fixtures/status/status.json")).unwrap();
+        assert_eq!(
+            status,
+            golden,
+            "{}",
+            serde_json::to_string_pretty(&status).unwrap()
+        );
+    }
+}
@@ -0,0 +1,3958 @@
+use std::{
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use rusqlite::{params, Connection, ErrorCode, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    capabilities::DeviceCapabilities,
+    config::StorageConfig,
+    device_policy::DeviceIdentity,
+    error::StorageError,
+    faults::{self, FaultPoint},
+    link_health::LinkHealth,
+    mmc::MmcHealth,
+    probe::DeviceContents,
+};
+
+/// Lets the registry be opened without touching the disk.
+pub const IN_MEMORY: &str = ":memory:";
+
+// Schema migrations, applied in order. The database's user_version is the
+// number of migrations it has seen; never edit one that has shipped, add a
+// new one instead.
+const MIGRATIONS: &[&str] = &[
+    r#"
+    CREATE TABLE devices (
+        id INTEGER PRIMARY KEY,
+        serial TEXT,
+        model TEXT,
+        wwn TEXT,
+        -- Kernel name the device had when it was last seen.
+        name TEXT NOT NULL,
+        -- DeviceIdentity as JSON.
+        info TEXT NOT NULL,
+        first_seen TEXT NOT NULL,
+        last_seen TEXT NOT NULL,
+        times_seen INTEGER NOT NULL,
+        present INTEGER NOT NULL
+    );
+    CREATE INDEX devices_serial_model ON devices (serial, model);
+    CREATE INDEX devices_wwn ON devices (wwn);
+    CREATE INDEX devices_name ON devices (name);
+"#,
+    r#"
+    -- DeviceContents as JSON, NULL until the device has been probed.
+    ALTER TABLE devices ADD COLUMN contents TEXT;
+"#,
+    r#"
+    -- The last few things that happened to each device, the oldest rows
+    -- are dropped as new ones come in.
+    CREATE TABLE device_events (
+        id INTEGER PRIMARY KEY,
+        device_id INTEGER NOT NULL,
+        at TEXT NOT NULL,
+        summary TEXT NOT NULL
+    );
+    CREATE INDEX device_events_device ON device_events (device_id, id);
+"#,
+    r#"
+    -- Set when smartctl disagrees with the kernel about the device's size,
+    -- as it does behind USB bridges that cut big drives short.
+    ALTER TABLE devices ADD COLUMN capacity_mismatch INTEGER NOT NULL DEFAULT 0;
+"#,
+    r#"
+    -- The firmware revision the device was last known to have, and one it
+    -- was seen with once since, which sticks once it's seen again.
+    ALTER TABLE devices ADD COLUMN firmware TEXT;
+    ALTER TABLE devices ADD COLUMN firmware_pending TEXT;
+"#,
+    r#"
+    -- DeviceCapabilities as JSON, NULL until they've been worked out.
+    ALTER TABLE devices ADD COLUMN capabilities TEXT;
+"#,
+    r#"
+    -- Each time a device was plugged in, from when it was found to when
+    -- it was lost. ended is NULL while it's still plugged in.
+    CREATE TABLE device_sessions (
+        id INTEGER PRIMARY KEY,
+        device_id INTEGER NOT NULL,
+        -- Its kernel name for the session.
+        name TEXT NOT NULL,
+        started TEXT NOT NULL,
+        ended TEXT
+    );
+    CREATE INDEX device_sessions_device ON device_sessions (device_id, id);
+    -- The session the device was in when it happened, NULL if none.
+    ALTER TABLE device_events ADD COLUMN session_id INTEGER;
+"#,
+    r#"
+    -- MmcHealth as JSON, NULL for anything but eMMC.
+    ALTER TABLE devices ADD COLUMN mmc_health TEXT;
+"#,
+    r#"
+    -- What people call the device, like "bay 7". Unique among the present
+    -- devices. alias_from_template is set when devices.alias_template
+    -- came up with it, rather than someone setting it by hand.
+    ALTER TABLE devices ADD COLUMN alias TEXT;
+    ALTER TABLE devices ADD COLUMN alias_from_template INTEGER NOT NULL DEFAULT 0;
+    CREATE INDEX devices_alias ON devices (alias);
+"#,
+    r#"
+    -- LinkHealth as JSON, the last time the device's link error counters
+    -- were read. NULL for devices without any.
+    ALTER TABLE devices ADD COLUMN link_health TEXT;
+"#,
+    r#"
+    -- Why the device is in quarantine, and since when. NULL when it isn't,
+    -- the daemon leaves anything that could change it alone while it is.
+    ALTER TABLE devices ADD COLUMN quarantine TEXT;
+    ALTER TABLE devices ADD COLUMN quarantined_since TEXT;
+"#,
+    r#"
+    -- Health problems that were notified about, one per device and reason
+    -- until the condition clears. Acknowledged ones aren't notified about
+    -- again until they clear or the acknowledgement runs out.
+    CREATE TABLE alerts (
+        id INTEGER PRIMARY KEY,
+        device_id INTEGER NOT NULL,
+        -- The notification kind, like device_became_read_only.
+        reason TEXT NOT NULL,
+        summary TEXT NOT NULL,
+        opened TEXT NOT NULL,
+        last_raised TEXT NOT NULL,
+        times INTEGER NOT NULL,
+        acked_at TEXT,
+        ack_note TEXT,
+        cleared_at TEXT
+    );
+    CREATE INDEX alerts_device ON alerts (device_id, reason, cleared_at);
+"#,
+    r#"
+    -- The registry's own name for the device, a random UUID that never
+    -- changes, and what it was told apart from others by the last time it
+    -- was found, see IdentityBasis. Records from before this were found by
+    -- their serial and model, or their WWN without a serial.
+    ALTER TABLE devices ADD COLUMN uuid TEXT;
+    ALTER TABLE devices ADD COLUMN identity_basis TEXT;
+    UPDATE devices SET
+        uuid = lower(hex(randomblob(4))) || '-' || lower(hex(randomblob(2))) || '-4' ||
+            substr(lower(hex(randomblob(2))), 2) || '-' ||
+            substr('89ab', 1 + abs(random()) % 4, 1) || substr(lower(hex(randomblob(2))), 2) ||
+            '-' || lower(hex(randomblob(6))),
+        identity_basis = CASE WHEN serial IS NULL THEN 'wwn' ELSE 'serial_model' END;
+    CREATE UNIQUE INDEX devices_uuid ON devices (uuid);
+    -- What the weaker bases go by: the USB port of the bridge the device is
+    -- behind, its size, and its /dev/disk/by-path link or device node.
+    ALTER TABLE devices ADD COLUMN usb_port TEXT;
+    ALTER TABLE devices ADD COLUMN size_bytes INTEGER;
+    ALTER TABLE devices ADD COLUMN by_path TEXT;
+    CREATE INDEX devices_usb_port ON devices (usb_port, size_bytes);
+    CREATE INDEX devices_by_path ON devices (by_path);
+    -- UUIDs of records that turned out to be the same drive as another
+    -- one and were merged into it, so they still lead to it.
+    CREATE TABLE merged_devices (
+        uuid TEXT PRIMARY KEY,
+        device_id INTEGER NOT NULL,
+        merged_at TEXT NOT NULL
+    );
+    -- Serials that two present devices had at once, which don't tell
+    -- drives apart.
+    CREATE TABLE shared_serials (
+        serial TEXT NOT NULL,
+        model TEXT,
+        seen TEXT NOT NULL
+    );
+    CREATE INDEX shared_serials_serial ON shared_serials (serial, model);
+"#,
+];
+
+/// How many events are kept per device unless Registry::set_event_limit
+/// says otherwise.
+pub const DEFAULT_EVENT_LIMIT: u32 = 200;
+
+/// How long after a device was lost it can come back and carry on with
+/// the same session, unless Registry::set_session_grace says otherwise.
+pub const DEFAULT_SESSION_GRACE: Duration = Duration::from_secs(60);
+
+// How many sessions are kept per device, the oldest are dropped first.
+const SESSION_LIMIT: u32 = 200;
+
+/// How often Registry::maintain should be called to not miss its hour.
+pub const MAINTENANCE_CHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+// Long enough that maintenance runs only once within its hour.
+const MAINTENANCE_COOLDOWN: Duration = Duration::from_secs(2 * 60 * 60);
+
+// Timestamps are UTC, second resolution, and sort as text.
+const NOW: &str = "strftime('%Y-%m-%dT%H:%M:%SZ', 'now')";
+
+// A random (version 4) UUID, the same as migration 13 gives the records
+// from before it.
+const NEW_UUID: &str = "lower(hex(randomblob(4))) || '-' || lower(hex(randomblob(2))) || '-4' || \
+                        substr(lower(hex(randomblob(2))), 2) || '-' || \
+                        substr('89ab', 1 + abs(random()) % 4, 1) || \
+                        substr(lower(hex(randomblob(2))), 2) || '-' || lower(hex(randomblob(6)))";
+
+/// What devices are told apart by unless Registry::set_identity says
+/// otherwise, the most trustworthy first.
+pub const DEFAULT_IDENTITY: &[IdentityBasis] = &[
+    IdentityBasis::Wwn,
+    IdentityBasis::SerialModel,
+    IdentityBasis::UsbPortSize,
+    IdentityBasis::Path,
+];
+
+/// Something a device can be told apart from others by. A device is found
+/// by the first in the chain it has that leads to a record, and a new
+/// record goes by the first it has at all.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IdentityBasis {
+    /// Its World Wide Name.
+    Wwn,
+    /// Its serial together with its model. Not for serials in
+    /// storage.shared_serials, or one another present device has too, the
+    /// way cheap USB bridges report the same serial for every drive.
+    SerialModel,
+    /// The USB port of the bridge it's behind and its size. Two drives the
+    /// same size that take turns on the same port look the same.
+    UsbPortSize,
+    /// Where it's attached, its /dev/disk/by-path link or its device node.
+    /// Another drive on the same port or name looks the same.
+    Path,
+}
+
+impl IdentityBasis {
+    /// As it's serialized.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IdentityBasis::Wwn => "wwn",
+            IdentityBasis::SerialModel => "serial_model",
+            IdentityBasis::UsbPortSize => "usb_port_size",
+            IdentityBasis::Path => "path",
+        }
+    }
+
+    fn parse(basis: &str) -> Option<Self> {
+        DEFAULT_IDENTITY
+            .iter()
+            .copied()
+            .find(|known| known.as_str() == basis)
+    }
+
+    // Whether two records matching on it proves they're the same drive.
+    // The weaker ones are guesses, good enough to find a record by but not
+    // to merge two.
+    fn proves(&self) -> bool {
+        matches!(self, IdentityBasis::Wwn | IdentityBasis::SerialModel)
+    }
+
+    fn describe(&self) -> &'static str {
+        match self {
+            IdentityBasis::Wwn => "its WWN",
+            IdentityBasis::SerialModel => "its serial and model",
+            IdentityBasis::UsbPortSize => "its USB port and size",
+            IdentityBasis::Path => "where it's attached",
+        }
+    }
+}
+
+/// A device as the registry remembers it.
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceRecord {
+    /// The registry's own ID, stable for as long as the record exists.
+    pub id: i64,
+    /// The registry's name for it, a UUID that stays the same whatever it
+    /// was found by, and what it goes by outside the registry. Records
+    /// merged into it are still found by theirs.
+    pub uuid: String,
+    /// What it was told apart from other devices by the last time it was
+    /// found. None if this version doesn't know the one that was used.
+    pub identity_basis: Option<IdentityBasis>,
+    /// The serial number, if it reported one.
+    pub serial: Option<String>,
+    /// The model, if it reported one.
+    pub model: Option<String>,
+    /// The World Wide Name, if it has one.
+    pub wwn: Option<String>,
+    /// The kernel name it had when it was last seen.
+    pub name: String,
+    /// Everything udev said about it the last time, as JSON.
+    pub info: serde_json::Value,
+    /// When it was first seen, as UTC RFC 3339 with second resolution.
+    pub first_seen: String,
+    /// When it was last seen, or when it left if it isn't present.
+    pub last_seen: String,
+    /// How many times it has shown up.
+    pub times_seen: i64,
+    /// Whether it's plugged in right now, as far as the daemon knows.
+    pub present: bool,
+    /// Its partitions and filesystems the last time it was probed, as
+    /// JSON. Null if it never was.
+    pub contents: serde_json::Value,
+    /// Whether smartctl disagreed with the kernel about its capacity the
+    /// last time it was checked. `info` has the kernel's side.
+    pub capacity_mismatch: bool,
+    /// Its firmware revision, once it was seen with it twice in a row.
+    pub firmware: Option<String>,
+    /// What can be done to it, as JSON, worked out when it was last found.
+    /// Null if that never happened.
+    pub capabilities: serde_json::Value,
+    /// How worn out an eMMC device is, as JSON, from when it was last
+    /// read. Null for anything else.
+    pub mmc_health: serde_json::Value,
+    /// What people call it, if it was given a name.
+    pub alias: Option<String>,
+    /// Whether the alias came from devices.alias_template, rather than
+    /// being set by hand.
+    pub alias_from_template: bool,
+    /// The kernel's error counters for the links it's on, as JSON, from
+    /// when they were last read. Null if it has none.
+    pub link_health: serde_json::Value,
+    /// Why it's in quarantine, if it is. Nothing that could change it is
+    /// done to it while it is.
+    pub quarantine: Option<String>,
+    /// When it was put in quarantine, as UTC RFC 3339 with second
+    /// resolution.
+    pub quarantined_since: Option<String>,
+}
+
+impl DeviceRecord {
+    /// Its alias if it has one, its kernel name if not.
+    pub fn display_name(&self) -> &str {
+        self.alias.as_deref().unwrap_or(&self.name)
+    }
+}
+
+/// A health problem with a device that was notified about, like it going
+/// read-only. There's one per device and reason until the condition
+/// clears.
+#[derive(Debug, Clone, Serialize)]
+pub struct Alert {
+    /// Its number, for acknowledging it by.
+    pub id: i64,
+    /// The device's row id.
+    pub device_id: i64,
+    /// The device's kernel name, as it was last seen.
+    pub device: String,
+    /// What people call the device, if anything.
+    pub alias: Option<String>,
+    /// The device's serial.
+    pub serial: Option<String>,
+    /// The notification kind, like `device_became_read_only`.
+    pub reason: String,
+    /// What the last notification about it said.
+    pub summary: String,
+    /// When it was first raised, as UTC RFC 3339 with second resolution.
+    pub opened: String,
+    /// When it was last raised.
+    pub last_raised: String,
+    /// How many times it was raised in all.
+    pub times: u32,
+    /// When someone acknowledged it, which keeps it from being notified
+    /// about.
+    pub acked_at: Option<String>,
+    /// What they said about it.
+    pub ack_note: Option<String>,
+    /// When its condition cleared, which closes it for good.
+    pub cleared_at: Option<String>,
+}
+
+impl Alert {
+    /// Where the alert is at.
+    pub fn state(&self) -> AlertState {
+        if self.cleared_at.is_some() {
+            AlertState::Cleared
+        } else if self.acked_at.is_some() {
+            AlertState::Acknowledged
+        } else {
+            AlertState::Open
+        }
+    }
+}
+
+/// Where an alert is at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertState {
+    /// Notified about every time it's raised.
+    Open,
+    /// Someone knows, it isn't notified about for now.
+    Acknowledged,
+    /// Its condition went away.
+    Cleared,
+}
+
+impl AlertState {
+    /// As it's serialized.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AlertState::Open => "open",
+            AlertState::Acknowledged => "acknowledged",
+            AlertState::Cleared => "cleared",
+        }
+    }
+}
+
+/// A device's firmware revision changing, between two times it was seen.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FirmwareChange {
+    /// The revision it had.
+    pub from: String,
+    /// The one it has now.
+    pub to: String,
+}
+
+/// Something that happened to a device, in a line.
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceEvent {
+    /// When, as UTC RFC 3339 with second resolution.
+    pub at: String,
+    /// What, like `Found as sdb`.
+    pub summary: String,
+    /// The session it happened in, None if the device wasn't plugged in.
+    pub session: Option<i64>,
+}
+
+/// A device and what happened to it lately, for Registry::recent_history.
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceHistory {
+    /// The device.
+    pub device: DeviceRecord,
+    /// Its events since the time asked for, oldest first.
+    pub events: Vec<DeviceEvent>,
+}
+
+/// One stretch of a device being plugged in.
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceSession {
+    /// The registry's own ID for it, what events are tagged with.
+    pub id: i64,
+    /// Its kernel name while it was plugged in.
+    pub name: String,
+    /// When it was found, as UTC RFC 3339 with second resolution.
+    pub started: String,
+    /// When it was lost, None while it's still plugged in.
+    pub ended: Option<String>,
+    /// How long it was plugged in for, None while it still is.
+    pub duration_secs: Option<i64>,
+}
+
+/// Numbers about the registry, for `hddmond status`.
+#[derive(Debug, Clone, Serialize)]
+pub struct StorageStats {
+    /// The database file, or IN_MEMORY.
+    pub path: PathBuf,
+    /// How big the database is, free pages included.
+    pub size_bytes: u64,
+    /// How many recorded devices are present.
+    pub present: u64,
+    /// How many recorded devices aren't.
+    pub absent: u64,
+    /// When this daemon last changed anything in it, as a unix timestamp.
+    pub last_write: Option<u64>,
+    /// Whether writes are paused because the disk is low on space.
+    pub low_on_space: bool,
+}
+
+/// Every drive the daemon has ever seen, each with a UUID of its own.
+/// Devices are told apart by the chain of IdentityBasis given to
+/// Registry::set_identity. A device that has none of them can't be told
+/// apart from the next one and isn't recorded.
+///
+/// When a device turns out, by its WWN or serial, to be the same drive as
+/// a record it wasn't found by, like once its WWN can be read, that record
+/// is merged into the one it was found by.
+pub struct Registry {
+    conn: Connection,
+    path: PathBuf,
+    last_maintenance: Option<Instant>,
+    last_write: Option<u64>,
+    event_limit: u32,
+    session_grace: Duration,
+    low_on_space: bool,
+    identity: IdentityChain,
+}
+
+// What Registry::set_identity was given.
+#[derive(Debug, Clone)]
+struct IdentityChain {
+    bases: Vec<IdentityBasis>,
+    shared_serials: Vec<String>,
+}
+
+impl Registry {
+    /// Opens the database at `path`, or an in-memory one for IN_MEMORY,
+    /// creating and migrating it as needed.
+    ///
+    /// A database that turns out to be corrupt is moved aside and replaced
+    /// with an empty one. Losing the history is bad, but not as bad as a
+    /// daemon that can't start until someone fixes it by hand.
+    pub fn open(path: &Path) -> Result<Self, StorageError> {
+        if path == Path::new(IN_MEMORY) {
+            return Self::open_file(path);
+        }
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|error| StorageError::CreateDir {
+                path: parent.to_path_buf(),
+                error,
+            })?;
+        }
+
+        let registry = match Self::open_file(path) {
+            Ok(registry) => registry,
+            Err(e) if is_corrupt(&e) => return Self::start_over(path, &e.to_string()),
+            Err(e) => return Err(e),
+        };
+
+        let checked = registry
+            .check("quick_check")
+            .map_err(|error| StorageError::Open {
+                path: path.to_path_buf(),
+                error,
+            })?;
+        match checked {
+            Some(problem) => {
+                drop(registry);
+                Self::start_over(path, &problem)
+            }
+            None => Ok(registry),
+        }
+    }
+
+    fn open_file(path: &Path) -> Result<Self, StorageError> {
+        let conn = if path == Path::new(IN_MEMORY) {
+            Connection::open_in_memory()
+        } else {
+            Connection::open(path)
+        }
+        .map_err(|error| StorageError::Open {
+            path: path.to_path_buf(),
+            error,
+        })?;
+
+        let mut registry = Self {
+            conn,
+            path: path.to_path_buf(),
+            last_maintenance: None,
+            last_write: None,
+            event_limit: DEFAULT_EVENT_LIMIT,
+            session_grace: DEFAULT_SESSION_GRACE,
+            low_on_space: false,
+            identity: IdentityChain {
+                bases: DEFAULT_IDENTITY.to_vec(),
+                shared_serials: vec![],
+            },
+        };
+        registry.migrate()?;
+
+        Ok(registry)
+    }
+
+    fn start_over(path: &Path, problem: &str) -> Result<Self, StorageError> {
+        if path != Path::new(IN_MEMORY) {
+            let suffix = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|since| since.as_secs())
+                .unwrap_or_default();
+            let aside = with_suffix(path, &format!(".corrupt-{}", suffix));
+
+            error!(
+                "Database {} is corrupt ({}), moving it to {} and starting with an empty one. \
+                 The device history is in the old file.",
+                path.display(),
+                problem,
+                aside.display()
+            );
+
+            fs::rename(path, &aside).map_err(|error| StorageError::MoveAside {
+                path: path.to_path_buf(),
+                error,
+            })?;
+            // A leftover journal belongs to the old file, it must not be
+            // replayed into the new one.
+            let journal = with_suffix(path, "-journal");
+            if journal.exists() {
+                fs::rename(&journal, with_suffix(&aside, "-journal")).map_err(|error| {
+                    StorageError::MoveAside {
+                        path: journal.clone(),
+                        error,
+                    }
+                })?;
+            }
+        }
+
+        Self::open_file(path)
+    }
+
+    fn migrate(&mut self) -> Result<(), StorageError> {
+        let migrate_error = |version, error| StorageError::Migrate {
+            path: self.path.clone(),
+            version,
+            error,
+        };
+
+        let version: usize = self
+            .conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .map_err(|error| migrate_error(0, error))?;
+
+        for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let mut apply = || -> rusqlite::Result<()> {
+                let tx = self.conn.transaction()?;
+                tx.execute_batch(migration)?;
+                tx.pragma_update(None, "user_version", index + 1)?;
+                tx.commit()
+            };
+            apply().map_err(|error| migrate_error(index + 1, error))?;
+            info!("Applied database migration {}", index + 1);
+        }
+
+        Ok(())
+    }
+
+    // Runs integrity_check or quick_check, returning what's wrong if
+    // anything is.
+    fn check(&self, pragma: &str) -> rusqlite::Result<Option<String>> {
+        let result = self
+            .conn
+            .query_row(&format!("PRAGMA {}", pragma), [], |row| {
+                row.get::<_, String>(0)
+            });
+
+        match result {
+            Ok(result) if result == "ok" => Ok(None),
+            Ok(result) => Ok(Some(result)),
+            Err(e) if is_corrupt_sqlite(&e) => Ok(Some(e.to_string())),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Prunes, checks and compacts the database, once a day during the
+    /// configured hour. Called every MAINTENANCE_CHECK_INTERVAL; it all
+    /// happens on the caller's thread, which is fine for a database this
+    /// small.
+    pub fn maintain(&mut self, config: &StorageConfig) -> Result<(), StorageError> {
+        let maintenance_error = |error| StorageError::Query {
+            operation: "maintain the database",
+            error,
+        };
+
+        let hour: u8 = self
+            .conn
+            .query_row(
+                "SELECT CAST(strftime('%H', 'now', 'localtime') AS INTEGER)",
+                [],
+                |row| row.get(0),
+            )
+            .map_err(maintenance_error)?;
+        self.maintain_at(hour, config)
+    }
+
+    // The same, once the local hour is known.
+    fn maintain_at(&mut self, hour: u8, config: &StorageConfig) -> Result<(), StorageError> {
+        let maintenance_error = |error| StorageError::Query {
+            operation: "maintain the database",
+            error,
+        };
+
+        let ran_recently = self
+            .last_maintenance
+            .is_some_and(|last| last.elapsed() < MAINTENANCE_COOLDOWN);
+        if hour != config.maintenance_hour || ran_recently {
+            return Ok(());
+        }
+        self.last_maintenance = Some(Instant::now());
+
+        info!("Starting database maintenance");
+
+        if let Some(problem) = self.check("integrity_check").map_err(maintenance_error)? {
+            // The connection has to be closed before its file is moved.
+            // Only it is replaced, everything the registry was configured
+            // with, and when maintenance last ran, stays as it was.
+            self.conn = Connection::open_in_memory().map_err(maintenance_error)?;
+            self.conn = Self::start_over(&self.path, &problem)?.conn;
+            return Ok(());
+        }
+
+        if let Some(days) = config.prune_absent_after_days {
+            let pruned = self.prune_absent(days).map_err(maintenance_error)?;
+            if pruned > 0 {
+                info!(
+                    "Pruned {} device(s) not seen for more than {} days",
+                    pruned, days
+                );
+            }
+        }
+
+        let pages: u64 = self
+            .conn
+            .query_row("PRAGMA page_count", [], |row| row.get(0))
+            .map_err(maintenance_error)?;
+        let free: u64 = self
+            .conn
+            .query_row("PRAGMA freelist_count", [], |row| row.get(0))
+            .map_err(maintenance_error)?;
+        if self.low_on_space {
+            // VACUUM writes a whole new copy of the database first.
+            info!("Not compacting the database, the disk is low on space");
+        } else if pages > 0 && free * 100 / pages >= config.vacuum_threshold_percent as u64 {
+            let page_size: u64 = self
+                .conn
+                .query_row("PRAGMA page_size", [], |row| row.get(0))
+                .map_err(maintenance_error)?;
+            self.conn
+                .execute_batch("VACUUM")
+                .map_err(maintenance_error)?;
+            let after: u64 = self
+                .conn
+                .query_row("PRAGMA page_count", [], |row| row.get(0))
+                .map_err(maintenance_error)?;
+            info!(
+                "Vacuumed the database, reclaimed {} KiB",
+                pages.saturating_sub(after) * page_size / 1024
+            );
+        }
+
+        info!("Database maintenance done");
+
+        Ok(())
+    }
+
+    // Forgets the devices that were last seen more than `days` ago and
+    // everything about them, and returns how many.
+    fn prune_absent(&mut self, days: u64) -> rusqlite::Result<usize> {
+        let pruned = self.conn.execute(
+            "DELETE FROM devices \
+             WHERE present = 0 AND last_seen < strftime('%Y-%m-%dT%H:%M:%SZ', 'now', ?1)",
+            params![format!("-{} days", days)],
+        )?;
+        if pruned > 0 {
+            self.conn.execute_batch(
+                "DELETE FROM device_events WHERE device_id NOT IN (SELECT id FROM devices); \
+                 DELETE FROM device_sessions WHERE device_id NOT IN (SELECT id FROM devices); \
+                 DELETE FROM alerts WHERE device_id NOT IN (SELECT id FROM devices); \
+                 DELETE FROM merged_devices WHERE device_id NOT IN (SELECT id FROM devices);",
+            )?;
+            self.wrote();
+        }
+        Ok(pruned)
+    }
+
+    /// Marks every device as absent. The daemon wasn't watching while it
+    /// was down, so nothing is known to be present until it's seen again.
+    /// Sessions left open end with the last thing that happened in them.
+    pub fn reset_presence(&mut self) -> Result<(), StorageError> {
+        self.conn
+            .execute_batch(
+                "UPDATE device_sessions SET ended = IFNULL( \
+                     (SELECT MAX(at) FROM device_events WHERE session_id = device_sessions.id), \
+                     started) \
+                 WHERE ended IS NULL; \
+                 UPDATE devices SET present = 0 WHERE present = 1;",
+            )
+            .map_err(|error| StorageError::Query {
+                operation: "reset which devices are present",
+                error,
+            })?;
+        self.wrote();
+        Ok(())
+    }
+
+    /// Records a device showing up, as a new record or as an old one seen
+    /// again, and returns its UUID. None if it has nothing the registry
+    /// tells devices apart by.
+    pub fn device_found(
+        &mut self,
+        identity: &DeviceIdentity,
+    ) -> Result<Option<String>, StorageError> {
+        let info = serde_json::to_string(identity).map_err(|error| StorageError::Serialize {
+            device: identity.name.clone(),
+            error,
+        })?;
+        let uuid = write_fault(&identity.name)
+            .and_then(|()| self.record_found(identity, &info))
+            .map_err(|error| StorageError::Device {
+                operation: "found",
+                device: identity.name.clone(),
+                error,
+            })?;
+        if uuid.is_some() {
+            self.wrote();
+        }
+        Ok(uuid)
+    }
+
+    /// How many events to keep per device, 0 to keep none.
+    pub fn set_event_limit(&mut self, limit: u32) {
+        self.event_limit = limit;
+    }
+
+    /// How long after a device was lost it can come back and carry on with
+    /// the same session, rather than start a new one. Covers cables being
+    /// reseated and bridges that reset.
+    pub fn set_session_grace(&mut self, grace: Duration) {
+        self.session_grace = grace;
+    }
+
+    /// What devices are told apart by, the most trustworthy first, and
+    /// serials that don't tell drives apart on top of the ones the registry
+    /// worked out itself.
+    pub fn set_identity(&mut self, bases: &[IdentityBasis], shared_serials: &[String]) {
+        self.identity = IdentityChain {
+            bases: bases.to_vec(),
+            shared_serials: shared_serials.to_vec(),
+        };
+    }
+
+    /// While the disk is low on space, no new device events are kept and
+    /// maintenance doesn't compact the database. Devices are still
+    /// recorded as they come and go.
+    pub fn set_low_on_space(&mut self, low: bool) {
+        self.low_on_space = low;
+    }
+
+    fn events_kept(&self) -> u32 {
+        if self.low_on_space {
+            0
+        } else {
+            self.event_limit
+        }
+    }
+
+    fn record_found(
+        &mut self,
+        identity: &DeviceIdentity,
+        info: &str,
+    ) -> rusqlite::Result<Option<String>> {
+        let event_limit = self.events_kept();
+        let grace_secs = self.session_grace.as_secs();
+        let tx = self.conn.transaction()?;
+
+        // Whatever had this name before is gone, even if we missed it
+        // leaving.
+        tx.execute(
+            &format!(
+                "UPDATE device_sessions SET ended = {} WHERE ended IS NULL AND device_id IN \
+                 (SELECT id FROM devices WHERE name = ?1 AND present = 1)",
+                NOW
+            ),
+            params![identity.name],
+        )?;
+        tx.execute(
+            "UPDATE devices SET present = 0 WHERE name = ?1 AND present = 1",
+            params![identity.name],
+        )?;
+
+        remember_shared_serial(&tx, identity)?;
+        let bases = bases(&tx, identity, &self.identity)?;
+        let basis = match bases.first() {
+            Some(&basis) => basis,
+            None => {
+                debug!(
+                    "{} has nothing storage.identity tells devices apart by, not recording it",
+                    identity.name
+                );
+                return Ok(None);
+            }
+        };
+
+        // Every record it could be, by each basis it has. It's the first
+        // one found, the others that prove to be the same drive are merged
+        // into that.
+        let mut found = None;
+        let mut merges = vec![];
+        let trusted = bases.contains(&IdentityBasis::SerialModel);
+        for &by in &bases {
+            for (id, present) in lookup(&tx, by, identity, trusted)? {
+                match found {
+                    None => found = Some(id),
+                    Some(found) if id != found && by.proves() && !present => {
+                        if !merges.iter().any(|&(merge, _)| merge == id) {
+                            merges.push((id, by));
+                        }
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+
+        let size = identity
+            .capacity
+            .as_ref()
+            .map(|capacity| capacity.size_bytes as i64);
+        let id = match found {
+            Some(id) => {
+                let was: Option<String> = tx.query_row(
+                    "SELECT identity_basis FROM devices WHERE id = ?1",
+                    params![id],
+                    |row| row.get(0),
+                )?;
+                tx.execute(
+                    &format!(
+                        "UPDATE devices SET name = ?2, serial = IFNULL(?3, serial), \
+                         model = IFNULL(?4, model), wwn = IFNULL(?5, wwn), info = ?6, \
+                         last_seen = {}, times_seen = times_seen + 1, present = 1, \
+                         identity_basis = ?7, usb_port = ?8, size_bytes = IFNULL(?9, size_bytes), \
+                         by_path = ?10 \
+                         WHERE id = ?1",
+                        NOW
+                    ),
+                    params![
+                        id,
+                        identity.name,
+                        identity.serial,
+                        identity.model,
+                        identity.wwn,
+                        info,
+                        basis.as_str(),
+                        identity.usb_port,
+                        size,
+                        by_path(identity)
+                    ],
+                )?;
+                if let Some(was) = was.as_deref().and_then(IdentityBasis::parse) {
+                    if was != basis {
+                        log_event(
+                            &tx,
+                            id,
+                            &format!(
+                                "Told apart by {} now, it was by {}",
+                                basis.describe(),
+                                was.describe()
+                            ),
+                            event_limit,
+                        )?;
+                    }
+                }
+                id
+            }
+            None => {
+                tx.execute(
+                    &format!(
+                        "INSERT INTO devices \
+                         (uuid, identity_basis, serial, model, wwn, usb_port, size_bytes, by_path, \
+                         name, info, first_seen, last_seen, times_seen, present) \
+                         VALUES ({uuid}, ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, {now}, {now}, 1, 1)",
+                        uuid = NEW_UUID,
+                        now = NOW
+                    ),
+                    params![
+                        basis.as_str(),
+                        identity.serial,
+                        identity.model,
+                        identity.wwn,
+                        identity.usb_port,
+                        size,
+                        by_path(identity),
+                        identity.name,
+                        info
+                    ],
+                )?;
+                tx.last_insert_rowid()
+            }
+        };
+
+        for (merge, by) in merges {
+            let uuid = merge_into(&tx, id, merge, by, event_limit)?;
+            info!(
+                "{} is the same drive as {} by {}, merged that record into its own",
+                identity.name,
+                uuid,
+                by.describe()
+            );
+        }
+
+        open_session(&tx, id, &identity.name, grace_secs)?;
+        log_event(&tx, id, &format!("Found as {}", identity.name), event_limit)?;
+        let uuid = tx.query_row(
+            "SELECT uuid FROM devices WHERE id = ?1",
+            params![id],
+            |row| row.get(0),
+        )?;
+        tx.commit()?;
+        Ok(Some(uuid))
+    }
+
+    /// Records the device with this kernel name leaving, and returns it if
+    /// it was in the registry. Once it's gone there's no asking udev who it
+    /// was anymore.
+    pub fn device_lost(&mut self, name: &str) -> Result<Option<DeviceRecord>, StorageError> {
+        let record = write_fault(name)
+            .and_then(|()| {
+                self.conn.query_row(
+                    &format!(
+                        "UPDATE devices SET last_seen = {}, present = 0 \
+                         WHERE name = ?1 AND present = 1 RETURNING {}",
+                        NOW, COLUMNS
+                    ),
+                    params![name],
+                    record_from_row,
+                )
+            })
+            .optional()
+            .and_then(|record| {
+                if let Some(record) = &record {
+                    log_event(
+                        &self.conn,
+                        record.id,
+                        &format!("Lost (was {})", name),
+                        self.events_kept(),
+                    )?;
+                    self.conn.execute(
+                        &format!(
+                            "UPDATE device_sessions SET ended = {} \
+                             WHERE device_id = ?1 AND ended IS NULL",
+                            NOW
+                        ),
+                        params![record.id],
+                    )?;
+                }
+                Ok(record)
+            })
+            .map_err(|error| StorageError::Device {
+                operation: "lost",
+                device: name.to_string(),
+                error,
+            })?;
+        if record.is_some() {
+            self.wrote();
+        }
+        Ok(record)
+    }
+
+    /// Adds to a device's events. A device the registry doesn't know is
+    /// left alone.
+    pub fn log_event(
+        &mut self,
+        identity: &DeviceIdentity,
+        summary: &str,
+    ) -> Result<(), StorageError> {
+        let log = || -> rusqlite::Result<bool> {
+            write_fault(&identity.name)?;
+            match find(&self.conn, identity, &self.identity)? {
+                Some(id) => log_event(&self.conn, id, summary, self.events_kept()).map(|()| true),
+                None => Ok(false),
+            }
+        };
+        let logged = log().map_err(|error| StorageError::Query {
+            operation: "log a device event",
+            error,
+        })?;
+
+        if logged {
+            self.wrote();
+        }
+        Ok(())
+    }
+
+    /// Records the firmware revision the device was just seen with, and
+    /// returns the change if that made it a different one than before.
+    ///
+    /// A new revision only counts once it has been seen twice in a row,
+    /// some USB bridges now and then report garbage for it. The first
+    /// revision a device is seen with counts right away, there's nothing
+    /// to mistake it for.
+    pub fn firmware_seen(
+        &mut self,
+        identity: &DeviceIdentity,
+    ) -> Result<Option<FirmwareChange>, StorageError> {
+        let firmware = match &identity.firmware {
+            Some(firmware) => firmware,
+            None => return Ok(None),
+        };
+
+        let (wrote, change) =
+            self.record_firmware(identity, firmware)
+                .map_err(|error| StorageError::Device {
+                    operation: "seen with its firmware",
+                    device: identity.name.clone(),
+                    error,
+                })?;
+        if wrote {
+            self.wrote();
+        }
+        Ok(change)
+    }
+
+    fn record_firmware(
+        &mut self,
+        identity: &DeviceIdentity,
+        firmware: &str,
+    ) -> rusqlite::Result<(bool, Option<FirmwareChange>)> {
+        let event_limit = self.events_kept();
+        let tx = self.conn.transaction()?;
+        let id = match find(&tx, identity, &self.identity)? {
+            Some(id) => id,
+            None => return Ok((false, None)),
+        };
+        let (known, pending): (Option<String>, Option<String>) = tx.query_row(
+            "SELECT firmware, firmware_pending FROM devices WHERE id = ?1",
+            params![id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+
+        let change = match known {
+            Some(known) if known == firmware => {
+                if pending.is_none() {
+                    return Ok((false, None));
+                }
+                None
+            }
+            Some(known) if pending.as_deref() == Some(firmware) => Some(FirmwareChange {
+                from: known,
+                to: firmware.to_string(),
+            }),
+            Some(known) => {
+                debug!(
+                    "{} says its firmware is {}, not {}, waiting to see it again",
+                    identity.name, firmware, known
+                );
+                tx.execute(
+                    "UPDATE devices SET firmware_pending = ?2 WHERE id = ?1",
+                    params![id, firmware],
+                )?;
+                tx.commit()?;
+                return Ok((true, None));
+            }
+            None => None,
+        };
+
+        tx.execute(
+            "UPDATE devices SET firmware = ?2, firmware_pending = NULL WHERE id = ?1",
+            params![id, firmware],
+        )?;
+        if let Some(change) = &change {
+            log_event(
+                &tx,
+                id,
+                &format!("Firmware changed from {} to {}", change.from, change.to),
+                event_limit,
+            )?;
+        }
+        tx.commit()?;
+        Ok((true, change))
+    }
+
+    /// The device with this UUID, serial, WWN or alias, or the one present
+    /// under this kernel name. The UUID of a record that was merged into
+    /// another one leads to that one. Present devices go first, for aliases
+    /// that went to another device since.
+    pub fn device(&self, key: &str) -> Result<Option<DeviceRecord>, StorageError> {
+        self.conn
+            .query_row(
+                &format!(
+                    "SELECT {} FROM devices \
+                     WHERE uuid = lower(?1) OR serial = ?1 OR wwn = ?1 OR alias = ?1 \
+                     OR (name = ?1 AND present = 1) \
+                     OR id = (SELECT device_id FROM merged_devices WHERE uuid = lower(?1)) \
+                     ORDER BY uuid = lower(?1) DESC, present DESC, last_seen DESC LIMIT 1",
+                    COLUMNS
+                ),
+                params![key],
+                record_from_row,
+            )
+            .optional()
+            .map_err(|error| StorageError::Query {
+                operation: "look the device up",
+                error,
+            })
+    }
+
+    /// A device's events, oldest first, only those in `session` if given.
+    pub fn events(
+        &self,
+        device_id: i64,
+        session: Option<i64>,
+    ) -> Result<Vec<DeviceEvent>, StorageError> {
+        let list = || -> rusqlite::Result<Vec<DeviceEvent>> {
+            let mut statement = self.conn.prepare(
+                "SELECT at, summary, session_id FROM device_events \
+                 WHERE device_id = ?1 AND (?2 IS NULL OR session_id = ?2) ORDER BY id",
+            )?;
+            let events = statement
+                .query_map(params![device_id, session], |row| {
+                    Ok(DeviceEvent {
+                        at: row.get(0)?,
+                        summary: row.get(1)?,
+                        session: row.get(2)?,
+                    })
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(events)
+        };
+
+        list().map_err(|error| StorageError::Query {
+            operation: "list the device's events",
+            error,
+        })
+    }
+
+    /// The times a device was plugged in, oldest first.
+    pub fn sessions(&self, device_id: i64) -> Result<Vec<DeviceSession>, StorageError> {
+        let list = || -> rusqlite::Result<Vec<DeviceSession>> {
+            let mut statement = self.conn.prepare(
+                "SELECT id, name, started, ended, \
+                 CAST(strftime('%s', ended) AS INTEGER) - CAST(strftime('%s', started) AS INTEGER) \
+                 FROM device_sessions WHERE device_id = ?1 ORDER BY id",
+            )?;
+            let sessions = statement
+                .query_map(params![device_id], |row| {
+                    Ok(DeviceSession {
+                        id: row.get(0)?,
+                        name: row.get(1)?,
+                        started: row.get(2)?,
+                        ended: row.get(3)?,
+                        duration_secs: row.get(4)?,
+                    })
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(sessions)
+        };
+
+        list().map_err(|error| StorageError::Query {
+            operation: "list the device's sessions",
+            error,
+        })
+    }
+
+    /// Records what was found on a device when it was probed. A device the
+    /// registry doesn't know is left alone.
+    pub fn set_contents(
+        &mut self,
+        identity: &DeviceIdentity,
+        contents: &DeviceContents,
+    ) -> Result<(), StorageError> {
+        let json = serde_json::to_string(contents).map_err(|error| StorageError::Serialize {
+            device: identity.name.clone(),
+            error,
+        })?;
+
+        let update = || -> rusqlite::Result<usize> {
+            match find(&self.conn, identity, &self.identity)? {
+                Some(id) => self.conn.execute(
+                    "UPDATE devices SET contents = ?2 WHERE id = ?1",
+                    params![id, json],
+                ),
+                None => Ok(0),
+            }
+        };
+        let updated = update().map_err(|error| StorageError::Device {
+            operation: "probed",
+            device: identity.name.clone(),
+            error,
+        })?;
+
+        if updated > 0 {
+            self.wrote();
+        }
+        Ok(())
+    }
+
+    /// Records what can be done to a device. A device the registry doesn't
+    /// know is left alone.
+    pub fn set_capabilities(
+        &mut self,
+        identity: &DeviceIdentity,
+        capabilities: &DeviceCapabilities,
+    ) -> Result<(), StorageError> {
+        let json =
+            serde_json::to_string(capabilities).map_err(|error| StorageError::Serialize {
+                device: identity.name.clone(),
+                error,
+            })?;
+
+        let update = || -> rusqlite::Result<usize> {
+            match find(&self.conn, identity, &self.identity)? {
+                Some(id) => self.conn.execute(
+                    "UPDATE devices SET capabilities = ?2 WHERE id = ?1",
+                    params![id, json],
+                ),
+                None => Ok(0),
+            }
+        };
+        let updated = update().map_err(|error| StorageError::Device {
+            operation: "probed for its capabilities",
+            device: identity.name.clone(),
+            error,
+        })?;
+
+        if updated > 0 {
+            self.wrote();
+        }
+        Ok(())
+    }
+
+    /// Records how worn out an eMMC device is, and adds it to the device's
+    /// events when that changed. A device the registry doesn't know is
+    /// left alone.
+    pub fn set_mmc_health(
+        &mut self,
+        identity: &DeviceIdentity,
+        health: &MmcHealth,
+    ) -> Result<(), StorageError> {
+        let json = serde_json::to_string(health).map_err(|error| StorageError::Serialize {
+            device: identity.name.clone(),
+            error,
+        })?;
+
+        let event_limit = self.events_kept();
+        let update = || -> rusqlite::Result<bool> {
+            let id = match find(&self.conn, identity, &self.identity)? {
+                Some(id) => id,
+                None => return Ok(false),
+            };
+            let updated = self.conn.execute(
+                "UPDATE devices SET mmc_health = ?2 WHERE id = ?1 AND mmc_health IS NOT ?2",
+                params![id, json],
+            )?;
+            if updated > 0 {
+                log_event(&self.conn, id, &format!("Health: {}", health), event_limit)?;
+            }
+            Ok(updated > 0)
+        };
+        let updated = update().map_err(|error| StorageError::Device {
+            operation: "read for its health",
+            device: identity.name.clone(),
+            error,
+        })?;
+
+        if updated {
+            self.wrote();
+        }
+        Ok(())
+    }
+
+    /// Records a device's link error counters, and returns the ones it had
+    /// before. A device the registry doesn't know is left alone.
+    pub fn set_link_health(
+        &mut self,
+        identity: &DeviceIdentity,
+        health: &LinkHealth,
+    ) -> Result<Option<LinkHealth>, StorageError> {
+        let json = serde_json::to_string(health).map_err(|error| StorageError::Serialize {
+            device: identity.name.clone(),
+            error,
+        })?;
+
+        let update = || -> rusqlite::Result<Option<Option<String>>> {
+            let id = match find(&self.conn, identity, &self.identity)? {
+                Some(id) => id,
+                None => return Ok(None),
+            };
+            let before = self.conn.query_row(
+                "SELECT link_health FROM devices WHERE id = ?1",
+                params![id],
+                |row| row.get(0),
+            )?;
+            self.conn.execute(
+                "UPDATE devices SET link_health = ?2 WHERE id = ?1",
+                params![id, json],
+            )?;
+            Ok(Some(before))
+        };
+        let before = update().map_err(|error| StorageError::Device {
+            operation: "read for its link errors",
+            device: identity.name.clone(),
+            error,
+        })?;
+
+        let before = match before {
+            Some(before) => before,
+            None => return Ok(None),
+        };
+        self.wrote();
+        // One an older version wrote and this one can't read is as good as
+        // none.
+        Ok(before.and_then(|before| serde_json::from_str(&before).ok()))
+    }
+
+    /// Records whether smartctl agreed with the kernel about the device's
+    /// capacity. A device the registry doesn't know is left alone.
+    pub fn set_capacity_mismatch(
+        &mut self,
+        identity: &DeviceIdentity,
+        mismatch: bool,
+    ) -> Result<(), StorageError> {
+        let update = || -> rusqlite::Result<usize> {
+            match find(&self.conn, identity, &self.identity)? {
+                Some(id) => self.conn.execute(
+                    "UPDATE devices SET capacity_mismatch = ?2 WHERE id = ?1",
+                    params![id, mismatch],
+                ),
+                None => Ok(0),
+            }
+        };
+        let updated = update().map_err(|error| StorageError::Device {
+            operation: "checked",
+            device: identity.name.clone(),
+            error,
+        })?;
+
+        if updated > 0 {
+            self.wrote();
+        }
+        Ok(())
+    }
+
+    /// How big the registry is and how many devices it knows about.
+    pub fn stats(&self) -> Result<StorageStats, StorageError> {
+        let query = || -> rusqlite::Result<StorageStats> {
+            let size_bytes = self.conn.query_row(
+                "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()",
+                [],
+                |row| row.get(0),
+            )?;
+            let (present, absent) = self.conn.query_row(
+                "SELECT IFNULL(SUM(present), 0), IFNULL(SUM(1 - present), 0) FROM devices",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )?;
+
+            Ok(StorageStats {
+                path: self.path.clone(),
+                size_bytes,
+                present,
+                absent,
+                last_write: self.last_write,
+                low_on_space: self.low_on_space,
+            })
+        };
+
+        query().map_err(|error| StorageError::Query {
+            operation: "count the devices",
+            error,
+        })
+    }
+
+    fn wrote(&mut self) {
+        self.last_write = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_secs())
+            .ok();
+    }
+
+    /// Gives the device with this serial, WWN, alias or present kernel
+    /// name an alias, or takes it away with None. One set like this sticks
+    /// over any devices.alias_template comes up with. Returns the device as
+    /// it is now.
+    pub fn set_alias(
+        &mut self,
+        key: &str,
+        alias: Option<&str>,
+    ) -> Result<DeviceRecord, StorageError> {
+        let mut device = self
+            .device(key)?
+            .ok_or_else(|| StorageError::NoSuchDevice {
+                key: key.to_string(),
+            })?;
+        if let Some(alias) = alias {
+            self.check_alias_free(device.id, alias)?;
+        }
+
+        let summary = match alias {
+            Some(alias) => format!("Called {}", alias),
+            None => "No longer called anything".to_string(),
+        };
+        let event_limit = self.events_kept();
+        let mut update = || -> rusqlite::Result<()> {
+            let tx = self.conn.transaction()?;
+            tx.execute(
+                "UPDATE devices SET alias = ?2, alias_from_template = 0 WHERE id = ?1",
+                params![device.id, alias],
+            )?;
+            log_event(&tx, device.id, &summary, event_limit)?;
+            tx.commit()
+        };
+        update().map_err(|error| StorageError::Device {
+            operation: "renamed",
+            device: device.name.clone(),
+            error,
+        })?;
+        self.wrote();
+
+        device.alias = alias.map(String::from);
+        device.alias_from_template = false;
+        Ok(device)
+    }
+
+    /// Puts a device, by serial, WWN, name or alias, in quarantine for
+    /// `reason`, or takes it out with None, and adds that to its events.
+    /// Returns the device as it is now.
+    pub fn set_quarantine(
+        &mut self,
+        key: &str,
+        reason: Option<&str>,
+    ) -> Result<DeviceRecord, StorageError> {
+        let mut device = self
+            .device(key)?
+            .ok_or_else(|| StorageError::NoSuchDevice {
+                key: key.to_string(),
+            })?;
+
+        let summary = match reason {
+            Some(reason) => format!("Quarantined: {}", reason),
+            None => "Out of quarantine".to_string(),
+        };
+        let event_limit = self.events_kept();
+        let mut update = || -> rusqlite::Result<Option<String>> {
+            let tx = self.conn.transaction()?;
+            tx.execute(
+                &format!(
+                    "UPDATE devices SET quarantine = ?2, \
+                     quarantined_since = CASE WHEN ?2 IS NULL THEN NULL \
+                     ELSE IFNULL(quarantined_since, {}) END WHERE id = ?1",
+                    NOW
+                ),
+                params![device.id, reason],
+            )?;
+            let since = tx.query_row(
+                "SELECT quarantined_since FROM devices WHERE id = ?1",
+                params![device.id],
+                |row| row.get(0),
+            )?;
+            log_event(&tx, device.id, &summary, event_limit)?;
+            tx.commit()?;
+            Ok(since)
+        };
+        let since = update().map_err(|error| StorageError::Device {
+            operation: "quarantined",
+            device: device.name.clone(),
+            error,
+        })?;
+        self.wrote();
+
+        device.quarantine = reason.map(String::from);
+        device.quarantined_since = since;
+        Ok(device)
+    }
+
+    /// Why a device is in quarantine, None if it isn't or the registry
+    /// doesn't know it.
+    pub fn quarantine(&self, identity: &DeviceIdentity) -> Result<Option<String>, StorageError> {
+        find(&self.conn, identity, &self.identity)
+            .and_then(|id| match id {
+                Some(id) => self.conn.query_row(
+                    "SELECT quarantine FROM devices WHERE id = ?1",
+                    params![id],
+                    |row| row.get(0),
+                ),
+                None => Ok(None),
+            })
+            .map_err(|error| StorageError::Query {
+                operation: "look up whether the device is in quarantine",
+                error,
+            })
+    }
+
+    /// Raises the device's alert for `reason`, opening one if there isn't
+    /// one, with the summary of what's being notified. An acknowledged one
+    /// stays that way unless it was acknowledged more than `realert_after`
+    /// ago, then it's open again. Returns it, or None if the registry
+    /// doesn't know the device.
+    pub fn raise_alert(
+        &mut self,
+        identity: &DeviceIdentity,
+        reason: &str,
+        summary: &str,
+        realert_after: Option<Duration>,
+    ) -> Result<Option<Alert>, StorageError> {
+        let event_limit = self.events_kept();
+        let ran_out = realert_after.map(|after| format!("-{} seconds", after.as_secs()));
+        let mut raise = || -> rusqlite::Result<Option<i64>> {
+            write_fault(&identity.name)?;
+            let tx = self.conn.transaction()?;
+            let device_id = match find(&tx, identity, &self.identity)? {
+                Some(id) => id,
+                None => return Ok(None),
+            };
+
+            let open = tx
+                .query_row(
+                    "SELECT id, acked_at IS NOT NULL AND ?3 IS NOT NULL \
+                     AND acked_at <= strftime('%Y-%m-%dT%H:%M:%SZ', 'now', ?3) \
+                     FROM alerts WHERE device_id = ?1 AND reason = ?2 AND cleared_at IS NULL \
+                     ORDER BY id DESC LIMIT 1",
+                    params![device_id, reason, ran_out],
+                    |row| Ok((row.get::<_, i64>(0)?, row.get::<_, bool>(1)?)),
+                )
+                .optional()?;
+            let id = match open {
+                Some((id, ran_out)) => {
+                    tx.execute(
+                        &format!(
+                            "UPDATE alerts SET summary = ?2, last_raised = {}, times = times + 1 \
+                             WHERE id = ?1",
+                            NOW
+                        ),
+                        params![id, summary],
+                    )?;
+                    if ran_out {
+                        tx.execute(
+                            "UPDATE alerts SET acked_at = NULL, ack_note = NULL WHERE id = ?1",
+                            params![id],
+                        )?;
+                        log_event(
+                            &tx,
+                            device_id,
+                            &format!("Alert {} is back, its acknowledgement ran out", id),
+                            event_limit,
+                        )?;
+                    }
+                    id
+                }
+                None => {
+                    tx.execute(
+                        &format!(
+                            "INSERT INTO alerts \
+                             (device_id, reason, summary, opened, last_raised, times) \
+                             VALUES (?1, ?2, ?3, {now}, {now}, 1)",
+                            now = NOW
+                        ),
+                        params![device_id, reason, summary],
+                    )?;
+                    let id = tx.last_insert_rowid();
+                    log_event(
+                        &tx,
+                        device_id,
+                        &format!("Alert {} raised, {}", id, reason),
+                        event_limit,
+                    )?;
+                    id
+                }
+            };
+            tx.commit()?;
+            Ok(Some(id))
+        };
+        let id = raise().map_err(|error| StorageError::Device {
+            operation: "alerted",
+            device: identity.name.clone(),
+            error,
+        })?;
+
+        match id {
+            Some(id) => {
+                self.wrote();
+                self.alert(id)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Closes the device's alert for `reason`, its condition went away.
+    /// Returns the alert, None if there wasn't one open.
+    pub fn clear_alert(
+        &mut self,
+        identity: &DeviceIdentity,
+        reason: &str,
+    ) -> Result<Option<Alert>, StorageError> {
+        let event_limit = self.events_kept();
+        let mut clear = || -> rusqlite::Result<Option<i64>> {
+            let tx = self.conn.transaction()?;
+            let device_id = match find(&tx, identity, &self.identity)? {
+                Some(id) => id,
+                None => return Ok(None),
+            };
+            let id = tx
+                .query_row(
+                    &format!(
+                        "UPDATE alerts SET cleared_at = {} \
+                         WHERE device_id = ?1 AND reason = ?2 AND cleared_at IS NULL RETURNING id",
+                        NOW
+                    ),
+                    params![device_id, reason],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            if let Some(id) = id {
+                log_event(
+                    &tx,
+                    device_id,
+                    &format!("Alert {} cleared", id),
+                    event_limit,
+                )?;
+            }
+            tx.commit()?;
+            Ok(id)
+        };
+        let id = clear().map_err(|error| StorageError::Device {
+            operation: "cleared of its alert",
+            device: identity.name.clone(),
+            error,
+        })?;
+
+        match id {
+            Some(id) => {
+                self.wrote();
+                self.alert(id)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Acknowledges an alert that hasn't cleared, with what the operator
+    /// had to say about it, and adds that to its device's events.
+    pub fn ack_alert(&mut self, id: i64, note: &str) -> Result<Alert, StorageError> {
+        let alert = self.alert(id)?.ok_or(StorageError::NoSuchAlert { id })?;
+        if alert.cleared_at.is_some() {
+            return Err(StorageError::AlertCleared { id });
+        }
+
+        let event_limit = self.events_kept();
+        let mut ack = || -> rusqlite::Result<()> {
+            let tx = self.conn.transaction()?;
+            tx.execute(
+                &format!(
+                    "UPDATE alerts SET acked_at = {}, ack_note = ?2 WHERE id = ?1",
+                    NOW
+                ),
+                params![id, note],
+            )?;
+            log_event(
+                &tx,
+                alert.device_id,
+                &format!("Alert {} acknowledged: {}", id, note),
+                event_limit,
+            )?;
+            tx.commit()
+        };
+        ack().map_err(|error| StorageError::Query {
+            operation: "acknowledge the alert",
+            error,
+        })?;
+        self.wrote();
+
+        self.alert(id)?.ok_or(StorageError::NoSuchAlert { id })
+    }
+
+    /// One alert, by its number.
+    pub fn alert(&self, id: i64) -> Result<Option<Alert>, StorageError> {
+        self.conn
+            .query_row(
+                &format!("SELECT {} WHERE a.id = ?1", ALERT_COLUMNS),
+                params![id],
+                alert_from_row,
+            )
+            .optional()
+            .map_err(|error| StorageError::Query {
+                operation: "look the alert up",
+                error,
+            })
+    }
+
+    /// Alerts, oldest first, only those for the device with `device_id`
+    /// and in `state` if given.
+    pub fn alerts(
+        &self,
+        device_id: Option<i64>,
+        state: Option<AlertState>,
+    ) -> Result<Vec<Alert>, StorageError> {
+        let list = || -> rusqlite::Result<Vec<Alert>> {
+            let mut statement = self.conn.prepare(&format!(
+                "SELECT {} WHERE (?1 IS NULL OR a.device_id = ?1) ORDER BY a.id",
+                ALERT_COLUMNS
+            ))?;
+            let alerts = statement
+                .query_map(params![device_id], alert_from_row)?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(alerts)
+        };
+        let alerts = list().map_err(|error| StorageError::Query {
+            operation: "list alerts",
+            error,
+        })?;
+        Ok(alerts
+            .into_iter()
+            .filter(|alert| state.is_none_or(|state| alert.state() == state))
+            .collect())
+    }
+
+    /// Gives a device the alias devices.alias_template came up with for it,
+    /// or None if it had nothing to go on, unless it has one set by hand.
+    /// Returns the alias it ends up with.
+    ///
+    /// Where it's plugged in can change, so an alias from the template is
+    /// worked out again every time the device is found. One that another
+    /// present device already has is taken away, and AliasTaken returned.
+    pub fn template_alias(
+        &mut self,
+        identity: &DeviceIdentity,
+        alias: Option<&str>,
+    ) -> Result<Option<String>, StorageError> {
+        let current = || -> rusqlite::Result<Option<(i64, Option<String>, bool)>> {
+            match find(&self.conn, identity, &self.identity)? {
+                Some(id) => self.conn.query_row(
+                    "SELECT alias, alias_from_template FROM devices WHERE id = ?1",
+                    params![id],
+                    |row| Ok(Some((id, row.get(0)?, row.get(1)?))),
+                ),
+                None => Ok(None),
+            }
+        };
+        let current = current().map_err(|error| StorageError::Query {
+            operation: "look up the device's alias",
+            error,
+        })?;
+        let (id, current) = match current {
+            Some((_, Some(current), false)) => return Ok(Some(current)),
+            Some((id, current, _)) => (id, current),
+            None => return Ok(None),
+        };
+
+        let taken = match alias {
+            Some(alias) => self.check_alias_free(id, alias).err(),
+            None => None,
+        };
+        let alias = if taken.is_some() { None } else { alias };
+        if current.as_deref() != alias {
+            let event_limit = self.events_kept();
+            let summary = match alias {
+                Some(alias) => format!("Called {}, from the alias template", alias),
+                None => {
+                    "No longer called anything, the alias template has no name for it".to_string()
+                }
+            };
+            let mut update = || -> rusqlite::Result<()> {
+                let tx = self.conn.transaction()?;
+                tx.execute(
+                    "UPDATE devices SET alias = ?2, alias_from_template = ?3 WHERE id = ?1",
+                    params![id, alias, alias.is_some()],
+                )?;
+                log_event(&tx, id, &summary, event_limit)?;
+                tx.commit()
+            };
+            update().map_err(|error| StorageError::Device {
+                operation: "renamed",
+                device: identity.name.clone(),
+                error,
+            })?;
+            self.wrote();
+        }
+
+        match taken {
+            Some(taken) => Err(taken),
+            None => Ok(alias.map(String::from)),
+        }
+    }
+
+    /// The device's alias, None if it has none or isn't in the registry.
+    pub fn alias(&self, identity: &DeviceIdentity) -> Result<Option<String>, StorageError> {
+        find(&self.conn, identity, &self.identity)
+            .and_then(|id| match id {
+                Some(id) => self.conn.query_row(
+                    "SELECT alias FROM devices WHERE id = ?1",
+                    params![id],
+                    |row| row.get(0),
+                ),
+                None => Ok(None),
+            })
+            .map_err(|error| StorageError::Query {
+                operation: "look up the device's alias",
+                error,
+            })
+    }
+
+    // AliasTaken if a present device other than `device_id` has it.
+    fn check_alias_free(&self, device_id: i64, alias: &str) -> Result<(), StorageError> {
+        let holder = self
+            .conn
+            .query_row(
+                "SELECT name, serial FROM devices \
+                 WHERE alias = ?1 AND present = 1 AND id != ?2 LIMIT 1",
+                params![alias, device_id],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?)),
+            )
+            .optional()
+            .map_err(|error| StorageError::Query {
+                operation: "check who has the alias",
+                error,
+            })?;
+        match holder {
+            Some((name, serial)) => Err(StorageError::AliasTaken {
+                alias: alias.to_string(),
+                holder: match serial {
+                    Some(serial) => format!("{} ({})", name, serial),
+                    None => name,
+                },
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Every device that's present or had events since `since`, a
+    /// timestamp like the ones in the registry, with those events. Read in
+    /// one transaction, so it's all from the same moment even while the
+    /// daemon writes.
+    pub fn recent_history(&self, since: &str) -> Result<Vec<DeviceHistory>, StorageError> {
+        let read = || -> rusqlite::Result<Vec<DeviceHistory>> {
+            let tx = self.conn.unchecked_transaction()?;
+            let devices = tx
+                .prepare(&format!(
+                    "SELECT {} FROM devices WHERE present = 1 OR id IN \
+                     (SELECT device_id FROM device_events WHERE at >= ?1) \
+                     ORDER BY last_seen DESC, id",
+                    COLUMNS
+                ))?
+                .query_map(params![since], record_from_row)?
+                .collect::<Result<Vec<_>, _>>()?;
+
+            let mut statement = tx.prepare(
+                "SELECT at, summary, session_id FROM device_events \
+                 WHERE device_id = ?1 AND at >= ?2 ORDER BY id",
+            )?;
+            let mut history = vec![];
+            for device in devices {
+                let events = statement
+                    .query_map(params![device.id, since], |row| {
+                        Ok(DeviceEvent {
+                            at: row.get(0)?,
+                            summary: row.get(1)?,
+                            session: row.get(2)?,
+                        })
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                history.push(DeviceHistory { device, events });
+            }
+            drop(statement);
+            tx.commit()?;
+            Ok(history)
+        };
+
+        read().map_err(|error| StorageError::Query {
+            operation: "read the recent device history",
+            error,
+        })
+    }
+
+    /// The devices that are present, or all of them, most recently seen
+    /// first.
+    pub fn devices(&self, include_absent: bool) -> Result<Vec<DeviceRecord>, StorageError> {
+        let list = || -> rusqlite::Result<Vec<DeviceRecord>> {
+            let mut statement = self.conn.prepare(&format!(
+                "SELECT {} FROM devices WHERE present = 1 OR ?1 ORDER BY last_seen DESC, id",
+                COLUMNS
+            ))?;
+
+            let records = statement
+                .query_map(params![include_absent], record_from_row)?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(records)
+        };
+
+        list().map_err(|error| StorageError::Query {
+            operation: "list the devices",
+            error,
+        })
+    }
+}
+
+// What record_from_row expects, in order.
+const COLUMNS: &str = "id, serial, model, wwn, name, info, first_seen, last_seen, times_seen, \
+                       present, contents, capacity_mismatch, firmware, capabilities, mmc_health, \
+                       alias, alias_from_template, link_health, quarantine, quarantined_since, \
+                       uuid, identity_basis";
+
+fn record_from_row(row: &Row) -> rusqlite::Result<DeviceRecord> {
+    let info: String = row.get(5)?;
+    Ok(DeviceRecord {
+        id: row.get(0)?,
+        serial: row.get(1)?,
+        model: row.get(2)?,
+        wwn: row.get(3)?,
+        name: row.get(4)?,
+        info: serde_json::from_str(&info).unwrap_or(serde_json::Value::Null),
+        first_seen: row.get(6)?,
+        last_seen: row.get(7)?,
+        times_seen: row.get(8)?,
+        present: row.get(9)?,
+        contents: row
+            .get::<_, Option<String>>(10)?
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .unwrap_or(serde_json::Value::Null),
+        capacity_mismatch: row.get(11)?,
+        firmware: row.get(12)?,
+        capabilities: row
+            .get::<_, Option<String>>(13)?
+            .and_then(|capabilities| serde_json::from_str(&capabilities).ok())
+            .unwrap_or(serde_json::Value::Null),
+        mmc_health: row
+            .get::<_, Option<String>>(14)?
+            .and_then(|health| serde_json::from_str(&health).ok())
+            .unwrap_or(serde_json::Value::Null),
+        alias: row.get(15)?,
+        alias_from_template: row.get(16)?,
+        link_health: row
+            .get::<_, Option<String>>(17)?
+            .and_then(|health| serde_json::from_str(&health).ok())
+            .unwrap_or(serde_json::Value::Null),
+        quarantine: row.get(18)?,
+        quarantined_since: row.get(19)?,
+        uuid: row.get(20)?,
+        identity_basis: row
+            .get::<_, Option<String>>(21)?
+            .as_deref()
+            .and_then(IdentityBasis::parse),
+    })
+}
+
+// A write failing like it would on a disk that's gone bad under the
+// database.
+fn write_fault(device: &str) -> rusqlite::Result<()> {
+    match faults::check(FaultPoint::SqliteWrite, Some(device), None) {
+        Some(fault) => {
+            std::thread::sleep(fault.delay);
+            Err(rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_IOERR),
+                Some(fault.message),
+            ))
+        }
+        None => Ok(()),
+    }
+}
+
+// Adds an event and drops whatever no longer fits under `limit`.
+const ALERT_COLUMNS: &str = "a.id, a.device_id, d.name, d.alias, d.serial, a.reason, a.summary, \
+                             a.opened, a.last_raised, a.times, a.acked_at, a.ack_note, \
+                             a.cleared_at FROM alerts a JOIN devices d ON d.id = a.device_id";
+
+fn alert_from_row(row: &Row) -> rusqlite::Result<Alert> {
+    Ok(Alert {
+        id: row.get(0)?,
+        device_id: row.get(1)?,
+        device: row.get(2)?,
+        alias: row.get(3)?,
+        serial: row.get(4)?,
+        reason: row.get(5)?,
+        summary: row.get(6)?,
+        opened: row.get(7)?,
+        last_raised: row.get(8)?,
+        times: row.get(9)?,
+        acked_at: row.get(10)?,
+        ack_note: row.get(11)?,
+        cleared_at: row.get(12)?,
+    })
+}
+
+fn log_event(conn: &Connection, device_id: i64, summary: &str, limit: u32) -> rusqlite::Result<()> {
+    if limit == 0 {
+        return Ok(());
+    }
+
+    conn.execute(
+        &format!(
+            "INSERT INTO device_events (device_id, at, summary, session_id) VALUES (?1, {}, ?2, \
+             (SELECT id FROM device_sessions WHERE device_id = ?1 AND ended IS NULL))",
+            NOW
+        ),
+        params![device_id, summary],
+    )?;
+    conn.execute(
+        "DELETE FROM device_events WHERE device_id = ?1 AND id <= \
+         (SELECT id FROM device_events WHERE device_id = ?1 ORDER BY id DESC LIMIT 1 OFFSET ?2)",
+        params![device_id, limit],
+    )?;
+    Ok(())
+}
+
+// Starts a session for a device that was just found. One that's still
+// open, or ended less than `grace_secs` ago, carries on instead.
+fn open_session(
+    conn: &Connection,
+    device_id: i64,
+    name: &str,
+    grace_secs: u64,
+) -> rusqlite::Result<()> {
+    let resumed = conn.execute(
+        "UPDATE device_sessions SET ended = NULL, name = ?2 WHERE id = \
+         (SELECT id FROM device_sessions WHERE device_id = ?1 ORDER BY id DESC LIMIT 1) \
+         AND (ended IS NULL OR ended >= strftime('%Y-%m-%dT%H:%M:%SZ', 'now', ?3))",
+        params![device_id, name, format!("-{} seconds", grace_secs)],
+    )?;
+    if resumed > 0 {
+        return Ok(());
+    }
+
+    conn.execute(
+        &format!(
+            "INSERT INTO device_sessions (device_id, name, started) VALUES (?1, ?2, {})",
+            NOW
+        ),
+        params![device_id, name],
+    )?;
+    conn.execute(
+        "DELETE FROM device_sessions WHERE device_id = ?1 AND id <= \
+         (SELECT id FROM device_sessions WHERE device_id = ?1 ORDER BY id DESC LIMIT 1 OFFSET ?2)",
+        params![device_id, SESSION_LIMIT],
+    )?;
+    Ok(())
+}
+
+// The record for a device the daemon found, the one present under its
+// name, or else the first one the chain finds it by.
+fn find(
+    conn: &Connection,
+    identity: &DeviceIdentity,
+    chain: &IdentityChain,
+) -> rusqlite::Result<Option<i64>> {
+    let present = conn
+        .query_row(
+            "SELECT id FROM devices WHERE name = ?1 AND present = 1",
+            params![identity.name],
+            |row| row.get(0),
+        )
+        .optional()?;
+    if present.is_some() {
+        return Ok(present);
+    }
+
+    let bases = bases(conn, identity, chain)?;
+    let trusted = bases.contains(&IdentityBasis::SerialModel);
+    for basis in bases {
+        if let Some(&(id, _)) = lookup(conn, basis, identity, trusted)?.first() {
+            return Ok(Some(id));
+        }
+    }
+    Ok(None)
+}
+
+// The bases in the chain the device has what it takes for, in order.
+fn bases(
+    conn: &Connection,
+    identity: &DeviceIdentity,
+    chain: &IdentityChain,
+) -> rusqlite::Result<Vec<IdentityBasis>> {
+    let mut bases = vec![];
+    for &basis in &chain.bases {
+        let has = match basis {
+            IdentityBasis::Wwn => identity.wwn.is_some(),
+            IdentityBasis::SerialModel => match &identity.serial {
+                Some(serial) => !is_shared_serial(conn, serial, identity, chain)?,
+                None => false,
+            },
+            IdentityBasis::UsbPortSize => {
+                identity.usb_port.is_some() && identity.capacity.is_some()
+            }
+            IdentityBasis::Path => true,
+        };
+        if has && !bases.contains(&basis) {
+            bases.push(basis);
+        }
+    }
+    Ok(bases)
+}
+
+fn is_shared_serial(
+    conn: &Connection,
+    serial: &str,
+    identity: &DeviceIdentity,
+    chain: &IdentityChain,
+) -> rusqlite::Result<bool> {
+    if chain
+        .shared_serials
+        .iter()
+        .any(|shared| shared.eq_ignore_ascii_case(serial))
+    {
+        return Ok(true);
+    }
+    conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM shared_serials WHERE serial = ?1 AND model IS ?2)",
+        params![serial, identity.model],
+        |row| row.get(0),
+    )
+}
+
+// Notes the device's serial as shared when another present device, one
+// that isn't the same drive by its WWN, has it too.
+fn remember_shared_serial(conn: &Connection, identity: &DeviceIdentity) -> rusqlite::Result<()> {
+    let serial = match &identity.serial {
+        Some(serial) => serial,
+        None => return Ok(()),
+    };
+    let twin = conn
+        .query_row(
+            "SELECT name FROM devices WHERE serial = ?1 AND model IS ?2 AND present = 1 \
+             AND name != ?3 AND (wwn IS NULL OR ?4 IS NULL OR wwn != ?4) LIMIT 1",
+            params![serial, identity.model, identity.name, identity.wwn],
+            |row| row.get::<_, String>(0),
+        )
+        .optional()?;
+    let twin = match twin {
+        Some(twin) => twin,
+        None => return Ok(()),
+    };
+
+    let added = conn.execute(
+        &format!(
+            "INSERT INTO shared_serials (serial, model, seen) SELECT ?1, ?2, {} \
+             WHERE NOT EXISTS (SELECT 1 FROM shared_serials WHERE serial = ?1 AND model IS ?2)",
+            NOW
+        ),
+        params![serial, identity.model],
+    )?;
+    if added > 0 {
+        warn!(
+            "{} and {} both say their serial is {}, drives with it are told apart by something \
+             else from now on",
+            identity.name, twin, serial
+        );
+    }
+    Ok(())
+}
+
+// The records the device could be by `basis`, with whether each is
+// present, present and most recently seen first. Never one that has a
+// different WWN, or a different serial it was told apart by while the
+// device's own serial can be trusted.
+fn lookup(
+    conn: &Connection,
+    basis: IdentityBasis,
+    identity: &DeviceIdentity,
+    trusted_serial: bool,
+) -> rusqlite::Result<Vec<(i64, bool)>> {
+    let matches = match basis {
+        IdentityBasis::Wwn => "wwn = ?1",
+        IdentityBasis::SerialModel => {
+            "serial = ?2 AND model IS ?3 AND identity_basis IN ('wwn', 'serial_model')"
+        }
+        IdentityBasis::UsbPortSize => "usb_port = ?4 AND size_bytes = ?5",
+        IdentityBasis::Path => "by_path = ?6",
+    };
+    let mut statement = conn.prepare_cached(&format!(
+        "SELECT id, present FROM devices WHERE {} \
+         AND (wwn IS NULL OR ?1 IS NULL OR wwn = ?1) \
+         AND (NOT ?7 OR serial IS NULL OR identity_basis NOT IN ('wwn', 'serial_model') \
+              OR (serial = ?2 AND model IS ?3)) \
+         ORDER BY present DESC, last_seen DESC, id",
+        matches
+    ))?;
+    let records = statement
+        .query_map(
+            params![
+                identity.wwn,
+                identity.serial,
+                identity.model,
+                identity.usb_port,
+                identity
+                    .capacity
+                    .as_ref()
+                    .map(|capacity| capacity.size_bytes as i64),
+                by_path(identity),
+                trusted_serial
+            ],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?
+        .collect();
+    records
+}
+
+// Its /dev/disk/by-path link, which stays with the port it's on, or its
+// device node if it has none.
+fn by_path(identity: &DeviceIdentity) -> Option<String> {
+    identity
+        .paths
+        .iter()
+        .find(|path| path.starts_with("/dev/disk/by-path"))
+        .or_else(|| identity.paths.first())
+        .map(|path| path.to_string_lossy().into_owned())
+}
+
+// Moves everything about the record `from` over to `into` and forgets
+// `from`, they're the same drive by `by`. Returns the UUID `from` had,
+// which leads to `into` from now on.
+//
+// Events and sessions are moved as they are. Of two open alerts for the
+// same reason the one `into` has is kept, with the other one's times on
+// top. Anything `into` knows wins over what `from` does.
+fn merge_into(
+    conn: &Connection,
+    into: i64,
+    from: i64,
+    by: IdentityBasis,
+    limit: u32,
+) -> rusqlite::Result<String> {
+    let uuid: String = conn.query_row(
+        "SELECT uuid FROM devices WHERE id = ?1",
+        params![from],
+        |row| row.get(0),
+    )?;
+
+    conn.execute(
+        "UPDATE alerts SET \
+         (opened, times, acked_at, ack_note) = (SELECT MIN(alerts.opened, other.opened), \
+             alerts.times + other.times, IFNULL(alerts.acked_at, other.acked_at), \
+             IFNULL(alerts.ack_note, other.ack_note) \
+             FROM alerts other WHERE other.device_id = ?2 AND other.reason = alerts.reason \
+             AND other.cleared_at IS NULL) \
+         WHERE device_id = ?1 AND cleared_at IS NULL AND reason IN \
+         (SELECT reason FROM alerts WHERE device_id = ?2 AND cleared_at IS NULL)",
+        params![into, from],
+    )?;
+    conn.execute(
+        "DELETE FROM alerts WHERE device_id = ?2 AND cleared_at IS NULL AND reason IN \
+         (SELECT reason FROM alerts WHERE device_id = ?1 AND cleared_at IS NULL)",
+        params![into, from],
+    )?;
+    for table in [
+        "alerts",
+        "device_events",
+        "device_sessions",
+        "merged_devices",
+    ] {
+        conn.execute(
+            &format!("UPDATE {} SET device_id = ?1 WHERE device_id = ?2", table),
+            params![into, from],
+        )?;
+    }
+
+    conn.execute(
+        "UPDATE devices SET \
+         (serial, model, wwn, first_seen, times_seen, contents, capacity_mismatch, firmware, \
+          capabilities, mmc_health, alias, alias_from_template, link_health, quarantine, \
+          quarantined_since) = \
+         (SELECT IFNULL(devices.serial, other.serial), IFNULL(devices.model, other.model), \
+             IFNULL(devices.wwn, other.wwn), MIN(devices.first_seen, other.first_seen), \
+             devices.times_seen + other.times_seen, IFNULL(devices.contents, other.contents), \
+             MAX(devices.capacity_mismatch, other.capacity_mismatch), \
+             IFNULL(devices.firmware, other.firmware), \
+             IFNULL(devices.capabilities, other.capabilities), \
+             IFNULL(devices.mmc_health, other.mmc_health), \
+             IFNULL(devices.alias, other.alias), \
+             IIF(devices.alias IS NULL, other.alias_from_template, devices.alias_from_template), \
+             IFNULL(devices.link_health, other.link_health), \
+             IFNULL(devices.quarantine, other.quarantine), \
+             IIF(devices.quarantine IS NULL, other.quarantined_since, devices.quarantined_since) \
+             FROM devices other WHERE other.id = ?2) \
+         WHERE id = ?1",
+        params![into, from],
+    )?;
+    conn.execute(
+        &format!(
+            "INSERT INTO merged_devices (uuid, device_id, merged_at) VALUES (?1, ?2, {})",
+            NOW
+        ),
+        params![uuid, into],
+    )?;
+    conn.execute("DELETE FROM devices WHERE id = ?1", params![from])?;
+
+    log_event(
+        conn,
+        into,
+        &format!(
+            "Merged with the record {}, the same drive by {}",
+            uuid,
+            by.describe()
+        ),
+        limit,
+    )?;
+    Ok(uuid)
+}
+
+fn is_corrupt(e: &StorageError) -> bool {
+    match e {
+        StorageError::Open { error, .. } | StorageError::Migrate { error, .. } => {
+            is_corrupt_sqlite(error)
+        }
+        _ => false,
+    }
+}
+
+fn is_corrupt_sqlite(e: &rusqlite::Error) -> bool {
+    matches!(
+        e.sqlite_error_code(),
+        Some(ErrorCode::DatabaseCorrupt | ErrorCode::NotADatabase)
+    )
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = OsString::from(path);
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mmc::PreEol;
+    use crate::test_dir::TestDir;
+
+    fn registry() -> Registry {
+        Registry::open(Path::new(IN_MEMORY)).unwrap()
+    }
+
+    // A SATA drive as udev would describe it plugged in as `name`, through
+    // the port `port`.
+    fn drive(name: &str, serial: &str, port: u8) -> DeviceIdentity {
+        DeviceIdentity {
+            name: name.to_string(),
+            paths: vec![
+                PathBuf::from(format!("/dev/{}", name)),
+                PathBuf::from(format!("/dev/disk/by-id/ata-WDC_WD40EFRX_{}", serial)),
+                PathBuf::from(format!("/dev/disk/by-path/pci-0000:00:17.0-ata-{}", port)),
+            ],
+            serial: Some(serial.to_string()),
+            model: Some("WDC WD40EFRX-68N32N0".to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn record(registry: &Registry, key: &str) -> DeviceRecord {
+        registry.device(key).unwrap().unwrap()
+    }
+
+    fn summaries(registry: &Registry, key: &str) -> Vec<String> {
+        let id = record(registry, key).id;
+        registry
+            .events(id, None)
+            .unwrap()
+            .into_iter()
+            .map(|event| event.summary)
+            .collect()
+    }
+
+    #[test]
+    fn a_new_device_gets_a_record() {
+        let mut registry = registry();
+        let uuid = registry
+            .device_found(&drive("sda", "WD-1", 1))
+            .unwrap()
+            .unwrap();
+
+        let sda = record(&registry, "WD-1");
+        assert_eq!(sda.uuid, uuid);
+        assert_eq!(sda.name, "sda");
+        assert_eq!(sda.times_seen, 1);
+        assert!(sda.present);
+        assert_eq!(sda.identity_basis, Some(IdentityBasis::SerialModel));
+        assert_eq!(sda.info["paths"][0], "/dev/sda");
+        assert_eq!(sda.first_seen, sda.last_seen);
+        assert_eq!(summaries(&registry, "WD-1"), ["Found as sda"]);
+    }
+
+    #[test]
+    fn reconnecting_under_another_name_updates_the_record() {
+        let mut registry = registry();
+        let uuid = registry.device_found(&drive("sda", "WD-1", 1)).unwrap();
+        let lost = registry.device_lost("sda").unwrap().unwrap();
+        assert_eq!(Some(lost.uuid), uuid);
+        assert!(!record(&registry, "WD-1").present);
+
+        // Plugged into another port, it comes back with a new name and new
+        // paths.
+        let again = registry.device_found(&drive("sdc", "WD-1", 3)).unwrap();
+        assert_eq!(again, uuid);
+
+        let sdc = record(&registry, "WD-1");
+        assert_eq!(sdc.id, lost.id);
+        assert_eq!(sdc.name, "sdc");
+        assert_eq!(sdc.times_seen, 2);
+        assert!(sdc.present);
+        assert_eq!(sdc.info["paths"][0], "/dev/sdc");
+        assert_eq!(
+            sdc.info["paths"][2],
+            "/dev/disk/by-path/pci-0000:00:17.0-ata-3"
+        );
+        assert_eq!(registry.devices(true).unwrap().len(), 1);
+        assert_eq!(
+            summaries(&registry, "WD-1"),
+            ["Found as sda", "Lost (was sda)", "Found as sdc"]
+        );
+        // By its kernel name only while it's present under it.
+        assert_eq!(record(&registry, "sdc").id, sdc.id);
+        assert!(registry.device("sda").unwrap().is_none());
+    }
+
+    #[test]
+    fn a_missed_remove_doesnt_make_a_second_record() {
+        let mut registry = registry();
+        let uuid = registry.device_found(&drive("sda", "WD-1", 1)).unwrap();
+        // The remove event never came, it's found again under a new name.
+        assert_eq!(
+            registry.device_found(&drive("sdb", "WD-1", 1)).unwrap(),
+            uuid
+        );
+
+        let devices = registry.devices(true).unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].name, "sdb");
+        assert_eq!(devices[0].times_seen, 2);
+        // Nothing is lost under the name it doesn't have anymore.
+        assert!(registry.device_lost("sda").unwrap().is_none());
+        assert!(record(&registry, "WD-1").present);
+    }
+
+    #[test]
+    fn another_drive_taking_the_name_gets_its_own_record() {
+        let mut registry = registry();
+        let first = registry.device_found(&drive("sda", "WD-1", 1)).unwrap();
+        // WD-1 was pulled without a remove event, and WD-2 went in the same
+        // port and got its name.
+        let second = registry.device_found(&drive("sda", "WD-2", 1)).unwrap();
+        assert_ne!(first, second);
+
+        assert!(!record(&registry, "WD-1").present);
+        assert!(record(&registry, "WD-2").present);
+        assert_eq!(record(&registry, "sda").serial.as_deref(), Some("WD-2"));
+        assert_eq!(registry.devices(false).unwrap().len(), 1);
+        assert_eq!(registry.devices(true).unwrap().len(), 2);
+
+        // Losing sda is WD-2 leaving.
+        let lost = registry.device_lost("sda").unwrap().unwrap();
+        assert_eq!(lost.serial.as_deref(), Some("WD-2"));
+    }
+
+    #[test]
+    fn details_it_stops_reporting_are_kept() {
+        let mut registry = registry();
+        let mut sda = drive("sda", "WD-1", 1);
+        sda.wwn = Some("0x50014ee2b5a1c3d4".to_string());
+        let uuid = registry.device_found(&sda).unwrap();
+        registry.device_lost("sda").unwrap();
+
+        // Behind a bridge that doesn't pass the WWN on.
+        sda.wwn = None;
+        assert_eq!(registry.device_found(&sda).unwrap(), uuid);
+        let record = record(&registry, "WD-1");
+        assert_eq!(record.wwn.as_deref(), Some("0x50014ee2b5a1c3d4"));
+        assert_eq!(record.identity_basis, Some(IdentityBasis::SerialModel));
+        assert!(summaries(&registry, "WD-1")
+            .contains(&"Told apart by its serial and model now, it was by its WWN".to_string()));
+    }
+
+    #[test]
+    fn the_model_is_part_of_the_identity() {
+        let mut registry = registry();
+        let mut without_model = drive("sda", "WD-1", 1);
+        without_model.model = None;
+        let first = registry.device_found(&without_model).unwrap();
+        registry.device_lost("sda").unwrap();
+
+        // The same serial with a model could be another make's drive.
+        let second = registry.device_found(&drive("sda", "WD-1", 1)).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(registry.devices(true).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn lookups_by_every_key() {
+        let mut registry = registry();
+        let mut sda = drive("sda", "WD-1", 1);
+        sda.wwn = Some("0x50014ee2b5a1c3d4".to_string());
+        let uuid = registry.device_found(&sda).unwrap().unwrap();
+
+        for key in [
+            uuid.as_str(),
+            &uuid.to_uppercase(),
+            "WD-1",
+            "0x50014ee2b5a1c3d4",
+            "sda",
+        ] {
+            assert_eq!(record(&registry, key).uuid, uuid, "{}", key);
+        }
+        assert!(registry.device("WD-2").unwrap().is_none());
+    }
+
+    #[test]
+    fn reset_presence_marks_everything_absent() {
+        let mut registry = registry();
+        registry.device_found(&drive("sda", "WD-1", 1)).unwrap();
+        registry.device_found(&drive("sdb", "WD-2", 2)).unwrap();
+        registry.reset_presence().unwrap();
+
+        assert!(registry.devices(false).unwrap().is_empty());
+        let sessions = registry.sessions(record(&registry, "WD-1").id).unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert!(sessions[0].ended.is_some());
+
+        // Found again after the restart, the same records.
+        registry.device_found(&drive("sdb", "WD-1", 1)).unwrap();
+        assert_eq!(record(&registry, "WD-1").times_seen, 2);
+        assert_eq!(registry.devices(true).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn devices_without_an_identity_arent_recorded() {
+        let mut registry = registry();
+        registry.set_identity(&[IdentityBasis::Wwn, IdentityBasis::SerialModel], &[]);
+        let bare = DeviceIdentity {
+            name: "sdz".to_string(),
+            paths: vec![PathBuf::from("/dev/sdz")],
+            ..Default::default()
+        };
+        assert_eq!(registry.device_found(&bare).unwrap(), None);
+        assert!(registry.devices(true).unwrap().is_empty());
+        assert!(registry.device_lost("sdz").unwrap().is_none());
+    }
+
+    // A database file in a temp dir, removed with the dir.
+    struct TempDb(TestDir);
+
+    impl TempDb {
+        fn new(name: &str) -> Self {
+            Self(TestDir::new(&format!("storage-{}", name)))
+        }
+
+        fn path(&self) -> PathBuf {
+            self.0.join("hddmond.db")
+        }
+
+        fn user_version(&self) -> usize {
+            Connection::open(self.path())
+                .unwrap()
+                .query_row("PRAGMA user_version", [], |row| row.get(0))
+                .unwrap()
+        }
+    }
+
+    #[test]
+    fn migrations_run_once_at_open() {
+        let db = TempDb::new("migrations");
+        let uuid = {
+            let mut registry = Registry::open(&db.path()).unwrap();
+            registry.device_found(&drive("sda", "WD-1", 1)).unwrap()
+        };
+        assert_eq!(db.user_version(), MIGRATIONS.len());
+
+        // Opening it again finds the record, nothing is migrated twice.
+        let registry = Registry::open(&db.path()).unwrap();
+        assert_eq!(Some(record(&registry, "WD-1").uuid), uuid);
+        assert_eq!(db.user_version(), MIGRATIONS.len());
+    }
+
+    // What a UUID of the registry's looks like: random, version 4, in
+    // lower case.
+    fn is_v4(uuid: &str) -> bool {
+        let groups = uuid.split('-').collect::<Vec<_>>();
+        groups.iter().map(|group| group.len()).eq([8, 4, 4, 4, 12])
+            && groups.iter().all(|group| {
+                group
+                    .chars()
+                    .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+            })
+            && groups[2].starts_with('4')
+            && groups[3].starts_with(['8', '9', 'a', 'b'])
+    }
+
+    #[test]
+    fn a_v12_database_gets_a_uuid_per_device() {
+        let db = TempDb::new("v12");
+        {
+            let conn = Connection::open(db.path()).unwrap();
+            for migration in &MIGRATIONS[..12] {
+                conn.execute_batch(migration).unwrap();
+            }
+            conn.pragma_update(None, "user_version", 12).unwrap();
+
+            let insert = |identity: &DeviceIdentity| {
+                conn.execute(
+                    "INSERT INTO devices (serial, model, wwn, name, info, first_seen, \
+                     last_seen, times_seen, present) \
+                     VALUES (?1, ?2, ?3, ?4, ?5, '2026-01-01T00:00:00Z', \
+                     '2026-01-02T00:00:00Z', 3, 0)",
+                    params![
+                        identity.serial,
+                        identity.model,
+                        identity.wwn,
+                        identity.name,
+                        serde_json::to_string(identity).unwrap()
+                    ],
+                )
+                .unwrap();
+            };
+            for n in 0..50 {
+                insert(&drive(&format!("sd{}", n), &format!("WD-{}", n), 1));
+            }
+            insert(&DeviceIdentity {
+                name: "nvme0n1".to_string(),
+                wwn: Some("eui.0025388b71b2ac41".to_string()),
+                ..Default::default()
+            });
+        }
+
+        let mut registry = Registry::open(&db.path()).unwrap();
+        assert_eq!(db.user_version(), MIGRATIONS.len());
+        let devices = registry.devices(true).unwrap();
+        assert_eq!(devices.len(), 51);
+        let uuids = devices
+            .iter()
+            .map(|device| device.uuid.as_str())
+            .collect::<std::collections::HashSet<_>>();
+        assert_eq!(uuids.len(), 51);
+        assert!(uuids.iter().all(|uuid| is_v4(uuid)), "{:?}", uuids);
+
+        for device in &devices {
+            let basis = match device.serial {
+                Some(_) => IdentityBasis::SerialModel,
+                None => IdentityBasis::Wwn,
+            };
+            assert_eq!(device.identity_basis, Some(basis), "{}", device.name);
+        }
+
+        // Found again, they're the records they were.
+        let wd7 = record(&registry, "WD-7");
+        assert_eq!(
+            registry.device_found(&drive("sdh", "WD-7", 4)).unwrap(),
+            Some(wd7.uuid.clone())
+        );
+        assert_eq!(record(&registry, "WD-7").times_seen, wd7.times_seen + 1);
+        assert_eq!(registry.devices(true).unwrap().len(), 51);
+        // And anything new gets one just like them.
+        let new = registry.device_found(&drive("sdz", "WD-NEW", 5)).unwrap();
+        assert!(is_v4(&new.unwrap()));
+    }
+
+    #[test]
+    fn missing_directories_are_created() {
+        let db = TempDb::new("dirs");
+        let path = db.0.join("var/lib/hddmond/hddmond.db");
+        Registry::open(&path).unwrap();
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn a_directory_that_cant_be_made_is_create_dir() {
+        let db = TempDb::new("not-a-dir");
+        fs::write(db.0.join("file"), "").unwrap();
+        let path = db.0.join("file/hddmond.db");
+
+        match Registry::open(&path) {
+            Err(StorageError::CreateDir { path: dir, error }) => {
+                assert_eq!(dir, db.0.join("file"));
+                assert_eq!(error.kind(), std::io::ErrorKind::AlreadyExists);
+            }
+            other => panic!("Expected CreateDir, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn unknown_devices_and_alerts_are_their_own_variants() {
+        let mut registry = registry();
+        registry.device_found(&drive("sda", "WD-1", 1)).unwrap();
+
+        for result in [
+            registry.set_alias("WD-2", Some("spare")),
+            registry.set_quarantine("sdb", Some("clicking")),
+        ] {
+            assert!(matches!(
+                result,
+                Err(StorageError::NoSuchDevice { ref key }) if key == "WD-2" || key == "sdb"
+            ));
+        }
+        assert!(matches!(
+            registry.ack_alert(42, "seen it"),
+            Err(StorageError::NoSuchAlert { id: 42 })
+        ));
+    }
+
+    #[test]
+    fn corrupt_databases_are_moved_aside() {
+        let db = TempDb::new("corrupt");
+        fs::write(
+            db.path(),
+            "this is not an SQLite database, not even close to one",
+        )
+        .unwrap();
+
+        let mut registry = Registry::open(&db.path()).unwrap();
+        registry.device_found(&drive("sda", "WD-1", 1)).unwrap();
+
+        let aside = fs::read_dir(&db.0)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|file| file.starts_with("hddmond.db.corrupt-"))
+            .count();
+        assert_eq!(aside, 1);
+        assert_eq!(db.user_version(), MIGRATIONS.len());
+    }
+
+    #[test]
+    fn corruption_found_by_maintenance_keeps_the_configuration() {
+        let db = TempDb::new("corrupt-maintenance");
+        let mut registry = Registry::open(&db.path()).unwrap();
+        registry.set_identity(&[IdentityBasis::Wwn, IdentityBasis::SerialModel], &[]);
+        // An index that no longer matches its table passes the quick_check
+        // at open, only maintenance's integrity_check finds it.
+        registry
+            .conn
+            .execute_batch(
+                "CREATE TABLE scratch (a, b); \
+                 INSERT INTO scratch VALUES (1, 2), (3, 4); \
+                 CREATE INDEX scratch_a ON scratch (a); \
+                 PRAGMA writable_schema = ON; \
+                 UPDATE sqlite_master SET sql = 'CREATE INDEX scratch_a ON scratch (b)' \
+                 WHERE name = 'scratch_a'; \
+                 PRAGMA writable_schema = OFF;",
+            )
+            .unwrap();
+        registry.conn = Connection::open(db.path()).unwrap();
+        assert!(registry.check("quick_check").unwrap().is_none());
+
+        let config = StorageConfig {
+            maintenance_hour: 3,
+            ..Default::default()
+        };
+        registry.maintain_at(3, &config).unwrap();
+        let aside = fs::read_dir(&db.0)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|file| file.starts_with("hddmond.db.corrupt-"))
+            .count();
+        assert_eq!(aside, 1);
+        assert!(registry.last_maintenance.is_some());
+
+        // Still told apart only by WWN and serial, the path alone won't do.
+        let bare = DeviceIdentity {
+            name: "sdz".to_string(),
+            paths: vec![PathBuf::from("/dev/sdz")],
+            ..Default::default()
+        };
+        assert_eq!(registry.device_found(&bare).unwrap(), None);
+        registry.device_found(&drive("sda", "WD-1", 1)).unwrap();
+        assert_eq!(serials(&registry), ["WD-1"]);
+    }
+
+    // Has the device with this serial last seen `age` ago, e.g. "-31 days".
+    fn last_seen(registry: &Registry, serial: &str, age: &str) {
+        registry
+            .conn
+            .execute(
+                "UPDATE devices SET last_seen = strftime('%Y-%m-%dT%H:%M:%SZ', 'now', ?2) \
+                 WHERE serial = ?1",
+                params![serial, age],
+            )
+            .unwrap();
+    }
+
+    fn rows(registry: &Registry, table: &str) -> i64 {
+        registry
+            .conn
+            .query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| {
+                row.get(0)
+            })
+            .unwrap()
+    }
+
+    fn serials(registry: &Registry) -> Vec<String> {
+        let mut serials = registry
+            .devices(true)
+            .unwrap()
+            .into_iter()
+            .filter_map(|device| device.serial)
+            .collect::<Vec<_>>();
+        serials.sort();
+        serials
+    }
+
+    // Absent drives last seen at different ages, and one present drive
+    // that hasn't been seen found again for ages.
+    fn seeded() -> Registry {
+        let mut registry = registry();
+        for (port, (serial, age)) in [
+            ("OLD", "-31 days"),
+            ("ANCIENT", "-400 days"),
+            ("ALMOST", "-29 days"),
+            ("JUST-INSIDE", "-30 days"),
+            ("PRESENT", "-90 days"),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let drive = drive(&format!("sd{}", port), serial, port as u8);
+            registry.device_found(&drive).unwrap();
+            registry
+                .raise_alert(&drive, "device_lost", "Lost", None)
+                .unwrap();
+            if serial != "PRESENT" {
+                registry.device_lost(&drive.name).unwrap();
+            }
+            last_seen(&registry, serial, age);
+        }
+        // The cutoff only has second resolution, keep this one clear of it.
+        last_seen(&registry, "JUST-INSIDE", "-2591940 seconds");
+        registry
+    }
+
+    #[test]
+    fn pruning_forgets_only_devices_absent_for_longer() {
+        let mut registry = seeded();
+        let present = record(&registry, "PRESENT").id;
+        registry
+            .conn
+            .execute(
+                "INSERT INTO merged_devices (uuid, device_id, merged_at) VALUES ('a', ?1, 'x'), \
+                 ('b', (SELECT id FROM devices WHERE serial = 'OLD'), 'x')",
+                params![present],
+            )
+            .unwrap();
+
+        assert_eq!(registry.prune_absent(30).unwrap(), 2);
+        assert_eq!(serials(&registry), ["ALMOST", "JUST-INSIDE", "PRESENT"]);
+
+        // Nothing of theirs is left behind.
+        for table in ["device_events", "device_sessions", "alerts"] {
+            let orphans: i64 = registry
+                .conn
+                .query_row(
+                    &format!(
+                        "SELECT COUNT(*) FROM {} WHERE device_id NOT IN (SELECT id FROM devices)",
+                        table
+                    ),
+                    [],
+                    |row| row.get(0),
+                )
+                .unwrap();
+            assert_eq!(orphans, 0, "{}", table);
+        }
+        assert_eq!(rows(&registry, "alerts"), 3);
+        assert_eq!(rows(&registry, "device_sessions"), 3);
+        assert_eq!(rows(&registry, "merged_devices"), 1);
+
+        // Nothing more to do the second time.
+        assert_eq!(registry.prune_absent(30).unwrap(), 0);
+    }
+
+    #[test]
+    fn pruning_with_other_ages() {
+        let cases: &[(u64, &[&str])] = &[
+            (0, &["PRESENT"]),
+            (1, &["PRESENT"]),
+            (29, &["ALMOST", "PRESENT"]),
+            (31, &["ALMOST", "JUST-INSIDE", "OLD", "PRESENT"]),
+            (365, &["ALMOST", "JUST-INSIDE", "OLD", "PRESENT"]),
+            (401, &["ALMOST", "ANCIENT", "JUST-INSIDE", "OLD", "PRESENT"]),
+        ];
+        for (days, left) in cases {
+            let mut registry = seeded();
+            registry.prune_absent(*days).unwrap();
+            assert_eq!(serials(&registry), *left, "{} days", days);
+        }
+    }
+
+    #[test]
+    fn maintenance_runs_once_in_its_hour() {
+        let mut registry = seeded();
+        let config = StorageConfig {
+            prune_absent_after_days: Some(30),
+            maintenance_hour: 3,
+            ..Default::default()
+        };
+
+        registry.maintain_at(2, &config).unwrap();
+        assert_eq!(serials(&registry).len(), 5);
+
+        registry.maintain_at(3, &config).unwrap();
+        assert_eq!(serials(&registry).len(), 3);
+
+        // Not again within the hour, even with more to prune.
+        last_seen(&registry, "ALMOST", "-40 days");
+        registry.maintain_at(3, &config).unwrap();
+        assert_eq!(serials(&registry).len(), 3);
+    }
+
+    #[test]
+    fn maintenance_doesnt_prune_unless_asked_to() {
+        let mut registry = seeded();
+        registry.maintain_at(3, &StorageConfig::default()).unwrap();
+        assert_eq!(serials(&registry).len(), 5);
+    }
+
+    #[test]
+    fn events_are_capped_per_device() {
+        let mut registry = registry();
+        registry.set_event_limit(3);
+        let sda = drive("sda", "WD-1", 1);
+        let sdb = drive("sdb", "WD-2", 2);
+        registry.device_found(&sda).unwrap();
+        registry.device_found(&sdb).unwrap();
+        for n in 0..5 {
+            registry.log_event(&sda, &format!("event {}", n)).unwrap();
+        }
+
+        assert_eq!(
+            summaries(&registry, "WD-1"),
+            ["event 2", "event 3", "event 4"]
+        );
+        // The other device's are its own.
+        assert_eq!(summaries(&registry, "WD-2"), ["Found as sdb"]);
+
+        registry.set_event_limit(0);
+        registry.log_event(&sda, "dropped").unwrap();
+        assert_eq!(
+            summaries(&registry, "WD-1"),
+            ["event 2", "event 3", "event 4"]
+        );
+    }
+
+    // Identifies the drive once for each of `revisions`, the way the
+    // daemon does each time it's found or changes, and says which of those
+    // times its firmware changed.
+    fn identify(
+        registry: &mut Registry,
+        drive: &mut DeviceIdentity,
+        revisions: &[&str],
+    ) -> Vec<Option<FirmwareChange>> {
+        revisions
+            .iter()
+            .map(|revision| {
+                drive.firmware = Some(revision.to_string());
+                registry.firmware_seen(drive).unwrap()
+            })
+            .collect()
+    }
+
+    fn change(from: &str, to: &str) -> Option<FirmwareChange> {
+        Some(FirmwareChange {
+            from: from.to_string(),
+            to: to.to_string(),
+        })
+    }
+
+    #[test]
+    fn firmware_changes_once_seen_twice() {
+        let mut registry = registry();
+        let mut sda = drive("sda", "WD-1", 1);
+        registry.device_found(&sda).unwrap();
+
+        assert_eq!(
+            identify(
+                &mut registry,
+                &mut sda,
+                &["80.00A80", "80.00A80", "82.00A82", "82.00A82", "82.00A82"]
+            ),
+            [None, None, None, change("80.00A80", "82.00A82"), None]
+        );
+        assert_eq!(
+            record(&registry, "WD-1").firmware.as_deref(),
+            Some("82.00A82")
+        );
+        assert_eq!(
+            summaries(&registry, "WD-1"),
+            ["Found as sda", "Firmware changed from 80.00A80 to 82.00A82"]
+        );
+
+        // Carried over being lost and found again.
+        registry.device_lost("sda").unwrap();
+        registry.device_found(&sda).unwrap();
+        assert_eq!(
+            identify(
+                &mut registry,
+                &mut sda,
+                &["82.00A82", "83.00A83", "83.00A83"]
+            ),
+            [None, None, change("82.00A82", "83.00A83")]
+        );
+
+        // Nothing to go on.
+        sda.firmware = None;
+        assert_eq!(registry.firmware_seen(&sda).unwrap(), None);
+        let mut unknown = drive("sdb", "WD-2", 2);
+        assert_eq!(identify(&mut registry, &mut unknown, &["1.0"]), [None]);
+        assert!(registry.device("WD-2").unwrap().is_none());
+    }
+
+    #[test]
+    fn a_flapping_bridge_doesnt_change_the_firmware() {
+        let mut registry = registry();
+        let mut sdb = drive("sdb", "WD-1", 1);
+        registry.device_found(&sdb).unwrap();
+
+        // A USB bridge that every so often reads back garbage in place of
+        // the revision, never the same garbage twice in a row.
+        let flapping = [
+            "80.00A80",
+            "\u{fffd}\u{fffd}",
+            "80.00A80",
+            "????",
+            "80.00A80",
+            "8\0.00",
+            "????",
+            "80.00A80",
+        ];
+        assert_eq!(
+            identify(&mut registry, &mut sdb, &flapping),
+            vec![None; flapping.len()]
+        );
+        assert_eq!(
+            record(&registry, "WD-1").firmware.as_deref(),
+            Some("80.00A80")
+        );
+        assert_eq!(summaries(&registry, "WD-1"), ["Found as sdb"]);
+
+        // Garbage then a real update, it's the update that counts.
+        assert_eq!(
+            identify(&mut registry, &mut sdb, &["????", "82.00A82", "82.00A82"]),
+            [None, None, change("80.00A80", "82.00A82")]
+        );
+    }
+
+    #[test]
+    fn the_log_wraps_around_in_order() {
+        let mut registry = registry();
+        registry.set_event_limit(4);
+        let sda = drive("sda", "WD-1", 1);
+        registry.device_found(&sda).unwrap();
+        for n in 0..23 {
+            registry.log_event(&sda, &format!("event {}", n)).unwrap();
+            let kept = summaries(&registry, "WD-1");
+            assert!(kept.len() <= 4, "{:?}", kept);
+            assert_eq!(kept.last().unwrap(), &format!("event {}", n));
+        }
+        assert_eq!(
+            summaries(&registry, "WD-1"),
+            ["event 19", "event 20", "event 21", "event 22"]
+        );
+
+        // A lower limit takes effect with the next event, a higher one lets
+        // the log grow from there.
+        registry.set_event_limit(2);
+        registry.log_event(&sda, "event 23").unwrap();
+        assert_eq!(summaries(&registry, "WD-1"), ["event 22", "event 23"]);
+        registry.set_event_limit(3);
+        registry.log_event(&sda, "event 24").unwrap();
+        assert_eq!(
+            summaries(&registry, "WD-1"),
+            ["event 22", "event 23", "event 24"]
+        );
+
+        // Found and lost are events like any other, and wrap the same way.
+        registry.device_lost("sda").unwrap();
+        registry.device_found(&sda).unwrap();
+        assert_eq!(
+            summaries(&registry, "WD-1"),
+            ["event 24", "Lost (was sda)", "Found as sda"]
+        );
+    }
+
+    #[test]
+    fn every_kind_of_event_lands_in_the_log() {
+        let mut registry = registry();
+        let mut sda = drive("sda", "WD-1", 1);
+        sda.firmware = Some("80.00A80".to_string());
+        registry.device_found(&sda).unwrap();
+        registry.firmware_seen(&sda).unwrap();
+
+        registry.log_event(&sda, "Changed").unwrap();
+        registry
+            .log_event(&sda, "Probed: gpt, vfat \"ESP\", ext4")
+            .unwrap();
+        registry
+            .log_event(&sda, "Probe failed: Input/output error (os error 5)")
+            .unwrap();
+        sda.firmware = Some("82.00A82".to_string());
+        registry.firmware_seen(&sda).unwrap();
+        registry.firmware_seen(&sda).unwrap();
+        registry.set_alias("WD-1", Some("bay-1")).unwrap();
+        registry.set_quarantine("WD-1", Some("clicking")).unwrap();
+        registry.set_quarantine("WD-1", None).unwrap();
+        let alert = registry
+            .raise_alert(&sda, "reallocated_sectors", "8 reallocated sectors", None)
+            .unwrap()
+            .unwrap();
+        registry.ack_alert(alert.id, "replacing it").unwrap();
+        registry.clear_alert(&sda, "reallocated_sectors").unwrap();
+        registry.device_lost("sda").unwrap();
+
+        let id = alert.id;
+        assert_eq!(
+            summaries(&registry, "WD-1"),
+            [
+                "Found as sda".to_string(),
+                "Changed".to_string(),
+                "Probed: gpt, vfat \"ESP\", ext4".to_string(),
+                "Probe failed: Input/output error (os error 5)".to_string(),
+                "Firmware changed from 80.00A80 to 82.00A82".to_string(),
+                "Called bay-1".to_string(),
+                "Quarantined: clicking".to_string(),
+                "Out of quarantine".to_string(),
+                format!("Alert {} raised, reallocated_sectors", id),
+                format!("Alert {} acknowledged: replacing it", id),
+                format!("Alert {} cleared", id),
+                "Lost (was sda)".to_string(),
+            ]
+        );
+        // Stamped like everything else in the registry.
+        let events = registry.events(record(&registry, "WD-1").id, None).unwrap();
+        assert!(
+            events.iter().all(|event| event.at.ends_with('Z')),
+            "{:?}",
+            events
+        );
+
+        // None of it is kept while the disk is low on space.
+        registry.set_low_on_space(true);
+        registry.log_event(&sda, "Changed").unwrap();
+        registry.device_found(&sda).unwrap();
+        assert_eq!(summaries(&registry, "WD-1").len(), 12);
+    }
+
+    #[test]
+    fn sessions_are_capped_per_device() {
+        let mut registry = registry();
+        registry.set_session_grace(Duration::ZERO);
+        let sda = drive("sda", "WD-1", 1);
+        for n in 0..SESSION_LIMIT + 5 {
+            registry.device_found(&sda).unwrap();
+            registry.device_lost("sda").unwrap();
+            // Ended in the past, so the next find doesn't carry on with it.
+            registry
+                .conn
+                .execute(
+                    "UPDATE device_sessions SET ended = '2000-01-01T00:00:00Z', name = ?1 \
+                     WHERE ended IS NOT NULL AND name = 'sda'",
+                    params![format!("n{}", n)],
+                )
+                .unwrap();
+        }
+
+        let sessions = registry.sessions(record(&registry, "WD-1").id).unwrap();
+        assert_eq!(sessions.len(), SESSION_LIMIT as usize);
+        assert_eq!(sessions[0].name, "n5");
+    }
+
+    // What session each of a device's events was tagged with, by summary.
+    fn tagged(registry: &Registry, key: &str) -> Vec<(String, Option<i64>)> {
+        let id = record(registry, key).id;
+        registry
+            .events(id, None)
+            .unwrap()
+            .into_iter()
+            .map(|event| (event.summary, event.session))
+            .collect()
+    }
+
+    // Has every session that ended end long enough ago to be out of the
+    // grace.
+    fn ended_long_ago(registry: &Registry) {
+        registry
+            .conn
+            .execute(
+                "UPDATE device_sessions SET ended = '2000-01-01T00:00:00Z' \
+                 WHERE ended IS NOT NULL",
+                [],
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn everything_between_found_and_lost_is_one_session() {
+        let mut registry = registry();
+        let mut sda = drive("sda", "WD-1", 1);
+        sda.firmware = Some("80.00A80".to_string());
+        let sdb = drive("sdb", "WD-2", 2);
+
+        // Two drives at work at once, their events interleaved.
+        registry.device_found(&sda).unwrap();
+        registry.device_found(&sdb).unwrap();
+        registry.firmware_seen(&sda).unwrap();
+        registry.log_event(&sda, "Probed: gpt, ext4").unwrap();
+        registry.log_event(&sdb, "Probed: nothing").unwrap();
+        registry.set_quarantine("WD-2", Some("clicking")).unwrap();
+        let alert = registry
+            .raise_alert(&sda, "reallocated_sectors", "8 reallocated sectors", None)
+            .unwrap()
+            .unwrap();
+        registry.log_event(&sda, "Verified blank").unwrap();
+        registry.device_lost("sda").unwrap();
+        registry.log_event(&sdb, "Blinked").unwrap();
+
+        let sda_id = record(&registry, "WD-1").id;
+        let sessions = registry.sessions(sda_id).unwrap();
+        assert_eq!(sessions.len(), 1);
+        let session = &sessions[0];
+        assert_eq!(session.name, "sda");
+        assert!(session.ended.is_some());
+        assert!(session.duration_secs.unwrap() >= 0);
+        let sda_events = tagged(&registry, "WD-1");
+        assert_eq!(sda_events.len(), 5);
+        assert!(
+            sda_events.iter().all(|(_, tag)| *tag == Some(session.id)),
+            "{:?}",
+            sda_events
+        );
+
+        // sdb is still plugged in, in a session of its own.
+        let sdb_id = record(&registry, "WD-2").id;
+        let sdb_sessions = registry.sessions(sdb_id).unwrap();
+        assert_eq!(sdb_sessions.len(), 1);
+        assert_ne!(sdb_sessions[0].id, session.id);
+        assert_eq!(sdb_sessions[0].ended, None);
+        assert_eq!(sdb_sessions[0].duration_secs, None);
+        let sdb_events = tagged(&registry, "WD-2");
+        assert_eq!(sdb_events.len(), 4);
+        assert!(sdb_events
+            .iter()
+            .all(|(_, tag)| *tag == Some(sdb_sessions[0].id)));
+
+        // What happens to a device that isn't plugged in is in none.
+        registry.ack_alert(alert.id, "replacing it").unwrap();
+        assert_eq!(
+            tagged(&registry, "WD-1").last().unwrap(),
+            &(
+                format!("Alert {} acknowledged: replacing it", alert.id),
+                None
+            )
+        );
+
+        // Filtering by session gets only its events, and nothing from
+        // another device's.
+        let in_session = registry.events(sda_id, Some(session.id)).unwrap();
+        assert_eq!(in_session.len(), 5);
+        assert_eq!(in_session[0].summary, "Found as sda");
+        assert_eq!(in_session[4].summary, "Lost (was sda)");
+        assert!(registry
+            .events(sda_id, Some(sdb_sessions[0].id))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn found_again_within_the_grace_carries_on() {
+        let mut registry = registry();
+        let sda = drive("sda", "WD-1", 1);
+        registry.device_found(&sda).unwrap();
+        registry.device_lost("sda").unwrap();
+        // Reseated, and back under another name.
+        registry.device_found(&drive("sdc", "WD-1", 1)).unwrap();
+        registry.log_event(&sda, "Probed: nothing").unwrap();
+
+        let id = record(&registry, "WD-1").id;
+        let sessions = registry.sessions(id).unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].name, "sdc");
+        assert_eq!(sessions[0].ended, None);
+        let events = tagged(&registry, "WD-1");
+        assert_eq!(events.len(), 4);
+        assert!(events.iter().all(|(_, tag)| *tag == Some(sessions[0].id)));
+
+        // Past it, a new one.
+        registry.device_lost("sdc").unwrap();
+        ended_long_ago(&registry);
+        registry.device_found(&sda).unwrap();
+        let sessions = registry.sessions(id).unwrap();
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions[0].ended.as_deref(), Some("2000-01-01T00:00:00Z"));
+        assert_eq!(sessions[1].ended, None);
+        assert_eq!(
+            tagged(&registry, "WD-1").last().unwrap(),
+            &("Found as sda".to_string(), Some(sessions[1].id))
+        );
+
+        // No grace at all, and even a device back right away starts over.
+        registry.set_session_grace(Duration::ZERO);
+        registry.device_lost("sda").unwrap();
+        ended_long_ago(&registry);
+        registry.device_found(&sda).unwrap();
+        assert_eq!(registry.sessions(id).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn a_missed_remove_ends_the_session() {
+        let mut registry = registry();
+        registry.device_found(&drive("sda", "WD-1", 1)).unwrap();
+        // Another drive in the same bay, the first was never seen leaving.
+        registry.device_found(&drive("sda", "WD-2", 1)).unwrap();
+
+        let first = registry.sessions(record(&registry, "WD-1").id).unwrap();
+        assert!(first[0].ended.is_some());
+        let second = registry.sessions(record(&registry, "WD-2").id).unwrap();
+        assert_eq!(second[0].ended, None);
+        assert_ne!(first[0].id, second[0].id);
+    }
+
+    #[test]
+    fn sessions_left_open_end_at_their_last_event() {
+        let mut registry = registry();
+        let sda = drive("sda", "WD-1", 1);
+        registry.device_found(&sda).unwrap();
+        registry.log_event(&sda, "Probed: nothing").unwrap();
+        // Found an hour before the daemon stopped, and probed after ten
+        // minutes.
+        registry
+            .conn
+            .execute_batch(
+                "UPDATE device_sessions SET started = '2026-01-01T10:00:00Z'; \
+                 UPDATE device_events SET at = '2026-01-01T10:00:00Z' \
+                 WHERE summary = 'Found as sda'; \
+                 UPDATE device_events SET at = '2026-01-01T10:10:00Z' \
+                 WHERE summary = 'Probed: nothing';",
+            )
+            .unwrap();
+
+        registry.reset_presence().unwrap();
+        let id = record(&registry, "WD-1").id;
+        let sessions = registry.sessions(id).unwrap();
+        assert_eq!(sessions[0].ended.as_deref(), Some("2026-01-01T10:10:00Z"));
+        assert_eq!(sessions[0].duration_secs, Some(600));
+
+        // Found in the next run, long after, it's a session of its own.
+        registry.device_found(&sda).unwrap();
+        let sessions = registry.sessions(id).unwrap();
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions[1].ended, None);
+    }
+
+    #[test]
+    fn mmc_health_is_kept_and_logged_when_it_changes() {
+        let mut registry = registry();
+        let mmcblk0 = DeviceIdentity {
+            name: "mmcblk0".to_string(),
+            serial: Some("0x5b1c2a9e".to_string()),
+            model: Some("DG4016".to_string()),
+            ..Default::default()
+        };
+        registry.device_found(&mmcblk0).unwrap();
+        let worn = |percent_used| MmcHealth {
+            life_time_b: Some(percent_used / 10),
+            percent_used: Some(percent_used),
+            pre_eol: Some(PreEol::Normal),
+            ..Default::default()
+        };
+
+        registry.set_mmc_health(&mmcblk0, &worn(30)).unwrap();
+        registry.set_mmc_health(&mmcblk0, &worn(30)).unwrap();
+        registry.set_mmc_health(&mmcblk0, &worn(90)).unwrap();
+        // Unknown to the registry, left alone.
+        registry
+            .set_mmc_health(&drive("mmcblk1", "0x0", 1), &worn(10))
+            .unwrap();
+
+        assert_eq!(
+            summaries(&registry, "0x5b1c2a9e"),
+            [
+                "Found as mmcblk0",
+                "Health: type A unknown, type B 20-30% used, pre-EOL normal",
+                "Health: type A unknown, type B 80-90% used, pre-EOL normal",
+            ]
+        );
+        let kept: MmcHealth =
+            serde_json::from_value(record(&registry, "0x5b1c2a9e").mmc_health).unwrap();
+        assert_eq!(kept, worn(90));
+        assert_eq!(
+            kept.concern().as_deref(),
+            Some("it has used up 80-90% of its rated life")
+        );
+        assert_eq!(registry.devices(true).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn an_alias_is_unique_among_present_devices() {
+        let mut registry = registry();
+        registry.device_found(&drive("sda", "WD-A", 1)).unwrap();
+        registry.device_found(&drive("sdb", "WD-B", 2)).unwrap();
+
+        let named = registry.set_alias("sda", Some("bay 1")).unwrap();
+        assert_eq!(named.alias.as_deref(), Some("bay 1"));
+        assert_eq!(named.display_name(), "bay 1");
+        assert!(!named.alias_from_template);
+        // Setting it again on the same device is fine.
+        registry.set_alias("WD-A", Some("bay 1")).unwrap();
+
+        let taken = registry.set_alias("sdb", Some("bay 1")).unwrap_err();
+        assert!(
+            matches!(&taken, StorageError::AliasTaken { alias, holder }
+                if alias == "bay 1" && holder == "sda (WD-A)"),
+            "{:?}",
+            taken
+        );
+        assert_eq!(taken.to_string(), "sda (WD-A) is already called bay 1");
+        assert_eq!(record(&registry, "WD-B").alias, None);
+        assert_eq!(record(&registry, "WD-B").display_name(), "sdb");
+
+        // Once the holder is gone, the name is free again.
+        registry.device_lost("sda").unwrap();
+        registry.set_alias("sdb", Some("bay 1")).unwrap();
+
+        assert!(matches!(
+            registry.set_alias("sdz", Some("bay 9")),
+            Err(StorageError::NoSuchDevice { key }) if key == "sdz"
+        ));
+
+        registry.set_alias("WD-B", None).unwrap();
+        assert_eq!(record(&registry, "WD-B").alias, None);
+        assert_eq!(
+            summaries(&registry, "WD-B"),
+            ["Found as sdb", "Called bay 1", "No longer called anything"]
+        );
+    }
+
+    #[test]
+    fn lookups_by_alias_prefer_the_present_device() {
+        let mut registry = registry();
+        registry.device_found(&drive("sda", "WD-A", 1)).unwrap();
+        registry.set_alias("sda", Some("scratch")).unwrap();
+        registry.device_lost("sda").unwrap();
+        registry.device_found(&drive("sdb", "WD-B", 2)).unwrap();
+        registry.set_alias("sdb", Some("scratch")).unwrap();
+
+        assert_eq!(record(&registry, "scratch").serial.as_deref(), Some("WD-B"));
+        registry.device_lost("sdb").unwrap();
+        registry.device_found(&drive("sda", "WD-A", 1)).unwrap();
+        assert_eq!(record(&registry, "scratch").serial.as_deref(), Some("WD-A"));
+        // Only a whole alias matches.
+        assert!(registry.device("scratc").unwrap().is_none());
+        assert!(registry.device("scratch ").unwrap().is_none());
+
+        assert_eq!(
+            registry.alias(&drive("sdb", "WD-B", 2)).unwrap().as_deref(),
+            Some("scratch")
+        );
+        assert_eq!(registry.alias(&drive("sdc", "WD-C", 3)).unwrap(), None);
+    }
+
+    #[test]
+    fn aliases_from_the_template() {
+        let mut registry = registry();
+        let sda = drive("sda", "WD-A", 1);
+        let sdb = drive("sdb", "WD-B", 2);
+        registry.device_found(&sda).unwrap();
+        registry.device_found(&sdb).unwrap();
+
+        assert_eq!(
+            registry
+                .template_alias(&sda, Some("bay-1"))
+                .unwrap()
+                .as_deref(),
+            Some("bay-1")
+        );
+        assert!(record(&registry, "WD-A").alias_from_template);
+        // Nothing changed, nothing logged.
+        registry.template_alias(&sda, Some("bay-1")).unwrap();
+        // Moved to another bay.
+        registry.template_alias(&sda, Some("bay-3")).unwrap();
+        assert_eq!(record(&registry, "bay-3").serial.as_deref(), Some("WD-A"));
+
+        // One that's taken isn't given, and any it had is taken away.
+        registry.template_alias(&sdb, Some("bay-2")).unwrap();
+        assert!(matches!(
+            registry.template_alias(&sdb, Some("bay-3")),
+            Err(StorageError::AliasTaken { holder, .. }) if holder == "sda (WD-A)"
+        ));
+        assert_eq!(record(&registry, "WD-B").alias, None);
+
+        // Nothing to go on takes the templated one away.
+        registry.template_alias(&sda, None).unwrap();
+        assert_eq!(record(&registry, "WD-A").alias, None);
+        assert_eq!(
+            summaries(&registry, "WD-A"),
+            [
+                "Found as sda",
+                "Called bay-1, from the alias template",
+                "Called bay-3, from the alias template",
+                "No longer called anything, the alias template has no name for it",
+            ]
+        );
+
+        // One set by hand wins.
+        registry.set_alias("sda", Some("boot")).unwrap();
+        assert_eq!(
+            registry
+                .template_alias(&sda, Some("bay-1"))
+                .unwrap()
+                .as_deref(),
+            Some("boot")
+        );
+        assert_eq!(
+            registry.template_alias(&sda, None).unwrap().as_deref(),
+            Some("boot")
+        );
+        assert!(!record(&registry, "boot").alias_from_template);
+
+        // Devices it doesn't know get nothing.
+        assert_eq!(
+            registry
+                .template_alias(&drive("sdc", "WD-C", 3), Some("bay-4"))
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn quarantine_lasts_until_its_lifted() {
+        let mut registry = registry();
+        let sda = drive("sda", "WD-A", 1);
+        registry.device_found(&sda).unwrap();
+        registry.device_found(&drive("sdb", "WD-B", 2)).unwrap();
+        registry.set_alias("sda", Some("evidence")).unwrap();
+
+        let quarantined = registry
+            .set_quarantine("evidence", Some("legal hold"))
+            .unwrap();
+        assert_eq!(quarantined.quarantine.as_deref(), Some("legal hold"));
+        let since = quarantined.quarantined_since.clone().unwrap();
+        assert_eq!(
+            registry.quarantine(&sda).unwrap().as_deref(),
+            Some("legal hold")
+        );
+        assert_eq!(registry.quarantine(&drive("sdb", "WD-B", 2)).unwrap(), None);
+
+        // A new reason keeps when it started. Replugging doesn't let it out.
+        registry.set_quarantine("WD-A", Some("case 42")).unwrap();
+        registry.device_lost("sda").unwrap();
+        registry.device_found(&drive("sdc", "WD-A", 3)).unwrap();
+        assert_eq!(
+            registry
+                .quarantine(&drive("sdc", "WD-A", 3))
+                .unwrap()
+                .as_deref(),
+            Some("case 42")
+        );
+        assert_eq!(
+            record(&registry, "WD-A").quarantined_since.as_deref(),
+            Some(since.as_str())
+        );
+
+        let lifted = registry.set_quarantine("sdc", None).unwrap();
+        assert_eq!(lifted.quarantine, None);
+        assert_eq!(lifted.quarantined_since, None);
+        assert_eq!(record(&registry, "WD-A").quarantined_since, None);
+        assert!(matches!(
+            registry.set_quarantine("sdz", Some("anything")),
+            Err(StorageError::NoSuchDevice { .. })
+        ));
+        assert_eq!(
+            summaries(&registry, "WD-A")
+                .into_iter()
+                .filter(|summary| summary.contains("uarantine"))
+                .collect::<Vec<_>>(),
+            [
+                "Quarantined: legal hold",
+                "Quarantined: case 42",
+                "Out of quarantine"
+            ]
+        );
+    }
+
+    #[test]
+    fn an_acknowledged_alert_stays_quiet_until_it_clears() {
+        let mut registry = registry();
+        let sda = drive("sda", "WD-A", 1);
+        registry.device_found(&sda).unwrap();
+        let raise = |registry: &mut Registry, realert_after| {
+            registry
+                .raise_alert(
+                    &sda,
+                    "device_became_read_only",
+                    "sda went read-only",
+                    realert_after,
+                )
+                .unwrap()
+                .unwrap()
+        };
+
+        let alert = raise(&mut registry, None);
+        assert_eq!(alert.state(), AlertState::Open);
+        let acked = registry.ack_alert(alert.id, "replacing it").unwrap();
+        assert_eq!(acked.state(), AlertState::Acknowledged);
+
+        // Raised again, it's the same alert and still acknowledged, which
+        // is what keeps it from being notified about.
+        let again = raise(&mut registry, None);
+        assert_eq!(again.id, alert.id);
+        assert_eq!(again.times, 2);
+        assert_eq!(again.ack_note.as_deref(), Some("replacing it"));
+        let again = raise(&mut registry, Some(Duration::from_secs(3600)));
+        assert_eq!(again.state(), AlertState::Acknowledged);
+
+        // Unless the acknowledgement has run out.
+        let back = raise(&mut registry, Some(Duration::ZERO));
+        assert_eq!(back.id, alert.id);
+        assert_eq!(back.state(), AlertState::Open);
+        assert_eq!(back.ack_note, None);
+
+        registry.ack_alert(alert.id, "really replacing it").unwrap();
+        let cleared = registry
+            .clear_alert(&sda, "device_became_read_only")
+            .unwrap()
+            .unwrap();
+        assert_eq!(cleared.state(), AlertState::Cleared);
+        assert!(matches!(
+            registry.ack_alert(alert.id, "too late"),
+            Err(StorageError::AlertCleared { .. })
+        ));
+        assert_eq!(
+            registry
+                .clear_alert(&sda, "device_became_read_only")
+                .unwrap()
+                .map(|alert| alert.id),
+            None
+        );
+
+        // Once cleared, it happening again is a new alert nobody has
+        // acknowledged.
+        let new = raise(&mut registry, None);
+        assert_ne!(new.id, alert.id);
+        assert_eq!(new.state(), AlertState::Open);
+        assert_eq!(
+            registry
+                .alerts(None, Some(AlertState::Cleared))
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(matches!(
+            registry.ack_alert(9999, "no such thing"),
+            Err(StorageError::NoSuchAlert { id: 9999 })
+        ));
+    }
+
+    // Has every session that ended end an hour ago, past the grace for
+    // carrying on with one.
+    fn sessions_ended_long_ago(registry: &Registry) {
+        registry
+            .conn
+            .execute(
+                "UPDATE device_sessions SET \
+                 started = strftime('%Y-%m-%dT%H:%M:%SZ', 'now', '-2 hours'), \
+                 ended = strftime('%Y-%m-%dT%H:%M:%SZ', 'now', '-1 hours') \
+                 WHERE ended IS NOT NULL",
+                [],
+            )
+            .unwrap();
+    }
+
+    // The same drive twice, first behind a bridge that doesn't pass its
+    // WWN on, with an alias and a quarantine, then behind one that doesn't
+    // pass its serial on.
+    fn two_records_of_one_drive(registry: &mut Registry) -> (String, String) {
+        let by_serial = drive("sda", "WD-1", 1);
+        let serial_uuid = registry.device_found(&by_serial).unwrap().unwrap();
+        registry.set_alias("sda", Some("bay 1")).unwrap();
+        registry.set_quarantine("sda", Some("clicking")).unwrap();
+        registry
+            .raise_alert(
+                &by_serial,
+                "device_became_read_only",
+                "sda went read-only",
+                None,
+            )
+            .unwrap();
+        registry.device_lost("sda").unwrap();
+        sessions_ended_long_ago(registry);
+
+        let by_wwn = DeviceIdentity {
+            name: "sdb".to_string(),
+            paths: vec![PathBuf::from("/dev/sdb")],
+            wwn: Some("0x50014ee2b5a1c3d4".to_string()),
+            ..Default::default()
+        };
+        let wwn_uuid = registry.device_found(&by_wwn).unwrap().unwrap();
+        registry
+            .raise_alert(
+                &by_wwn,
+                "device_became_read_only",
+                "sdb went read-only",
+                None,
+            )
+            .unwrap();
+        registry.device_lost("sdb").unwrap();
+        sessions_ended_long_ago(registry);
+        assert_ne!(serial_uuid, wwn_uuid);
+        assert_eq!(registry.devices(true).unwrap().len(), 2);
+        (serial_uuid, wwn_uuid)
+    }
+
+    fn with_both(name: &str) -> DeviceIdentity {
+        DeviceIdentity {
+            wwn: Some("0x50014ee2b5a1c3d4".to_string()),
+            ..drive(name, "WD-1", 3)
+        }
+    }
+
+    #[test]
+    fn a_wwn_turning_up_merges_the_records() {
+        let mut registry = registry();
+        registry.set_identity(
+            &[
+                IdentityBasis::SerialModel,
+                IdentityBasis::Wwn,
+                IdentityBasis::Path,
+            ],
+            &[],
+        );
+        let (serial_uuid, wwn_uuid) = two_records_of_one_drive(&mut registry);
+
+        // Seen with both, it's the record it was told apart by first in the
+        // chain, with the other one's history.
+        let found = registry.device_found(&with_both("sdc")).unwrap().unwrap();
+        assert_eq!(found, serial_uuid);
+        let devices = registry.devices(true).unwrap();
+        assert_eq!(devices.len(), 1);
+        let merged = &devices[0];
+        assert_eq!(merged.identity_basis, Some(IdentityBasis::SerialModel));
+        assert_eq!(merged.wwn.as_deref(), Some("0x50014ee2b5a1c3d4"));
+        assert_eq!(merged.alias.as_deref(), Some("bay 1"));
+        assert_eq!(merged.quarantine.as_deref(), Some("clicking"));
+        assert_eq!(merged.times_seen, 3);
+
+        // The merged UUID still leads to it.
+        assert_eq!(record(&registry, &wwn_uuid).uuid, serial_uuid);
+        let merged_rows: Vec<(String, i64)> = registry
+            .conn
+            .prepare("SELECT uuid, device_id FROM merged_devices")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(merged_rows, [(wwn_uuid.clone(), merged.id)]);
+
+        // Every session and event once, and one alert raised by both.
+        let sessions = registry.sessions(merged.id).unwrap();
+        let mut names = sessions
+            .iter()
+            .map(|session| session.name.as_str())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["sda", "sdb", "sdc"]);
+        let summaries = summaries(&registry, &serial_uuid);
+        for summary in ["Found as sda", "Found as sdb", "Found as sdc"] {
+            assert_eq!(
+                summaries.iter().filter(|s| *s == summary).count(),
+                1,
+                "{:?}",
+                summaries
+            );
+        }
+        assert!(summaries.contains(&format!(
+            "Merged with the record {}, the same drive by its WWN",
+            wwn_uuid
+        )));
+        let alerts = registry.alerts(None, None).unwrap();
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].device_id, merged.id);
+        assert_eq!(alerts[0].times, 2);
+        assert_eq!(rows(&registry, "devices"), 1);
+    }
+
+    #[test]
+    fn by_default_the_wwn_record_is_kept() {
+        let mut registry = registry();
+        let (serial_uuid, wwn_uuid) = two_records_of_one_drive(&mut registry);
+
+        let found = registry.device_found(&with_both("sdc")).unwrap().unwrap();
+        assert_eq!(found, wwn_uuid);
+        assert_eq!(registry.devices(true).unwrap().len(), 1);
+        let merged = record(&registry, &serial_uuid);
+        assert_eq!(merged.uuid, wwn_uuid);
+        assert_eq!(merged.identity_basis, Some(IdentityBasis::Wwn));
+        assert_eq!(merged.serial.as_deref(), Some("WD-1"));
+        assert_eq!(merged.alias.as_deref(), Some("bay 1"));
+        assert_eq!(rows(&registry, "merged_devices"), 1);
+
+        // Seeing it again merges nothing more.
+        registry.device_lost("sdc").unwrap();
+        sessions_ended_long_ago(&registry);
+        registry.device_found(&with_both("sdd")).unwrap();
+        assert_eq!(rows(&registry, "merged_devices"), 1);
+        assert_eq!(registry.sessions(merged.id).unwrap().len(), 4);
+    }
+
+    // A drive behind a USB bridge on `port`, known only by its device node
+    // otherwise.
+    fn behind_usb(name: &str, serial: Option<&str>, port: &str, size_bytes: u64) -> DeviceIdentity {
+        DeviceIdentity {
+            name: name.to_string(),
+            paths: vec![PathBuf::from(format!("/dev/{}", name))],
+            serial: serial.map(str::to_string),
+            model: Some("ASM1153 SATA Bridge".to_string()),
+            usb_port: Some(port.to_string()),
+            capacity: Some(crate::capacity::Capacity {
+                size_bytes,
+                logical_block_size: 512,
+                physical_block_size: 4096,
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn shared_serials_never_merge() {
+        const TB: u64 = 1_000_000_000_000;
+        let mut registry = registry();
+        registry.set_identity(DEFAULT_IDENTITY, &["0123456789ABCDEF".to_string()]);
+
+        // Two bridges of the same make, with the serial they all have.
+        let first = registry
+            .device_found(&behind_usb("sda", Some("0123456789ABCDEF"), "2-1", 4 * TB))
+            .unwrap();
+        registry.device_lost("sda").unwrap();
+        let second = registry
+            .device_found(&behind_usb("sdb", Some("0123456789ABCDEF"), "2-2", 8 * TB))
+            .unwrap();
+        assert_ne!(first, second);
+        registry.device_lost("sdb").unwrap();
+        assert_eq!(
+            registry
+                .device_found(&behind_usb("sdc", Some("0123456789ABCDEF"), "2-1", 4 * TB))
+                .unwrap(),
+            first
+        );
+
+        // One that two devices have at once is shared from then on.
+        registry.device_found(&drive("sdd", "WD-TWIN", 4)).unwrap();
+        registry.device_found(&drive("sde", "WD-TWIN", 5)).unwrap();
+        registry.device_lost("sdd").unwrap();
+        registry.device_lost("sde").unwrap();
+        let twin = DeviceIdentity {
+            wwn: Some("0x5000c500a1b2c3d4".to_string()),
+            ..drive("sdf", "WD-TWIN", 6)
+        };
+        registry.device_found(&twin).unwrap();
+
+        assert_eq!(registry.devices(true).unwrap().len(), 5);
+        assert_eq!(rows(&registry, "merged_devices"), 0);
+        assert_eq!(rows(&registry, "shared_serials"), 1);
+    }
+
+    #[test]
+    fn weak_bases_never_merge() {
+        const TB: u64 = 1_000_000_000_000;
+        let mut registry = registry();
+
+        // Found by the USB port and size, then by the device node.
+        let by_port = registry
+            .device_found(&behind_usb("sda", None, "1-1", 4 * TB))
+            .unwrap()
+            .unwrap();
+        registry.device_lost("sda").unwrap();
+        let by_path = registry
+            .device_found(&DeviceIdentity {
+                name: "sdc".to_string(),
+                paths: vec![PathBuf::from("/dev/sdc")],
+                ..Default::default()
+            })
+            .unwrap()
+            .unwrap();
+        registry.device_lost("sdc").unwrap();
+        assert_eq!(
+            record(&registry, &by_path).identity_basis,
+            Some(IdentityBasis::Path)
+        );
+
+        // Something on that port with that size, with that device node,
+        // matches both but is only a guess at either.
+        let found = registry
+            .device_found(&behind_usb("sdc", None, "1-1", 4 * TB))
+            .unwrap()
+            .unwrap();
+        assert_eq!(found, by_port);
+        assert_eq!(
+            record(&registry, &by_port).identity_basis,
+            Some(IdentityBasis::UsbPortSize)
+        );
+        assert_eq!(registry.devices(true).unwrap().len(), 2);
+        assert_eq!(rows(&registry, "merged_devices"), 0);
+        assert_eq!(
+            registry
+                .sessions(record(&registry, &by_path).id)
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+}
@@ -0,0 +1,393 @@
+use std::{
+    any::Any,
+    backtrace::Backtrace,
+    collections::{BTreeMap, VecDeque},
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use deno_core::futures::FutureExt;
+use tokio::task::JoinHandle;
+
+/// How a supervised task is restarted after it panics. Restarts back off
+/// from backoff_base, doubling each time up to backoff_max, and once there
+/// have been max_restarts within window the task is given up on.
+#[derive(Debug, Clone, Copy)]
+pub struct RestartPolicy {
+    /// How long to wait before the first restart.
+    pub backoff_base: Duration,
+    /// The longest a restart is ever waited for.
+    pub backoff_max: Duration,
+    /// How many restarts within window are allowed.
+    pub max_restarts: u32,
+    /// How far back restarts are counted.
+    pub window: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            backoff_base: Duration::from_secs(1),
+            backoff_max: Duration::from_secs(60),
+            max_restarts: 5,
+            window: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+/// How a supervised subsystem is doing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubsystemState {
+    /// Up and doing its work.
+    Running,
+    /// Panicked, waiting out the backoff before starting again.
+    Restarting,
+    /// Panicked too often and won't be started again.
+    Failed,
+    /// Finished on its own, like a notifier whose queue was closed.
+    Stopped,
+}
+
+impl SubsystemState {
+    /// The name it goes by in `hddmond status`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SubsystemState::Running => "running",
+            SubsystemState::Restarting => "restarting",
+            SubsystemState::Failed => "failed",
+            SubsystemState::Stopped => "stopped",
+        }
+    }
+}
+
+/// Every supervised subsystem by name, and how it's doing. Cheap to clone,
+/// the clones share the same subsystems.
+#[derive(Debug, Clone, Default)]
+pub struct Health {
+    subsystems: Arc<Mutex<BTreeMap<String, SubsystemState>>>,
+}
+
+impl Health {
+    /// No subsystems yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the subsystem `name`, running, and hands back its flag to keep
+    /// up to date.
+    pub fn register(&self, name: &str) -> HealthFlag {
+        let flag = HealthFlag {
+            name: name.to_string(),
+            subsystems: self.subsystems.clone(),
+        };
+        flag.set(SubsystemState::Running);
+        flag
+    }
+
+    /// Every subsystem's state right now.
+    pub fn snapshot(&self) -> BTreeMap<String, SubsystemState> {
+        lock(&self.subsystems).clone()
+    }
+
+    /// The subsystems that were given up on.
+    pub fn failed(&self) -> Vec<String> {
+        lock(&self.subsystems)
+            .iter()
+            .filter(|(_, state)| **state == SubsystemState::Failed)
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+}
+
+/// One subsystem's entry in Health.
+#[derive(Debug)]
+pub struct HealthFlag {
+    name: String,
+    subsystems: Arc<Mutex<BTreeMap<String, SubsystemState>>>,
+}
+
+impl HealthFlag {
+    /// Records what the subsystem is up to now.
+    pub fn set(&self, state: SubsystemState) {
+        lock(&self.subsystems).insert(self.name.clone(), state);
+    }
+}
+
+// Nothing that holds this lock can panic halfway through, a poisoned one
+// is as good as any.
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Sends panics to the log with a backtrace, instead of to stderr, which is
+/// /dev/null once we've daemonized. Covers every thread, supervised or not.
+pub fn install_panic_hook() {
+    panic::set_hook(Box::new(|info| {
+        let thread = std::thread::current();
+        error!(
+            "Thread {} panicked at {}: {}\n{}",
+            thread.name().unwrap_or("<unnamed>"),
+            info.location()
+                .map(|location| location.to_string())
+                .unwrap_or_default(),
+            panic_message(info.payload()),
+            Backtrace::force_capture()
+        );
+    }));
+}
+
+/// Runs a long-lived task, starting it again with a fresh future from
+/// `task` whenever it panics, as the policy allows. The health flag follows
+/// along. The returned handle finishes once the task does on its own, or is
+/// given up on.
+pub fn spawn<F, Fut>(
+    name: String,
+    policy: RestartPolicy,
+    health: HealthFlag,
+    mut task: F,
+) -> JoinHandle<()>
+where
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut restarts: VecDeque<Instant> = VecDeque::new();
+
+        loop {
+            health.set(SubsystemState::Running);
+
+            let payload = match AssertUnwindSafe(task()).catch_unwind().await {
+                Ok(()) => {
+                    health.set(SubsystemState::Stopped);
+                    return;
+                }
+                Err(payload) => payload,
+            };
+
+            let now = Instant::now();
+            while restarts
+                .front()
+                .is_some_and(|restart| now.duration_since(*restart) >= policy.window)
+            {
+                restarts.pop_front();
+            }
+
+            if restarts.len() >= policy.max_restarts as usize {
+                error!(
+                    "{} panicked ({}) after {} restarts within {:?}, giving up on it",
+                    name,
+                    panic_message(payload.as_ref()),
+                    restarts.len(),
+                    policy.window
+                );
+                health.set(SubsystemState::Failed);
+                return;
+            }
+
+            restarts.push_back(now);
+            let delay = policy
+                .backoff_base
+                .saturating_mul(2u32.saturating_pow(restarts.len() as u32 - 1))
+                .min(policy.backoff_max);
+            warn!(
+                "{} panicked ({}), restarting it in {:?}",
+                name,
+                panic_message(payload.as_ref()),
+                delay
+            );
+            health.set(SubsystemState::Restarting);
+            tokio::time::sleep(delay).await;
+        }
+    })
+}
+
+// panic!() payloads are a &str for literals and a String when formatted.
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "no message"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(max_restarts: u32, window: Duration) -> RestartPolicy {
+        RestartPolicy {
+            backoff_base: Duration::from_millis(10),
+            backoff_max: Duration::from_millis(50),
+            max_restarts,
+            window,
+        }
+    }
+
+    // A subsystem that runs for `runs_for` and then panics, on every start
+    // `panics` says it should, and otherwise finishes. Counts its starts,
+    // and when each was.
+    struct Subsystem {
+        starts: Arc<Mutex<Vec<tokio::time::Instant>>>,
+    }
+
+    impl Subsystem {
+        fn spawn(
+            health: &Health,
+            policy: RestartPolicy,
+            runs_for: Duration,
+            panics: impl Fn(usize) -> bool + Send + Sync + 'static,
+        ) -> (Self, JoinHandle<()>) {
+            let starts = Arc::new(Mutex::new(vec![]));
+            let panics = Arc::new(panics);
+            let task_starts = starts.clone();
+            let handle = spawn(
+                "mock".to_string(),
+                policy,
+                health.register("mock"),
+                move || {
+                    let (starts, panics) = (task_starts.clone(), panics.clone());
+                    async move {
+                        let start = {
+                            let mut starts = starts.lock().unwrap();
+                            starts.push(tokio::time::Instant::now());
+                            starts.len()
+                        };
+                        tokio::time::sleep(runs_for).await;
+                        if panics(start) {
+                            panic!("Start {} blew up", start);
+                        }
+                    }
+                },
+            );
+            (Self { starts }, handle)
+        }
+
+        fn starts(&self) -> usize {
+            self.starts.lock().unwrap().len()
+        }
+
+        // How long after each panic the next start was.
+        fn delays(&self, runs_for: Duration) -> Vec<Duration> {
+            self.starts
+                .lock()
+                .unwrap()
+                .windows(2)
+                .map(|starts| starts[1] - starts[0] - runs_for)
+                .collect()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_panicking_subsystem_is_restarted_then_given_up_on() {
+        let health = Health::new();
+        let other = health.register("other");
+        let (subsystem, handle) = Subsystem::spawn(
+            &health,
+            policy(3, Duration::from_secs(600)),
+            Duration::ZERO,
+            |_| true,
+        );
+
+        // Giving up is the supervisor finishing, not it panicking too.
+        handle.await.unwrap();
+        assert_eq!(subsystem.starts(), 4);
+        assert_eq!(health.failed(), ["mock"]);
+        assert_eq!(health.snapshot()["mock"], SubsystemState::Failed);
+        // Nothing else is affected.
+        assert_eq!(health.snapshot()["other"], SubsystemState::Running);
+        other.set(SubsystemState::Stopped);
+        assert_eq!(health.failed(), ["mock"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn restarts_back_off_up_to_the_max() {
+        let health = Health::new();
+        let (subsystem, handle) = Subsystem::spawn(
+            &health,
+            policy(5, Duration::from_secs(600)),
+            Duration::ZERO,
+            |_| true,
+        );
+        handle.await.unwrap();
+
+        let ms = Duration::from_millis;
+        assert_eq!(
+            subsystem.delays(Duration::ZERO),
+            [ms(10), ms(20), ms(40), ms(50), ms(50)]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shows_restarting_during_the_backoff() {
+        let health = Health::new();
+        let (subsystem, handle) = Subsystem::spawn(
+            &health,
+            RestartPolicy {
+                backoff_base: Duration::from_secs(5),
+                backoff_max: Duration::from_secs(5),
+                ..policy(1, Duration::from_secs(600))
+            },
+            Duration::ZERO,
+            |start| start == 1,
+        );
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(subsystem.starts(), 1);
+        assert_eq!(health.snapshot()["mock"], SubsystemState::Restarting);
+
+        handle.await.unwrap();
+        assert_eq!(subsystem.starts(), 2);
+        assert_eq!(health.snapshot()["mock"], SubsystemState::Stopped);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_subsystem_that_finishes_isnt_restarted() {
+        let health = Health::new();
+        let (subsystem, handle) = Subsystem::spawn(
+            &health,
+            policy(3, Duration::from_secs(600)),
+            Duration::from_secs(1),
+            |_| false,
+        );
+
+        assert_eq!(health.snapshot()["mock"], SubsystemState::Running);
+        handle.await.unwrap();
+        assert_eq!(subsystem.starts(), 1);
+        assert_eq!(health.snapshot()["mock"], SubsystemState::Stopped);
+        assert!(health.failed().is_empty());
+    }
+
+    // The window goes by the wall clock, so this one runs in real time.
+    #[tokio::test]
+    async fn panics_outside_the_window_are_forgotten() {
+        let health = Health::new();
+        // Past one restart within 100ms it'd be failed by the third start,
+        // but every start runs for longer than that.
+        let (subsystem, handle) = Subsystem::spawn(
+            &health,
+            policy(1, Duration::from_millis(100)),
+            Duration::from_millis(120),
+            |start| start < 4,
+        );
+
+        handle.await.unwrap();
+        assert_eq!(subsystem.starts(), 4);
+        assert_eq!(health.snapshot()["mock"], SubsystemState::Stopped);
+    }
+
+    #[test]
+    fn panic_messages() {
+        let literal: Box<dyn Any + Send> = Box::new("literal");
+        let formatted: Box<dyn Any + Send> = Box::new(format!("formatted {}", 1));
+        let other: Box<dyn Any + Send> = Box::new(42);
+        assert_eq!(panic_message(literal.as_ref()), "literal");
+        assert_eq!(panic_message(formatted.as_ref()), "formatted 1");
+        assert_eq!(panic_message(other.as_ref()), "no message");
+    }
+}
@@ -0,0 +1,66 @@
+// A scratch directory for a test, made fresh under the system temp dir and
+// removed with everything in it on drop, pass or panic. The library's and
+// the binary's tests have it as `crate::test_dir`, the integration tests
+// include this same file with `#[path]`, and some of them only need part of
+// it.
+#![allow(dead_code)]
+
+use std::{
+    fs, io,
+    ops::Deref,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+// Dirs this process has made, so tests running at once never share one.
+static MADE: AtomicUsize = AtomicUsize::new(0);
+
+pub struct TestDir(PathBuf);
+
+impl TestDir {
+    // The name is the test's `name`, the process ID and a count. The dir
+    // is created with `create_dir`, never reused: if it's already there
+    // (another run of the same binary that ended up with this pid and
+    // crashed before cleaning up) the next count is tried instead.
+    pub fn new(name: &str) -> Self {
+        loop {
+            let path = std::env::temp_dir().join(format!(
+                "hddmond-{}-{}-{}",
+                name,
+                process::id(),
+                MADE.fetch_add(1, Ordering::Relaxed)
+            ));
+            match fs::create_dir(&path) {
+                Ok(()) => return Self(path),
+                Err(error) if error.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(error) => panic!("making {}: {}", path.display(), error),
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+// So it goes wherever a path does.
+impl Deref for TestDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TestDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
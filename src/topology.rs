@@ -0,0 +1,561 @@
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+
+use crate::error::TopologyError;
+
+/// Where a device is plugged in, worked out from its place in sysfs. Every
+/// part is optional, a SATA disk has no USB port and a virtio disk has
+/// hardly anything at all.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct DeviceLocation {
+    /// Kernel name, e.g. `sda`.
+    pub device: String,
+    /// The PCI controller it's behind.
+    pub controller: Option<Controller>,
+    /// Its address on the SCSI bus, for anything the SCSI layer drives.
+    pub scsi: Option<ScsiAddress>,
+    /// The NVMe controller, e.g. `nvme0`.
+    pub nvme: Option<String>,
+    /// The SAS expander port it's on.
+    pub sas: Option<SasPort>,
+    /// The USB port it's plugged into.
+    pub usb: Option<UsbPort>,
+    /// The enclosure bay it's in.
+    pub enclosure: Option<EnclosureSlot>,
+}
+
+/// The PCI device closest to the disk, the HBA or the USB or NVMe
+/// controller.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct Controller {
+    /// e.g. `0000:00:1f.2`.
+    pub pci_address: String,
+    /// The kernel driver bound to it, like `ahci` or `mpt3sas`.
+    pub driver: Option<String>,
+}
+
+/// host:channel:target:lun, as in `lsscsi`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ScsiAddress {
+    /// The SCSI host, the HBA or the SATA port.
+    pub host: u32,
+    /// The channel on the host, 0 for nearly everything.
+    pub channel: u32,
+    /// The target on the channel.
+    pub target: u32,
+    /// The logical unit on the target.
+    pub lun: u32,
+}
+
+/// The expander a SAS disk hangs off of, and the phy(s) of the port it's on.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SasPort {
+    /// e.g. `expander-0:0`.
+    pub expander: String,
+    /// e.g. `phy-0:0:5`.
+    pub phys: Vec<String>,
+}
+
+/// The chain of hub ports from the root hub down, which is what the bus
+/// numbers in `lsusb -t` follow.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UsbPort {
+    /// The USB bus, which is one root hub.
+    pub bus: u32,
+    /// The port on each hub, from the root hub down.
+    pub ports: Vec<u32>,
+}
+
+// `2-1.4`, the way the kernel names the port.
+impl fmt::Display for UsbPort {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            self.bus,
+            self.ports
+                .iter()
+                .map(|port| port.to_string())
+                .collect::<Vec<_>>()
+                .join(".")
+        )
+    }
+}
+
+/// The bay in an enclosure the disk sits in, if the enclosure tells.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct EnclosureSlot {
+    /// The enclosure's SCSI address, e.g. `0:0:8:0`.
+    pub enclosure: String,
+    /// The slot's name, as the enclosure gives it.
+    pub slot: String,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+impl DeviceLocation {
+    /// Looks where the device is in /sys. None for a device that isn't
+    /// there, or is a virtual one like a loop device. NVMe namespaces behind
+    /// native multipath are virtual too, they belong to no one controller.
+    pub fn lookup(name: &str) -> Option<Self> {
+        Self::from_sysfs(Path::new("/sys"), name)
+    }
+
+    /// Same as lookup, but with sysfs mounted at `sys`.
+    pub fn from_sysfs(sys: &Path, name: &str) -> Option<Self> {
+        let path = sys.join("class/block").join(name).canonicalize().ok()?;
+        if path
+            .components()
+            .any(|component| component.as_os_str() == "virtual")
+        {
+            return None;
+        }
+
+        let mut location = Self {
+            device: name.to_string(),
+            ..Default::default()
+        };
+
+        // Walks the device's path from the root down, so anything that can
+        // show up more than once, like PCI bridges or USB hubs, ends on the
+        // one closest to the disk.
+        let mut dir = PathBuf::new();
+        let mut parent = String::new();
+        for component in path.components() {
+            dir.push(component);
+            let component = component.as_os_str().to_string_lossy();
+
+            if is_pci_address(&component) {
+                location.controller = Some(Controller {
+                    pci_address: component.to_string(),
+                    driver: driver(&dir),
+                });
+            } else if let Some(address) = scsi_address(&component) {
+                location.scsi = Some(address);
+                location.enclosure = enclosure_slot(&dir);
+            } else if parent == "nvme" && is_numbered(&component, "nvme") {
+                location.nvme = Some(component.to_string());
+            } else if component.starts_with("expander-") {
+                location.sas = Some(SasPort {
+                    expander: component.to_string(),
+                    phys: vec![],
+                });
+            } else if component.starts_with("port-") {
+                // Ports above the expander are the HBA's own.
+                if let Some(sas) = &mut location.sas {
+                    sas.phys = phys(&dir);
+                }
+            } else if let Some(usb) = usb_port(&component) {
+                location.usb = Some(usb);
+            }
+
+            parent = component.into_owned();
+        }
+
+        Some(location)
+    }
+
+    /// One line for humans, like `0:0:3:0, enclosure 0:0:8:0 slot 3`.
+    pub fn describe(&self) -> String {
+        let mut parts = vec![];
+        if let Some(usb) = &self.usb {
+            parts.push(format!("usb {}", usb));
+        } else if let Some(scsi) = &self.scsi {
+            parts.push(format!(
+                "{}:{}:{}:{}",
+                scsi.host, scsi.channel, scsi.target, scsi.lun
+            ));
+        }
+        if let Some(nvme) = &self.nvme {
+            parts.push(nvme.clone());
+        }
+        if let Some(sas) = &self.sas {
+            match sas.phys.as_slice() {
+                [] => parts.push(sas.expander.clone()),
+                phys => parts.push(format!("{} {}", sas.expander, phys.join(","))),
+            }
+        }
+        if let Some(enclosure) = &self.enclosure {
+            parts.push(format!(
+                "enclosure {} slot {}",
+                enclosure.enclosure, enclosure.slot
+            ));
+        }
+
+        if parts.is_empty() {
+            "-".to_string()
+        } else {
+            parts.join(", ")
+        }
+    }
+}
+
+impl EnclosureSlot {
+    /// Turns the slot's locate LED on or off. Needs root, like everything
+    /// else in sysfs worth writing to.
+    pub fn set_locate(&self, on: bool) -> Result<(), TopologyError> {
+        fs::write(self.path.join("locate"), if on { "1" } else { "0" }).map_err(|error| {
+            TopologyError::Locate {
+                enclosure: self.enclosure.clone(),
+                slot: self.slot.clone(),
+                on,
+                error,
+            }
+        })
+    }
+}
+
+/// Every disk in sysfs that isn't a partition or a virtual device.
+pub fn disks(sys: &Path) -> Vec<DeviceLocation> {
+    let entries = match fs::read_dir(sys.join("class/block")) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+
+    let mut disks = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| !entry.path().join("partition").exists())
+        .filter_map(|entry| DeviceLocation::from_sysfs(sys, &entry.file_name().to_string_lossy()))
+        .collect::<Vec<_>>();
+    disks.sort_by(|a, b| a.device.cmp(&b.device));
+    disks
+}
+
+// `0000:00:1f.2`, domain:bus:slot.function in hex.
+fn is_pci_address(name: &str) -> bool {
+    let fields = name.split([':', '.']).collect::<Vec<_>>();
+    fields.len() == 4
+        && fields
+            .iter()
+            .zip([4, 2, 2, 1])
+            .all(|(field, len)| field.len() == len && field.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+// `2:0:1:0`, host:channel:target:lun.
+fn scsi_address(name: &str) -> Option<ScsiAddress> {
+    let fields = name
+        .split(':')
+        .map(|field| field.parse().ok())
+        .collect::<Option<Vec<u32>>>()?;
+    match fields.as_slice() {
+        [host, channel, target, lun] => Some(ScsiAddress {
+            host: *host,
+            channel: *channel,
+            target: *target,
+            lun: *lun,
+        }),
+        _ => None,
+    }
+}
+
+// `nvme0` for prefix `nvme`.
+fn is_numbered(name: &str, prefix: &str) -> bool {
+    name.strip_prefix(prefix)
+        .is_some_and(|number| !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()))
+}
+
+// `1-2.3`, bus 1, port 3 of the hub on port 2. Interfaces like `1-2.3:1.0`
+// don't match, their ports are already in the hub's name.
+fn usb_port(name: &str) -> Option<UsbPort> {
+    let (bus, ports) = name.split_once('-')?;
+    Some(UsbPort {
+        bus: bus.parse().ok()?,
+        ports: ports
+            .split('.')
+            .map(|port| port.parse().ok())
+            .collect::<Option<Vec<_>>>()?,
+    })
+}
+
+fn driver(dir: &Path) -> Option<String> {
+    let driver = fs::read_link(dir.join("driver")).ok()?;
+    Some(driver.file_name()?.to_string_lossy().into_owned())
+}
+
+// The SCSI device of a disk in an enclosure links to its slot as
+// `enclosure_device:<slot>`.
+fn enclosure_slot(dir: &Path) -> Option<EnclosureSlot> {
+    let link = fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .find(|entry| {
+            entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with("enclosure_device:")
+        })?;
+    let path = link.path().canonicalize().ok()?;
+
+    Some(EnclosureSlot {
+        enclosure: path.parent()?.file_name()?.to_string_lossy().into_owned(),
+        slot: path.file_name()?.to_string_lossy().into_owned(),
+        path,
+    })
+}
+
+fn phys(dir: &Path) -> Vec<String> {
+    let mut phys = fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                .filter(|name| name.starts_with("phy-"))
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    phys.sort();
+    phys
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    // The parts of a sysfs tree that locating a disk looks at, laid out the
+    // way the kernel lays them out for each kind of controller.
+    struct FakeSys(TestDir);
+
+    impl FakeSys {
+        fn new(name: &str) -> Self {
+            let root = TestDir::new(&format!("topology-{}", name));
+            fs::create_dir_all(root.join("class/block")).unwrap();
+            Self(root)
+        }
+
+        fn devices(&self, path: &str) -> PathBuf {
+            self.0.join("devices").join(path)
+        }
+
+        // A disk, or partition, at devices/`path`, listed in class/block.
+        fn block(&self, path: &str) -> PathBuf {
+            let dir = self.devices(path);
+            fs::create_dir_all(&dir).unwrap();
+            symlink(
+                &dir,
+                self.0.join("class/block").join(dir.file_name().unwrap()),
+            )
+            .unwrap();
+            dir
+        }
+
+        fn driver(&self, path: &str, driver: &str) {
+            let target = self.0.join("bus/pci/drivers").join(driver);
+            fs::create_dir_all(&target).unwrap();
+            symlink(target, self.dir(path).join("driver")).unwrap();
+        }
+
+        fn dir(&self, path: &str) -> PathBuf {
+            let dir = self.devices(path);
+            fs::create_dir_all(&dir).unwrap();
+            dir
+        }
+
+        fn location(&self, name: &str) -> Option<DeviceLocation> {
+            DeviceLocation::from_sysfs(&self.0, name)
+        }
+    }
+
+    fn controller(pci_address: &str, driver: &str) -> Option<Controller> {
+        Some(Controller {
+            pci_address: pci_address.to_string(),
+            driver: Some(driver.to_string()),
+        })
+    }
+
+    fn scsi(host: u32, channel: u32, target: u32, lun: u32) -> Option<ScsiAddress> {
+        Some(ScsiAddress {
+            host,
+            channel,
+            target,
+            lun,
+        })
+    }
+
+    #[test]
+    fn sata_disk_on_ahci() {
+        let sys = FakeSys::new("sata");
+        sys.block("pci0000:00/0000:00:17.0/ata1/host0/target0:0:0/0:0:0:0/block/sda");
+        sys.driver("pci0000:00/0000:00:17.0", "ahci");
+
+        let location = sys.location("sda").unwrap();
+        assert_eq!(
+            location,
+            DeviceLocation {
+                device: "sda".to_string(),
+                controller: controller("0000:00:17.0", "ahci"),
+                scsi: scsi(0, 0, 0, 0),
+                ..Default::default()
+            }
+        );
+        assert_eq!(location.describe(), "0:0:0:0");
+    }
+
+    #[test]
+    fn sas_disk_behind_an_expander_in_an_enclosure() {
+        let sys = FakeSys::new("sas");
+        let hba = "pci0000:00/0000:00:01.0/0000:01:00.0";
+        let expander = format!("{}/host0/port-0:0/expander-0:0", hba);
+        sys.driver("pci0000:00/0000:00:01.0", "pcieport");
+        sys.driver(hba, "mpt3sas");
+        // The HBA's wide port to the expander, whose phys aren't the disk's.
+        for phy in 0..4 {
+            sys.dir(&format!("{}/host0/port-0:0/phy-0:{}", hba, phy));
+        }
+        let slot = sys.dir(&format!(
+            "{}/port-0:0:8/end_device-0:0:8/target0:0:8/0:0:8:0/enclosure/0:0:8:0/Slot05",
+            expander
+        ));
+        sys.dir(&format!("{}/port-0:0:5/phy-0:0:5", expander));
+        let disk = sys.block(&format!(
+            "{}/port-0:0:5/end_device-0:0:5/target0:0:5/0:0:5:0/block/sdf",
+            expander
+        ));
+        symlink(
+            &slot,
+            disk.parent()
+                .unwrap()
+                .parent()
+                .unwrap()
+                .join("enclosure_device:Slot05"),
+        )
+        .unwrap();
+
+        let location = sys.location("sdf").unwrap();
+        assert_eq!(location.controller, controller("0000:01:00.0", "mpt3sas"));
+        assert_eq!(location.scsi, scsi(0, 0, 5, 0));
+        assert_eq!(
+            location.sas,
+            Some(SasPort {
+                expander: "expander-0:0".to_string(),
+                phys: vec!["phy-0:0:5".to_string()],
+            })
+        );
+        let enclosure = location.enclosure.as_ref().unwrap();
+        assert_eq!(
+            (enclosure.enclosure.as_str(), enclosure.slot.as_str()),
+            ("0:0:8:0", "Slot05")
+        );
+        assert_eq!(
+            location.describe(),
+            "0:0:5:0, expander-0:0 phy-0:0:5, enclosure 0:0:8:0 slot Slot05"
+        );
+
+        enclosure.set_locate(true).unwrap();
+        assert_eq!(fs::read_to_string(slot.join("locate")).unwrap(), "1");
+        enclosure.set_locate(false).unwrap();
+        assert_eq!(fs::read_to_string(slot.join("locate")).unwrap(), "0");
+    }
+
+    #[test]
+    fn nvme_namespace() {
+        let sys = FakeSys::new("nvme");
+        sys.block("pci0000:00/0000:00:1d.0/0000:3d:00.0/nvme/nvme0/nvme0n1");
+        sys.driver("pci0000:00/0000:00:1d.0", "pcieport");
+        sys.driver("pci0000:00/0000:00:1d.0/0000:3d:00.0", "nvme");
+        // With native multipath the namespace belongs to the subsystem.
+        sys.block("virtual/nvme-subsystem/nvme-subsys1/nvme1n1");
+
+        let location = sys.location("nvme0n1").unwrap();
+        assert_eq!(
+            location,
+            DeviceLocation {
+                device: "nvme0n1".to_string(),
+                controller: controller("0000:3d:00.0", "nvme"),
+                nvme: Some("nvme0".to_string()),
+                ..Default::default()
+            }
+        );
+        assert_eq!(location.describe(), "nvme0");
+        assert_eq!(sys.location("nvme1n1"), None);
+    }
+
+    #[test]
+    fn usb_disk_behind_a_hub() {
+        let sys = FakeSys::new("usb");
+        sys.block(
+            "pci0000:00/0000:00:14.0/usb2/2-1/2-1.4/2-1.4:1.0/host6/target6:0:0/6:0:0:0/block/sdg",
+        );
+        sys.driver("pci0000:00/0000:00:14.0", "xhci_hcd");
+
+        let location = sys.location("sdg").unwrap();
+        assert_eq!(
+            location,
+            DeviceLocation {
+                device: "sdg".to_string(),
+                controller: controller("0000:00:14.0", "xhci_hcd"),
+                scsi: scsi(6, 0, 0, 0),
+                usb: Some(UsbPort {
+                    bus: 2,
+                    ports: vec![1, 4],
+                }),
+                ..Default::default()
+            }
+        );
+        assert_eq!(location.describe(), "usb 2-1.4");
+    }
+
+    #[test]
+    fn disks_skip_partitions_and_virtual_devices() {
+        let sys = FakeSys::new("disks");
+        let sda = sys.block("pci0000:00/0000:00:17.0/ata1/host0/target0:0:0/0:0:0:0/block/sda");
+        let sda1 =
+            sys.block("pci0000:00/0000:00:17.0/ata1/host0/target0:0:0/0:0:0:0/block/sda/sda1");
+        fs::write(sda1.join("partition"), "1\n").unwrap();
+        sys.block("virtual/block/loop0");
+        sys.block("pci0000:00/0000:00:1d.0/0000:3d:00.0/nvme/nvme0/nvme0n1");
+        let vda = sys.block("pci0000:00/0000:00:04.0/virtio1/block/vda");
+        assert!(sda.exists() && vda.exists());
+
+        let disks = disks(&sys.0);
+        let names = disks
+            .iter()
+            .map(|disk| disk.device.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["nvme0n1", "sda", "vda"]);
+        // Hardly anything to say about a virtio disk.
+        assert_eq!(disks[2].describe(), "-");
+        assert_eq!(sys.location("missing"), None);
+    }
+
+    #[test]
+    fn names() {
+        for (name, pci) in [
+            ("0000:00:1f.2", true),
+            ("0000:3d:00.0", true),
+            ("pci0000:00", false),
+            ("0000:00:1f", false),
+            ("0000:00:1g.2", false),
+            ("00000:00:1f.2", false),
+        ] {
+            assert_eq!(is_pci_address(name), pci, "{}", name);
+        }
+
+        assert_eq!(scsi_address("2:0:1:0"), scsi(2, 0, 1, 0));
+        for name in ["target2:0:1", "2:0:1", "2:0:1:0:0", "a:0:1:0", "host2"] {
+            assert_eq!(scsi_address(name), None, "{}", name);
+        }
+
+        assert_eq!(
+            usb_port("1-2.3"),
+            Some(UsbPort {
+                bus: 1,
+                ports: vec![2, 3],
+            })
+        );
+        for name in ["usb1", "1-2.3:1.0", "1-", "x-1", "end_device-0:0:5"] {
+            assert_eq!(usb_port(name), None, "{}", name);
+        }
+
+        assert!(is_numbered("nvme0", "nvme"));
+        assert!(is_numbered("nvme12", "nvme"));
+        assert!(!is_numbered("nvme0n1", "nvme"));
+        assert!(!is_numbered("nvme", "nvme"));
+    }
+}
@@ -0,0 +1,204 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    fs,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Where the daemon's own time goes, counted by wrapping the few places that
+/// do anything expensive rather than by profiling. Bumping a counter is a
+/// couple of relaxed atomic adds, see benches/usage.rs.
+pub struct Counter {
+    calls: AtomicU64,
+    nanos: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl Counter {
+    /// Nothing counted yet.
+    pub const fn new() -> Self {
+        Self {
+            calls: AtomicU64::new(0),
+            nanos: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+        }
+    }
+
+    /// Runs `f`, counting the call and how long it took.
+    pub fn time<T>(&self, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.record(start.elapsed());
+        result
+    }
+
+    /// Counts a call that took `elapsed`.
+    pub fn record(&self, elapsed: Duration) {
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        self.nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Counts `bytes` more read or written.
+    pub fn add_bytes(&self, bytes: u64) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// What was counted so far.
+    pub fn stats(&self) -> CounterStats {
+        CounterStats {
+            calls: self.calls.load(Ordering::Relaxed),
+            seconds: Duration::from_nanos(self.nanos.load(Ordering::Relaxed)).as_secs_f64(),
+            bytes: self.bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Default for Counter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Waiting for smartctl to exit, for the scans and everything asked about a
+/// drive. The smartctl backend's scans parse inside the wrapper crate and
+/// count here whole.
+pub static SMARTCTL_WAIT: Counter = Counter::new();
+/// Making sense of what smartctl printed.
+pub static SMARTCTL_PARSE: Counter = Counter::new();
+/// Waiting for hdparm to exit.
+pub static HDPARM_WAIT: Counter = Counter::new();
+/// Reading partition tables and filesystem signatures, with the bytes read.
+pub static PROBE_READ: Counter = Counter::new();
+/// Plugin hooks, from the call to the promise settling, ops included.
+pub static PLUGIN_HOOKS: Counter = Counter::new();
+
+const COUNTERS: &[(&str, &Counter)] = &[
+    ("smartctl_wait", &SMARTCTL_WAIT),
+    ("smartctl_parse", &SMARTCTL_PARSE),
+    ("hdparm_wait", &HDPARM_WAIT),
+    ("probe_read", &PROBE_READ),
+    ("plugin_hooks", &PLUGIN_HOOKS),
+];
+
+/// A counter's totals since the daemon started.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct CounterStats {
+    /// How many times it was counted.
+    pub calls: u64,
+    /// How long those took, in all.
+    pub seconds: f64,
+    /// Bytes read or written, for the counters that count them.
+    pub bytes: u64,
+}
+
+/// For `hddmond status`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Usage {
+    /// CPU time of the whole process, every thread, in user mode.
+    pub cpu_user_seconds: f64,
+    /// And in the kernel.
+    pub cpu_system_seconds: f64,
+    /// Each of the counters above, by name.
+    pub counters: BTreeMap<String, CounterStats>,
+}
+
+/// The process's CPU time and every counter so far.
+pub fn usage() -> Usage {
+    let (cpu_user_seconds, cpu_system_seconds) = cpu_seconds().unwrap_or_default();
+    Usage {
+        cpu_user_seconds,
+        cpu_system_seconds,
+        counters: COUNTERS
+            .iter()
+            .map(|(name, counter)| (name.to_string(), counter.stats()))
+            .collect(),
+    }
+}
+
+// utime and stime from /proc/self/stat, in USER_HZ ticks, which are 100 a
+// second on every architecture Linux runs on.
+fn cpu_seconds() -> Option<(f64, f64)> {
+    let stat = fs::read_to_string("/proc/self/stat").ok()?;
+    // The command name can have spaces and parentheses in it, everything
+    // after its closing parenthesis is fields from state on.
+    let fields = stat
+        .rsplit_once(')')?
+        .1
+        .split_whitespace()
+        .collect::<Vec<_>>();
+    let ticks = |index: usize| fields.get(index)?.parse::<u64>().ok();
+    Some((ticks(11)? as f64 / 100.0, ticks(12)? as f64 / 100.0))
+}
+
+/// The Prometheus text format, for node_exporter's textfile collector.
+pub fn prometheus(usage: &Usage, uptime_secs: u64) -> String {
+    let mut text = String::new();
+    // Writing to a String can't fail.
+    let _ = writeln!(
+        text,
+        "# HELP hddmond_uptime_seconds How long the daemon has been running.\n\
+         # TYPE hddmond_uptime_seconds gauge\n\
+         hddmond_uptime_seconds {}",
+        uptime_secs
+    );
+    let _ = writeln!(
+        text,
+        "# HELP hddmond_cpu_seconds_total CPU time the daemon used.\n\
+         # TYPE hddmond_cpu_seconds_total counter\n\
+         hddmond_cpu_seconds_total{{mode=\"user\"}} {}\n\
+         hddmond_cpu_seconds_total{{mode=\"system\"}} {}",
+        usage.cpu_user_seconds, usage.cpu_system_seconds
+    );
+
+    family(
+        &mut text,
+        usage,
+        "calls_total",
+        "Calls into each instrumented path.",
+        |stats| stats.calls.to_string(),
+    );
+    family(
+        &mut text,
+        usage,
+        "seconds_total",
+        "Time spent in each instrumented path.",
+        |stats| stats.seconds.to_string(),
+    );
+    family(
+        &mut text,
+        usage,
+        "bytes_total",
+        "Bytes read by each instrumented path.",
+        |stats| stats.bytes.to_string(),
+    );
+
+    text
+}
+
+// One metric, with a line for every counter.
+fn family(
+    text: &mut String,
+    usage: &Usage,
+    suffix: &str,
+    help: &str,
+    value: impl Fn(&CounterStats) -> String,
+) {
+    let _ = writeln!(
+        text,
+        "# HELP hddmond_subsystem_{0} {1}\n# TYPE hddmond_subsystem_{0} counter",
+        suffix, help
+    );
+    for (name, stats) in &usage.counters {
+        let _ = writeln!(
+            text,
+            "hddmond_subsystem_{}{{path=\"{}\"}} {}",
+            suffix,
+            name,
+            value(stats)
+        );
+    }
+}
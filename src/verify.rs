@@ -0,0 +1,301 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+
+use crate::block_io::{AlignedBuf, BlockIo, CacheBypass};
+
+// How much is read at a time, and what sampling picks from.
+const CHUNK_BYTES: usize = 1 << 20;
+
+/// Whether the device read back as all zeroes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum Verdict {
+    /// Every byte that was read is zero.
+    Blank,
+    /// Something was left on it.
+    NotBlank {
+        /// The first byte that isn't zero.
+        offset: u64,
+    },
+    /// A sector that couldn't be read counts as not blank, there's no
+    /// telling what's on it.
+    ReadError {
+        /// The sector, in logical sectors.
+        lba: u64,
+        /// What reading it failed with.
+        error: String,
+    },
+}
+
+/// What verifying a device came to.
+#[derive(Debug, Clone, Serialize)]
+pub struct Verification {
+    /// The device that was read.
+    pub device: PathBuf,
+    /// How big it is.
+    pub size_bytes: u64,
+    /// Its logical sector size.
+    pub sector_size: u64,
+    /// Where reading started, 0 unless picking up from an earlier run.
+    pub from_offset: u64,
+    /// How much of the device from there was read, 100 unless sampled.
+    pub sample_percent: f64,
+    /// How much was read in all.
+    pub checked_bytes: u64,
+    /// Whether what was read came off the disk for certain.
+    pub cache_bypass: CacheBypass,
+    /// What was found.
+    #[serde(flatten)]
+    pub verdict: Verdict,
+}
+
+impl Verification {
+    /// Whether the device read back as blank.
+    pub fn passed(&self) -> bool {
+        self.verdict == Verdict::Blank
+    }
+}
+
+/// Reads `path` back and checks that it's all zeroes, bypassing the page
+/// cache so what's checked comes off the disk itself. Never writes.
+///
+/// With `sample_percent` under 100 only that share of the device is read,
+/// in 1 MiB chunks spread evenly over it. `progress` is called with the
+/// offset reading got to after every chunk, which a later run can start
+/// from again. Errors are only for not being able to open the device, read
+/// errors are a verdict.
+pub fn verify_blank(
+    path: &Path,
+    sector_size: u64,
+    from_offset: u64,
+    sample_percent: f64,
+    mut progress: impl FnMut(u64, u64),
+) -> io::Result<Verification> {
+    let io = BlockIo::open(path, sector_size)?;
+    let size = io.size()?;
+    let from_offset = (from_offset - from_offset % sector_size).min(size);
+
+    let mut verification = Verification {
+        device: path.to_path_buf(),
+        size_bytes: size,
+        sector_size,
+        from_offset,
+        sample_percent,
+        checked_bytes: 0,
+        cache_bypass: io.bypass(),
+        verdict: Verdict::Blank,
+    };
+
+    let chunks = (size - from_offset).div_ceil(CHUNK_BYTES as u64);
+    let sampled = ((chunks as f64 * sample_percent / 100.0).ceil() as u64).clamp(1, chunks.max(1));
+
+    let mut buf = AlignedBuf::new(CHUNK_BYTES);
+    for i in 0..sampled.min(chunks) {
+        let offset = from_offset + i * chunks / sampled * CHUNK_BYTES as u64;
+        let len = (size - offset).min(CHUNK_BYTES as u64) as usize;
+
+        let verdict = match io.read_exact_at(&mut buf, offset, len) {
+            Ok(()) => first_nonzero(buf.get(len), offset),
+            // Find out which sector it was.
+            Err(_) => check_sectors(&io, &mut buf, offset, len, sector_size),
+        };
+        verification.checked_bytes += len as u64;
+        if let Some(verdict) = verdict {
+            verification.verdict = verdict;
+            break;
+        }
+
+        progress(offset + len as u64, size);
+    }
+
+    Ok(verification)
+}
+
+fn check_sectors(
+    io: &BlockIo,
+    buf: &mut AlignedBuf,
+    offset: u64,
+    len: usize,
+    sector_size: u64,
+) -> Option<Verdict> {
+    let mut sector = offset;
+    while sector < offset + len as u64 {
+        let want = (offset + len as u64 - sector).min(sector_size) as usize;
+        if let Err(e) = io.read_exact_at(buf, sector, want) {
+            return Some(Verdict::ReadError {
+                lba: sector / sector_size,
+                error: e.to_string(),
+            });
+        }
+        if let Some(verdict) = first_nonzero(buf.get(want), sector) {
+            return Some(verdict);
+        }
+        sector += sector_size;
+    }
+    None
+}
+
+fn first_nonzero(data: &[u8], offset: u64) -> Option<Verdict> {
+    let position = data.iter().position(|&byte| byte != 0)?;
+    Some(Verdict::NotBlank {
+        offset: offset + position as u64,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, os::unix::fs::FileExt};
+
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    const MIB: u64 = CHUNK_BYTES as u64;
+
+    // A sparse file standing in for a wiped drive, with `written` bytes
+    // wherever the wipe missed.
+    struct Image {
+        path: PathBuf,
+        _dir: TestDir,
+    }
+
+    impl Image {
+        fn new(name: &str, size: u64, written: &[(u64, &[u8])]) -> Self {
+            let dir = TestDir::new(&format!("verify-{}", name));
+            let path = dir.join("wiped.img");
+            let file = File::create(&path).unwrap();
+            file.set_len(size).unwrap();
+            for (offset, bytes) in written {
+                file.write_all_at(bytes, *offset).unwrap();
+            }
+            Self { path, _dir: dir }
+        }
+
+        fn verify(&self, from_offset: u64, sample_percent: f64) -> (Verification, Vec<u64>) {
+            let mut reached = vec![];
+            let verification =
+                verify_blank(&self.path, 512, from_offset, sample_percent, |at, _| {
+                    reached.push(at)
+                })
+                .unwrap();
+            (verification, reached)
+        }
+    }
+
+    #[test]
+    fn a_blank_image_passes() {
+        // Not a whole number of chunks, the last one comes up short.
+        let size = 3 * MIB + 512 * 7;
+        let image = Image::new("blank", size, &[]);
+        let (verification, reached) = image.verify(0, 100.0);
+
+        assert!(verification.passed());
+        assert_eq!(verification.size_bytes, size);
+        assert_eq!(verification.checked_bytes, size);
+        assert_eq!(reached, [MIB, 2 * MIB, 3 * MIB, size]);
+    }
+
+    #[test]
+    fn the_first_byte_that_isnt_zero_is_reported() {
+        let size = 4 * MIB;
+        for (written, offset, checked) in [
+            (vec![(0, &b"\x01"[..])], 0, MIB),
+            (
+                vec![(MIB + 12345, &b"\x00\x00\xff"[..])],
+                MIB + 12347,
+                2 * MIB,
+            ),
+            // Only the first one counts.
+            (
+                vec![(3 * MIB, &b"x"[..]), (2 * MIB + 1, &b"y"[..])],
+                2 * MIB + 1,
+                3 * MIB,
+            ),
+            (vec![(size - 1, &b"\x80"[..])], size - 1, size),
+        ] {
+            let image = Image::new("not-blank", size, &written);
+            let (verification, reached) = image.verify(0, 100.0);
+
+            assert!(!verification.passed());
+            assert_eq!(
+                verification.verdict,
+                Verdict::NotBlank { offset },
+                "{:?}",
+                written
+            );
+            assert_eq!(verification.checked_bytes, checked);
+            // Progress is only what was found blank.
+            assert_eq!(reached.last().copied().unwrap_or(0), checked - MIB);
+        }
+    }
+
+    #[test]
+    fn picking_up_from_an_earlier_run() {
+        let size = 4 * MIB;
+        let image = Image::new("resume", size, &[(MIB - 1, b"!")]);
+
+        let (verification, reached) = image.verify(MIB + 100, 100.0);
+        assert!(verification.passed());
+        // Rounded down to the sector it's in.
+        assert_eq!(verification.from_offset, MIB);
+        assert_eq!(verification.checked_bytes, 3 * MIB);
+        assert_eq!(reached, [2 * MIB, 3 * MIB, 4 * MIB]);
+
+        let (verification, _) = image.verify(MIB - 512, 100.0);
+        assert_eq!(verification.verdict, Verdict::NotBlank { offset: MIB - 1 });
+
+        // Past the end there's nothing left to read.
+        let (verification, reached) = image.verify(size * 2, 100.0);
+        assert!(verification.passed());
+        assert_eq!(verification.from_offset, size);
+        assert_eq!(verification.checked_bytes, 0);
+        assert_eq!(reached, [] as [u64; 0]);
+    }
+
+    #[test]
+    fn sampling_reads_chunks_spread_over_the_device() {
+        let size = 10 * MIB;
+        let blank = Image::new("sampled", size, &[]);
+        let (verification, reached) = blank.verify(0, 20.0);
+        assert!(verification.passed());
+        assert_eq!(verification.checked_bytes, 2 * MIB);
+        assert_eq!(reached, [MIB, 6 * MIB]);
+
+        // Between the samples it's missed, only a full read finds it.
+        let missed = Image::new("sampled-missed", size, &[(3 * MIB, b"?")]);
+        assert!(missed.verify(0, 20.0).0.passed());
+        assert_eq!(
+            missed.verify(0, 100.0).0.verdict,
+            Verdict::NotBlank { offset: 3 * MIB }
+        );
+        let hit = Image::new("sampled-hit", size, &[(5 * MIB + 7, b"?")]);
+        assert_eq!(
+            hit.verify(0, 20.0).0.verdict,
+            Verdict::NotBlank {
+                offset: 5 * MIB + 7
+            }
+        );
+
+        // However little is asked for, something is read.
+        let (verification, _) = blank.verify(0, 0.0);
+        assert_eq!(verification.checked_bytes, MIB);
+        let (verification, _) = blank.verify(0, 250.0);
+        assert_eq!(verification.checked_bytes, size);
+    }
+
+    #[test]
+    fn empty_and_missing_devices() {
+        let empty = Image::new("empty", 0, &[]);
+        let (verification, reached) = empty.verify(0, 100.0);
+        assert!(verification.passed());
+        assert_eq!(verification.checked_bytes, 0);
+        assert_eq!(reached, [] as [u64; 0]);
+
+        let error =
+            verify_blank(Path::new("/nonexistent/sdx"), 512, 0, 100.0, |_, _| {}).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    }
+}
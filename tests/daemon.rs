@@ -0,0 +1,104 @@
+// Starts the hddmond binary while another "daemon" (this test) already
+// holds the pidfile or the instance lock, and checks it refuses to start,
+// saying who's running, before it forks or touches any device.
+
+use std::{
+    fs,
+    path::Path,
+    process::{self, Command, Output},
+};
+
+use hddmond::daemon::InstanceLock;
+
+#[path = "../src/test_dir.rs"]
+mod test_dir;
+
+use test_dir::TestDir;
+
+fn hddmond(config: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_hddmond"))
+        .arg("--config")
+        .arg(config)
+        .args(args)
+        .env_clear()
+        .output()
+        .unwrap()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn live_pidfile_refuses_to_daemonize() {
+    let dir = TestDir::new("daemon-pidfile");
+    let pidfile = dir.join("hddmond.pid");
+    fs::write(&pidfile, format!("{}\n", process::id())).unwrap();
+    let config = dir.join("hddmond.toml");
+    fs::write(
+        &config,
+        format!(
+            "[daemon]\ndaemonize = true\npidfile = {:?}\ncontrol_socket = {:?}\n\
+             [logging.file]\npath = {:?}\n[storage]\npath = \":memory:\"\n",
+            pidfile,
+            dir.join("hddmond.sock"),
+            dir.join("hddmond.log"),
+        ),
+    )
+    .unwrap();
+
+    let output = hddmond(&config, &[]);
+    assert!(!output.status.success());
+    let stderr = stderr(&output);
+    assert!(
+        stderr.contains(&format!(
+            "hddmond is already running as pid {} (see {})",
+            process::id(),
+            pidfile.display()
+        )),
+        "{}",
+        stderr
+    );
+    // Still ours, and nothing got far enough to log.
+    assert_eq!(
+        fs::read_to_string(&pidfile).unwrap(),
+        format!("{}\n", process::id())
+    );
+    assert!(!dir.join("hddmond.log").exists());
+}
+
+#[test]
+fn held_instance_lock_refuses_to_start() {
+    let dir = TestDir::new("daemon-lock");
+    let database = dir.join("hddmond.db");
+    let config = dir.join("hddmond.toml");
+    fs::write(
+        &config,
+        format!(
+            "[daemon]\ncontrol_socket = {:?}\n[storage]\npath = {:?}\n",
+            dir.join("hddmond.sock"),
+            database,
+        ),
+    )
+    .unwrap();
+
+    let lock_path = dir.join("hddmond.db.lock");
+    let lock = InstanceLock::try_lock(&lock_path).unwrap().unwrap();
+
+    let output = hddmond(&config, &[]);
+    assert!(!output.status.success());
+    let stderr = stderr(&output);
+    assert!(
+        stderr.contains(&format!(
+            "hddmond is already running as pid {} (it holds {})",
+            process::id(),
+            lock_path.display()
+        )),
+        "{}",
+        stderr
+    );
+    // It didn't get as far as opening the registry.
+    assert!(!database.exists());
+
+    drop(lock);
+}
@@ -0,0 +1,441 @@
+// Injects failures where the faults feature has seams and checks each one
+// is handled the way it's meant to be: read errors become bad ranges, a
+// smartctl run that hangs and fails only costs its own device, a registry
+// write that fails leaves the rest recorded, a delivery that fails is
+// retried.
+//
+//     cargo test --features faults --test faults
+
+#![cfg(feature = "faults")]
+
+use std::{
+    fs,
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process,
+    sync::{mpsc, Mutex, MutexGuard},
+    thread,
+    time::{Duration, Instant},
+};
+
+use hddmond::{
+    capabilities::{Capability, CapabilityProber},
+    config::{IdentifyConfig, NotifiersConfig},
+    device_policy::DeviceIdentity,
+    faults,
+    identify_queue::IdentifyQueue,
+    image,
+    notifiers::{notification::Notification, notifier_host::NotifierHost},
+    storage::{Registry, IN_MEMORY},
+    supervisor::Health,
+    verify::{self, Verdict},
+};
+use sha2::{Digest, Sha256};
+
+#[path = "../src/test_dir.rs"]
+mod test_dir;
+
+use test_dir::TestDir;
+
+// The faults are global to the process, the tests take turns with them.
+static TURN: Mutex<()> = Mutex::new(());
+
+// Faults set for one test, cleared again when it's done however it went.
+struct Faults(#[allow(dead_code)] MutexGuard<'static, ()>);
+
+impl Faults {
+    fn take() -> Self {
+        let turn = TURN.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        faults::control("clear");
+        Self(turn)
+    }
+
+    fn set(&self, spec: &str) {
+        let answer = faults::control(&format!("set {}", spec));
+        assert!(!answer.contains("\"error\""), "{}", answer);
+    }
+}
+
+impl Drop for Faults {
+    fn drop(&mut self) {
+        faults::control("clear");
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[test]
+fn a_read_error_at_an_offset_is_a_bad_range_in_the_image() {
+    let faults = Faults::take();
+    let dir = TestDir::new("faults-image");
+    let source = dir.join("hddmond-fault-disk");
+    let mut data = (0..3 << 20).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    fs::write(&source, &data).unwrap();
+
+    // A bad sector a way into the second chunk.
+    let bad = (1 << 20) + 8 * 512;
+    faults.set(&format!(
+        r#"{{"point": "block_read", "scope": "hddmond-fault-disk", "offset": {}}}"#,
+        bad + 100
+    ));
+    let (record, out) = image::image(&source, 512, Vec::new(), false, |_, _| {}).unwrap();
+
+    assert_eq!(record.bad_ranges.len(), 1, "{:?}", record.bad_ranges);
+    assert_eq!(record.bad_ranges[0].offset, bad);
+    assert_eq!(record.bad_ranges[0].bytes, 512);
+    assert!(record.bad_ranges[0].error.contains("os error 5"));
+    assert_eq!(record.bad_bytes, 512);
+    // Zeroes in its place, and everything else as it was, hashed as
+    // written.
+    data[bad as usize..bad as usize + 512].fill(0);
+    assert_eq!(out.len(), data.len());
+    assert!(out == data);
+    assert_eq!(record.sha256, hex(&data));
+
+    // The same fault fails verifying it's blank at that sector.
+    let blank = dir.join("hddmond-fault-blank");
+    fs::write(&blank, vec![0; 3 << 20]).unwrap();
+    faults.set(&format!(
+        r#"{{"point": "block_read", "scope": "hddmond-fault-blank", "offset": {}}}"#,
+        bad
+    ));
+    let verification = verify::verify_blank(&blank, 512, 0, 100.0, |_, _| {}).unwrap();
+    match verification.verdict {
+        Verdict::ReadError { lba, .. } => assert_eq!(lba, bad / 512),
+        verdict => panic!("Expected a read error, got {:?}", verdict),
+    }
+    // Other devices read fine.
+    let other = dir.join("hddmond-fault-other");
+    fs::write(&other, vec![0; 1 << 20]).unwrap();
+    assert!(verify::verify_blank(&other, 512, 0, 100.0, |_, _| {})
+        .unwrap()
+        .passed());
+}
+
+#[test]
+#[ignore = "needs a loop device (root)"]
+fn a_loop_device_with_bad_sectors_is_imaged_around_them() {
+    let faults = Faults::take();
+    let dir = TestDir::new("faults-image-loop");
+    let backing = dir.join("disk.img");
+    let data = (0..(2 << 20) + 3 * 4096)
+        .map(|i| (i % 251) as u8)
+        .collect::<Vec<_>>();
+    fs::write(&backing, &data).unwrap();
+    let output = process::Command::new("losetup")
+        .args(["--find", "--show", "--sector-size", "4096"])
+        .arg(&backing)
+        .output()
+        .expect("losetup to run");
+    assert!(
+        output.status.success(),
+        "No loop device: {}",
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    let node = PathBuf::from(String::from_utf8_lossy(&output.stdout).trim());
+    let name = node.file_name().unwrap().to_string_lossy().into_owned();
+
+    // Two sectors next to each other in the first chunk, and the last one.
+    for offset in [4096 * 5, 4096 * 6, data.len() - 4096] {
+        faults.set(&format!(
+            r#"{{"point": "block_read", "scope": {:?}, "offset": {}}}"#,
+            name, offset
+        ));
+    }
+    let imaged = image::image(&node, 4096, Vec::new(), true, |_, _| {});
+    let _ = process::Command::new("losetup")
+        .arg("-d")
+        .arg(&node)
+        .status();
+    let (record, gzipped) = imaged.unwrap();
+
+    let bad = record
+        .bad_ranges
+        .iter()
+        .map(|range| (range.offset, range.bytes))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        bad,
+        [(4096 * 5, 8192), (data.len() as u64 - 4096, 4096)],
+        "{:?}",
+        record.bad_ranges
+    );
+    assert_eq!(record.bad_bytes, 3 * 4096);
+
+    let mut expected = data.clone();
+    for (offset, bytes) in bad {
+        expected[offset as usize..(offset + bytes) as usize].fill(0);
+    }
+    let mut raw = vec![];
+    flate2::read::MultiGzDecoder::new(&gzipped[..])
+        .read_to_end(&mut raw)
+        .unwrap();
+    assert!(raw == expected);
+    assert_eq!(record.sha256, hex(&expected));
+    assert!(record.compressed);
+}
+
+// Trimmed from what smartctl 7.3 prints with `-i -c -H -j`.
+const SATA: &str = r#"{
+  "device": { "name": "/dev/sda", "type": "sat", "protocol": "ATA" },
+  "ata_security": { "enabled": false, "frozen": false },
+  "smart_status": { "passed": true },
+  "ata_smart_data": { "capabilities": { "self_tests_supported": true } }
+}"#;
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn a_smartctl_run_that_hangs_only_costs_its_own_device() {
+    let faults = Faults::take();
+    let dir = TestDir::new("faults-identify");
+    let json = dir.join("smartctl.json");
+    fs::write(&json, SATA).unwrap();
+    let smartctl = dir.join("smartctl");
+    fs::write(&smartctl, format!("#!/bin/sh\ncat {}\n", json.display())).unwrap();
+    fs::set_permissions(&smartctl, fs::Permissions::from_mode(0o755)).unwrap();
+
+    // Hangs for a while, then fails, like a run that timed out.
+    faults.set(r#"{"point": "smartctl_exec", "scope": "hddmond-fault-b", "delay_ms": 500}"#);
+
+    let mut prober = CapabilityProber::new(
+        Some(&smartctl),
+        IdentifyQueue::new(&IdentifyConfig::default()),
+    );
+    let started = Instant::now();
+    for name in ["hddmond-fault-a", "hddmond-fault-b", "hddmond-fault-c"] {
+        let node = dir.join(name);
+        fs::write(&node, "").unwrap();
+        prober.probe(
+            &DeviceIdentity {
+                name: name.to_string(),
+                paths: vec![node],
+                ..Default::default()
+            },
+            false,
+        );
+    }
+
+    let mut probed = vec![];
+    for _ in 0..3 {
+        let (identity, capabilities) = tokio::time::timeout(Duration::from_secs(10), prober.next())
+            .await
+            .expect("A device was never probed")
+            .unwrap();
+        probed.push((identity.name, capabilities, started.elapsed()));
+    }
+
+    // The other two didn't wait for it.
+    let (hung, rest): (Vec<_>, Vec<_>) = probed
+        .into_iter()
+        .partition(|(name, _, _)| name == "hddmond-fault-b");
+    assert_eq!(rest.len(), 2);
+    for (name, capabilities, elapsed) in &rest {
+        assert_eq!(capabilities.self_test, Capability::Yes, "{}", name);
+        assert!(
+            *elapsed < Duration::from_millis(500),
+            "{} took {:?}",
+            name,
+            elapsed
+        );
+    }
+    // What smartctl would have said is unknown, the rest is as for the
+    // others.
+    let (_, capabilities, elapsed) = &hung[0];
+    assert!(*elapsed >= Duration::from_millis(500));
+    assert_eq!(capabilities.self_test, Capability::Unknown);
+    assert_eq!(capabilities.ata_secure_erase, Capability::Unknown);
+    assert_ne!(rest[0].1.ata_secure_erase, Capability::Unknown);
+    assert_eq!(capabilities.write, rest[0].1.write);
+    assert_eq!(capabilities.read_only, rest[0].1.read_only);
+}
+
+fn drive(name: &str, serial: &str) -> DeviceIdentity {
+    DeviceIdentity {
+        name: name.to_string(),
+        serial: Some(serial.to_string()),
+        model: Some("WDC WD40EFRX-68N32N0".to_string()),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn a_failed_registry_write_leaves_the_rest_recorded() {
+    let faults = Faults::take();
+    let mut registry = Registry::open(Path::new(IN_MEMORY)).unwrap();
+    faults.set(r#"{"point": "sqlite_write", "scope": "sdb", "once": true}"#);
+
+    registry.device_found(&drive("sda", "WD-A")).unwrap();
+    let error = registry.device_found(&drive("sdb", "WD-B")).unwrap_err();
+    assert!(error.to_string().contains("sdb"), "{}", error);
+    registry.device_found(&drive("sdc", "WD-C")).unwrap();
+    assert!(registry.device("WD-B").unwrap().is_none());
+
+    // The next time it's seen it's recorded, and nothing was half done.
+    registry.device_found(&drive("sdb", "WD-B")).unwrap();
+    let mut serials = registry
+        .devices(false)
+        .unwrap()
+        .into_iter()
+        .map(|device| device.serial.unwrap())
+        .collect::<Vec<_>>();
+    serials.sort();
+    assert_eq!(serials, ["WD-A", "WD-B", "WD-C"]);
+    let id = registry.device("WD-B").unwrap().unwrap().id;
+    assert_eq!(registry.events(id, None).unwrap().len(), 1);
+
+    // A device lost while writes fail is still lost once they work.
+    faults.set(r#"{"point": "sqlite_write", "scope": "sda"}"#);
+    assert!(registry.device_lost("sda").is_err());
+    assert!(registry.device("WD-A").unwrap().unwrap().present);
+    faults::control("clear");
+    registry.device_lost("sda").unwrap();
+    assert!(!registry.device("WD-A").unwrap().unwrap().present);
+}
+
+// Answers every POST with a 200 and sends on when it came in.
+fn webhook() -> (String, mpsc::Receiver<Instant>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    let (sender, receiver) = mpsc::channel();
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut length = 0;
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap_or(0) > 0 && line != "\r\n" {
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+                line.clear();
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            let _ = stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n");
+            if sender.send(Instant::now()).is_err() {
+                break;
+            }
+        }
+    });
+
+    (url, receiver)
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn a_failed_delivery_is_retried() {
+    let faults = Faults::take();
+    let (url, posts) = webhook();
+    let config: NotifiersConfig = toml::from_str(&format!(
+        "group_window_secs = 0\ndedup_window_secs = 0\n\
+         [[webhooks]]\nurl = {:?}\nmax_attempts = 3\n",
+        url
+    ))
+    .unwrap();
+    let mut host = NotifierHost::new(&config, &Health::new()).unwrap();
+    let name = host.stats()[0].name.clone();
+    faults.set(&format!(
+        r#"{{"point": "notifier_delivery", "scope": {:?}, "once": true}}"#,
+        name
+    ));
+
+    let sent = Instant::now();
+    host.notify(Notification::device_found(&drive("sda", "WD-A")));
+    let received = posts
+        .recv_timeout(Duration::from_secs(10))
+        .expect("The notification was never delivered");
+    // After the first retry's backoff.
+    assert!(received - sent >= Duration::from_secs(1));
+
+    // Counted once the webhook's answer is back, which is after it's sent on.
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while host.stats()[0].delivered == 0 && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let stats = &host.stats()[0];
+    assert_eq!(stats.delivered, 1);
+    assert_eq!(stats.failed, 0);
+    assert!(
+        stats
+            .last_error
+            .as_deref()
+            .is_some_and(|error| error.contains("Injected")),
+        "{:?}",
+        stats.last_error
+    );
+    host.shutdown().await;
+}
+
+#[test]
+fn faults_are_set_listed_and_cleared_over_the_control_commands() {
+    let _faults = Faults::take();
+    let listed = |answer: String| -> serde_json::Value { serde_json::from_str(&answer).unwrap() };
+
+    let answer = listed(faults::control(
+        r#"set {"point": "block_read", "scope": "sdb", "offset": 4096, "once": true}"#,
+    ));
+    assert_eq!(answer["faults"][0]["point"], "block_read");
+    assert_eq!(answer["faults"][0]["probability"], 1.0);
+    assert_eq!(
+        listed(faults::control("list"))["faults"]
+            .as_array()
+            .unwrap()
+            .len(),
+        1
+    );
+
+    for (command, error) in [
+        (r#"set {"point": "disk_on_fire"}"#, "Not a fault"),
+        (
+            r#"set {"point": "block_read", "probability": 1.5}"#,
+            "probability has to be between 0 and 1",
+        ),
+        ("explode", "Unknown faults command \"explode\""),
+    ] {
+        let answer = listed(faults::control(command));
+        assert!(
+            answer["error"].as_str().unwrap().starts_with(error),
+            "{}: {}",
+            command,
+            answer
+        );
+    }
+
+    // Once fires, then is gone.
+    assert!(faults::check(faults::FaultPoint::BlockRead, Some("sdb"), Some((0, 512))).is_none());
+    assert!(faults::check(
+        faults::FaultPoint::BlockRead,
+        Some("sda"),
+        Some((4096, 512))
+    )
+    .is_none());
+    assert!(faults::check(
+        faults::FaultPoint::BlockRead,
+        Some("sdb"),
+        Some((4096, 512))
+    )
+    .is_some());
+    assert!(faults::check(
+        faults::FaultPoint::BlockRead,
+        Some("sdb"),
+        Some((4096, 512))
+    )
+    .is_none());
+
+    // Never, whatever the roll.
+    faults::control(r#"set {"point": "smartctl_exec", "probability": 0}"#);
+    assert!((0..100).all(|_| faults::check(faults::FaultPoint::SmartctlExec, None, None).is_none()));
+    assert_eq!(
+        listed(faults::control("clear"))["faults"],
+        serde_json::json!([])
+    );
+}
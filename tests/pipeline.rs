@@ -0,0 +1,242 @@
+// Runs the daemon out of the library alone, the way the binary does but
+// with mock monitors in place of udev and smartctl.
+
+use std::{collections::HashMap, fs, path::Path};
+
+use hddmond::{
+    config::{Backend, Config},
+    control,
+    device_policy::DeviceIdentity,
+    error::ScanError,
+    pipeline::Pipeline,
+    scanners::{
+        composite_scanner::CompositeMonitor,
+        mock_scanner::MockDeviceMonitor,
+        scanner::{DeviceMonitor, DeviceStream, ScanEventType},
+    },
+    storage::{Registry, IN_MEMORY},
+};
+use serde_json::Value;
+use tokio_stream::StreamExt;
+
+#[path = "../src/test_dir.rs"]
+mod test_dir;
+
+use test_dir::TestDir;
+
+// A config that keeps everything in `dir`.
+fn config(dir: &TestDir) -> Config {
+    let mut config = Config::default();
+    config.storage.path = dir.join("hddmond.db");
+    config.audit.path = Some(dir.join("audit.jsonl"));
+    config.daemon.pidfile = None;
+    config.daemon.control_socket = Some(dir.join("hddmond.sock"));
+    config.plugin_host.dir = dir.join("plugins");
+    config
+}
+
+// Monitors are made on the thread they're read on, hence `monitor`.
+fn pipeline<M: DeviceMonitor + 'static>(
+    dir: &TestDir,
+    monitor: impl FnOnce() -> M + Send + 'static,
+) -> Pipeline {
+    let config = config(dir);
+    let path = dir.join("hddmond.toml");
+    let reloaded = config.clone();
+    Pipeline::new("test", config, path, move || Ok(reloaded.clone()))
+        .with_monitor(|_| Ok((Backend::Composite, Box::new(monitor()))))
+}
+
+// The events of the audit trail, in order.
+fn audited(dir: &TestDir) -> Vec<String> {
+    fs::read_to_string(dir.join("audit.jsonl"))
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str::<Value>(line).unwrap()["event"].to_string())
+        .map(|event| event.trim_matches('"').to_string())
+        .collect()
+}
+
+// Knows what udev would have said about its devices, and never ends, the
+// way a real monitor doesn't.
+struct Watching {
+    events: Vec<ScanEventType>,
+    identities: Vec<DeviceIdentity>,
+}
+
+impl DeviceMonitor for Watching {
+    fn watch_events(&self) -> Result<DeviceStream, ScanError> {
+        Ok(Box::pin(
+            tokio_stream::iter(self.events.clone()).chain(tokio_stream::pending()),
+        ))
+    }
+
+    fn identities(&self) -> Vec<DeviceIdentity> {
+        self.identities.clone()
+    }
+}
+
+fn found(device: &str) -> ScanEventType {
+    ScanEventType::DeviceFound(device.to_string())
+}
+
+fn lost(device: &str) -> ScanEventType {
+    ScanEventType::DeviceLost(device.to_string())
+}
+
+fn identity(name: &str, serial: &str) -> DeviceIdentity {
+    DeviceIdentity {
+        name: name.to_string(),
+        serial: Some(serial.to_string()),
+        model: Some("WDC WD40EFRX-68N32N0".to_string()),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn mock_monitors_feed_the_registry() {
+    // What udev would have said about each device.
+    let identities = HashMap::from([
+        ("sda", identity("sda", "WD-A")),
+        ("sdb", identity("sdb", "WD-B")),
+        ("sdc", identity("sdc", "WD-C")),
+    ]);
+
+    let mut udev = MockDeviceMonitor::new([found("sda"), found("sdb")]);
+    udev.push(lost("sda"));
+    let smartctl = MockDeviceMonitor::new([found("sdb"), found("sdc"), lost("sdb")]);
+    let monitor = CompositeMonitor::new(vec![Box::new(udev), Box::new(smartctl)]);
+
+    let mut registry = Registry::open(Path::new(IN_MEMORY)).unwrap();
+    let mut stream = monitor.watch_events().unwrap();
+    let mut seen = vec![];
+    while let Some(event) = stream.next().await {
+        match &event {
+            ScanEventType::DeviceFound(name) => {
+                registry.device_found(&identities[name.as_str()]).unwrap();
+            }
+            ScanEventType::DeviceLost(name) => {
+                registry.device_lost(name).unwrap();
+            }
+            _ => {}
+        }
+        seen.push(event);
+    }
+
+    // sdb was seen by both and only lost by smartctl, so it's still there.
+    assert_eq!(
+        seen,
+        [found("sda"), found("sdb"), found("sdc"), lost("sda")]
+    );
+
+    let mut present = registry
+        .devices(false)
+        .unwrap()
+        .into_iter()
+        .map(|device| device.serial.unwrap())
+        .collect::<Vec<_>>();
+    present.sort();
+    assert_eq!(present, ["WD-B", "WD-C"]);
+    assert_eq!(registry.devices(true).unwrap().len(), 3);
+}
+
+#[tokio::test]
+async fn the_daemon_records_what_the_monitor_sees() {
+    let dir = TestDir::new("pipeline-records");
+    let monitor = || {
+        let udev = MockDeviceMonitor::new([found("sda"), found("sdb"), lost("sda")]);
+        let smartctl = MockDeviceMonitor::new([found("sdb")]);
+        CompositeMonitor::new(vec![Box::new(udev), Box::new(smartctl)])
+    };
+
+    // The mock monitors end once they've played out, so does the daemon.
+    pipeline(&dir, monitor).run().await.unwrap();
+
+    let registry = Registry::open(&dir.join("hddmond.db")).unwrap();
+    let present: Vec<_> = registry
+        .devices(false)
+        .unwrap()
+        .into_iter()
+        .map(|device| device.name)
+        .collect();
+    assert_eq!(present, ["sdb"]);
+    assert_eq!(registry.devices(true).unwrap().len(), 2);
+    assert_eq!(
+        audited(&dir),
+        [
+            "daemon_started",
+            "device_found",
+            "device_found",
+            "device_lost"
+        ]
+    );
+}
+
+#[tokio::test]
+async fn the_daemon_answers_over_its_control_socket() {
+    let dir = TestDir::new("pipeline-control");
+    let monitor = || Watching {
+        events: vec![found("sda")],
+        identities: vec![identity("sda", "WD-A")],
+    };
+    let daemon = tokio::spawn(pipeline(&dir, monitor).run());
+
+    let socket = dir.join("hddmond.sock");
+    let ask = |command: String| {
+        let socket = socket.clone();
+        tokio::task::spawn_blocking(move || {
+            // Until the daemon has bound it.
+            for _ in 0..100 {
+                if let Ok(answer) = control::query(&socket, &command) {
+                    return serde_json::from_str::<Value>(&answer).unwrap();
+                }
+                std::thread::sleep(std::time::Duration::from_millis(50));
+            }
+            panic!("No answer to {}", command);
+        })
+    };
+
+    let quarantined = ask(r#"quarantine {"device": "WD-A", "reason": "clicking"}"#.to_string())
+        .await
+        .unwrap();
+    assert_eq!(quarantined["device"]["quarantine"], "clicking");
+    let answer = ask("shutdown".to_string()).await.unwrap();
+    assert_eq!(answer["shutting_down"], true);
+    daemon.await.unwrap().unwrap();
+
+    let registry = Registry::open(&dir.join("hddmond.db")).unwrap();
+    let device = registry.device("WD-A").unwrap().unwrap();
+    assert_eq!(device.quarantine.as_deref(), Some("clicking"));
+    assert_eq!(
+        audited(&dir),
+        [
+            "daemon_started",
+            "device_found",
+            "device_quarantined",
+            "daemon_stopped"
+        ]
+    );
+}
+
+#[test]
+fn a_monitor_that_cant_start_stops_the_composite() {
+    struct Broken;
+
+    impl DeviceMonitor for Broken {
+        fn watch_events(&self) -> Result<DeviceStream, ScanError> {
+            Err(ScanError::SmartctlNotFound {
+                path: None,
+                error: "not in PATH".into(),
+            })
+        }
+    }
+
+    let monitor = CompositeMonitor::new(vec![
+        Box::new(MockDeviceMonitor::new([found("sda")])),
+        Box::new(Broken),
+    ]);
+    assert!(matches!(
+        monitor.watch_events(),
+        Err(ScanError::SmartctlNotFound { .. })
+    ));
+}
@@ -0,0 +1,131 @@
+// Loads examples/plugins/log_devices.js into a real plugin host and checks
+// what its hooks did. The only thing the example does is call hddmond.log,
+// so a logger recording every call of that op is enough to see each hook
+// ran, in order, with the device it was given.
+
+use std::{
+    collections::HashMap,
+    fs,
+    sync::{Mutex, Once},
+};
+
+use hddmond::{
+    plugins::{plugin_host::PluginHost, plugin_limits::PluginLimits},
+    scanners::scanner::ScanEventType,
+};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+#[path = "../src/test_dir.rs"]
+mod test_dir;
+
+use test_dir::TestDir;
+
+// (target, level, message) of everything logged through op_hddmond_log.
+static CALLS: Mutex<Vec<(String, Level, String)>> = Mutex::new(vec![]);
+
+struct Recorder;
+
+impl Log for Recorder {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        if record.target().starts_with("plugin::") {
+            CALLS.lock().unwrap().push((
+                record.target().to_string(),
+                record.level(),
+                record.args().to_string(),
+            ));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+fn record_calls() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        log::set_boxed_logger(Box::new(Recorder)).unwrap();
+        log::set_max_level(LevelFilter::Trace);
+    });
+}
+
+fn plugin_dir(name: &str) -> TestDir {
+    let dir = TestDir::new(name);
+    fs::copy(
+        concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/examples/plugins/log_devices.js"
+        ),
+        dir.join("log_devices.js"),
+    )
+    .unwrap();
+    dir
+}
+
+#[tokio::test]
+async fn example_plugin_hooks_see_every_device_event() {
+    record_calls();
+    let dir = plugin_dir("example-plugin");
+
+    let mut host = PluginHost::load_dir(&dir, PluginLimits::default(), HashMap::new())
+        .await
+        .unwrap();
+    let statuses = host.statuses();
+    assert_eq!(statuses.len(), 1);
+    assert_eq!(statuses[0].name, "log_devices");
+    assert_eq!(statuses[0].state, "running");
+
+    host.dispatch(&ScanEventType::DeviceFound("sda".to_string()));
+    host.dispatch(&ScanEventType::DeviceFound("sdb".to_string()));
+    // No hook for these, they mustn't reach the plugin.
+    host.dispatch(&ScanEventType::DeviceChanged("sdb".to_string()));
+    host.dispatch(&ScanEventType::Unknown("sdc".to_string()));
+    host.dispatch(&ScanEventType::DeviceLost("sda".to_string()));
+
+    // Waits for the plugin to work through everything queued.
+    host.shutdown().await;
+
+    let calls = CALLS.lock().unwrap();
+    let calls = calls
+        .iter()
+        .filter(|(target, _, _)| target == "plugin::log_devices")
+        .map(|(_, level, message)| (*level, message.as_str()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        calls,
+        [
+            (Level::Info, "found sda"),
+            (Level::Info, "found sdb"),
+            // getDevices() already reflects the loss by the time the hook
+            // runs.
+            (Level::Info, "lost sda, 1 left"),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn broken_plugin_doesnt_stop_the_example_from_loading() {
+    record_calls();
+    let dir = plugin_dir("broken-plugin");
+    fs::write(dir.join("broken.js"), "export function onDeviceFound( {").unwrap();
+
+    let host = PluginHost::load_dir(&dir, PluginLimits::default(), HashMap::new())
+        .await
+        .unwrap();
+    let states = host
+        .statuses()
+        .into_iter()
+        .map(|status| (status.name, status.state))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        states,
+        [
+            ("broken".to_string(), "failed"),
+            ("log_devices".to_string(), "running"),
+        ]
+    );
+
+    host.shutdown().await;
+}
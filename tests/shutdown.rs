@@ -0,0 +1,167 @@
+// Runs a stand-in daemon in a child process, which is this test binary run
+// again with only `stand_in_daemon` and HDDMOND_TEST_CHECKPOINT set. Its
+// "long task" takes a while to write a checkpoint file once the shutdown
+// starts, and the tests send it SIGTERM and check what made it to the file
+// by the time it exited.
+
+use std::{
+    env, fs,
+    path::PathBuf,
+    process::{Child, Command, ExitStatus, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use hddmond::shutdown::Shutdown;
+use nix::{
+    sys::signal::{kill, Signal},
+    unistd::Pid,
+};
+
+#[path = "../src/test_dir.rs"]
+mod test_dir;
+
+use test_dir::TestDir;
+
+const CHECKPOINT: &str = "HDDMOND_TEST_CHECKPOINT";
+const TEARDOWN_MS: &str = "HDDMOND_TEST_TEARDOWN_MS";
+const GRACE_MS: &str = "HDDMOND_TEST_GRACE_MS";
+
+fn env_ms(name: &str) -> Duration {
+    Duration::from_millis(env::var(name).unwrap().parse().unwrap())
+}
+
+// Does nothing unless it's the child.
+#[test]
+fn stand_in_daemon() {
+    let checkpoint = match env::var(CHECKPOINT) {
+        Ok(checkpoint) => PathBuf::from(checkpoint),
+        Err(_) => return,
+    };
+    let (teardown, grace) = (env_ms(TEARDOWN_MS), env_ms(GRACE_MS));
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(async {
+        let mut shutdown = Shutdown::new().unwrap();
+        fs::write(&checkpoint, "running").unwrap();
+
+        let signal = shutdown.requested().await;
+        shutdown
+            .run(grace, async {
+                fs::write(&checkpoint, "stopping").unwrap();
+                tokio::time::sleep(teardown).await;
+                fs::write(&checkpoint, format!("saved after {}", signal)).unwrap();
+            })
+            .await;
+    });
+
+    // Only gets here if the shutdown returned, the rest of the cleanup.
+    let saved = fs::read_to_string(&checkpoint).unwrap();
+    fs::write(&checkpoint, format!("{}, exited", saved)).unwrap();
+}
+
+struct StandIn {
+    child: Child,
+    checkpoint: PathBuf,
+    _dir: TestDir,
+}
+
+impl StandIn {
+    fn start(name: &str, teardown_ms: u64, grace_ms: u64) -> Self {
+        let dir = TestDir::new(name);
+        let checkpoint = dir.join("checkpoint");
+        let child = Command::new(env::current_exe().unwrap())
+            .args(["--exact", "stand_in_daemon", "--test-threads", "1"])
+            .env(CHECKPOINT, &checkpoint)
+            .env(TEARDOWN_MS, teardown_ms.to_string())
+            .env(GRACE_MS, grace_ms.to_string())
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+
+        let stand_in = Self {
+            child,
+            checkpoint,
+            _dir: dir,
+        };
+        stand_in.wait_for("running");
+        stand_in
+    }
+
+    fn checkpoint(&self) -> String {
+        fs::read_to_string(&self.checkpoint).unwrap_or_default()
+    }
+
+    fn wait_for(&self, checkpoint: &str) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while self.checkpoint() != checkpoint {
+            assert!(
+                Instant::now() < deadline,
+                "Still at \"{}\"",
+                self.checkpoint()
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn signal(&self, signal: Signal) {
+        kill(Pid::from_raw(self.child.id() as i32), signal).unwrap();
+    }
+
+    fn wait(&mut self) -> ExitStatus {
+        self.child.wait().unwrap()
+    }
+}
+
+impl Drop for StandIn {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[test]
+fn sigterm_lets_the_long_task_save_its_checkpoint() {
+    let mut daemon = StandIn::start("shutdown-sigterm", 300, 10_000);
+
+    daemon.signal(Signal::SIGTERM);
+    assert!(daemon.wait().success());
+    assert_eq!(daemon.checkpoint(), "saved after SIGTERM, exited");
+}
+
+#[test]
+fn sigint_does_the_same() {
+    let mut daemon = StandIn::start("shutdown-sigint", 100, 10_000);
+
+    daemon.signal(Signal::SIGINT);
+    assert!(daemon.wait().success());
+    assert_eq!(daemon.checkpoint(), "saved after SIGINT, exited");
+}
+
+#[test]
+fn a_second_signal_exits_on_the_spot() {
+    let mut daemon = StandIn::start("shutdown-twice", 60_000, 60_000);
+
+    daemon.signal(Signal::SIGTERM);
+    daemon.wait_for("stopping");
+    let signalled = Instant::now();
+    daemon.signal(Signal::SIGTERM);
+
+    assert_eq!(daemon.wait().code(), Some(1));
+    assert!(signalled.elapsed() < Duration::from_secs(10));
+    // Neither the task nor the rest of the cleanup got to finish.
+    assert_eq!(daemon.checkpoint(), "stopping");
+}
+
+#[test]
+fn the_grace_period_cuts_a_slow_teardown_short() {
+    let mut daemon = StandIn::start("shutdown-grace", 60_000, 300);
+
+    let signalled = Instant::now();
+    daemon.signal(Signal::SIGTERM);
+
+    assert!(daemon.wait().success());
+    assert!(signalled.elapsed() < Duration::from_secs(10));
+    // The task was abandoned, but the cleanup after it still ran.
+    assert_eq!(daemon.checkpoint(), "stopping, exited");
+}
@@ -0,0 +1,246 @@
+// Runs the whole daemon against a simulated fleet: drives come and go on a
+// schedule, and the test watches them arrive at a webhook and in
+// `hddmond status`, the way someone would on a real machine.
+
+use std::{
+    fs,
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    path::{Path, PathBuf},
+    process::{self, Child, Command, Stdio},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use nix::{
+    sys::signal::{kill, Signal},
+    unistd::Pid,
+};
+use serde_json::Value;
+
+#[path = "../src/test_dir.rs"]
+mod test_dir;
+
+use test_dir::TestDir;
+
+// Three drives there from the start, and a hot swap bay that sees one come
+// a second in and go again a second later.
+const FLEET: &str = r#"
+speed = 10.0
+
+[[drives]]
+name = "shelf"
+count = 3
+model = "WDC WD40EFRX-68N32N0"
+serial = "WD-SIM"
+
+[[drives]]
+name = "bay"
+serial = "ST-SIM"
+model = "ST8000VN004-2M2101"
+present = false
+hotplug = [
+  { at_secs = 10, action = "insert" },
+  { at_secs = 20, action = "remove" },
+]
+"#;
+
+// Answers every POST with a 200, and sends on each body as JSON.
+fn webhook() -> (String, mpsc::Receiver<Value>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    let (sender, receiver) = mpsc::channel();
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut length = 0;
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap_or(0) > 0 && line != "\r\n" {
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+                line.clear();
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            let _ = stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n");
+
+            if sender.send(serde_json::from_slice(&body).unwrap()).is_err() {
+                break;
+            }
+        }
+    });
+
+    (url, receiver)
+}
+
+struct Daemon {
+    child: Child,
+    config: PathBuf,
+    log: PathBuf,
+}
+
+impl Daemon {
+    fn start(dir: &TestDir, webhook: &str) -> Self {
+        let fleet = dir.join("fleet.toml");
+        fs::write(&fleet, FLEET).unwrap();
+        let config = dir.join("hddmond.toml");
+        fs::write(
+            &config,
+            format!(
+                "[daemon]\ncontrol_socket = {:?}\n\
+                 [plugin_host]\ndir = {:?}\n\
+                 [notifiers]\ngroup_window_secs = 0\ndedup_window_secs = 0\n\
+                 [[notifiers.webhooks]]\nurl = {:?}\n",
+                dir.join("hddmond.sock"),
+                dir.join("plugins"),
+                webhook,
+            ),
+        )
+        .unwrap();
+
+        let log = dir.join("stderr.log");
+        let child = Command::new(env!("CARGO_BIN_EXE_hddmond"))
+            .arg("--config")
+            .arg(&config)
+            .arg("--simulate")
+            .arg(&fleet)
+            .env_clear()
+            .stdout(Stdio::null())
+            .stderr(fs::File::create(&log).unwrap())
+            .spawn()
+            .unwrap();
+        Self { child, config, log }
+    }
+
+    fn log(&self) -> String {
+        fs::read_to_string(&self.log).unwrap_or_default()
+    }
+
+    // `hddmond status`, once the daemon answers.
+    fn status(&self) -> Value {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            let output = hddmond(&self.config, &["status"]);
+            if output.status.success() {
+                return serde_json::from_slice(&output.stdout).unwrap();
+            }
+            assert!(
+                Instant::now() < deadline,
+                "The daemon never answered: {}\n{}",
+                String::from_utf8_lossy(&output.stderr),
+                self.log()
+            );
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    fn stop(mut self) -> process::ExitStatus {
+        kill(Pid::from_raw(self.child.id() as i32), Signal::SIGTERM).unwrap();
+        self.child.wait().unwrap()
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn hddmond(config: &Path, args: &[&str]) -> process::Output {
+    Command::new(env!("CARGO_BIN_EXE_hddmond"))
+        .arg("--config")
+        .arg(config)
+        .args(args)
+        .env_clear()
+        .output()
+        .unwrap()
+}
+
+fn present(status: &Value) -> Vec<String> {
+    let mut devices = status["devices"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|device| device["device"].as_str().unwrap().to_string())
+        .collect::<Vec<_>>();
+    devices.sort();
+    devices
+}
+
+#[test]
+fn drives_come_and_go_through_the_whole_daemon() {
+    let dir = TestDir::new("simulated");
+    let (url, notifications) = webhook();
+    let daemon = Daemon::start(&dir, &url);
+
+    let status = daemon.status();
+    assert_eq!(status["backend"], "simulated");
+    let subsystems = status["subsystems"].as_object().unwrap();
+    assert!(!subsystems.is_empty());
+    assert!(
+        subsystems.values().all(|state| state == "running"),
+        "{:?}",
+        subsystems
+    );
+
+    // Every drive, in the order the schedule brings them. Drives found
+    // together can come as one digest, which lists them all in its group.
+    let (mut seen, mut posts) = (vec![], 0);
+    while seen.len() < 5 {
+        let notification = notifications
+            .recv_timeout(Duration::from_secs(10))
+            .unwrap_or_else(|_| panic!("Only got {:?}\n{}", seen, daemon.log()));
+        posts += 1;
+
+        let event = notification["event"].as_str().unwrap().to_string();
+        let members = match notification["group"].as_array() {
+            Some(group) if !group.is_empty() => group.clone(),
+            _ => vec![notification],
+        };
+        for member in members {
+            seen.push((
+                event.clone(),
+                member["device"].as_str().unwrap().to_string(),
+                member["serial"].as_str().unwrap_or_default().to_string(),
+            ));
+        }
+    }
+    let (startup, hotplug) = seen.split_at_mut(3);
+    startup.sort();
+    let event = |event: &str, device: &str, serial: &str| {
+        (event.to_string(), device.to_string(), serial.to_string())
+    };
+    assert_eq!(
+        startup,
+        [
+            event("device_found", "shelf0", "WD-SIM-0"),
+            event("device_found", "shelf1", "WD-SIM-1"),
+            event("device_found", "shelf2", "WD-SIM-2"),
+        ]
+    );
+    assert_eq!(
+        hotplug,
+        [
+            event("device_found", "bay", "ST-SIM"),
+            event("device_lost", "bay", "ST-SIM"),
+        ]
+    );
+
+    // The registry kept the bay's drive, as gone.
+    let status = daemon.status();
+    assert_eq!(present(&status), ["shelf0", "shelf1", "shelf2"]);
+    assert_eq!(status["storage"]["present"], 3);
+    assert_eq!(status["storage"]["absent"], 1);
+    assert_eq!(status["notifiers"][0]["delivered"], posts);
+
+    let log = daemon.log();
+    assert!(daemon.stop().success(), "{}", log);
+}
@@ -0,0 +1,133 @@
+// Hands hotplug notifications to two webhooks, one of which takes a second
+// to answer each of them, and checks the other one still gets every
+// notification about as soon as it's sent.
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use hddmond::{
+    config::NotifiersConfig,
+    device_policy::DeviceIdentity,
+    notifiers::{notification::Notification, notifier_host::NotifierHost},
+    supervisor::Health,
+};
+
+const HOTPLUGS: usize = 5;
+
+// How late the fast webhook may get a notification. Far more than it takes,
+// and far less than the slow one holds each of them up for.
+const LATENCY_BOUND: Duration = Duration::from_millis(500);
+
+// Answers every POST with a 200 after `delay`, one at a time, and sends on
+// when each one came in and what devices it was for. Notifications that
+// waited together go out as one digest, listing them all in its group.
+fn webhook(delay: Duration) -> (String, mpsc::Receiver<(Instant, Vec<String>)>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    let (sender, receiver) = mpsc::channel();
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut length = 0;
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap_or(0) > 0 && line != "\r\n" {
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+                line.clear();
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            let received = Instant::now();
+
+            thread::sleep(delay);
+            let _ = stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n");
+
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let members = match body["group"].as_array() {
+                Some(group) if !group.is_empty() => group.clone(),
+                _ => vec![body],
+            };
+            let devices = members
+                .iter()
+                .map(|member| member["device"].as_str().unwrap().to_string())
+                .collect();
+            if sender.send((received, devices)).is_err() {
+                break;
+            }
+        }
+    });
+
+    (url, receiver)
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn a_slow_webhook_doesnt_hold_up_the_others() {
+    let (slow_url, slow) = webhook(Duration::from_secs(1));
+    let (fast_url, fast) = webhook(Duration::ZERO);
+    let config: NotifiersConfig = toml::from_str(&format!(
+        "group_window_secs = 0\ndedup_window_secs = 0\n\
+         [[webhooks]]\nurl = {:?}\ntimeout_ms = 30000\n\
+         [[webhooks]]\nurl = {:?}\n",
+        slow_url, fast_url
+    ))
+    .unwrap();
+    let mut host = NotifierHost::new(&config, &Health::new()).unwrap();
+
+    let mut sent = vec![];
+    for n in 0..HOTPLUGS {
+        let identity = DeviceIdentity {
+            name: format!("sd{}", (b'a' + n as u8) as char),
+            serial: Some(format!("WD-{}", n)),
+            ..Default::default()
+        };
+        let notifying = Instant::now();
+        host.notify(Notification::device_found(&identity));
+        // Only ever queued, whoever it's for.
+        assert!(notifying.elapsed() < Duration::from_millis(50));
+        sent.push((notifying, identity.name));
+
+        // Apart enough to not go out as one digest.
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+
+    for (notified, device) in &sent {
+        let (received, got) = fast
+            .recv_timeout(Duration::from_secs(10))
+            .unwrap_or_else(|_| panic!("The fast webhook never got {}", device));
+        assert_eq!(got, std::slice::from_ref(device));
+        let latency = received - *notified;
+        assert!(
+            latency < LATENCY_BOUND,
+            "{} took {:?} to get to the fast webhook",
+            device,
+            latency
+        );
+    }
+
+    // The slow one gets them all too, in its own time, the ones that had
+    // to wait likely as a digest.
+    let mut slow_devices = vec![];
+    while slow_devices.len() < HOTPLUGS {
+        let (_, devices) = slow
+            .recv_timeout(Duration::from_secs(10))
+            .unwrap_or_else(|_| panic!("The slow webhook only got {:?}", slow_devices));
+        slow_devices.extend(devices);
+    }
+    assert_eq!(
+        slow_devices,
+        sent.iter()
+            .map(|(_, device)| device.clone())
+            .collect::<Vec<_>>()
+    );
+}
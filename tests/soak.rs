@@ -0,0 +1,328 @@
+// Soak test: pushes device events for a fleet of simulated drives through
+// what the daemon does with each one, as fast as it can, and checks that
+// the memory in use stops growing once it has warmed up.
+//
+//     cargo test --release --test soak -- --ignored
+//
+// SOAK_EVENTS sets how many events (1,000,000 by default), SOAK_DRIVES how
+// many drives they go round. A short run without the plugins goes with the
+// rest of the tests, it's the only other test in here so the counts aren't
+// thrown off by another one running alongside.
+//
+// What's allocated through Rust is counted exactly. V8 and SQLite's C side
+// allocate with malloc behind the allocator's back, those only show in the
+// resident set size, which also moves with whatever malloc keeps cached.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    collections::HashMap,
+    env, fs,
+    path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Instant,
+};
+
+use hddmond::{
+    config::{NotifiersConfig, PluginHostConfig},
+    device_policy::{self, DevicePolicy},
+    notifiers::{notification::Notification, notifier_host::NotifierHost},
+    plugins::plugin_host::PluginHost,
+    scanners::{
+        scanner::{DeviceMonitor, ScanEventType},
+        simulated_scanner::{Fleet, FleetDrive, SimulatedMonitor},
+    },
+    storage::{Registry, IN_MEMORY},
+    supervisor::Health,
+};
+
+#[path = "../src/test_dir.rs"]
+mod test_dir;
+
+use test_dir::TestDir;
+
+// Bytes allocated and not yet freed.
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+
+struct Counting;
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            LIVE.fetch_add(layout.size(), Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            LIVE.fetch_add(layout.size(), Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        LIVE.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = System.realloc(ptr, layout, new_size);
+        if !new.is_null() {
+            LIVE.fetch_add(new_size, Ordering::Relaxed);
+            LIVE.fetch_sub(layout.size(), Ordering::Relaxed);
+        }
+        new
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+const SAMPLES: usize = 20;
+// Samples before this one are warmup: caches filling, the registry
+// reaching its event limit, queues reaching their size.
+const WARMUP: usize = 5;
+// How much the memory in use may move after warmup.
+const BAND_BYTES: usize = 1024 * 1024;
+const RSS_BAND_BYTES: usize = 16 * 1024 * 1024;
+
+struct Sample {
+    events: usize,
+    live_bytes: usize,
+    rss_bytes: usize,
+}
+
+fn setting(name: &str, default: usize) -> usize {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+#[test]
+#[ignore]
+fn a_million_events() {
+    soak_test(
+        setting("SOAK_EVENTS", 1_000_000),
+        setting("SOAK_DRIVES", 50),
+        true,
+    );
+}
+
+// Long enough for every drive to reach its event limit in the registry
+// well before the last sample. The plugins can't be counted on to be
+// there wherever the tests run.
+#[test]
+fn memory_holds_steady_over_a_short_run() {
+    soak_test(20_000, 10, false);
+}
+
+fn soak_test(events: usize, drives: usize, plugins: bool) {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("Can't start the tokio runtime");
+    let samples = runtime.block_on(soak(events, drives, plugins));
+
+    println!(
+        "{:>10}  {:>12}  {:>12}",
+        "events", "live bytes", "rss bytes"
+    );
+    for sample in &samples {
+        println!(
+            "{:>10}  {:>12}  {:>12}",
+            sample.events, sample.live_bytes, sample.rss_bytes
+        );
+    }
+
+    let settled = &samples[WARMUP.min(samples.len() - 1)..];
+    let live = check(
+        "Memory in use",
+        settled,
+        |sample| sample.live_bytes,
+        BAND_BYTES,
+    );
+    let rss = check(
+        "The resident set",
+        settled,
+        |sample| sample.rss_bytes,
+        RSS_BAND_BYTES,
+    );
+    assert!(live && rss, "The memory in use kept growing after warmup");
+}
+
+fn check(what: &str, samples: &[Sample], bytes: impl Fn(&Sample) -> usize, band: usize) -> bool {
+    let low = samples.iter().map(&bytes).min().unwrap_or(0);
+    let high = samples.iter().map(&bytes).max().unwrap_or(0);
+    let growth = samples.last().map_or(0, &bytes) as i64 - samples.first().map_or(0, &bytes) as i64;
+    println!(
+        "{} after warmup: {} to {} bytes, {:+} from the first sample",
+        what, low, high, growth
+    );
+
+    if high - low > band {
+        eprintln!(
+            "{} moved by {} bytes after warmup, more than {}",
+            what,
+            high - low,
+            band
+        );
+        return false;
+    }
+    true
+}
+
+// From /proc/self/statm, in pages.
+fn rss_bytes() -> usize {
+    let pages = fs::read_to_string("/proc/self/statm")
+        .ok()
+        .and_then(|statm| statm.split_whitespace().nth(1)?.parse::<usize>().ok())
+        .unwrap_or(0);
+    // 4 KiB pages everywhere this runs.
+    pages * 4096
+}
+
+// The drives come and go in turn: every event finds the next drive the
+// other way round from how it left it.
+async fn soak(events: usize, drives: usize, plugins: bool) -> Vec<Sample> {
+    let fleet = Fleet {
+        speed: 1.0,
+        drives: vec![FleetDrive {
+            name: Some("soak".to_string()),
+            count: drives,
+            model: Some("Soak Test Drive".to_string()),
+            serial: Some("SOAK".to_string()),
+            present: false,
+            hotplug: vec![],
+            stagger_secs: 0,
+        }],
+    };
+    let monitor = SimulatedMonitor::new(&fleet);
+
+    let mut device_policy = DevicePolicy::new(Default::default());
+    device_policy.add_identities(monitor.identities());
+    let mut registry = Registry::open(Path::new(IN_MEMORY)).expect("Can't open the registry");
+    let mut notifier_host =
+        NotifierHost::new(&notifiers(), &Health::new()).expect("Can't start the notifiers");
+
+    let plugin_dir = plugin_dir(plugins);
+    let mut plugin_host = PluginHost::load_dir(
+        &plugin_dir,
+        PluginHostConfig::default().limits(),
+        HashMap::new(),
+    )
+    .await
+    .expect("Can't load the plugins");
+
+    let names = monitor
+        .identities()
+        .into_iter()
+        .map(|identity| identity.name)
+        .collect::<Vec<_>>();
+    let mut present = vec![false; names.len()];
+
+    let every = (events / SAMPLES).max(1);
+    // Up front, or its own growing would show.
+    let mut samples = Vec::with_capacity(SAMPLES + 1);
+    let started = Instant::now();
+    for done in 0..events {
+        let drive = done % names.len();
+        let event = if present[drive] {
+            ScanEventType::DeviceLost(names[drive].clone())
+        } else {
+            ScanEventType::DeviceFound(names[drive].clone())
+        };
+        present[drive] = !present[drive];
+
+        handle(
+            event,
+            &mut device_policy,
+            &mut registry,
+            &mut notifier_host,
+            &mut plugin_host,
+        );
+
+        if done % every == every - 1 {
+            // Lets the plugin and notifier tasks catch up before measuring.
+            tokio::task::yield_now().await;
+            samples.push(Sample {
+                events: done + 1,
+                live_bytes: LIVE.load(Ordering::Relaxed),
+                rss_bytes: rss_bytes(),
+            });
+        }
+    }
+    eprintln!("{} events in {:.1?}", events, started.elapsed());
+
+    plugin_host.shutdown().await;
+    notifier_host.shutdown().await;
+
+    samples
+}
+
+// What the daemon's event loop does with a found or lost event, minus
+// the probes that need a real device.
+fn handle(
+    event: ScanEventType,
+    device_policy: &mut DevicePolicy,
+    registry: &mut Registry,
+    notifier_host: &mut NotifierHost,
+    plugin_host: &mut PluginHost,
+) {
+    let event = match device_policy.filter(event) {
+        Some(event) => event,
+        None => return,
+    };
+    plugin_host.dispatch(&event);
+
+    match event {
+        ScanEventType::DeviceFound(device) => {
+            let identity = device_policy.identity(&device);
+            registry
+                .device_found(&identity)
+                .expect("Can't record a found drive");
+            notifier_host.notify(Notification::device_found(&identity));
+        }
+        ScanEventType::DeviceLost(device) => {
+            let name = device_policy::device_name(&device);
+            let record = registry
+                .device_lost(name)
+                .expect("Can't record a lost drive");
+            notifier_host.notify(Notification::device_lost(name, record.as_ref()));
+        }
+        _ => {}
+    }
+}
+
+// A webhook that's always in quiet hours, bar the last minute of the day,
+// so it holds on to every notification instead of sending it. Nothing is
+// deduplicated first, everything reaches it.
+fn notifiers() -> NotifiersConfig {
+    toml::from_str(
+        r#"
+        dedup_window_secs = 0
+
+        [[webhooks]]
+        url = "http://127.0.0.1:9/"
+        quiet_hours = "00:00-23:59"
+        "#,
+    )
+    .expect("The notifier config doesn't parse")
+}
+
+// The example plugin, so every event goes through a JS runtime too, or an
+// empty directory.
+fn plugin_dir(plugins: bool) -> TestDir {
+    let dir = TestDir::new("soak");
+    if !plugins {
+        return dir;
+    }
+    fs::copy(
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("examples/plugins/log_devices.js"),
+        dir.join("log_devices.js"),
+    )
+    .expect("Can't copy the example plugin");
+    dir
+}
@@ -0,0 +1,145 @@
+// Runs `hddmond test-email` against a local SMTP server, and checks the
+// test email arrives for every configured target.
+
+use std::{
+    fs,
+    io::{BufRead, BufReader, Write},
+    net::TcpListener,
+    process::{Command, Output},
+    sync::mpsc,
+    thread,
+};
+
+#[path = "../src/test_dir.rs"]
+mod test_dir;
+
+use test_dir::TestDir;
+
+// Takes one mail per connection, until the test is done, and sends back
+// (envelope recipients, data) for each.
+fn smtp_server() -> (u16, mpsc::Receiver<(Vec<String>, String)>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (sender, receiver) = mpsc::channel();
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut writer = stream.unwrap();
+            let mut reader = BufReader::new(writer.try_clone().unwrap());
+            let mut reply = |line: &str| writer.write_all(format!("{}\r\n", line).as_bytes());
+            let (mut to, mut data) = (vec![], String::new());
+
+            reply("220 test ESMTP").unwrap();
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap_or(0) > 0 {
+                let command = line.trim_end().to_string();
+                line.clear();
+                if command.to_uppercase() == "DATA" {
+                    reply("354 Go ahead").unwrap();
+                    while reader.read_line(&mut line).unwrap_or(0) > 0 && line.trim_end() != "." {
+                        data.push_str(&line);
+                        line.clear();
+                    }
+                    line.clear();
+                    reply("250 Queued").unwrap();
+                } else if command.to_uppercase() == "QUIT" {
+                    let _ = reply("221 Bye");
+                    break;
+                } else {
+                    if let Some(rcpt) = command.strip_prefix("RCPT TO:") {
+                        to.push(rcpt.trim_matches(|c| c == '<' || c == '>').to_string());
+                    }
+                    reply("250 OK").unwrap();
+                }
+            }
+
+            if sender.send((to, data)).is_err() {
+                break;
+            }
+        }
+    });
+
+    (port, receiver)
+}
+
+fn hddmond_test_email(name: &str, config: &str) -> Output {
+    let dir = TestDir::new(name);
+    let path = dir.join("hddmond.toml");
+    fs::write(&path, config).unwrap();
+    Command::new(env!("CARGO_BIN_EXE_hddmond"))
+        .arg("--config")
+        .arg(&path)
+        .arg("test-email")
+        .env_clear()
+        .output()
+        .unwrap()
+}
+
+fn email_target(port: u16, to: &str) -> String {
+    format!(
+        "[[notifiers.emails]]\nserver = \"127.0.0.1\"\nport = {}\ntls = \"none\"\n\
+         from = \"hddmond@bench.example\"\nto = [\"{}\"]\n",
+        port, to
+    )
+}
+
+#[test]
+fn sends_a_test_email_through_every_target() {
+    let (port, mails) = smtp_server();
+    let config =
+        email_target(port, "ops@bench.example") + &email_target(port, "lead@bench.example");
+
+    let output = hddmond_test_email("test-email", &config);
+    assert!(output.status.success(), "{:?}", output);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(
+        stdout,
+        "Test email sent (email via 127.0.0.1)\nTest email sent (email via 127.0.0.1)\n"
+    );
+
+    for to in ["ops@bench.example", "lead@bench.example"] {
+        let (recipients, data) = mails.recv().unwrap();
+        assert_eq!(recipients, [to]);
+        assert!(data.contains("Subject: hddmond test email\r\n"), "{}", data);
+        assert!(
+            data.contains("This is a test email from hddmond."),
+            "{}",
+            data
+        );
+    }
+}
+
+#[test]
+fn failures_are_reported_per_target() {
+    let (port, mails) = smtp_server();
+    // Nothing listens on the discard port.
+    let config = email_target(9, "ops@bench.example") + &email_target(port, "lead@bench.example");
+
+    let output = hddmond_test_email("test-email-failed", &config);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Test email failed (email via 127.0.0.1)"),
+        "{}",
+        stderr
+    );
+    assert!(
+        stderr.contains("1 of the email notifiers failed"),
+        "{}",
+        stderr
+    );
+    // The other one still went out.
+    assert_eq!(mails.recv().unwrap().0, ["lead@bench.example"]);
+}
+
+#[test]
+fn needs_an_email_target() {
+    let output = hddmond_test_email("test-email-none", "");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("No email notifiers configured in [[notifiers.emails]]"),
+        "{}",
+        stderr
+    );
+}